@@ -0,0 +1,122 @@
+//! Times the interpreter on a few small workloads, to tell whether a change to it is a win.
+//!
+//! Run it with `cargo bench --bench vm`, optionally followed by the names of the workloads to run.
+//! Each one runs a few times in a fresh state, and the fastest run is reported, the others being
+//! slowed down by whatever else the machine was doing.
+
+use std::env;
+use std::time::{Duration, Instant};
+
+use tei::Lua;
+
+const RUNS: usize = 5;
+
+/// Recursive calls and integer arithmetic.
+const FIB: &str = "
+local function fib(n)
+    if n < 2 then return n end
+    return fib(n - 1) + fib(n - 2)
+end
+assert(fib(27) == 196418)
+";
+
+/// Float arithmetic and field access, after the benchmarks game's n-body.
+const NBODY: &str = "
+local sqrt = math.sqrt
+local PI = math.pi
+local SOLAR_MASS = 4 * PI * PI
+local DAYS_PER_YEAR = 365.24
+local bodies = {
+    {x = 0, y = 0, z = 0, vx = 0, vy = 0, vz = 0, mass = SOLAR_MASS},
+    {x = 4.84143144246472090e+00, y = -1.16032004402742839e+00, z = -1.03622044471123109e-01,
+     vx = 1.66007664274403694e-03 * DAYS_PER_YEAR, vy = 7.69901118419740425e-03 * DAYS_PER_YEAR,
+     vz = -6.90460016972063023e-05 * DAYS_PER_YEAR, mass = 9.54791938424326609e-04 * SOLAR_MASS},
+    {x = 8.34336671824457987e+00, y = 4.12479856412430479e+00, z = -4.03523417114321381e-01,
+     vx = -2.76742510726862411e-03 * DAYS_PER_YEAR, vy = 4.99852801234917238e-03 * DAYS_PER_YEAR,
+     vz = 2.30417297573763929e-05 * DAYS_PER_YEAR, mass = 2.85885980666130812e-04 * SOLAR_MASS},
+    {x = 1.28943695621391310e+01, y = -1.51111514016986312e+01, z = -2.23307578892655734e-01,
+     vx = 2.96460137564761618e-03 * DAYS_PER_YEAR, vy = 2.37847173959480950e-03 * DAYS_PER_YEAR,
+     vz = -2.96589568540237556e-05 * DAYS_PER_YEAR, mass = 4.36624404335156298e-05 * SOLAR_MASS},
+    {x = 1.53796971148509165e+01, y = -2.59193146099879641e+01, z = 1.79258772950371181e-01,
+     vx = 2.68067772490389322e-03 * DAYS_PER_YEAR, vy = 1.62824170038242295e-03 * DAYS_PER_YEAR,
+     vz = -9.51592254519715870e-05 * DAYS_PER_YEAR, mass = 5.15138902046611451e-05 * SOLAR_MASS},
+}
+
+local function advance(dt)
+    local n = #bodies
+    for i = 1, n do
+        local bi = bodies[i]
+        local bix, biy, biz, bimass = bi.x, bi.y, bi.z, bi.mass
+        local bivx, bivy, bivz = bi.vx, bi.vy, bi.vz
+        for j = i + 1, n do
+            local bj = bodies[j]
+            local dx, dy, dz = bix - bj.x, biy - bj.y, biz - bj.z
+            local d2 = dx * dx + dy * dy + dz * dz
+            local mag = dt / (d2 * sqrt(d2))
+            local bm = bj.mass * mag
+            bivx = bivx - dx * bm
+            bivy = bivy - dy * bm
+            bivz = bivz - dz * bm
+            bm = bimass * mag
+            bj.vx = bj.vx + dx * bm
+            bj.vy = bj.vy + dy * bm
+            bj.vz = bj.vz + dz * bm
+        end
+        bi.vx, bi.vy, bi.vz = bivx, bivy, bivz
+        bi.x = bix + dt * bivx
+        bi.y = biy + dt * bivy
+        bi.z = biz + dt * bivz
+    end
+end
+
+for _ = 1, 100000 do advance(0.01) end
+";
+
+/// Building, reading and sorting tables, with string keys and method calls.
+const TABLES: &str = "
+local Point = {}
+Point.__index = Point
+function Point.new(x, y) return setmetatable({x = x, y = y}, Point) end
+function Point:length2() return self.x * self.x + self.y * self.y end
+
+local points = {}
+for i = 1, 100000 do points[#points + 1] = Point.new(i % 97, i % 89) end
+local total = 0
+for _ = 1, 5 do
+    for _, p in ipairs(points) do total = total + p:length2() end
+end
+local counts = {}
+for i = 1, 200000 do
+    local key = 'k' .. (i % 1000)
+    counts[key] = (counts[key] or 0) + 1
+end
+table.sort(points, function(a, b) return a:length2() < b:length2() end)
+assert(total > 0 and counts.k0 == 200)
+";
+
+const WORKLOADS: [(&str, &str); 3] = [("fib", FIB), ("nbody", NBODY), ("tables", TABLES)];
+
+fn main() {
+    // `cargo bench` passes `--bench`.
+    let filters: Vec<String> = env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with('-'))
+        .collect();
+    for (name, source) in WORKLOADS {
+        if !filters.is_empty() && !filters.iter().any(|f| name.contains(f.as_str())) {
+            continue;
+        }
+        let best = (0..RUNS).map(|_| run(source)).min().unwrap_or_default();
+        println!("{name:<8} {:>8.1} ms", best.as_secs_f64() * 1000.0);
+    }
+}
+
+fn run(source: &str) -> Duration {
+    let mut lua = Lua::new();
+    lua.enter(|ctx| {
+        let function = ctx.load("=bench", source).expect("workloads compile");
+        let start = Instant::now();
+        ctx.call(function, &[]).expect("workloads run");
+        start.elapsed()
+    })
+}
@@ -0,0 +1,128 @@
+//! Runs scripts in a web page, with the state kept between runs so that each one sees the globals
+//! the ones before it set.
+//!
+//! Build the module with `cargo build --release --example browser --target wasm32-unknown-unknown`
+//! and serve `examples/browser.html` with
+//! `target/wasm32-unknown-unknown/release/examples/browser.wasm` next to it. The page and the
+//! module talk through the exported functions below, with no bindings generated: the page writes
+//! a script into the input buffer, calls `run`, and reads what it printed from the output buffer.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use tei::stdlib::{self, OsOptions};
+use tei::Lua;
+
+/// What the scripts print, and the results or error of the last one.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Session {
+    lua: Lua,
+    input: Vec<u8>,
+    output: Output,
+    /// The output of the last run, which the page reads.
+    last: Vec<u8>,
+}
+
+thread_local! {
+    static SESSION: RefCell<Session> = RefCell::new(Session::new());
+}
+
+impl Session {
+    fn new() -> Session {
+        let output = Output::default();
+        let mut lua = Lua::empty();
+        lua.enter(|ctx| {
+            stdlib::load_base(ctx);
+            stdlib::load_coroutine(ctx);
+            stdlib::load_math(ctx);
+            stdlib::load_string(ctx);
+            stdlib::load_table(ctx);
+            stdlib::load_utf8(ctx);
+            stdlib::load_os_with(ctx, os_options());
+            ctx.set_stdout(output.clone());
+            ctx.set_stderr(output.clone());
+        });
+        Session {
+            lua,
+            input: Vec::new(),
+            output,
+            last: Vec::new(),
+        }
+    }
+}
+
+/// The os library without the file system or the environment, none of which a page has, and with
+/// the page's clocks.
+fn os_options() -> OsOptions {
+    OsOptions {
+        date: true,
+        time: true,
+        clock: true,
+        #[cfg(target_arch = "wasm32")]
+        clock_source: Some(|| unsafe { page::performance_now() } / 1000.0),
+        #[cfg(target_arch = "wasm32")]
+        time_source: Some(|| (unsafe { page::date_now() } / 1000.0).floor() as i64),
+        ..OsOptions::sandboxed()
+    }
+}
+
+/// The functions the page gives the module, as the `env` of its imports.
+#[cfg(target_arch = "wasm32")]
+mod page {
+    extern "C" {
+        /// `performance.now()`, in milliseconds.
+        pub fn performance_now() -> f64;
+        /// `Date.now()`, in milliseconds since the epoch.
+        pub fn date_now() -> f64;
+    }
+}
+
+/// Makes room for a script of `len` bytes, and returns where the page writes it.
+#[no_mangle]
+pub extern "C" fn input(len: usize) -> *mut u8 {
+    SESSION.with_borrow_mut(|session| {
+        session.input.resize(len, 0);
+        session.input.as_mut_ptr()
+    })
+}
+
+/// Runs the script in the input buffer, and returns the length of what it printed, followed by
+/// its results or its error.
+#[no_mangle]
+pub extern "C" fn run() -> usize {
+    SESSION.with_borrow_mut(|session| {
+        let source = String::from_utf8_lossy(&session.input).into_owned();
+        let outcome = session.lua.enter(|ctx| match ctx.eval(&source) {
+            Ok(values) if values.is_empty() => String::new(),
+            Ok(values) => {
+                let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+                values.join("\t") + "\n"
+            }
+            Err(err) => format!("error: {err}\n"),
+        });
+        let mut output = session.output.0.lock().unwrap();
+        output.extend_from_slice(outcome.as_bytes());
+        session.last = std::mem::take(&mut *output);
+        session.last.len()
+    })
+}
+
+/// Where the output of the last run is.
+#[no_mangle]
+pub extern "C" fn output() -> *const u8 {
+    SESSION.with_borrow(|session| session.last.as_ptr())
+}
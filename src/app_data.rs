@@ -0,0 +1,190 @@
+//! Host values kept with a state, one of each type, for callbacks to find.
+
+use std::any::{Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use crate::{Context, MaybeSend};
+
+/// The values, each in its own cell so that different types can be borrowed at once. The map itself
+/// is borrowed as long as any value is, which keeps the cells where they are.
+#[derive(Default)]
+pub(crate) struct AppData(RefCell<Cells>);
+
+type Cells = HashMap<TypeId, Slot>;
+type Slot = RefCell<Box<dyn Any>>;
+
+/// A shared borrow of a value stored with [`Context::set_app_data`].
+pub struct AppDataRef<'a, T> {
+    // Declared first so that it is dropped before the borrow of the map it points into.
+    value: Ref<'a, T>,
+    _map: Ref<'a, Cells>,
+}
+
+/// A mutable borrow of a value stored with [`Context::set_app_data`].
+pub struct AppDataRefMut<'a, T> {
+    value: RefMut<'a, T>,
+    _map: Ref<'a, Cells>,
+}
+
+impl<'a, T> Deref for AppDataRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for AppDataRef<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T> Deref for AppDataRefMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<'a, T> DerefMut for AppDataRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for AppDataRefMut<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl AppData {
+    /// The cell holding the `T`, with the map borrowed for as long as the cell is used.
+    fn cell<T: 'static>(&self) -> Option<(Ref<'_, Cells>, &Slot)> {
+        let map = self.0.try_borrow().expect("app data is being changed");
+        let cell: *const Slot = map.get(&TypeId::of::<T>())?;
+        // SAFETY: the cell can only move or be dropped through a mutable borrow of the map, which
+        // the returned borrow rules out for as long as the cell is used.
+        Some((map, unsafe { &*cell }))
+    }
+
+    fn get<T: 'static>(&self) -> Option<AppDataRef<'_, T>> {
+        let (map, cell) = self.cell::<T>()?;
+        let value = cell
+            .try_borrow()
+            .unwrap_or_else(|_| panic!("{} is mutably borrowed", std::any::type_name::<T>()));
+        Some(AppDataRef {
+            value: Ref::map(value, |value| value.downcast_ref().expect("keyed by type")),
+            _map: map,
+        })
+    }
+
+    fn get_mut<T: 'static>(&self) -> Option<AppDataRefMut<'_, T>> {
+        let (map, cell) = self.cell::<T>()?;
+        let value = cell
+            .try_borrow_mut()
+            .unwrap_or_else(|_| panic!("{} is already borrowed", std::any::type_name::<T>()));
+        Some(AppDataRefMut {
+            value: RefMut::map(value, |value| value.downcast_mut().expect("keyed by type")),
+            _map: map,
+        })
+    }
+
+    fn map_mut(&self) -> RefMut<'_, Cells> {
+        self.0
+            .try_borrow_mut()
+            .expect("app data can't be changed while any of it is borrowed")
+    }
+}
+
+impl<'gc> Context<'gc> {
+    /// Stores `data` with the state, replacing and returning any `T` stored before. Callbacks get
+    /// at it through their [`Context`], rather than capturing it themselves.
+    ///
+    /// # Panics
+    ///
+    /// If any app data is borrowed.
+    pub fn set_app_data<T: MaybeSend + 'static>(self, data: T) -> Option<T> {
+        let old = self
+            .state()
+            .app_data()
+            .map_mut()
+            .insert(TypeId::of::<T>(), RefCell::new(Box::new(data)))?;
+        Some(*old.into_inner().downcast().expect("keyed by type"))
+    }
+
+    /// Borrows the `T` stored with the state, if there is one.
+    ///
+    /// # Panics
+    ///
+    /// If it is mutably borrowed.
+    pub fn app_data_ref<T: 'static>(self) -> Option<AppDataRef<'gc, T>> {
+        self.state().app_data().get()
+    }
+
+    /// Mutably borrows the `T` stored with the state, if there is one.
+    ///
+    /// # Panics
+    ///
+    /// If it is borrowed at all.
+    pub fn app_data_mut<T: 'static>(self) -> Option<AppDataRefMut<'gc, T>> {
+        self.state().app_data().get_mut()
+    }
+
+    /// Takes the `T` stored with the state out of it.
+    ///
+    /// # Panics
+    ///
+    /// If any app data is borrowed.
+    pub fn remove_app_data<T: 'static>(self) -> Option<T> {
+        let old = self
+            .state()
+            .app_data()
+            .map_mut()
+            .remove(&TypeId::of::<T>())?;
+        Some(*old.into_inner().downcast().expect("keyed by type"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Function, Lua, LuaString};
+
+    struct Config {
+        greeting: String,
+    }
+
+    #[test]
+    fn callbacks_find_app_data() {
+        let mut lua = Lua::new();
+        let output = lua.enter(|ctx| {
+            assert!(ctx.app_data_ref::<Config>().is_none());
+            ctx.set_app_data(Config {
+                greeting: "hello".into(),
+            });
+            ctx.set_app_data(Vec::<String>::new());
+            let greet = Function::from_typed_fn(&ctx, |ctx, name: String| {
+                let config = ctx.app_data_ref::<Config>().unwrap();
+                let line = format!("{}, {name}", config.greeting);
+                ctx.app_data_mut::<Vec<String>>().unwrap().push(line);
+                Ok(())
+            });
+            let name = LuaString::new(&ctx, b"greet");
+            ctx.globals().set(&ctx, name, greet).unwrap();
+            ctx.eval("greet('a') greet('b')").unwrap();
+            let old = ctx.set_app_data(Config {
+                greeting: "bye".into(),
+            });
+            assert_eq!(old.unwrap().greeting, "hello");
+            ctx.eval("greet('c')").unwrap();
+            ctx.remove_app_data::<Vec<String>>().unwrap()
+        });
+        assert_eq!(output, ["hello, a", "hello, b", "bye, c"]);
+        assert!(lua.enter(|ctx| ctx.app_data_mut::<Vec<String>>().is_none()));
+    }
+}
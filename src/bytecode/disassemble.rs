@@ -0,0 +1,298 @@
+//! Decoding a function's bytecode back into instructions with their operands spelled out, and
+//! listing it in the manner of `luac -l`.
+
+use std::fmt::{self, Write as _};
+
+use super::{is_constant, Instruction, OpCode, Prototype, MAX_RK_INDEX};
+use crate::stdlib::{inspect, InspectOptions};
+use crate::{LuaString, Value};
+
+/// What an operand of an instruction refers to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operand<'gc> {
+    /// The register `R[x]`.
+    Register(u32),
+    /// The constant `K[x]` and its value.
+    Constant(u32, Value<'gc>),
+    /// The upvalue `UpValue[x]` and its name, unless debug information was stripped.
+    Upvalue(u32, Option<LuaString<'gc>>),
+    /// The index of the instruction a jump goes to.
+    Jump(usize),
+    /// The nested function `KPROTO[x]`.
+    Function(u32),
+    /// A count, a flag or an integer.
+    Immediate(i64),
+}
+
+/// An instruction of a [`Prototype`], decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoded<'gc> {
+    /// The index of the instruction in the function's code.
+    pub pc: usize,
+    /// The source line, if known.
+    pub line: Option<u32>,
+    pub instruction: Instruction,
+    /// `None` for an invalid instruction, which has no operands.
+    pub op: Option<OpCode>,
+    /// The operands the opcode uses, in the order `A`, `B`, `C` or `A`, `Bx`.
+    pub operands: Vec<Operand<'gc>>,
+}
+
+/// The decoded instructions of a function, from [`Prototype::instructions`].
+pub struct Instructions<'a, 'gc> {
+    proto: &'a Prototype<'gc>,
+    pc: usize,
+}
+
+impl<'gc> Prototype<'gc> {
+    /// Decodes each instruction of the function, leaving out those of nested functions.
+    pub fn instructions(&self) -> Instructions<'_, 'gc> {
+        Instructions { proto: self, pc: 0 }
+    }
+
+    /// Decodes the instruction at `pc`.
+    pub fn decode(&self, pc: usize) -> Option<Decoded<'gc>> {
+        let instruction = *self.code.get(pc)?;
+        let op = instruction.opcode();
+        let operands = match op {
+            Some(op) => self.operands(pc, op, instruction),
+            None => Vec::new(),
+        };
+        Some(Decoded {
+            pc,
+            line: self.line_at(pc),
+            instruction,
+            op,
+            operands,
+        })
+    }
+
+    fn operands(&self, pc: usize, op: OpCode, i: Instruction) -> Vec<Operand<'gc>> {
+        use Operand::{Immediate, Register};
+
+        let (a, b, c) = (i.a(), i.b(), i.c());
+        let constant = |k: u32| {
+            let value = self
+                .constants
+                .get(k as usize)
+                .copied()
+                .unwrap_or(Value::Nil);
+            Operand::Constant(k, value)
+        };
+        let rk = |x: u32| {
+            if is_constant(x) {
+                constant(x & MAX_RK_INDEX)
+            } else {
+                Register(x)
+            }
+        };
+        let upvalue = |u: u32| Operand::Upvalue(u, self.upvalue_names.get(u as usize).copied());
+        let jump = Operand::Jump((pc as i64 + 1 + i.sbx() as i64) as usize);
+        let int = |n: u32| Immediate(n as i64);
+        match op {
+            OpCode::Move | OpCode::Unm | OpCode::BNot | OpCode::Not | OpCode::Len => {
+                vec![Register(a), Register(b)]
+            }
+            OpCode::LoadK => vec![Register(a), constant(i.bx())],
+            OpCode::LoadI => vec![Register(a), Immediate(i.sbx() as i64)],
+            OpCode::LoadBool
+            | OpCode::NewTable
+            | OpCode::Call
+            | OpCode::TailCall
+            | OpCode::SetList => vec![Register(a), int(b), int(c)],
+            OpCode::LoadNil | OpCode::Return | OpCode::VarArg => vec![Register(a), int(b)],
+            OpCode::GetTabUp => vec![Register(a), upvalue(b), rk(c)],
+            OpCode::SetTabUp => vec![upvalue(a), rk(b), rk(c)],
+            OpCode::GetUpval | OpCode::SetUpval => vec![Register(a), upvalue(b)],
+            OpCode::GetTable | OpCode::Method => vec![Register(a), Register(b), rk(c)],
+            OpCode::SetTable => vec![Register(a), rk(b), rk(c)],
+            OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Mod
+            | OpCode::Pow
+            | OpCode::Div
+            | OpCode::IDiv
+            | OpCode::BAnd
+            | OpCode::BOr
+            | OpCode::BXor
+            | OpCode::Shl
+            | OpCode::Shr => vec![Register(a), rk(b), rk(c)],
+            OpCode::Concat => vec![Register(a), Register(b), Register(c)],
+            OpCode::Jmp => vec![int(a), jump],
+            OpCode::Eq | OpCode::Lt | OpCode::Le => vec![int(a), rk(b), rk(c)],
+            OpCode::Test | OpCode::TForCall => vec![Register(a), int(c)],
+            OpCode::TestSet => vec![Register(a), Register(b), int(c)],
+            OpCode::ForLoop | OpCode::ForPrep | OpCode::TForLoop => vec![Register(a), jump],
+            OpCode::Closure => vec![Register(a), Operand::Function(i.bx())],
+            OpCode::Tbc => vec![Register(a), constant(i.bx())],
+            OpCode::ExtraArg => vec![int(i.bx())],
+        }
+    }
+}
+
+impl<'a, 'gc> Iterator for Instructions<'a, 'gc> {
+    type Item = Decoded<'gc>;
+
+    fn next(&mut self) -> Option<Decoded<'gc>> {
+        let decoded = self.proto.decode(self.pc)?;
+        self.pc += 1;
+        Some(decoded)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.proto.code.len().saturating_sub(self.pc);
+        (left, Some(left))
+    }
+}
+
+impl ExactSizeIterator for Instructions<'_, '_> {}
+
+/// Lists the bytecode of `proto` and of the functions nested in it, in the manner of `luac -l`.
+///
+/// Each function starts with a header giving where it was defined and its sizes, followed by a
+/// line per instruction with its index from 1, source line, opcode and operands. Constants in
+/// operands are written `-1 - x`, and a comment after the operands gives the values of constants,
+/// the names of upvalues and where jumps go.
+pub fn disassemble(proto: &Prototype<'_>) -> String {
+    let mut out = String::new();
+    // Writing to a string can't fail.
+    let _ = list(&mut out, proto, true);
+    out
+}
+
+fn list(out: &mut String, proto: &Prototype<'_>, main: bool) -> fmt::Result {
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    writeln!(
+        out,
+        "\n{} <{}:{},{}> ({} instruction{})",
+        if main { "main" } else { "function" },
+        String::from_utf8_lossy(proto.chunk_name.as_bytes()),
+        proto.line_defined,
+        proto.last_line_defined,
+        proto.code.len(),
+        plural(proto.code.len()),
+    )?;
+    let counts = [
+        (proto.max_stack as usize, "slot"),
+        (proto.upvalues.len(), "upvalue"),
+        (proto.local_vars.len(), "local"),
+        (proto.constants.len(), "constant"),
+        (proto.prototypes.len(), "function"),
+    ];
+    write!(
+        out,
+        "{}{} param{}",
+        proto.num_params,
+        if proto.is_vararg { "+" } else { "" },
+        plural(proto.num_params as usize),
+    )?;
+    for (n, what) in counts {
+        write!(out, ", {n} {what}{}", plural(n))?;
+    }
+    out.push('\n');
+
+    let options = InspectOptions::default();
+    for decoded in proto.instructions() {
+        let line = match decoded.line {
+            Some(line) => line.to_string(),
+            None => "-".to_owned(),
+        };
+        write!(out, "\t{}\t[{line}]\t", decoded.pc + 1)?;
+        let Some(op) = decoded.op else {
+            writeln!(out, "<invalid {:#010x}>", decoded.instruction.0)?;
+            continue;
+        };
+        write!(out, "{:<9}\t", format!("{op:?}").to_uppercase())?;
+
+        let mut comment = Vec::new();
+        for (n, operand) in decoded.operands.iter().enumerate() {
+            if n > 0 {
+                out.push(' ');
+            }
+            match *operand {
+                Operand::Register(x) | Operand::Function(x) => write!(out, "{x}")?,
+                Operand::Constant(k, value) => {
+                    write!(out, "{}", -1 - k as i64)?;
+                    comment.push(inspect(value, &options));
+                }
+                Operand::Upvalue(u, name) => {
+                    write!(out, "{u}")?;
+                    comment.push(match name {
+                        Some(name) => String::from_utf8_lossy(name.as_bytes()).into_owned(),
+                        None => "-".to_owned(),
+                    });
+                }
+                Operand::Jump(target) => {
+                    write!(out, "{}", target as i64 - decoded.pc as i64 - 1)?;
+                    comment.push(format!("to {}", target + 1));
+                }
+                Operand::Immediate(n) => write!(out, "{n}")?,
+            }
+        }
+        if !comment.is_empty() {
+            write!(out, "\t; {}", comment.join(" "))?;
+        }
+        out.push('\n');
+    }
+    for nested in proto.prototypes.iter() {
+        list(out, nested, false)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Function, Lua};
+
+    #[test]
+    fn lists_functions() {
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let source = "local x = 1\nfunction f() return x + 2 end\nwhile x do x = nil end";
+            let Function::Closure(main) = ctx.load("=chunk", source).unwrap() else {
+                unreachable!();
+            };
+            let proto = main.proto();
+
+            let first = proto.instructions().next().unwrap();
+            assert_eq!(first.op, Some(OpCode::LoadI));
+            assert_eq!(first.line, Some(1));
+            assert_eq!(
+                first.operands,
+                [Operand::Register(0), Operand::Immediate(1)]
+            );
+            let set = proto
+                .instructions()
+                .find(|d| d.op == Some(OpCode::SetTabUp))
+                .unwrap();
+            assert!(matches!(
+                set.operands[..],
+                [Operand::Upvalue(0, Some(env)), Operand::Constant(0, Value::String(name)), _]
+                    if env.as_bytes() == b"_ENV" && name.as_bytes() == b"f"
+            ));
+            let jumps: Vec<_> = proto
+                .instructions()
+                .filter_map(|d| match d.operands.last() {
+                    Some(&Operand::Jump(target)) => Some((d.pc, target)),
+                    _ => None,
+                })
+                .collect();
+            assert!(jumps.iter().any(|&(pc, target)| target < pc), "{jumps:?}");
+
+            let listed = disassemble(&proto);
+            assert!(listed.starts_with("\nmain <chunk:0,0> ("), "{listed}");
+            assert!(
+                listed.contains("SETTABUP \t0 -1 1\t; _ENV \"f\""),
+                "{listed}"
+            );
+            assert!(listed.contains("\nfunction <chunk:2,2> (3 instructions)\n"));
+            assert!(
+                listed.contains("0 params, 2 slots, 1 upvalue, 0 locals, 1 constant, 0 functions")
+            );
+            assert!(listed.contains("GETUPVAL \t1 0\t; x"), "{listed}");
+            assert!(listed.contains("ADD      \t0 1 -1\t; 2"), "{listed}");
+        });
+    }
+}
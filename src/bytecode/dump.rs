@@ -0,0 +1,545 @@
+//! The binary chunk format: prototypes serialized so they can be loaded without compiling.
+//!
+//! A chunk starts with a header: [`SIGNATURE`], a format version byte, a few bytes that get mangled
+//! by newline conversions, the sizes of instructions, integers and floats, and then a sample integer
+//! and float. Numbers are written in the byte order of the machine that dumped them, and the samples
+//! let a loader tell it was written by a machine that encodes them the same way.
+//!
+//! The main function follows. Each function is laid out as its header fields, code, constants,
+//! upvalues, nested functions, line information and the names of its locals and upvalues, with
+//! counts written before every list.
+//!
+//! Strings, both constants and names, are written once per chunk. The first time a string comes up
+//! it is written in full after a reference of 0, and after that only as its reference: `n` for the
+//! `n`th string written. Loading gives every function the same string for each of them.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{InlineCache, Instruction, LocalVar, Prototype, UpvalueDesc};
+use crate::mem::{Gc, Mutation};
+use crate::{LuaString, Value};
+
+/// The bytes every binary chunk starts with, which can't start Lua source.
+pub const SIGNATURE: &[u8] = b"\x1bLua";
+
+/// The version of the layout written by [`dump`]. Chunks of any other version are rejected.
+pub const FORMAT_VERSION: u8 = 6;
+
+/// Catches chunks that went through a text-mode conversion of line endings.
+const CHECK_DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+const CHECK_INTEGER: i64 = 0x5678;
+const CHECK_NUMBER: f64 = 370.5;
+
+/// How deeply functions may be nested in a loaded chunk, which bounds the recursion of the loader.
+const MAX_DEPTH: usize = 200;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_INTEGER: u8 = 3;
+const TAG_NUMBER: u8 = 4;
+const TAG_STRING: u8 = 5;
+
+/// Why a binary chunk couldn't be loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UndumpError {
+    NotBinary,
+    VersionMismatch,
+    /// The bytes meant to catch newline conversions have been changed.
+    Corrupted,
+    /// The chunk was written by a machine with different number sizes or byte order; says which.
+    FormatMismatch(&'static str),
+    Truncated,
+    /// The chunk is complete but holds something that can't be part of a function.
+    Malformed(&'static str),
+}
+
+impl fmt::Display for UndumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UndumpError::NotBinary => f.write_str("not a binary chunk"),
+            UndumpError::VersionMismatch => f.write_str("format version mismatch"),
+            UndumpError::Corrupted => f.write_str("corrupted chunk"),
+            UndumpError::FormatMismatch(what) => write!(f, "{what} mismatch"),
+            UndumpError::Truncated => f.write_str("truncated chunk"),
+            UndumpError::Malformed(what) => f.write_str(what),
+        }
+    }
+}
+
+impl std::error::Error for UndumpError {}
+
+/// Serializes `proto` and everything nested in it. With `strip` set, the chunk name and debug
+/// information are left out: line numbers and the names of locals and upvalues.
+pub fn dump(proto: &Prototype<'_>, strip: bool) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+    out.push(FORMAT_VERSION);
+    out.extend_from_slice(CHECK_DATA);
+    out.extend_from_slice(&[
+        std::mem::size_of::<Instruction>() as u8,
+        std::mem::size_of::<i64>() as u8,
+        std::mem::size_of::<f64>() as u8,
+    ]);
+    out.extend_from_slice(&CHECK_INTEGER.to_ne_bytes());
+    out.extend_from_slice(&CHECK_NUMBER.to_ne_bytes());
+    let name = if strip {
+        &[][..]
+    } else {
+        proto.chunk_name.as_bytes()
+    };
+    write_bytes(&mut out, name);
+    dump_function(&mut out, &mut HashMap::new(), proto, strip);
+    out
+}
+
+fn write_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_ne_bytes());
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) {
+    write_u32(out, u32::try_from(len).expect("too many items to dump"));
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u64).to_ne_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Writes a reference to `s`, followed by `s` itself the first time it is written. `strings` maps
+/// the strings written so far to their references.
+fn write_string(out: &mut Vec<u8>, strings: &mut HashMap<Vec<u8>, u32>, s: LuaString<'_>) {
+    if let Some(&n) = strings.get(s.as_bytes()) {
+        write_u32(out, n);
+        return;
+    }
+    let n = u32::try_from(strings.len() + 1).expect("too many strings to dump");
+    strings.insert(s.as_bytes().to_vec(), n);
+    write_u32(out, 0);
+    write_bytes(out, s.as_bytes());
+}
+
+fn dump_function(
+    out: &mut Vec<u8>,
+    strings: &mut HashMap<Vec<u8>, u32>,
+    proto: &Prototype<'_>,
+    strip: bool,
+) {
+    write_u32(out, proto.line_defined);
+    write_u32(out, proto.last_line_defined);
+    out.extend_from_slice(&[proto.num_params, proto.is_vararg as u8, proto.max_stack]);
+
+    write_len(out, proto.code.len());
+    for i in proto.code.iter() {
+        write_u32(out, i.0);
+    }
+
+    write_len(out, proto.constants.len());
+    for &k in proto.constants.iter() {
+        match k {
+            Value::Nil => out.push(TAG_NIL),
+            Value::Boolean(false) => out.push(TAG_FALSE),
+            Value::Boolean(true) => out.push(TAG_TRUE),
+            Value::Integer(i) => {
+                out.push(TAG_INTEGER);
+                out.extend_from_slice(&i.to_ne_bytes());
+            }
+            Value::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_ne_bytes());
+            }
+            Value::String(s) => {
+                out.push(TAG_STRING);
+                write_string(out, strings, s);
+            }
+            _ => unreachable!("constants are never {}s", k.type_name()),
+        }
+    }
+
+    write_len(out, proto.upvalues.len());
+    for desc in proto.upvalues.iter() {
+        match *desc {
+            UpvalueDesc::Local(r) => out.extend_from_slice(&[0, r]),
+            UpvalueDesc::Outer(u) => out.extend_from_slice(&[1, u]),
+        }
+    }
+
+    write_len(out, proto.prototypes.len());
+    for p in proto.prototypes.iter() {
+        dump_function(out, strings, p, strip);
+    }
+
+    let lines: &[u32] = if strip { &[] } else { &proto.line_info };
+    write_len(out, lines.len());
+    for &line in lines {
+        write_u32(out, line);
+    }
+    let columns: &[u32] = if strip { &[] } else { &proto.column_info };
+    write_len(out, columns.len());
+    for &column in columns {
+        write_u32(out, column);
+    }
+
+    let local_vars: &[LocalVar<'_>] = if strip { &[] } else { &proto.local_vars };
+    write_len(out, local_vars.len());
+    for var in local_vars {
+        write_string(out, strings, var.name);
+        write_u32(out, var.start_pc);
+        write_u32(out, var.end_pc);
+    }
+
+    let upvalue_names: &[LuaString<'_>] = if strip { &[] } else { &proto.upvalue_names };
+    write_len(out, upvalue_names.len());
+    for &name in upvalue_names {
+        write_string(out, strings, name);
+    }
+}
+
+/// Loads a chunk written by [`dump`].
+///
+/// As in the reference implementation, the bytecode itself isn't verified: a crafted chunk can make
+/// the interpreter panic, although never access memory it shouldn't, unless it passes
+/// [`verify`](super::verify) first.
+pub fn undump<'gc>(
+    mc: &Mutation<'gc>,
+    chunk: &[u8],
+) -> Result<Gc<'gc, Prototype<'gc>>, UndumpError> {
+    let rest = chunk
+        .strip_prefix(SIGNATURE)
+        .ok_or(UndumpError::NotBinary)?;
+    let mut reader = Reader {
+        mc,
+        bytes: rest,
+        strings: Vec::new(),
+    };
+    reader.header()?;
+    let name = match reader.bytes()? {
+        [] => b"?".as_slice(),
+        name => name,
+    };
+    let chunk_name = LuaString::new(mc, name);
+    let proto = reader.function(chunk_name, 0)?;
+    if !reader.bytes.is_empty() {
+        return Err(UndumpError::Malformed("trailing bytes after chunk"));
+    }
+    Ok(proto)
+}
+
+struct Reader<'a, 'gc> {
+    mc: &'a Mutation<'gc>,
+    bytes: &'a [u8],
+    /// The strings read so far, in order, for later references to them.
+    strings: Vec<LuaString<'gc>>,
+}
+
+impl<'a, 'gc> Reader<'a, 'gc> {
+    /// Checks everything in the header after the signature.
+    fn header(&mut self) -> Result<(), UndumpError> {
+        if self.u8()? != FORMAT_VERSION {
+            return Err(UndumpError::VersionMismatch);
+        }
+        if self.take::<6>()? != CHECK_DATA {
+            return Err(UndumpError::Corrupted);
+        }
+        let sizes = [
+            ("instruction size", std::mem::size_of::<Instruction>()),
+            ("integer size", std::mem::size_of::<i64>()),
+            ("float size", std::mem::size_of::<f64>()),
+        ];
+        for (what, size) in sizes {
+            if self.u8()? as usize != size {
+                return Err(UndumpError::FormatMismatch(what));
+            }
+        }
+        if i64::from_ne_bytes(self.take()?) != CHECK_INTEGER {
+            return Err(UndumpError::FormatMismatch("integer format"));
+        }
+        if f64::from_ne_bytes(self.take()?) != CHECK_NUMBER {
+            return Err(UndumpError::FormatMismatch("float format"));
+        }
+        Ok(())
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], UndumpError> {
+        if self.bytes.len() < N {
+            return Err(UndumpError::Truncated);
+        }
+        let (head, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, UndumpError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, UndumpError> {
+        Ok(u32::from_ne_bytes(self.take()?))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], UndumpError> {
+        let len = u64::from_ne_bytes(self.take()?);
+        if len > self.bytes.len() as u64 {
+            return Err(UndumpError::Truncated);
+        }
+        let (head, rest) = self.bytes.split_at(len as usize);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn string(&mut self) -> Result<LuaString<'gc>, UndumpError> {
+        match self.u32()? {
+            0 => {
+                let s = LuaString::new(self.mc, self.bytes()?);
+                self.strings.push(s);
+                Ok(s)
+            }
+            n => self
+                .strings
+                .get(n as usize - 1)
+                .copied()
+                .ok_or(UndumpError::Malformed("invalid string reference")),
+        }
+    }
+
+    /// Reads a list count. Every item takes at least a byte, so a count larger than what is left
+    /// can only mean the chunk was cut short, and checking it keeps bogus counts from allocating.
+    fn len(&mut self) -> Result<usize, UndumpError> {
+        let len = self.u32()? as usize;
+        if len > self.bytes.len() {
+            return Err(UndumpError::Truncated);
+        }
+        Ok(len)
+    }
+
+    fn function(
+        &mut self,
+        chunk_name: LuaString<'gc>,
+        depth: usize,
+    ) -> Result<Gc<'gc, Prototype<'gc>>, UndumpError> {
+        if depth > MAX_DEPTH {
+            return Err(UndumpError::Malformed("functions nested too deeply"));
+        }
+        let line_defined = self.u32()?;
+        let last_line_defined = self.u32()?;
+        let [num_params, is_vararg, max_stack] = self.take()?;
+
+        let count = self.len()?;
+        let mut code = Vec::with_capacity(count);
+        for _ in 0..count {
+            let i = Instruction(self.u32()?);
+            if i.opcode().is_none() {
+                return Err(UndumpError::Malformed("invalid instruction"));
+            }
+            code.push(i);
+        }
+
+        let count = self.len()?;
+        let mut constants = Vec::with_capacity(count);
+        for _ in 0..count {
+            constants.push(match self.u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Boolean(false),
+                TAG_TRUE => Value::Boolean(true),
+                TAG_INTEGER => Value::Integer(i64::from_ne_bytes(self.take()?)),
+                TAG_NUMBER => Value::Number(f64::from_ne_bytes(self.take()?)),
+                TAG_STRING => Value::String(self.string()?),
+                _ => return Err(UndumpError::Malformed("invalid constant")),
+            });
+        }
+
+        let count = self.len()?;
+        let mut upvalues = Vec::with_capacity(count);
+        for _ in 0..count {
+            upvalues.push(match self.take()? {
+                [0, r] => UpvalueDesc::Local(r),
+                [1, u] => UpvalueDesc::Outer(u),
+                _ => return Err(UndumpError::Malformed("invalid upvalue")),
+            });
+        }
+
+        let count = self.len()?;
+        let mut prototypes = Vec::with_capacity(count);
+        for _ in 0..count {
+            prototypes.push(self.function(chunk_name, depth + 1)?);
+        }
+
+        let count = self.len()?;
+        let mut line_info = Vec::with_capacity(count);
+        for _ in 0..count {
+            line_info.push(self.u32()?);
+        }
+        let count = self.len()?;
+        let mut column_info = Vec::with_capacity(count);
+        for _ in 0..count {
+            column_info.push(self.u32()?);
+        }
+
+        let count = self.len()?;
+        let mut local_vars = Vec::with_capacity(count);
+        for _ in 0..count {
+            local_vars.push(LocalVar {
+                name: self.string()?,
+                start_pc: self.u32()?,
+                end_pc: self.u32()?,
+            });
+        }
+
+        let count = self.len()?;
+        let mut upvalue_names = Vec::with_capacity(count);
+        for _ in 0..count {
+            upvalue_names.push(self.string()?);
+        }
+
+        Ok(Gc::new(
+            self.mc,
+            Prototype {
+                chunk_name,
+                line_defined,
+                last_line_defined,
+                num_params,
+                is_vararg: is_vararg != 0,
+                max_stack,
+                cache: InlineCache::new(code.len()),
+                code: code.into(),
+                constants: constants.into(),
+                prototypes: prototypes.into(),
+                upvalues: upvalues.into(),
+                line_info: line_info.into(),
+                column_info: column_info.into(),
+                local_vars: local_vars.into(),
+                upvalue_names: upvalue_names.into(),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::mem::{Arena, Rootable};
+
+    struct Empty;
+
+    impl<'a> Rootable<'a> for Empty {
+        type Root = ();
+    }
+
+    #[test]
+    fn round_trips_prototypes() {
+        let arena = Arena::<Empty>::new(|_| ());
+        arena.mutate(|mc, _| {
+            let source =
+                "local x, s = 1.5, 'str'\nreturn function(a, ...) return x + a, s, nil, true end";
+            let proto = compile(mc, source.as_bytes(), "chunk").unwrap();
+            let loaded = undump(mc, &dump(&proto, false)).unwrap();
+            assert_eq!(dump(&loaded, false), dump(&proto, false));
+            assert_eq!(loaded.chunk_name.as_bytes(), b"chunk");
+            assert_eq!(
+                loaded.prototypes[0].line_info,
+                proto.prototypes[0].line_info
+            );
+
+            let stripped = undump(mc, &dump(&proto, true)).unwrap();
+            assert_eq!(stripped.chunk_name.as_bytes(), b"?");
+            assert!(stripped.prototypes[0].line_info.is_empty());
+            assert_eq!(
+                loaded.prototypes[0].column_info,
+                proto.prototypes[0].column_info
+            );
+            assert!(stripped.prototypes[0].column_info.is_empty());
+            assert!(stripped.prototypes[0].local_vars.is_empty());
+            assert_eq!(
+                loaded.prototypes[0].local_vars,
+                proto.prototypes[0].local_vars
+            );
+            assert_eq!(
+                loaded.prototypes[0].upvalue_names[..],
+                proto.prototypes[0].upvalue_names[..]
+            );
+            assert_eq!(stripped.prototypes[0].code, proto.prototypes[0].code);
+        });
+    }
+
+    #[test]
+    fn shares_strings() {
+        let arena = Arena::<Empty>::new(|_| ());
+        arena.mutate(|mc, _| {
+            let literal = "'a fairly long string literal'";
+            let source = format!(
+                "local s = {literal}\nreturn function() return {literal}, s end, \
+                 function() return function() return {literal}, s end end"
+            );
+            let proto = compile(mc, source.as_bytes(), "chunk").unwrap();
+            fn string<'gc>(p: &Prototype<'gc>) -> LuaString<'gc> {
+                match p.constants[..] {
+                    [Value::String(s), ..] => s,
+                    _ => panic!("expected a string constant"),
+                }
+            }
+            fn check(main: &Prototype<'_>) {
+                let first = string(main);
+                let nested = &main.prototypes[1].prototypes[0];
+                assert!(first.ptr_eq(string(&main.prototypes[0])));
+                assert!(first.ptr_eq(string(nested)));
+                assert!(main.local_vars[0].name.ptr_eq(nested.upvalue_names[0]));
+            }
+            check(&proto);
+
+            let chunk = dump(&proto, false);
+            check(&undump(mc, &chunk).unwrap());
+            let text = b"a fairly long string literal";
+            let found: Vec<_> = chunk
+                .windows(text.len())
+                .enumerate()
+                .filter(|&(_, w)| w == text)
+                .map(|(at, _)| at)
+                .collect();
+            assert_eq!(found.len(), 1);
+
+            // The reference comes before the length of the string.
+            let mut bad = chunk;
+            let at = found[0] - 8 - 4;
+            bad[at..at + 4].copy_from_slice(&99u32.to_ne_bytes());
+            assert_eq!(
+                undump(mc, &bad).unwrap_err(),
+                UndumpError::Malformed("invalid string reference")
+            );
+        });
+    }
+
+    #[test]
+    fn rejects_bad_chunks() {
+        let arena = Arena::<Empty>::new(|_| ());
+        arena.mutate(|mc, _| {
+            let proto = compile(mc, b"return 1", "chunk").unwrap();
+            let chunk = dump(&proto, false);
+            let err = |bytes: &[u8]| undump(mc, bytes).unwrap_err();
+            assert_eq!(err(b"return 1"), UndumpError::NotBinary);
+            assert_eq!(err(&chunk[..chunk.len() - 1]), UndumpError::Truncated);
+            let mut version = chunk.clone();
+            version[SIGNATURE.len()] += 1;
+            assert_eq!(err(&version), UndumpError::VersionMismatch);
+            let mut newlines = chunk.clone();
+            newlines.remove(SIGNATURE.len() + 3);
+            assert_eq!(err(&newlines), UndumpError::Corrupted);
+
+            // The sample integer comes after the version, the check bytes and three sizes.
+            let int_at = SIGNATURE.len() + 1 + CHECK_DATA.len() + 3;
+            let mut sizes = chunk.clone();
+            sizes[int_at - 2] = 4;
+            assert_eq!(err(&sizes), UndumpError::FormatMismatch("integer size"));
+            let mut swapped = chunk.clone();
+            swapped[int_at..int_at + 8].reverse();
+            assert_eq!(err(&swapped).to_string(), "integer format mismatch");
+
+            let mut trailing = chunk;
+            trailing.push(0);
+            assert_eq!(
+                err(&trailing),
+                UndumpError::Malformed("trailing bytes after chunk")
+            );
+        });
+    }
+}
@@ -0,0 +1,180 @@
+//! The register-based instruction set executed by the interpreter.
+//!
+//! Instructions are 32 bits wide and follow the classic PUC-Lua layout:
+//!
+//! ```text
+//!  31       23       14        6      0
+//!  |   B(9)  |  C(9)   |  A(8)  | op(6)|   iABC
+//!  |      Bx(18)       |  A(8)  | op(6)|   iABx / iAsBx
+//! ```
+//!
+//! Operands written `RK(x)` refer to the constant `K[x - 256]` when `x >= 256` and to register `R[x]`
+//! otherwise.
+
+mod disassemble;
+mod dump;
+mod names;
+mod opcode;
+mod prototype;
+mod verify;
+
+pub use self::disassemble::{disassemble, Decoded, Instructions, Operand};
+pub use self::dump::{dump, undump, UndumpError, FORMAT_VERSION, SIGNATURE};
+pub use self::opcode::{OpCode, OpMode};
+pub use self::prototype::{InlineCache, LocalVar, Prototype, UpvalueDesc};
+pub use self::verify::{verify, VerifyError};
+
+use std::fmt;
+
+pub const SIZE_OP: u32 = 6;
+pub const SIZE_A: u32 = 8;
+pub const SIZE_B: u32 = 9;
+pub const SIZE_C: u32 = 9;
+pub const SIZE_BX: u32 = SIZE_B + SIZE_C;
+
+const POS_A: u32 = SIZE_OP;
+const POS_C: u32 = POS_A + SIZE_A;
+const POS_B: u32 = POS_C + SIZE_C;
+const POS_BX: u32 = POS_C;
+
+pub const MAX_A: u32 = (1 << SIZE_A) - 1;
+pub const MAX_B: u32 = (1 << SIZE_B) - 1;
+pub const MAX_C: u32 = (1 << SIZE_C) - 1;
+pub const MAX_BX: u32 = (1 << SIZE_BX) - 1;
+/// The bias applied to signed `sBx` operands.
+pub const MAX_SBX: i32 = (MAX_BX >> 1) as i32;
+
+/// Set on a `B`/`C` operand to make it refer to a constant.
+pub const RK_CONSTANT: u32 = 1 << (SIZE_B - 1);
+/// The largest constant index that fits in an `RK` operand.
+pub const MAX_RK_INDEX: u32 = RK_CONSTANT - 1;
+
+/// The number of list items accumulated before a `SETLIST` is emitted by table constructors.
+pub const FIELDS_PER_FLUSH: u32 = 50;
+
+/// A single encoded instruction.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Instruction(pub u32);
+
+impl Instruction {
+    #[inline]
+    pub fn abc(op: OpCode, a: u32, b: u32, c: u32) -> Instruction {
+        debug_assert!(a <= MAX_A && b <= MAX_B && c <= MAX_C);
+        Instruction(op as u32 | (a << POS_A) | (b << POS_B) | (c << POS_C))
+    }
+
+    #[inline]
+    pub fn abx(op: OpCode, a: u32, bx: u32) -> Instruction {
+        debug_assert!(a <= MAX_A && bx <= MAX_BX);
+        Instruction(op as u32 | (a << POS_A) | (bx << POS_BX))
+    }
+
+    #[inline]
+    pub fn asbx(op: OpCode, a: u32, sbx: i32) -> Instruction {
+        debug_assert!((-MAX_SBX..=MAX_SBX + 1).contains(&sbx));
+        Instruction::abx(op, a, (sbx + MAX_SBX) as u32)
+    }
+
+    /// Decodes the opcode, or returns `None` for an invalid instruction.
+    #[inline]
+    pub fn opcode(self) -> Option<OpCode> {
+        OpCode::from_u8((self.0 & ((1 << SIZE_OP) - 1)) as u8)
+    }
+
+    #[inline]
+    pub fn a(self) -> u32 {
+        (self.0 >> POS_A) & MAX_A
+    }
+
+    #[inline]
+    pub fn b(self) -> u32 {
+        (self.0 >> POS_B) & MAX_B
+    }
+
+    #[inline]
+    pub fn c(self) -> u32 {
+        (self.0 >> POS_C) & MAX_C
+    }
+
+    #[inline]
+    pub fn bx(self) -> u32 {
+        (self.0 >> POS_BX) & MAX_BX
+    }
+
+    #[inline]
+    pub fn sbx(self) -> i32 {
+        self.bx() as i32 - MAX_SBX
+    }
+
+    #[inline]
+    pub fn set_a(&mut self, a: u32) {
+        debug_assert!(a <= MAX_A);
+        self.0 = (self.0 & !(MAX_A << POS_A)) | (a << POS_A);
+    }
+
+    #[inline]
+    pub fn set_b(&mut self, b: u32) {
+        debug_assert!(b <= MAX_B);
+        self.0 = (self.0 & !(MAX_B << POS_B)) | (b << POS_B);
+    }
+
+    #[inline]
+    pub fn set_c(&mut self, c: u32) {
+        debug_assert!(c <= MAX_C);
+        self.0 = (self.0 & !(MAX_C << POS_C)) | (c << POS_C);
+    }
+
+    #[inline]
+    pub fn set_sbx(&mut self, sbx: i32) {
+        let bx = (sbx + MAX_SBX) as u32;
+        debug_assert!(bx <= MAX_BX);
+        self.0 = (self.0 & !(MAX_BX << POS_BX)) | (bx << POS_BX);
+    }
+}
+
+/// Returns true if an `RK` operand refers to a constant.
+#[inline]
+pub fn is_constant(rk: u32) -> bool {
+    rk & RK_CONSTANT != 0
+}
+
+/// Encodes a constant index as an `RK` operand.
+#[inline]
+pub fn rk_constant(index: u32) -> u32 {
+    debug_assert!(index <= MAX_RK_INDEX);
+    index | RK_CONSTANT
+}
+
+impl fmt::Debug for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(op) = self.opcode() else {
+            return write!(f, "<invalid {:#010x}>", self.0);
+        };
+        match op.mode() {
+            OpMode::ABC => write!(f, "{:?} {} {} {}", op, self.a(), self.b(), self.c()),
+            OpMode::ABx => write!(f, "{:?} {} {}", op, self.a(), self.bx()),
+            OpMode::AsBx => write!(f, "{:?} {} {}", op, self.a(), self.sbx()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operand_round_trip() {
+        let mut i = Instruction::abc(OpCode::Add, 3, rk_constant(7), 255);
+        assert_eq!(i.opcode(), Some(OpCode::Add));
+        assert_eq!((i.a(), i.b(), i.c()), (3, RK_CONSTANT | 7, 255));
+        assert!(is_constant(i.b()) && !is_constant(i.c()));
+        i.set_c(12);
+        assert_eq!(i.c(), 12);
+
+        let mut j = Instruction::asbx(OpCode::Jmp, 0, -5);
+        assert_eq!(j.sbx(), -5);
+        j.set_sbx(MAX_SBX);
+        assert_eq!(j.sbx(), MAX_SBX);
+        assert_eq!(Instruction::abx(OpCode::LoadK, 1, MAX_BX).bx(), MAX_BX);
+    }
+}
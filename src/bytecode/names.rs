@@ -0,0 +1,132 @@
+//! Works out which variable a register holds at some point in a function, so that runtime errors
+//! can say `attempt to call a nil value (global 'f')` rather than leave the reader guessing.
+//!
+//! This follows the reference implementation: a register either holds a local in scope, or else
+//! the last instruction that set it tells where its value came from. Instructions inside a
+//! conditional jump's reach are ignored, since they might not have run.
+
+use super::{is_constant, Instruction, OpCode, Prototype, RK_CONSTANT};
+use crate::{LuaString, Value};
+
+impl<'gc> Prototype<'gc> {
+    /// The name of the local in register `reg` at `pc`, if one is in scope there.
+    pub fn local_name(&self, reg: u32, pc: usize) -> Option<LuaString<'gc>> {
+        let pc = pc as u32;
+        self.local_vars
+            .iter()
+            .take_while(|var| var.start_pc <= pc)
+            .filter(|var| pc < var.end_pc)
+            .nth(reg as usize)
+            .map(|var| var.name)
+    }
+
+    /// Describes the value register `reg` holds when the instruction at `pc` runs, as the kind of
+    /// variable it came from (`"local"`, `"global"`, `"field"`, `"method"`, `"upvalue"` or
+    /// `"constant"`) and its name.
+    pub fn register_name(&self, pc: usize, reg: u32) -> Option<(&'static str, LuaString<'gc>)> {
+        if let Some(name) = self.local_name(reg, pc) {
+            return Some(("local", name));
+        }
+        let set_at = self.find_set_register(pc, reg)?;
+        let i = self.code[set_at];
+        match i.opcode()? {
+            // Only moves from a lower register come from a variable; the others are temporaries.
+            OpCode::Move if i.b() < i.a() => self.register_name(set_at, i.b()),
+            OpCode::GetTabUp => {
+                let table = self.upvalue_names.get(i.b() as usize).copied();
+                Some((self.field_kind(table), self.key_name(set_at, i.c())?))
+            }
+            OpCode::GetTable => {
+                let table = self.local_name(i.b(), set_at);
+                Some((self.field_kind(table), self.key_name(set_at, i.c())?))
+            }
+            OpCode::GetUpval => Some(("upvalue", *self.upvalue_names.get(i.b() as usize)?)),
+            OpCode::LoadK => match self.constants[i.bx() as usize] {
+                Value::String(s) => Some(("constant", s)),
+                _ => None,
+            },
+            OpCode::Method if reg == i.a() => Some(("method", self.key_name(set_at, i.c())?)),
+            // The object a method is called on is copied above the method.
+            OpCode::Method => self.register_name(set_at, i.b()),
+            _ => None,
+        }
+    }
+
+    /// Describes an `RK` operand of the instruction at `pc`. Of the constants, only strings have
+    /// a name to give.
+    pub fn rk_name(&self, pc: usize, rk: u32) -> Option<(&'static str, LuaString<'gc>)> {
+        if is_constant(rk) {
+            match self.constants[(rk & !RK_CONSTANT) as usize] {
+                Value::String(s) => Some(("constant", s)),
+                _ => None,
+            }
+        } else {
+            self.register_name(pc, rk)
+        }
+    }
+
+    /// Fields of `_ENV` are globals.
+    fn field_kind(&self, table: Option<LuaString<'gc>>) -> &'static str {
+        match table {
+            Some(name) if name.as_bytes() == b"_ENV" => "global",
+            _ => "field",
+        }
+    }
+
+    /// The name of the key of a table access, if it is a string constant.
+    fn key_name(&self, pc: usize, rk: u32) -> Option<LuaString<'gc>> {
+        match self.rk_name(pc, rk) {
+            Some(("constant", name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Finds the last instruction before `pc` that sets `reg`, unless it is one a jump before `pc`
+    /// might have skipped.
+    fn find_set_register(&self, pc: usize, reg: u32) -> Option<usize> {
+        let mut set_at = None;
+        // Instructions before this one are not skipped by any jump seen so far.
+        let mut jump_target = 0;
+        for (at, &i) in self.code[..pc].iter().enumerate() {
+            let Some(op) = i.opcode() else {
+                continue;
+            };
+            if op == OpCode::Jmp {
+                let dest = (at as i64 + 1 + i.sbx() as i64) as usize;
+                if at < dest && dest <= pc {
+                    jump_target = jump_target.max(dest);
+                }
+                continue;
+            }
+            if sets_register(op, i, reg) {
+                set_at = if at < jump_target { None } else { Some(at) };
+            }
+        }
+        set_at
+    }
+}
+
+/// Whether an instruction may change `reg`.
+fn sets_register(op: OpCode, i: Instruction, reg: u32) -> bool {
+    let a = i.a();
+    match op {
+        OpCode::LoadNil => a <= reg && reg <= a + i.b(),
+        OpCode::Method => reg == a || reg == a + 1,
+        OpCode::Call | OpCode::TailCall | OpCode::VarArg => reg >= a,
+        OpCode::TForCall => reg >= a + 4,
+        OpCode::ForPrep | OpCode::ForLoop => a <= reg && reg <= a + 3,
+        OpCode::SetTabUp
+        | OpCode::SetUpval
+        | OpCode::SetTable
+        | OpCode::Jmp
+        | OpCode::Eq
+        | OpCode::Lt
+        | OpCode::Le
+        | OpCode::Test
+        | OpCode::Return
+        | OpCode::SetList
+        | OpCode::Tbc
+        | OpCode::ExtraArg => false,
+        _ => reg == a,
+    }
+}
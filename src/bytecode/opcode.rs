@@ -0,0 +1,135 @@
+/// How the operands of an instruction are laid out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpMode {
+    ABC,
+    ABx,
+    AsBx,
+}
+
+macro_rules! opcodes {
+    ($($(#[doc = $doc:literal])* $name:ident = $mode:ident,)*) => {
+        /// Instruction opcodes. `R[x]` is a register, `K[x]` a constant and `RK(x)` either of them.
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($(#[doc = $doc])* $name,)*
+        }
+
+        impl OpCode {
+            const ALL: &'static [OpCode] = &[$(OpCode::$name,)*];
+
+            #[inline]
+            pub fn from_u8(op: u8) -> Option<OpCode> {
+                OpCode::ALL.get(op as usize).copied()
+            }
+
+            pub fn mode(self) -> OpMode {
+                match self {
+                    $(OpCode::$name => OpMode::$mode,)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    /// `R[A] := R[B]`
+    Move = ABC,
+    /// `R[A] := K[Bx]`
+    LoadK = ABx,
+    /// `R[A] := sBx` as an integer.
+    LoadI = AsBx,
+    /// `R[A] := (B != 0)`; if `C != 0` skip the next instruction.
+    LoadBool = ABC,
+    /// `R[A], ..., R[A+B] := nil`
+    LoadNil = ABC,
+    /// `R[A] := UpValue[B][RK(C)]`, how globals are read through `_ENV`.
+    GetTabUp = ABC,
+    /// `UpValue[A][RK(B)] := RK(C)`
+    SetTabUp = ABC,
+    /// `R[A] := UpValue[B]`
+    GetUpval = ABC,
+    /// `UpValue[B] := R[A]`
+    SetUpval = ABC,
+    /// `R[A] := R[B][RK(C)]`
+    GetTable = ABC,
+    /// `R[A][RK(B)] := RK(C)`
+    SetTable = ABC,
+    /// `R[A] := {}` with array size hint `B` and hash size hint `C`.
+    NewTable = ABC,
+    /// `R[A+1] := R[B]; R[A] := R[B][RK(C)]`
+    Method = ABC,
+    /// `R[A] := RK(B) + RK(C)`
+    Add = ABC,
+    /// `R[A] := RK(B) - RK(C)`
+    Sub = ABC,
+    /// `R[A] := RK(B) * RK(C)`
+    Mul = ABC,
+    /// `R[A] := RK(B) % RK(C)`
+    Mod = ABC,
+    /// `R[A] := RK(B) ^ RK(C)`
+    Pow = ABC,
+    /// `R[A] := RK(B) / RK(C)`
+    Div = ABC,
+    /// `R[A] := RK(B) // RK(C)`
+    IDiv = ABC,
+    /// `R[A] := RK(B) & RK(C)`
+    BAnd = ABC,
+    /// `R[A] := RK(B) | RK(C)`
+    BOr = ABC,
+    /// `R[A] := RK(B) ~ RK(C)`
+    BXor = ABC,
+    /// `R[A] := RK(B) << RK(C)`
+    Shl = ABC,
+    /// `R[A] := RK(B) >> RK(C)`
+    Shr = ABC,
+    /// `R[A] := -R[B]`
+    Unm = ABC,
+    /// `R[A] := ~R[B]`
+    BNot = ABC,
+    /// `R[A] := not R[B]`
+    Not = ABC,
+    /// `R[A] := #R[B]`
+    Len = ABC,
+    /// `R[A] := R[B] .. ... .. R[C]`
+    Concat = ABC,
+    /// `pc += sBx`; if `A != 0`, first close the upvalues and to-be-closed variables of `R[A-1]` and
+    /// the registers above it.
+    Jmp = AsBx,
+    /// `if (RK(B) == RK(C)) != A then pc++`
+    Eq = ABC,
+    /// `if (RK(B) < RK(C)) != A then pc++`
+    Lt = ABC,
+    /// `if (RK(B) <= RK(C)) != A then pc++`
+    Le = ABC,
+    /// `if truthy(R[A]) != C then pc++`
+    Test = ABC,
+    /// `if truthy(R[B]) == C then R[A] := R[B] else pc++`
+    TestSet = ABC,
+    /// `R[A], ..., R[A+C-2] := R[A](R[A+1], ..., R[A+B-1])`
+    Call = ABC,
+    /// `return R[A](R[A+1], ..., R[A+B-1])`, reusing the current frame.
+    TailCall = ABC,
+    /// `return R[A], ..., R[A+B-2]`, closing the frame's to-be-closed variables first.
+    Return = ABC,
+    /// Numeric for loop step: `R[A] += R[A+2]; if R[A] <?= R[A+1] then { pc += sBx; R[A+3] := R[A] }`.
+    /// Integer loops count down an iteration count kept in `R[A+1]` instead of comparing.
+    ForLoop = AsBx,
+    /// Numeric for loop setup: checks the loop runs at all, then `R[A+3] := R[A]` and jumps to the
+    /// body through the `FORLOOP` at `pc + sBx`. Otherwise skips past that `FORLOOP`.
+    ForPrep = AsBx,
+    /// `R[A+4], ..., R[A+3+C] := R[A](R[A+1], R[A+2])`, with `R[A+3]` the loop's closing value.
+    TForCall = ABC,
+    /// `if R[A+4] != nil then { R[A+2] := R[A+4]; pc += sBx }`
+    TForLoop = AsBx,
+    /// `R[A][(C-1)*FPF+i] := R[A+i]` for `1 <= i <= B`.
+    SetList = ABC,
+    /// `R[A] := closure(KPROTO[Bx])`, capturing upvalues as described by the prototype.
+    Closure = ABx,
+    /// `R[A], ..., R[A+B-2] := vararg`, or all of the extra arguments if `B == 0`.
+    VarArg = ABC,
+    /// Marks `R[A]` as to-be-closed, with `K[Bx]` its name for error messages.
+    Tbc = ABx,
+    /// Extra operand for the previous instruction.
+    ExtraArg = ABx,
+}
@@ -0,0 +1,116 @@
+use std::cell::Cell;
+
+use crate::mem::{Gc, Managed, Tracer};
+use crate::{LuaString, Value};
+
+use super::Instruction;
+
+/// A compiled Lua function: its bytecode, constants, nested functions and debug information.
+#[derive(Debug)]
+pub struct Prototype<'gc> {
+    /// The name of the chunk the function was defined in, used in error messages.
+    pub chunk_name: LuaString<'gc>,
+    /// The line of the `function` keyword, or 0 for the main function of a chunk.
+    pub line_defined: u32,
+    pub last_line_defined: u32,
+    pub num_params: u8,
+    pub is_vararg: bool,
+    /// The number of registers the function needs.
+    pub max_stack: u8,
+    pub code: Box<[Instruction]>,
+    /// Constants referenced by `LOADK` and `RK` operands: only nil, booleans, numbers and strings.
+    pub constants: Box<[Value<'gc>]>,
+    pub prototypes: Box<[Gc<'gc, Prototype<'gc>>]>,
+    /// Where each upvalue of a closure over this prototype comes from.
+    pub upvalues: Box<[UpvalueDesc]>,
+    /// The source line of each instruction.
+    pub line_info: Box<[u32]>,
+    /// The column each instruction's expression or statement starts at on its line, or 0 where
+    /// unknown. Empty if debug information was stripped.
+    pub column_info: Box<[u32]>,
+    /// The function's locals, in the order they were declared. Empty if debug information was
+    /// stripped.
+    pub local_vars: Box<[LocalVar<'gc>]>,
+    /// The name of each upvalue. Empty if debug information was stripped.
+    pub upvalue_names: Box<[LuaString<'gc>]>,
+    /// Where the table accesses of the code last found their keys.
+    pub cache: InlineCache,
+}
+
+/// A hint for each instruction of a function: the slot of a table's hash part where the string key
+/// it last looked up or assigned was found, so that running it again can skip the probe.
+///
+/// Hints are checked against the key in the slot before they are used, so one left behind by a
+/// table growing, losing the key or changing its metatable costs a probe and nothing else. Without
+/// hints, an empty cache, every access probes.
+///
+/// With the `jit` feature, the cache also counts how often the function runs, and keeps the native
+/// code compiled for it once it has run often enough.
+#[derive(Debug, Default)]
+pub struct InlineCache {
+    hints: Box<[Cell<u32>]>,
+    #[cfg(feature = "jit")]
+    pub(crate) jit: crate::vm::jit::JitState,
+}
+
+impl InlineCache {
+    /// A cache for `len` instructions.
+    pub fn new(len: usize) -> InlineCache {
+        InlineCache {
+            hints: (0..len).map(|_| Cell::new(0)).collect(),
+            #[cfg(feature = "jit")]
+            jit: Default::default(),
+        }
+    }
+
+    pub(crate) fn hint(&self, pc: usize) -> Option<&Cell<u32>> {
+        self.hints.get(pc)
+    }
+}
+
+/// A local variable and the instructions it is in scope for, `start_pc..end_pc`.
+///
+/// Locals take registers in the order they come into scope, so the `n`th local in scope at some
+/// instruction lives in register `n`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LocalVar<'gc> {
+    pub name: LuaString<'gc>,
+    pub start_pc: u32,
+    pub end_pc: u32,
+}
+
+/// Where a new closure finds one of its upvalues.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpvalueDesc {
+    /// The local in the given register of the function creating the closure.
+    Local(u8),
+    /// The upvalue with the given index of the function creating the closure.
+    Outer(u8),
+}
+
+impl<'gc> Prototype<'gc> {
+    /// The source line of the instruction at `pc`, if known.
+    pub fn line_at(&self, pc: usize) -> Option<u32> {
+        self.line_info.get(pc).copied()
+    }
+
+    /// The source column of the instruction at `pc`, if known.
+    pub fn column_at(&self, pc: usize) -> Option<u32> {
+        self.column_info
+            .get(pc)
+            .copied()
+            .filter(|&column| column > 0)
+    }
+}
+
+unsafe impl<'gc> Managed for Prototype<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.chunk_name.trace(tracer);
+        self.constants.trace(tracer);
+        self.prototypes.trace(tracer);
+        for var in self.local_vars.iter() {
+            var.name.trace(tracer);
+        }
+        self.upvalue_names.trace(tracer);
+    }
+}
@@ -0,0 +1,278 @@
+//! Checking that bytecode from outside the compiler stays inside its function: that registers,
+//! constants, upvalues and nested functions exist, and that control never leaves the code.
+//!
+//! The interpreter trusts what the compiler makes, and a crafted binary chunk could otherwise make
+//! it index out of bounds. This follows the checks of the reference implementation's old
+//! `luaG_checkcode`, on the instruction set of this crate.
+
+use std::fmt;
+
+use super::{Decoded, OpCode, Operand, Prototype, UpvalueDesc};
+
+/// Why a function failed [verification](verify).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    /// The line the function was defined at, or 0 for the main function.
+    pub line_defined: u32,
+    /// The index of the offending instruction, if it was an instruction.
+    pub pc: Option<usize>,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.reason)?;
+        if let Some(pc) = self.pc {
+            write!(f, " at instruction {}", pc + 1)?;
+        }
+        match self.line_defined {
+            0 => f.write_str(" in the main function"),
+            line => write!(f, " in the function at line {line}"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Checks `proto` and the functions nested in it before they are run, as
+/// [`Context::set_verify_bytecode`](crate::Context::set_verify_bytecode) has done for binary
+/// chunks. Whatever the compiler makes passes.
+pub fn verify(proto: &Prototype<'_>) -> Result<(), VerifyError> {
+    verify_function(proto)?;
+    for nested in proto.prototypes.iter() {
+        for &desc in nested.upvalues.iter() {
+            let captured = match desc {
+                UpvalueDesc::Local(r) => r < proto.max_stack,
+                UpvalueDesc::Outer(u) => (u as usize) < proto.upvalues.len(),
+            };
+            if !captured {
+                return Err(VerifyError {
+                    line_defined: nested.line_defined,
+                    pc: None,
+                    reason: "upvalue captures nothing",
+                });
+            }
+        }
+        verify(nested)?;
+    }
+    Ok(())
+}
+
+fn verify_function(proto: &Prototype<'_>) -> Result<(), VerifyError> {
+    let fail = |pc, reason| VerifyError {
+        line_defined: proto.line_defined,
+        pc,
+        reason,
+    };
+    if proto.num_params > proto.max_stack {
+        return Err(fail(None, "more parameters than registers"));
+    }
+    // Control must not run off the end.
+    match proto.code.last().and_then(|i| i.opcode()) {
+        Some(OpCode::Return | OpCode::Jmp) => {}
+        _ => return Err(fail(None, "code does not end with a return")),
+    }
+
+    let len = proto.code.len();
+    let mut previous: Option<Decoded<'_>> = None;
+    for decoded in proto.instructions() {
+        let pc = decoded.pc;
+        let Some(op) = decoded.op else {
+            return Err(fail(Some(pc), "invalid instruction"));
+        };
+        for operand in &decoded.operands {
+            let valid = match *operand {
+                Operand::Register(r) => r < proto.max_stack as u32,
+                Operand::Constant(k, _) => (k as usize) < proto.constants.len(),
+                Operand::Upvalue(u, _) => (u as usize) < proto.upvalues.len(),
+                Operand::Function(f) => (f as usize) < proto.prototypes.len(),
+                // Jumps may not land on the operand of the instruction before.
+                Operand::Jump(target) => {
+                    target < len
+                        && proto.code[target].opcode() != Some(OpCode::ExtraArg)
+                        && !uses_top(proto, target)
+                }
+                Operand::Immediate(_) => true,
+            };
+            if !valid {
+                return Err(fail(Some(pc), operand_error(operand)));
+            }
+        }
+
+        let (a, b, c) = (
+            decoded.instruction.a(),
+            decoded.instruction.b(),
+            decoded.instruction.c(),
+        );
+        // The last register each instruction reads or writes, beyond the operands themselves.
+        let last = match op {
+            OpCode::LoadNil => Some(a + b),
+            OpCode::Method => Some(a + 1),
+            OpCode::Jmp if a > 0 => Some(a - 1),
+            OpCode::Concat if b > c => return Err(fail(Some(pc), "empty concatenation")),
+            OpCode::Call if c > 1 => Some(a + b.max(c - 1).saturating_sub(1)),
+            OpCode::Call | OpCode::TailCall => Some((a + b).saturating_sub(1)),
+            OpCode::SetList => Some(a + b),
+            OpCode::Return | OpCode::VarArg => Some((a + b).saturating_sub(2)),
+            OpCode::ForLoop | OpCode::ForPrep => Some(a + 3),
+            OpCode::TForCall => Some(a + 3 + c),
+            OpCode::TForLoop => Some(a + 4),
+            _ => None,
+        };
+        if last.is_some_and(|last| last >= proto.max_stack as u32) {
+            return Err(fail(Some(pc), "register out of range"));
+        }
+
+        let skips = match op {
+            OpCode::LoadBool => c != 0,
+            OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet => true,
+            OpCode::SetList => c == 0,
+            _ => false,
+        };
+        if skips && pc + 2 >= len {
+            return Err(fail(Some(pc), "skips past the end of the code"));
+        }
+        if op == OpCode::SetList && c == 0 && proto.code[pc + 1].opcode() != Some(OpCode::ExtraArg)
+        {
+            return Err(fail(Some(pc), "missing EXTRAARG"));
+        }
+        if op == OpCode::ExtraArg
+            && !previous
+                .as_ref()
+                .is_some_and(|p| p.op == Some(OpCode::SetList) && p.instruction.c() == 0)
+        {
+            return Err(fail(Some(pc), "unexpected EXTRAARG"));
+        }
+        if op == OpCode::ForPrep {
+            let target = (pc as i64 + 1 + decoded.instruction.sbx() as i64) as usize;
+            let i = proto.code[target];
+            if i.opcode() != Some(OpCode::ForLoop) || i.a() != a {
+                return Err(fail(Some(pc), "FORPREP without its FORLOOP"));
+            }
+        }
+
+        // Instructions that take values up to the top of the stack must follow one that set it.
+        if uses_top(proto, pc) && !previous.as_ref().is_some_and(|p| sets_top(p)) {
+            return Err(fail(Some(pc), "open range of registers without a top"));
+        }
+        if sets_top(&decoded) && !uses_top(proto, pc + 1) {
+            return Err(fail(Some(pc), "open results left unused"));
+        }
+        previous = Some(decoded);
+    }
+    Ok(())
+}
+
+fn operand_error(operand: &Operand<'_>) -> &'static str {
+    match operand {
+        Operand::Register(_) => "register out of range",
+        Operand::Constant(..) => "constant out of range",
+        Operand::Upvalue(..) => "upvalue out of range",
+        Operand::Function(_) => "function out of range",
+        Operand::Jump(_) => "invalid jump",
+        Operand::Immediate(_) => unreachable!("immediates are always valid"),
+    }
+}
+
+/// Whether the instruction leaves its results open, up to a new top of the stack.
+fn sets_top(decoded: &Decoded<'_>) -> bool {
+    let i = decoded.instruction;
+    match decoded.op {
+        Some(OpCode::Call) => i.c() == 0,
+        Some(OpCode::VarArg) => i.b() == 0,
+        Some(OpCode::TailCall) => true,
+        _ => false,
+    }
+}
+
+/// Whether the instruction at `pc` takes the registers up to the top of the stack.
+fn uses_top(proto: &Prototype<'_>, pc: usize) -> bool {
+    let Some(&i) = proto.code.get(pc) else {
+        return false;
+    };
+    match i.opcode() {
+        Some(OpCode::Call | OpCode::TailCall | OpCode::Return | OpCode::SetList) => i.b() == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{dump, InlineCache, Instruction};
+    use crate::{Function, Lua};
+
+    /// `proto` with its code replaced.
+    fn with_code<'gc>(proto: &Prototype<'gc>, code: Vec<Instruction>) -> Prototype<'gc> {
+        Prototype {
+            chunk_name: proto.chunk_name,
+            line_defined: proto.line_defined,
+            last_line_defined: proto.last_line_defined,
+            num_params: proto.num_params,
+            is_vararg: proto.is_vararg,
+            max_stack: proto.max_stack,
+            cache: InlineCache::new(code.len()),
+            code: code.into(),
+            constants: proto.constants.clone(),
+            prototypes: proto.prototypes.clone(),
+            upvalues: proto.upvalues.clone(),
+            line_info: proto.line_info.clone(),
+            column_info: proto.column_info.clone(),
+            local_vars: proto.local_vars.clone(),
+            upvalue_names: proto.upvalue_names.clone(),
+        }
+    }
+
+    #[test]
+    fn checks_bytecode() {
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let source = "local t = {...}
+                for i = 1, #t do t[i] = t[i] * 2 end
+                for k, v in pairs(t) do print(k, v) end
+                local function f(...) return select('#', ...), ... end
+                local g = function() return f(t[1], f()) end
+                return t[1] == 2 and 'a' or 'b', g(), {f()}";
+            let Function::Closure(main) = ctx.load("=chunk", source).unwrap() else {
+                unreachable!();
+            };
+            let proto = main.proto();
+            verify(&proto).unwrap();
+
+            let mut code = proto.code.to_vec();
+            let fails = |code: Vec<Instruction>| verify(&with_code(&proto, code)).unwrap_err();
+            code[0] = Instruction::abx(OpCode::LoadK, 0, 200);
+            let err = fails(code.clone());
+            assert_eq!(
+                err.to_string(),
+                "constant out of range at instruction 1 in the main function"
+            );
+            code[0] = Instruction::abc(OpCode::Move, proto.max_stack as u32, 0, 0);
+            assert_eq!(fails(code.clone()).reason, "register out of range");
+            code[0] = Instruction::asbx(OpCode::Jmp, 0, -2);
+            assert_eq!(fails(code.clone()).reason, "invalid jump");
+            code[0] = Instruction::abc(OpCode::Return, 0, 0, 0);
+            assert_eq!(
+                fails(code.clone()).reason,
+                "open range of registers without a top"
+            );
+            code.truncate(1);
+            code[0] = Instruction::abc(OpCode::Move, 0, 0, 0);
+            assert_eq!(fails(code).reason, "code does not end with a return");
+
+            ctx.set_verify_bytecode(true);
+            ctx.load("=chunk", dump(&proto, false)).unwrap();
+            let mut code = proto.code.to_vec();
+            code[0] = Instruction::abx(OpCode::LoadK, 0, 200);
+            let bad = dump(&with_code(&proto, code), false);
+            let err = ctx.load("=chunk", &bad).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "chunk: bad binary format (constant out of range at instruction 1 in the main \
+                 function)"
+            );
+            ctx.set_verify_bytecode(false);
+            assert!(ctx.load("=chunk", &bad).is_ok());
+        });
+    }
+}
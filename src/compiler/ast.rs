@@ -0,0 +1,320 @@
+//! The syntax tree produced by the [parser](super::parser).
+//!
+//! Every node carries the [`Span`] of the source it was parsed from.
+
+use super::Span;
+
+/// A sequence of statements, optionally ending in a `return`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stats: Vec<Stat>,
+    pub ret: Option<Return>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Return {
+    pub values: Vec<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Attrib {
+    /// `<const>`
+    Const,
+    /// `<close>`
+    Close,
+}
+
+/// A variable declared by a `local` statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalName {
+    pub name: Name,
+    pub attrib: Option<Attrib>,
+}
+
+impl LocalName {
+    /// The span of the name, leaving out the attribute.
+    pub fn span(&self) -> Span {
+        self.name.span
+    }
+}
+
+/// The name in a `function a.b.c:m() end` statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncName {
+    /// The variable and the fields leading up to the function: `a`, `b`, `c`.
+    pub path: Vec<Name>,
+    /// The method name after a colon. The parser adds the implicit `self` parameter to the function body.
+    pub method: Option<Name>,
+}
+
+impl FuncName {
+    pub fn span(&self) -> Span {
+        let first = self.path.first().map_or(Span::default(), |name| name.span);
+        match self.method.as_ref().or(self.path.last()) {
+            Some(last) => first.to(last.span),
+            None => first,
+        }
+    }
+}
+
+/// The parameters and body shared by function expressions and statements.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionBody {
+    /// The named parameters, starting with `self` for methods.
+    pub params: Vec<Name>,
+    pub is_vararg: bool,
+    pub body: Block,
+    /// From the `function` keyword to the closing `end`.
+    pub span: Span,
+    /// The line of the closing `end`.
+    pub end_line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stat {
+    /// `a, b.c, d[e] = f, g`. Every target is a [`Expr::Name`] or [`Expr::Index`].
+    Assign {
+        targets: Vec<Expr>,
+        values: Vec<Expr>,
+        span: Span,
+    },
+    /// A function or method call evaluated for its side effects.
+    Call(Expr),
+    /// `::name::`
+    Label(Name),
+    Break(Span),
+    Goto(Name),
+    Do(Block),
+    While {
+        cond: Expr,
+        body: Block,
+        span: Span,
+    },
+    Repeat {
+        body: Block,
+        cond: Expr,
+        span: Span,
+    },
+    /// `if c1 then b1 elseif c2 then b2 else b3 end`
+    If {
+        branches: Vec<(Expr, Block)>,
+        else_block: Option<Block>,
+        span: Span,
+    },
+    NumericFor {
+        var: Name,
+        start: Box<Expr>,
+        limit: Box<Expr>,
+        step: Option<Box<Expr>>,
+        body: Block,
+        span: Span,
+    },
+    GenericFor {
+        names: Vec<Name>,
+        exprs: Vec<Expr>,
+        body: Block,
+        span: Span,
+    },
+    Function {
+        name: FuncName,
+        body: Box<FunctionBody>,
+        span: Span,
+    },
+    LocalFunction {
+        name: Name,
+        body: Box<FunctionBody>,
+        span: Span,
+    },
+    Local {
+        names: Vec<LocalName>,
+        values: Vec<Expr>,
+        span: Span,
+    },
+}
+
+impl Stat {
+    pub fn span(&self) -> Span {
+        match self {
+            Stat::Call(e) => e.span(),
+            Stat::Label(n) | Stat::Goto(n) => n.span,
+            Stat::Break(span) => *span,
+            Stat::Do(b) => b.span,
+            Stat::Assign { span, .. }
+            | Stat::While { span, .. }
+            | Stat::Repeat { span, .. }
+            | Stat::If { span, .. }
+            | Stat::NumericFor { span, .. }
+            | Stat::GenericFor { span, .. }
+            | Stat::Function { span, .. }
+            | Stat::LocalFunction { span, .. }
+            | Stat::Local { span, .. } => *span,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IDiv,
+    Mod,
+    Pow,
+    Concat,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinOp {
+    /// The left and right binding power of the operator. Right-associative operators bind tighter on the left.
+    pub fn priority(self) -> (u8, u8) {
+        match self {
+            BinOp::Or => (1, 1),
+            BinOp::And => (2, 2),
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => (3, 3),
+            BinOp::BitOr => (4, 4),
+            BinOp::BitXor => (5, 5),
+            BinOp::BitAnd => (6, 6),
+            BinOp::Shl | BinOp::Shr => (7, 7),
+            BinOp::Concat => (9, 8),
+            BinOp::Add | BinOp::Sub => (10, 10),
+            BinOp::Mul | BinOp::Div | BinOp::IDiv | BinOp::Mod => (11, 11),
+            BinOp::Pow => (14, 13),
+        }
+    }
+}
+
+/// The binding power of unary operators: tighter than everything but `^`.
+pub const UNARY_PRIORITY: u8 = 12;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnOp {
+    /// `-`
+    Neg,
+    Not,
+    /// `#`
+    Len,
+    /// `~`
+    BitNot,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableField {
+    /// `expr`, stored at the next array index.
+    Positional(Expr),
+    /// `name = expr`
+    Named(Name, Expr),
+    /// `[key] = expr`
+    Keyed(Expr, Expr),
+}
+
+impl TableField {
+    /// The span of the field, except for the brackets around the key of a keyed one.
+    pub fn span(&self) -> Span {
+        match self {
+            TableField::Positional(value) => value.span(),
+            TableField::Named(name, value) => name.span.to(value.span()),
+            TableField::Keyed(key, value) => key.span().to(value.span()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Nil(Span),
+    True(Span),
+    False(Span),
+    Integer(i64, Span),
+    Float(f64, Span),
+    String(Vec<u8>, Span),
+    /// `...`
+    Vararg(Span),
+    Function(Box<FunctionBody>),
+    Table {
+        fields: Vec<TableField>,
+        span: Span,
+    },
+    Binary {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        span: Span,
+    },
+    Unary {
+        op: UnOp,
+        operand: Box<Expr>,
+        span: Span,
+    },
+    Name(Name),
+    /// `object[key]`, or `object.name` with the name as a string key.
+    Index {
+        object: Box<Expr>,
+        key: Box<Expr>,
+        span: Span,
+    },
+    Call {
+        func: Box<Expr>,
+        args: Vec<Expr>,
+        span: Span,
+    },
+    /// `object:method(args)`
+    MethodCall {
+        object: Box<Expr>,
+        method: Name,
+        args: Vec<Expr>,
+        span: Span,
+    },
+    /// A parenthesized expression, which truncates calls and varargs to a single value.
+    Paren(Box<Expr>, Span),
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Nil(span)
+            | Expr::True(span)
+            | Expr::False(span)
+            | Expr::Integer(_, span)
+            | Expr::Float(_, span)
+            | Expr::String(_, span)
+            | Expr::Vararg(span)
+            | Expr::Paren(_, span) => *span,
+            Expr::Function(f) => f.span,
+            Expr::Name(n) => n.span,
+            Expr::Table { span, .. }
+            | Expr::Binary { span, .. }
+            | Expr::Unary { span, .. }
+            | Expr::Index { span, .. }
+            | Expr::Call { span, .. }
+            | Expr::MethodCall { span, .. } => *span,
+        }
+    }
+
+    /// Returns true for expressions that can produce any number of values.
+    pub fn is_multi(&self) -> bool {
+        matches!(
+            self,
+            Expr::Call { .. } | Expr::MethodCall { .. } | Expr::Vararg(_)
+        )
+    }
+}
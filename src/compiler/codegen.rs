@@ -0,0 +1,2109 @@
+//! Lowers the syntax tree into register-based bytecode.
+//!
+//! Locals live in consecutive registers starting at 0, in declaration order, and temporaries are allocated in a
+//! stack above them. Every statement starts and ends with no temporaries in use.
+
+use std::collections::HashMap;
+use std::mem;
+
+use crate::bytecode::{
+    self, InlineCache, Instruction, LocalVar, OpCode, Prototype, UpvalueDesc, FIELDS_PER_FLUSH,
+    MAX_A, MAX_B, MAX_BX, MAX_C, MAX_RK_INDEX, MAX_SBX,
+};
+use crate::mem::{Gc, Mutation};
+use crate::vm::ops::{self, ArithOp, BitOp};
+use crate::{LuaString, Value};
+
+use super::ast::{
+    Attrib, BinOp, Block, Expr, FuncName, FunctionBody, Name, Stat, TableField, UnOp,
+};
+use super::{peephole, CompileError, CompileOptions, Span};
+
+/// Registers available to a function; `MAX_A` is kept free so `A + 1` operands stay encodable.
+const MAX_REGISTERS: u32 = MAX_A;
+/// The maximum number of active locals in one function.
+const MAX_LOCALS: usize = 200;
+/// The maximum number of upvalues of one function, limited by the `u8` in [`UpvalueDesc`].
+const MAX_UPVALUES: usize = 255;
+
+/// Generates the main function of a chunk from its syntax tree.
+pub fn generate<'gc>(
+    mc: &Mutation<'gc>,
+    chunk: &Block,
+    chunk_name: &str,
+    options: CompileOptions,
+) -> Result<Gc<'gc, Prototype<'gc>>, CompileError> {
+    let mut codegen = Codegen {
+        mc,
+        options,
+        chunk_name: LuaString::new(mc, chunk_name.as_bytes()),
+        funcs: Vec::new(),
+        span: chunk.span,
+        strings: HashMap::new(),
+    };
+    let mut main = FuncState::new(0, true);
+    // Whoever creates the main closure supplies `_ENV`, so its description is never used.
+    main.upvalues
+        .push(("_ENV".to_owned(), UpvalueDesc::Local(0)));
+    codegen.funcs.push(main);
+    codegen.block(chunk)?;
+    codegen.finish_function(0)
+}
+
+/// A hashable form of the constant values, so each one is stored once per function.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Constant {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    /// Floats are keyed by their bits, which keeps `0.0` and `-0.0` apart, and `1.0` apart from `1`.
+    Float(u64),
+    String(Vec<u8>),
+}
+
+struct Scope {
+    /// The number of active locals when the scope was entered.
+    num_locals: usize,
+    is_loop: bool,
+    /// Jumps out of the loop that still need to be patched to its end.
+    breaks: Vec<usize>,
+    /// Whether a closure captures one of the scope's own locals, or one of them is to-be-closed.
+    /// Either way they need closing when the scope ends.
+    captured: bool,
+    /// Whether a closure captures a local of a scope nested in this one.
+    nested_captured: bool,
+}
+
+/// A label in one of the blocks being generated.
+struct Label {
+    name: String,
+    pc: usize,
+    line: u32,
+    /// The number of active locals a `goto` lands with.
+    num_locals: usize,
+    /// The number of scopes entered when the label was declared.
+    depth: usize,
+}
+
+/// A `goto` whose label hasn't been seen yet.
+struct PendingGoto {
+    name: String,
+    jump: usize,
+    line: u32,
+    /// The number of active locals at the `goto`, lowered as it leaves scopes.
+    num_locals: usize,
+    /// The number of scopes around the `goto`, lowered as it leaves scopes.
+    depth: usize,
+    /// Whether a scope the `goto` leaves has captured locals, so the jump needs to close them.
+    close: bool,
+}
+
+struct FuncState<'gc> {
+    code: Vec<Instruction>,
+    /// The line and column each instruction came from.
+    positions: Vec<(u32, u32)>,
+    constants: Vec<Value<'gc>>,
+    constant_indices: HashMap<Constant, u32>,
+    prototypes: Vec<Gc<'gc, Prototype<'gc>>>,
+    /// The active locals. The local at index `i` lives in register `i`.
+    locals: Vec<Local>,
+    /// Every local declared so far, for debug information.
+    local_vars: Vec<LocalVar<'gc>>,
+    scopes: Vec<Scope>,
+    /// The labels of the enclosing blocks, the only ones a `goto` can see.
+    labels: Vec<Label>,
+    pending_gotos: Vec<PendingGoto>,
+    /// The names of the function's upvalues and where to capture them from.
+    upvalues: Vec<(String, UpvalueDesc)>,
+    free_reg: u32,
+    max_stack: u32,
+    num_params: u8,
+    is_vararg: bool,
+    line_defined: u32,
+}
+
+impl<'gc> FuncState<'gc> {
+    fn new(line_defined: u32, is_vararg: bool) -> FuncState<'gc> {
+        FuncState {
+            code: Vec::new(),
+            positions: Vec::new(),
+            constants: Vec::new(),
+            constant_indices: HashMap::new(),
+            prototypes: Vec::new(),
+            locals: Vec::new(),
+            local_vars: Vec::new(),
+            scopes: Vec::new(),
+            labels: Vec::new(),
+            pending_gotos: Vec::new(),
+            upvalues: Vec::new(),
+            free_reg: 0,
+            max_stack: 2,
+            num_params: 0,
+            is_vararg,
+            line_defined,
+        }
+    }
+}
+
+struct Local {
+    name: String,
+    attrib: Option<Attrib>,
+    /// Its entry in [`FuncState::local_vars`].
+    var: usize,
+}
+
+/// What a name refers to.
+enum Var {
+    Local(u32),
+    Upvalue(u32),
+    /// A field of `_ENV`.
+    Global,
+}
+
+/// An assignment target with its table and key already evaluated.
+enum Target {
+    Local(u32),
+    Upvalue(u32),
+    /// A field of an `_ENV` held in an upvalue, with the key as an `RK` operand.
+    Global {
+        env: u32,
+        key: u32,
+    },
+    Index {
+        table: u32,
+        key: u32,
+    },
+}
+
+struct Codegen<'gc, 'a> {
+    mc: &'a Mutation<'gc>,
+    options: CompileOptions,
+    chunk_name: LuaString<'gc>,
+    /// The function being generated is last; the ones enclosing it come before.
+    funcs: Vec<FuncState<'gc>>,
+    /// The node being generated, for line information and error locations.
+    span: Span,
+    /// The strings made so far for the whole chunk, so that every function using a literal or a
+    /// name shares one copy of it.
+    strings: HashMap<Vec<u8>, LuaString<'gc>>,
+}
+
+impl<'gc, 'a> Codegen<'gc, 'a> {
+    fn fs(&mut self) -> &mut FuncState<'gc> {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn string(&mut self, bytes: &[u8]) -> LuaString<'gc> {
+        if let Some(&s) = self.strings.get(bytes) {
+            return s;
+        }
+        let s = LuaString::new(self.mc, bytes);
+        self.strings.insert(bytes.to_vec(), s);
+        s
+    }
+
+    fn error(&self, message: impl Into<String>) -> CompileError {
+        CompileError::new(message, self.span)
+    }
+
+    fn pc(&mut self) -> usize {
+        self.fs().code.len()
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        let position = (self.span.line, self.span.column);
+        let fs = self.fs();
+        fs.code.push(instruction);
+        fs.positions.push(position);
+        fs.code.len() - 1
+    }
+
+    fn emit_abc(&mut self, op: OpCode, a: u32, b: u32, c: u32) -> usize {
+        self.emit(Instruction::abc(op, a, b, c))
+    }
+
+    fn emit_abx(&mut self, op: OpCode, a: u32, bx: u32) -> usize {
+        self.emit(Instruction::abx(op, a, bx))
+    }
+
+    /// Emits a jump to be patched later.
+    fn emit_jump(&mut self) -> usize {
+        self.emit(Instruction::asbx(OpCode::Jmp, 0, 0))
+    }
+
+    fn patch_jump(&mut self, jump: usize, target: usize) -> Result<(), CompileError> {
+        let offset = target as i64 - (jump as i64 + 1);
+        if offset.abs() > MAX_SBX as i64 {
+            return Err(self.error("control structure too long"));
+        }
+        self.fs().code[jump].set_sbx(offset as i32);
+        Ok(())
+    }
+
+    fn patch_to_here(&mut self, jumps: Vec<usize>) -> Result<(), CompileError> {
+        let here = self.pc();
+        for jump in jumps {
+            self.patch_jump(jump, here)?;
+        }
+        Ok(())
+    }
+
+    fn emit_jump_to(&mut self, target: usize) -> Result<(), CompileError> {
+        let jump = self.emit_jump();
+        self.patch_jump(jump, target)
+    }
+
+    fn num_locals(&mut self) -> u32 {
+        self.fs().locals.len() as u32
+    }
+
+    fn reserve(&mut self, n: u32) -> Result<u32, CompileError> {
+        let fs = self.funcs.last_mut().unwrap();
+        let reg = fs.free_reg;
+        fs.free_reg += n;
+        if fs.free_reg > MAX_REGISTERS {
+            return Err(self.error("function or expression needs too many registers"));
+        }
+        fs.max_stack = fs.max_stack.max(fs.free_reg);
+        Ok(reg)
+    }
+
+    fn set_free_reg(&mut self, reg: u32) {
+        self.fs().free_reg = reg;
+    }
+
+    /// Makes sure the function's frame has at least `size` registers.
+    fn check_stack(&mut self, size: u32) -> Result<(), CompileError> {
+        if size > MAX_REGISTERS {
+            return Err(self.error("function or expression needs too many registers"));
+        }
+        let fs = self.fs();
+        fs.max_stack = fs.max_stack.max(size);
+        Ok(())
+    }
+
+    fn constant(&mut self, constant: Constant) -> Result<u32, CompileError> {
+        if let Some(&index) = self.fs().constant_indices.get(&constant) {
+            return Ok(index);
+        }
+        let value = match &constant {
+            Constant::Nil => Value::Nil,
+            Constant::Boolean(b) => Value::Boolean(*b),
+            Constant::Integer(i) => Value::Integer(*i),
+            Constant::Float(bits) => Value::Number(f64::from_bits(*bits)),
+            Constant::String(s) => Value::String(self.string(s)),
+        };
+        let fs = self.funcs.last_mut().unwrap();
+        let index = fs.constants.len() as u32;
+        if index > MAX_BX {
+            return Err(self.error("constant table overflow"));
+        }
+        fs.constants.push(value);
+        fs.constant_indices.insert(constant, index);
+        Ok(index)
+    }
+
+    fn string_constant(&mut self, s: &str) -> Result<u32, CompileError> {
+        self.constant(Constant::String(s.as_bytes().to_vec()))
+    }
+
+    /// Turns a constant into an `RK` operand, loading it into a fresh register if its index is too large.
+    fn constant_rk(&mut self, index: u32) -> Result<u32, CompileError> {
+        if index <= MAX_RK_INDEX {
+            Ok(bytecode::rk_constant(index))
+        } else {
+            let reg = self.reserve(1)?;
+            self.emit_abx(OpCode::LoadK, reg, index);
+            Ok(reg)
+        }
+    }
+
+    fn enter_scope(&mut self, is_loop: bool) {
+        let num_locals = self.fs().locals.len();
+        self.fs().scopes.push(Scope {
+            num_locals,
+            is_loop,
+            breaks: Vec::new(),
+            captured: false,
+            nested_captured: false,
+        });
+    }
+
+    fn leave_scope(&mut self) -> Result<(), CompileError> {
+        let scope = self.fs().scopes.pop().unwrap();
+        self.remove_locals(scope.num_locals);
+        self.set_free_reg(scope.num_locals as u32);
+        // The `A` operand of a jump closes the upvalues from register `A - 1` up.
+        let close = scope.num_locals as u32 + 1;
+        if scope.captured {
+            self.emit(Instruction::asbx(OpCode::Jmp, close, 0));
+        }
+        let depth = self.fs().scopes.len();
+        let fs = self.fs();
+        fs.labels.retain(|l| l.depth <= depth);
+        // Gotos leaving the block now wait for a label in the enclosing one.
+        for goto in &mut fs.pending_gotos {
+            if goto.depth > depth {
+                goto.depth = depth;
+            }
+            if goto.num_locals > scope.num_locals {
+                goto.num_locals = scope.num_locals;
+                goto.close |= scope.captured;
+            }
+        }
+        let any_captured = scope.captured || scope.nested_captured;
+        if let Some(parent) = self.fs().scopes.last_mut() {
+            parent.nested_captured |= any_captured;
+        }
+        if scope.is_loop {
+            if any_captured {
+                for &jump in &scope.breaks {
+                    self.fs().code[jump].set_a(close);
+                }
+            }
+            self.patch_to_here(scope.breaks)?;
+        }
+        Ok(())
+    }
+
+    /// Activates locals for the registers just above the current ones, which must already hold their values.
+    fn add_locals(&mut self, names: impl IntoIterator<Item = String>) -> Result<(), CompileError> {
+        for name in names {
+            let var = LocalVar {
+                name: self.string(name.as_bytes()),
+                start_pc: self.pc() as u32,
+                end_pc: 0,
+            };
+            let fs = self.funcs.last_mut().unwrap();
+            fs.locals.push(Local {
+                name,
+                attrib: None,
+                var: fs.local_vars.len(),
+            });
+            fs.local_vars.push(var);
+            if fs.locals.len() > MAX_LOCALS {
+                let line = fs.line_defined;
+                return Err(self.error(format!(
+                    "too many local variables (limit is {MAX_LOCALS}) in {}",
+                    if line == 0 {
+                        "main function".to_owned()
+                    } else {
+                        format!("function at line {line}")
+                    }
+                )));
+            }
+        }
+        let num_locals = self.num_locals();
+        self.check_stack(num_locals)?;
+        self.set_free_reg(num_locals);
+        Ok(())
+    }
+
+    /// Ends the scope of the locals above the first `num_locals`.
+    fn remove_locals(&mut self, num_locals: usize) {
+        let fs = self.funcs.last_mut().unwrap();
+        let pc = fs.code.len() as u32;
+        for local in fs.locals.drain(num_locals..) {
+            fs.local_vars[local.var].end_pc = pc;
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> Result<Var, CompileError> {
+        if let Some(reg) = self.fs().locals.iter().rposition(|l| l.name == name) {
+            return Ok(Var::Local(reg as u32));
+        }
+        let level = self.funcs.len() - 1;
+        Ok(match self.resolve_upvalue(level, name)? {
+            Some(index) => Var::Upvalue(index),
+            None => Var::Global,
+        })
+    }
+
+    /// Like [`Codegen::resolve`], but rejects `<const>` and `<close>` locals, from this function or
+    /// an enclosing one.
+    fn resolve_assignable(&mut self, name: &str) -> Result<Var, CompileError> {
+        let local = self
+            .funcs
+            .iter()
+            .rev()
+            .find_map(|fs| fs.locals.iter().rfind(|l| l.name == name));
+        if local.is_some_and(|l| l.attrib.is_some()) {
+            return Err(self.error(format!("attempt to assign to const variable '{name}'")));
+        }
+        self.resolve(name)
+    }
+
+    /// Resolves the `_ENV` a global is looked up in, and the global's name as an `RK` operand.
+    ///
+    /// `_ENV` is never a global itself, since the main function always has it as an upvalue.
+    fn resolve_global(&mut self, name: &str) -> Result<(Var, u32), CompileError> {
+        let env = self.resolve("_ENV")?;
+        let key = self.string_constant(name)?;
+        Ok((env, self.constant_rk(key)?))
+    }
+
+    /// Finds or adds the upvalue for `name` in the function at `level`, capturing it from the
+    /// enclosing functions. Returns `None` if no enclosing function has such a local.
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Result<Option<u32>, CompileError> {
+        if let Some(index) = self.funcs[level]
+            .upvalues
+            .iter()
+            .position(|(n, _)| n == name)
+        {
+            return Ok(Some(index as u32));
+        }
+        if level == 0 {
+            return Ok(None);
+        }
+
+        let parent = &mut self.funcs[level - 1];
+        let desc = if let Some(reg) = parent.locals.iter().rposition(|l| l.name == name) {
+            // The innermost scope that started below the local is the one that declared it.
+            if let Some(scope) = parent.scopes.iter_mut().rev().find(|s| s.num_locals <= reg) {
+                scope.captured = true;
+            }
+            UpvalueDesc::Local(reg as u8)
+        } else {
+            match self.resolve_upvalue(level - 1, name)? {
+                Some(index) => UpvalueDesc::Outer(index as u8),
+                None => return Ok(None),
+            }
+        };
+
+        let fs = &mut self.funcs[level];
+        if fs.upvalues.len() >= MAX_UPVALUES {
+            return Err(self.error(format!("too many upvalues (limit is {MAX_UPVALUES})")));
+        }
+        fs.upvalues.push((name.to_owned(), desc));
+        Ok(Some(fs.upvalues.len() as u32 - 1))
+    }
+
+    /// Pops the current function and builds its prototype.
+    fn finish_function(&mut self, last_line: u32) -> Result<Gc<'gc, Prototype<'gc>>, CompileError> {
+        if let Some(goto) = self.fs().pending_gotos.first() {
+            let message = format!(
+                "no visible label '{}' for <goto> at line {}",
+                goto.name, goto.line
+            );
+            return Err(self.error(message));
+        }
+        self.emit_abc(OpCode::Return, 0, 1, 0);
+        self.remove_locals(0);
+        let mut fs = self.funcs.pop().unwrap();
+        if self.options.optimize > 0 {
+            peephole::optimize(&mut fs.code, &mut fs.positions, &mut fs.local_vars);
+        }
+        let upvalue_names = fs
+            .upvalues
+            .iter()
+            .map(|(name, _)| self.string(name.as_bytes()))
+            .collect();
+        Ok(Gc::new(
+            self.mc,
+            Prototype {
+                chunk_name: self.chunk_name,
+                line_defined: fs.line_defined,
+                last_line_defined: last_line,
+                num_params: fs.num_params,
+                is_vararg: fs.is_vararg,
+                max_stack: fs.max_stack as u8,
+                cache: InlineCache::new(fs.code.len()),
+                code: fs.code.into(),
+                constants: fs.constants.into(),
+                prototypes: fs.prototypes.into(),
+                upvalue_names,
+                upvalues: fs.upvalues.into_iter().map(|(_, desc)| desc).collect(),
+                line_info: fs.positions.iter().map(|&(line, _)| line).collect(),
+                column_info: fs.positions.iter().map(|&(_, column)| column).collect(),
+                local_vars: fs.local_vars.into(),
+            },
+        ))
+    }
+
+    fn block(&mut self, block: &Block) -> Result<(), CompileError> {
+        self.block_until(block, false)
+    }
+
+    /// Generates a block, which for a `repeat` body is followed by the loop's condition.
+    fn block_until(&mut self, block: &Block, until: bool) -> Result<(), CompileError> {
+        for (i, stat) in block.stats.iter().enumerate() {
+            self.span = stat.span();
+            match stat {
+                Stat::Label(name) => {
+                    // Nothing runs after a label that ends its block, so jumping to it leaves the
+                    // block's locals, which lets `goto continue` skip over them.
+                    let last = !until
+                        && block.ret.is_none()
+                        && block.stats[i + 1..]
+                            .iter()
+                            .all(|s| matches!(s, Stat::Label(_)));
+                    self.label(name, last)?;
+                }
+                _ => self.stat(stat)?,
+            }
+            let num_locals = self.num_locals();
+            self.set_free_reg(num_locals);
+        }
+        if let Some(ret) = &block.ret {
+            self.span = ret.span;
+            let base = self.num_locals();
+            // Variables still to be closed when the call returns rule out a tail call.
+            let tbc = self
+                .fs()
+                .locals
+                .iter()
+                .any(|l| l.attrib == Some(Attrib::Close));
+            if let ([call @ (Expr::Call { .. } | Expr::MethodCall { .. })], false) =
+                (&ret.values[..], tbc)
+            {
+                let func = self.reserve(1)?;
+                self.call(call, func, None)?;
+                let fs = self.fs();
+                let last = fs.code.last_mut().expect("a call was just emitted");
+                *last = Instruction::abc(OpCode::TailCall, last.a(), last.b(), 0);
+                self.emit_abc(OpCode::Return, base, 0, 0);
+                return Ok(());
+            }
+            let count = self.expr_list(&ret.values)?;
+            match count {
+                Some(n) => self.emit_abc(OpCode::Return, base, n + 1, 0),
+                None => self.emit_abc(OpCode::Return, base, 0, 0),
+            };
+        }
+        Ok(())
+    }
+
+    fn scoped_block(&mut self, block: &Block, is_loop: bool) -> Result<(), CompileError> {
+        self.enter_scope(is_loop);
+        self.block(block)?;
+        self.leave_scope()
+    }
+
+    fn stat(&mut self, stat: &Stat) -> Result<(), CompileError> {
+        match stat {
+            Stat::Assign {
+                targets, values, ..
+            } => self.assign(targets, values),
+            Stat::Call(call) => {
+                let base = self.reserve(1)?;
+                self.call(call, base, Some(0))
+            }
+            Stat::Label(name) => self.label(name, false),
+            Stat::Goto(name) => self.goto(name),
+            Stat::Break(_) => {
+                let Some(scope) = self.fs().scopes.iter().rposition(|s| s.is_loop) else {
+                    let line = self.span.line;
+                    return Err(self.error(format!("break outside a loop at line {line}")));
+                };
+                let jump = self.emit_jump();
+                self.fs().scopes[scope].breaks.push(jump);
+                Ok(())
+            }
+            Stat::Do(block) => self.scoped_block(block, false),
+            Stat::While { cond, body, .. } => {
+                let start = self.pc();
+                let exits = self.cond(cond, false)?;
+                self.enter_scope(true);
+                self.scoped_block(body, false)?;
+                self.emit_jump_to(start)?;
+                self.patch_to_here(exits)?;
+                self.leave_scope()
+            }
+            Stat::Repeat { body, cond, .. } => {
+                let start = self.pc();
+                // The condition can see the body's locals, so it is generated inside the loop's scope.
+                self.enter_scope(true);
+                self.block_until(body, true)?;
+                self.span = cond.span();
+                let back = self.cond(cond, false)?;
+                let scope = self.fs().scopes.last().unwrap();
+                let close = scope.captured.then_some(scope.num_locals as u32 + 1);
+                for jump in back {
+                    // Each iteration gets fresh locals, so going around again closes the old ones.
+                    if let Some(close) = close {
+                        self.fs().code[jump].set_a(close);
+                    }
+                    self.patch_jump(jump, start)?;
+                }
+                self.leave_scope()
+            }
+            Stat::If {
+                branches,
+                else_block,
+                ..
+            } => {
+                let mut ends = Vec::new();
+                for (i, (cond, block)) in branches.iter().enumerate() {
+                    self.span = cond.span();
+                    let skip = self.cond(cond, false)?;
+                    self.scoped_block(block, false)?;
+                    if i + 1 < branches.len() || else_block.is_some() {
+                        ends.push(self.emit_jump());
+                    }
+                    self.patch_to_here(skip)?;
+                }
+                if let Some(block) = else_block {
+                    self.scoped_block(block, false)?;
+                }
+                self.patch_to_here(ends)
+            }
+            Stat::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+                ..
+            } => {
+                self.enter_scope(true);
+                let base = self.reserve(3)?;
+                self.expr(start, base)?;
+                self.expr(limit, base + 1)?;
+                match step {
+                    Some(step) => self.expr(step, base + 2)?,
+                    None => {
+                        self.emit(Instruction::asbx(OpCode::LoadI, base + 2, 1));
+                    }
+                }
+                // The three control registers are hidden locals, so the loop variable lands after them.
+                self.add_locals([
+                    "(for state)".into(),
+                    "(for state)".into(),
+                    "(for state)".into(),
+                ])?;
+                let prep = self.emit(Instruction::asbx(OpCode::ForPrep, base, 0));
+                let body_start = self.pc();
+                self.loop_body([&var.name].into_iter(), body)?;
+                let prep_target = self.pc();
+                self.patch_jump(prep, prep_target)?;
+                let back = self.emit(Instruction::asbx(OpCode::ForLoop, base, 0));
+                self.patch_jump(back, body_start)?;
+                self.leave_scope()
+            }
+            Stat::GenericFor {
+                names, exprs, body, ..
+            } => {
+                self.enter_scope(true);
+                let base = self.num_locals();
+                // The iterator function, its state, the control variable and a closing value.
+                self.expr_list_to(exprs, base, 4)?;
+                self.add_locals([
+                    "(for state)".into(),
+                    "(for state)".into(),
+                    "(for state)".into(),
+                    "(for state)".into(),
+                ])?;
+                let index = self.string_constant("(for state)")?;
+                self.emit_abx(OpCode::Tbc, base + 3, index);
+                if let Some(scope) = self.fs().scopes.last_mut() {
+                    scope.captured = true;
+                }
+                // TFORCALL copies the first three control values above them before calling.
+                self.check_stack(base + 7)?;
+                let to_call = self.emit_jump();
+                let body_start = self.pc();
+                self.loop_body(names.iter().map(|n| &n.name), body)?;
+                self.patch_to_here(vec![to_call])?;
+                self.emit_abc(OpCode::TForCall, base, 0, names.len() as u32);
+                let back = self.emit(Instruction::asbx(OpCode::TForLoop, base, 0));
+                self.patch_jump(back, body_start)?;
+                self.leave_scope()
+            }
+            Stat::Function { name, body, .. } => self.function_stat(name, body),
+            Stat::LocalFunction { name, body, .. } => {
+                let reg = self.reserve(1)?;
+                self.add_locals([name.name.clone()])?;
+                self.function(body, reg)
+            }
+            Stat::Local { names, values, .. } => {
+                let base = self.num_locals();
+                self.expr_list_to(values, base, names.len() as u32)?;
+                self.add_locals(names.iter().map(|n| n.name.name.clone()))?;
+                for (i, name) in names.iter().enumerate() {
+                    let reg = base + i as u32;
+                    self.fs().locals[reg as usize].attrib = name.attrib;
+                    if name.attrib == Some(Attrib::Close) {
+                        let index = self.string_constant(&name.name.name)?;
+                        self.emit_abx(OpCode::Tbc, reg, index);
+                        if let Some(scope) = self.fs().scopes.last_mut() {
+                            scope.captured = true;
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn label(&mut self, name: &Name, last: bool) -> Result<(), CompileError> {
+        let line = name.span.line;
+        if let Some(label) = self.fs().labels.iter().find(|l| l.name == name.name) {
+            let message = format!(
+                "label '{}' already defined on line {}",
+                name.name, label.line
+            );
+            return Err(self.error(message));
+        }
+        let num_locals = match (last, self.fs().scopes.last()) {
+            (false, _) => self.fs().locals.len(),
+            (true, Some(scope)) => scope.num_locals,
+            (true, None) => self.fs().num_params as usize,
+        };
+        let depth = self.fs().scopes.len();
+        let pc = self.pc();
+        self.fs().labels.push(Label {
+            name: name.name.clone(),
+            pc,
+            line,
+            num_locals,
+            depth,
+        });
+
+        let gotos = std::mem::take(&mut self.fs().pending_gotos);
+        let (resolved, pending) = gotos
+            .into_iter()
+            .partition::<Vec<_>, _>(|g| g.name == name.name && g.depth == depth);
+        self.fs().pending_gotos = pending;
+        for goto in resolved {
+            if goto.num_locals < num_locals {
+                let local = &self.fs().locals[goto.num_locals].name;
+                let message = format!(
+                    "<goto {}> at line {} jumps into the scope of local '{local}'",
+                    goto.name, goto.line
+                );
+                return Err(self.error(message));
+            }
+            if goto.close {
+                self.fs().code[goto.jump].set_a(num_locals as u32 + 1);
+            }
+            self.patch_jump(goto.jump, pc)?;
+        }
+        Ok(())
+    }
+
+    fn goto(&mut self, name: &Name) -> Result<(), CompileError> {
+        let num_locals = self.fs().locals.len();
+        let label = self.fs().labels.iter().find(|l| l.name == name.name);
+        if let Some(&Label {
+            pc,
+            num_locals: level,
+            ..
+        }) = label
+        {
+            // A backward jump leaves the locals declared since the label.
+            let jump = self.emit_jump();
+            if num_locals > level {
+                self.fs().code[jump].set_a(level as u32 + 1);
+            }
+            return self.patch_jump(jump, pc);
+        }
+        let jump = self.emit_jump();
+        let depth = self.fs().scopes.len();
+        self.fs().pending_gotos.push(PendingGoto {
+            name: name.name.clone(),
+            jump,
+            line: name.span.line,
+            num_locals,
+            depth,
+            close: false,
+        });
+        Ok(())
+    }
+
+    /// Generates the body of a `for` loop along with its variables, which are scoped to a single
+    /// iteration.
+    fn loop_body<'n>(
+        &mut self,
+        vars: impl ExactSizeIterator<Item = &'n String>,
+        body: &Block,
+    ) -> Result<(), CompileError> {
+        self.enter_scope(false);
+        self.reserve(vars.len() as u32)?;
+        self.add_locals(vars.cloned())?;
+        self.block(body)?;
+        self.leave_scope()
+    }
+
+    fn function_stat(&mut self, name: &FuncName, body: &FunctionBody) -> Result<(), CompileError> {
+        let (last, path) = match &name.method {
+            Some(method) => (method, &name.path[..]),
+            None => name.path.split_last().unwrap(),
+        };
+        if path.is_empty() {
+            let target = self.target(&Expr::Name(last.clone()), false)?;
+            let reg = self.reserve(1)?;
+            self.function(body, reg)?;
+            return self.store(target, reg);
+        }
+
+        let table = self.reserve(1)?;
+        self.expr(&Expr::Name(path[0].clone()), table)?;
+        for field in &path[1..] {
+            let key = self.string_constant(&field.name)?;
+            let key = self.constant_rk(key)?;
+            self.emit_abc(OpCode::GetTable, table, table, key);
+        }
+        let key = self.string_constant(&last.name)?;
+        let key = self.constant_rk(key)?;
+        let value = self.reserve(1)?;
+        self.function(body, value)?;
+        self.store(Target::Index { table, key }, value)
+    }
+
+    fn assign(&mut self, targets: &[Expr], values: &[Expr]) -> Result<(), CompileError> {
+        if let ([target], [value]) = (targets, values) {
+            let target = self.target(target, false)?;
+            return match target {
+                Target::Local(reg) => self.expr(value, reg),
+                target => {
+                    let value = self.expr_rk(value)?;
+                    self.store(target, value)
+                }
+            };
+        }
+
+        // Evaluate every table and key first, then every value, and only then assign, so that the assignments
+        // cannot affect each other's operands.
+        let targets = targets
+            .iter()
+            .map(|t| self.target(t, true))
+            .collect::<Result<Vec<_>, _>>()?;
+        let base = self.fs().free_reg;
+        self.expr_list_to(values, base, targets.len() as u32)?;
+        for (i, target) in targets.into_iter().enumerate().rev() {
+            self.store(target, base + i as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Evaluates the table and key of an assignment target. With `copy`, locals used as the table or key are
+    /// copied to temporaries, so that assigning to them in the same statement doesn't change the target.
+    fn target(&mut self, target: &Expr, copy: bool) -> Result<Target, CompileError> {
+        match target {
+            Expr::Name(name) => match self.resolve_assignable(&name.name)? {
+                Var::Local(reg) => Ok(Target::Local(reg)),
+                Var::Upvalue(index) => Ok(Target::Upvalue(index)),
+                Var::Global => match self.resolve_global(&name.name)? {
+                    (Var::Local(table), key) => Ok(Target::Index { table, key }),
+                    (Var::Upvalue(env), key) => Ok(Target::Global { env, key }),
+                    (Var::Global, _) => unreachable!("`_ENV` is always in scope"),
+                },
+            },
+            Expr::Index { object, key, .. } => {
+                let (table, key) = if copy {
+                    let table = self.expr_to_new_reg(object)?;
+                    let key = match fold(key) {
+                        Some(c) => {
+                            let index = self.constant(c)?;
+                            self.constant_rk(index)?
+                        }
+                        None => self.expr_to_new_reg(key)?,
+                    };
+                    (table, key)
+                } else {
+                    (self.expr_any(object)?, self.expr_rk(key)?)
+                };
+                Ok(Target::Index { table, key })
+            }
+            _ => unreachable!("the parser only produces names and indexes as assignment targets"),
+        }
+    }
+
+    fn store(&mut self, target: Target, value: u32) -> Result<(), CompileError> {
+        match target {
+            Target::Local(reg) => {
+                if reg != value {
+                    self.emit_abc(OpCode::Move, reg, value, 0);
+                }
+            }
+            Target::Upvalue(index) => {
+                let value = self.rk_to_reg(value)?;
+                self.emit_abc(OpCode::SetUpval, value, index, 0);
+            }
+            Target::Global { env, key } => {
+                self.emit_abc(OpCode::SetTabUp, env, key, value);
+            }
+            Target::Index { table, key } => {
+                self.emit_abc(OpCode::SetTable, table, key, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn rk_to_reg(&mut self, rk: u32) -> Result<u32, CompileError> {
+        if bytecode::is_constant(rk) {
+            let reg = self.reserve(1)?;
+            self.emit_abx(OpCode::LoadK, reg, rk & MAX_RK_INDEX);
+            Ok(reg)
+        } else {
+            Ok(rk)
+        }
+    }
+
+    /// Generates a nested function into `dst`.
+    fn function(&mut self, body: &FunctionBody, dst: u32) -> Result<(), CompileError> {
+        let span = self.span;
+        self.funcs
+            .push(FuncState::new(body.span.line, body.is_vararg));
+        self.span = body.span;
+        self.fs().num_params = body.params.len() as u8;
+        self.reserve(body.params.len() as u32)?;
+        self.add_locals(body.params.iter().map(|p| p.name.clone()))?;
+        self.block(&body.body)?;
+        // The closing return belongs to the `end`, whose column isn't kept.
+        self.span.line = body.end_line;
+        self.span.column = 0;
+        let proto = self.finish_function(body.end_line)?;
+        self.span = span;
+
+        let fs = self.fs();
+        let index = fs.prototypes.len() as u32;
+        if index > MAX_BX {
+            return Err(self.error("too many nested functions"));
+        }
+        fs.prototypes.push(proto);
+        self.emit_abx(OpCode::Closure, dst, index);
+        Ok(())
+    }
+
+    /// Evaluates `exprs` into consecutive registers starting at the first free one, returning how many values
+    /// were produced, or `None` if the last expression left all of its results on the stack.
+    fn expr_list(&mut self, exprs: &[Expr]) -> Result<Option<u32>, CompileError> {
+        for (i, expr) in exprs.iter().enumerate() {
+            let reg = self.reserve(1)?;
+            if i + 1 == exprs.len() && expr.is_multi() {
+                self.multi(expr, reg, None)?;
+                return Ok(None);
+            }
+            self.expr(expr, reg)?;
+        }
+        Ok(Some(exprs.len() as u32))
+    }
+
+    /// Evaluates `exprs` adjusted to exactly `want` values into registers starting at `base`, which must be the
+    /// first free register. Leaves those registers reserved.
+    fn expr_list_to(&mut self, exprs: &[Expr], base: u32, want: u32) -> Result<(), CompileError> {
+        debug_assert_eq!(base, self.fs().free_reg);
+        let mut produced = 0;
+        for (i, expr) in exprs.iter().enumerate() {
+            let reg = self.reserve(1)?;
+            if i + 1 == exprs.len() && expr.is_multi() {
+                let extra = want.saturating_sub(i as u32);
+                self.multi(expr, reg, Some(extra))?;
+                produced = i as u32 + extra;
+                self.set_free_reg(reg);
+                self.reserve(extra)?;
+                break;
+            }
+            self.expr(expr, reg)?;
+            produced += 1;
+        }
+        if produced < want {
+            let first = base + produced;
+            self.set_free_reg(first);
+            self.reserve(want - produced)?;
+            self.emit_abc(OpCode::LoadNil, first, want - produced - 1, 0);
+        }
+        self.set_free_reg(base + want);
+        Ok(())
+    }
+
+    /// Evaluates a call or `...` into `base`, which must be the last reserved register, keeping `results`
+    /// values, or all of them if `None`.
+    fn multi(&mut self, expr: &Expr, base: u32, results: Option<u32>) -> Result<(), CompileError> {
+        match expr {
+            Expr::Call { .. } | Expr::MethodCall { .. } => self.call(expr, base, results),
+            Expr::Vararg(_) => {
+                let b = results.map_or(0, |n| n + 1);
+                if b > MAX_B {
+                    return Err(self.error("function or expression needs too many registers"));
+                }
+                self.emit_abc(OpCode::VarArg, base, b, 0);
+                Ok(())
+            }
+            _ => unreachable!("only calls and varargs produce multiple values"),
+        }
+    }
+
+    /// Generates a call with the function at `base`, which must be the last reserved register.
+    fn call(&mut self, expr: &Expr, base: u32, results: Option<u32>) -> Result<(), CompileError> {
+        debug_assert_eq!(base + 1, self.fs().free_reg);
+        let args = match expr {
+            Expr::Call { func, args, .. } => {
+                self.expr(func, base)?;
+                args
+            }
+            Expr::MethodCall {
+                object,
+                method,
+                args,
+                ..
+            } => {
+                let object = self.expr_any(object)?;
+                let key = self.string_constant(&method.name)?;
+                let key = self.constant_rk(key)?;
+                self.emit_abc(OpCode::Method, base, object, key);
+                self.set_free_reg(base + 1);
+                self.reserve(1)?;
+                args
+            }
+            _ => unreachable!("not a call"),
+        };
+        let span = self.span;
+        self.span = expr.span();
+
+        let nargs = match self.expr_list(args)? {
+            Some(n) => n + u32::from(matches!(expr, Expr::MethodCall { .. })) + 1,
+            None => 0,
+        };
+        let c = results.map_or(0, |n| n + 1);
+        if c > MAX_C {
+            return Err(self.error("function or expression needs too many registers"));
+        }
+        self.emit_abc(OpCode::Call, base, nargs, c);
+        self.span = span;
+        self.set_free_reg(base + 1);
+        Ok(())
+    }
+
+    /// Evaluates an expression into a register that is fresh, even if the expression is a local.
+    fn expr_to_new_reg(&mut self, expr: &Expr) -> Result<u32, CompileError> {
+        let reg = self.reserve(1)?;
+        self.expr(expr, reg)?;
+        Ok(reg)
+    }
+
+    /// Evaluates an expression into some register: the local's own register if it is one, otherwise a fresh one.
+    fn expr_any(&mut self, expr: &Expr) -> Result<u32, CompileError> {
+        if let Expr::Name(name) = expr {
+            if let Var::Local(reg) = self.resolve(&name.name)? {
+                return Ok(reg);
+            }
+        }
+        self.expr_to_new_reg(expr)
+    }
+
+    /// Evaluates an expression into an `RK` operand.
+    fn expr_rk(&mut self, expr: &Expr) -> Result<u32, CompileError> {
+        if let Some(constant) = fold(expr) {
+            let index = self.constant(constant)?;
+            if index <= MAX_RK_INDEX {
+                return Ok(bytecode::rk_constant(index));
+            }
+        }
+        self.expr_any(expr)
+    }
+
+    fn load_constant(&mut self, constant: Constant, dst: u32) -> Result<(), CompileError> {
+        match constant {
+            Constant::Nil => {
+                self.emit_abc(OpCode::LoadNil, dst, 0, 0);
+            }
+            Constant::Boolean(b) => {
+                self.emit_abc(OpCode::LoadBool, dst, b as u32, 0);
+            }
+            Constant::Integer(i) if (-MAX_SBX as i64..=MAX_SBX as i64).contains(&i) => {
+                self.emit(Instruction::asbx(OpCode::LoadI, dst, i as i32));
+            }
+            constant => {
+                let index = self.constant(constant)?;
+                self.emit_abx(OpCode::LoadK, dst, index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates an expression into `dst`, adjusted to a single value. Temporaries used along the way are freed.
+    fn expr(&mut self, expr: &Expr, dst: u32) -> Result<(), CompileError> {
+        let free_reg = self.fs().free_reg;
+        self.expr_inner(expr, dst)?;
+        self.set_free_reg(free_reg);
+        Ok(())
+    }
+
+    fn expr_inner(&mut self, expr: &Expr, dst: u32) -> Result<(), CompileError> {
+        // The instructions point at the expression they evaluate, so that runtime errors can.
+        let span = mem::replace(&mut self.span, expr.span());
+        let result = self.expr_at(expr, dst);
+        self.span = span;
+        result
+    }
+
+    fn expr_at(&mut self, expr: &Expr, dst: u32) -> Result<(), CompileError> {
+        if let Some(constant) = fold(expr) {
+            return self.load_constant(constant, dst);
+        }
+
+        // Expressions that write `dst` before they are done reading their operands go through a temporary when
+        // `dst` is a local, as in `x = {x}` or `x = y and x`.
+        let writes_early = matches!(
+            expr,
+            Expr::Table { .. }
+                | Expr::Binary {
+                    op: BinOp::And | BinOp::Or,
+                    ..
+                }
+        );
+        if writes_early && dst < self.num_locals() {
+            let tmp = self.reserve(1)?;
+            self.expr_inner(expr, tmp)?;
+            self.emit_abc(OpCode::Move, dst, tmp, 0);
+            return Ok(());
+        }
+
+        match expr {
+            Expr::Nil(_)
+            | Expr::True(_)
+            | Expr::False(_)
+            | Expr::Integer(..)
+            | Expr::Float(..)
+            | Expr::String(..) => unreachable!("literals are folded"),
+            Expr::Vararg(_) => {
+                self.emit_abc(OpCode::VarArg, dst, 2, 0);
+            }
+            Expr::Function(body) => self.function(body, dst)?,
+            Expr::Table { fields, .. } => self.table(fields, dst)?,
+            Expr::Binary { op, lhs, rhs, .. } => self.binary(*op, lhs, rhs, dst)?,
+            Expr::Unary { op, operand, .. } => {
+                let op = match op {
+                    UnOp::Neg => OpCode::Unm,
+                    UnOp::Not => OpCode::Not,
+                    UnOp::Len => OpCode::Len,
+                    UnOp::BitNot => OpCode::BNot,
+                };
+                let operand = self.expr_any(operand)?;
+                self.emit_abc(op, dst, operand, 0);
+            }
+            Expr::Name(name) => match self.resolve(&name.name)? {
+                Var::Local(reg) => {
+                    if reg != dst {
+                        self.emit_abc(OpCode::Move, dst, reg, 0);
+                    }
+                }
+                Var::Upvalue(index) => {
+                    self.emit_abc(OpCode::GetUpval, dst, index, 0);
+                }
+                Var::Global => match self.resolve_global(&name.name)? {
+                    (Var::Local(env), key) => {
+                        self.emit_abc(OpCode::GetTable, dst, env, key);
+                    }
+                    (Var::Upvalue(env), key) => {
+                        self.emit_abc(OpCode::GetTabUp, dst, env, key);
+                    }
+                    (Var::Global, _) => unreachable!("`_ENV` is always in scope"),
+                },
+            },
+            Expr::Index { object, key, .. } => {
+                let object = self.expr_any(object)?;
+                let key = self.expr_rk(key)?;
+                self.emit_abc(OpCode::GetTable, dst, object, key);
+            }
+            Expr::Call { .. } | Expr::MethodCall { .. } => {
+                if dst >= self.num_locals() && dst + 1 == self.fs().free_reg {
+                    self.call(expr, dst, Some(1))?;
+                } else {
+                    let base = self.reserve(1)?;
+                    self.call(expr, base, Some(1))?;
+                    self.emit_abc(OpCode::Move, dst, base, 0);
+                }
+            }
+            Expr::Paren(inner, _) => self.expr_inner(inner, dst)?,
+        }
+        Ok(())
+    }
+
+    fn binary(&mut self, op: BinOp, lhs: &Expr, rhs: &Expr, dst: u32) -> Result<(), CompileError> {
+        let arith = match op {
+            BinOp::Add => Some(OpCode::Add),
+            BinOp::Sub => Some(OpCode::Sub),
+            BinOp::Mul => Some(OpCode::Mul),
+            BinOp::Div => Some(OpCode::Div),
+            BinOp::IDiv => Some(OpCode::IDiv),
+            BinOp::Mod => Some(OpCode::Mod),
+            BinOp::Pow => Some(OpCode::Pow),
+            BinOp::BitAnd => Some(OpCode::BAnd),
+            BinOp::BitOr => Some(OpCode::BOr),
+            BinOp::BitXor => Some(OpCode::BXor),
+            BinOp::Shl => Some(OpCode::Shl),
+            BinOp::Shr => Some(OpCode::Shr),
+            _ => None,
+        };
+        if let Some(opcode) = arith {
+            let b = self.expr_rk(lhs)?;
+            let c = self.expr_rk(rhs)?;
+            self.emit_abc(opcode, dst, b, c);
+            return Ok(());
+        }
+
+        match op {
+            BinOp::Concat => {
+                // Concatenation is right associative, so `a .. b .. c` is flattened into one instruction over
+                // consecutive registers.
+                let mut operands = vec![lhs];
+                let mut rest = rhs;
+                while let Expr::Binary {
+                    op: BinOp::Concat,
+                    lhs,
+                    rhs,
+                    ..
+                } = rest
+                {
+                    operands.push(lhs);
+                    rest = rhs;
+                }
+                operands.push(rest);
+                let first = self.fs().free_reg;
+                for operand in operands.iter().copied() {
+                    self.expr_to_new_reg(operand)?;
+                }
+                let last = self.fs().free_reg - 1;
+                self.emit_abc(OpCode::Concat, dst, first, last);
+            }
+            BinOp::And | BinOp::Or => {
+                self.expr(lhs, dst)?;
+                self.emit_abc(OpCode::Test, dst, 0, (op == BinOp::Or) as u32);
+                let skip = self.emit_jump();
+                self.expr(rhs, dst)?;
+                self.patch_to_here(vec![skip])?;
+            }
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                let free_reg = self.fs().free_reg;
+                let when_true = self.compare(op, lhs, rhs, true)?;
+                self.set_free_reg(free_reg);
+                self.emit_abc(OpCode::LoadBool, dst, 0, 1);
+                self.patch_to_here(when_true)?;
+                self.emit_abc(OpCode::LoadBool, dst, 1, 0);
+            }
+            _ => unreachable!("arithmetic and bitwise operators are handled above"),
+        }
+        Ok(())
+    }
+
+    /// Generates code that jumps when the truthiness of `expr` is `jump_if`, and falls through otherwise.
+    /// Returns the jumps to patch to their destination.
+    fn cond(&mut self, expr: &Expr, jump_if: bool) -> Result<Vec<usize>, CompileError> {
+        let free_reg = self.fs().free_reg;
+        let jumps = self.cond_inner(expr, jump_if)?;
+        self.set_free_reg(free_reg);
+        Ok(jumps)
+    }
+
+    fn cond_inner(&mut self, expr: &Expr, jump_if: bool) -> Result<Vec<usize>, CompileError> {
+        if let Some(constant) = fold(expr) {
+            let truthy = !matches!(constant, Constant::Nil | Constant::Boolean(false));
+            return Ok(if truthy == jump_if {
+                vec![self.emit_jump()]
+            } else {
+                Vec::new()
+            });
+        }
+        match expr {
+            Expr::Unary {
+                op: UnOp::Not,
+                operand,
+                ..
+            } => self.cond(operand, !jump_if),
+            Expr::Paren(inner, _) => self.cond(inner, jump_if),
+            Expr::Binary {
+                op: op @ (BinOp::And | BinOp::Or),
+                lhs,
+                rhs,
+                ..
+            } => {
+                // `a and b` is false as soon as `a` is, and `a or b` is true as soon as `a` is.
+                let short_circuit = *op == BinOp::Or;
+                if jump_if == short_circuit {
+                    let mut jumps = self.cond(lhs, jump_if)?;
+                    jumps.extend(self.cond(rhs, jump_if)?);
+                    Ok(jumps)
+                } else {
+                    let skip = self.cond(lhs, !jump_if)?;
+                    let jumps = self.cond(rhs, jump_if)?;
+                    self.patch_to_here(skip)?;
+                    Ok(jumps)
+                }
+            }
+            Expr::Binary {
+                op: op @ (BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge),
+                lhs,
+                rhs,
+                ..
+            } => self.compare(*op, lhs, rhs, jump_if),
+            _ => {
+                let reg = self.expr_any(expr)?;
+                self.emit_abc(OpCode::Test, reg, 0, jump_if as u32);
+                Ok(vec![self.emit_jump()])
+            }
+        }
+    }
+
+    /// Generates a comparison followed by a jump taken when its result is `jump_if`.
+    fn compare(
+        &mut self,
+        op: BinOp,
+        lhs: &Expr,
+        rhs: &Expr,
+        jump_if: bool,
+    ) -> Result<Vec<usize>, CompileError> {
+        let b = self.expr_rk(lhs)?;
+        let c = self.expr_rk(rhs)?;
+        // `a > b` is `b < a`, and `a ~= b` is the jump of `a == b` taken the other way.
+        let (opcode, b, c, expect) = match op {
+            BinOp::Eq => (OpCode::Eq, b, c, jump_if),
+            BinOp::Ne => (OpCode::Eq, b, c, !jump_if),
+            BinOp::Lt => (OpCode::Lt, b, c, jump_if),
+            BinOp::Le => (OpCode::Le, b, c, jump_if),
+            BinOp::Gt => (OpCode::Lt, c, b, jump_if),
+            BinOp::Ge => (OpCode::Le, c, b, jump_if),
+            _ => unreachable!("not a comparison"),
+        };
+        self.emit_abc(opcode, expect as u32, b, c);
+        Ok(vec![self.emit_jump()])
+    }
+
+    fn table(&mut self, fields: &[TableField], dst: u32) -> Result<(), CompileError> {
+        let array = fields
+            .iter()
+            .filter(|f| matches!(f, TableField::Positional(_)))
+            .count();
+        let hash = fields.len() - array;
+        self.emit_abc(
+            OpCode::NewTable,
+            dst,
+            (array as u32).min(MAX_B),
+            (hash as u32).min(MAX_C),
+        );
+
+        let first = self.fs().free_reg;
+        let mut pending = 0;
+        let mut batch = 1;
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                TableField::Positional(value) => {
+                    let reg = self.reserve(1)?;
+                    if i + 1 == fields.len() && value.is_multi() {
+                        self.multi(value, reg, None)?;
+                        self.set_list(dst, 0, batch)?;
+                        self.set_free_reg(first);
+                        return Ok(());
+                    }
+                    self.expr(value, reg)?;
+                    pending += 1;
+                    if pending == FIELDS_PER_FLUSH {
+                        self.set_list(dst, pending, batch)?;
+                        self.set_free_reg(first);
+                        pending = 0;
+                        batch += 1;
+                    }
+                }
+                TableField::Named(name, value) => {
+                    let free_reg = self.fs().free_reg;
+                    let key = self.string_constant(&name.name)?;
+                    let key = self.constant_rk(key)?;
+                    let value = self.expr_rk(value)?;
+                    self.emit_abc(OpCode::SetTable, dst, key, value);
+                    self.set_free_reg(free_reg);
+                }
+                TableField::Keyed(key, value) => {
+                    let free_reg = self.fs().free_reg;
+                    let key = self.expr_rk(key)?;
+                    let value = self.expr_rk(value)?;
+                    self.emit_abc(OpCode::SetTable, dst, key, value);
+                    self.set_free_reg(free_reg);
+                }
+            }
+        }
+        if pending > 0 {
+            self.set_list(dst, pending, batch)?;
+        }
+        self.set_free_reg(first);
+        Ok(())
+    }
+
+    fn set_list(&mut self, table: u32, count: u32, batch: u32) -> Result<(), CompileError> {
+        if batch <= MAX_C {
+            self.emit_abc(OpCode::SetList, table, count, batch);
+        } else {
+            self.emit_abc(OpCode::SetList, table, count, 0);
+            self.emit_abx(OpCode::ExtraArg, 0, batch);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the constant an expression evaluates to, if it is a literal or an operation on constants
+/// that can be evaluated at compile time.
+///
+/// Operations that would raise an error, like integer division by zero or a bitwise operation on a
+/// float with no integer representation, are left for the interpreter to report.
+fn fold(expr: &Expr) -> Option<Constant> {
+    Some(match expr {
+        Expr::Nil(_) => Constant::Nil,
+        Expr::True(_) => Constant::Boolean(true),
+        Expr::False(_) => Constant::Boolean(false),
+        Expr::Integer(i, _) => Constant::Integer(*i),
+        Expr::Float(f, _) => Constant::Float(f.to_bits()),
+        Expr::String(s, _) => Constant::String(s.clone()),
+        Expr::Paren(inner, _) => fold(inner)?,
+        Expr::Unary { op, operand, .. } => {
+            let x = fold_number(operand)?;
+            match op {
+                UnOp::Neg => number_constant(ops::arith(ArithOp::Unm, x, x).ok()??),
+                UnOp::BitNot => number_constant(ops::bitwise(BitOp::Not, x, x).ok()??),
+                UnOp::Not | UnOp::Len => return None,
+            }
+        }
+        Expr::Binary {
+            op: BinOp::Concat,
+            lhs,
+            rhs,
+            ..
+        } => {
+            let mut bytes = Vec::new();
+            for operand in [lhs, rhs] {
+                match fold(operand)? {
+                    Constant::String(s) => bytes.extend_from_slice(&s),
+                    constant => {
+                        ops::write_concat_operand(&mut bytes, number_value(&constant)?);
+                    }
+                }
+            }
+            Constant::String(bytes)
+        }
+        Expr::Binary { op, lhs, rhs, .. } => {
+            let (x, y) = (fold_number(lhs)?, fold_number(rhs)?);
+            let result = match op {
+                BinOp::Add => ops::arith(ArithOp::Add, x, y),
+                BinOp::Sub => ops::arith(ArithOp::Sub, x, y),
+                BinOp::Mul => ops::arith(ArithOp::Mul, x, y),
+                BinOp::Div => ops::arith(ArithOp::Div, x, y),
+                BinOp::IDiv => ops::arith(ArithOp::IDiv, x, y),
+                BinOp::Mod => ops::arith(ArithOp::Mod, x, y),
+                BinOp::Pow => ops::arith(ArithOp::Pow, x, y),
+                BinOp::BitAnd => ops::bitwise(BitOp::And, x, y),
+                BinOp::BitOr => ops::bitwise(BitOp::Or, x, y),
+                BinOp::BitXor => ops::bitwise(BitOp::Xor, x, y),
+                BinOp::Shl => ops::bitwise(BitOp::Shl, x, y),
+                BinOp::Shr => ops::bitwise(BitOp::Shr, x, y),
+                _ => return None,
+            };
+            number_constant(result.ok()??)
+        }
+        _ => return None,
+    })
+}
+
+/// Folds an operand of an arithmetic or bitwise operation. Strings are not folded, since whether
+/// they convert to numbers is only decided at runtime.
+fn fold_number(expr: &Expr) -> Option<Value<'static>> {
+    number_value(&fold(expr)?)
+}
+
+fn number_value(constant: &Constant) -> Option<Value<'static>> {
+    match *constant {
+        Constant::Integer(i) => Some(Value::Integer(i)),
+        Constant::Float(bits) => Some(Value::Number(f64::from_bits(bits))),
+        _ => None,
+    }
+}
+
+fn number_constant(value: Value<'_>) -> Constant {
+    match value {
+        Value::Integer(i) => Constant::Integer(i),
+        Value::Number(n) => Constant::Float(n.to_bits()),
+        _ => unreachable!("arithmetic on numbers produces numbers"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::OpCode;
+    use crate::compiler::{compile, compile_with, CompileOptions};
+    use crate::mem::Arena;
+    use crate::vm::{self, Thread};
+    use crate::{Closure, Context, State, StateRoot, Value};
+
+    /// Compiles and runs `source`, returning its results separated by commas. Checks that the
+    /// peephole pass doesn't change the outcome.
+    fn run(source: &str) -> Result<String, String> {
+        let result = run_with(source, CompileOptions::default());
+        assert_eq!(
+            result,
+            run_with(
+                source,
+                CompileOptions {
+                    optimize: 0,
+                    ..CompileOptions::default()
+                },
+            )
+        );
+        result
+    }
+
+    #[allow(clippy::redundant_closure)]
+    fn new_arena() -> Arena<StateRoot> {
+        Arena::new(|mc| State::new(mc))
+    }
+
+    fn run_with(source: &str, options: CompileOptions) -> Result<String, String> {
+        new_arena().mutate(|mc, state| exec(Context::new(mc, state), source, options))
+    }
+
+    fn exec(ctx: Context<'_>, source: &str, options: CompileOptions) -> Result<String, String> {
+        let proto =
+            compile_with(&ctx, source.as_bytes(), "test", options).map_err(|e| e.to_string())?;
+        let closure = Closure::with_env(&ctx, proto, Value::Table(ctx.globals()));
+        let results = vm::call(ctx, Thread::new(&ctx), Value::Function(closure.into()), &[])
+            .map_err(|e| e.to_string())?;
+        Ok(results
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(", "))
+    }
+
+    #[test]
+    fn expressions_and_locals() {
+        assert_eq!(
+            run("local a, b = 3, 4 local c = a * b + 2 return c, a - b, 7 // 2, -a").unwrap(),
+            "14, -1, 3, -3"
+        );
+        assert_eq!(
+            run("local a, b, c = 1 return a, b, c").unwrap(),
+            "1, nil, nil"
+        );
+        assert_eq!(
+            run("local a, b = 1, 2 a, b = b, a return a, b").unwrap(),
+            "2, 1"
+        );
+        assert_eq!(
+            run("x = 5 local y = x .. 'a' .. 1 return y, #y").unwrap(),
+            "5a1, 3"
+        );
+        assert_eq!(
+            run("local a = nil return a or 'd', a and 1, 1 and 2, not a").unwrap(),
+            "d, nil, 2, true"
+        );
+        assert_eq!(
+            run("return 1 < 2, 2 <= 1, 3 > 2, 'a' ~= 'a', 1 == 1.0").unwrap(),
+            "true, false, true, false, true"
+        );
+        assert_eq!(
+            run("return 9007199254740993, 0.5 * 3").unwrap(),
+            "9007199254740993, 1.5"
+        );
+    }
+
+    /// Compiles `source` and returns the opcodes of its main function.
+    fn opcodes(source: &str) -> Vec<OpCode> {
+        new_arena().mutate(|mc, _| {
+            let proto = compile(mc, source.as_bytes(), "test").unwrap();
+            proto.code.iter().map(|i| i.opcode().unwrap()).collect()
+        })
+    }
+
+    #[test]
+    fn constant_folding() {
+        assert_eq!(
+            opcodes("return 2^10, 60 * 60 * 24, 'a' .. 1 .. -(2.5)"),
+            [OpCode::LoadK, OpCode::LoadI, OpCode::LoadK, OpCode::Return]
+        );
+
+        assert_eq!(
+            run("return 2^10, 60 * 60 * 24, 7 // 2.0, -(-3), 1 << 62, 0xF0 | ~0xFF, 3 >> 1.0")
+                .unwrap(),
+            "1024.0, 86400, 3.0, 3, 4611686018427387904, -16, 1"
+        );
+        assert_eq!(run("return 'a' .. 1 .. 2.0, 1 .. ''").unwrap(), "a12.0, 1");
+        assert_eq!(
+            run("return 1 / 0, -1 % 0.0 ~= -1 % 0.0").unwrap(),
+            "inf, true"
+        );
+        assert_eq!(
+            run("local x = 1 // 0").unwrap_err(),
+            "test:1: attempt to perform 'n//0'"
+        );
+        assert_eq!(run("if 1 + 1 then return 'yes' end").unwrap(), "yes");
+    }
+
+    #[test]
+    fn control_flow() {
+        let source = "
+            local s = 0
+            for i = 1, 10 do
+                if i % 2 == 0 then s = s + i elseif i == 9 then break else s = s - 1 end
+            end
+            local n = 0
+            while true do n = n + 1 if n >= 5 then break end end
+            repeat local m = n n = n - 2 until m < 3
+            for i = 3, 1, -1 do s = s * 10 + i end
+            return s, n
+        ";
+        assert_eq!(run(source).unwrap(), "16321, -1");
+        assert_eq!(run("for i = 1.0, 2 do return i end").unwrap(), "1.0");
+    }
+
+    #[test]
+    fn number_subtypes() {
+        // Operands are locals so that the interpreter does the arithmetic, not the folder.
+        let prelude = "
+            local max = 9223372036854775807
+            local min = max + 1
+            local zero, inf = 0, 1 / 0
+            local big, f53 = 9007199254740993, 2.0 ^ 53
+            return %s
+        ";
+        let cases = [
+            (
+                "min, min - 1 == max, max * 2",
+                "-9223372036854775808, true, -2",
+            ),
+            (
+                "7 // (zero + 2), -7 // 2, 7 // -2, 7.5 // 2",
+                "3, -4, -4, 3.0",
+            ),
+            ("7 % -3, -7 % 3, -7.5 % 2, 6 % -3", "-2, 2, 0.5, 0"),
+            ("min // -1, min % -1", "-9223372036854775808, 0"),
+            (
+                "5 % inf, -5 % inf, 5 % -inf, 1 // (zero + 0.0)",
+                "5.0, inf, -inf, inf",
+            ),
+            ("3 / 2, 4 / 2, 2 ^ 2, zero + 0.0", "1.5, 2.0, 4.0, 0.0"),
+            (
+                "big < f53, big <= f53, big > f53, big == f53",
+                "false, false, true, false",
+            ),
+            (
+                "max < 2 ^ 63, min < -2 ^ 63, min <= -2 ^ 63",
+                "true, false, true",
+            ),
+            ("1 == 1.0, zero < 0 / 0, 0 / 0 < zero", "true, false, false"),
+        ];
+        for (exprs, expected) in cases {
+            assert_eq!(
+                run(&prelude.replace("%s", exprs)).unwrap(),
+                expected,
+                "{exprs}"
+            );
+        }
+        assert_eq!(
+            run("local z = 0 return 1 // z").unwrap_err(),
+            "test:1: attempt to perform 'n//0'"
+        );
+        assert_eq!(
+            run("local z = 0 return 1 % z").unwrap_err(),
+            "test:1: attempt to perform 'n%0'"
+        );
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let prelude = "
+            local a, b, n = 0xF0, 0x3C, 1
+            local whole, s = 2.0, '0x10'
+            return %s
+        ";
+        let cases = [
+            ("a & b, a | b, a ~ b, ~a", "48, 252, 204, -241"),
+            (
+                "n << 4, a >> n, n << 64, n << -1, -1 >> 63",
+                "16, 120, 0, 0, 1",
+            ),
+            (
+                "n << 63, (n << 63) >> 63, -1 >> 1",
+                "-9223372036854775808, 1, 9223372036854775807",
+            ),
+            ("whole | n, s & 0xFF, ' 3 ' | 0, '1e1' ~ 0", "3, 16, 3, 10"),
+            ("a | b ~ n & a << n", "252"),
+        ];
+        for (exprs, expected) in cases {
+            assert_eq!(
+                run(&prelude.replace("%s", exprs)).unwrap(),
+                expected,
+                "{exprs}"
+            );
+        }
+        assert_eq!(
+            run("local x = 1.5 return x | 0").unwrap_err(),
+            "test:1: number (local 'x') has no integer representation"
+        );
+        assert_eq!(
+            run("local s = '1.5' return ~s").unwrap_err(),
+            "test:1: number has no integer representation"
+        );
+        assert_eq!(
+            run("local t = {} return 1 & t").unwrap_err(),
+            "test:1: attempt to perform bitwise operation on a table value (local 't')"
+        );
+        assert_eq!(
+            run("local s = 'x' return s >> 1").unwrap_err(),
+            "test:1: attempt to perform bitwise operation on a string value (local 's')"
+        );
+    }
+
+    #[test]
+    fn for_loops_near_integer_limits() {
+        let source = "
+            local max = 9223372036854775807
+            local min = max + 1
+            local function count(a, b, c)
+                local n, last = 0
+                for i = a, b, c or 1 do n, last = n + 1, i end
+                return n, last
+            end
+            return %s
+        ";
+        let cases = [
+            ("count(max - 2, max)", "3, 9223372036854775807"),
+            ("count(min, min + 2)", "3, -9223372036854775806"),
+            ("count(min + 1, min, -1)", "2, -9223372036854775808"),
+            ("count(0, max, max // 2 + 1)", "2, 4611686018427387904"),
+            ("count(max, min, min)", "2, -1"),
+            ("count(1, 0)", "0, nil"),
+            ("count(1, 3.5)", "3, 3"),
+            ("count(1, -1 / 0)", "0, nil"),
+            ("count(1, 2, 0.5)", "3, 2.0"),
+            ("count(max, 2^63, -1)", "0, nil"),
+            ("count(min, -2^63 - 2^11, 1)", "0, nil"),
+            ("count(1, 0/0)", "0, nil"),
+            ("count(1.0, 0/0)", "1, 1.0"),
+            ("count('1', 2)", "2, 2.0"),
+            ("count(1, '0x2')", "2, 2"),
+        ];
+        for (exprs, expected) in cases {
+            assert_eq!(
+                run(&source.replace("%s", exprs)).unwrap(),
+                expected,
+                "{exprs}"
+            );
+        }
+        assert_eq!(
+            run("for i = 1, 10, 0 do end").unwrap_err(),
+            "test:1: 'for' step is zero"
+        );
+        assert_eq!(
+            run("for i = 1, 'x' do end").unwrap_err(),
+            "test:1: bad 'for' limit (number expected, got string)"
+        );
+        assert_eq!(
+            run("for i = {}, 1.5 do end").unwrap_err(),
+            "test:1: bad 'for' initial value (number expected, got table)"
+        );
+    }
+
+    #[test]
+    fn tables_and_functions() {
+        let items = (1..=120)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        assert_eq!(
+            run(&format!("local t = {{{items}}} return #t, t[1], t[120]")).unwrap(),
+            "120, 1, 120"
+        );
+
+        let source = "
+            local function pair(a) return a, a * 2 end
+            local t = {x = 'x', [2 + 3] = 'five', pair(1), pair(10)}
+            function t.sum(a, b, c) return a + b + c end
+            local obj = {n = 3}
+            function obj:get(k) return self.n * k end
+            local function iter(t, i)
+                i = i + 1
+                if t[i] then return i, t[i] end
+            end
+            local total = 0
+            for i, v in iter, t, 0 do total = total + i * v end
+            return t[3], t[5], t.sum(1, pair(2)), obj:get(2), total, t.x
+        ";
+        assert_eq!(run(source).unwrap(), "20, five, 7, 6, 81, x");
+    }
+
+    #[test]
+    fn varargs_and_multiple_results() {
+        let source = "
+            local function id(...) return ... end
+            local function first(a, ...) return a end
+            local function rest(a, ...) return ... end
+            local function three() return 1, 2, 3 end
+            return %s
+        ";
+        let cases = [
+            ("id(1, 2, 3)", "1, 2, 3"),
+            ("id()", ""),
+            ("(id(1, 2, 3))", "1"),
+            ("id(1, 2), 10", "1, 10"),
+            ("id(three())", "1, 2, 3"),
+            ("id(three(), three())", "1, 1, 2, 3"),
+            ("first(), first(4, 5)", "nil, 4"),
+            ("rest(1), rest(1, 2, 3)", "nil, 2, 3"),
+            (
+                "#{id(1, 2, 3)}, #{id(1, 2, 3), 4}, #{three(), three()}",
+                "3, 2, 4",
+            ),
+        ];
+        for (exprs, expected) in cases {
+            assert_eq!(
+                run(&source.replace("%s", exprs)).unwrap(),
+                expected,
+                "{exprs}"
+            );
+        }
+
+        assert_eq!(
+            run("local function f(...) local a, b, c = ... return c, b, a end return f(1, 2)")
+                .unwrap(),
+            "nil, 2, 1"
+        );
+        // Fixed parameters of a vararg function can be captured and assigned like any other.
+        assert_eq!(
+            run("local function f(n, ...)
+                    local extra = select2(...)
+                    local g = function() n = n + extra return n end
+                    return g(), g()
+                end
+                function select2(a, b) return b end
+                return f(1, 10, 20)")
+            .unwrap(),
+            "21, 41"
+        );
+        // The main chunk receives the arguments it is called with.
+        assert_eq!(
+            run("local a, b = ... return a, b, ...").unwrap(),
+            "nil, nil"
+        );
+    }
+
+    #[test]
+    fn tail_calls() {
+        // Far deeper than the frame limit, so this only finishes if tail calls reuse the frame.
+        let source = "
+            local odd
+            local function even(n) if n == 0 then return true end return odd(n - 1) end
+            function odd(n) if n == 0 then return false end return even(n - 1) end
+            return even(300001), odd(300001)
+        ";
+        assert_eq!(run(source).unwrap(), "false, true");
+
+        let source = "
+            local obj = {n = 2}
+            function obj:scale(a, b) return a * self.n, b * self.n end
+            local function f(a, b) return obj:scale(a, b) end
+            local function g(...) return f(...) end
+            return g(3, 4)
+        ";
+        assert_eq!(run(source).unwrap(), "6, 8");
+
+        // A tail call still closes the caller's upvalues.
+        let source = "
+            local function id(f) return f end
+            local function make() local x = 5 return id(function() return x end) end
+            local g = make()
+            local t = {make()}
+            return g(), t[1]()
+        ";
+        assert_eq!(run(source).unwrap(), "5, 5");
+
+        assert_eq!(
+            opcodes("return f(1)"),
+            [
+                OpCode::GetTabUp,
+                OpCode::LoadI,
+                OpCode::TailCall,
+                OpCode::Return
+            ]
+        );
+        assert!(!opcodes("return (f(1))").contains(&OpCode::TailCall));
+    }
+
+    #[test]
+    fn closures_and_upvalues() {
+        let source = "
+            local function counter()
+                local n = 0
+                return function() n = n + 1 return n end, function() return n end
+            end
+            local inc, get = counter()
+            inc() inc()
+            local inc2 = counter()
+            inc2()
+
+            local fns = {}
+            for i = 1, 3 do fns[i] = function() return i end end
+            local j = 0
+            while j < 3 do j = j + 1 local k = j * 10 fns[#fns + 1] = function() return k end end
+            local t = {}
+            repeat local r = #t t[#t + 1] = function() return r end until r >= 2
+
+            local function outer()
+                local a = 1
+                return function() return function() a = a + 1 return a end end
+            end
+            local deep = outer()()
+            deep()
+            return get(), inc2(), fns[1]() + fns[2]() + fns[3](), fns[4]() + fns[6](), t[1]() + t[3](), deep()
+        ";
+        assert_eq!(run(source).unwrap(), "2, 2, 6, 40, 2, 3");
+
+        let source = "
+            local fs = {}
+            for i = 1, 10 do
+                local x = i
+                fs[i] = function() x = x * 2 return x end
+                if i == 2 then break end
+            end
+            local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end
+            return fs[1]() + fs[2](), fs[1](), fib(15)
+        ";
+        assert_eq!(run(source).unwrap(), "6, 4, 610");
+    }
+
+    #[test]
+    fn closed_upvalues_survive_collection() {
+        let mut arena = new_arena();
+        let options = CompileOptions::default();
+        arena.mutate(|mc, state| {
+            let source = "local t = {v = 'kept'} function get() return t.v end";
+            exec(Context::new(mc, state), source, options).unwrap();
+        });
+        arena.collect_all();
+        arena.mutate(|mc, state| {
+            let result = exec(Context::new(mc, state), "return get()", options);
+            assert_eq!(result.unwrap(), "kept");
+        });
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            run("local x = 1\nlocal t = nil\nreturn t.x").unwrap_err(),
+            "test:3: attempt to index a nil value (local 't')"
+        );
+        assert_eq!(
+            run("if x then\nbreak end").unwrap_err(),
+            "2: break outside a loop at line 2"
+        );
+        let locals: Vec<_> = (0..201).map(|i| format!("local a{i}")).collect();
+        let locals = locals.join(" ");
+        assert!(run(&locals)
+            .unwrap_err()
+            .contains("too many local variables"));
+    }
+
+    #[test]
+    fn goto_and_labels() {
+        let continue_loop = "
+            local sum = 0
+            for i = 1, 10 do
+                if i % 2 == 0 then goto continue end
+                local odd = i
+                sum = sum + odd
+                ::continue::
+            end
+            return sum
+        ";
+        assert_eq!(run(continue_loop).unwrap(), "25");
+
+        // Each pass around the backward jump gets a fresh `x` for the closures to capture.
+        let backward = "
+            local fs, i = {}, 1
+            ::top::
+            local x = i * 10
+            fs[i] = function() return x end
+            i = i + 1
+            if i <= 3 then goto top end
+            return fs[1](), fs[2](), fs[3]()
+        ";
+        assert_eq!(run(backward).unwrap(), "10, 20, 30");
+
+        let nested = "
+            local n = 0
+            for i = 1, 3 do
+                for j = 1, 3 do
+                    n = n + 1
+                    if i * j == 4 then goto done end
+                end
+            end
+            ::done::
+            do goto skip end
+            n = -1
+            ::skip::
+            return n
+        ";
+        assert_eq!(run(nested).unwrap(), "5");
+
+        let errors = [
+            (
+                "goto l local x ::l:: print(x)",
+                "jumps into the scope of local 'x'",
+            ),
+            (
+                "do ::l:: end goto l",
+                "no visible label 'l' for <goto> at line 1",
+            ),
+            (
+                "goto l do ::l:: end",
+                "no visible label 'l' for <goto> at line 1",
+            ),
+            ("::l:: do ::l:: end", "label 'l' already defined on line 1"),
+            (
+                "::l:: local f = function() goto l end",
+                "no visible label 'l'",
+            ),
+            (
+                "repeat goto l local x ::l:: until x",
+                "jumps into the scope of local 'x'",
+            ),
+        ];
+        for (source, expected) in errors {
+            let err = run(source).unwrap_err();
+            assert!(err.contains(expected), "{source}: {err}");
+        }
+        // A label ending a block is past its locals, so this jump is fine.
+        assert_eq!(run("do goto l local x ::l:: end return 1").unwrap(), "1");
+    }
+
+    #[test]
+    fn local_attributes() {
+        assert_eq!(
+            run("local x <const>, y = 1, 2 y = x + y return y").unwrap(),
+            "3"
+        );
+        let errors = [
+            (
+                "local x <const> = 1 x = 2",
+                "attempt to assign to const variable 'x'",
+            ),
+            (
+                "local x <close> = nil local function f() x = 1 end",
+                "attempt to assign to const variable 'x'",
+            ),
+            ("local x <const> = 1 do local x = 2 x = 3 end", ""),
+            (
+                "local x <close> = {}",
+                "variable 'x' got a non-closable value",
+            ),
+            (
+                "local x <close> = 1",
+                "variable 'x' got a non-closable value",
+            ),
+        ];
+        for (source, expected) in errors {
+            match run(source) {
+                Ok(_) => assert!(expected.is_empty(), "{source}"),
+                Err(err) => assert!(
+                    err.contains(expected) && !expected.is_empty(),
+                    "{source}: {err}"
+                ),
+            }
+        }
+        assert_eq!(
+            run("local x <close>, y = false, nil local z <close> = nil return 1").unwrap(),
+            "1"
+        );
+
+        // The variable still has to be closed after the call returns.
+        assert!(!opcodes("local x <close> = nil return f()").contains(&OpCode::TailCall));
+        assert!(opcodes("do local x <close> = nil end return f()").contains(&OpCode::TailCall));
+    }
+}
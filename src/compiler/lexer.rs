@@ -0,0 +1,1009 @@
+//! Splits Lua 5.4 source into tokens.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use super::{CompatLevel, CompileError, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    And,
+    Break,
+    Do,
+    Else,
+    ElseIf,
+    End,
+    False,
+    For,
+    Function,
+    Goto,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+
+    /// `+`
+    Add,
+    /// `-`
+    Minus,
+    /// `*`
+    Mul,
+    /// `/`
+    Div,
+    /// `//`
+    IDiv,
+    /// `%`
+    Mod,
+    /// `^`
+    Pow,
+    /// `#`
+    Len,
+    /// `&`
+    BitAnd,
+    /// `~`
+    BitXor,
+    /// `|`
+    BitOr,
+    /// `<<`
+    Shl,
+    /// `>>`
+    Shr,
+    /// `..`
+    Concat,
+    /// `...`
+    Dots,
+    /// `==`
+    Eq,
+    /// `~=`
+    Ne,
+    /// `<=`
+    Le,
+    /// `>=`
+    Ge,
+    /// `<`
+    Lt,
+    /// `>`
+    Gt,
+    /// `=`
+    Assign,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    /// `::`
+    DoubleColon,
+    Semicolon,
+    Colon,
+    Comma,
+    Dot,
+
+    Name(String),
+    /// A string literal with its escapes resolved. Lua strings are arbitrary bytes.
+    String(Vec<u8>),
+    Integer(i64),
+    Float(f64),
+    Eof,
+}
+
+const KEYWORDS: &[(&str, Token)] = &[
+    ("and", Token::And),
+    ("break", Token::Break),
+    ("do", Token::Do),
+    ("else", Token::Else),
+    ("elseif", Token::ElseIf),
+    ("end", Token::End),
+    ("false", Token::False),
+    ("for", Token::For),
+    ("function", Token::Function),
+    ("goto", Token::Goto),
+    ("if", Token::If),
+    ("in", Token::In),
+    ("local", Token::Local),
+    ("nil", Token::Nil),
+    ("not", Token::Not),
+    ("or", Token::Or),
+    ("repeat", Token::Repeat),
+    ("return", Token::Return),
+    ("then", Token::Then),
+    ("true", Token::True),
+    ("until", Token::Until),
+    ("while", Token::While),
+];
+
+/// Whether `s` can be written as a name: an identifier that isn't a keyword.
+pub fn is_name(s: &[u8]) -> bool {
+    match s.split_first() {
+        Some((first, rest)) => {
+            (first.is_ascii_alphabetic() || *first == b'_')
+                && rest.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+                && !KEYWORDS.iter().any(|(word, _)| word.as_bytes() == s)
+        }
+        None => false,
+    }
+}
+
+impl fmt::Display for Token {
+    /// Describes the token the way the parser's "expected" messages refer to it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((word, _)) = KEYWORDS.iter().find(|(_, t)| t == self) {
+            return write!(f, "'{word}'");
+        }
+        let symbol = match self {
+            Token::Add => "+",
+            Token::Minus => "-",
+            Token::Mul => "*",
+            Token::Div => "/",
+            Token::IDiv => "//",
+            Token::Mod => "%",
+            Token::Pow => "^",
+            Token::Len => "#",
+            Token::BitAnd => "&",
+            Token::BitXor => "~",
+            Token::BitOr => "|",
+            Token::Shl => "<<",
+            Token::Shr => ">>",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Eq => "==",
+            Token::Ne => "~=",
+            Token::Le => "<=",
+            Token::Ge => ">=",
+            Token::Lt => "<",
+            Token::Gt => ">",
+            Token::Assign => "=",
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::LeftBrace => "{",
+            Token::RightBrace => "}",
+            Token::LeftBracket => "[",
+            Token::RightBracket => "]",
+            Token::DoubleColon => "::",
+            Token::Semicolon => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Name(_) => return f.write_str("<name>"),
+            Token::String(_) => return f.write_str("<string>"),
+            Token::Integer(_) | Token::Float(_) => return f.write_str("<number>"),
+            Token::Eof => return f.write_str("<eof>"),
+            _ => unreachable!("keywords are handled above"),
+        };
+        write!(f, "'{symbol}'")
+    }
+}
+
+/// The value of a numeric literal.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+/// Converts a string to a number following Lua's rules: decimal and hexadecimal integers and floats, with
+/// optional surrounding whitespace and a leading sign. Decimal integers that overflow become floats, while
+/// hexadecimal integers wrap around.
+pub fn parse_number(s: &[u8]) -> Option<Number> {
+    let s = trim(s);
+    let (negative, digits) = match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let number = if digits.len() > 2 && digits[0] == b'0' && matches!(digits[1], b'x' | b'X') {
+        parse_hex(&digits[2..])?
+    } else {
+        parse_decimal(digits)?
+    };
+    Some(match (negative, number) {
+        (true, Number::Integer(i)) => Number::Integer(i.wrapping_neg()),
+        (true, Number::Float(f)) => Number::Float(-f),
+        (false, number) => number,
+    })
+}
+
+/// Strips the whitespace Lua's lexer skips from both ends of `s`.
+pub(crate) fn trim(mut s: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = s {
+        if !is_space(*first) {
+            break;
+        }
+        s = rest;
+    }
+    while let [rest @ .., last] = s {
+        if !is_space(*last) {
+            break;
+        }
+        s = rest;
+    }
+    s
+}
+
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c)
+}
+
+fn parse_decimal(s: &[u8]) -> Option<Number> {
+    if s.is_empty() {
+        return None;
+    }
+    if s.iter().all(u8::is_ascii_digit) {
+        let mut n: i64 = 0;
+        for &c in s {
+            match n
+                .checked_mul(10)
+                .and_then(|n| n.checked_add((c - b'0') as i64))
+            {
+                Some(next) => n = next,
+                None => return parse_decimal_float(s).map(Number::Float),
+            }
+        }
+        return Some(Number::Integer(n));
+    }
+    parse_decimal_float(s).map(Number::Float)
+}
+
+fn parse_decimal_float(s: &[u8]) -> Option<f64> {
+    // Rust's float parser also accepts words like "inf" and "nan", which Lua does not.
+    if !s
+        .iter()
+        .all(|c| c.is_ascii_digit() || matches!(c, b'.' | b'e' | b'E' | b'+' | b'-'))
+    {
+        return None;
+    }
+    if !s
+        .iter()
+        .take_while(|c| !matches!(c, b'e' | b'E'))
+        .any(u8::is_ascii_digit)
+    {
+        return None;
+    }
+    std::str::from_utf8(s).ok()?.parse().ok()
+}
+
+fn parse_hex(s: &[u8]) -> Option<Number> {
+    let mut mantissa: u64 = 0;
+    let mut float_mantissa: f64 = 0.0;
+    let mut exponent: i64 = 0;
+    let mut any_digit = false;
+    let mut seen_dot = false;
+    let mut is_float = false;
+    let mut i = 0;
+
+    while i < s.len() {
+        let c = s[i];
+        if c == b'.' {
+            if seen_dot {
+                return None;
+            }
+            seen_dot = true;
+            is_float = true;
+        } else if let Some(d) = (c as char).to_digit(16) {
+            any_digit = true;
+            mantissa = mantissa.wrapping_mul(16).wrapping_add(d as u64);
+            float_mantissa = float_mantissa * 16.0 + d as f64;
+            if seen_dot {
+                exponent -= 4;
+            }
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    if !any_digit {
+        return None;
+    }
+
+    if i < s.len() {
+        if !matches!(s[i], b'p' | b'P') {
+            return None;
+        }
+        is_float = true;
+        i += 1;
+        let negative = match s.get(i) {
+            Some(b'-') => {
+                i += 1;
+                true
+            }
+            Some(b'+') => {
+                i += 1;
+                false
+            }
+            _ => false,
+        };
+        let digits = &s[i..];
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        let mut e: i64 = 0;
+        for &c in digits {
+            e = e.saturating_mul(10).saturating_add((c - b'0') as i64);
+        }
+        exponent = exponent.saturating_add(if negative { -e } else { e });
+    }
+
+    if is_float {
+        let exponent = exponent.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        Some(Number::Float(scale_by_power_of_two(
+            float_mantissa,
+            exponent,
+        )))
+    } else {
+        Some(Number::Integer(mantissa as i64))
+    }
+}
+
+/// Computes `x * 2^exp` as C's `ldexp` does. A single power of two can't reach the subnormal
+/// numbers, so the scaling goes in steps, each exact until the last.
+fn scale_by_power_of_two(mut x: f64, mut exp: i32) -> f64 {
+    const STEP: i32 = 1000;
+    while exp > STEP && x.is_finite() {
+        x *= 2f64.powi(STEP);
+        exp -= STEP;
+    }
+    while exp < -STEP && x != 0.0 {
+        x *= 2f64.powi(-STEP);
+        exp += STEP;
+    }
+    x * 2f64.powi(exp)
+}
+
+/// A function handing out source a piece at a time, returning `None` or an empty piece at the end.
+pub type Reader<'a> = Box<dyn FnMut() -> Option<Vec<u8>> + 'a>;
+
+/// How much read source a lexer keeps past what it needs before letting go of it.
+const RELEASE_THRESHOLD: usize = 4096;
+
+/// Produces tokens from Lua source on demand.
+///
+/// The source can be given whole, or pulled from a [`Reader`] only as far as the next token needs.
+/// Positions in spans count from the start of the source either way.
+pub struct Lexer<'a> {
+    /// The source read so far, less the `offset` bytes before it that have been released.
+    buffer: Cow<'a, [u8]>,
+    offset: usize,
+    /// Where more source comes from, until it runs out.
+    reader: Option<Reader<'a>>,
+    pos: usize,
+    line: u32,
+    /// Where the current line starts.
+    line_start: usize,
+    compat: CompatLevel,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a [u8]) -> Lexer<'a> {
+        Lexer {
+            buffer: Cow::Borrowed(source),
+            offset: 0,
+            reader: None,
+            pos: 0,
+            line: 1,
+            line_start: 0,
+            compat: CompatLevel::default(),
+        }
+    }
+
+    /// A lexer reading its source from `reader` as it goes.
+    pub fn from_reader(reader: Reader<'a>) -> Lexer<'a> {
+        Lexer {
+            buffer: Cow::Owned(Vec::new()),
+            offset: 0,
+            reader: Some(reader),
+            pos: 0,
+            line: 1,
+            line_start: 0,
+            compat: CompatLevel::default(),
+        }
+    }
+
+    /// Lets go of the source read before `pos`, which no span [`Lexer::text`] will be asked about
+    /// starts before. Source given whole is kept as it is.
+    pub fn release(&mut self, pos: usize) {
+        if let Cow::Owned(buffer) = &mut self.buffer {
+            let n = pos.saturating_sub(self.offset).min(buffer.len());
+            // Dropping the front moves what is left, so that waits until most of it can go.
+            if n >= RELEASE_THRESHOLD && n * 2 >= buffer.len() {
+                buffer.drain(..n);
+                self.offset += n;
+            }
+        }
+    }
+
+    /// Reads the source as written for the version of Lua `compat` names: for Lua 5.1, `goto` is a
+    /// name and `//` two divisions.
+    pub fn set_compat(&mut self, compat: CompatLevel) {
+        self.compat = compat;
+    }
+
+    pub fn compat(&self) -> CompatLevel {
+        self.compat
+    }
+
+    /// The current line.
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    /// The source text of a span, for "near" clauses in error messages.
+    pub fn text(&self, span: Span) -> String {
+        let end = self.offset + self.buffer.len();
+        if span.start >= end {
+            return "<eof>".into();
+        }
+        let start = span.start.max(self.offset);
+        String::from_utf8_lossy(self.slice(start, span.end.clamp(start, end))).into_owned()
+    }
+
+    /// Returns the next token and its span, or [`Token::Eof`] once the source is exhausted.
+    pub fn next_token(&mut self) -> Result<(Token, Span), CompileError> {
+        self.skip_whitespace_and_comments()?;
+        let start = self.pos;
+        let (line, column) = (self.line, self.column(start));
+        let token = self.read_token(start)?;
+        Ok((token, Span::new(start, self.pos, line, column)))
+    }
+
+    /// The column of `pos`, on the current line.
+    fn column(&self, pos: usize) -> u32 {
+        (pos.saturating_sub(self.line_start) + 1) as u32
+    }
+
+    /// The byte at position `i`, reading as far as that if need be.
+    fn byte(&mut self, i: usize) -> Option<u8> {
+        while i >= self.offset + self.buffer.len() {
+            let piece = self.reader.as_mut().and_then(|read| read());
+            match piece {
+                Some(piece) if !piece.is_empty() => self.buffer.to_mut().extend_from_slice(&piece),
+                _ => {
+                    self.reader = None;
+                    return None;
+                }
+            }
+        }
+        Some(self.buffer[i - self.offset])
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.byte(self.pos)
+    }
+
+    fn peek_at(&mut self, offset: usize) -> Option<u8> {
+        self.byte(self.pos + offset)
+    }
+
+    /// The source between two positions that have been read and not released.
+    fn slice(&self, start: usize, end: usize) -> &[u8] {
+        &self.buffer[start - self.offset..end - self.offset]
+    }
+
+    fn error_near(&self, message: &str, start: usize) -> CompileError {
+        let span = Span::new(start, self.pos, self.line, self.column(start));
+        CompileError::new(format!("{message} near '{}'", self.text(span)), span)
+    }
+
+    fn error_at_eof(&self, message: &str, start: usize) -> CompileError {
+        CompileError::new(
+            format!("{message} near <eof>"),
+            Span::new(start, self.pos, self.line, self.column(start)),
+        )
+    }
+
+    /// Consumes a line break, treating `\r\n` and `\n\r` as a single one.
+    fn newline(&mut self) {
+        let first = self.peek();
+        self.pos += 1;
+        if let Some(next) = self.peek() {
+            if matches!(next, b'\n' | b'\r') && Some(next) != first {
+                self.pos += 1;
+            }
+        }
+        self.line += 1;
+        self.line_start = self.pos;
+    }
+
+    fn skip_whitespace_and_comments(&mut self) -> Result<(), CompileError> {
+        while let Some(c) = self.peek() {
+            match c {
+                b'\n' | b'\r' => self.newline(),
+                b' ' | b'\t' | 0x0b | 0x0c => self.pos += 1,
+                b'-' if self.peek_at(1) == Some(b'-') => {
+                    let start = self.pos;
+                    self.pos += 2;
+                    if self.peek() == Some(b'[') {
+                        if let Some(level) = self.long_bracket_level() {
+                            self.read_long_string(start, level, "comment")?;
+                            continue;
+                        }
+                    }
+                    while !matches!(self.peek(), None | Some(b'\n' | b'\r')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_token(&mut self, start: usize) -> Result<Token, CompileError> {
+        let Some(c) = self.peek() else {
+            return Ok(Token::Eof);
+        };
+
+        macro_rules! one_or_two {
+            ($one:expr, $($second:literal => $two:expr),*) => {{
+                self.pos += 1;
+                match self.peek() {
+                    $(Some($second) => {
+                        self.pos += 1;
+                        $two
+                    })*
+                    _ => $one,
+                }
+            }};
+        }
+
+        let token = match c {
+            b'[' => {
+                if let Some(level) = self.long_bracket_level() {
+                    Token::String(self.read_long_string(start, level, "string")?)
+                } else if self.peek_at(1) == Some(b'=') {
+                    self.pos += 2;
+                    return Err(self.error_near("invalid long string delimiter", start));
+                } else {
+                    self.pos += 1;
+                    Token::LeftBracket
+                }
+            }
+            b'"' | b'\'' => Token::String(self.read_string(c)?),
+            b'.' => {
+                if self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) {
+                    self.read_numeral()?
+                } else if self.peek_at(1) == Some(b'.') {
+                    self.pos += 2;
+                    if self.peek() == Some(b'.') {
+                        self.pos += 1;
+                        Token::Dots
+                    } else {
+                        Token::Concat
+                    }
+                } else {
+                    self.pos += 1;
+                    Token::Dot
+                }
+            }
+            b'0'..=b'9' => self.read_numeral()?,
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_')
+                {
+                    self.pos += 1;
+                }
+                // Identifiers are ASCII, so this cannot fail.
+                let name = std::str::from_utf8(self.slice(start, self.pos)).unwrap();
+                match KEYWORDS.iter().find(|(word, _)| *word == name) {
+                    Some((_, Token::Goto)) if self.compat == CompatLevel::Lua51 => {
+                        Token::Name(name.to_owned())
+                    }
+                    Some((_, keyword)) => keyword.clone(),
+                    None => Token::Name(name.to_owned()),
+                }
+            }
+            b'+' => one_or_two!(Token::Add,),
+            b'-' => one_or_two!(Token::Minus,),
+            b'*' => one_or_two!(Token::Mul,),
+            b'/' if self.compat == CompatLevel::Lua51 => one_or_two!(Token::Div,),
+            b'/' => one_or_two!(Token::Div, b'/' => Token::IDiv),
+            b'%' => one_or_two!(Token::Mod,),
+            b'^' => one_or_two!(Token::Pow,),
+            b'#' => one_or_two!(Token::Len,),
+            b'&' => one_or_two!(Token::BitAnd,),
+            b'~' => one_or_two!(Token::BitXor, b'=' => Token::Ne),
+            b'|' => one_or_two!(Token::BitOr,),
+            b'<' => one_or_two!(Token::Lt, b'<' => Token::Shl, b'=' => Token::Le),
+            b'>' => one_or_two!(Token::Gt, b'>' => Token::Shr, b'=' => Token::Ge),
+            b'=' => one_or_two!(Token::Assign, b'=' => Token::Eq),
+            b':' => one_or_two!(Token::Colon, b':' => Token::DoubleColon),
+            b'(' => one_or_two!(Token::LeftParen,),
+            b')' => one_or_two!(Token::RightParen,),
+            b'{' => one_or_two!(Token::LeftBrace,),
+            b'}' => one_or_two!(Token::RightBrace,),
+            b']' => one_or_two!(Token::RightBracket,),
+            b';' => one_or_two!(Token::Semicolon,),
+            b',' => one_or_two!(Token::Comma,),
+            _ => {
+                self.pos += 1;
+                return Err(self.error_near("unexpected symbol", start));
+            }
+        };
+        Ok(token)
+    }
+
+    /// If a long bracket (`[[`, `[==[`, ...) starts at the current position, returns its level.
+    fn long_bracket_level(&mut self) -> Option<usize> {
+        let mut i = self.pos + 1;
+        while self.byte(i) == Some(b'=') {
+            i += 1;
+        }
+        (self.byte(i) == Some(b'[')).then_some(i - self.pos - 1)
+    }
+
+    fn read_long_string(
+        &mut self,
+        start: usize,
+        level: usize,
+        what: &str,
+    ) -> Result<Vec<u8>, CompileError> {
+        self.pos += level + 2;
+        // A line break directly after the opening bracket is not part of the string.
+        if matches!(self.peek(), Some(b'\n' | b'\r')) {
+            self.newline();
+        }
+        let mut contents = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error_at_eof(&format!("unfinished long {what}"), start)),
+                Some(b']') => {
+                    let mut i = self.pos + 1;
+                    while self.byte(i) == Some(b'=') {
+                        i += 1;
+                    }
+                    if i - self.pos - 1 == level && self.byte(i) == Some(b']') {
+                        self.pos = i + 1;
+                        return Ok(contents);
+                    }
+                    contents.push(b']');
+                    self.pos += 1;
+                }
+                Some(b'\n' | b'\r') => {
+                    contents.push(b'\n');
+                    self.newline();
+                }
+                Some(c) => {
+                    contents.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn read_string(&mut self, quote: u8) -> Result<Vec<u8>, CompileError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut contents = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error_at_eof("unfinished string", start)),
+                Some(b'\n' | b'\r') => return Err(self.error_near("unfinished string", start)),
+                Some(c) if c == quote => {
+                    self.pos += 1;
+                    return Ok(contents);
+                }
+                Some(b'\\') => self.read_escape(start, &mut contents)?,
+                Some(c) => {
+                    contents.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn read_escape(&mut self, start: usize, contents: &mut Vec<u8>) -> Result<(), CompileError> {
+        self.pos += 1;
+        let Some(c) = self.peek() else {
+            return Err(self.error_at_eof("unfinished string", start));
+        };
+        let simple = match c {
+            b'a' => Some(0x07),
+            b'b' => Some(0x08),
+            b'f' => Some(0x0c),
+            b'n' => Some(b'\n'),
+            b'r' => Some(b'\r'),
+            b't' => Some(b'\t'),
+            b'v' => Some(0x0b),
+            b'\\' | b'"' | b'\'' => Some(c),
+            _ => None,
+        };
+        if let Some(byte) = simple {
+            contents.push(byte);
+            self.pos += 1;
+            return Ok(());
+        }
+
+        match c {
+            b'\n' | b'\r' => {
+                contents.push(b'\n');
+                self.newline();
+            }
+            b'x' => {
+                self.pos += 1;
+                let mut byte = 0;
+                for _ in 0..2 {
+                    let digit = self.peek().and_then(|c| (c as char).to_digit(16));
+                    self.pos += 1;
+                    let Some(digit) = digit else {
+                        return Err(self.error_near("hexadecimal digit expected", start));
+                    };
+                    byte = byte * 16 + digit as u8;
+                }
+                contents.push(byte);
+            }
+            b'z' => {
+                self.pos += 1;
+                while let Some(c) = self.peek() {
+                    if matches!(c, b'\n' | b'\r') {
+                        self.newline();
+                    } else if is_space(c) {
+                        self.pos += 1;
+                    } else {
+                        break;
+                    }
+                }
+            }
+            b'u' => {
+                self.pos += 1;
+                if self.peek() != Some(b'{') {
+                    self.pos += 1;
+                    return Err(self.error_near("missing '{' in \\u{xxxx}", start));
+                }
+                self.pos += 1;
+                let mut code: u32 = 0;
+                let mut digits = 0;
+                while let Some(digit) = self.peek().and_then(|c| (c as char).to_digit(16)) {
+                    code = code
+                        .checked_mul(16)
+                        .map(|c| c + digit)
+                        .filter(|&c| c <= 0x7fff_ffff)
+                        .ok_or_else(|| self.error_near("UTF-8 value too large", start))?;
+                    digits += 1;
+                    self.pos += 1;
+                }
+                if digits == 0 {
+                    self.pos += 1;
+                    return Err(self.error_near("hexadecimal digit expected", start));
+                }
+                if self.peek() != Some(b'}') {
+                    self.pos += 1;
+                    return Err(self.error_near("missing '}' in \\u{xxxx}", start));
+                }
+                self.pos += 1;
+                encode_utf8(code, contents);
+            }
+            b'0'..=b'9' => {
+                let mut value: u32 = 0;
+                for _ in 0..3 {
+                    match self.peek() {
+                        Some(c @ b'0'..=b'9') => {
+                            value = value * 10 + (c - b'0') as u32;
+                            self.pos += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                if value > 255 {
+                    return Err(self.error_near("decimal escape too large", start));
+                }
+                contents.push(value as u8);
+            }
+            _ => {
+                self.pos += 1;
+                return Err(self.error_near("invalid escape sequence", start));
+            }
+        }
+        Ok(())
+    }
+
+    fn read_numeral(&mut self) -> Result<Token, CompileError> {
+        let start = self.pos;
+        let mut exponent = b"eE";
+        if self.peek() == Some(b'0') && matches!(self.peek_at(1), Some(b'x' | b'X')) {
+            self.pos += 2;
+            exponent = b"pP";
+        }
+        // Like the reference lexer, greedily read anything that could continue a numeral and validate afterwards,
+        // so that `3x` is reported as malformed instead of being split into two tokens.
+        while let Some(c) = self.peek() {
+            if exponent.contains(&c) && matches!(self.peek_at(1), Some(b'+' | b'-')) {
+                self.pos += 2;
+            } else if c.is_ascii_alphanumeric() || c == b'.' || c == b'_' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        match parse_number(self.slice(start, self.pos)) {
+            Some(Number::Integer(i)) => Ok(Token::Integer(i)),
+            Some(Number::Float(f)) => Ok(Token::Float(f)),
+            None => Err(self.error_near("malformed number", start)),
+        }
+    }
+}
+
+/// Encodes a code point of up to 31 bits using the original, extended UTF-8 scheme that Lua's `\u` escape uses.
+pub(crate) fn encode_utf8(code: u32, out: &mut Vec<u8>) {
+    if code < 0x80 {
+        out.push(code as u8);
+        return;
+    }
+    let mut buf = [0u8; 6];
+    let mut n = 0;
+    // The largest value that fits in the first byte's payload, shrinking as continuation bytes are added.
+    let mut first_max = 0x3f;
+    let mut code = code;
+    while code > first_max {
+        buf[5 - n] = 0x80 | (code & 0x3f) as u8;
+        n += 1;
+        code >>= 6;
+        first_max >>= 1;
+    }
+    let lead = (!first_max << 1) as u8 | code as u8;
+    out.push(lead);
+    out.extend_from_slice(&buf[6 - n..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        let mut lexer = Lexer::new(source.as_bytes());
+        let mut tokens = Vec::new();
+        loop {
+            match lexer.next_token().unwrap() {
+                (Token::Eof, _) => return tokens,
+                (token, _) => tokens.push(token),
+            }
+        }
+    }
+
+    fn error(source: &str) -> String {
+        let mut lexer = Lexer::new(source.as_bytes());
+        loop {
+            match lexer.next_token() {
+                Ok((Token::Eof, _)) => panic!("no error in {source:?}"),
+                Ok(_) => {}
+                Err(err) => return err.message,
+            }
+        }
+    }
+
+    #[test]
+    fn symbols_and_keywords() {
+        assert_eq!(
+            tokens("local x <const> = a.b:c(...) // 2 ~= #t .. y >> 1 :: goto"),
+            vec![
+                Token::Local,
+                Token::Name("x".into()),
+                Token::Lt,
+                Token::Name("const".into()),
+                Token::Gt,
+                Token::Assign,
+                Token::Name("a".into()),
+                Token::Dot,
+                Token::Name("b".into()),
+                Token::Colon,
+                Token::Name("c".into()),
+                Token::LeftParen,
+                Token::Dots,
+                Token::RightParen,
+                Token::IDiv,
+                Token::Integer(2),
+                Token::Ne,
+                Token::Len,
+                Token::Name("t".into()),
+                Token::Concat,
+                Token::Name("y".into()),
+                Token::Shr,
+                Token::Integer(1),
+                Token::DoubleColon,
+                Token::Goto,
+            ]
+        );
+    }
+
+    #[test]
+    fn numerals() {
+        assert_eq!(
+            tokens("3 3.0 2.5 250.0e-2 0.25E1 34e1 0x0.1E 0xA23p-4 0X1.921FB54442D18P+1 .5"),
+            vec![
+                Token::Integer(3),
+                Token::Float(3.0),
+                Token::Float(2.5),
+                Token::Float(2.5),
+                Token::Float(2.5),
+                Token::Float(340.0),
+                Token::Float(0.1171875),
+                Token::Float(162.1875),
+                Token::Float(std::f64::consts::PI),
+                Token::Float(0.5),
+            ]
+        );
+        assert_eq!(
+            tokens("0xff 0xffffffffffffffff 9223372036854775807 9223372036854775808"),
+            vec![
+                Token::Integer(255),
+                Token::Integer(-1),
+                Token::Integer(i64::MAX),
+                Token::Float(9223372036854775808.0),
+            ]
+        );
+        assert_eq!(error("x = 3x"), "malformed number near '3x'");
+        assert_eq!(error("1..2"), "malformed number near '1..2'");
+        assert_eq!(parse_number(b"  -0x10  "), Some(Number::Integer(-16)));
+        assert_eq!(parse_number(b"inf"), None);
+        assert_eq!(parse_number(b"1e"), None);
+        // Subnormal hexadecimal floats, as `%q` writes them.
+        assert_eq!(
+            parse_number(b"0x0.0000000000001p-1022"),
+            Some(Number::Float(f64::from_bits(1)))
+        );
+    }
+
+    #[test]
+    fn strings_and_comments() {
+        assert_eq!(
+            tokens(
+                "'a\\tb' \"\\65\\x42\\u{43}\\u{20AC}\" [[\nline1\nline2]] [==[a]]b]==] -- comment\n--[[ long\ncomment ]] 'x\\z\n     y'"
+            ),
+            vec![
+                Token::String(b"a\tb".to_vec()),
+                Token::String("ABC€".as_bytes().to_vec()),
+                Token::String(b"line1\nline2".to_vec()),
+                Token::String(b"a]]b".to_vec()),
+                Token::String(b"xy".to_vec()),
+            ]
+        );
+        assert_eq!(error("'abc"), "unfinished string near <eof>");
+        assert_eq!(error("'abc\n'"), "unfinished string near ''abc'");
+        assert_eq!(error("'\\q'"), "invalid escape sequence near ''\\q'");
+        assert_eq!(error("'\\300'"), "decimal escape too large near ''\\300'");
+        assert_eq!(
+            error("--[[ never closed"),
+            "unfinished long comment near <eof>"
+        );
+        assert_eq!(error("x @ y"), "unexpected symbol near '@'");
+    }
+
+    #[test]
+    fn reading_incrementally() {
+        let mut lines = (1..=5000).map(|i| format!("x{i} = 'line {i}'\n").into_bytes());
+        let mut lexer = Lexer::from_reader(Box::new(move || lines.next()));
+        let mut count = 0;
+        let mut most_buffered = 0;
+        loop {
+            let (token, span) = lexer.next_token().unwrap();
+            if token == Token::Eof {
+                break;
+            }
+            count += 1;
+            assert_eq!(span.line, (count - 1) / 3 + 1);
+            // A parser releases what comes before the token it is looking at.
+            lexer.release(span.start);
+            most_buffered = most_buffered.max(lexer.buffer.len());
+        }
+        assert_eq!(count, 15000);
+        assert!(most_buffered < 2 * RELEASE_THRESHOLD + 64);
+    }
+
+    #[test]
+    fn spans_track_lines() {
+        let mut lexer = Lexer::new(b"a\r\n\n  bc -- x\n[[\n]] d");
+        let expected = [(0, 1, 1, 1), (6, 8, 3, 3), (14, 19, 4, 1), (20, 21, 5, 4)];
+        for (start, end, line, column) in expected {
+            let (_, span) = lexer.next_token().unwrap();
+            assert_eq!(span, Span::new(start, end, line, column));
+        }
+        assert_eq!(lexer.next_token().unwrap().0, Token::Eof);
+    }
+}
@@ -0,0 +1,180 @@
+//! Turning Lua source text into [`Prototype`](crate::bytecode::Prototype)s.
+
+pub mod ast;
+pub mod codegen;
+pub mod lexer;
+pub mod parser;
+mod peephole;
+pub mod visit;
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::bytecode::Prototype;
+use crate::mem::{Gc, Mutation};
+
+/// Settings for compiling a chunk.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CompileOptions {
+    /// `0` emits code exactly as generated; anything higher also runs a peephole pass over it.
+    pub optimize: u8,
+    /// The version of Lua the source is written for.
+    pub compat: CompatLevel,
+}
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            optimize: 1,
+            compat: CompatLevel::default(),
+        }
+    }
+}
+
+/// The version of Lua that code is written for, so that code written for an older one can run
+/// unchanged.
+///
+/// Source for Lua 5.1 can use `goto` as a name, and has no `//` operator; neither it nor source for
+/// Lua 5.3 has the `<const>` and `<close>` attributes of locals. Libraries opened for Lua 5.1 add
+/// its `getfenv`, `setfenv`, `loadstring` and `string.gfind`, and all say their version in
+/// `_VERSION`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum CompatLevel {
+    Lua51,
+    Lua53,
+    #[default]
+    Lua54,
+}
+
+/// Compiles a chunk of Lua source into the prototype of its main function, with the default
+/// [`CompileOptions`].
+///
+/// `chunk_name` is used in error messages and debug information, as in `chunk:3: attempt to index a nil value`.
+pub fn compile<'gc>(
+    mc: &Mutation<'gc>,
+    source: &[u8],
+    chunk_name: &str,
+) -> Result<Gc<'gc, Prototype<'gc>>, CompileError> {
+    compile_with(mc, source, chunk_name, CompileOptions::default())
+}
+
+/// Like [`compile`], with explicit options.
+pub fn compile_with<'gc>(
+    mc: &Mutation<'gc>,
+    source: &[u8],
+    chunk_name: &str,
+    options: CompileOptions,
+) -> Result<Gc<'gc, Prototype<'gc>>, CompileError> {
+    let chunk = parser::parse_with(source, options.compat)?;
+    codegen::generate(mc, &chunk, chunk_name, options)
+}
+
+/// Like [`compile`], reading the source from `reader` a piece at a time as the parser gets to it,
+/// so that a large chunk needn't be held in memory whole.
+pub fn compile_from<'gc>(
+    mc: &Mutation<'gc>,
+    reader: lexer::Reader<'_>,
+    chunk_name: &str,
+) -> Result<Gc<'gc, Prototype<'gc>>, CompileError> {
+    compile_from_with(mc, reader, chunk_name, CompileOptions::default())
+}
+
+/// Like [`compile_from`], with explicit options.
+pub fn compile_from_with<'gc>(
+    mc: &Mutation<'gc>,
+    reader: lexer::Reader<'_>,
+    chunk_name: &str,
+    options: CompileOptions,
+) -> Result<Gc<'gc, Prototype<'gc>>, CompileError> {
+    let chunk = parser::parse_from_with(reader, options.compat)?;
+    codegen::generate(mc, &chunk, chunk_name, options)
+}
+
+/// Formats a chunk name for messages the way Lua does: `=name` is used as it is, `@file` names a
+/// file, and anything else is the source itself, shortened to its first line.
+pub fn chunk_id(name: &[u8]) -> String {
+    /// Room for the name, as in the reference implementation.
+    const ID_SIZE: usize = 60;
+    let name = String::from_utf8_lossy(name);
+    if let Some(rest) = name.strip_prefix('=') {
+        rest.chars().take(ID_SIZE - 1).collect()
+    } else if let Some(file) = name.strip_prefix('@') {
+        let count = file.chars().count();
+        if count < ID_SIZE {
+            file.to_owned()
+        } else {
+            let tail: String = file.chars().skip(count - (ID_SIZE - 4)).collect();
+            format!("...{tail}")
+        }
+    } else {
+        // Leaves room for `[string "`, `..."]` and a terminator.
+        let room = ID_SIZE - 15;
+        let first_line = name.split('\n').next().unwrap_or("");
+        if !name.contains('\n') && name.chars().count() < room {
+            format!("[string \"{name}\"]")
+        } else {
+            let start: String = first_line.chars().take(room).collect();
+            format!("[string \"{start}...\"]")
+        }
+    }
+}
+
+/// A range of bytes in the source, along with the line and column it starts at.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    /// The 1-based line number of `start`.
+    pub line: u32,
+    /// The 1-based column of `start`, counted in bytes from the start of its line, or 0 if unknown.
+    pub column: u32,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: u32, column: u32) -> Span {
+        Span {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    /// The smallest span covering both `self` and `other`, starting at the line and column of
+    /// `self`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+            ..self
+        }
+    }
+
+    pub fn range(self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+/// A syntax error, or a program the compiler cannot translate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl CompileError {
+    pub fn new(message: impl Into<String>, span: Span) -> CompileError {
+        CompileError {
+            message: message.into(),
+            span,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span.line, self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
@@ -0,0 +1,1033 @@
+//! A recursive-descent parser for Lua 5.4, stopping at the first syntax error, or going on past
+//! them with [`parse_recovering`].
+
+use super::ast::{
+    Attrib, BinOp, Block, Expr, FuncName, FunctionBody, LocalName, Name, Return, Stat, TableField,
+    UnOp, UNARY_PRIORITY,
+};
+use super::lexer::{Lexer, Reader, Token};
+use super::{CompatLevel, CompileError, Span};
+
+/// How deeply statements and expressions may nest before the parser gives up, keeping recursion bounded.
+const MAX_DEPTH: u32 = 200;
+
+/// Parses a whole chunk.
+pub fn parse(source: &[u8]) -> Result<Block, CompileError> {
+    parse_with(source, CompatLevel::default())
+}
+
+/// Parses a whole chunk written for the version of Lua `compat` names.
+pub fn parse_with(source: &[u8], compat: CompatLevel) -> Result<Block, CompileError> {
+    parse_lexed(Lexer::new(source), compat)
+}
+
+/// Parses a whole chunk read from `reader` a piece at a time, holding on to little more of the
+/// source than the statement being parsed.
+pub fn parse_from(reader: Reader<'_>) -> Result<Block, CompileError> {
+    parse_from_with(reader, CompatLevel::default())
+}
+
+/// Like [`parse_from`], for a chunk written for the version of Lua `compat` names.
+pub fn parse_from_with(reader: Reader<'_>, compat: CompatLevel) -> Result<Block, CompileError> {
+    parse_lexed(Lexer::from_reader(reader), compat)
+}
+
+fn parse_lexed(mut lexer: Lexer<'_>, compat: CompatLevel) -> Result<Block, CompileError> {
+    lexer.set_compat(compat);
+    let mut parser = Parser::new(lexer);
+    parser.advance()?;
+    let block = parser.block()?;
+    if parser.token != Token::Eof {
+        return Err(parser.error_expected(&Token::Eof));
+    }
+    Ok(block)
+}
+
+/// How serious a [`Diagnostic`] is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Never reported by the parser itself, for linters built on it.
+    Warning,
+    Error,
+}
+
+/// A problem found in the source by [`parse_recovering`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub severity: Severity,
+}
+
+impl From<CompileError> for Diagnostic {
+    fn from(err: CompileError) -> Diagnostic {
+        Diagnostic {
+            message: err.message,
+            span: err.span,
+            severity: Severity::Error,
+        }
+    }
+}
+
+/// Parses a whole chunk for tools that look at source as it is being written, going on past
+/// syntax errors. Each error is reported, in order, and the statement it is in left out of the
+/// tree; parsing picks up again at the next token that looks like the start of a statement or the
+/// end of a block.
+///
+/// The tree is only as good as the source is. With no diagnostics, it is the one [`parse_with`]
+/// returns.
+pub fn parse_recovering(source: &[u8], compat: CompatLevel) -> (Block, Vec<Diagnostic>) {
+    let mut lexer = Lexer::new(source);
+    lexer.set_compat(compat);
+    let mut parser = Parser::new(lexer);
+    parser.diagnostics = Some(Vec::new());
+    parser.skip_errors();
+    let mut block = parser.block().expect("errors are recovered from");
+    // Whatever closes a block that isn't open is skipped, and the chunk goes on after it.
+    while parser.token != Token::Eof {
+        let err = parser.error_expected(&Token::Eof);
+        parser.report(err);
+        parser.skip_errors();
+        let rest = parser.block().expect("errors are recovered from");
+        block.stats.extend(rest.stats);
+        block.ret = block.ret.or(rest.ret);
+        block.span.end = rest.span.end.max(block.span.end);
+    }
+    let diagnostics = parser.diagnostics.take().unwrap_or_default();
+    (block, diagnostics)
+}
+
+struct Parser<'a> {
+    lexer: Lexer<'a>,
+    token: Token,
+    span: Span,
+    ahead: Option<(Token, Span)>,
+    /// The end of the previous token, where the node being parsed ends.
+    prev_end: usize,
+    /// Where the source an error message may still quote starts, if before the current token.
+    keep_from: Option<usize>,
+    depth: u32,
+    /// Whether each enclosing function accepts `...`.
+    vararg: Vec<bool>,
+    /// The errors gone past so far, when recovering from them.
+    diagnostics: Option<Vec<Diagnostic>>,
+}
+
+impl<'a> Parser<'a> {
+    /// A parser yet to read its first token.
+    fn new(lexer: Lexer<'a>) -> Parser<'a> {
+        Parser {
+            lexer,
+            token: Token::Eof,
+            span: Span::default(),
+            ahead: None,
+            prev_end: 0,
+            keep_from: None,
+            depth: 0,
+            vararg: vec![true],
+            diagnostics: None,
+        }
+    }
+
+    fn report(&mut self, err: CompileError) {
+        if let Some(diagnostics) = &mut self.diagnostics {
+            diagnostics.push(err.into());
+        }
+    }
+
+    /// Advances, reporting the tokens the lexer fails to read until it reads one.
+    fn skip_errors(&mut self) {
+        while let Err(err) = self.advance() {
+            self.report(err);
+        }
+    }
+
+    /// Returns `err` unless recovering from errors. Otherwise reports it, and skips ahead to where
+    /// a statement may start or the block ends, past the statement that began at `start`.
+    fn recover(&mut self, err: CompileError, start: Span, depth: u32) -> Result<(), CompileError> {
+        if self.diagnostics.is_none() {
+            return Err(err);
+        }
+        let line = err.span.line;
+        self.report(err);
+        self.depth = depth;
+        loop {
+            let resumes = match self.token {
+                _ if self.block_follows(true) => true,
+                Token::Local
+                | Token::Function
+                | Token::If
+                | Token::While
+                | Token::For
+                | Token::Repeat
+                | Token::Do
+                | Token::Return
+                | Token::Goto
+                | Token::Break
+                | Token::DoubleColon
+                | Token::Semicolon => true,
+                // Assignments and calls, on a line of their own.
+                Token::Name(_) | Token::LeftParen => self.span.line > line,
+                _ => false,
+            };
+            if resumes && self.span.start > start.start {
+                return Ok(());
+            }
+            self.skip_errors();
+        }
+    }
+
+    fn advance(&mut self) -> Result<(), CompileError> {
+        self.prev_end = self.span.end;
+        let (token, span) = match self.ahead.take() {
+            Some(next) => next,
+            None => self.lexer.next_token()?,
+        };
+        self.token = token;
+        self.span = span;
+        self.lexer
+            .release(self.keep_from.unwrap_or(self.span.start));
+        Ok(())
+    }
+
+    fn peek(&mut self) -> Result<&Token, CompileError> {
+        if self.ahead.is_none() {
+            self.ahead = Some(self.lexer.next_token()?);
+        }
+        Ok(&self.ahead.as_ref().unwrap().0)
+    }
+
+    /// Consumes the current token if it is `token`.
+    fn test_next(&mut self, token: &Token) -> Result<bool, CompileError> {
+        if self.token == *token {
+            self.advance()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn expect(&mut self, token: &Token) -> Result<(), CompileError> {
+        if self.test_next(token)? {
+            Ok(())
+        } else {
+            Err(self.error_expected(token))
+        }
+    }
+
+    /// Expects the token closing a construct opened by `opener` at `line`.
+    fn expect_match(
+        &mut self,
+        closer: &Token,
+        opener: &Token,
+        line: u32,
+    ) -> Result<(), CompileError> {
+        if self.test_next(closer)? {
+            Ok(())
+        } else if line == self.span.line {
+            Err(self.error_expected(closer))
+        } else {
+            Err(self.error_near(&format!(
+                "{closer} expected (to close {opener} at line {line})"
+            )))
+        }
+    }
+
+    /// The span from `start` to the end of the previous token.
+    fn span_from(&self, start: Span) -> Span {
+        Span {
+            end: self.prev_end.max(start.end),
+            ..start
+        }
+    }
+
+    fn error_near(&self, message: &str) -> CompileError {
+        let near = match self.token {
+            Token::Eof => "<eof>".to_owned(),
+            _ => format!("'{}'", self.lexer.text(self.span)),
+        };
+        CompileError::new(format!("{message} near {near}"), self.span)
+    }
+
+    fn error_expected(&self, token: &Token) -> CompileError {
+        match token {
+            Token::Eof => self.error_near("'<eof>' expected"),
+            _ => self.error_near(&format!("{token} expected")),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), CompileError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error_near("chunk has too many syntax levels"));
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn name(&mut self) -> Result<Name, CompileError> {
+        if let Token::Name(name) = &self.token {
+            let name = Name {
+                name: name.clone(),
+                span: self.span,
+            };
+            self.advance()?;
+            Ok(name)
+        } else {
+            Err(self.error_near("<name> expected"))
+        }
+    }
+
+    fn block_follows(&self, with_until: bool) -> bool {
+        match self.token {
+            Token::Else | Token::ElseIf | Token::End | Token::Eof => true,
+            Token::Until => with_until,
+            _ => false,
+        }
+    }
+
+    fn block(&mut self) -> Result<Block, CompileError> {
+        let start = self.span;
+        let mut stats = Vec::new();
+        let mut ret = None;
+        while !self.block_follows(true) {
+            let (at, depth) = (self.span, self.depth);
+            if self.token == Token::Return {
+                match self.return_stat() {
+                    Ok(stat) => {
+                        ret = Some(stat);
+                        break;
+                    }
+                    Err(err) => self.recover(err, at, depth)?,
+                }
+                continue;
+            }
+            match self.statement() {
+                Ok(stat) => stats.extend(stat),
+                Err(err) => self.recover(err, at, depth)?,
+            }
+        }
+        Ok(Block {
+            stats,
+            ret,
+            span: Span {
+                end: self.prev_end.max(start.start),
+                ..start
+            },
+        })
+    }
+
+    fn return_stat(&mut self) -> Result<Return, CompileError> {
+        let start = self.span;
+        self.advance()?;
+        let values = if self.block_follows(true) || self.token == Token::Semicolon {
+            Vec::new()
+        } else {
+            self.expr_list()?
+        };
+        self.test_next(&Token::Semicolon)?;
+        Ok(Return {
+            values,
+            span: self.span_from(start),
+        })
+    }
+
+    fn statement(&mut self) -> Result<Option<Stat>, CompileError> {
+        self.enter()?;
+        let stat = self.statement_inner();
+        self.leave();
+        stat
+    }
+
+    fn statement_inner(&mut self) -> Result<Option<Stat>, CompileError> {
+        let start = self.span;
+        let line = start.line;
+        let stat = match self.token {
+            Token::Semicolon => {
+                self.advance()?;
+                return Ok(None);
+            }
+            Token::If => self.if_stat()?,
+            Token::While => {
+                self.advance()?;
+                let cond = self.expr()?;
+                self.expect(&Token::Do)?;
+                let body = self.block()?;
+                self.expect_match(&Token::End, &Token::While, line)?;
+                Stat::While {
+                    cond,
+                    body,
+                    span: self.span_from(start),
+                }
+            }
+            Token::Do => {
+                self.advance()?;
+                let body = self.block()?;
+                self.expect_match(&Token::End, &Token::Do, line)?;
+                Stat::Do(body)
+            }
+            Token::For => self.for_stat()?,
+            Token::Repeat => {
+                self.advance()?;
+                let body = self.block()?;
+                self.expect_match(&Token::Until, &Token::Repeat, line)?;
+                let cond = self.expr()?;
+                Stat::Repeat {
+                    body,
+                    cond,
+                    span: self.span_from(start),
+                }
+            }
+            Token::Function => {
+                self.advance()?;
+                let mut path = vec![self.name()?];
+                while self.test_next(&Token::Dot)? {
+                    path.push(self.name()?);
+                }
+                let method = if self.test_next(&Token::Colon)? {
+                    Some(self.name()?)
+                } else {
+                    None
+                };
+                let body = self.function_body(start, method.is_some())?;
+                Stat::Function {
+                    name: FuncName { path, method },
+                    body: Box::new(body),
+                    span: self.span_from(start),
+                }
+            }
+            Token::Local => {
+                self.advance()?;
+                if self.test_next(&Token::Function)? {
+                    let name = self.name()?;
+                    let body = self.function_body(start, false)?;
+                    Stat::LocalFunction {
+                        name,
+                        body: Box::new(body),
+                        span: self.span_from(start),
+                    }
+                } else {
+                    self.local_stat(start)?
+                }
+            }
+            Token::DoubleColon => {
+                self.advance()?;
+                let name = self.name()?;
+                self.expect(&Token::DoubleColon)?;
+                Stat::Label(name)
+            }
+            Token::Break => {
+                self.advance()?;
+                Stat::Break(start)
+            }
+            Token::Goto => {
+                self.advance()?;
+                Stat::Goto(self.name()?)
+            }
+            _ => self.expr_stat()?,
+        };
+        Ok(Some(stat))
+    }
+
+    fn if_stat(&mut self) -> Result<Stat, CompileError> {
+        let start = self.span;
+        let mut branches = Vec::new();
+        let mut else_block = None;
+        loop {
+            // Skips `if` or `elseif`.
+            self.advance()?;
+            let cond = self.expr()?;
+            self.expect(&Token::Then)?;
+            branches.push((cond, self.block()?));
+            match self.token {
+                Token::ElseIf => continue,
+                Token::Else => {
+                    self.advance()?;
+                    else_block = Some(self.block()?);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        self.expect_match(&Token::End, &Token::If, start.line)?;
+        Ok(Stat::If {
+            branches,
+            else_block,
+            span: self.span_from(start),
+        })
+    }
+
+    fn for_stat(&mut self) -> Result<Stat, CompileError> {
+        let start = self.span;
+        self.advance()?;
+        let first = self.name()?;
+        let stat = match self.token {
+            Token::Assign => {
+                self.advance()?;
+                let init = self.expr()?;
+                self.expect(&Token::Comma)?;
+                let limit = self.expr()?;
+                let step = if self.test_next(&Token::Comma)? {
+                    Some(self.expr()?)
+                } else {
+                    None
+                };
+                self.expect(&Token::Do)?;
+                let body = self.block()?;
+                self.expect_match(&Token::End, &Token::For, start.line)?;
+                Stat::NumericFor {
+                    var: first,
+                    start: Box::new(init),
+                    limit: Box::new(limit),
+                    step: step.map(Box::new),
+                    body,
+                    span: self.span_from(start),
+                }
+            }
+            Token::Comma | Token::In => {
+                let mut names = vec![first];
+                while self.test_next(&Token::Comma)? {
+                    names.push(self.name()?);
+                }
+                self.expect(&Token::In)?;
+                let exprs = self.expr_list()?;
+                self.expect(&Token::Do)?;
+                let body = self.block()?;
+                self.expect_match(&Token::End, &Token::For, start.line)?;
+                Stat::GenericFor {
+                    names,
+                    exprs,
+                    body,
+                    span: self.span_from(start),
+                }
+            }
+            _ => return Err(self.error_near("'=' or 'in' expected")),
+        };
+        Ok(stat)
+    }
+
+    fn local_stat(&mut self, start: Span) -> Result<Stat, CompileError> {
+        let mut names = Vec::new();
+        let mut has_close = false;
+        loop {
+            let name = self.name()?;
+            let has_attribs = self.lexer.compat() >= CompatLevel::Lua54;
+            let attrib = if has_attribs && self.test_next(&Token::Lt)? {
+                let attrib = self.name()?;
+                let attrib = match attrib.name.as_str() {
+                    "const" => Attrib::Const,
+                    "close" => Attrib::Close,
+                    other => {
+                        return Err(CompileError::new(
+                            format!("unknown attribute '{other}'"),
+                            attrib.span,
+                        ))
+                    }
+                };
+                self.expect(&Token::Gt)?;
+                if attrib == Attrib::Close {
+                    if has_close {
+                        return Err(CompileError::new(
+                            "multiple to-be-closed variables in local list",
+                            name.span,
+                        ));
+                    }
+                    has_close = true;
+                }
+                Some(attrib)
+            } else {
+                None
+            };
+            names.push(LocalName { name, attrib });
+            if !self.test_next(&Token::Comma)? {
+                break;
+            }
+        }
+        let values = if self.test_next(&Token::Assign)? {
+            self.expr_list()?
+        } else {
+            Vec::new()
+        };
+        Ok(Stat::Local {
+            names,
+            values,
+            span: self.span_from(start),
+        })
+    }
+
+    /// Parses what may be the target of an assignment, keeping its source for the error message if
+    /// it turns out not to be one.
+    fn target(&mut self) -> Result<Expr, CompileError> {
+        let outer = self.keep_from;
+        self.keep_from = Some(outer.unwrap_or(self.span.start));
+        let target = self.suffixed_expr();
+        self.keep_from = outer;
+        target
+    }
+
+    fn check_assignable(&self, target: &Expr) -> Result<(), CompileError> {
+        if matches!(target, Expr::Name(_) | Expr::Index { .. }) {
+            return Ok(());
+        }
+        Err(CompileError::new(
+            format!("syntax error near '{}'", self.lexer.text(target.span())),
+            target.span(),
+        ))
+    }
+
+    fn expr_stat(&mut self) -> Result<Stat, CompileError> {
+        let start = self.span;
+        let first = self.target()?;
+        if matches!(self.token, Token::Assign | Token::Comma) {
+            self.check_assignable(&first)?;
+            let mut targets = vec![first];
+            while self.test_next(&Token::Comma)? {
+                let target = self.target()?;
+                self.check_assignable(&target)?;
+                targets.push(target);
+            }
+            self.expect(&Token::Assign)?;
+            let values = self.expr_list()?;
+            Ok(Stat::Assign {
+                targets,
+                values,
+                span: self.span_from(start),
+            })
+        } else if matches!(first, Expr::Call { .. } | Expr::MethodCall { .. }) {
+            Ok(Stat::Call(first))
+        } else {
+            Err(self.error_near("syntax error"))
+        }
+    }
+
+    /// Parses the parameter list and body of a function whose `function` keyword (or `local`) is at `start`.
+    fn function_body(
+        &mut self,
+        start: Span,
+        is_method: bool,
+    ) -> Result<FunctionBody, CompileError> {
+        let mut params = Vec::new();
+        if is_method {
+            params.push(Name {
+                name: "self".into(),
+                span: start,
+            });
+        }
+        let mut is_vararg = false;
+        self.expect(&Token::LeftParen)?;
+        if self.token != Token::RightParen {
+            loop {
+                match self.token {
+                    Token::Name(_) => params.push(self.name()?),
+                    Token::Dots => {
+                        self.advance()?;
+                        is_vararg = true;
+                    }
+                    _ => return Err(self.error_near("<name> expected")),
+                }
+                if is_vararg || !self.test_next(&Token::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.expect(&Token::RightParen)?;
+
+        self.vararg.push(is_vararg);
+        let body = self.block();
+        self.vararg.pop();
+        let body = body?;
+
+        let end_line = self.span.line;
+        self.expect_match(&Token::End, &Token::Function, start.line)?;
+        Ok(FunctionBody {
+            params,
+            is_vararg,
+            body,
+            span: self.span_from(start),
+            end_line,
+        })
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<Expr>, CompileError> {
+        let mut exprs = vec![self.expr()?];
+        while self.test_next(&Token::Comma)? {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn expr(&mut self) -> Result<Expr, CompileError> {
+        self.sub_expr(0)
+    }
+
+    /// Parses an expression whose binary operators bind tighter than `limit`.
+    fn sub_expr(&mut self, limit: u8) -> Result<Expr, CompileError> {
+        self.enter()?;
+        let start = self.span;
+        let unary = match self.token {
+            Token::Not => Some(UnOp::Not),
+            Token::Minus => Some(UnOp::Neg),
+            Token::Len => Some(UnOp::Len),
+            Token::BitXor => Some(UnOp::BitNot),
+            _ => None,
+        };
+        let mut lhs = match unary {
+            Some(op) => {
+                self.advance()?;
+                let operand = self.sub_expr(UNARY_PRIORITY)?;
+                Expr::Unary {
+                    op,
+                    operand: Box::new(operand),
+                    span: self.span_from(start),
+                }
+            }
+            None => self.simple_expr()?,
+        };
+
+        while let Some(op) = binary_op(&self.token) {
+            let (left, right) = op.priority();
+            if left <= limit {
+                break;
+            }
+            self.advance()?;
+            let rhs = self.sub_expr(right)?;
+            lhs = Expr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                span: self.span_from(start),
+            };
+        }
+        self.leave();
+        Ok(lhs)
+    }
+
+    fn simple_expr(&mut self) -> Result<Expr, CompileError> {
+        let span = self.span;
+        let expr = match &self.token {
+            Token::Integer(i) => Expr::Integer(*i, span),
+            Token::Float(f) => Expr::Float(*f, span),
+            Token::String(s) => Expr::String(s.clone(), span),
+            Token::Nil => Expr::Nil(span),
+            Token::True => Expr::True(span),
+            Token::False => Expr::False(span),
+            Token::Dots => {
+                if !self.vararg.last().copied().unwrap_or(false) {
+                    return Err(self.error_near("cannot use '...' outside a vararg function"));
+                }
+                Expr::Vararg(span)
+            }
+            Token::LeftBrace => return self.table_constructor(),
+            Token::Function => {
+                self.advance()?;
+                return Ok(Expr::Function(Box::new(self.function_body(span, false)?)));
+            }
+            _ => return self.suffixed_expr(),
+        };
+        self.advance()?;
+        Ok(expr)
+    }
+
+    fn primary_expr(&mut self) -> Result<Expr, CompileError> {
+        match self.token {
+            Token::Name(_) => Ok(Expr::Name(self.name()?)),
+            Token::LeftParen => {
+                let start = self.span;
+                self.advance()?;
+                let inner = self.expr()?;
+                self.expect_match(&Token::RightParen, &Token::LeftParen, start.line)?;
+                Ok(Expr::Paren(Box::new(inner), self.span_from(start)))
+            }
+            _ => Err(self.error_near("unexpected symbol")),
+        }
+    }
+
+    fn suffixed_expr(&mut self) -> Result<Expr, CompileError> {
+        let start = self.span;
+        let mut expr = self.primary_expr()?;
+        loop {
+            match self.token {
+                Token::Dot => {
+                    self.advance()?;
+                    let name = self.name()?;
+                    expr = Expr::Index {
+                        object: Box::new(expr),
+                        key: Box::new(Expr::String(name.name.into_bytes(), name.span)),
+                        span: self.span_from(start),
+                    };
+                }
+                Token::LeftBracket => {
+                    self.advance()?;
+                    let key = self.expr()?;
+                    self.expect(&Token::RightBracket)?;
+                    expr = Expr::Index {
+                        object: Box::new(expr),
+                        key: Box::new(key),
+                        span: self.span_from(start),
+                    };
+                }
+                Token::Colon => {
+                    self.advance()?;
+                    let method = self.name()?;
+                    let args = self.call_args()?;
+                    expr = Expr::MethodCall {
+                        object: Box::new(expr),
+                        method,
+                        args,
+                        span: self.span_from(start),
+                    };
+                }
+                Token::LeftParen | Token::String(_) | Token::LeftBrace => {
+                    let args = self.call_args()?;
+                    expr = Expr::Call {
+                        func: Box::new(expr),
+                        args,
+                        span: self.span_from(start),
+                    };
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn call_args(&mut self) -> Result<Vec<Expr>, CompileError> {
+        match &self.token {
+            Token::String(s) => {
+                let arg = Expr::String(s.clone(), self.span);
+                self.advance()?;
+                Ok(vec![arg])
+            }
+            Token::LeftBrace => Ok(vec![self.table_constructor()?]),
+            Token::LeftParen => {
+                let line = self.span.line;
+                self.advance()?;
+                let args = if self.token == Token::RightParen {
+                    Vec::new()
+                } else {
+                    self.expr_list()?
+                };
+                self.expect_match(&Token::RightParen, &Token::LeftParen, line)?;
+                Ok(args)
+            }
+            _ => Err(self.error_near("function arguments expected")),
+        }
+    }
+
+    fn table_constructor(&mut self) -> Result<Expr, CompileError> {
+        let start = self.span;
+        self.expect(&Token::LeftBrace)?;
+        let mut fields = Vec::new();
+        while self.token != Token::RightBrace {
+            self.enter()?;
+            let named = matches!(self.token, Token::Name(_)) && *self.peek()? == Token::Assign;
+            let field = match self.token {
+                Token::Name(_) if named => {
+                    let name = self.name()?;
+                    self.advance()?;
+                    TableField::Named(name, self.expr()?)
+                }
+                Token::LeftBracket => {
+                    self.advance()?;
+                    let key = self.expr()?;
+                    self.expect(&Token::RightBracket)?;
+                    self.expect(&Token::Assign)?;
+                    TableField::Keyed(key, self.expr()?)
+                }
+                _ => TableField::Positional(self.expr()?),
+            };
+            self.leave();
+            fields.push(field);
+            if !self.test_next(&Token::Comma)? && !self.test_next(&Token::Semicolon)? {
+                break;
+            }
+        }
+        self.expect_match(&Token::RightBrace, &Token::LeftBrace, start.line)?;
+        Ok(Expr::Table {
+            fields,
+            span: self.span_from(start),
+        })
+    }
+}
+
+fn binary_op(token: &Token) -> Option<BinOp> {
+    Some(match token {
+        Token::Add => BinOp::Add,
+        Token::Minus => BinOp::Sub,
+        Token::Mul => BinOp::Mul,
+        Token::Div => BinOp::Div,
+        Token::IDiv => BinOp::IDiv,
+        Token::Mod => BinOp::Mod,
+        Token::Pow => BinOp::Pow,
+        Token::Concat => BinOp::Concat,
+        Token::BitAnd => BinOp::BitAnd,
+        Token::BitOr => BinOp::BitOr,
+        Token::BitXor => BinOp::BitXor,
+        Token::Shl => BinOp::Shl,
+        Token::Shr => BinOp::Shr,
+        Token::Eq => BinOp::Eq,
+        Token::Ne => BinOp::Ne,
+        Token::Lt => BinOp::Lt,
+        Token::Le => BinOp::Le,
+        Token::Gt => BinOp::Gt,
+        Token::Ge => BinOp::Ge,
+        Token::And => BinOp::And,
+        Token::Or => BinOp::Or,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renders an expression with explicit parentheses, to check precedence and associativity.
+    fn show(expr: &Expr) -> String {
+        match expr {
+            Expr::Integer(i, _) => i.to_string(),
+            Expr::Name(n) => n.name.clone(),
+            Expr::Binary { op, lhs, rhs, .. } => format!("({} {:?} {})", show(lhs), op, show(rhs)),
+            Expr::Unary { op, operand, .. } => format!("({:?} {})", op, show(operand)),
+            other => format!("{other:?}"),
+        }
+    }
+
+    fn parse_expr(source: &str) -> String {
+        let block = parse(format!("return {source}").as_bytes()).unwrap();
+        show(&block.ret.unwrap().values[0])
+    }
+
+    fn error(source: &str) -> String {
+        parse(source.as_bytes()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(parse_expr("1 + 2 * 3"), "(1 Add (2 Mul 3))");
+        assert_eq!(parse_expr("1 - 2 - 3"), "((1 Sub 2) Sub 3)");
+        assert_eq!(parse_expr("a .. b .. c"), "(a Concat (b Concat c))");
+        assert_eq!(parse_expr("2 ^ 3 ^ 2"), "(2 Pow (3 Pow 2))");
+        assert_eq!(parse_expr("-x ^ 2"), "(Neg (x Pow 2))");
+        assert_eq!(parse_expr("not a == b"), "((Not a) Eq b)");
+        assert_eq!(parse_expr("a or b and c"), "(a Or (b And c))");
+        assert_eq!(
+            parse_expr("1 | 2 ~ 3 & 4 << 5"),
+            "(1 BitOr (2 BitXor (3 BitAnd (4 Shl 5))))"
+        );
+        assert_eq!(parse_expr("a < b .. c"), "(a Lt (b Concat c))");
+    }
+
+    #[test]
+    fn statements() {
+        let block = parse(
+            b"local a <const>, b = 1, 2
+              function t.x.y:m(p, ...) return self end
+              for i = 1, 10, 2 do end
+              for k, v in pairs(t) do end
+              a, t[1] = f(), g 'x' {1}
+              ::top:: goto top
+              repeat local z until z
+              if a then elseif b then else end",
+        )
+        .unwrap();
+        assert_eq!(block.stats.len(), 9);
+
+        let Stat::Local {
+            names,
+            values,
+            span,
+        } = &block.stats[0]
+        else {
+            panic!("expected a local statement");
+        };
+        assert_eq!(names[0].attrib, Some(Attrib::Const));
+        assert_eq!(names[1].attrib, None);
+        assert_eq!(values.len(), 2);
+        assert_eq!((span.start, span.line), (0, 1));
+
+        let Stat::Function { name, body, span } = &block.stats[1] else {
+            panic!("expected a function statement");
+        };
+        assert_eq!(name.path.len(), 3);
+        assert_eq!(name.method.as_ref().unwrap().name, "m");
+        let params: Vec<_> = body.params.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(params, ["self", "p"]);
+        assert!(body.is_vararg);
+        assert_eq!((span.line, body.end_line), (2, 2));
+
+        let Stat::Assign {
+            targets, values, ..
+        } = &block.stats[4]
+        else {
+            panic!("expected an assignment");
+        };
+        assert_eq!(targets.len(), 2);
+        let Expr::Call { func, args, .. } = &values[1] else {
+            panic!("expected a call");
+        };
+        assert!(matches!(args[0], Expr::Table { .. }));
+        assert!(matches!(**func, Expr::Call { .. }));
+        assert!(matches!(&block.stats[7], Stat::Repeat { .. }));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(error("x = = 1"), "1: unexpected symbol near '='");
+        assert_eq!(error("f() = 1"), "1: syntax error near 'f()'");
+        assert_eq!(error("x"), "1: syntax error near <eof>");
+        assert_eq!(
+            error("if x then\n\n"),
+            "3: 'end' expected (to close 'if' at line 1) near <eof>"
+        );
+        assert_eq!(error("for x do end"), "1: '=' or 'in' expected near 'do'");
+        assert_eq!(error("return 1 x = 2"), "1: '<eof>' expected near 'x'");
+        assert_eq!(
+            error("local function f() return ... end"),
+            "1: cannot use '...' outside a vararg function near '...'"
+        );
+        assert_eq!(error("local x <foo> = 1"), "1: unknown attribute 'foo'");
+        assert_eq!(error("local t = {1, 2"), "1: '}' expected near <eof>");
+
+        // Unoptimized builds use a lot of stack per nesting level, more than the default test thread has.
+        let deep = std::thread::Builder::new()
+            .stack_size(16 << 20)
+            .spawn(|| error(&"(".repeat(300)))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(deep, "1: chunk has too many syntax levels near '('");
+    }
+
+    #[test]
+    fn recovers_from_errors() {
+        let source =
+            "local a = 1\nx = = 2\nif a then\n  f(\n  local b = 3\nend\nend\nc = 'open\nreturn a";
+        let (block, diagnostics) = parse_recovering(source.as_bytes(), CompatLevel::default());
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.span.line, d.message.as_str(), d.severity))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (2, "unexpected symbol near '='", Severity::Error),
+                (5, "unexpected symbol near 'local'", Severity::Error),
+                (7, "'<eof>' expected near 'end'", Severity::Error),
+                (8, "unfinished string near ''open'", Severity::Error),
+            ]
+        );
+        // `local a`, the `if` holding `local b`, and the return.
+        assert_eq!(block.stats.len(), 2);
+        let Stat::If { branches, .. } = &block.stats[1] else {
+            panic!("{:?}", block.stats[1]);
+        };
+        assert_eq!(branches[0].1.stats.len(), 1);
+        assert!(block.ret.is_some());
+
+        let (block, diagnostics) = parse_recovering(b"return 1", CompatLevel::default());
+        assert!(diagnostics.is_empty());
+        assert_eq!(block, parse(b"return 1").unwrap());
+    }
+}
@@ -0,0 +1,289 @@
+//! Local cleanups over the bytecode of a single function, run after code generation.
+//!
+//! Code generation emits a jump for every branch of every control structure and a load for every
+//! expression, without looking at what surrounds them. The passes here remove the redundancy that
+//! leaves behind while keeping every jump pointing at the same code.
+
+use crate::bytecode::{Instruction, LocalVar, OpCode};
+
+/// Rewrites `code` in place, keeping `lines`, the source position of each instruction, and the
+/// scopes of `local_vars` in step with it.
+pub(crate) fn optimize<T>(
+    code: &mut Vec<Instruction>,
+    lines: &mut Vec<T>,
+    local_vars: &mut [LocalVar<'_>],
+) {
+    // Each pass can expose more work for the others, but rarely more than once or twice.
+    for _ in 0..4 {
+        thread_jumps(code);
+        let mut keep = reachable(code);
+        let changed = remove_redundant(code, &mut keep);
+        if keep.iter().all(|&k| k) && !changed {
+            break;
+        }
+        compact(code, lines, local_vars, &keep);
+    }
+}
+
+fn opcode(i: Instruction) -> OpCode {
+    i.opcode().expect("invalid opcode in generated code")
+}
+
+/// Returns where an instruction jumps to, for the instructions that take a `sBx` jump offset.
+fn jump_target(code: &[Instruction], pc: usize) -> Option<usize> {
+    let i = code[pc];
+    match opcode(i) {
+        OpCode::Jmp | OpCode::ForPrep | OpCode::ForLoop | OpCode::TForLoop => {
+            Some((pc as i64 + 1 + i.sbx() as i64) as usize)
+        }
+        _ => None,
+    }
+}
+
+/// Returns true for instructions that may skip the instruction after them.
+fn skips_next(i: Instruction) -> bool {
+    match opcode(i) {
+        OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet => true,
+        OpCode::LoadBool => i.c() != 0,
+        // With `C` zero the batch number is in the following `EXTRAARG`.
+        OpCode::SetList => i.c() == 0,
+        _ => false,
+    }
+}
+
+/// Returns true for a jump that doesn't also close upvalues.
+fn is_plain_jump(i: Instruction) -> bool {
+    opcode(i) == OpCode::Jmp && i.a() == 0
+}
+
+/// Points jumps that land on an unconditional jump at that jump's destination instead.
+fn thread_jumps(code: &mut [Instruction]) {
+    for pc in 0..code.len() {
+        let Some(mut target) = jump_target(code, pc) else {
+            continue;
+        };
+        // Bounded, since a chain of jumps can loop forever.
+        for _ in 0..code.len() {
+            if !is_plain_jump(code[target]) {
+                break;
+            }
+            target = jump_target(code, target).unwrap();
+        }
+        code[pc].set_sbx((target as i64 - pc as i64 - 1) as i32);
+    }
+}
+
+/// Marks the instructions that can be executed.
+fn reachable(code: &[Instruction]) -> Vec<bool> {
+    let mut seen = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(pc) = pending.pop() {
+        if pc >= code.len() || seen[pc] {
+            continue;
+        }
+        seen[pc] = true;
+        let i = code[pc];
+        match opcode(i) {
+            OpCode::Return => {}
+            OpCode::Jmp | OpCode::ForPrep => pending.extend(jump_target(code, pc)),
+            OpCode::ForLoop | OpCode::TForLoop => {
+                pending.extend(jump_target(code, pc));
+                pending.push(pc + 1);
+            }
+            OpCode::LoadBool if i.c() != 0 => {
+                // Whatever follows has to stay in place for the skip to land in the right spot.
+                if pc + 1 < code.len() {
+                    seen[pc + 1] = true;
+                }
+                pending.push(pc + 2);
+            }
+            OpCode::SetList if i.c() == 0 => {
+                seen[pc + 1] = true;
+                pending.push(pc + 2);
+            }
+            _ => {
+                pending.push(pc + 1);
+                if skips_next(i) {
+                    pending.push(pc + 2);
+                }
+            }
+        }
+    }
+    seen
+}
+
+/// Returns the registers an instruction loads without reading anything from the stack, or `None`
+/// for any other instruction.
+fn loaded_registers(i: Instruction) -> Option<(u32, u32)> {
+    match opcode(i) {
+        OpCode::LoadK | OpCode::LoadI => Some((i.a(), i.a())),
+        OpCode::LoadBool if i.c() == 0 => Some((i.a(), i.a())),
+        OpCode::LoadNil => Some((i.a(), i.a() + i.b())),
+        _ => None,
+    }
+}
+
+/// Drops loads that are immediately overwritten, and merges consecutive `LOADNIL`s over adjacent
+/// registers. Returns true if any instruction was rewritten rather than just dropped.
+fn remove_redundant(code: &mut [Instruction], keep: &mut [bool]) -> bool {
+    let mut targets = vec![false; code.len() + 1];
+    for pc in 0..code.len() {
+        if let Some(target) = jump_target(code, pc) {
+            targets[target] = true;
+        }
+    }
+
+    let mut changed = false;
+    // Walks the kept instructions as pairs, with `prev` the one before the first of the pair.
+    let mut prev: Option<usize> = None;
+    let mut first: Option<usize> = None;
+    for pc in 0..code.len() {
+        if !keep[pc] {
+            continue;
+        }
+        // A jump to the very next instruction does nothing, unless something skips over it.
+        if jump_target(code, pc) == Some(pc + 1)
+            && is_plain_jump(code[pc])
+            && !first.is_some_and(|f| skips_next(code[f]))
+        {
+            keep[pc] = false;
+            continue;
+        }
+
+        let Some(f) = first else {
+            first = Some(pc);
+            continue;
+        };
+        let guarded = prev.is_some_and(|p| skips_next(code[p])) || skips_next(code[f]);
+        if !guarded {
+            if let (Some((a1, b1)), Some((a2, b2))) =
+                (loaded_registers(code[f]), loaded_registers(code[pc]))
+            {
+                if a2 <= a1 && b1 <= b2 {
+                    // The second load overwrites everything the first one does.
+                    keep[f] = false;
+                    first = Some(pc);
+                    continue;
+                }
+                let both_nil =
+                    opcode(code[f]) == OpCode::LoadNil && opcode(code[pc]) == OpCode::LoadNil;
+                if both_nil && !targets[pc] && a2 <= b1 + 1 && a1 <= b2 + 1 {
+                    let (a, b) = (a1.min(a2), b1.max(b2));
+                    code[f] = Instruction::abc(OpCode::LoadNil, a, b - a, 0);
+                    keep[pc] = false;
+                    changed = true;
+                    continue;
+                }
+            }
+        }
+        prev = first;
+        first = Some(pc);
+    }
+    changed
+}
+
+/// Removes the instructions not marked in `keep`, adjusting jump offsets and local scopes to match.
+fn compact<T>(
+    code: &mut Vec<Instruction>,
+    lines: &mut Vec<T>,
+    local_vars: &mut [LocalVar<'_>],
+    keep: &[bool],
+) {
+    // `new_pc[pc]` is the number of kept instructions before `pc`, which is where `pc` ends up, or
+    // where the next kept instruction does if it is removed.
+    let mut new_pc = Vec::with_capacity(code.len() + 1);
+    let mut count = 0;
+    for &k in keep {
+        new_pc.push(count);
+        count += k as usize;
+    }
+    new_pc.push(count);
+
+    for pc in 0..code.len() {
+        if let Some(target) = jump_target(code, pc) {
+            let offset = new_pc[target] as i64 - new_pc[pc] as i64 - 1;
+            code[pc].set_sbx(offset as i32);
+        }
+    }
+    for var in local_vars {
+        var.start_pc = new_pc[var.start_pc as usize] as u32;
+        var.end_pc = new_pc[var.end_pc as usize] as u32;
+    }
+
+    let mut pc = 0;
+    code.retain(|_| {
+        pc += 1;
+        keep[pc - 1]
+    });
+    let mut pc = 0;
+    lines.retain(|_| {
+        pc += 1;
+        keep[pc - 1]
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(code: Vec<Instruction>) -> Vec<Instruction> {
+        let mut code = code;
+        let mut lines = vec![1; code.len()];
+        optimize(&mut code, &mut lines, &mut []);
+        assert_eq!(code.len(), lines.len());
+        code
+    }
+
+    #[test]
+    fn threads_jumps_and_removes_dead_code() {
+        let code = run(vec![
+            Instruction::abc(OpCode::Test, 0, 0, 0),
+            Instruction::asbx(OpCode::Jmp, 0, 2),
+            Instruction::asbx(OpCode::LoadI, 1, 1),
+            Instruction::abc(OpCode::Return, 1, 2, 0),
+            Instruction::asbx(OpCode::Jmp, 0, 1),
+            Instruction::asbx(OpCode::LoadI, 1, 2),
+            Instruction::abc(OpCode::Return, 1, 2, 0),
+            Instruction::abc(OpCode::Return, 0, 1, 0),
+        ]);
+        assert_eq!(
+            code,
+            [
+                Instruction::abc(OpCode::Test, 0, 0, 0),
+                Instruction::asbx(OpCode::Jmp, 0, 2),
+                Instruction::asbx(OpCode::LoadI, 1, 1),
+                Instruction::abc(OpCode::Return, 1, 2, 0),
+                Instruction::abc(OpCode::Return, 1, 2, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_loads() {
+        let code = run(vec![
+            Instruction::abc(OpCode::LoadNil, 0, 0, 0),
+            Instruction::abc(OpCode::LoadNil, 1, 1, 0),
+            Instruction::asbx(OpCode::LoadI, 3, 1),
+            Instruction::asbx(OpCode::LoadI, 3, 2),
+            Instruction::abc(OpCode::Return, 0, 5, 0),
+        ]);
+        assert_eq!(
+            code,
+            [
+                Instruction::abc(OpCode::LoadNil, 0, 2, 0),
+                Instruction::asbx(OpCode::LoadI, 3, 2),
+                Instruction::abc(OpCode::Return, 0, 5, 0),
+            ]
+        );
+
+        // The pair of loads that turns a comparison into a boolean must stay as it is.
+        let comparison = vec![
+            Instruction::abc(OpCode::Lt, 1, 0, 1),
+            Instruction::asbx(OpCode::Jmp, 0, 1),
+            Instruction::abc(OpCode::LoadBool, 2, 0, 1),
+            Instruction::abc(OpCode::LoadBool, 2, 1, 0),
+            Instruction::abc(OpCode::Return, 2, 2, 0),
+        ];
+        assert_eq!(run(comparison.clone()), comparison);
+    }
+}
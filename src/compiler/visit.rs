@@ -0,0 +1,466 @@
+//! Walking the [syntax tree](super::ast), for tools that look at or rewrite parts of it without
+//! matching every kind of node themselves.
+//!
+//! A [`Visit`] or [`VisitMut`] overrides the methods for the nodes it cares about. Each method
+//! defaults to the `walk_` function of the same name, which visits the children of the node in
+//! source order; an override calls it to go on into them, or doesn't to skip them.
+
+use super::ast::{Block, Expr, FuncName, FunctionBody, LocalName, Name, Return, Stat, TableField};
+
+/// Looks at a syntax tree. Borrows are for `'ast`, so that a visitor can keep the nodes it finds.
+pub trait Visit<'ast> {
+    fn visit_block(&mut self, block: &'ast Block) {
+        walk_block(self, block);
+    }
+
+    fn visit_stat(&mut self, stat: &'ast Stat) {
+        walk_stat(self, stat);
+    }
+
+    fn visit_return(&mut self, ret: &'ast Return) {
+        walk_return(self, ret);
+    }
+
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        walk_expr(self, expr);
+    }
+
+    fn visit_function_body(&mut self, body: &'ast FunctionBody) {
+        walk_function_body(self, body);
+    }
+
+    fn visit_table_field(&mut self, field: &'ast TableField) {
+        walk_table_field(self, field);
+    }
+
+    /// Every name in the tree: variables read, assigned and declared, parameters, labels, and the
+    /// parts of function and method names.
+    fn visit_name(&mut self, _name: &'ast Name) {}
+}
+
+pub fn walk_block<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, block: &'ast Block) {
+    for stat in &block.stats {
+        visitor.visit_stat(stat);
+    }
+    if let Some(ret) = &block.ret {
+        visitor.visit_return(ret);
+    }
+}
+
+pub fn walk_stat<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, stat: &'ast Stat) {
+    match stat {
+        Stat::Assign {
+            targets, values, ..
+        } => {
+            for expr in targets.iter().chain(values) {
+                visitor.visit_expr(expr);
+            }
+        }
+        Stat::Call(call) => visitor.visit_expr(call),
+        Stat::Label(name) | Stat::Goto(name) => visitor.visit_name(name),
+        Stat::Break(_) => {}
+        Stat::Do(body) => visitor.visit_block(body),
+        Stat::While { cond, body, .. } => {
+            visitor.visit_expr(cond);
+            visitor.visit_block(body);
+        }
+        Stat::Repeat { body, cond, .. } => {
+            visitor.visit_block(body);
+            visitor.visit_expr(cond);
+        }
+        Stat::If {
+            branches,
+            else_block,
+            ..
+        } => {
+            for (cond, body) in branches {
+                visitor.visit_expr(cond);
+                visitor.visit_block(body);
+            }
+            if let Some(body) = else_block {
+                visitor.visit_block(body);
+            }
+        }
+        Stat::NumericFor {
+            var,
+            start,
+            limit,
+            step,
+            body,
+            ..
+        } => {
+            visitor.visit_name(var);
+            visitor.visit_expr(start);
+            visitor.visit_expr(limit);
+            if let Some(step) = step {
+                visitor.visit_expr(step);
+            }
+            visitor.visit_block(body);
+        }
+        Stat::GenericFor {
+            names, exprs, body, ..
+        } => {
+            for name in names {
+                visitor.visit_name(name);
+            }
+            for expr in exprs {
+                visitor.visit_expr(expr);
+            }
+            visitor.visit_block(body);
+        }
+        Stat::Function { name, body, .. } => {
+            let FuncName { path, method } = name;
+            for name in path.iter().chain(method) {
+                visitor.visit_name(name);
+            }
+            visitor.visit_function_body(body);
+        }
+        Stat::LocalFunction { name, body, .. } => {
+            visitor.visit_name(name);
+            visitor.visit_function_body(body);
+        }
+        Stat::Local { names, values, .. } => {
+            for LocalName { name, .. } in names {
+                visitor.visit_name(name);
+            }
+            for expr in values {
+                visitor.visit_expr(expr);
+            }
+        }
+    }
+}
+
+pub fn walk_return<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, ret: &'ast Return) {
+    for expr in &ret.values {
+        visitor.visit_expr(expr);
+    }
+}
+
+pub fn walk_expr<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, expr: &'ast Expr) {
+    match expr {
+        Expr::Nil(_)
+        | Expr::True(_)
+        | Expr::False(_)
+        | Expr::Integer(..)
+        | Expr::Float(..)
+        | Expr::String(..)
+        | Expr::Vararg(_) => {}
+        Expr::Function(body) => visitor.visit_function_body(body),
+        Expr::Table { fields, .. } => {
+            for field in fields {
+                visitor.visit_table_field(field);
+            }
+        }
+        Expr::Binary { lhs, rhs, .. } => {
+            visitor.visit_expr(lhs);
+            visitor.visit_expr(rhs);
+        }
+        Expr::Unary { operand, .. } => visitor.visit_expr(operand),
+        Expr::Name(name) => visitor.visit_name(name),
+        Expr::Index { object, key, .. } => {
+            visitor.visit_expr(object);
+            visitor.visit_expr(key);
+        }
+        Expr::Call { func, args, .. } => {
+            visitor.visit_expr(func);
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        }
+        Expr::MethodCall {
+            object,
+            method,
+            args,
+            ..
+        } => {
+            visitor.visit_expr(object);
+            visitor.visit_name(method);
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        }
+        Expr::Paren(inner, _) => visitor.visit_expr(inner),
+    }
+}
+
+pub fn walk_function_body<'ast, V: Visit<'ast> + ?Sized>(
+    visitor: &mut V,
+    body: &'ast FunctionBody,
+) {
+    for param in &body.params {
+        visitor.visit_name(param);
+    }
+    visitor.visit_block(&body.body);
+}
+
+pub fn walk_table_field<'ast, V: Visit<'ast> + ?Sized>(visitor: &mut V, field: &'ast TableField) {
+    match field {
+        TableField::Positional(value) => visitor.visit_expr(value),
+        TableField::Named(name, value) => {
+            visitor.visit_name(name);
+            visitor.visit_expr(value);
+        }
+        TableField::Keyed(key, value) => {
+            visitor.visit_expr(key);
+            visitor.visit_expr(value);
+        }
+    }
+}
+
+/// Rewrites a syntax tree in place, visiting it in the order [`Visit`] does.
+pub trait VisitMut {
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        walk_block_mut(self, block);
+    }
+
+    fn visit_stat_mut(&mut self, stat: &mut Stat) {
+        walk_stat_mut(self, stat);
+    }
+
+    fn visit_return_mut(&mut self, ret: &mut Return) {
+        walk_return_mut(self, ret);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr);
+    }
+
+    fn visit_function_body_mut(&mut self, body: &mut FunctionBody) {
+        walk_function_body_mut(self, body);
+    }
+
+    fn visit_table_field_mut(&mut self, field: &mut TableField) {
+        walk_table_field_mut(self, field);
+    }
+
+    /// Every name in the tree, as for [`Visit::visit_name`].
+    fn visit_name_mut(&mut self, _name: &mut Name) {}
+}
+
+pub fn walk_block_mut<V: VisitMut + ?Sized>(visitor: &mut V, block: &mut Block) {
+    for stat in &mut block.stats {
+        visitor.visit_stat_mut(stat);
+    }
+    if let Some(ret) = &mut block.ret {
+        visitor.visit_return_mut(ret);
+    }
+}
+
+pub fn walk_stat_mut<V: VisitMut + ?Sized>(visitor: &mut V, stat: &mut Stat) {
+    match stat {
+        Stat::Assign {
+            targets, values, ..
+        } => {
+            for expr in targets.iter_mut().chain(values) {
+                visitor.visit_expr_mut(expr);
+            }
+        }
+        Stat::Call(call) => visitor.visit_expr_mut(call),
+        Stat::Label(name) | Stat::Goto(name) => visitor.visit_name_mut(name),
+        Stat::Break(_) => {}
+        Stat::Do(body) => visitor.visit_block_mut(body),
+        Stat::While { cond, body, .. } => {
+            visitor.visit_expr_mut(cond);
+            visitor.visit_block_mut(body);
+        }
+        Stat::Repeat { body, cond, .. } => {
+            visitor.visit_block_mut(body);
+            visitor.visit_expr_mut(cond);
+        }
+        Stat::If {
+            branches,
+            else_block,
+            ..
+        } => {
+            for (cond, body) in branches {
+                visitor.visit_expr_mut(cond);
+                visitor.visit_block_mut(body);
+            }
+            if let Some(body) = else_block {
+                visitor.visit_block_mut(body);
+            }
+        }
+        Stat::NumericFor {
+            var,
+            start,
+            limit,
+            step,
+            body,
+            ..
+        } => {
+            visitor.visit_name_mut(var);
+            visitor.visit_expr_mut(start);
+            visitor.visit_expr_mut(limit);
+            if let Some(step) = step {
+                visitor.visit_expr_mut(step);
+            }
+            visitor.visit_block_mut(body);
+        }
+        Stat::GenericFor {
+            names, exprs, body, ..
+        } => {
+            for name in names {
+                visitor.visit_name_mut(name);
+            }
+            for expr in exprs {
+                visitor.visit_expr_mut(expr);
+            }
+            visitor.visit_block_mut(body);
+        }
+        Stat::Function { name, body, .. } => {
+            let FuncName { path, method } = name;
+            for name in path.iter_mut().chain(method) {
+                visitor.visit_name_mut(name);
+            }
+            visitor.visit_function_body_mut(body);
+        }
+        Stat::LocalFunction { name, body, .. } => {
+            visitor.visit_name_mut(name);
+            visitor.visit_function_body_mut(body);
+        }
+        Stat::Local { names, values, .. } => {
+            for LocalName { name, .. } in names {
+                visitor.visit_name_mut(name);
+            }
+            for expr in values {
+                visitor.visit_expr_mut(expr);
+            }
+        }
+    }
+}
+
+pub fn walk_return_mut<V: VisitMut + ?Sized>(visitor: &mut V, ret: &mut Return) {
+    for expr in &mut ret.values {
+        visitor.visit_expr_mut(expr);
+    }
+}
+
+pub fn walk_expr_mut<V: VisitMut + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match expr {
+        Expr::Nil(_)
+        | Expr::True(_)
+        | Expr::False(_)
+        | Expr::Integer(..)
+        | Expr::Float(..)
+        | Expr::String(..)
+        | Expr::Vararg(_) => {}
+        Expr::Function(body) => visitor.visit_function_body_mut(body),
+        Expr::Table { fields, .. } => {
+            for field in fields {
+                visitor.visit_table_field_mut(field);
+            }
+        }
+        Expr::Binary { lhs, rhs, .. } => {
+            visitor.visit_expr_mut(lhs);
+            visitor.visit_expr_mut(rhs);
+        }
+        Expr::Unary { operand, .. } => visitor.visit_expr_mut(operand),
+        Expr::Name(name) => visitor.visit_name_mut(name),
+        Expr::Index { object, key, .. } => {
+            visitor.visit_expr_mut(object);
+            visitor.visit_expr_mut(key);
+        }
+        Expr::Call { func, args, .. } => {
+            visitor.visit_expr_mut(func);
+            for arg in args {
+                visitor.visit_expr_mut(arg);
+            }
+        }
+        Expr::MethodCall {
+            object,
+            method,
+            args,
+            ..
+        } => {
+            visitor.visit_expr_mut(object);
+            visitor.visit_name_mut(method);
+            for arg in args {
+                visitor.visit_expr_mut(arg);
+            }
+        }
+        Expr::Paren(inner, _) => visitor.visit_expr_mut(inner),
+    }
+}
+
+pub fn walk_function_body_mut<V: VisitMut + ?Sized>(visitor: &mut V, body: &mut FunctionBody) {
+    for param in &mut body.params {
+        visitor.visit_name_mut(param);
+    }
+    visitor.visit_block_mut(&mut body.body);
+}
+
+pub fn walk_table_field_mut<V: VisitMut + ?Sized>(visitor: &mut V, field: &mut TableField) {
+    match field {
+        TableField::Positional(value) => visitor.visit_expr_mut(value),
+        TableField::Named(name, value) => {
+            visitor.visit_name_mut(name);
+            visitor.visit_expr_mut(value);
+        }
+        TableField::Keyed(key, value) => {
+            visitor.visit_expr_mut(key);
+            visitor.visit_expr_mut(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::parser::parse;
+
+    /// The globals a chunk assigns to, ignoring shadowing by locals.
+    struct Assigned<'ast>(Vec<&'ast str>);
+
+    impl<'ast> Visit<'ast> for Assigned<'ast> {
+        fn visit_stat(&mut self, stat: &'ast Stat) {
+            if let Stat::Assign { targets, .. } = stat {
+                for target in targets {
+                    if let Expr::Name(name) = target {
+                        self.0.push(&name.name);
+                    }
+                }
+            }
+            walk_stat(self, stat);
+        }
+
+        // Skips nested functions.
+        fn visit_function_body(&mut self, _body: &'ast FunctionBody) {}
+    }
+
+    struct Rename;
+
+    impl VisitMut for Rename {
+        fn visit_name_mut(&mut self, name: &mut Name) {
+            if name.name == "x" {
+                "renamed".clone_into(&mut name.name);
+            }
+        }
+    }
+
+    #[test]
+    fn visits_trees() {
+        let source = b"a = 1
+            local function f(x) b = x end
+            for i = 1, 2 do c, t.d = i, {x = i} end
+            if a then e = f(function() g = 1 end) end
+            return x:x()";
+        let mut block = parse(source).unwrap();
+        let mut assigned = Assigned(Vec::new());
+        assigned.visit_block(&block);
+        assert_eq!(assigned.0, ["a", "c", "e"]);
+
+        Rename.visit_block_mut(&mut block);
+        let mut names = Vec::new();
+        struct Names<'a>(&'a mut Vec<String>);
+        impl Visit<'_> for Names<'_> {
+            fn visit_name(&mut self, name: &Name) {
+                self.0.push(name.name.clone());
+            }
+        }
+        Names(&mut names).visit_block(&block);
+        assert_eq!(
+            names.join(" "),
+            "a f renamed b renamed i c t i renamed i a e f g renamed renamed"
+        );
+    }
+}
@@ -0,0 +1,762 @@
+//! Conversions between Rust and Lua values, which give native functions written in Rust typed
+//! arguments and results.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Deref, DerefMut};
+
+use crate::vm::ops;
+use crate::{
+    AnyUserData, Context, Function, LuaError, LuaString, RuntimeError, Table, Thread, UserData,
+    Value,
+};
+
+/// Why a value couldn't be converted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionError {
+    /// The type converted from: a Lua type name, or a Rust one going the other way.
+    pub from: &'static str,
+    /// The type converted to.
+    pub to: &'static str,
+    /// What went wrong, when it isn't just that the types don't match.
+    pub message: Option<String>,
+}
+
+impl ConversionError {
+    /// The error for a `value` that isn't of the type `to` at all.
+    pub fn mismatch(value: Value<'_>, to: &'static str) -> ConversionError {
+        ConversionError {
+            from: value.type_name(),
+            to,
+            message: None,
+        }
+    }
+
+    fn with_message(value: Value<'_>, to: &'static str, message: &str) -> ConversionError {
+        ConversionError {
+            message: Some(message.to_owned()),
+            ..ConversionError::mismatch(value, to)
+        }
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.message {
+            Some(message) => f.write_str(message),
+            None => write!(f, "{} expected, got {}", self.to, self.from),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl From<ConversionError> for RuntimeError {
+    fn from(err: ConversionError) -> RuntimeError {
+        RuntimeError::new(err.to_string())
+    }
+}
+
+impl<'gc> From<ConversionError> for LuaError<'gc> {
+    fn from(err: ConversionError) -> LuaError<'gc> {
+        RuntimeError::from(err).into()
+    }
+}
+
+/// An argument that couldn't be converted, with its position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgumentError {
+    /// The index of the argument among the ones converted, counting from 0.
+    pub index: usize,
+    pub error: ConversionError,
+}
+
+impl ArgumentError {
+    /// The error a function called `name` raises for it, when the converted arguments start at
+    /// position `first` (counting from 1).
+    pub fn into_runtime_error(self, name: &str, first: usize) -> RuntimeError {
+        RuntimeError::new(format!(
+            "bad argument #{} to '{name}' ({})",
+            first + self.index,
+            self.error
+        ))
+    }
+}
+
+/// A Rust value that converts to a Lua value.
+pub trait IntoLua<'gc> {
+    fn into_lua(self, ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError>;
+}
+
+/// A Rust value that can be converted from a Lua value.
+pub trait FromLua<'gc>: Sized {
+    fn from_lua(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError>;
+}
+
+/// Rust values that convert to any number of Lua values, such as the results of a function.
+pub trait IntoLuaMulti<'gc> {
+    fn into_lua_multi(self, ctx: Context<'gc>) -> Result<Vec<Value<'gc>>, ConversionError>;
+}
+
+/// Rust values converted from a list of Lua values, such as the arguments to a function. Missing
+/// values convert as nil and extra ones are ignored.
+pub trait FromLuaMulti<'gc>: Sized {
+    fn from_lua_multi(ctx: Context<'gc>, values: &[Value<'gc>]) -> Result<Self, ArgumentError>;
+}
+
+impl<'gc> IntoLua<'gc> for Value<'gc> {
+    fn into_lua(self, _ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        Ok(self)
+    }
+}
+
+impl<'gc> FromLua<'gc> for Value<'gc> {
+    fn from_lua(_ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        Ok(value)
+    }
+}
+
+impl<'gc> IntoLua<'gc> for bool {
+    fn into_lua(self, _ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        Ok(Value::Boolean(self))
+    }
+}
+
+/// Any value converts, by Lua's truthiness.
+impl<'gc> FromLua<'gc> for bool {
+    fn from_lua(_ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        Ok(value.to_bool())
+    }
+}
+
+impl<'gc> IntoLua<'gc> for i64 {
+    fn into_lua(self, _ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        Ok(Value::Integer(self))
+    }
+}
+
+/// Floats with an integer value and strings holding one convert too, as they do for the standard
+/// library's functions.
+impl<'gc> FromLua<'gc> for i64 {
+    fn from_lua(_ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        match ops::coerce_number(value) {
+            Some(n) => n.to_integer().ok_or_else(|| {
+                ConversionError::with_message(
+                    value,
+                    "integer",
+                    "number has no integer representation",
+                )
+            }),
+            None => Err(ConversionError::mismatch(value, "number")),
+        }
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($ty:ty),*) => {$(
+        impl<'gc> IntoLua<'gc> for $ty {
+            fn into_lua(self, _ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+                match i64::try_from(self) {
+                    Ok(i) => Ok(Value::Integer(i)),
+                    Err(_) => Ok(Value::Number(self as f64)),
+                }
+            }
+        }
+
+        impl<'gc> FromLua<'gc> for $ty {
+            fn from_lua(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+                let i = i64::from_lua(ctx, value)?;
+                <$ty>::try_from(i).map_err(|_| {
+                    ConversionError::with_message(value, stringify!($ty), "number out of range")
+                })
+            }
+        }
+    )*};
+}
+
+integer_conversions!(i8, u8, i16, u16, i32, u32, u64, isize, usize);
+
+impl<'gc> IntoLua<'gc> for f64 {
+    fn into_lua(self, _ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        Ok(Value::Number(self))
+    }
+}
+
+/// Integers and strings holding a number convert too.
+impl<'gc> FromLua<'gc> for f64 {
+    fn from_lua(_ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        ops::coerce_number(value)
+            .and_then(Value::to_number)
+            .ok_or_else(|| ConversionError::mismatch(value, "number"))
+    }
+}
+
+impl<'gc> IntoLua<'gc> for f32 {
+    fn into_lua(self, _ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        Ok(Value::Number(self.into()))
+    }
+}
+
+impl<'gc> FromLua<'gc> for f32 {
+    fn from_lua(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        Ok(f64::from_lua(ctx, value)? as f32)
+    }
+}
+
+impl<'gc> IntoLua<'gc> for LuaString<'gc> {
+    fn into_lua(self, _ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        Ok(Value::String(self))
+    }
+}
+
+/// Numbers convert too, as they do for the standard library's functions.
+impl<'gc> FromLua<'gc> for LuaString<'gc> {
+    fn from_lua(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        match value {
+            Value::String(s) => Ok(s),
+            Value::Integer(_) | Value::Number(_) => {
+                let mut bytes = Vec::new();
+                ops::write_concat_operand(&mut bytes, value);
+                Ok(LuaString::from_vec(&ctx, bytes))
+            }
+            _ => Err(ConversionError::mismatch(value, "string")),
+        }
+    }
+}
+
+impl<'gc> IntoLua<'gc> for &str {
+    fn into_lua(self, ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        Ok(Value::String(LuaString::new(&ctx, self.as_bytes())))
+    }
+}
+
+impl<'gc> IntoLua<'gc> for String {
+    fn into_lua(self, ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        Ok(Value::String(LuaString::from_vec(&ctx, self.into_bytes())))
+    }
+}
+
+/// Like a [`LuaString`], but the bytes have to be valid UTF-8.
+impl<'gc> FromLua<'gc> for String {
+    fn from_lua(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        let s = LuaString::from_lua(ctx, value)?;
+        match s.to_str() {
+            Ok(s) => Ok(s.to_owned()),
+            Err(_) => Err(ConversionError::with_message(
+                value,
+                "String",
+                "string is not valid UTF-8",
+            )),
+        }
+    }
+}
+
+macro_rules! reference_conversions {
+    ($($ty:ident => $name:literal),*) => {$(
+        impl<'gc> IntoLua<'gc> for $ty<'gc> {
+            fn into_lua(self, _ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+                Ok(Value::$ty(self))
+            }
+        }
+
+        impl<'gc> FromLua<'gc> for $ty<'gc> {
+            fn from_lua(_ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+                match value {
+                    Value::$ty(v) => Ok(v),
+                    _ => Err(ConversionError::mismatch(value, $name)),
+                }
+            }
+        }
+    )*};
+}
+
+reference_conversions!(Table => "table", Function => "function", Thread => "thread");
+
+impl<'gc> IntoLua<'gc> for AnyUserData<'gc> {
+    fn into_lua(self, _ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        Ok(Value::UserData(self))
+    }
+}
+
+impl<'gc> FromLua<'gc> for AnyUserData<'gc> {
+    fn from_lua(_ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        match value {
+            Value::UserData(u) => Ok(u),
+            _ => Err(ConversionError::mismatch(value, "userdata")),
+        }
+    }
+}
+
+/// A sequence.
+impl<'gc, T: IntoLua<'gc>> IntoLua<'gc> for Vec<T> {
+    fn into_lua(self, ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        let table = Table::with_capacity(&ctx, self.len(), 0);
+        for (i, v) in self.into_iter().enumerate() {
+            let v = v.into_lua(ctx)?;
+            table
+                .set(&ctx, i as i64 + 1, v)
+                .expect("integer keys are always valid");
+        }
+        Ok(Value::Table(table))
+    }
+}
+
+/// The sequence of a table, from 1 up to its border. Metamethods aren't called.
+impl<'gc, T: FromLua<'gc>> FromLua<'gc> for Vec<T> {
+    fn from_lua(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        let Value::Table(table) = value else {
+            return Err(ConversionError::mismatch(value, "table"));
+        };
+        (1..=table.length())
+            .map(|i| {
+                T::from_lua(ctx, table.get(i as i64)).map_err(|err| {
+                    ConversionError::with_message(
+                        value,
+                        "Vec",
+                        &format!("bad element #{i} ({err})"),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+/// Builds a table from key-value pairs, failing on a nil or NaN key.
+fn table_from_entries<'gc, K, V>(
+    ctx: Context<'gc>,
+    entries: impl ExactSizeIterator<Item = (K, V)>,
+    from: &'static str,
+) -> Result<Value<'gc>, ConversionError>
+where
+    K: IntoLua<'gc>,
+    V: IntoLua<'gc>,
+{
+    let table = Table::with_capacity(&ctx, 0, entries.len());
+    for (k, v) in entries {
+        let (k, v) = (k.into_lua(ctx)?, v.into_lua(ctx)?);
+        table.set(&ctx, k, v).map_err(|err| ConversionError {
+            from,
+            to: "table",
+            message: Some(err.to_string()),
+        })?;
+    }
+    Ok(Value::Table(table))
+}
+
+/// Converts every entry of a table, without calling metamethods.
+fn entries_from_table<'gc, K, V, C>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+    to: &'static str,
+) -> Result<C, ConversionError>
+where
+    K: FromLua<'gc>,
+    V: FromLua<'gc>,
+    C: FromIterator<(K, V)>,
+{
+    let Value::Table(table) = value else {
+        return Err(ConversionError::mismatch(value, "table"));
+    };
+    let mut entries = Vec::new();
+    let mut key = Value::Nil;
+    while let Ok(Some((k, v))) = table.next(key) {
+        let bad = |what: &str, err: ConversionError| {
+            ConversionError::with_message(value, to, &format!("bad {what} ({err})"))
+        };
+        let converted = K::from_lua(ctx, k).map_err(|err| bad("key", err))?;
+        let v = V::from_lua(ctx, v).map_err(|err| bad(&format!("value for key {k}"), err))?;
+        entries.push((converted, v));
+        key = k;
+    }
+    Ok(entries.into_iter().collect())
+}
+
+impl<'gc, K, V, S> IntoLua<'gc> for HashMap<K, V, S>
+where
+    K: IntoLua<'gc>,
+    V: IntoLua<'gc>,
+{
+    fn into_lua(self, ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        table_from_entries(ctx, self.into_iter(), "HashMap")
+    }
+}
+
+impl<'gc, K, V, S> FromLua<'gc> for HashMap<K, V, S>
+where
+    K: FromLua<'gc> + Eq + Hash,
+    V: FromLua<'gc>,
+    S: BuildHasher + Default,
+{
+    fn from_lua(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        entries_from_table(ctx, value, "HashMap")
+    }
+}
+
+impl<'gc, K: IntoLua<'gc>, V: IntoLua<'gc>> IntoLua<'gc> for BTreeMap<K, V> {
+    fn into_lua(self, ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        table_from_entries(ctx, self.into_iter(), "BTreeMap")
+    }
+}
+
+impl<'gc, K: FromLua<'gc> + Ord, V: FromLua<'gc>> FromLua<'gc> for BTreeMap<K, V> {
+    fn from_lua(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        entries_from_table(ctx, value, "BTreeMap")
+    }
+}
+
+/// `None` is nil.
+impl<'gc, T: IntoLua<'gc>> IntoLua<'gc> for Option<T> {
+    fn into_lua(self, ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        match self {
+            Some(v) => v.into_lua(ctx),
+            None => Ok(Value::Nil),
+        }
+    }
+}
+
+/// Nil is `None`, so the argument is optional.
+impl<'gc, T: FromLua<'gc>> FromLua<'gc> for Option<T> {
+    fn from_lua(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        match value {
+            Value::Nil => Ok(None),
+            _ => T::from_lua(ctx, value).map(Some),
+        }
+    }
+}
+
+/// A userdata type moves into a new userdata, with the metatable
+/// [`UserData::register`] describes.
+impl<'gc, T: UserData> IntoLua<'gc> for T {
+    fn into_lua(self, ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+        Ok(Value::UserData(ctx.create_userdata(self)))
+    }
+}
+
+/// A userdata type that can be cloned is cloned out of a userdata holding one.
+impl<'gc, T: UserData + Clone> FromLua<'gc> for T {
+    fn from_lua(_ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+        let to = crate::userdata::short_type_name::<T>();
+        let Value::UserData(u) = value else {
+            return Err(ConversionError::mismatch(value, to));
+        };
+        match u.borrow::<T>() {
+            Ok(v) => Ok(v.clone()),
+            Err(crate::UserDataError::WrongType) => Err(ConversionError {
+                from: u.type_name(),
+                to,
+                message: None,
+            }),
+            Err(err) => Err(ConversionError::with_message(value, to, &err.to_string())),
+        }
+    }
+}
+
+impl<'gc> IntoLuaMulti<'gc> for () {
+    fn into_lua_multi(self, _ctx: Context<'gc>) -> Result<Vec<Value<'gc>>, ConversionError> {
+        Ok(Vec::new())
+    }
+}
+
+impl<'gc> FromLuaMulti<'gc> for () {
+    fn from_lua_multi(_ctx: Context<'gc>, _values: &[Value<'gc>]) -> Result<Self, ArgumentError> {
+        Ok(())
+    }
+}
+
+impl<'gc, T: IntoLua<'gc>> IntoLuaMulti<'gc> for T {
+    fn into_lua_multi(self, ctx: Context<'gc>) -> Result<Vec<Value<'gc>>, ConversionError> {
+        Ok(vec![self.into_lua(ctx)?])
+    }
+}
+
+impl<'gc, T: FromLua<'gc>> FromLuaMulti<'gc> for T {
+    fn from_lua_multi(ctx: Context<'gc>, values: &[Value<'gc>]) -> Result<Self, ArgumentError> {
+        convert_argument(ctx, values, 0)
+    }
+}
+
+/// Converts `values[index]`, calling a missing value "no value" in the error.
+fn convert_argument<'gc, T: FromLua<'gc>>(
+    ctx: Context<'gc>,
+    values: &[Value<'gc>],
+    index: usize,
+) -> Result<T, ArgumentError> {
+    let value = values.get(index).copied();
+    T::from_lua(ctx, value.unwrap_or_default()).map_err(|mut error| {
+        if value.is_none() {
+            error.from = "no value";
+        }
+        ArgumentError { index, error }
+    })
+}
+
+/// Tuples convert element by element, except that the last element takes all the remaining values:
+/// like a function call at the end of a Lua expression list, it can be a [`Variadic`] or a
+/// [`MultiValue`].
+macro_rules! tuple_conversions {
+    ($($name:ident $index:tt,)* ; $last:ident $last_index:tt) => {
+        impl<'gc, $($name: IntoLua<'gc>,)* $last: IntoLuaMulti<'gc>> IntoLuaMulti<'gc>
+            for ($($name,)* $last,)
+        {
+            fn into_lua_multi(self, ctx: Context<'gc>) -> Result<Vec<Value<'gc>>, ConversionError> {
+                let mut values = vec![$(self.$index.into_lua(ctx)?),*];
+                values.extend(self.$last_index.into_lua_multi(ctx)?);
+                Ok(values)
+            }
+        }
+
+        impl<'gc, $($name: FromLua<'gc>,)* $last: FromLuaMulti<'gc>> FromLuaMulti<'gc>
+            for ($($name,)* $last,)
+        {
+            fn from_lua_multi(
+                ctx: Context<'gc>,
+                values: &[Value<'gc>],
+            ) -> Result<Self, ArgumentError> {
+                Ok((
+                    $(convert_argument::<$name>(ctx, values, $index)?,)*
+                    {
+                        let rest = values.get($last_index..).unwrap_or_default();
+                        $last::from_lua_multi(ctx, rest).map_err(|mut err| {
+                            err.index += $last_index;
+                            err
+                        })?
+                    },
+                ))
+            }
+        }
+    };
+}
+
+tuple_conversions!(; A 0);
+tuple_conversions!(A 0,; B 1);
+tuple_conversions!(A 0, B 1,; C 2);
+tuple_conversions!(A 0, B 1, C 2,; D 3);
+tuple_conversions!(A 0, B 1, C 2, D 3,; E 4);
+tuple_conversions!(A 0, B 1, C 2, D 3, E 4,; F 5);
+tuple_conversions!(A 0, B 1, C 2, D 3, E 4, F 5,; G 6);
+tuple_conversions!(A 0, B 1, C 2, D 3, E 4, F 5, G 6,; H 7);
+tuple_conversions!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7,; I 8);
+tuple_conversions!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8,; J 9);
+tuple_conversions!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9,; K 10);
+tuple_conversions!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10,; L 11);
+
+/// Any number of Lua values, as they are: all the arguments of a function, or all its results.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MultiValue<'gc>(Vec<Value<'gc>>);
+
+impl<'gc> MultiValue<'gc> {
+    pub fn new() -> MultiValue<'gc> {
+        MultiValue(Vec::new())
+    }
+
+    pub fn from_vec(values: Vec<Value<'gc>>) -> MultiValue<'gc> {
+        MultiValue(values)
+    }
+
+    pub fn into_vec(self) -> Vec<Value<'gc>> {
+        self.0
+    }
+}
+
+impl<'gc> Deref for MultiValue<'gc> {
+    type Target = Vec<Value<'gc>>;
+
+    fn deref(&self) -> &Vec<Value<'gc>> {
+        &self.0
+    }
+}
+
+impl<'gc> DerefMut for MultiValue<'gc> {
+    fn deref_mut(&mut self) -> &mut Vec<Value<'gc>> {
+        &mut self.0
+    }
+}
+
+impl<'gc> FromIterator<Value<'gc>> for MultiValue<'gc> {
+    fn from_iter<I: IntoIterator<Item = Value<'gc>>>(iter: I) -> Self {
+        MultiValue(iter.into_iter().collect())
+    }
+}
+
+impl<'gc> IntoIterator for MultiValue<'gc> {
+    type Item = Value<'gc>;
+    type IntoIter = std::vec::IntoIter<Value<'gc>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'gc> IntoLuaMulti<'gc> for MultiValue<'gc> {
+    fn into_lua_multi(self, _ctx: Context<'gc>) -> Result<Vec<Value<'gc>>, ConversionError> {
+        Ok(self.0)
+    }
+}
+
+impl<'gc> FromLuaMulti<'gc> for MultiValue<'gc> {
+    fn from_lua_multi(_ctx: Context<'gc>, values: &[Value<'gc>]) -> Result<Self, ArgumentError> {
+        Ok(MultiValue(values.to_vec()))
+    }
+}
+
+/// Any number of values of one type, each converted on its own, like the `...` of
+/// `string.char(...)`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T> Variadic<T> {
+    pub fn new() -> Variadic<T> {
+        Variadic(Vec::new())
+    }
+}
+
+impl<T> Deref for Variadic<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Variadic<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.0
+    }
+}
+
+impl<T> FromIterator<T> for Variadic<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Variadic(iter.into_iter().collect())
+    }
+}
+
+impl<T> IntoIterator for Variadic<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'gc, T: IntoLua<'gc>> IntoLuaMulti<'gc> for Variadic<T> {
+    fn into_lua_multi(self, ctx: Context<'gc>) -> Result<Vec<Value<'gc>>, ConversionError> {
+        self.0.into_iter().map(|v| v.into_lua(ctx)).collect()
+    }
+}
+
+impl<'gc, T: FromLua<'gc>> FromLuaMulti<'gc> for Variadic<T> {
+    fn from_lua_multi(ctx: Context<'gc>, values: &[Value<'gc>]) -> Result<Self, ArgumentError> {
+        (0..values.len())
+            .map(|index| convert_argument(ctx, values, index))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lua;
+
+    #[test]
+    fn conversions() {
+        Lua::new().enter(|ctx| {
+            let v = vec![1_i64, 2, 3].into_lua(ctx).unwrap();
+            assert_eq!(Vec::<i64>::from_lua(ctx, v).unwrap(), [1, 2, 3]);
+            let map = HashMap::from([("a".to_owned(), 1.5), ("b".to_owned(), 2.0)]);
+            let v = map.clone().into_lua(ctx).unwrap();
+            assert_eq!(HashMap::<String, f64>::from_lua(ctx, v).unwrap(), map);
+            assert_eq!(Option::<i64>::from_lua(ctx, Value::Nil).unwrap(), None);
+            assert_eq!(String::from_lua(ctx, Value::Integer(12)).unwrap(), "12");
+            assert_eq!(
+                f64::from_lua(ctx, "0x10".into_lua(ctx).unwrap()).unwrap(),
+                16.0
+            );
+
+            let errors = |source: &str| {
+                let value = ctx.eval(source).unwrap()[0];
+                let results = [
+                    u8::from_lua(ctx, value).err(),
+                    Vec::<i64>::from_lua(ctx, value).err(),
+                    HashMap::<String, i64>::from_lua(ctx, value).err(),
+                ];
+                let errors = results.iter().flatten().map(|err| err.to_string());
+                errors.collect::<Vec<_>>().join("; ")
+            };
+            assert_eq!(
+                errors("return 300"),
+                "number out of range; table expected, got number; table expected, got number"
+            );
+            assert_eq!(
+                errors("return {1, 'x'}"),
+                "number expected, got table; bad element #2 (number expected, got string); \
+                 bad value for key 2 (number expected, got string)"
+            );
+            let table = ctx.eval("return {a = 'x'}").unwrap()[0];
+            assert_eq!(
+                HashMap::<String, i64>::from_lua(ctx, table)
+                    .unwrap_err()
+                    .to_string(),
+                "bad value for key a (number expected, got string)"
+            );
+            assert_eq!(
+                i64::from_lua(ctx, Value::Number(1.5))
+                    .unwrap_err()
+                    .to_string(),
+                "number has no integer representation"
+            );
+
+            let args = [Value::Integer(1)];
+            let (a, b, c) = <(i64, Option<String>, bool)>::from_lua_multi(ctx, &args).unwrap();
+            assert_eq!((a, b, c), (1, None, false));
+            let err = <(i64, String)>::from_lua_multi(ctx, &args).unwrap_err();
+            assert_eq!(
+                err.into_runtime_error("f", 1).to_string(),
+                "bad argument #2 to 'f' (string expected, got no value)"
+            );
+        });
+    }
+
+    #[test]
+    fn multiple_values() {
+        Lua::new().enter(|ctx| {
+            let args = [
+                "a".into_lua(ctx).unwrap(),
+                Value::Integer(1),
+                Value::Number(2.0),
+            ];
+            let (s, rest) = <(String, Variadic<i64>)>::from_lua_multi(ctx, &args).unwrap();
+            assert_eq!((s.as_str(), rest.0), ("a", vec![1, 2]));
+            let (_, all) = <(Value, MultiValue)>::from_lua_multi(ctx, &args).unwrap();
+            assert_eq!(all.len(), 2);
+            let (first, rest) = <(i64, Variadic<i64>)>::from_lua_multi(ctx, &args[1..]).unwrap();
+            assert_eq!((first, rest.0), (1, vec![2]));
+
+            let bad = [Value::Integer(1), Value::Integer(2), Value::Boolean(true)];
+            let err = <(i64, Variadic<i64>)>::from_lua_multi(ctx, &bad).unwrap_err();
+            assert_eq!(
+                err.into_runtime_error("sum", 1).to_string(),
+                "bad argument #3 to 'sum' (number expected, got boolean)"
+            );
+
+            let results = (true, Variadic(vec![1_i64, 2]))
+                .into_lua_multi(ctx)
+                .unwrap();
+            let results: Vec<_> = results.iter().map(|v| v.to_string()).collect();
+            assert_eq!(results, ["true", "1", "2"]);
+
+            let sum =
+                Function::from_typed_fn(&ctx, |_, (label, values): (String, Variadic<f64>)| {
+                    let total: f64 = values.iter().sum();
+                    Ok((label, total, values.len()))
+                });
+            ctx.globals()
+                .set(&ctx, "sum".into_lua(ctx).unwrap(), sum)
+                .unwrap();
+            let results = ctx.eval("return sum('total', 1, 2, 3.5)").unwrap();
+            let results: Vec<_> = results.iter().map(|v| v.to_string()).collect();
+            assert_eq!(results, ["total", "6.5", "3"]);
+        });
+    }
+}
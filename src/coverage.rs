@@ -0,0 +1,443 @@
+//! Recording which lines and branches of each chunk run, through the state's hook, for measuring
+//! how much of the scripts a test suite exercises.
+//!
+//! The hook is called before every instruction of every thread. The instructions are counted by
+//! function, and a function is known by its chunk and where in it it is defined, so that loading a
+//! chunk again, as each run of a test suite may, adds to the same counts. The functions of a chunk
+//! are all known once its main function has run, so the ones never called show up as well.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write as _};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::bytecode::{OpCode, Prototype};
+use crate::vm::{Address, Hook, HookMask, ReplacedHook};
+use crate::{Context, Function, NativeReturn, Thread};
+
+/// Records how many times the lines of the state's chunks run, and which way their branches go,
+/// from when it is started until it is stopped.
+///
+/// Scripts run many times slower while it is recording. A thread's own hook, as `debug.sethook`
+/// sets, keeps it from seeing that thread.
+pub struct Coverage {
+    recorder: Arc<Mutex<Recorder>>,
+    /// The hook the coverage replaced, put back when it stops.
+    replaced: ReplacedHook,
+}
+
+impl Coverage {
+    /// Starts recording every thread through [`Context::set_hook`], replacing the hook set there
+    /// until the coverage is stopped.
+    pub fn start(ctx: Context<'_>) -> Coverage {
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        let hook = {
+            let recorder = Arc::clone(&recorder);
+            Function::from_fn(&ctx, move |_, stack| {
+                lock(&recorder).instruction(stack.thread());
+                stack.clear();
+                Ok(NativeReturn::Return)
+            })
+        };
+        let replaced = ctx.replace_hook(Some(Hook {
+            function: hook.into(),
+            mask: HookMask::default(),
+            count: 1,
+        }));
+        Coverage { recorder, replaced }
+    }
+
+    /// What has been recorded so far.
+    pub fn report(&self) -> CoverageReport {
+        lock(&self.recorder).report()
+    }
+
+    /// Sets every count back to zero, as between two runs of a test suite. The chunks seen so far
+    /// stay in the report, with nothing run.
+    pub fn reset(&self) {
+        lock(&self.recorder).reset();
+    }
+
+    /// Stops recording, putting back the hook the coverage replaced, and returns what it recorded.
+    pub fn stop(self, ctx: Context<'_>) -> CoverageReport {
+        self.replaced.restore(ctx);
+        lock(&self.recorder).report()
+    }
+}
+
+/// What a [`Coverage`] recorded, by chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageReport {
+    /// The chunks, in the order of their names.
+    pub chunks: Vec<ChunkCoverage>,
+}
+
+/// How the lines and branches of a chunk ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkCoverage {
+    pub chunk: String,
+    /// Every line with code on it, in order, including those that never ran.
+    pub lines: Vec<LineCoverage>,
+    /// Every conditional instruction, in the order of their lines.
+    pub branches: Vec<BranchCoverage>,
+}
+
+/// How many times a line ran: the most any of its instructions did.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LineCoverage {
+    pub line: u32,
+    pub hits: u64,
+}
+
+/// Which way a comparison, a test or the end of a loop went, each time it ran.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BranchCoverage {
+    pub line: u32,
+    /// How many times the next instruction ran.
+    pub fell_through: u64,
+    /// How many times the code went elsewhere.
+    pub jumped: u64,
+}
+
+impl ChunkCoverage {
+    /// The number of lines that ran at least once.
+    pub fn lines_hit(&self) -> usize {
+        self.lines.iter().filter(|line| line.hits > 0).count()
+    }
+
+    /// The number of ways branches went at least once, out of twice as many as there are branches.
+    pub fn branches_hit(&self) -> usize {
+        self.branches
+            .iter()
+            .map(|b| usize::from(b.fell_through > 0) + usize::from(b.jumped > 0))
+            .sum()
+    }
+}
+
+impl CoverageReport {
+    /// The report in the tracefile format of `lcov`, which coverage tools and services read. Chunks
+    /// loaded from files are named by their paths there, with the `@` left off.
+    pub fn lcov(&self) -> String {
+        let mut out = String::new();
+        for chunk in &self.chunks {
+            // Writing to a string can't fail.
+            let _ = write_lcov(&mut out, chunk);
+        }
+        out
+    }
+}
+
+fn write_lcov(out: &mut String, chunk: &ChunkCoverage) -> fmt::Result {
+    writeln!(out, "TN:\nSF:{}", chunk.chunk)?;
+    for line in &chunk.lines {
+        writeln!(out, "DA:{},{}", line.line, line.hits)?;
+    }
+    for (block, branch) in chunk.branches.iter().enumerate() {
+        let ran = branch.fell_through + branch.jumped > 0;
+        for (n, taken) in [branch.fell_through, branch.jumped].into_iter().enumerate() {
+            match ran {
+                true => writeln!(out, "BRDA:{},{block},{n},{taken}", branch.line)?,
+                false => writeln!(out, "BRDA:{},{block},{n},-", branch.line)?,
+            }
+        }
+    }
+    writeln!(
+        out,
+        "BRF:{}\nBRH:{}\nLF:{}\nLH:{}\nend_of_record",
+        chunk.branches.len() * 2,
+        chunk.branches_hit(),
+        chunk.lines.len(),
+        chunk.lines_hit(),
+    )
+}
+
+impl fmt::Display for CoverageReport {
+    /// A line per chunk, with how many of its lines and branches ran.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |hit: usize, all: usize| match all {
+            0 => 100.0,
+            _ => hit as f64 * 100.0 / all as f64,
+        };
+        writeln!(f, "{:>7} {:>9}  chunk", "lines", "branches")?;
+        for chunk in &self.chunks {
+            let branches = chunk.branches.len() * 2;
+            writeln!(
+                f,
+                "{:>6.1}% {:>8.1}%  {}",
+                percent(chunk.lines_hit(), chunk.lines.len()),
+                percent(chunk.branches_hit(), branches),
+                chunk.chunk
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn lock(recorder: &Mutex<Recorder>) -> MutexGuard<'_, Recorder> {
+    recorder.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Default)]
+struct Recorder {
+    functions: Vec<Counts>,
+    /// The functions by chunk and the lines they are defined at and end on.
+    ids: HashMap<(String, u32, u32), usize>,
+    /// The functions of the prototypes seen, by address, to save building the key each time.
+    seen: HashMap<Address, usize>,
+    /// The conditional instruction each thread ran last, until the one after it tells which way it
+    /// went.
+    pending: HashMap<Address, Pending>,
+}
+
+/// The counts of a function's instructions.
+struct Counts {
+    chunk: String,
+    line_defined: u32,
+    lines: Box<[u32]>,
+    hits: Vec<u64>,
+    /// For the conditional instructions, the times each went on to the next instruction and
+    /// elsewhere.
+    branches: Vec<Option<[u64; 2]>>,
+}
+
+struct Pending {
+    function: usize,
+    pc: usize,
+    depth: usize,
+}
+
+impl Recorder {
+    /// Counts the instruction the innermost Lua function of `thread` is about to run.
+    fn instruction(&mut self, thread: Thread<'_>) {
+        let Some((closure, pc)) = thread.innermost() else {
+            return;
+        };
+        let function = self.function(&closure.proto());
+        let depth = thread.depth();
+        let key = Address::from(thread.as_ptr());
+        if let Some(pending) = self.pending.get(&key) {
+            // Deeper, a metamethod the instruction called is still running.
+            if depth <= pending.depth {
+                let pending = self.pending.remove(&key).expect("just found");
+                if depth == pending.depth && function == pending.function {
+                    let went = usize::from(pc != pending.pc + 1);
+                    if let Some(counts) = &mut self.functions[function].branches[pending.pc] {
+                        counts[went] += 1;
+                    }
+                }
+            }
+        }
+        let counts = &mut self.functions[function];
+        let Some(hits) = counts.hits.get_mut(pc) else {
+            return;
+        };
+        *hits += 1;
+        if counts.branches[pc].is_some() {
+            self.pending.insert(
+                key,
+                Pending {
+                    function,
+                    pc,
+                    depth,
+                },
+            );
+        }
+    }
+
+    /// The function of `proto`, starting to count it if it hasn't been seen. A main function
+    /// brings in the functions nested in it.
+    fn function(&mut self, proto: &Prototype<'_>) -> usize {
+        let address = Address::from(proto as *const Prototype<'_>);
+        if let Some(&id) = self.seen.get(&address) {
+            let counts = &self.functions[id];
+            // The address may have been reused by another function since.
+            if counts.chunk.as_bytes() == proto.chunk_name.as_bytes()
+                && counts.line_defined == proto.line_defined
+                && counts.hits.len() == proto.code.len()
+            {
+                return id;
+            }
+        }
+        let id = self.register(proto);
+        if proto.line_defined == 0 {
+            let mut nested = proto.prototypes.to_vec();
+            while let Some(proto) = nested.pop() {
+                self.register(&proto);
+                nested.extend(proto.prototypes.iter().copied());
+            }
+        }
+        id
+    }
+
+    fn register(&mut self, proto: &Prototype<'_>) -> usize {
+        let key = (
+            proto.chunk_name.to_string(),
+            proto.line_defined,
+            proto.last_line_defined,
+        );
+        let counts = Counts {
+            chunk: key.0.clone(),
+            line_defined: proto.line_defined,
+            lines: proto.line_info.clone(),
+            hits: vec![0; proto.code.len()],
+            branches: proto
+                .code
+                .iter()
+                .map(|i| conditional(i.opcode()).then_some([0; 2]))
+                .collect(),
+        };
+        let id = match self.ids.get(&key) {
+            // Loaded again: the same code adds to the counts, and changed code starts over.
+            Some(&id) => {
+                if self.functions[id].lines != counts.lines {
+                    self.functions[id] = counts;
+                }
+                id
+            }
+            None => {
+                self.functions.push(counts);
+                self.ids.insert(key, self.functions.len() - 1);
+                self.functions.len() - 1
+            }
+        };
+        let address = Address::from(proto as *const Prototype<'_>);
+        self.seen.insert(address, id);
+        id
+    }
+
+    fn reset(&mut self) {
+        for counts in &mut self.functions {
+            counts.hits.fill(0);
+            for branch in counts.branches.iter_mut().flatten() {
+                *branch = [0; 2];
+            }
+        }
+        self.pending.clear();
+    }
+
+    fn report(&self) -> CoverageReport {
+        let mut chunks: BTreeMap<&str, (BTreeMap<u32, u64>, Vec<BranchCoverage>)> = BTreeMap::new();
+        for counts in &self.functions {
+            let (lines, branches) = chunks.entry(&counts.chunk).or_default();
+            for (pc, &hits) in counts.hits.iter().enumerate() {
+                let Some(&line) = counts.lines.get(pc) else {
+                    continue;
+                };
+                let most = lines.entry(line).or_default();
+                *most = (*most).max(hits);
+                if let Some([fell_through, jumped]) = counts.branches[pc] {
+                    branches.push(BranchCoverage {
+                        line,
+                        fell_through,
+                        jumped,
+                    });
+                }
+            }
+        }
+        let chunks = chunks
+            .into_iter()
+            .map(|(chunk, (lines, mut branches))| {
+                branches.sort_by_key(|branch| branch.line);
+                ChunkCoverage {
+                    chunk: chunk.to_owned(),
+                    lines: lines
+                        .into_iter()
+                        .map(|(line, hits)| LineCoverage { line, hits })
+                        .collect(),
+                    branches,
+                }
+            })
+            .collect();
+        CoverageReport { chunks }
+    }
+}
+
+/// Whether the instruction decides between going on to the next one and going elsewhere.
+fn conditional(op: Option<OpCode>) -> bool {
+    matches!(
+        op,
+        Some(
+            OpCode::Eq
+                | OpCode::Lt
+                | OpCode::Le
+                | OpCode::Test
+                | OpCode::TestSet
+                | OpCode::ForLoop
+                | OpCode::TForLoop
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lua;
+
+    #[test]
+    fn records_lines_and_branches() {
+        let source = "\
+local function sign(n)
+  if n < 0 then
+    return -1
+  end
+  return 1
+end
+local function unused()
+  return 0
+end
+for i = 1, 3 do sign(i) end
+return sign(1)";
+        let mut lua = Lua::new();
+        let (first, second) = lua.enter(|ctx| {
+            // The hook in place before comes back once the coverage stops.
+            let hook = Hook {
+                function: ctx.eval("return print").unwrap()[0],
+                mask: HookMask::default(),
+                count: 100,
+            };
+            ctx.set_hook(Some(hook));
+            let coverage = Coverage::start(ctx);
+            ctx.call(ctx.load("@t.lua", source).unwrap(), &[]).unwrap();
+            let first = coverage.report();
+            coverage.reset();
+            ctx.call(ctx.load("@t.lua", source).unwrap(), &[]).unwrap();
+            ctx.call(ctx.load("@t.lua", source).unwrap(), &[]).unwrap();
+            let second = coverage.stop(ctx);
+            let restored = ctx.hook().unwrap();
+            assert_eq!((restored.function, restored.count), (hook.function, 100));
+            ctx.set_hook(None);
+            (first, second)
+        });
+
+        let [chunk] = &first.chunks[..] else {
+            panic!("{first:?}");
+        };
+        assert_eq!(chunk.chunk, "t.lua");
+        let hits = |chunk: &ChunkCoverage, line| {
+            chunk.lines.iter().find(|l| l.line == line).map(|l| l.hits)
+        };
+        assert_eq!(hits(chunk, 2), Some(4));
+        assert_eq!(hits(chunk, 3), Some(0));
+        assert_eq!(hits(chunk, 5), Some(4));
+        // The function never called is there, with nothing run.
+        assert_eq!(hits(chunk, 8), Some(0));
+        assert_eq!(hits(chunk, 4), None);
+        let branch = |line| *chunk.branches.iter().find(|b| b.line == line).unwrap();
+        // `n < 0` was never true.
+        assert_eq!(branch(2).fell_through + branch(2).jumped, 4);
+        assert_eq!(chunk.branches_hit(), 3);
+        assert_eq!(chunk.lines_hit(), chunk.lines.len() - 2);
+
+        // Loading the chunk again adds to the same counts.
+        assert_eq!(hits(&second.chunks[0], 2), Some(8));
+        let lcov = first.lcov();
+        assert!(
+            lcov.starts_with("TN:\nSF:t.lua\nDA:1,1\nDA:2,4\nDA:3,0\n"),
+            "{lcov}"
+        );
+        assert!(
+            lcov.ends_with("BRF:4\nBRH:3\nLF:8\nLH:6\nend_of_record\n"),
+            "{lcov}"
+        );
+        assert!(first.to_string().contains("75.0%  t.lua"), "{first}");
+    }
+}
@@ -0,0 +1,602 @@
+//! An interactive debugger working through the state's hook: breakpoints by chunk and line,
+//! stepping into, over and out of calls, and looking at and changing the locals and upvalues of
+//! the functions on the stack.
+//!
+//! The debugger talks to nothing itself. When a script pauses it sends a [`Frontend`] an
+//! [`Event`], then carries out the [`Command`]s the frontend answers with until one lets the
+//! script go on. The frontend is whatever sits on the other end: a prompt on a terminal, a socket,
+//! or an adapter for an editor's debugging protocol.
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::stdlib::{inspect, InspectOptions};
+use crate::vm::{self, Address, Hook, HookMask, ReplacedHook};
+use crate::{Context, Function, LuaError, MaybeSend, NativeReturn, Thread, Value};
+
+/// The other end of a [`Debugger`], which is told where scripts pause and says what to do next.
+///
+/// Both methods are called while the script is paused, from inside the hook, so a frontend reading
+/// commands from elsewhere blocks the script until they come.
+pub trait Frontend {
+    /// Passes on what happened: a pause, or the answer to the last command.
+    fn event(&mut self, event: Event);
+
+    /// Waits for the next command. `None`, as when the other end has gone away, lets the script run
+    /// on without stepping.
+    fn command(&mut self) -> Option<Command>;
+}
+
+/// What a [`Frontend`] can ask of a paused script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Runs on until a breakpoint.
+    Continue,
+    /// Runs until a new line starts, in this function or any it calls.
+    StepInto,
+    /// Runs until a new line starts in this function or one it returns to.
+    StepOver,
+    /// Runs until a new line starts in a function this one returns to.
+    StepOut,
+    SetBreakpoint(Breakpoint),
+    ClearBreakpoint(Breakpoint),
+    /// Answered with [`Event::Backtrace`].
+    Backtrace,
+    /// Answered with [`Event::Variables`] holding the locals in scope in the function `level`
+    /// frames down, with 1 the one paused in.
+    Locals {
+        level: usize,
+    },
+    /// Answered with [`Event::Variables`] holding the upvalues of the function `level` frames down.
+    Upvalues {
+        level: usize,
+    },
+    /// Assigns the value of the Lua expression `value`, which sees the globals but no locals, to
+    /// the innermost local `name` in scope in the function `level` frames down. Answered with the
+    /// variable as it now is.
+    SetLocal {
+        level: usize,
+        name: String,
+        value: String,
+    },
+    /// Assigns the value of the Lua expression `value` to the upvalue `name` of the function
+    /// `level` frames down. Answered with the variable as it now is.
+    SetUpvalue {
+        level: usize,
+        name: String,
+        value: String,
+    },
+}
+
+/// What a [`Debugger`] tells its [`Frontend`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The script paused at the start of `line` of the chunk `chunk`.
+    Stopped {
+        reason: StopReason,
+        chunk: String,
+        line: u32,
+    },
+    /// The breakpoints, after one was set or cleared.
+    Breakpoints(Vec<Breakpoint>),
+    /// The Lua functions on the stack, innermost first.
+    Backtrace(Vec<StackFrame>),
+    Variables(Vec<Variable>),
+    /// A command couldn't be carried out.
+    Error(String),
+}
+
+/// Why a script paused.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint,
+    /// A step finished.
+    Step,
+    /// The host asked for a pause with [`Debugger::pause`].
+    Pause,
+}
+
+/// A line to pause at.
+///
+/// `chunk` is matched against the chunk names the way a file is: `main.lua` stops in a chunk
+/// loaded as `@scripts/main.lua`, and the other way around, as long as one ends with the other at
+/// a `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub chunk: String,
+    pub line: u32,
+}
+
+impl Breakpoint {
+    pub fn new(chunk: &str, line: u32) -> Breakpoint {
+        Breakpoint {
+            chunk: chunk.to_owned(),
+            line,
+        }
+    }
+
+    fn matches(&self, chunk: &[u8], line: u32) -> bool {
+        let (a, b) = (self.chunk.as_bytes(), chunk);
+        let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+        self.line == line
+            && long.ends_with(short)
+            && (long.len() == short.len() || long[long.len() - short.len() - 1] == b'/')
+    }
+}
+
+/// A Lua function on the stack of a paused script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    /// How many frames down the function is, with 1 the one paused in.
+    pub level: usize,
+    /// The name its caller knows it by, if it can tell.
+    pub name: Option<String>,
+    pub chunk: String,
+    /// The line the function is defined at, 0 for a main chunk.
+    pub line_defined: u32,
+    /// The line running, if the function has line information.
+    pub line: Option<u32>,
+}
+
+/// A local or upvalue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    /// The value, as [`inspect`] writes it.
+    pub value: String,
+    pub type_name: &'static str,
+}
+
+/// Pauses scripts at breakpoints and after steps, and lets a [`Frontend`] look around and change
+/// variables while they are paused, from when it is attached until it is detached.
+///
+/// A thread's own hook, as `debug.sethook` sets, keeps the debugger from seeing it, and the code
+/// run by hooks isn't debugged.
+pub struct Debugger {
+    session: Arc<Mutex<Session>>,
+    /// The hook the debugger replaced, put back when it is detached.
+    replaced: ReplacedHook,
+}
+
+impl Debugger {
+    /// Starts debugging every thread through [`Context::set_hook`], replacing the hook set there
+    /// until the debugger is detached. Scripts run as usual until they reach a breakpoint.
+    pub fn attach(ctx: Context<'_>, frontend: impl Frontend + MaybeSend + 'static) -> Debugger {
+        // Without the `send` feature, neither the frontend nor the session needs to be `Send`.
+        #[allow(clippy::arc_with_non_send_sync)]
+        let session = Arc::new(Mutex::new(Session {
+            frontend: Box::new(frontend),
+            breakpoints: Vec::new(),
+            step: None,
+        }));
+        let hook = {
+            let session = Arc::clone(&session);
+            Function::from_fn(&ctx, move |ctx, stack| {
+                if let Value::Integer(line) = stack.get(1) {
+                    let line = u32::try_from(line).unwrap_or(0);
+                    lock(&session).line(ctx, stack.thread(), line);
+                }
+                stack.clear();
+                Ok(NativeReturn::Return)
+            })
+        };
+        let replaced = ctx.replace_hook(Some(Hook {
+            function: hook.into(),
+            mask: HookMask {
+                call: false,
+                ret: false,
+                line: true,
+            },
+            count: 0,
+        }));
+        Debugger { session, replaced }
+    }
+
+    pub fn set_breakpoint(&self, breakpoint: Breakpoint) {
+        lock(&self.session).set_breakpoint(breakpoint);
+    }
+
+    /// Removes the breakpoint, returning whether there was one.
+    pub fn clear_breakpoint(&self, breakpoint: &Breakpoint) -> bool {
+        lock(&self.session).clear_breakpoint(breakpoint)
+    }
+
+    pub fn breakpoints(&self) -> Vec<Breakpoint> {
+        lock(&self.session).breakpoints.clone()
+    }
+
+    /// Pauses whatever script starts a new line next.
+    pub fn pause(&self) {
+        lock(&self.session).step = Some(Step::Pause);
+    }
+
+    /// Stops debugging, putting back the hook the debugger replaced.
+    pub fn detach(self, ctx: Context<'_>) {
+        self.replaced.restore(ctx);
+    }
+}
+
+fn lock(session: &Mutex<Session>) -> MutexGuard<'_, Session> {
+    session.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(feature = "send")]
+type BoxedFrontend = Box<dyn Frontend + Send>;
+#[cfg(not(feature = "send"))]
+type BoxedFrontend = Box<dyn Frontend>;
+
+struct Session {
+    frontend: BoxedFrontend,
+    breakpoints: Vec<Breakpoint>,
+    /// Where the step in progress ends, if one is.
+    step: Option<Step>,
+}
+
+/// Which new lines end a step.
+#[derive(Copy, Clone)]
+enum Step {
+    Pause,
+    Into,
+    /// Any in `thread` at no more than `depth` Lua functions deep.
+    Over {
+        thread: Address,
+        depth: usize,
+    },
+    /// Any in `thread` at fewer than `depth` Lua functions deep.
+    Out {
+        thread: Address,
+        depth: usize,
+    },
+}
+
+impl Session {
+    /// Pauses if `line`, starting to run in `thread`, ends a step or has a breakpoint.
+    fn line<'gc>(&mut self, ctx: Context<'gc>, thread: Thread<'gc>, line: u32) {
+        let depth = thread.depth();
+        let stepped = match self.step {
+            None => None,
+            Some(Step::Pause) => Some(StopReason::Pause),
+            Some(Step::Into) => Some(StopReason::Step),
+            Some(Step::Over {
+                thread: t,
+                depth: d,
+            }) => (t == Address::from(thread.as_ptr()) && depth <= d).then_some(StopReason::Step),
+            Some(Step::Out {
+                thread: t,
+                depth: d,
+            }) => (t == Address::from(thread.as_ptr()) && depth < d).then_some(StopReason::Step),
+        };
+        let Some(info) = thread.frame_info(1) else {
+            return;
+        };
+        let chunk = info.closure.proto().chunk_name;
+        let reason = stepped.or_else(|| {
+            self.breakpoints
+                .iter()
+                .any(|b| b.matches(chunk.as_bytes(), line))
+                .then_some(StopReason::Breakpoint)
+        });
+        let Some(reason) = reason else {
+            return;
+        };
+        self.frontend.event(Event::Stopped {
+            reason,
+            chunk: chunk.to_string(),
+            line,
+        });
+        self.step = self.paused(ctx, thread, depth);
+    }
+
+    /// Carries out commands until one lets the script go on, returning the step it takes.
+    fn paused<'gc>(
+        &mut self,
+        ctx: Context<'gc>,
+        thread: Thread<'gc>,
+        depth: usize,
+    ) -> Option<Step> {
+        let this = Address::from(thread.as_ptr());
+        loop {
+            let event = match self.frontend.command() {
+                None | Some(Command::Continue) => return None,
+                Some(Command::StepInto) => return Some(Step::Into),
+                Some(Command::StepOver) => {
+                    return Some(Step::Over {
+                        thread: this,
+                        depth,
+                    });
+                }
+                Some(Command::StepOut) => {
+                    return Some(Step::Out {
+                        thread: this,
+                        depth,
+                    });
+                }
+                Some(Command::SetBreakpoint(breakpoint)) => {
+                    self.set_breakpoint(breakpoint);
+                    Event::Breakpoints(self.breakpoints.clone())
+                }
+                Some(Command::ClearBreakpoint(breakpoint)) => {
+                    self.clear_breakpoint(&breakpoint);
+                    Event::Breakpoints(self.breakpoints.clone())
+                }
+                Some(Command::Backtrace) => Event::Backtrace(backtrace(thread)),
+                Some(Command::Locals { level }) => match locals(ctx, thread, level) {
+                    Some(locals) => Event::Variables(locals.iter().map(variable).collect()),
+                    None => no_function(level),
+                },
+                Some(Command::Upvalues { level }) => match upvalues(thread, level) {
+                    Some(upvalues) => Event::Variables(upvalues.iter().map(variable).collect()),
+                    None => no_function(level),
+                },
+                Some(Command::SetLocal { level, name, value }) => {
+                    set_local(ctx, thread, level, &name, &value)
+                }
+                Some(Command::SetUpvalue { level, name, value }) => {
+                    set_upvalue(ctx, thread, level, &name, &value)
+                }
+            };
+            self.frontend.event(event);
+        }
+    }
+
+    fn set_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    fn clear_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| b != breakpoint);
+        self.breakpoints.len() != len
+    }
+}
+
+fn backtrace(thread: Thread<'_>) -> Vec<StackFrame> {
+    (1..=thread.depth())
+        .filter_map(|level| {
+            let info = thread.frame_info(level)?;
+            let proto = info.closure.proto();
+            Some(StackFrame {
+                level,
+                name: info.name.map(|(_, name)| name.to_string()),
+                chunk: proto.chunk_name.to_string(),
+                line_defined: proto.line_defined,
+                line: info.current_line,
+            })
+        })
+        .collect()
+}
+
+/// The named locals in scope `level` frames down, with the number [`Thread::local`] takes each by.
+fn locals<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    level: usize,
+) -> Option<Vec<(i64, String, Value<'gc>)>> {
+    thread.frame_info(level)?;
+    let locals = (1..)
+        .map_while(|n| {
+            thread
+                .local(&ctx, level, n)
+                .map(|(name, value)| (n, name, value))
+        })
+        // Temporaries and the hidden state of loops have names in parentheses.
+        .filter(|(_, name, _)| !name.as_bytes().starts_with(b"("))
+        .map(|(n, name, value)| (n, name.to_string(), value))
+        .collect();
+    Some(locals)
+}
+
+/// The upvalues of the function `level` frames down, by index.
+fn upvalues(thread: Thread<'_>, level: usize) -> Option<Vec<(usize, String, Value<'_>)>> {
+    let closure = thread.frame_info(level)?.closure;
+    let names = &closure.proto().upvalue_names;
+    let upvalues = closure
+        .upvalues()
+        .iter()
+        .enumerate()
+        .map(|(i, upvalue)| {
+            let name = match names.get(i) {
+                Some(name) => name.to_string(),
+                None => "?".to_owned(),
+            };
+            (i, name, upvalue.value())
+        })
+        .collect();
+    Some(upvalues)
+}
+
+/// How variables are written: the fields of a table, but not the tables in them.
+const VALUES: InspectOptions = InspectOptions {
+    depth: 1,
+    width: 64,
+    metatables: false,
+};
+
+fn variable<T>(&(_, ref name, value): &(T, String, Value<'_>)) -> Variable {
+    Variable {
+        name: name.clone(),
+        value: inspect(value, &VALUES),
+        type_name: value.type_name(),
+    }
+}
+
+fn no_function(level: usize) -> Event {
+    Event::Error(format!("no function at level {level}"))
+}
+
+fn set_local<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    level: usize,
+    name: &str,
+    value: &str,
+) -> Event {
+    let Some(locals) = locals(ctx, thread, level) else {
+        return no_function(level);
+    };
+    // The last one declared shadows the others.
+    let Some(&(n, ..)) = locals.iter().rev().find(|(_, local, _)| local == name) else {
+        return Event::Error(format!("no local '{name}' in scope"));
+    };
+    match evaluate(ctx, thread, value) {
+        Ok(value) => {
+            thread.set_local(&ctx, level, n, value);
+            Event::Variables(vec![variable(&(n, name.to_owned(), value))])
+        }
+        Err(err) => Event::Error(err.to_string()),
+    }
+}
+
+fn set_upvalue<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    level: usize,
+    name: &str,
+    value: &str,
+) -> Event {
+    let Some(upvalues) = upvalues(thread, level) else {
+        return no_function(level);
+    };
+    let Some(&(i, ..)) = upvalues.iter().find(|(_, upvalue, _)| upvalue == name) else {
+        return Event::Error(format!("no upvalue '{name}'"));
+    };
+    let closure = thread
+        .frame_info(level)
+        .expect("the function was just found")
+        .closure;
+    match evaluate(ctx, thread, value) {
+        Ok(value) => {
+            closure.upvalues()[i].set_value(&ctx, value);
+            Event::Variables(vec![variable(&(i, name.to_owned(), value))])
+        }
+        Err(err) => Event::Error(err.to_string()),
+    }
+}
+
+/// The first value of the expression `source`, evaluated with the globals in scope. It runs on the
+/// paused thread, where the hook is running, so that it isn't debugged in turn.
+fn evaluate<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    source: &str,
+) -> Result<Value<'gc>, LuaError<'gc>> {
+    let function = ctx.load("=(debugger)", format!("return {source}"))?;
+    let results = vm::call(ctx, thread, function.into(), &[])?;
+    Ok(results.first().copied().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lua;
+
+    /// Answers with commands from a list, and keeps the events.
+    struct Script {
+        commands: Vec<Command>,
+        events: Arc<Mutex<Vec<Event>>>,
+    }
+
+    impl Frontend for Script {
+        fn event(&mut self, event: Event) {
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn command(&mut self) -> Option<Command> {
+            (!self.commands.is_empty()).then(|| self.commands.remove(0))
+        }
+    }
+
+    #[test]
+    fn breaks_steps_and_inspects() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let commands = vec![
+            // Paused at the breakpoint on line 3.
+            Command::Locals { level: 1 },
+            Command::SetLocal {
+                level: 1,
+                name: "n".to_owned(),
+                value: "math.max(10, 100)".to_owned(),
+            },
+            Command::StepOver,
+            // Line 4.
+            Command::Upvalues { level: 1 },
+            Command::Backtrace,
+            Command::StepOut,
+            // Line 7, back in the main chunk.
+            Command::SetUpvalue {
+                level: 1,
+                name: "nope".to_owned(),
+                value: "1".to_owned(),
+            },
+            Command::StepInto,
+            // Line 3 again, in the second call of `f`.
+            Command::ClearBreakpoint(Breakpoint::new("t.lua", 3)),
+        ];
+        let frontend = Script {
+            commands,
+            events: Arc::clone(&events),
+        };
+        let source = "local base = 1
+local function f(n)
+  local m = n + 1
+  return m + base
+end
+local x = f(1)
+x = x + f(2)
+return x";
+        let result = Lua::new().enter(|ctx| {
+            let debugger = Debugger::attach(ctx, frontend);
+            debugger.set_breakpoint(Breakpoint::new("t.lua", 3));
+            let function = ctx.load("@scripts/t.lua", source).unwrap();
+            let result = ctx.call(function, &[]).unwrap()[0].to_string();
+            debugger.detach(ctx);
+            assert!(ctx.hook().is_none());
+            result
+        });
+        // The first call of `f` had `n` changed to 100: 102 + 4.
+        assert_eq!(result, "106");
+
+        let stopped = |reason, line| Event::Stopped {
+            reason,
+            chunk: "scripts/t.lua".to_owned(),
+            line,
+        };
+        let variable = |name: &str, value: &str, type_name| Variable {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            type_name,
+        };
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events[..],
+            [
+                stopped(StopReason::Breakpoint, 3),
+                Event::Variables(vec![variable("n", "1", "number")]),
+                Event::Variables(vec![variable("n", "100", "number")]),
+                stopped(StopReason::Step, 4),
+                Event::Variables(vec![variable("base", "1", "number")]),
+                Event::Backtrace(vec![
+                    StackFrame {
+                        level: 1,
+                        name: Some("f".to_owned()),
+                        chunk: "scripts/t.lua".to_owned(),
+                        line_defined: 2,
+                        line: Some(4),
+                    },
+                    StackFrame {
+                        level: 2,
+                        name: None,
+                        chunk: "scripts/t.lua".to_owned(),
+                        line_defined: 0,
+                        line: Some(6),
+                    },
+                ]),
+                stopped(StopReason::Step, 7),
+                Event::Error("no upvalue 'nope'".to_owned()),
+                stopped(StopReason::Step, 3),
+                Event::Breakpoints(Vec::new()),
+            ]
+        );
+    }
+}
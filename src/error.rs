@@ -0,0 +1,582 @@
+use std::any::Any;
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::{Context, LuaString, RegistryKey, UserData, UserDataMethods, Value};
+
+/// An error raised while running Lua code, carrying only a message.
+///
+/// The interpreter's operations raise these; they become [`LuaError`]s as they leave the interpreter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    message: String,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> RuntimeError {
+        RuntimeError {
+            message: message.into(),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for RuntimeError {}
+
+/// How many of the innermost and outermost entries of a long traceback are displayed.
+const TRACEBACK_HEAD: usize = 10;
+const TRACEBACK_TAIL: usize = 11;
+
+/// An error as Lua code sees it: any value, raised by `error` or made from a [`RuntimeError`]'s
+/// message, along with the traceback of the Lua frames it unwound.
+///
+/// Formatting with `{:#}` appends the traceback to the message, leaving out the middle of a long one
+/// the way the reference implementation does.
+#[derive(Debug, Clone)]
+pub struct LuaError<'gc> {
+    value: ErrorValue<'gc>,
+    trace: Option<Box<Trace>>,
+    /// Set once an `xpcall` message handler has seen the error, so that outer levels leave it be.
+    pub(crate) handled: bool,
+}
+
+/// Where an error came from, kept apart and made only once there is something to keep, as errors
+/// are returned through every level of the interpreter's native stack.
+#[derive(Debug, Clone, Default)]
+struct Trace {
+    traceback: Vec<String>,
+    position: Option<SourcePosition>,
+}
+
+#[derive(Debug, Clone)]
+enum ErrorValue<'gc> {
+    /// A message not yet turned into a Lua string, which is only done if Lua code gets to see it.
+    Message(String),
+    /// A Rust error, which Lua code sees wrapped in an [`ExternalError`] userdata.
+    External(Arc<dyn StdError + Send + Sync>),
+    Value(Value<'gc>),
+}
+
+/// The userdata a Rust error raised by a callback becomes when Lua code catches it. It converts to
+/// a string as the error's message, and raising it again raises the Rust error again.
+struct ExternalError(Arc<dyn StdError + Send + Sync>);
+
+impl UserData for ExternalError {
+    fn register(methods: &mut UserDataMethods<Self>) {
+        methods.add_meta_method("__tostring", |_, this, ()| Ok(this.0.to_string()));
+    }
+}
+
+/// The Rust error a value wraps, if it is an [`ExternalError`].
+fn external(value: Value<'_>) -> Option<Arc<dyn StdError + Send + Sync>> {
+    match value {
+        Value::UserData(u) => u.borrow::<ExternalError>().ok().map(|e| Arc::clone(&e.0)),
+        _ => None,
+    }
+}
+
+impl<'gc> LuaError<'gc> {
+    pub fn new(value: Value<'gc>) -> LuaError<'gc> {
+        LuaError {
+            value: ErrorValue::Value(value),
+            trace: None,
+            handled: false,
+        }
+    }
+
+    /// An error carrying a Rust error, for a callback to raise. Lua code that catches it gets a
+    /// userdata converting to a string as the error's message; if the userdata is raised again and
+    /// the error reaches the host, [`into_owned`](LuaError::into_owned) gives back the Rust error.
+    pub fn external(err: impl Into<Box<dyn StdError + Send + Sync>>) -> LuaError<'gc> {
+        LuaError {
+            value: ErrorValue::External(Arc::from(err.into())),
+            trace: None,
+            handled: false,
+        }
+    }
+
+    /// The error value, as `pcall` returns it.
+    pub fn value(&self, ctx: Context<'gc>) -> Value<'gc> {
+        match &self.value {
+            ErrorValue::Message(message) => Value::String(LuaString::new(&ctx, message.as_bytes())),
+            ErrorValue::External(err) => {
+                Value::UserData(ctx.create_userdata(ExternalError(Arc::clone(err))))
+            }
+            ErrorValue::Value(value) => *value,
+        }
+    }
+
+    /// The Rust error the error carries, if a callback raised one.
+    pub fn external_error(&self) -> Option<&(dyn StdError + Send + Sync + 'static)> {
+        match &self.value {
+            ErrorValue::External(err) => Some(&**err),
+            _ => None,
+        }
+    }
+
+    /// The Lua frames the error unwound through, innermost first, each like
+    /// `chunk:line: in function <chunk:line>`. The native functions it left between them, like a
+    /// callback that called Lua code, show up as `[C]: in function 'name'`, or `[C]: in ?` when
+    /// the caller has no name for them.
+    pub fn traceback(&self) -> &[String] {
+        self.trace.as_ref().map_or(&[], |trace| &trace.traceback)
+    }
+
+    /// Replaces the error value, keeping the traceback and position.
+    pub(crate) fn set_value(&mut self, value: Value<'gc>) {
+        self.value = ErrorValue::Value(value);
+    }
+
+    pub(crate) fn push_traceback(&mut self, entry: String) {
+        self.trace
+            .get_or_insert_with(Box::default)
+            .traceback
+            .push(entry);
+    }
+
+    /// Where the interpreter raised the error, if it was an operation of a Lua function that failed
+    /// rather than a call to `error` or a native function.
+    pub fn position(&self) -> Option<&SourcePosition> {
+        self.trace.as_ref()?.position.as_ref()
+    }
+
+    pub(crate) fn set_position(&mut self, position: SourcePosition) {
+        self.trace.get_or_insert_with(Box::default).position = Some(position);
+    }
+
+    /// Takes the error out of the arena, so that it can be returned from [`Lua::enter`](crate::Lua::enter).
+    /// An error value other than a message is kept in the registry.
+    pub fn into_owned(self, ctx: Context<'gc>) -> Error {
+        let payload = match self.value {
+            ErrorValue::Message(message) => Payload::Message(message),
+            ErrorValue::External(err) => Payload::External(err),
+            ErrorValue::Value(value) => match external(value) {
+                Some(err) => Payload::External(err),
+                None => Payload::Value {
+                    message: LuaError::new(value).to_string(),
+                    value: ctx.create_registry_value(value),
+                },
+            },
+        };
+        Error {
+            payload,
+            trace: self.trace,
+        }
+    }
+
+    /// Like [`into_owned`](LuaError::into_owned), but with an error value other than a message
+    /// turned into its message, so that the error doesn't refer to the state it came from.
+    pub(crate) fn into_detached(self) -> Error {
+        let payload = match self.value {
+            ErrorValue::Message(message) => Payload::Message(message),
+            ErrorValue::External(err) => Payload::External(err),
+            ErrorValue::Value(value) => match external(value) {
+                Some(err) => Payload::External(err),
+                None => Payload::Message(LuaError::new(value).to_string()),
+            },
+        };
+        Error {
+            payload,
+            trace: self.trace,
+        }
+    }
+}
+
+impl<'gc> From<RuntimeError> for LuaError<'gc> {
+    fn from(err: RuntimeError) -> Self {
+        LuaError {
+            value: ErrorValue::Message(err.message),
+            trace: None,
+            handled: false,
+        }
+    }
+}
+
+impl<'gc> fmt::Display for LuaError<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            ErrorValue::Message(message) => f.write_str(message)?,
+            ErrorValue::External(err) => write!(f, "{err}")?,
+            ErrorValue::Value(value) if external(*value).is_some() => {
+                write!(f, "{}", external(*value).expect("checked"))?
+            }
+            ErrorValue::Value(
+                value @ (Value::String(_) | Value::Integer(_) | Value::Number(_)),
+            ) => write!(f, "{value}")?,
+            ErrorValue::Value(value) => {
+                write!(f, "(error object is a {} value)", value.type_name())?
+            }
+        }
+        if f.alternate() && !self.traceback().is_empty() {
+            write_traceback(f, self.traceback())?;
+        }
+        Ok(())
+    }
+}
+
+/// A [`LuaError`] taken out of the arena with [`LuaError::into_owned`], which a host can keep, or
+/// return through its own errors.
+///
+/// A Rust error a callback raised comes out as it went in, even after Lua code caught it and raised
+/// it again, and is the error's [`source`](StdError::source). Any other value but a message stays
+/// a Lua value, held in the registry.
+pub struct Error {
+    payload: Payload,
+    trace: Option<Box<Trace>>,
+}
+
+enum Payload {
+    Message(String),
+    External(Arc<dyn StdError + Send + Sync>),
+    /// The value, and the message displayed for it.
+    Value {
+        value: RegistryKey,
+        message: String,
+    },
+}
+
+impl Error {
+    /// The error value, as `pcall` would have returned it.
+    pub fn value<'gc>(&self, ctx: Context<'gc>) -> Value<'gc> {
+        match &self.payload {
+            Payload::Message(message) => Value::String(LuaString::new(&ctx, message.as_bytes())),
+            Payload::External(err) => {
+                Value::UserData(ctx.create_userdata(ExternalError(Arc::clone(err))))
+            }
+            Payload::Value { value, .. } => ctx.registry_value(value),
+        }
+    }
+
+    /// The Rust error a callback raised, if the error is one.
+    pub fn external(&self) -> Option<&(dyn StdError + Send + Sync + 'static)> {
+        match &self.payload {
+            Payload::External(err) => Some(&**err),
+            _ => None,
+        }
+    }
+
+    /// The Rust error a callback raised, if the error is one of type `E`.
+    pub fn downcast_ref<E: StdError + 'static>(&self) -> Option<&E> {
+        self.external()?.downcast_ref()
+    }
+
+    /// The Lua frames the error unwound through, innermost first.
+    pub fn traceback(&self) -> &[String] {
+        self.trace.as_ref().map_or(&[], |trace| &trace.traceback)
+    }
+
+    /// Where the interpreter raised the error; see [`LuaError::position`].
+    pub fn position(&self) -> Option<&SourcePosition> {
+        self.trace.as_ref()?.position.as_ref()
+    }
+
+    /// Turns the error back into the [`LuaError`] it was taken from, traceback and position
+    /// included.
+    pub(crate) fn into_lua_error(self, ctx: Context<'_>) -> LuaError<'_> {
+        let value = match self.payload {
+            Payload::Message(message) => ErrorValue::Message(message),
+            Payload::External(err) => ErrorValue::External(err),
+            Payload::Value { value, .. } => ErrorValue::Value(ctx.registry_value(&value)),
+        };
+        LuaError {
+            value,
+            trace: self.trace,
+            handled: false,
+        }
+    }
+}
+
+/// A place in the source of a chunk, as the debug information of its functions records it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourcePosition {
+    /// The chunk, named as in error messages.
+    pub chunk: String,
+    pub line: u32,
+    /// The column, counted in bytes from 1, if the chunk's debug information has columns.
+    pub column: Option<u32>,
+}
+
+impl SourcePosition {
+    /// The line of `source` the position is on, numbered, with a caret under the column:
+    ///
+    /// ```text
+    ///  3 | local total = count + item.price
+    ///    |                       ^
+    /// ```
+    ///
+    /// `None` if `source` has no such line.
+    pub fn snippet(&self, source: &[u8]) -> Option<String> {
+        let index = (self.line as usize).checked_sub(1)?;
+        let text = source.split(|&b| b == b'\n').nth(index)?;
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let mut out = format!(" {number} | {}", String::from_utf8_lossy(text));
+        if let Some(column) = self.column {
+            // Tabs stay tabs, so that the caret lines up however they are shown.
+            let before = text.iter().take(column.saturating_sub(1) as usize);
+            let pad: String = before
+                .map(|&b| if b == b'\t' { '\t' } else { ' ' })
+                .collect();
+            out.push_str(&format!("\n {gutter} | {pad}^"));
+        }
+        Some(out)
+    }
+}
+
+impl fmt::Display for SourcePosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.chunk, self.line)?;
+        if let Some(column) = self.column {
+            write!(f, ":{column}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.payload {
+            Payload::Message(message) | Payload::Value { message, .. } => f.write_str(message)?,
+            Payload::External(err) => write!(f, "{err}")?,
+        }
+        if f.alternate() && !self.traceback().is_empty() {
+            write_traceback(f, self.traceback())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Error");
+        match &self.payload {
+            Payload::Message(message) => debug.field("message", message),
+            Payload::External(err) => debug.field("external", err),
+            Payload::Value { value, message } => {
+                debug.field("value", value).field("message", message)
+            }
+        };
+        debug
+            .field("traceback", &self.traceback())
+            .field("position", &self.position())
+            .finish()
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match &self.payload {
+            Payload::External(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+/// Writes `stack traceback:` and the entries, each on a line of its own, leaving out the middle of
+/// a long traceback.
+pub(crate) fn write_traceback(out: &mut impl fmt::Write, entries: &[String]) -> fmt::Result {
+    out.write_str("\nstack traceback:")?;
+    let len = entries.len();
+    // Skipping a single entry would save nothing.
+    let skip = if len > TRACEBACK_HEAD + TRACEBACK_TAIL + 1 {
+        len - TRACEBACK_HEAD - TRACEBACK_TAIL
+    } else {
+        0
+    };
+    for (i, entry) in entries.iter().enumerate() {
+        if i == TRACEBACK_HEAD && skip > 0 {
+            write!(out, "\n\t...\t(skipping {skip} levels)")?;
+        }
+        if !(TRACEBACK_HEAD..TRACEBACK_HEAD + skip).contains(&i) {
+            write!(out, "\n\t{entry}")?;
+        }
+    }
+    Ok(())
+}
+
+impl<'gc> StdError for LuaError<'gc> {}
+
+/// A panic caught in a native function, which raises it as an external error; see
+/// [`Context::set_catch_panics`].
+///
+/// The panic's payload is kept for the host to take back and resume unwinding with, once the error
+/// has come out of Lua.
+pub struct PanicError {
+    message: String,
+    payload: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl PanicError {
+    pub(crate) fn new(payload: Box<dyn Any + Send>) -> PanicError {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Box<dyn Any>".to_owned(),
+            },
+        };
+        PanicError {
+            message,
+            payload: Mutex::new(Some(payload)),
+        }
+    }
+
+    /// The panic message, if the panic had one.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The payload the function panicked with, for [`std::panic::resume_unwind`]. It is handed out
+    /// only once.
+    pub fn take_payload(&self) -> Option<Box<dyn Any + Send>> {
+        self.payload.lock().ok()?.take()
+    }
+}
+
+impl fmt::Display for PanicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "native function panicked: {}", self.message)
+    }
+}
+
+impl fmt::Debug for PanicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PanicError")
+            .field("message", &self.message)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StdError for PanicError {}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{Function, Lua};
+
+    #[test]
+    fn external_errors_cross_lua() {
+        let mut lua = Lua::new();
+        let err = lua.enter(|ctx| {
+            let open = Function::from_fn(&ctx, |_, _| {
+                Err(LuaError::external(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no such file",
+                )))
+            });
+            let name = LuaString::new(&ctx, b"open");
+            ctx.globals().set(&ctx, name, open).unwrap();
+            let source = "local ok, e = pcall(open)
+                          assert(not ok and tostring(e) == 'no such file')
+                          local t = setmetatable({}, {__index = function() error(e) end})
+                          return t.x";
+            ctx.eval(source).unwrap_err().into_owned(ctx)
+        });
+        assert_eq!(err.to_string(), "no such file");
+        let io_err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io_err.kind(), io::ErrorKind::NotFound);
+        assert!(err.source().is_some());
+        assert!(err
+            .traceback()
+            .iter()
+            .any(|entry| entry.contains("main chunk")));
+    }
+
+    #[test]
+    fn panics_become_errors() {
+        let mut lua = Lua::new();
+        let err = lua.enter(|ctx| {
+            let explode = Function::from_typed_fn(&ctx, |_, n: i64| -> Result<(), LuaError> {
+                panic!("exploded with {n}")
+            });
+            let name = LuaString::new(&ctx, b"explode");
+            ctx.globals().set(&ctx, name, explode).unwrap();
+            let caught = ctx.eval("local ok, e = pcall(explode, 1) return tostring(e)");
+            assert_eq!(
+                caught.unwrap()[0].to_string(),
+                "native function panicked: exploded with 1"
+            );
+            ctx.eval("explode(2)").unwrap_err().into_owned(ctx)
+        });
+        let panic = err.downcast_ref::<PanicError>().unwrap();
+        assert_eq!(panic.message(), "exploded with 2");
+        let payload = panic.take_payload().unwrap();
+        assert_eq!(payload.downcast_ref::<String>().unwrap(), "exploded with 2");
+        assert!(panic.take_payload().is_none());
+        assert_eq!(
+            lua.enter(|ctx| ctx.eval("return 1 + 1").unwrap()[0].to_string()),
+            "2"
+        );
+
+        lua.enter(|ctx| ctx.set_catch_panics(false));
+        let unwound = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lua.enter(|ctx| ctx.eval("explode(3)").is_err())
+        }));
+        assert!(unwound.is_err());
+    }
+
+    #[test]
+    fn error_values_are_kept() {
+        let mut lua = Lua::new();
+        let err = lua.enter(|ctx| {
+            let err = ctx.eval("error({code = 42})").unwrap_err();
+            assert_eq!(err.to_string(), "(error object is a table value)");
+            err.into_owned(ctx)
+        });
+        assert!(err.external().is_none());
+        let code = lua.enter(|ctx| {
+            let Value::Table(t) = err.value(ctx) else {
+                panic!("expected a table");
+            };
+            t.get_str("code").to_string()
+        });
+        assert_eq!(code, "42");
+    }
+
+    #[test]
+    fn positions_have_columns() {
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let source = "local t = {}\nlocal n = 1\nlocal y = n + t.x.y";
+            let err = ctx
+                .load("=chunk", source)
+                .unwrap()
+                .call::<_, ()>(ctx, ())
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "chunk:3: attempt to index a nil value (field 'x')"
+            );
+            let position = err.position().unwrap();
+            assert_eq!(position.to_string(), "chunk:3:15");
+            assert_eq!(
+                position.snippet(source.as_bytes()).unwrap(),
+                " 3 | local y = n + t.x.y\n   |               ^"
+            );
+
+            ctx.set_error_columns(true);
+            let err = ctx.eval("local a; a()").unwrap_err();
+            assert!(err
+                .to_string()
+                .ends_with(":1:10: attempt to call a nil value (local 'a')"));
+            let err = ctx
+                .load("=e", "\terror('no')")
+                .unwrap()
+                .call::<_, ()>(ctx, ());
+            assert_eq!(err.unwrap_err().to_string(), "e:1:2: no");
+        });
+    }
+}
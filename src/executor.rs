@@ -0,0 +1,348 @@
+//! Running Lua code that calls asynchronous Rust functions.
+//!
+//! An async callback, made with [`Callback::from_async_fn`](crate::Callback::from_async_fn), can
+//! only be called from the coroutine an [`Executor`] runs. Calling it starts its future and
+//! suspends the coroutine. The executor polls the future outside of [`Lua::enter`], so the heap can
+//! be collected and other code run in the meantime, and resumes the coroutine with the future's
+//! results once they are ready.
+//!
+//! The coroutine is also stopped whenever the heap has grown enough for the collector to have
+//! work to do, so that garbage is collected while a long call runs rather than only once it ends.
+
+use std::future::{self, Future};
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{self, Poll, Wake};
+use std::thread;
+
+use crate::vm;
+use crate::{
+    Context, Error, Function, Lua, LuaError, RegistryKey, RuntimeError, Thread, ThreadStatus, Value,
+};
+
+/// Turns the output of a finished future into the values to resume with. The future can't produce
+/// Lua values itself, as it completes outside of the arena.
+#[cfg(not(feature = "send"))]
+pub(crate) type AsyncResults =
+    Box<dyn for<'gc> FnOnce(Context<'gc>) -> Result<Vec<Value<'gc>>, LuaError<'gc>>>;
+#[cfg(feature = "send")]
+pub(crate) type AsyncResults =
+    Box<dyn for<'gc> FnOnce(Context<'gc>) -> Result<Vec<Value<'gc>>, LuaError<'gc>> + Send>;
+
+#[cfg(not(feature = "send"))]
+pub(crate) type PendingFuture = Pin<Box<dyn Future<Output = Result<AsyncResults, RuntimeError>>>>;
+#[cfg(feature = "send")]
+pub(crate) type PendingFuture =
+    Pin<Box<dyn Future<Output = Result<AsyncResults, RuntimeError>> + Send>>;
+
+/// What the state knows of the executor running in it.
+#[derive(Default)]
+pub(crate) struct ExecutorSlot {
+    /// The coroutine an executor is resuming, which async callbacks may suspend.
+    pub(crate) thread: Option<*const ()>,
+    /// The future the async callback that suspended it started.
+    pub(crate) pending: Option<PendingFuture>,
+}
+
+/// Runs a function as a coroutine, waiting on the futures of the async callbacks it calls.
+///
+/// The executor is made inside [`Lua::enter`] and polled, or [`run`](Executor::run), outside of it.
+/// Once it is finished, the function's results are taken inside again with
+/// [`take_results`](Executor::take_results).
+///
+/// A plain `coroutine.yield` from the function's own level suspends it until the next poll, which
+/// lets a long-running script give other tasks a turn. So does the collector having work to do,
+/// which it does before the coroutine is resumed.
+pub struct Executor {
+    thread: RegistryKey,
+    step: Step,
+}
+
+enum Step {
+    /// Resume the coroutine with the values these produce, or raise the error in it.
+    Resume(Result<AsyncResults, RuntimeError>),
+    /// Wait for the future of an async callback.
+    Wait(PendingFuture),
+    /// The function returned these values or died with this error.
+    Done(Result<Vec<RegistryKey>, Error>),
+    /// The outcome has been taken.
+    Taken,
+}
+
+impl Executor {
+    /// Prepares to call `function` with `args`, which happens on the first poll.
+    pub fn new<'gc>(ctx: Context<'gc>, function: Function<'gc>, args: &[Value<'gc>]) -> Executor {
+        let thread = Thread::with_function(&ctx, function);
+        let args: Vec<_> = args.iter().map(|&v| ctx.create_registry_value(v)).collect();
+        let args: AsyncResults =
+            Box::new(move |ctx| Ok(args.iter().map(|key| ctx.registry_value(key)).collect()));
+        Executor {
+            thread: ctx.create_registry_value(thread),
+            step: Step::Resume(Ok(args)),
+        }
+    }
+
+    /// The coroutine running the function.
+    pub fn thread<'gc>(&self, ctx: Context<'gc>) -> Thread<'gc> {
+        match ctx.registry_value(&self.thread) {
+            Value::Thread(thread) => thread,
+            _ => unreachable!("the registry holds the executor's thread"),
+        }
+    }
+
+    /// Whether the function has returned or failed.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.step, Step::Done(_) | Step::Taken)
+    }
+
+    /// Runs the function until it finishes or waits on a future that isn't ready. `cx` is woken
+    /// when the future can make progress.
+    pub fn poll(&mut self, lua: &mut Lua, cx: &mut task::Context<'_>) -> Poll<()> {
+        loop {
+            match &mut self.step {
+                Step::Resume(_) => {
+                    let Step::Resume(input) = mem::replace(&mut self.step, Step::Taken) else {
+                        unreachable!()
+                    };
+                    let thread = &self.thread;
+                    self.step = lua.enter(|ctx| resume(ctx, thread, input));
+                    if matches!(self.step, Step::Resume(_)) {
+                        cx.waker().wake_by_ref();
+                        return Poll::Pending;
+                    }
+                }
+                Step::Wait(future) => match future.as_mut().poll(cx) {
+                    Poll::Ready(output) => self.step = Step::Resume(output),
+                    Poll::Pending => return Poll::Pending,
+                },
+                Step::Done(_) | Step::Taken => return Poll::Ready(()),
+            }
+        }
+    }
+
+    /// Polls the function to completion.
+    pub async fn run(&mut self, lua: &mut Lua) {
+        future::poll_fn(|cx| self.poll(lua, cx)).await
+    }
+
+    /// Polls the function to completion on the current thread, which sleeps while the future of
+    /// an async callback isn't ready.
+    pub fn run_blocking(&mut self, lua: &mut Lua) {
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = task::Context::from_waker(&waker);
+        while self.poll(lua, &mut cx).is_pending() {
+            if matches!(self.step, Step::Wait(_)) {
+                thread::park();
+            }
+        }
+    }
+
+    /// What the function returned or the error it died with, once it is finished. It is handed
+    /// out only once.
+    pub fn take_results<'gc>(
+        &mut self,
+        ctx: Context<'gc>,
+    ) -> Option<Result<Vec<Value<'gc>>, LuaError<'gc>>> {
+        if !matches!(self.step, Step::Done(_)) {
+            return None;
+        }
+        let Step::Done(outcome) = mem::replace(&mut self.step, Step::Taken) else {
+            unreachable!()
+        };
+        Some(match outcome {
+            Ok(results) => Ok(results.iter().map(|key| ctx.registry_value(key)).collect()),
+            Err(err) => Err(err.into_lua_error(ctx)),
+        })
+    }
+}
+
+/// Wakes the thread [`Executor::run_blocking`] parks.
+struct Unpark(thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Whether `thread` is the coroutine an executor is resuming.
+pub(crate) fn drives(ctx: Context<'_>, thread: Thread<'_>) -> bool {
+    ctx.state().executor().borrow().thread == Some(thread.as_ptr())
+}
+
+/// Resumes the coroutine of an executor once, returning what to do next.
+fn resume(
+    ctx: Context<'_>,
+    thread: &RegistryKey,
+    input: Result<AsyncResults, RuntimeError>,
+) -> Step {
+    let Value::Thread(thread) = ctx.registry_value(thread) else {
+        unreachable!("the registry holds the executor's thread")
+    };
+    // The collections in between found these, and `call` would run them on its way in.
+    if !ctx.state().finalizers().borrow().pending.is_empty() {
+        vm::run_finalizers(ctx, Thread::new(&ctx));
+    }
+    let slot = ctx.state().executor();
+    let outer = slot.borrow_mut().thread.replace(thread.as_ptr());
+    let result = match input.map_err(LuaError::from).and_then(|input| input(ctx)) {
+        Ok(args) => thread.resume(ctx, &args),
+        Err(err) => thread.resume_error(ctx, err),
+    };
+    let pending = {
+        let mut slot = slot.borrow_mut();
+        slot.thread = outer;
+        slot.pending.take()
+    };
+    match result {
+        Ok(_) if thread.status() != ThreadStatus::Dead => match pending {
+            Some(future) => Step::Wait(future),
+            None => Step::Resume(Ok(Box::new(|_| Ok(Vec::new())))),
+        },
+        Ok(results) => Step::Done(Ok(results
+            .into_iter()
+            .map(|v| ctx.create_registry_value(v))
+            .collect())),
+        Err(err) => Step::Done(Err(err.into_owned(ctx))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::task::Wake;
+
+    use super::*;
+    use crate::LuaString;
+
+    struct NoWake;
+
+    impl Wake for NoWake {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Loads `source` into an executor, with `fetch(n)` waiting for the host to fill `slot` and
+    /// then returning the slot's value times `n`.
+    fn start(lua: &mut Lua, slot: &Arc<Mutex<Option<i64>>>, source: &str) -> Executor {
+        let slot = slot.clone();
+        lua.enter(|ctx| {
+            let fetch = Function::from_async_fn(&ctx, move |_, n: i64| {
+                let slot = slot.clone();
+                future::poll_fn(move |_| match slot.lock().unwrap().take() {
+                    Some(0) => Poll::Ready(Err(RuntimeError::new("no data"))),
+                    Some(v) => Poll::Ready(Ok(v * n)),
+                    None => Poll::Pending,
+                })
+            });
+            let name = LuaString::new(&ctx, b"fetch");
+            ctx.globals().set(&ctx, name, fetch).unwrap();
+            let function = ctx.load("=main", source).unwrap();
+            Executor::new(ctx, function, &[])
+        })
+    }
+
+    #[test]
+    fn waits_on_futures() {
+        let waker = Arc::new(NoWake).into();
+        let mut cx = task::Context::from_waker(&waker);
+        let mut lua = Lua::new();
+        let slot = Arc::new(Mutex::new(None));
+
+        let mut executor = start(
+            &mut lua,
+            &slot,
+            "local a = fetch(2) coroutine.yield() local b = fetch(3) return a + b",
+        );
+        assert!(executor.poll(&mut lua, &mut cx).is_pending());
+        *slot.lock().unwrap() = Some(10);
+        // Once after the future is ready, then again after the plain yield.
+        assert!(executor.poll(&mut lua, &mut cx).is_pending());
+        assert!(executor.poll(&mut lua, &mut cx).is_pending());
+        assert!(!executor.is_finished());
+        *slot.lock().unwrap() = Some(1);
+        assert!(executor.poll(&mut lua, &mut cx).is_ready());
+        let results = lua.enter(|ctx| executor.take_results(ctx).unwrap().unwrap()[0].to_string());
+        assert_eq!(results, "23");
+        assert!(lua.enter(|ctx| executor.take_results(ctx).is_none()));
+
+        let mut failing = start(&mut lua, &slot, "local x <close> = nil\nreturn fetch(1)");
+        *slot.lock().unwrap() = Some(0);
+        assert!(failing.poll(&mut lua, &mut cx).is_ready());
+        let err = lua.enter(|ctx| format!("{:#}", failing.take_results(ctx).unwrap().unwrap_err()));
+        assert_eq!(err, "no data\nstack traceback:\n\tmain:2: in main chunk");
+
+        let errors = [
+            (
+                "return pcall(fetch, 1)",
+                "false, attempt to yield across a C-call boundary",
+            ),
+            (
+                "local v = fetch('x') return v",
+                "bad argument #1 to 'fetch' (number expected, got string)",
+            ),
+            (
+                "return coroutine.wrap(fetch)(1)",
+                "attempt to call an async function outside of an executor",
+            ),
+        ];
+        for (source, expected) in errors {
+            let mut executor = start(&mut lua, &slot, source);
+            assert!(executor.poll(&mut lua, &mut cx).is_ready());
+            let results = lua.enter(|ctx| match executor.take_results(ctx).unwrap() {
+                Ok(values) => values.iter().map(|v| v.to_string()).collect(),
+                Err(err) => vec![err.to_string()],
+            });
+            assert_eq!(results.join(", "), expected);
+        }
+        let outside = lua.enter(|ctx| ctx.eval("fetch(1)").unwrap_err().to_string());
+        assert_eq!(
+            outside,
+            "attempt to call an async function outside of an executor"
+        );
+    }
+
+    #[test]
+    fn collects_when_asked() {
+        let waker = Arc::new(NoWake).into();
+        let mut cx = task::Context::from_waker(&waker);
+        let mut lua = Lua::new();
+        let source = "local t = {}
+            for i = 1, 100000 do t[i] = {} end
+            local before = collectgarbage('count')
+            t = nil
+            collectgarbage()
+            local weak = setmetatable({}, {__mode = 'k'})
+            weak[{}] = true
+            local steps = 1
+            while not collectgarbage('step') do steps = steps + 1 end
+            return before, collectgarbage('count'), steps, next(weak),
+                pcall(collectgarbage, 'step')";
+        let mut executor = lua.enter(|ctx| {
+            let function = ctx.load("=main", source).unwrap();
+            Executor::new(ctx, function, &[])
+        });
+        while executor.poll(&mut lua, &mut cx).is_pending() {}
+        let results = lua.enter(|ctx| {
+            let results = executor.take_results(ctx).unwrap().unwrap();
+            results.iter().map(|v| v.to_string()).collect::<Vec<_>>()
+        });
+        let (before, after): (f64, f64) =
+            (results[0].parse().unwrap(), results[1].parse().unwrap());
+        assert!(after < before / 10.0, "{results:?}");
+        assert!(results[2].parse::<u32>().unwrap() >= 1);
+        // Inside `pcall` the coroutine can't be suspended, so no step can be taken.
+        assert_eq!(
+            results[3..],
+            [
+                "nil",
+                "false",
+                "attempt to step the collector across a C-call boundary"
+            ]
+        );
+
+        let step = lua.enter(|ctx| ctx.eval("collectgarbage('step')").unwrap_err().to_string());
+        assert_eq!(step, "attempt to collect garbage outside of an executor");
+    }
+}
@@ -0,0 +1,887 @@
+//! A subset of the C API of Lua 5.4 over the VM here, so that C and C++ code written against
+//! `lua.h` can run on tei in simple cases: creating a state, moving values through the stack,
+//! reading and writing tables and globals, loading chunks, and calling functions both ways. The
+//! macros of `lua.h`, such as `lua_pcall`, `lua_pop` and `lua_tostring`, expand to functions here.
+//!
+//! The functions are exported unmangled, for a `cdylib` or `staticlib` crate depending on tei with
+//! this feature to stand in for liblua. They have the contracts the Lua manual gives them, which
+//! the safety of each rests on: a valid state, valid stack indices, nul-terminated strings.
+//!
+//! What differs from liblua:
+//!
+//! - Errors don't unwind the C stack. An error raised in a C function, by [`lua_error`] or by a
+//!   call failing, is recorded and raised when the function returns, so `return lua_error(L);` works
+//!   as usual but code after a failing call still runs. An error outside of any C function aborts,
+//!   as liblua's default panic function does.
+//! - There are no pseudo-indices, so neither the registry nor the upvalues of C closures.
+//! - There is no full or light userdata, no `lua_next`, and no continuations: C functions can't
+//!   yield, so the `k` of [`lua_callk`] and [`lua_pcallk`] is never called.
+#![allow(non_camel_case_types, non_snake_case, clippy::missing_safety_doc)]
+
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, CStr};
+use std::{ptr, slice};
+
+use crate::lua::{load_chunk, load_libraries};
+use crate::vm::ops::{self, MetaResult};
+use crate::{
+    Context, Function, Lua, LuaError, LuaString, NativeReturn, RuntimeError, StashedTable, Table,
+    Value,
+};
+
+pub type lua_Number = f64;
+pub type lua_Integer = i64;
+pub type lua_KContext = isize;
+pub type lua_CFunction = unsafe extern "C" fn(*mut lua_State) -> c_int;
+pub type lua_KFunction = unsafe extern "C" fn(*mut lua_State, c_int, lua_KContext) -> c_int;
+
+pub const LUA_OK: c_int = 0;
+pub const LUA_ERRRUN: c_int = 2;
+pub const LUA_ERRSYNTAX: c_int = 3;
+pub const LUA_ERRMEM: c_int = 4;
+pub const LUA_ERRERR: c_int = 5;
+
+pub const LUA_MULTRET: c_int = -1;
+
+pub const LUA_TNONE: c_int = -1;
+pub const LUA_TNIL: c_int = 0;
+pub const LUA_TBOOLEAN: c_int = 1;
+pub const LUA_TLIGHTUSERDATA: c_int = 2;
+pub const LUA_TNUMBER: c_int = 3;
+pub const LUA_TSTRING: c_int = 4;
+pub const LUA_TTABLE: c_int = 5;
+pub const LUA_TFUNCTION: c_int = 6;
+pub const LUA_TUSERDATA: c_int = 7;
+pub const LUA_TTHREAD: c_int = 8;
+
+/// A state together with the stack the C API works on.
+pub struct lua_State {
+    lua: UnsafeCell<Lua>,
+    /// Slot `i` of the stack is `values[i]`, for `i` in `1..=top`. An error waiting for a C
+    /// function to return is kept in `values[0]`.
+    values: StashedTable,
+    /// The slot below the first of the running C function, or 0 outside of any.
+    base: Cell<usize>,
+    top: Cell<usize>,
+    /// The context of the innermost `enter` or C function running, while there is one, so that
+    /// calls made from C functions don't enter the state again.
+    ctx: Cell<*const Context<'static>>,
+    /// How many C functions are running.
+    calls: Cell<usize>,
+    error: Cell<bool>,
+    /// Nul-terminated copies of the strings [`lua_tolstring`] returned, by slot.
+    strings: RefCell<HashMap<usize, Box<[u8]>>>,
+}
+
+impl lua_State {
+    fn new(mut lua: Lua) -> lua_State {
+        let values = lua.enter(|ctx| ctx.stash(Table::new(&ctx)));
+        lua_State {
+            lua: UnsafeCell::new(lua),
+            values,
+            base: Cell::new(0),
+            top: Cell::new(0),
+            ctx: Cell::new(ptr::null()),
+            calls: Cell::new(0),
+            error: Cell::new(false),
+            strings: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn with_ctx<R>(&self, f: impl for<'gc> FnOnce(Context<'gc>, Table<'gc>) -> R) -> R {
+        let current = self.ctx.get();
+        if !current.is_null() {
+            // SAFETY: the pointer is only set while the context it points to is in scope further up
+            // the stack, and `f` can't keep anything of it.
+            let ctx = unsafe { *current };
+            return f(ctx, ctx.fetch(&self.values));
+        }
+        // SAFETY: with no context set, nothing else is borrowing the state.
+        let lua = unsafe { &mut *self.lua.get() };
+        lua.enter(|ctx| {
+            self.ctx.set(erase(&ctx));
+            let result = f(ctx, ctx.fetch(&self.values));
+            self.ctx.set(ptr::null());
+            result
+        })
+    }
+
+    fn slot(&self, index: c_int) -> Option<usize> {
+        let (base, top) = (self.base.get(), self.top.get());
+        let slot = match index {
+            0 => return None,
+            1.. => base + index as usize,
+            _ => (top + 1).checked_sub(index.unsigned_abs() as usize)?,
+        };
+        (slot > base && slot <= top).then_some(slot)
+    }
+
+    fn get<'gc>(&self, values: Table<'gc>, index: c_int) -> Value<'gc> {
+        match self.slot(index) {
+            Some(slot) => values.get(slot as i64),
+            None => Value::Nil,
+        }
+    }
+
+    fn replace<'gc>(&self, ctx: Context<'gc>, values: Table<'gc>, slot: usize, value: Value<'gc>) {
+        values
+            .set(&ctx, slot as i64, value)
+            .expect("slots are valid keys");
+    }
+
+    fn push<'gc>(&self, ctx: Context<'gc>, values: Table<'gc>, value: Value<'gc>) {
+        let top = self.top.get() + 1;
+        self.replace(ctx, values, top, value);
+        self.top.set(top);
+    }
+
+    fn pop<'gc>(&self, ctx: Context<'gc>, values: Table<'gc>) -> Value<'gc> {
+        let top = self.top.get();
+        if top == self.base.get() {
+            return Value::Nil;
+        }
+        let value = values.get(top as i64);
+        self.set_top(ctx, values, top - 1);
+        value
+    }
+
+    fn set_top<'gc>(&self, ctx: Context<'gc>, values: Table<'gc>, top: usize) {
+        let mut strings = self.strings.borrow_mut();
+        for slot in top + 1..=self.top.get() {
+            self.replace(ctx, values, slot, Value::Nil);
+            strings.remove(&slot);
+        }
+        self.top.set(top);
+    }
+
+    /// Raises `err` when the running C function returns, or aborts if there is none.
+    fn raise<'gc>(&self, ctx: Context<'gc>, values: Table<'gc>, err: LuaError<'gc>) {
+        if self.calls.get() == 0 {
+            eprintln!("PANIC: unprotected error in call to Lua API ({err})");
+            std::process::abort();
+        }
+        self.replace(ctx, values, 0, err.value(ctx));
+        self.error.set(true);
+    }
+
+    /// Calls the function below the `nargs` values at the top, replacing them all with `nresults`
+    /// results.
+    fn call<'gc>(
+        &self,
+        ctx: Context<'gc>,
+        values: Table<'gc>,
+        nargs: c_int,
+        nresults: c_int,
+    ) -> Result<(), LuaError<'gc>> {
+        let top = self.top.get();
+        let function = top - nargs as usize;
+        let args: Vec<_> = (function + 1..=top)
+            .map(|slot| values.get(slot as i64))
+            .collect();
+        let f = values.get(function as i64);
+        self.set_top(ctx, values, function - 1);
+        let mut results = ctx.call(f, &args)?;
+        if nresults != LUA_MULTRET {
+            results.resize(nresults as usize, Value::Nil);
+        }
+        for value in results {
+            self.push(ctx, values, value);
+        }
+        Ok(())
+    }
+}
+
+/// A pointer to `ctx` to keep in [`lua_State::ctx`] while it is in scope.
+fn erase(ctx: &Context<'_>) -> *const Context<'static> {
+    (ctx as *const Context<'_>).cast()
+}
+
+unsafe fn state<'a>(L: *mut lua_State) -> &'a lua_State {
+    &*L
+}
+
+unsafe fn name(ctx: Context<'_>, s: *const c_char) -> Value<'_> {
+    Value::String(LuaString::new(&ctx, CStr::from_ptr(s).to_bytes()))
+}
+
+fn type_code(value: Value<'_>) -> c_int {
+    match value {
+        Value::Nil => LUA_TNIL,
+        Value::Boolean(_) => LUA_TBOOLEAN,
+        Value::Integer(_) | Value::Number(_) => LUA_TNUMBER,
+        Value::String(_) => LUA_TSTRING,
+        Value::Table(_) => LUA_TTABLE,
+        Value::Function(_) => LUA_TFUNCTION,
+        Value::UserData(_) => LUA_TUSERDATA,
+        Value::Thread(_) => LUA_TTHREAD,
+    }
+}
+
+fn index<'gc>(
+    ctx: Context<'gc>,
+    obj: Value<'gc>,
+    key: Value<'gc>,
+) -> Result<Value<'gc>, LuaError<'gc>> {
+    match ops::index(ctx, obj, key)? {
+        MetaResult::Value(value) => Ok(value),
+        MetaResult::Call(f, args) => Ok(ctx.call(f, &args)?.first().copied().unwrap_or_default()),
+    }
+}
+
+fn new_index<'gc>(
+    ctx: Context<'gc>,
+    obj: Value<'gc>,
+    key: Value<'gc>,
+    value: Value<'gc>,
+) -> Result<(), LuaError<'gc>> {
+    if let Some((f, args)) = ops::new_index(ctx, obj, key, value)? {
+        ctx.call(f, &args)?;
+    }
+    Ok(())
+}
+
+/// Gets `obj[key]` onto the stack, returning the type of the value.
+fn get_onto<'gc>(
+    l: &lua_State,
+    ctx: Context<'gc>,
+    values: Table<'gc>,
+    obj: Value<'gc>,
+    key: Value<'gc>,
+) -> c_int {
+    let value = index(ctx, obj, key).unwrap_or_else(|err| {
+        l.raise(ctx, values, err);
+        Value::Nil
+    });
+    l.push(ctx, values, value);
+    type_code(value)
+}
+
+/// Sets `obj[key]` to the value at the top of the stack, popping it.
+fn set_from<'gc>(
+    l: &lua_State,
+    ctx: Context<'gc>,
+    values: Table<'gc>,
+    obj: Value<'gc>,
+    key: Value<'gc>,
+) {
+    let value = l.pop(ctx, values);
+    if let Err(err) = new_index(ctx, obj, key, value) {
+        l.raise(ctx, values, err);
+    }
+}
+
+/// The state a C function was pushed onto, which owns it.
+struct Owner(*mut lua_State);
+
+// SAFETY: a C function moves between threads only along with the state that owns it.
+unsafe impl Send for Owner {}
+
+impl Owner {
+    /// The pointer, taken through a method so that closures capture the whole `Owner`.
+    fn get(&self) -> *mut lua_State {
+        self.0
+    }
+}
+
+/// The function scripts see for `f`, moving its arguments onto a stack of its own and its results
+/// back off it.
+fn c_function(ctx: Context<'_>, L: *mut lua_State, f: lua_CFunction) -> Function<'_> {
+    let owner = Owner(L);
+    Function::from_fn(&ctx, move |ctx, stack| {
+        let L = owner.get();
+        // SAFETY: the function lives in the state, so the state is still open when it is called.
+        let l = unsafe { state(L) };
+        let values = ctx.fetch(&l.values);
+        let (base, top) = (l.base.get(), l.top.get());
+        l.base.set(top);
+        for &value in stack.iter() {
+            l.push(ctx, values, value);
+        }
+
+        let outer = l.ctx.replace(erase(&ctx));
+        l.calls.set(l.calls.get() + 1);
+        // SAFETY: `f` was pushed by C code, which vouches for it.
+        let n = unsafe { f(L) };
+        l.calls.set(l.calls.get() - 1);
+        l.ctx.set(outer);
+
+        let end = l.top.get();
+        let n = (n.max(0) as usize).min(end - top);
+        let results: Vec<_> = (end - n + 1..=end)
+            .map(|slot| values.get(slot as i64))
+            .collect();
+        l.set_top(ctx, values, top);
+        l.base.set(base);
+        if l.error.replace(false) {
+            let err = values.get(0);
+            l.replace(ctx, values, 0, Value::Nil);
+            return Err(LuaError::new(err));
+        }
+        stack.replace(&results);
+        Ok(NativeReturn::Return)
+    })
+}
+
+/// Creates a state with empty globals.
+#[no_mangle]
+pub extern "C" fn luaL_newstate() -> *mut lua_State {
+    Box::into_raw(Box::new(lua_State::new(Lua::empty())))
+}
+
+/// Opens the libraries [`Lua::new`] opens.
+#[no_mangle]
+pub unsafe extern "C" fn luaL_openlibs(L: *mut lua_State) {
+    state(L).with_ctx(|ctx, _| load_libraries(ctx));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_close(L: *mut lua_State) {
+    drop(Box::from_raw(L));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_absindex(L: *mut lua_State, idx: c_int) -> c_int {
+    let l = state(L);
+    if idx > 0 {
+        idx
+    } else {
+        (l.top.get() - l.base.get()) as c_int + idx + 1
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_gettop(L: *mut lua_State) -> c_int {
+    let l = state(L);
+    (l.top.get() - l.base.get()) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_settop(L: *mut lua_State, idx: c_int) {
+    let l = state(L);
+    let top = if idx >= 0 {
+        l.base.get() + idx as usize
+    } else {
+        l.top.get() + 1 - idx.unsigned_abs() as usize
+    };
+    if top < l.top.get() {
+        l.with_ctx(|ctx, values| l.set_top(ctx, values, top));
+    } else {
+        l.top.set(top);
+    }
+}
+
+/// Always succeeds, as the stack grows as needed.
+#[no_mangle]
+pub unsafe extern "C" fn lua_checkstack(_L: *mut lua_State, _n: c_int) -> c_int {
+    1
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushvalue(L: *mut lua_State, idx: c_int) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| l.push(ctx, values, l.get(values, idx)));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_rotate(L: *mut lua_State, idx: c_int, n: c_int) {
+    let l = state(L);
+    let Some(start) = l.slot(idx) else { return };
+    l.with_ctx(|ctx, values| {
+        let slots = start..=l.top.get();
+        let mut rotated: Vec<_> = slots.clone().map(|s| values.get(s as i64)).collect();
+        let shift = n.rem_euclid(rotated.len() as c_int) as usize;
+        rotated.rotate_right(shift);
+        for (slot, value) in slots.zip(rotated) {
+            l.replace(ctx, values, slot, value);
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_copy(L: *mut lua_State, fromidx: c_int, toidx: c_int) {
+    let l = state(L);
+    let Some(to) = l.slot(toidx) else { return };
+    l.with_ctx(|ctx, values| l.replace(ctx, values, to, l.get(values, fromidx)));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_type(L: *mut lua_State, idx: c_int) -> c_int {
+    let l = state(L);
+    if l.slot(idx).is_none() {
+        return LUA_TNONE;
+    }
+    l.with_ctx(|_, values| type_code(l.get(values, idx)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_typename(_L: *mut lua_State, tp: c_int) -> *const c_char {
+    let name = match tp {
+        LUA_TNIL => c"nil",
+        LUA_TBOOLEAN => c"boolean",
+        LUA_TLIGHTUSERDATA | LUA_TUSERDATA => c"userdata",
+        LUA_TNUMBER => c"number",
+        LUA_TSTRING => c"string",
+        LUA_TTABLE => c"table",
+        LUA_TFUNCTION => c"function",
+        LUA_TTHREAD => c"thread",
+        _ => c"no value",
+    };
+    name.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_isnumber(L: *mut lua_State, idx: c_int) -> c_int {
+    let l = state(L);
+    l.with_ctx(|_, values| ops::coerce_number(l.get(values, idx)).is_some() as c_int)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_isstring(L: *mut lua_State, idx: c_int) -> c_int {
+    let l = state(L);
+    l.with_ctx(|_, values| {
+        let value = l.get(values, idx);
+        matches!(
+            value,
+            Value::String(_) | Value::Integer(_) | Value::Number(_)
+        ) as c_int
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_isinteger(L: *mut lua_State, idx: c_int) -> c_int {
+    let l = state(L);
+    l.with_ctx(|_, values| matches!(l.get(values, idx), Value::Integer(_)) as c_int)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_toboolean(L: *mut lua_State, idx: c_int) -> c_int {
+    let l = state(L);
+    l.with_ctx(|_, values| l.get(values, idx).to_bool() as c_int)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_tonumberx(
+    L: *mut lua_State,
+    idx: c_int,
+    isnum: *mut c_int,
+) -> lua_Number {
+    let l = state(L);
+    let n = l.with_ctx(|_, values| ops::coerce_number(l.get(values, idx))?.to_number());
+    if !isnum.is_null() {
+        *isnum = n.is_some() as c_int;
+    }
+    n.unwrap_or(0.0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_tointegerx(
+    L: *mut lua_State,
+    idx: c_int,
+    isnum: *mut c_int,
+) -> lua_Integer {
+    let l = state(L);
+    let n = l.with_ctx(|_, values| ops::coerce_number(l.get(values, idx))?.to_integer());
+    if !isnum.is_null() {
+        *isnum = n.is_some() as c_int;
+    }
+    n.unwrap_or(0)
+}
+
+/// Returns the string at `idx`, converting a number there to a string in place, or null for any
+/// other value. The pointer stays valid while the value is on the stack.
+#[no_mangle]
+pub unsafe extern "C" fn lua_tolstring(
+    L: *mut lua_State,
+    idx: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    let l = state(L);
+    let string = l.slot(idx).and_then(|slot| {
+        l.with_ctx(|ctx, values| {
+            let value = values.get(slot as i64);
+            let string = match value {
+                Value::String(s) => s,
+                Value::Integer(_) | Value::Number(_) => {
+                    let s = LuaString::from_vec(&ctx, value.to_string().into_bytes());
+                    l.replace(ctx, values, slot, Value::String(s));
+                    s
+                }
+                _ => return None,
+            };
+            let bytes = string.as_bytes();
+            let mut strings = l.strings.borrow_mut();
+            let copy = strings.entry(slot).or_default();
+            if copy.get(..bytes.len()) != Some(bytes) || copy.len() != bytes.len() + 1 {
+                *copy = [bytes, &[0]].concat().into_boxed_slice();
+            }
+            Some((copy.as_ptr(), bytes.len()))
+        })
+    });
+    let (ptr, n) = string.unwrap_or((ptr::null(), 0));
+    if !len.is_null() {
+        *len = n;
+    }
+    ptr as *const c_char
+}
+
+/// The length of a string, or the border of a table without calling `__len`.
+#[no_mangle]
+pub unsafe extern "C" fn lua_rawlen(L: *mut lua_State, idx: c_int) -> u64 {
+    let l = state(L);
+    l.with_ctx(|_, values| match l.get(values, idx) {
+        Value::String(s) => s.len() as u64,
+        Value::Table(t) => t.length() as u64,
+        _ => 0,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushnil(L: *mut lua_State) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| l.push(ctx, values, Value::Nil));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushnumber(L: *mut lua_State, n: lua_Number) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| l.push(ctx, values, Value::Number(n)));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushinteger(L: *mut lua_State, n: lua_Integer) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| l.push(ctx, values, Value::Integer(n)));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushboolean(L: *mut lua_State, b: c_int) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| l.push(ctx, values, Value::Boolean(b != 0)));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushlstring(
+    L: *mut lua_State,
+    s: *const c_char,
+    len: usize,
+) -> *const c_char {
+    let l = state(L);
+    let bytes = if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(s as *const u8, len)
+    };
+    l.with_ctx(|ctx, values| {
+        let string = LuaString::new(&ctx, bytes);
+        l.push(ctx, values, Value::String(string));
+    });
+    lua_tolstring(L, -1, ptr::null_mut())
+}
+
+/// Pushes a copy of `s`, or nil if it is null.
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushstring(L: *mut lua_State, s: *const c_char) -> *const c_char {
+    if s.is_null() {
+        lua_pushnil(L);
+        return ptr::null();
+    }
+    lua_pushlstring(L, s, CStr::from_ptr(s).to_bytes().len())
+}
+
+/// Pushes `f` as a function. C closures can't have upvalues here, so `n` must be 0.
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushcclosure(L: *mut lua_State, f: lua_CFunction, n: c_int) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        if n != 0 {
+            let err = RuntimeError::new("C closures with upvalues are not supported");
+            l.raise(ctx, values, err.into());
+        }
+        l.push(ctx, values, Value::Function(c_function(ctx, L, f)));
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_createtable(L: *mut lua_State, _narr: c_int, _nrec: c_int) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| l.push(ctx, values, Value::Table(Table::new(&ctx))));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_getglobal(L: *mut lua_State, name: *const c_char) -> c_int {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        let globals = Value::Table(ctx.globals());
+        get_onto(l, ctx, values, globals, self::name(ctx, name))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_setglobal(L: *mut lua_State, name: *const c_char) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        let globals = Value::Table(ctx.globals());
+        set_from(l, ctx, values, globals, self::name(ctx, name));
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_getfield(L: *mut lua_State, idx: c_int, k: *const c_char) -> c_int {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        let obj = l.get(values, idx);
+        get_onto(l, ctx, values, obj, name(ctx, k))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_setfield(L: *mut lua_State, idx: c_int, k: *const c_char) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        let obj = l.get(values, idx);
+        set_from(l, ctx, values, obj, name(ctx, k));
+    });
+}
+
+/// Replaces the key at the top of the stack with its value in the table at `idx`.
+#[no_mangle]
+pub unsafe extern "C" fn lua_gettable(L: *mut lua_State, idx: c_int) -> c_int {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        let obj = l.get(values, idx);
+        let key = l.pop(ctx, values);
+        get_onto(l, ctx, values, obj, key)
+    })
+}
+
+/// Sets the key below the top of the stack to the value at the top in the table at `idx`,
+/// popping both.
+#[no_mangle]
+pub unsafe extern "C" fn lua_settable(L: *mut lua_State, idx: c_int) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        let obj = l.get(values, idx);
+        let value = l.pop(ctx, values);
+        let key = l.pop(ctx, values);
+        if let Err(err) = new_index(ctx, obj, key, value) {
+            l.raise(ctx, values, err);
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_rawgeti(L: *mut lua_State, idx: c_int, n: lua_Integer) -> c_int {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        let value = match l.get(values, idx) {
+            Value::Table(t) => t.get(n),
+            _ => {
+                l.raise(ctx, values, RuntimeError::new("table expected").into());
+                Value::Nil
+            }
+        };
+        l.push(ctx, values, value);
+        type_code(value)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_rawseti(L: *mut lua_State, idx: c_int, n: lua_Integer) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        let table = l.get(values, idx);
+        let value = l.pop(ctx, values);
+        let result = match table {
+            Value::Table(t) => t
+                .set(&ctx, n, value)
+                .map_err(|err| RuntimeError::new(err.to_string())),
+            _ => Err(RuntimeError::new("table expected")),
+        };
+        if let Err(err) = result {
+            l.raise(ctx, values, err.into());
+        }
+    });
+}
+
+/// Loads a chunk as [`Context::load`] does, pushing the function, or the message with
+/// [`LUA_ERRSYNTAX`]. `name` and `mode` may be null, for the chunk name `"?"` and both modes.
+#[no_mangle]
+pub unsafe extern "C" fn luaL_loadbufferx(
+    L: *mut lua_State,
+    buff: *const c_char,
+    sz: usize,
+    name: *const c_char,
+    mode: *const c_char,
+) -> c_int {
+    let l = state(L);
+    let source = if sz == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(buff as *const u8, sz)
+    };
+    let name = if name.is_null() {
+        b"?"
+    } else {
+        CStr::from_ptr(name).to_bytes()
+    };
+    let mode = if mode.is_null() {
+        b"bt"
+    } else {
+        CStr::from_ptr(mode).to_bytes()
+    };
+    l.with_ctx(|ctx, values| {
+        let env = Value::Table(ctx.globals());
+        match load_chunk(ctx, source, name, mode, env) {
+            Ok(closure) => {
+                l.push(ctx, values, Value::Function(closure.into()));
+                LUA_OK
+            }
+            Err(message) => {
+                let message = LuaString::from_vec(&ctx, message.into_bytes());
+                l.push(ctx, values, Value::String(message));
+                LUA_ERRSYNTAX
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn luaL_loadstring(L: *mut lua_State, s: *const c_char) -> c_int {
+    let len = CStr::from_ptr(s).to_bytes().len();
+    luaL_loadbufferx(L, s, len, s, ptr::null())
+}
+
+/// Calls the function below the `nargs` values at the top of the stack, raising any error.
+#[no_mangle]
+pub unsafe extern "C" fn lua_callk(
+    L: *mut lua_State,
+    nargs: c_int,
+    nresults: c_int,
+    _ctx: lua_KContext,
+    _k: Option<lua_KFunction>,
+) {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        if let Err(err) = l.call(ctx, values, nargs, nresults) {
+            l.raise(ctx, values, err);
+        }
+    });
+}
+
+/// Calls the function below the `nargs` values at the top of the stack. An error is left on the
+/// stack in place of the function and its arguments, after passing it through the message handler
+/// at `msgh`, if that isn't 0.
+#[no_mangle]
+pub unsafe extern "C" fn lua_pcallk(
+    L: *mut lua_State,
+    nargs: c_int,
+    nresults: c_int,
+    msgh: c_int,
+    _ctx: lua_KContext,
+    _k: Option<lua_KFunction>,
+) -> c_int {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        let handler = l.get(values, msgh);
+        let Err(err) = l.call(ctx, values, nargs, nresults) else {
+            return LUA_OK;
+        };
+        let (value, status) = match handler {
+            Value::Nil => (err.value(ctx), LUA_ERRRUN),
+            _ => match ctx.call(handler, &[err.value(ctx)]) {
+                Ok(results) => (results.first().copied().unwrap_or_default(), LUA_ERRRUN),
+                Err(err) => (err.value(ctx), LUA_ERRERR),
+            },
+        };
+        l.push(ctx, values, value);
+        status
+    })
+}
+
+/// Raises the value at the top of the stack as an error, once the running C function returns.
+#[no_mangle]
+pub unsafe extern "C" fn lua_error(L: *mut lua_State) -> c_int {
+    let l = state(L);
+    l.with_ctx(|ctx, values| {
+        let value = l.pop(ctx, values);
+        l.raise(ctx, values, LuaError::new(value));
+    });
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn sum(L: *mut lua_State) -> c_int {
+        let mut sum = 0;
+        for i in 1..=lua_gettop(L) {
+            sum += lua_tointegerx(L, i, ptr::null_mut());
+        }
+        lua_pushinteger(L, sum);
+        1
+    }
+
+    unsafe extern "C" fn fail(L: *mut lua_State) -> c_int {
+        lua_pushstring(L, c"failed".as_ptr());
+        lua_error(L)
+    }
+
+    unsafe fn to_str<'a>(L: *mut lua_State, idx: c_int) -> &'a str {
+        CStr::from_ptr(lua_tolstring(L, idx, ptr::null_mut()))
+            .to_str()
+            .unwrap()
+    }
+
+    unsafe fn run(L: *mut lua_State, source: &CStr) -> c_int {
+        assert_eq!(luaL_loadstring(L, source.as_ptr()), LUA_OK);
+        lua_pcallk(L, 0, LUA_MULTRET, 0, 0, None)
+    }
+
+    #[test]
+    fn c_api() {
+        unsafe {
+            let L = luaL_newstate();
+            luaL_openlibs(L);
+            lua_pushcclosure(L, sum, 0);
+            lua_setglobal(L, c"sum".as_ptr());
+            lua_pushcclosure(L, fail, 0);
+            lua_setglobal(L, c"fail".as_ptr());
+
+            assert_eq!(run(L, c"return sum(1, 2, 3), 'x' .. sum(4)"), LUA_OK);
+            assert_eq!(lua_gettop(L), 2);
+            assert_eq!(lua_tointegerx(L, 1, ptr::null_mut()), 6);
+            assert_eq!(to_str(L, -1), "x4");
+            assert_eq!(to_str(L, 1), "6");
+            assert_eq!(lua_type(L, 1), LUA_TSTRING);
+            assert_eq!(lua_type(L, 3), LUA_TNONE);
+            lua_settop(L, 0);
+
+            assert_eq!(run(L, c"return pcall(fail)"), LUA_OK);
+            assert_eq!(lua_toboolean(L, 1), 0);
+            assert_eq!(to_str(L, 2), "failed");
+            lua_settop(L, 0);
+            assert_eq!(run(L, c"fail()"), LUA_ERRRUN);
+            assert_eq!(to_str(L, -1), "failed");
+            lua_settop(L, 0);
+            assert_eq!(luaL_loadstring(L, c"return +".as_ptr()), LUA_ERRSYNTAX);
+            lua_settop(L, 0);
+
+            lua_createtable(L, 0, 0);
+            lua_pushinteger(L, 7);
+            lua_setfield(L, -2, c"n".as_ptr());
+            lua_pushstring(L, c"a".as_ptr());
+            lua_rawseti(L, -2, 1);
+            lua_setglobal(L, c"t".as_ptr());
+            assert_eq!(run(L, c"return t.n * 2, t[1]"), LUA_OK);
+            assert_eq!(lua_tonumberx(L, 1, ptr::null_mut()), 14.0);
+            assert_eq!(to_str(L, 2), "a");
+            assert_eq!(lua_getglobal(L, c"t".as_ptr()), LUA_TTABLE);
+            assert_eq!(lua_getfield(L, -1, c"n".as_ptr()), LUA_TNUMBER);
+            assert_eq!(lua_rawlen(L, -2), 1);
+            assert_eq!(lua_gettop(L), 4);
+            lua_close(L);
+        }
+    }
+}
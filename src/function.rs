@@ -0,0 +1,871 @@
+use std::fmt;
+use std::future::Future;
+
+use crate::bytecode::Prototype;
+use crate::executor::AsyncResults;
+use crate::mem::{Gc, Lock, Managed, Mutation, Tracer};
+use crate::vm::{Stack, Thread};
+use crate::{Context, FromLuaMulti, IntoLuaMulti, LuaError, MaybeSend, RuntimeError, Value};
+
+/// A native function callable from Lua.
+///
+/// Arguments are passed on the stack, and whatever the function leaves on the stack is returned, or
+/// yielded if it asks to yield.
+pub type NativeFn =
+    for<'gc> fn(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>>;
+
+/// What to do with the values a native function leaves on the stack.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NativeReturn {
+    /// Return them to the caller.
+    Return,
+    /// Yield them from the running coroutine, which is suspended until it is resumed. The values
+    /// passed to that resume become the function's results, or are passed to the [`Sequence`] set
+    /// with [`Stack::then`].
+    ///
+    /// This is only allowed when the function is called directly from Lua code running in a
+    /// coroutine, or through [`NativeReturn::Call`] by a function that was, but not from a
+    /// metamethod or from native code calling it with [`vm::call`](crate::vm::call).
+    Yield,
+    /// Call the first of them with the rest as arguments. The results of the call become the
+    /// function's results, or are passed to the [`Sequence`] set with [`Stack::then`].
+    ///
+    /// The call is made from the interpreter loop the function was called from, so that what it
+    /// calls can yield as though it had been called from Lua code.
+    Call,
+}
+
+/// A callable Lua value: either a closure over compiled bytecode, or a native function, with or
+/// without upvalues of its own, or a Rust closure.
+#[derive(Copy, Clone)]
+pub enum Function<'gc> {
+    Closure(Closure<'gc>),
+    Native(NativeFn),
+    NativeClosure(NativeClosure<'gc>),
+    Callback(Callback<'gc>),
+}
+
+impl<'gc> Function<'gc> {
+    /// A function calling the Rust closure `f`. See [`Callback::from_fn`].
+    pub fn from_fn<F>(mc: &Mutation<'gc>, f: F) -> Function<'gc>
+    where
+        F: Fn(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>>
+            + MaybeSend
+            + 'static,
+    {
+        Function::Callback(Callback::from_fn(mc, f))
+    }
+
+    /// A function calling the Rust closure `f` with converted arguments. See
+    /// [`Callback::from_typed_fn`].
+    pub fn from_typed_fn<A, R, F>(mc: &Mutation<'gc>, f: F) -> Function<'gc>
+    where
+        A: FromLuaMulti<'gc>,
+        R: IntoLuaMulti<'gc>,
+        F: Fn(Context<'gc>, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+    {
+        Function::Callback(Callback::from_typed_fn(mc, f))
+    }
+
+    /// A function starting the future `f` returns. See [`Callback::from_async_fn`].
+    pub fn from_async_fn<A, R, F, Fut>(mc: &Mutation<'gc>, f: F) -> Function<'gc>
+    where
+        A: FromLuaMulti<'gc>,
+        R: for<'r> IntoLuaMulti<'r> + MaybeSend + 'static,
+        F: Fn(Context<'gc>, A) -> Fut + MaybeSend + 'static,
+        Fut: Future<Output = Result<R, RuntimeError>> + MaybeSend + 'static,
+    {
+        Function::Callback(Callback::from_async_fn(mc, f))
+    }
+
+    /// Calls the function with `args` on a new thread, converting its results to `R`.
+    ///
+    /// Results that don't convert raise a "bad result" error, the way arguments that don't convert
+    /// raise a "bad argument" one.
+    pub fn call<A, R>(self, ctx: Context<'gc>, args: A) -> Result<R, LuaError<'gc>>
+    where
+        A: IntoLuaMulti<'gc>,
+        R: FromLuaMulti<'gc>,
+    {
+        let args = args.into_lua_multi(ctx)?;
+        let results = ctx.call(self, &args)?;
+        convert_results(ctx, &results)
+    }
+
+    /// The identity of the function, for display and hashing.
+    pub fn as_ptr(self) -> *const () {
+        match self {
+            Function::Closure(c) => c.as_ptr(),
+            Function::Native(f) => f as *const (),
+            Function::NativeClosure(c) => c.as_ptr(),
+            Function::Callback(c) => c.as_ptr(),
+        }
+    }
+
+    /// The order the function was made in, see [`Gc::serial`], or `None` for a plain Rust
+    /// function, which isn't allocated.
+    pub(crate) fn serial(self) -> Option<u32> {
+        match self {
+            Function::Closure(c) => Some(c.serial()),
+            Function::Native(_) => None,
+            Function::NativeClosure(c) => Some(c.serial()),
+            Function::Callback(c) => Some(c.serial()),
+        }
+    }
+}
+
+/// Converts the results of a call to `R`, raising a "bad result" error for the first that doesn't
+/// convert.
+pub(crate) fn convert_results<'gc, R: FromLuaMulti<'gc>>(
+    ctx: Context<'gc>,
+    results: &[Value<'gc>],
+) -> Result<R, LuaError<'gc>> {
+    R::from_lua_multi(ctx, results).map_err(|err| {
+        let message = format!("bad result #{} ({})", err.index + 1, err.error);
+        RuntimeError::new(message).into()
+    })
+}
+
+impl<'gc> PartialEq for Function<'gc> {
+    fn eq(&self, other: &Function<'gc>) -> bool {
+        match (*self, *other) {
+            (Function::Closure(a), Function::Closure(b)) => a == b,
+            (Function::Native(a), Function::Native(b)) => a as usize == b as usize,
+            (Function::NativeClosure(a), Function::NativeClosure(b)) => a == b,
+            (Function::Callback(a), Function::Callback(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl<'gc> Eq for Function<'gc> {}
+
+impl<'gc> fmt::Debug for Function<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Function::Closure(c) => fmt::Debug::fmt(c, f),
+            Function::Native(n) => write!(f, "Native({:p})", *n as *const ()),
+            Function::NativeClosure(c) => fmt::Debug::fmt(c, f),
+            Function::Callback(c) => fmt::Debug::fmt(c, f),
+        }
+    }
+}
+
+impl<'gc> From<Closure<'gc>> for Function<'gc> {
+    fn from(closure: Closure<'gc>) -> Self {
+        Function::Closure(closure)
+    }
+}
+
+unsafe impl<'gc> Managed for Function<'gc> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Function::Closure(c) => c.trace(tracer),
+            Function::NativeClosure(c) => c.trace(tracer),
+            Function::Callback(c) => c.trace(tracer),
+            Function::Native(_) => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ClosureState<'gc> {
+    pub proto: Gc<'gc, Prototype<'gc>>,
+    pub upvalues: Box<[UpValue<'gc>]>,
+}
+
+unsafe impl<'gc> Managed for ClosureState<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.proto.trace(tracer);
+        self.upvalues.trace(tracer);
+    }
+}
+
+/// An instance of a compiled Lua function.
+#[derive(Copy, Clone)]
+pub struct Closure<'gc>(Gc<'gc, ClosureState<'gc>>);
+
+impl<'gc> Closure<'gc> {
+    /// Creates a closure with fresh, closed upvalues holding nil.
+    pub fn new(mc: &Mutation<'gc>, proto: Gc<'gc, Prototype<'gc>>) -> Closure<'gc> {
+        let upvalues = proto
+            .upvalues
+            .iter()
+            .map(|_| UpValue::new(mc, UpValueState::Closed(Value::Nil)))
+            .collect();
+        Closure::with_upvalues(mc, proto, upvalues)
+    }
+
+    /// Creates the closure for the main function of a chunk, whose one upvalue is `_ENV`, the
+    /// table its globals are looked up in.
+    pub fn with_env(
+        mc: &Mutation<'gc>,
+        proto: Gc<'gc, Prototype<'gc>>,
+        env: Value<'gc>,
+    ) -> Closure<'gc> {
+        let upvalues = proto
+            .upvalues
+            .iter()
+            .enumerate()
+            .map(|(i, _)| {
+                let value = if i == 0 { env } else { Value::Nil };
+                UpValue::new(mc, UpValueState::Closed(value))
+            })
+            .collect();
+        Closure::with_upvalues(mc, proto, upvalues)
+    }
+
+    pub fn with_upvalues(
+        mc: &Mutation<'gc>,
+        proto: Gc<'gc, Prototype<'gc>>,
+        upvalues: Box<[UpValue<'gc>]>,
+    ) -> Closure<'gc> {
+        debug_assert_eq!(proto.upvalues.len(), upvalues.len());
+        Closure(Gc::new(mc, ClosureState { proto, upvalues }))
+    }
+
+    #[inline]
+    pub fn proto(self) -> Gc<'gc, Prototype<'gc>> {
+        self.0.as_ref().proto
+    }
+
+    #[inline]
+    pub fn upvalues(self) -> &'gc [UpValue<'gc>] {
+        &self.0.as_ref().upvalues
+    }
+
+    #[inline]
+    pub fn as_ptr(self) -> *const () {
+        Gc::as_ptr(self.0).cast()
+    }
+
+    /// The order the closure was made in; see [`Gc::serial`].
+    #[inline]
+    pub(crate) fn serial(self) -> u32 {
+        Gc::serial(self.0)
+    }
+
+    /// Returns true if the current collection has marked the closure so far.
+    #[inline]
+    pub(crate) fn is_marked(self, tracer: &Tracer) -> bool {
+        tracer.is_marked(self.0)
+    }
+}
+
+impl<'gc> PartialEq for Closure<'gc> {
+    fn eq(&self, other: &Closure<'gc>) -> bool {
+        Gc::ptr_eq(self.0, other.0)
+    }
+}
+
+impl<'gc> Eq for Closure<'gc> {}
+
+impl<'gc> fmt::Debug for Closure<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Closure({:p})", self.as_ptr())
+    }
+}
+
+unsafe impl<'gc> Managed for Closure<'gc> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.trace(tracer)
+    }
+}
+
+impl<'gc> From<NativeClosure<'gc>> for Function<'gc> {
+    fn from(closure: NativeClosure<'gc>) -> Self {
+        Function::NativeClosure(closure)
+    }
+}
+
+pub struct NativeClosureState<'gc> {
+    pub function: NativeFn,
+    pub upvalues: Box<[Gc<'gc, Lock<Value<'gc>>>]>,
+}
+
+unsafe impl<'gc> Managed for NativeClosureState<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.upvalues.trace(tracer);
+    }
+}
+
+/// A native function together with values it keeps between calls, like a C closure in the reference
+/// implementation. The function reads and writes them through [`Stack::upvalue`] and
+/// [`Stack::set_upvalue`].
+#[derive(Copy, Clone)]
+pub struct NativeClosure<'gc>(Gc<'gc, NativeClosureState<'gc>>);
+
+impl<'gc> NativeClosure<'gc> {
+    pub fn new(
+        mc: &Mutation<'gc>,
+        function: NativeFn,
+        upvalues: &[Value<'gc>],
+    ) -> NativeClosure<'gc> {
+        let upvalues = upvalues
+            .iter()
+            .map(|&v| Gc::new(mc, Lock::new(v)))
+            .collect();
+        NativeClosure(Gc::new(mc, NativeClosureState { function, upvalues }))
+    }
+
+    #[inline]
+    pub fn function(self) -> NativeFn {
+        self.0.as_ref().function
+    }
+
+    #[inline]
+    pub fn upvalues(self) -> &'gc [Gc<'gc, Lock<Value<'gc>>>] {
+        &self.0.as_ref().upvalues
+    }
+
+    #[inline]
+    pub fn as_ptr(self) -> *const () {
+        Gc::as_ptr(self.0).cast()
+    }
+
+    /// The order the native closure was made in; see [`Gc::serial`].
+    #[inline]
+    pub(crate) fn serial(self) -> u32 {
+        Gc::serial(self.0)
+    }
+
+    /// Returns true if the current collection has marked the closure so far.
+    #[inline]
+    pub(crate) fn is_marked(self, tracer: &Tracer) -> bool {
+        tracer.is_marked(self.0)
+    }
+}
+
+impl<'gc> PartialEq for NativeClosure<'gc> {
+    fn eq(&self, other: &NativeClosure<'gc>) -> bool {
+        Gc::ptr_eq(self.0, other.0)
+    }
+}
+
+impl<'gc> Eq for NativeClosure<'gc> {}
+
+impl<'gc> fmt::Debug for NativeClosure<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeClosure({:p})", self.as_ptr())
+    }
+}
+
+unsafe impl<'gc> Managed for NativeClosure<'gc> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.trace(tracer)
+    }
+}
+
+/// The Rust side of a [`Callback`]: a function together with the Lua values it keeps alive, which
+/// its [`Managed`] implementation reports to the collector.
+pub trait CallbackFn<'gc>: Managed {
+    fn call(
+        &self,
+        ctx: Context<'gc>,
+        stack: &mut Stack<'gc, '_>,
+    ) -> Result<NativeReturn, LuaError<'gc>>;
+}
+
+/// The rest of a native function's work after it yields or makes a call, set with
+/// [`Stack::then`].
+///
+/// The sequence is stepped with the values the coroutine is resumed with, or the results of the
+/// call, and returns like a native function does. After another yield or call it is stepped again,
+/// unless it set another sequence to take over.
+pub trait Sequence<'gc>: Managed {
+    fn step(
+        &mut self,
+        ctx: Context<'gc>,
+        stack: &mut Stack<'gc, '_>,
+    ) -> Result<NativeReturn, LuaError<'gc>>;
+}
+
+/// A sequence that can't hold Lua values between steps, since it is `'static`.
+pub(crate) struct StaticSequence<F>(pub(crate) F);
+
+unsafe impl<F: MaybeSend> Managed for StaticSequence<F> {}
+
+impl<'gc, F> Sequence<'gc> for StaticSequence<F>
+where
+    F: FnMut(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> + MaybeSend,
+{
+    fn step(
+        &mut self,
+        ctx: Context<'gc>,
+        stack: &mut Stack<'gc, '_>,
+    ) -> Result<NativeReturn, LuaError<'gc>> {
+        (self.0)(ctx, stack)
+    }
+}
+
+/// A closure that can't hold Lua values, since it is `'static`.
+struct StaticFn<F>(F);
+
+unsafe impl<F: MaybeSend> Managed for StaticFn<F> {}
+
+impl<'gc, F> CallbackFn<'gc> for StaticFn<F>
+where
+    F: Fn(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> + MaybeSend,
+{
+    fn call(
+        &self,
+        ctx: Context<'gc>,
+        stack: &mut Stack<'gc, '_>,
+    ) -> Result<NativeReturn, LuaError<'gc>> {
+        (self.0)(ctx, stack)
+    }
+}
+
+/// A closure given the Lua values it keeps alive as its first argument.
+struct RootedFn<R, F> {
+    root: R,
+    f: F,
+}
+
+unsafe impl<R: Managed, F: MaybeSend> Managed for RootedFn<R, F> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.root.trace(tracer);
+    }
+}
+
+impl<'gc, R, F> CallbackFn<'gc> for RootedFn<R, F>
+where
+    R: Managed,
+    F: Fn(&R, Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> + MaybeSend,
+{
+    fn call(
+        &self,
+        ctx: Context<'gc>,
+        stack: &mut Stack<'gc, '_>,
+    ) -> Result<NativeReturn, LuaError<'gc>> {
+        (self.f)(&self.root, ctx, stack)
+    }
+}
+
+pub struct CallbackState<'gc> {
+    function: Box<dyn CallbackFn<'gc> + 'gc>,
+}
+
+unsafe impl<'gc> Managed for CallbackState<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.function.trace(tracer);
+    }
+}
+
+/// A Rust closure callable from Lua, for exposing host functionality to scripts.
+///
+/// It is called like a native function, getting its arguments on the stack and leaving its results
+/// there, but it can capture state. Captured Lua values have to be reported to the collector, so
+/// they go in the `root` of [`from_fn_with`](Callback::from_fn_with) or in a [`CallbackFn`]
+/// implementation rather than being captured directly.
+#[derive(Copy, Clone)]
+pub struct Callback<'gc>(Gc<'gc, CallbackState<'gc>>);
+
+impl<'gc> Callback<'gc> {
+    pub fn new(mc: &Mutation<'gc>, function: impl CallbackFn<'gc> + 'gc) -> Callback<'gc> {
+        let function = Box::new(function);
+        Callback(Gc::new(mc, CallbackState { function }))
+    }
+
+    /// A callback calling `f`, which can capture anything but Lua values.
+    pub fn from_fn<F>(mc: &Mutation<'gc>, f: F) -> Callback<'gc>
+    where
+        F: Fn(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>>
+            + MaybeSend
+            + 'static,
+    {
+        Callback::new(mc, StaticFn(f))
+    }
+
+    /// A callback calling `f` with `root`, which holds the Lua values it needs and is traced along
+    /// with the callback.
+    pub fn from_fn_with<R, F>(mc: &Mutation<'gc>, root: R, f: F) -> Callback<'gc>
+    where
+        R: Managed + 'gc,
+        F: Fn(&R, Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>>
+            + MaybeSend
+            + 'static,
+    {
+        Callback::new(mc, RootedFn { root, f })
+    }
+
+    /// A callback calling `f` with its arguments converted to `A`, returning the values `R`
+    /// converts to. An argument that doesn't convert raises a "bad argument" error naming the
+    /// function as the calling code did.
+    pub fn from_typed_fn<A, R, F>(mc: &Mutation<'gc>, f: F) -> Callback<'gc>
+    where
+        A: FromLuaMulti<'gc>,
+        R: IntoLuaMulti<'gc>,
+        F: Fn(Context<'gc>, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+    {
+        Callback::from_fn(mc, move |ctx, stack| {
+            let args = typed_args(ctx, stack)?;
+            let results = f(ctx, args)?.into_lua_multi(ctx)?;
+            stack.replace(&results);
+            Ok(NativeReturn::Return)
+        })
+    }
+
+    /// A callback starting the future `f` returns for its converted arguments, which suspends the
+    /// calling coroutine until the future's results are ready. `f` itself runs right away and can
+    /// use Lua values; the future can't, since it is polled outside of the arena.
+    ///
+    /// The future is polled by the [`Executor`](crate::Executor) running the coroutine, and calling
+    /// the function anywhere else is an error, as it is from inside a metamethod or a call from
+    /// native code, where the coroutine couldn't be suspended.
+    pub fn from_async_fn<A, R, F, Fut>(mc: &Mutation<'gc>, f: F) -> Callback<'gc>
+    where
+        A: FromLuaMulti<'gc>,
+        R: for<'r> IntoLuaMulti<'r> + MaybeSend + 'static,
+        F: Fn(Context<'gc>, A) -> Fut + MaybeSend + 'static,
+        Fut: Future<Output = Result<R, RuntimeError>> + MaybeSend + 'static,
+    {
+        Callback::from_fn(mc, move |ctx, stack| {
+            let thread = stack.thread();
+            if ctx.state().executor().borrow().thread != Some(thread.as_ptr()) {
+                return Err(RuntimeError::new(
+                    "attempt to call an async function outside of an executor",
+                )
+                .into());
+            }
+            if !thread.is_yieldable(ctx) {
+                return Err(RuntimeError::new("attempt to yield across a C-call boundary").into());
+            }
+            let future = f(ctx, typed_args(ctx, stack)?);
+            let future = async move {
+                let output = future.await?;
+                let results: AsyncResults = Box::new(move |ctx| Ok(output.into_lua_multi(ctx)?));
+                Ok(results)
+            };
+            ctx.state().executor().borrow_mut().pending = Some(Box::pin(future));
+            stack.clear();
+            Ok(NativeReturn::Yield)
+        })
+    }
+
+    #[inline]
+    pub fn call(
+        self,
+        ctx: Context<'gc>,
+        stack: &mut Stack<'gc, '_>,
+    ) -> Result<NativeReturn, LuaError<'gc>> {
+        self.0.as_ref().function.call(ctx, stack)
+    }
+
+    #[inline]
+    pub fn as_ptr(self) -> *const () {
+        Gc::as_ptr(self.0).cast()
+    }
+
+    /// The order the callback was made in; see [`Gc::serial`].
+    #[inline]
+    pub(crate) fn serial(self) -> u32 {
+        Gc::serial(self.0)
+    }
+
+    /// Returns true if the current collection has marked the callback so far.
+    #[inline]
+    pub(crate) fn is_marked(self, tracer: &Tracer) -> bool {
+        tracer.is_marked(self.0)
+    }
+}
+
+/// Converts a typed callback's arguments, raising a "bad argument" error naming the function as the
+/// calling code did for one that doesn't convert.
+pub(crate) fn typed_args<'gc, A: FromLuaMulti<'gc>>(
+    ctx: Context<'gc>,
+    stack: &Stack<'gc, '_>,
+) -> Result<A, RuntimeError> {
+    A::from_lua_multi(ctx, stack).map_err(|err| {
+        let name = stack.function_name();
+        let name = name
+            .as_ref()
+            .map_or("?".into(), |name| name.to_string_lossy());
+        err.into_runtime_error(&name, 1)
+    })
+}
+
+impl<'gc> From<Callback<'gc>> for Function<'gc> {
+    fn from(callback: Callback<'gc>) -> Self {
+        Function::Callback(callback)
+    }
+}
+
+impl<'gc> PartialEq for Callback<'gc> {
+    fn eq(&self, other: &Callback<'gc>) -> bool {
+        Gc::ptr_eq(self.0, other.0)
+    }
+}
+
+impl<'gc> Eq for Callback<'gc> {}
+
+impl<'gc> fmt::Debug for Callback<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Callback({:p})", self.as_ptr())
+    }
+}
+
+unsafe impl<'gc> Managed for Callback<'gc> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.trace(tracer)
+    }
+}
+
+/// Where the value of an upvalue currently lives.
+#[derive(Debug, Copy, Clone)]
+pub enum UpValueState<'gc> {
+    /// In a register of a function that is still running, at the given index of its thread's stack.
+    Open { thread: Thread<'gc>, index: usize },
+    /// In the upvalue itself, once the variable's scope has ended.
+    Closed(Value<'gc>),
+}
+
+unsafe impl<'gc> Managed for UpValueState<'gc> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            UpValueState::Open { thread, .. } => thread.trace(tracer),
+            UpValueState::Closed(v) => v.trace(tracer),
+        }
+    }
+}
+
+/// A local variable captured by one or more closures.
+///
+/// While the variable's function is running the upvalue is open and refers to the register on the
+/// stack, so the function and its closures see each other's writes. When the variable goes out of
+/// scope the value is moved into the upvalue, which keeps it alive for the closures still using it.
+#[derive(Copy, Clone)]
+pub struct UpValue<'gc>(Gc<'gc, Lock<UpValueState<'gc>>>);
+
+impl<'gc> UpValue<'gc> {
+    pub fn new(mc: &Mutation<'gc>, state: UpValueState<'gc>) -> UpValue<'gc> {
+        UpValue(Gc::new(mc, Lock::new(state)))
+    }
+
+    #[inline]
+    pub fn get(self) -> UpValueState<'gc> {
+        self.0.get()
+    }
+
+    #[inline]
+    pub fn set(self, mc: &Mutation<'gc>, state: UpValueState<'gc>) {
+        self.0.set(mc, state)
+    }
+}
+
+impl<'gc> PartialEq for UpValue<'gc> {
+    fn eq(&self, other: &UpValue<'gc>) -> bool {
+        Gc::ptr_eq(self.0, other.0)
+    }
+}
+
+impl<'gc> Eq for UpValue<'gc> {}
+
+impl<'gc> fmt::Debug for UpValue<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("UpValue").field(&self.get()).finish()
+    }
+}
+
+unsafe impl<'gc> Managed for UpValue<'gc> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.trace(tracer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{Lua, LuaString, MultiValue, Table};
+
+    fn set_global<'gc>(ctx: Context<'gc>, name: &str, f: impl Into<Function<'gc>>) {
+        ctx.globals()
+            .set(&ctx, LuaString::new(&ctx, name.as_bytes()), f.into())
+            .expect("string keys are always valid");
+    }
+
+    #[test]
+    fn callbacks() {
+        let calls = Arc::new(AtomicI64::new(0));
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let counter = calls.clone();
+            let count = Function::from_fn(&ctx, move |_, stack| {
+                let n = counter.fetch_add(1, Ordering::Relaxed) + 1;
+                stack.replace(&[Value::Integer(n)]);
+                Ok(NativeReturn::Return)
+            });
+            set_global(ctx, "count", count);
+            let add = Function::from_typed_fn(&ctx, |_, (a, b): (i64, i64)| Ok(a + b));
+            set_global(ctx, "add", add);
+            // The table is only reachable through the callback.
+            let seen = Table::new(&ctx);
+            let remember = Callback::from_fn_with(&ctx, seen, |seen, ctx, stack| {
+                let n = seen.length() as i64 + 1;
+                seen.set(&ctx, n, stack.get(0))
+                    .expect("integer keys are valid");
+                stack.replace(&[Value::Integer(n)]);
+                Ok(NativeReturn::Return)
+            });
+            set_global(ctx, "remember", remember);
+            ctx.eval("count() remember('a') remember({})").unwrap();
+        });
+        lua.collect_all();
+        let results = lua.enter(|ctx| {
+            let results = ctx.eval(
+                "local function bad() local n = add(1, 'x') return n end
+                return count(), add(2, 3), remember('b'), select(2, pcall(add, 1)),
+                    select(2, pcall(bad)), count == count, count ~= add",
+            );
+            results
+                .unwrap()
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            results,
+            [
+                "2",
+                "5",
+                "3",
+                "bad argument #2 to '?' (number expected, got no value)",
+                "bad argument #2 to 'add' (number expected, got string)",
+                "true",
+                "true",
+            ]
+        );
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn typed_calls() {
+        Lua::new().enter(|ctx| {
+            let f = ctx
+                .eval("return function(n, s) return n > 3, s:rep(n), nil end")
+                .unwrap()[0];
+            let Value::Function(f) = f else {
+                panic!("not a function")
+            };
+            let (big, s): (bool, String) = f.call(ctx, (5, "x")).unwrap();
+            assert_eq!((big, s.as_str()), (true, "xxxxx"));
+            let all: MultiValue = f.call(ctx, (1, "y")).unwrap();
+            assert_eq!(all.len(), 3);
+
+            let err = f.call::<_, (bool, i64)>(ctx, (2, "z")).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "bad result #2 (number expected, got string)"
+            );
+            let err = f.call::<_, ()>(ctx, "x").unwrap_err();
+            assert!(err.to_string().contains("attempt to compare"), "{err}");
+        });
+    }
+
+    /// Calls `f` with `a` and then with `b`, returning the first result of each call.
+    struct Map2<'gc> {
+        f: Value<'gc>,
+        b: Value<'gc>,
+        first: Option<Value<'gc>>,
+    }
+
+    unsafe impl<'gc> Managed for Map2<'gc> {
+        fn trace(&self, tracer: &mut Tracer) {
+            self.f.trace(tracer);
+            self.b.trace(tracer);
+            self.first.trace(tracer);
+        }
+    }
+
+    impl<'gc> Sequence<'gc> for Map2<'gc> {
+        fn step(
+            &mut self,
+            _: Context<'gc>,
+            stack: &mut Stack<'gc, '_>,
+        ) -> Result<NativeReturn, LuaError<'gc>> {
+            match self.first {
+                None => {
+                    self.first = Some(stack.get(0));
+                    stack.replace(&[self.f, self.b]);
+                    Ok(NativeReturn::Call)
+                }
+                Some(first) => {
+                    let second = stack.get(0);
+                    stack.replace(&[first, second]);
+                    Ok(NativeReturn::Return)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sequences() {
+        let results = Lua::new().enter(|ctx| {
+            // Yields 1 to n in turn, then returns the sum of the values it was resumed with.
+            let count_to = Function::from_fn(&ctx, |_, stack| {
+                let n = stack.get(0).to_integer().unwrap_or(0);
+                let (mut i, mut sum) = (1, 0);
+                stack.replace(&[Value::Integer(i)]);
+                stack.then_fn(move |_, stack| {
+                    sum += stack.get(0).to_integer().unwrap_or(0);
+                    i += 1;
+                    if i > n {
+                        stack.replace(&[Value::Integer(sum)]);
+                        return Ok(NativeReturn::Return);
+                    }
+                    stack.replace(&[Value::Integer(i)]);
+                    Ok(NativeReturn::Yield)
+                });
+                Ok(NativeReturn::Yield)
+            });
+            set_global(ctx, "count_to", count_to);
+            let map2 = Function::from_fn(&ctx, |_, stack| {
+                let (f, a, b) = (stack.get(0), stack.get(1), stack.get(2));
+                stack.then(Map2 { f, b, first: None });
+                stack.replace(&[f, a]);
+                Ok(NativeReturn::Call)
+            });
+            set_global(ctx, "map2", map2);
+            let results = ctx.eval(
+                "local counter = coroutine.wrap(function() return 'sum', count_to(3) end)
+                local counted = {counter(), counter(10), counter(20), counter(30)}
+                local function f(x) return coroutine.yield(x) * 10 end
+                local co = coroutine.wrap(function() return map2(f, 1, 2) end)
+                local a, b = co(), co(5)
+                local c, d = co(7)
+                local function inc(x) return x + 1 end
+                return table.concat(counted, ' '), a, b, c, d, (map2(inc, 1, 2)),
+                    select('#', map2(inc, 1, 2)), select(2, pcall(map2, assert, false)),
+                    select(2, pcall(map2, coroutine.yield, 1))",
+            );
+            results
+                .unwrap()
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            results,
+            [
+                "1 2 3 sum 60",
+                "1",
+                "2",
+                "50",
+                "70",
+                "2",
+                "2",
+                "assertion failed!",
+                "attempt to yield from outside a coroutine",
+            ]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! Rust iterators handed to scripts as the iterators of a generic `for`.
+
+use std::error::Error as StdError;
+use std::sync::{Arc, Mutex};
+
+use crate::convert::IntoLuaMulti;
+use crate::{
+    Context, ConversionError, Function, LuaError, LuaString, MaybeSend, NativeReturn, RuntimeError,
+    Table, Value,
+};
+
+/// Converts to the values a generic `for` loop takes, which call `next` on the iterator for each
+/// turn of the loop and convert the item to the loop's variables.
+///
+/// A typed function returning `LuaIter(words.into_iter().enumerate())` lets a script write
+/// `for i, word in words(text) do ... end`, with no table built in between. The loop ends at the
+/// first item whose first value converts to nil, or when the iterator does. Leaving the loop early,
+/// with `break` or an error, drops the iterator through the loop's closing value.
+pub struct LuaIter<I>(pub I);
+
+/// Like [`LuaIter`], for an iterator of results: an error item raises the error in the loop.
+pub struct TryLuaIter<I>(pub I);
+
+impl<'gc, I> IntoLuaMulti<'gc> for LuaIter<I>
+where
+    I: Iterator + MaybeSend + 'static,
+    I::Item: for<'r> IntoLuaMulti<'r>,
+{
+    fn into_lua_multi(self, ctx: Context<'gc>) -> Result<Vec<Value<'gc>>, ConversionError> {
+        Ok(generic_for(ctx, self.0, |ctx, item| {
+            Ok(item.into_lua_multi(ctx)?)
+        }))
+    }
+}
+
+impl<'gc, I, T, E> IntoLuaMulti<'gc> for TryLuaIter<I>
+where
+    I: Iterator<Item = Result<T, E>> + MaybeSend + 'static,
+    T: for<'r> IntoLuaMulti<'r>,
+    E: Into<Box<dyn StdError + Send + Sync>>,
+{
+    fn into_lua_multi(self, ctx: Context<'gc>) -> Result<Vec<Value<'gc>>, ConversionError> {
+        Ok(generic_for(ctx, self.0, |ctx, item| match item {
+            Ok(item) => Ok(item.into_lua_multi(ctx)?),
+            Err(err) => Err(LuaError::external(err)),
+        }))
+    }
+}
+
+/// The iterator function, its two unused state values and the closing value, sharing `iter` until
+/// it runs out or the loop is closed.
+fn generic_for<'gc, I, F>(ctx: Context<'gc>, iter: I, convert: F) -> Vec<Value<'gc>>
+where
+    I: Iterator + MaybeSend + 'static,
+    F: Fn(Context<'gc>, I::Item) -> Result<Vec<Value<'gc>>, LuaError<'gc>> + MaybeSend + 'static,
+{
+    let iter = Arc::new(Mutex::new(Some(iter)));
+
+    let next = Function::from_fn(&ctx, {
+        let iter = iter.clone();
+        move |ctx, stack| {
+            let mut iter = iter
+                .try_lock()
+                .map_err(|_| RuntimeError::new("iterator called while it is running"))?;
+            let item = iter.as_mut().and_then(Iterator::next);
+            let values = match item {
+                Some(item) => convert(ctx, item)?,
+                None => {
+                    *iter = None;
+                    Vec::new()
+                }
+            };
+            stack.replace(&values);
+            Ok(NativeReturn::Return)
+        }
+    });
+
+    let close = Function::from_fn(&ctx, move |_, stack| {
+        if let Ok(mut iter) = iter.lock() {
+            drop(iter.take());
+        }
+        stack.clear();
+        Ok(NativeReturn::Return)
+    });
+    let metatable = Table::new(&ctx);
+    let key = LuaString::new(&ctx, b"__close");
+    metatable
+        .set(&ctx, key, close)
+        .expect("string keys are always valid");
+    let closing = Table::new(&ctx);
+    closing.set_metatable(&ctx, Some(metatable));
+
+    vec![
+        Value::Function(next),
+        Value::Nil,
+        Value::Nil,
+        Value::Table(closing),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::Lua;
+
+    /// Counts up to a limit, recording when it is dropped.
+    struct Count {
+        n: i64,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Iterator for Count {
+        type Item = (i64, String);
+
+        fn next(&mut self) -> Option<(i64, String)> {
+            self.n += 1;
+            (self.n <= 5).then(|| (self.n, "x".repeat(self.n as usize)))
+        }
+    }
+
+    impl Drop for Count {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn generic_for_loops() {
+        let mut lua = Lua::new();
+        let dropped = Arc::new(AtomicBool::new(false));
+        lua.enter(|ctx| {
+            let count = Function::from_typed_fn(&ctx, {
+                let dropped = dropped.clone();
+                move |_, ()| {
+                    let dropped = dropped.clone();
+                    Ok(LuaIter(Count { n: 0, dropped }))
+                }
+            });
+            let parse = Function::from_typed_fn(&ctx, |_, text: String| {
+                let numbers: Vec<String> = text.split(',').map(String::from).collect();
+                Ok(TryLuaIter(numbers.into_iter().map(|s| s.parse::<i64>())))
+            });
+            let globals = ctx.globals();
+            globals
+                .set(&ctx, LuaString::new(&ctx, b"count"), count)
+                .unwrap();
+            globals
+                .set(&ctx, LuaString::new(&ctx, b"parse"), parse)
+                .unwrap();
+
+            let out = ctx
+                .eval("local s = '' for i, x in count() do s = s .. i .. x end return s")
+                .unwrap();
+            assert_eq!(out[0].to_string(), "1x2xx3xxx4xxxx5xxxxx");
+            assert!(dropped.swap(false, Ordering::Relaxed));
+
+            let out = ctx
+                .eval("for i in count() do if i == 2 then break end end return true")
+                .unwrap();
+            assert_eq!(out[0], Value::Boolean(true));
+            assert!(dropped.swap(false, Ordering::Relaxed));
+
+            let out = ctx
+                .eval("local n = 0 for v in parse('1,2,3') do n = n + v end return n")
+                .unwrap();
+            assert_eq!(out[0], Value::Integer(6));
+            let err = ctx.eval("for v in parse('1,x') do end").unwrap_err();
+            assert_eq!(err.to_string(), "invalid digit found in string");
+        });
+    }
+}
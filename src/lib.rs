@@ -1,14 +1,66 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+pub mod bytecode;
+pub mod compiler;
+pub mod debugger;
+pub mod mem;
+pub mod stdlib;
+pub mod syntax;
+pub mod vm;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "serde")]
+pub mod serde;
+
+mod app_data;
+mod convert;
+mod coverage;
+mod error;
+mod executor;
+mod function;
+mod iter;
+mod lua;
+mod pool;
+mod profile;
+mod registry;
+mod reload;
+mod sandbox;
+mod scope;
+mod stash;
+mod state;
+mod string;
+mod table;
+mod userdata;
+mod value;
+
+#[cfg(feature = "derive")]
+pub use tei_derive::{FromLua, IntoLua};
+
+pub use self::app_data::{AppDataRef, AppDataRefMut};
+pub use self::compiler::CompatLevel;
+pub use self::convert::{
+    ArgumentError, ConversionError, FromLua, FromLuaMulti, IntoLua, IntoLuaMulti, MultiValue,
+    Variadic,
+};
+pub use self::coverage::{BranchCoverage, ChunkCoverage, Coverage, CoverageReport, LineCoverage};
+pub use self::error::{Error, LuaError, PanicError, RuntimeError, SourcePosition};
+pub use self::executor::Executor;
+pub use self::function::{
+    Callback, CallbackFn, CallbackState, Closure, ClosureState, Function, NativeClosure,
+    NativeClosureState, NativeFn, NativeReturn, Sequence, UpValue, UpValueState,
+};
+pub use self::iter::{LuaIter, TryLuaIter};
+pub use self::lua::{Lua, MaybeSend, Timeout};
+pub use self::pool::{JoinHandle, VmPool, VmPoolBuilder};
+pub use self::profile::{FunctionProfile, LineProfile, Profile, Profiler};
+pub use self::registry::RegistryKey;
+pub use self::sandbox::{Library, SandboxBuilder};
+pub use self::scope::Scope;
+pub use self::stash::{
+    Fetchable, Stashable, StashedFunction, StashedTable, StashedThread, StashedUserData,
+};
+pub use self::state::{Context, State, StateRoot};
+pub use self::string::{LuaString, LuaStringBuilder};
+pub use self::table::{InvalidTableKey, RawTable, Table, TablePairs, TableState};
+pub use self::userdata::{AnyUserData, UserData, UserDataError, UserDataMethods, UserDataState};
+pub use self::value::Value;
+pub use self::vm::{OutOfFuel, Thread, ThreadStatus};
@@ -0,0 +1,611 @@
+//! The entry point for embedding: a state together with the arena it lives in.
+
+use std::fmt;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::{Duration, Instant};
+
+use crate::bytecode::{self, SIGNATURE};
+use crate::compiler::{chunk_id, compile_from_with, compile_with, CompatLevel, CompileOptions};
+use crate::function::convert_results;
+use crate::mem::{Arena, Metrics};
+use crate::vm::{self, Thread};
+use crate::{
+    stdlib, Closure, Context, Error, Executor, Fetchable, FromLuaMulti, Function, IntoLuaMulti,
+    LuaError, RuntimeError, StashedFunction, State, StateRoot, Table, Value,
+};
+
+/// How many instructions [`Lua::call_with_timeout`] lets run between looks at the clock.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+const TIMEOUT_CHECK_INTERVAL: u32 = 1000;
+
+/// A Lua state and the heap holding everything in it.
+///
+/// Values only exist inside [`Lua::enter`], which hands out a [`Context`] to load and run code
+/// with. Garbage is collected in between, as the heap grows.
+pub struct Lua {
+    arena: Arena<StateRoot>,
+}
+
+impl Lua {
+    /// Creates a state with the standard library loaded.
+    pub fn new() -> Lua {
+        Lua::with_compat(CompatLevel::default())
+    }
+
+    /// Creates a state running code written for the version of Lua `compat` names, with the
+    /// standard library of that version loaded.
+    pub fn with_compat(compat: CompatLevel) -> Lua {
+        let mut lua = Lua::empty();
+        lua.enter(|ctx| {
+            ctx.set_compat_level(compat);
+            load_libraries(ctx);
+        });
+        lua
+    }
+
+    /// Creates a state with the standard library and the debug library loaded.
+    ///
+    /// The debug library can read and change any function's locals and upvalues and any value's
+    /// metatable, which undoes whatever a host or a script keeps private that way. Only give it to
+    /// code that is trusted as much as the host itself.
+    pub fn with_debug() -> Lua {
+        let mut lua = Lua::new();
+        lua.enter(stdlib::load_debug);
+        lua
+    }
+
+    /// Creates a state with empty globals.
+    #[allow(clippy::redundant_closure)]
+    pub fn empty() -> Lua {
+        Lua {
+            arena: Arena::new(|mc| State::new(mc)),
+        }
+    }
+
+    /// Runs `f` with access to the state, then does any collection work allocation has made due.
+    ///
+    /// The values of dropped [`RegistryKey`](crate::RegistryKey)s are released first.
+    pub fn enter<F, R>(&mut self, f: F) -> R
+    where
+        F: for<'gc> FnOnce(Context<'gc>) -> R,
+    {
+        let result = self.arena.mutate(|mc, state| {
+            let ctx = Context::new(mc, state);
+            ctx.expire_registry_values();
+            f(ctx)
+        });
+        self.arena.collect_debt();
+        result
+    }
+
+    /// Runs `f` with the value behind a handle made with [`Context::stash`].
+    pub fn fetch<S, F, R>(&mut self, stashed: &S, f: F) -> R
+    where
+        S: Fetchable,
+        F: for<'gc> FnOnce(Context<'gc>, S::Fetched<'gc>) -> R,
+    {
+        self.enter(|ctx| f(ctx, stashed.fetch(ctx)))
+    }
+
+    /// Calls a stashed function with `args`, converting its results to `R`.
+    ///
+    /// The function runs as the coroutine of an [`Executor`], which leaves the state whenever the
+    /// collector has work to do, so garbage is collected as the call goes. Async callbacks can be
+    /// called, the current thread waiting on their futures, and a `coroutine.yield` from the
+    /// function's own level returns right away.
+    pub fn call<A, R>(&mut self, function: &StashedFunction, args: A) -> Result<R, Error>
+    where
+        A: for<'gc> IntoLuaMulti<'gc>,
+        R: for<'gc> FromLuaMulti<'gc>,
+    {
+        let mut executor = self.enter(|ctx| match args.into_lua_multi(ctx) {
+            Ok(args) => Ok(Executor::new(ctx, function.fetch(ctx), &args)),
+            Err(err) => Err(LuaError::from(err).into_owned(ctx)),
+        })?;
+        executor.run_blocking(self);
+        self.enter(|ctx| {
+            let results = executor
+                .take_results(ctx)
+                .expect("the executor is finished");
+            results
+                .and_then(|results| convert_results(ctx, &results))
+                .map_err(|err| err.into_owned(ctx))
+        })
+    }
+
+    /// Like [`Lua::call`], raising a [`Timeout`] error in the scripts it runs once `timeout` has
+    /// passed. The clock is read every thousand instructions through the state's
+    /// [hook](Context::set_hook), which the watchdog takes the place of for the length of the call.
+    ///
+    /// Once past the deadline, every check raises the error again, so a script catching it can't
+    /// go on for long. Native functions aren't interrupted, and neither are threads that set a
+    /// hook of their own with `debug.sethook`.
+    ///
+    /// There is no clock to go by on `wasm32-unknown-unknown`, where this is left out; bound
+    /// scripts there with [fuel](Context::set_fuel) instead.
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub fn call_with_timeout<A, R>(
+        &mut self,
+        function: &StashedFunction,
+        args: A,
+        timeout: Duration,
+    ) -> Result<R, Error>
+    where
+        A: for<'gc> IntoLuaMulti<'gc>,
+        R: for<'gc> FromLuaMulti<'gc>,
+    {
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            return self.call(function, args);
+        };
+        let previous = self.enter(|ctx| {
+            let watchdog = Function::from_fn(&ctx, move |_, _| match Instant::now() < deadline {
+                true => Ok(crate::NativeReturn::Return),
+                false => Err(LuaError::external(Timeout)),
+            });
+            ctx.replace_hook(Some(vm::Hook {
+                function: watchdog.into(),
+                mask: vm::HookMask::default(),
+                count: TIMEOUT_CHECK_INTERVAL,
+            }))
+        });
+        let result = self.call(function, args);
+        self.enter(|ctx| previous.restore(ctx));
+        result
+    }
+
+    /// Makes `require(name)` return the table `loader` makes the first time; see
+    /// [`Context::preload_module`].
+    pub fn preload_module<F>(&mut self, name: &str, loader: F)
+    where
+        F: for<'gc> Fn(Context<'gc>) -> Result<Table<'gc>, LuaError<'gc>> + MaybeSend + 'static,
+    {
+        self.enter(|ctx| ctx.preload_module(name, loader));
+    }
+
+    /// Runs a complete collection cycle.
+    pub fn collect_all(&mut self) {
+        self.arena.collect_all();
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        self.arena.metrics()
+    }
+}
+
+// SAFETY: a `Lua` owns everything its heap reaches. What the host puts there, be it callbacks,
+// userdata, app data, streams or searchers, is bounded by `MaybeSend`, and the state shares nothing
+// with the host but the registry's list of dropped keys, which is behind a mutex. `Gc` pointers
+// can't leave `enter`, so none outlive the move on the thread the state was on.
+#[cfg(feature = "send")]
+unsafe impl Send for Lua {}
+
+/// `Send` with the `send` feature, and implemented by every type without it. The functions that
+/// put host values into a state require it, so that the state can be sent to other threads when
+/// the feature is on.
+#[cfg(feature = "send")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "send")]
+impl<T: Send + ?Sized> MaybeSend for T {}
+
+/// `Send` with the `send` feature, and implemented by every type without it. The functions that
+/// put host values into a state require it, so that the state can be sent to other threads when
+/// the feature is on.
+#[cfg(not(feature = "send"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "send"))]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// The error raised in a script called with [`Lua::call_with_timeout`] that runs past its deadline.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timeout;
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timed out")
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+impl Default for Lua {
+    fn default() -> Lua {
+        Lua::new()
+    }
+}
+
+impl<'gc> Context<'gc> {
+    /// Compiles `source`, Lua text or a binary chunk, into a function whose globals are those of
+    /// the state.
+    ///
+    /// `name` follows the conventions of the chunk names given to `load`: `"=name"` appears in
+    /// messages as it is, `"@file.lua"` names a file, and anything else is shown as source text.
+    pub fn load(
+        self,
+        name: &str,
+        source: impl AsRef<[u8]>,
+    ) -> Result<Function<'gc>, LuaError<'gc>> {
+        let env = Value::Table(self.globals());
+        let closure = load_chunk(self, source.as_ref(), name.as_bytes(), b"bt", env)
+            .map_err(RuntimeError::new)?;
+        Ok(closure.into())
+    }
+
+    /// Like [`Context::load`], with the source handed out a piece at a time by `reader` until it
+    /// returns `None` or an empty piece. Text is compiled as it arrives, so a large generated or
+    /// streamed chunk needn't be held in memory whole.
+    pub fn load_from(
+        self,
+        name: &str,
+        reader: impl FnMut() -> Option<Vec<u8>>,
+    ) -> Result<Function<'gc>, LuaError<'gc>> {
+        let env = Value::Table(self.globals());
+        let closure = load_chunk_from(self, reader, name.as_bytes(), b"bt", env)
+            .map_err(RuntimeError::new)?;
+        Ok(closure.into())
+    }
+
+    /// Calls `function` with `args` on a new thread, returning its results.
+    pub fn call(
+        self,
+        function: impl Into<Value<'gc>>,
+        args: &[Value<'gc>],
+    ) -> Result<Vec<Value<'gc>>, LuaError<'gc>> {
+        vm::call(self, Thread::new(&self), function.into(), args)
+    }
+
+    /// Runs `source` and returns its results. Like an interactive interpreter, `source` is first
+    /// tried as a list of expressions to return, so `"1 + 2"` evaluates to `3`.
+    pub fn eval(self, source: &str) -> Result<Vec<Value<'gc>>, LuaError<'gc>> {
+        let function = match self.load(source, format!("return {source};")) {
+            Ok(function) => function,
+            Err(_) => self.load(source, source)?,
+        };
+        self.call(function, &[])
+    }
+}
+
+/// Loads the standard library, as [`Lua::new`] has it.
+pub(crate) fn load_libraries(ctx: Context<'_>) {
+    stdlib::load_base(ctx);
+    stdlib::load_package(ctx);
+    stdlib::load_string(ctx);
+    stdlib::load_coroutine(ctx);
+    #[cfg(feature = "io")]
+    stdlib::load_io(ctx);
+    stdlib::load_math(ctx);
+    #[cfg(feature = "os")]
+    stdlib::load_os(ctx);
+    stdlib::load_table(ctx);
+    stdlib::load_utf8(ctx);
+}
+
+/// Turns `source` into a function whose `_ENV` is `env`, the way `load` does. `mode` contains `t`
+/// to allow text and `b` to allow binary chunks. Errors are messages ready to hand to Lua code.
+pub(crate) fn load_chunk<'gc>(
+    ctx: Context<'gc>,
+    source: &[u8],
+    name: &[u8],
+    mode: &[u8],
+    env: Value<'gc>,
+) -> Result<Closure<'gc>, String> {
+    let name = chunk_id(name);
+    let binary = source.starts_with(SIGNATURE);
+    check_mode(binary, mode)?;
+    if binary && !ctx.binary_chunks() {
+        return Err("attempt to load a binary chunk (binary chunks are disabled)".to_owned());
+    }
+    let proto = if binary {
+        let proto = bytecode::undump(&ctx, source)
+            .map_err(|e| format!("{name}: bad binary format ({e})"))?;
+        if ctx.verify_bytecode() {
+            bytecode::verify(&proto).map_err(|e| format!("{name}: bad binary format ({e})"))?;
+        }
+        proto
+    } else {
+        compile_with(&ctx, source, &name, compile_options(ctx))
+            .map_err(|e| format!("{name}:{e}"))?
+    };
+    Ok(Closure::with_env(&ctx, proto, env))
+}
+
+/// Like [`load_chunk`], with the source handed out a piece at a time by `reader` until it returns
+/// `None` or an empty piece. Text is compiled as it is read; a binary chunk is read whole first.
+pub(crate) fn load_chunk_from<'gc>(
+    ctx: Context<'gc>,
+    mut reader: impl FnMut() -> Option<Vec<u8>>,
+    name: &[u8],
+    mode: &[u8],
+    env: Value<'gc>,
+) -> Result<Closure<'gc>, String> {
+    let first = reader().unwrap_or_default();
+    if first.first() == Some(&SIGNATURE[0]) {
+        let mut source = first;
+        while let Some(piece) = reader().filter(|piece| !piece.is_empty()) {
+            source.extend_from_slice(&piece);
+        }
+        return load_chunk(ctx, &source, name, mode, env);
+    }
+    let name = chunk_id(name);
+    check_mode(false, mode)?;
+    let mut first = Some(first);
+    let reader = Box::new(move || first.take().or_else(&mut reader));
+    let proto = compile_from_with(&ctx, reader, &name, compile_options(ctx))
+        .map_err(|e| format!("{name}:{e}"))?;
+    Ok(Closure::with_env(&ctx, proto, env))
+}
+
+fn compile_options(ctx: Context<'_>) -> CompileOptions {
+    CompileOptions {
+        compat: ctx.compat_level(),
+        ..CompileOptions::default()
+    }
+}
+
+/// Checks that `mode` allows a binary or text chunk, as `load` takes it.
+fn check_mode(binary: bool, mode: &[u8]) -> Result<(), String> {
+    let (kind, allowed) = match binary {
+        true => ("binary", mode.contains(&b'b')),
+        false => ("text", mode.contains(&b't')),
+    };
+    if allowed {
+        return Ok(());
+    }
+    Err(format!(
+        "attempt to load a {kind} chunk (mode is '{}')",
+        String::from_utf8_lossy(mode)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[Value<'_>]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    fn times_out() {
+        let mut lua = Lua::new();
+        let f = lua.enter(|ctx| {
+            let source = "if ... then return 'done' end
+                -- Catching the error only gets as far as the next check.
+                local ok, err = pcall(function() while true do end end)
+                while true do end";
+            ctx.stash(ctx.load("=f", source).unwrap())
+        });
+        let timeout = Duration::from_millis(20);
+        let done: String = lua.call_with_timeout(&f, true, timeout).unwrap();
+        assert_eq!(done, "done");
+        let err = lua
+            .call_with_timeout::<_, ()>(&f, false, timeout)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Timeout>(), Some(&Timeout));
+        lua.enter(|ctx| assert!(ctx.hook().is_none()));
+    }
+
+    #[test]
+    fn stashed_values() {
+        let mut lua = Lua::new();
+        let (add, totals) = lua.enter(|ctx| {
+            let add = ctx
+                .load(
+                    "=add",
+                    "local t, k, n = ... t[k] = (t[k] or 0) + n return t[k]",
+                )
+                .unwrap();
+            (ctx.stash(add), ctx.stash(crate::Table::new(&ctx)))
+        });
+        lua.collect_all();
+        assert_eq!(lua.call::<_, i64>(&add, (&totals, "a", 2)).unwrap(), 2);
+        assert_eq!(lua.call::<_, i64>(&add, (&totals, "a", 3)).unwrap(), 5);
+        let err = lua
+            .call::<_, crate::StashedTable>(&add, (&totals, "b", 1))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bad result #1 (table expected, got number)"
+        );
+        let err = lua.call::<_, ()>(&add, (&totals, "a", true)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "add:1: attempt to perform arithmetic on a boolean value (local 'n')"
+        );
+        let sum = lua.fetch(&totals, |_, totals| totals.get_str("a").to_string());
+        assert_eq!(sum, "5");
+    }
+
+    #[test]
+    fn collects_during_calls() {
+        let mut lua = Lua::new();
+        let f = lua.enter(|ctx| {
+            let source = "local peak, finalized = 0, false
+                setmetatable({}, {__gc = function() finalized = true end})
+                for i = 1, 200000 do
+                    local t = {i}
+                    if i % 1000 == 0 then peak = math.max(peak, collectgarbage('count')) end
+                end
+                return peak, finalized";
+            ctx.stash(ctx.load("=f", source).unwrap())
+        });
+        let start = lua.metrics().total_allocation() as f64 / 1024.0;
+        // Each table takes over 100 bytes, 20 MB in all were none of them collected.
+        let (peak, finalized): (f64, bool) = lua.call(&f, ()).unwrap();
+        assert!(peak < start + 2048.0, "{start} {peak}");
+        assert!(finalized);
+    }
+
+    #[test]
+    fn load_and_call() {
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let f = ctx
+                .load("=main", "x = ... return select('#', ...)")
+                .unwrap();
+            let results = ctx.call(f, &[Value::Integer(7), Value::Nil]).unwrap();
+            assert_eq!(strings(&results), ["2"]);
+            assert_eq!(ctx.globals().get_str("x"), Value::Integer(7));
+
+            let err = ctx.load("@script.lua", "\n\nerror('oops')").unwrap();
+            assert_eq!(
+                ctx.call(err, &[]).unwrap_err().to_string(),
+                "script.lua:3: oops"
+            );
+            let syntax = ctx.load("=main", "x = = 1").unwrap_err();
+            assert_eq!(syntax.to_string(), "main:1: unexpected symbol near '='");
+
+            let mut pieces = ["return 6", " * ", "7"].into_iter();
+            let streamed = ctx
+                .load_from("=stream", || pieces.next().map(|s| s.as_bytes().to_vec()))
+                .unwrap();
+            assert_eq!(strings(&ctx.call(streamed, &[]).unwrap()), ["42"]);
+        });
+        // Globals persist from one entry to the next.
+        let x = lua.enter(|ctx| strings(&ctx.eval("x * 6").unwrap()));
+        assert_eq!(x, ["42"]);
+    }
+
+    #[test]
+    fn eval_expressions_and_statements() {
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            assert_eq!(strings(&ctx.eval("1 + 2, 'a'").unwrap()), ["3", "a"]);
+            assert!(ctx.eval("y = 5").unwrap().is_empty());
+            assert_eq!(strings(&ctx.eval("y").unwrap()), ["5"]);
+            assert_eq!(ctx.eval("error('e', 0)").unwrap_err().to_string(), "e");
+        });
+        let empty = Lua::empty().enter(|ctx| ctx.eval("select").unwrap()[0].is_nil());
+        assert!(empty);
+    }
+
+    #[test]
+    fn compat_levels() {
+        let run = |compat, source: &str| {
+            Lua::with_compat(compat).enter(|ctx| match ctx.eval(source) {
+                Ok(values) => strings(&values).join(", "),
+                Err(err) => err.to_string(),
+            })
+        };
+        let lua51 = [
+            ("local goto = 1 return goto, _VERSION", "1, Lua 5.1"),
+            (
+                "return 7 // 2",
+                "[string \"return 7 // 2\"]:1: unexpected symbol near '/'",
+            ),
+            (
+                "local s = '' for w in string.gfind('a b', '%a') do s = s .. w end return s",
+                "ab",
+            ),
+            ("return loadstring('return 1')()", "1"),
+            (
+                "local f = setfenv(loadstring('return x'), {x = 'env'})
+                return f(), getfenv(f).x, getfenv(0) == _G, getfenv(print) == _G",
+                "env, env, true, true",
+            ),
+            ("setfenv(1, {y = 2}) return y", "2"),
+        ];
+        for (source, expected) in lua51 {
+            assert_eq!(run(CompatLevel::Lua51, source), expected, "{source}");
+        }
+        let local = "local x <const> = 1 return x";
+        assert_eq!(run(CompatLevel::Lua54, local), "1");
+        assert!(run(CompatLevel::Lua53, local).ends_with("unexpected symbol near '<'"));
+        assert_eq!(
+            run(CompatLevel::Lua53, "return 7 // 2, setfenv, string.gfind"),
+            "3, nil, nil"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "io")]
+    fn captured_output() {
+        use std::io::{self, Write};
+        use std::sync::{Arc, Mutex};
+
+        /// A writer into a buffer the test keeps a handle to.
+        #[derive(Clone, Default)]
+        struct Capture(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (stdout, stderr) = (Capture::default(), Capture::default());
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            ctx.set_stdout(stdout.clone());
+            ctx.set_stderr(stderr.clone());
+            let chunk = ctx
+                .load(
+                    "=main",
+                    "print('a', 1, nil)
+                    io.write('b', 2, '\\n')
+                    io.stderr:write('c\\n')
+                    setmetatable({}, {__gc = function() error('in gc') end})",
+                )
+                .unwrap();
+            ctx.call(chunk, &[]).unwrap();
+        });
+        lua.collect_all();
+        // The finalizer runs, and fails, on the way into the next call.
+        lua.enter(|ctx| {
+            let chunk = ctx.load("=late", "error('uncaught')").unwrap();
+            let err = ctx.call(chunk, &[]).unwrap_err();
+            ctx.report_error(&err);
+        });
+        assert_eq!(stdout.0.lock().unwrap().as_slice(), b"a\t1\tnil\nb2\n");
+        assert_eq!(
+            String::from_utf8_lossy(&stderr.0.lock().unwrap()),
+            "c\nmain:4: in gc\nstack traceback:\n\tmain:4: in function <main:4>\n\
+             late:1: uncaught\nstack traceback:\n\tlate:1: in main chunk\n"
+        );
+    }
+
+    #[cfg(feature = "send")]
+    #[test]
+    fn moves_between_threads() {
+        use std::sync::atomic::{AtomicI64, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        use crate::{Executor, Function, LuaString, RegistryKey};
+
+        fn assert_send<T: Send>() {}
+        assert_send::<Lua>();
+        assert_send::<Executor>();
+        assert_send::<RegistryKey>();
+
+        let calls = Arc::new(AtomicI64::new(0));
+        let mut lua = Lua::new();
+        let key = lua.enter(|ctx| {
+            let counter = calls.clone();
+            let count = Function::from_typed_fn(&ctx, move |_, ()| {
+                Ok(counter.fetch_add(1, Ordering::Relaxed) + 1)
+            });
+            let name = LuaString::new(&ctx, b"count");
+            ctx.globals().set(&ctx, name, count).unwrap();
+            ctx.create_registry_value(ctx.eval("{}").unwrap()[0])
+        });
+        let (mut lua, key) = thread::spawn(move || {
+            lua.enter(|ctx| ctx.eval("count() total = count()").map(drop).unwrap());
+            (lua, key)
+        })
+        .join()
+        .unwrap();
+        let total = lua.enter(|ctx| {
+            assert!(matches!(ctx.registry_value(&key), Value::Table(_)));
+            ctx.eval("total").unwrap()[0].to_string()
+        });
+        assert_eq!(total, "2");
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        thread::spawn(move || drop(key)).join().unwrap();
+    }
+}
@@ -0,0 +1,712 @@
+use std::cell::{Cell, RefCell};
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+use super::gc::{Color, GcHeader, Invariant};
+use super::{Gc, GcWeak, Managed};
+
+/// Tuning parameters for the incremental collector.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Pacing {
+    /// How large the heap may grow, as a percentage of its size after the last cycle, before a new cycle starts.
+    pub pause: u32,
+    /// How much collection work is done per allocated byte, as a percentage.
+    pub step_multiplier: u32,
+    /// The minimum amount of work, in bytes, done by a single collection step.
+    pub min_step: usize,
+}
+
+impl Pacing {
+    pub const DEFAULT: Pacing = Pacing {
+        pause: 200,
+        step_multiplier: 200,
+        min_step: 8 * 1024,
+    };
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        Pacing::DEFAULT
+    }
+}
+
+/// The smallest heap size at which an automatic collection cycle is started.
+const MIN_THRESHOLD: usize = 64 * 1024;
+
+/// Memory statistics of an arena, shared between the collector and the mutator.
+#[derive(Debug)]
+pub struct Metrics {
+    total: Cell<usize>,
+    debt: Cell<usize>,
+    threshold: Cell<usize>,
+    cycles: Cell<u64>,
+    pacing: Cell<Pacing>,
+    running: Cell<bool>,
+    /// Whether a cycle has started and not yet finished.
+    collecting: Cell<bool>,
+    /// Work asked for from inside a mutation, done by the next [`Arena::collect_debt`].
+    requested_work: Cell<usize>,
+    full_collection_requested: Cell<bool>,
+    limit: Cell<usize>,
+    /// The heap size [`Metrics::exceeds_limit`] last said yes at, until a cycle completes.
+    limit_reported: Cell<usize>,
+    /// Set once [`Metrics::collect_before_failing`] has said yes, until a cycle leaves the heap
+    /// under the limit.
+    limit_collecting: Cell<bool>,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics {
+            total: Cell::new(0),
+            debt: Cell::new(0),
+            threshold: Cell::new(MIN_THRESHOLD),
+            cycles: Cell::new(0),
+            pacing: Cell::new(Pacing::DEFAULT),
+            running: Cell::new(true),
+            collecting: Cell::new(false),
+            requested_work: Cell::new(0),
+            full_collection_requested: Cell::new(false),
+            limit: Cell::new(usize::MAX),
+            limit_reported: Cell::new(0),
+            limit_collecting: Cell::new(false),
+        }
+    }
+
+    /// The number of bytes currently allocated, including external allocations reported to the arena.
+    #[inline]
+    pub fn total_allocation(&self) -> usize {
+        self.total.get()
+    }
+
+    /// The number of completed collection cycles.
+    #[inline]
+    pub fn cycles(&self) -> u64 {
+        self.cycles.get()
+    }
+
+    #[inline]
+    pub fn pacing(&self) -> Pacing {
+        self.pacing.get()
+    }
+
+    #[inline]
+    pub fn set_pacing(&self, pacing: Pacing) {
+        self.pacing.set(pacing);
+    }
+
+    /// Whether [`Arena::collect_debt`] collects as the heap grows.
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.running.get()
+    }
+
+    /// Stops or restarts collection driven by allocation. Work asked for explicitly still happens
+    /// while stopped.
+    #[inline]
+    pub fn set_running(&self, running: bool) {
+        self.running.set(running);
+    }
+
+    /// Asks for at least `bytes` of collection work to be done by the next
+    /// [`Arena::collect_debt`], whether or not the heap has grown enough to call for it.
+    ///
+    /// Nothing can be collected during a mutation, so this is how code running inside one drives
+    /// the collector.
+    #[inline]
+    pub fn request_work(&self, bytes: usize) {
+        self.requested_work
+            .set(self.requested_work.get().saturating_add(bytes));
+    }
+
+    /// Asks for the next [`Arena::collect_debt`] to run a complete cycle, like
+    /// [`Arena::collect_all`].
+    #[inline]
+    pub fn request_full_collection(&self) {
+        self.full_collection_requested.set(true);
+    }
+
+    /// Whether the next [`Arena::collect_debt`] has work to do: work was asked for, or the heap
+    /// has grown by at least a step's worth while a cycle is due or under way.
+    ///
+    /// A long mutation can check this where it is able to stop, and leave the arena for the
+    /// collector to catch up.
+    #[inline]
+    pub fn collection_due(&self) -> bool {
+        if self.full_collection_requested.get() || self.requested_work.get() > 0 {
+            return true;
+        }
+        self.running.get()
+            && self.debt.get() >= self.pacing.get().min_step
+            && (self.collecting.get() || self.total.get() >= self.threshold.get())
+    }
+
+    /// The heap size past which [`Metrics::exceeds_limit`] says so, if any.
+    #[inline]
+    pub fn memory_limit(&self) -> Option<usize> {
+        Some(self.limit.get()).filter(|&limit| limit != usize::MAX)
+    }
+
+    /// Sets or removes the memory limit. Nothing in the arena enforces it: it is up to the code
+    /// allocating to check [`Metrics::exceeds_limit`] and stop.
+    #[inline]
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.limit.set(limit.unwrap_or(usize::MAX));
+        self.limit_reported.set(0);
+        self.limit_collecting.set(false);
+    }
+
+    /// Returns true if the heap is past the memory limit and has grown since the last time this
+    /// returned true, asking for the next [`Arena::collect_debt`] to collect everything it can.
+    ///
+    /// Garbage only goes away in a collection, so code that fails as it gets a yes can go on as
+    /// long as it doesn't allocate, until a collection brings the heap back down.
+    #[inline]
+    pub fn exceeds_limit(&self) -> bool {
+        let total = self.total.get();
+        if total <= self.limit.get() || total <= self.limit_reported.get() {
+            return false;
+        }
+        self.limit_reported.set(total);
+        self.full_collection_requested.set(true);
+        true
+    }
+
+    /// For code that has just been told the heap [exceeds the limit](Metrics::exceeds_limit), and
+    /// can wait for the full collection that asked for: returns true if it should, and see whether
+    /// the heap is still over the limit afterwards, rather than fail now. Only the first time is
+    /// it told to wait, until a collection brings the heap back under the limit.
+    #[inline]
+    pub fn collect_before_failing(&self) -> bool {
+        !self.limit_collecting.replace(true)
+    }
+
+    /// Reports memory owned by a managed value but allocated outside of the arena, such as the buffer of a
+    /// growable collection, so it counts towards collector pacing.
+    #[inline]
+    pub fn mark_external_allocation(&self, bytes: usize) {
+        self.total.set(self.total.get() + bytes);
+        self.debt.set(self.debt.get() + bytes);
+    }
+
+    /// Reports that memory previously passed to [`Metrics::mark_external_allocation`] has been freed.
+    #[inline]
+    pub fn mark_external_deallocation(&self, bytes: usize) {
+        self.total.set(self.total.get().saturating_sub(bytes));
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Sleep,
+    Propagate,
+    Sweep,
+}
+
+/// Collects the managed pointers reported by [`Managed::trace`].
+pub struct Tracer {
+    gray: Vec<NonNull<GcHeader>>,
+    /// The object being traced, if it isn't the root.
+    current: Option<NonNull<GcHeader>>,
+    /// Objects to call [`Managed::clear_weak`] on once marking is done.
+    weak: Vec<NonNull<GcHeader>>,
+    /// Objects to trace again at the end of marking, until that stops marking anything new.
+    ephemerons: Vec<NonNull<GcHeader>>,
+    /// The number of objects marked so far, to tell when retracing ephemerons has converged.
+    marked: usize,
+}
+
+impl Tracer {
+    fn new() -> Tracer {
+        Tracer {
+            gray: Vec::new(),
+            current: None,
+            weak: Vec::new(),
+            ephemerons: Vec::new(),
+            marked: 0,
+        }
+    }
+
+    /// Returns true if marking has reached the target of `gc` so far.
+    #[inline]
+    pub fn is_marked<T>(&self, gc: Gc<'_, T>) -> bool {
+        let color = unsafe { gc.header().as_ref() }.color();
+        matches!(color, Color::Gray | Color::Black)
+    }
+
+    /// Asks for [`Managed::clear_weak`] to be called on the object being traced, for objects that
+    /// leave some of their pointers untraced. Returns false while tracing the root, which is never
+    /// asked: it must trace them all.
+    #[inline]
+    pub fn register_weak(&mut self) -> bool {
+        if let Some(header) = self.current {
+            self.weak.push(header);
+        }
+        self.current.is_some()
+    }
+
+    /// Asks for the object being traced to be traced again before marking finishes, for objects
+    /// that only trace some pointers once others have been marked. Retracing repeats for as long as
+    /// it keeps marking new objects.
+    #[inline]
+    pub fn register_ephemeron(&mut self) {
+        if let Some(header) = self.current {
+            self.ephemerons.push(header);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn trace_header(&mut self, header: NonNull<GcHeader>) {
+        let h = unsafe { header.as_ref() };
+        match h.color() {
+            Color::White0 | Color::White1 | Color::WeakWhite => {
+                self.marked += 1;
+                if h.needs_trace() {
+                    h.set_color(Color::Gray);
+                    self.gray.push(header);
+                } else {
+                    h.set_color(Color::Black);
+                }
+            }
+            Color::Gray | Color::Black => {}
+        }
+    }
+
+    /// Traces an object again, with the tracer knowing which object it is.
+    fn trace_object(&mut self, header: NonNull<GcHeader>) {
+        self.current = Some(header);
+        unsafe { (header.as_ref().vtable.trace)(header, self) };
+        self.current = None;
+    }
+
+    #[inline]
+    pub(crate) fn trace_weak_header(&mut self, header: NonNull<GcHeader>) {
+        let h = unsafe { header.as_ref() };
+        if matches!(h.color(), Color::White0 | Color::White1) {
+            h.set_color(Color::WeakWhite);
+        }
+    }
+}
+
+pub(crate) struct Collector {
+    all: Cell<Option<NonNull<GcHeader>>>,
+    phase: Cell<Phase>,
+    white: Cell<Color>,
+    gray_again: RefCell<Vec<NonNull<GcHeader>>>,
+    sweep: Cell<Option<NonNull<GcHeader>>>,
+    sweep_prev: Cell<Option<NonNull<GcHeader>>>,
+    tracer: Tracer,
+    metrics: Metrics,
+    /// The serial of the next allocation.
+    serial: Cell<u32>,
+}
+
+impl Collector {
+    fn new() -> Collector {
+        Collector {
+            all: Cell::new(None),
+            phase: Cell::new(Phase::Sleep),
+            white: Cell::new(Color::White0),
+            gray_again: RefCell::new(Vec::new()),
+            sweep: Cell::new(None),
+            sweep_prev: Cell::new(None),
+            tracer: Tracer::new(),
+            metrics: Metrics::new(),
+            serial: Cell::new(0),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn allocation_white(&self) -> Color {
+        self.white.get()
+    }
+
+    #[inline]
+    fn old_white(&self) -> Color {
+        match self.white.get() {
+            Color::White0 => Color::White1,
+            _ => Color::White0,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn next_serial(&self) -> u32 {
+        let serial = self.serial.get();
+        self.serial.set(serial.wrapping_add(1));
+        serial
+    }
+
+    pub(crate) fn link(&self, header: NonNull<GcHeader>, size: usize) {
+        unsafe { header.as_ref() }.next.set(self.all.get());
+        self.all.set(Some(header));
+        if self.phase.get() == Phase::Sweep && self.sweep_prev.get().is_none() {
+            self.sweep_prev.set(Some(header));
+        }
+        self.metrics.mark_external_allocation(size);
+    }
+
+    #[inline]
+    pub(crate) fn is_dead(&self, header: NonNull<GcHeader>) -> bool {
+        let h = unsafe { header.as_ref() };
+        if !h.is_live() {
+            return true;
+        }
+        self.phase.get() == Phase::Sweep && {
+            let color = h.color();
+            color == Color::WeakWhite || color == self.old_white()
+        }
+    }
+
+    #[inline]
+    pub(crate) fn backward_barrier(&self, header: NonNull<GcHeader>) {
+        if self.phase.get() == Phase::Propagate {
+            let h = unsafe { header.as_ref() };
+            if h.color() == Color::Black {
+                h.set_color(Color::Gray);
+                self.gray_again.borrow_mut().push(header);
+            }
+        }
+    }
+
+    fn start_cycle<R: Managed + ?Sized>(&mut self, root: &R) {
+        debug_assert_eq!(self.phase.get(), Phase::Sleep);
+        self.phase.set(Phase::Propagate);
+        self.metrics.collecting.set(true);
+        root.trace(&mut self.tracer);
+    }
+
+    /// Blackens gray objects until the budget is spent, returning true once no gray objects remain.
+    fn propagate(&mut self, budget: &mut isize) -> bool {
+        loop {
+            if *budget <= 0 {
+                return false;
+            }
+            let header = match self.tracer.gray.pop() {
+                Some(header) => header,
+                None => {
+                    let mut again = self.gray_again.borrow_mut();
+                    if again.is_empty() {
+                        return true;
+                    }
+                    self.tracer.gray.append(&mut again);
+                    continue;
+                }
+            };
+            let h = unsafe { header.as_ref() };
+            h.set_color(Color::Black);
+            *budget -= h.vtable.size as isize;
+            self.tracer.trace_object(header);
+        }
+    }
+
+    /// Finishes marking in one go, retracing the root since changes to it aren't tracked.
+    fn finish_marking<R: Managed + ?Sized>(&mut self, root: &R) {
+        root.trace(&mut self.tracer);
+        let mut unlimited = isize::MAX;
+        self.propagate(&mut unlimited);
+        loop {
+            let marked = self.tracer.marked;
+            for header in std::mem::take(&mut self.tracer.ephemerons) {
+                self.tracer.trace_object(header);
+            }
+            self.propagate(&mut unlimited);
+            if self.tracer.marked == marked {
+                break;
+            }
+        }
+    }
+
+    /// Lets the objects that asked for it drop their pointers to unmarked objects.
+    fn clear_weak(&mut self, before_finalization: bool) {
+        let weak = std::mem::take(&mut self.tracer.weak);
+        for &header in &weak {
+            unsafe {
+                (header.as_ref().vtable.clear_weak)(header, &self.tracer, before_finalization)
+            };
+        }
+        self.tracer.weak = weak;
+    }
+
+    /// Marks an object that marking didn't reach, along with everything it references once
+    /// marking continues.
+    fn resurrect(&self, header: NonNull<GcHeader>) {
+        let h = unsafe { header.as_ref() };
+        if matches!(h.color(), Color::White0 | Color::White1 | Color::WeakWhite) {
+            if h.needs_trace() {
+                h.set_color(Color::Gray);
+                self.gray_again.borrow_mut().push(header);
+            } else {
+                h.set_color(Color::Black);
+            }
+        }
+    }
+
+    /// Flips the current white, moving to the sweep phase.
+    fn start_sweep(&mut self) {
+        self.tracer.weak.clear();
+        self.tracer.ephemerons.clear();
+        self.white.set(self.old_white());
+        self.phase.set(Phase::Sweep);
+        self.sweep.set(self.all.get());
+        self.sweep_prev.set(None);
+    }
+
+    /// Frees dead objects until the budget is spent, returning true once the sweep is complete.
+    fn sweep(&mut self, budget: &mut isize) -> bool {
+        let old_white = self.old_white();
+        let white = self.white.get();
+        while let Some(header) = self.sweep.get() {
+            if *budget <= 0 {
+                return false;
+            }
+            let h = unsafe { header.as_ref() };
+            let next = h.next.get();
+            let size = h.vtable.size;
+            *budget -= size as isize;
+            self.sweep.set(next);
+
+            let color = h.color();
+            if color == old_white {
+                match self.sweep_prev.get() {
+                    Some(prev) => unsafe { prev.as_ref() }.next.set(next),
+                    None => self.all.set(next),
+                }
+                self.metrics
+                    .mark_external_deallocation(size + GcHeader::heap_size(header));
+                unsafe { GcHeader::free(header) };
+            } else {
+                if color == Color::WeakWhite {
+                    self.metrics
+                        .mark_external_deallocation(GcHeader::heap_size(header));
+                    unsafe { GcHeader::drop_value(header) };
+                }
+                h.set_color(white);
+                self.sweep_prev.set(Some(header));
+            }
+        }
+
+        self.phase.set(Phase::Sleep);
+        self.metrics.collecting.set(false);
+        self.sweep_prev.set(None);
+        let pause = self.metrics.pacing.get().pause as usize;
+        let threshold = (self.metrics.total.get() / 100).saturating_mul(pause);
+        self.metrics.threshold.set(threshold.max(MIN_THRESHOLD));
+        self.metrics.cycles.set(self.metrics.cycles.get() + 1);
+        self.metrics.limit_reported.set(0);
+        if self.metrics.total.get() <= self.metrics.limit.get() {
+            self.metrics.limit_collecting.set(false);
+        }
+        true
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        let mut next = self.all.get();
+        while let Some(header) = next {
+            unsafe {
+                next = header.as_ref().next.get();
+                GcHeader::free(header);
+            }
+        }
+    }
+}
+
+/// The handle through which all allocation and mutation of managed objects happens.
+///
+/// A `&'gc Mutation<'gc>` is only ever available inside [`Arena::mutate`], and no collection can run while one
+/// is alive, so every `Gc<'gc, _>` stays valid for the whole mutation.
+pub struct Mutation<'gc> {
+    collector: Collector,
+    _invariant: Invariant<'gc>,
+}
+
+impl<'gc> Mutation<'gc> {
+    #[inline]
+    pub fn metrics(&self) -> &Metrics {
+        &self.collector.metrics
+    }
+
+    #[inline]
+    pub(crate) fn collector(&self) -> &Collector {
+        &self.collector
+    }
+
+    /// Re-grays `header` if the collector has already traced it, so that newly stored pointers are seen.
+    #[inline]
+    pub(crate) fn backward_barrier(&self, header: NonNull<GcHeader>) {
+        self.collector.backward_barrier(header)
+    }
+}
+
+/// Access to the heap once a cycle has marked everything reachable, before anything is freed.
+///
+/// Objects the root only holds weak pointers to can be told apart from live ones here, and kept
+/// alive for one more cycle, which is how objects get a last chance to run code before they go.
+pub struct Finalization<'gc> {
+    mc: &'gc Mutation<'gc>,
+}
+
+impl<'gc> Finalization<'gc> {
+    /// Returns true if nothing but weak pointers reach the target.
+    pub fn is_dead<T>(&self, weak: GcWeak<'gc, T>) -> bool {
+        let color = unsafe { weak.header().as_ref() }.color();
+        matches!(color, Color::White0 | Color::White1 | Color::WeakWhite)
+    }
+
+    /// Keeps the target, and everything it references, alive through this cycle. The pointer
+    /// returned must be stored somewhere reachable to keep it alive any longer.
+    pub fn resurrect<T>(&self, weak: GcWeak<'gc, T>) -> Gc<'gc, T> {
+        self.mc.collector.resurrect(weak.header());
+        weak.upgrade(self.mc)
+            .expect("objects are only dropped while sweeping")
+    }
+}
+
+impl<'gc> Deref for Finalization<'gc> {
+    type Target = Mutation<'gc>;
+
+    #[inline]
+    fn deref(&self) -> &Mutation<'gc> {
+        self.mc
+    }
+}
+
+/// Names the root type of an [`Arena`] for every possible `'gc` lifetime.
+pub trait Rootable<'a>: 'static {
+    type Root: Managed + 'a;
+
+    /// Called when a cycle has finished marking, with the chance to resurrect objects through
+    /// `fc`. Does nothing by default.
+    fn finalize(fc: &Finalization<'a>, root: &'a Self::Root) {
+        let _ = (fc, root);
+    }
+}
+
+/// The root type of `R` branded with the lifetime `'a`.
+pub type Root<'a, R> = <R as Rootable<'a>>::Root;
+
+/// A garbage collected heap with a single root object.
+///
+/// Managed objects can only be created and accessed inside [`Arena::mutate`]; collection only happens in
+/// between, through [`Arena::collect_debt`] or [`Arena::collect_all`], when everything live must be reachable
+/// from the root.
+pub struct Arena<R: for<'a> Rootable<'a>> {
+    root: Root<'static, R>,
+    mutation: Box<Mutation<'static>>,
+}
+
+impl<R: for<'a> Rootable<'a>> Arena<R> {
+    pub fn new<F>(f: F) -> Arena<R>
+    where
+        F: for<'gc> FnOnce(&'gc Mutation<'gc>) -> Root<'gc, R>,
+    {
+        let mutation = Box::new(Mutation {
+            collector: Collector::new(),
+            _invariant: Invariant::default(),
+        });
+        // The closure is universally quantified over `'gc`, so nothing branded can escape it except the root.
+        let mc: &'static Mutation<'static> = unsafe { &*(&*mutation as *const Mutation<'static>) };
+        let root = f(mc);
+        Arena { root, mutation }
+    }
+
+    /// Runs `f` with access to the root, allowing allocation and mutation of managed objects.
+    #[inline]
+    pub fn mutate<F, T>(&self, f: F) -> T
+    where
+        F: for<'gc> FnOnce(&'gc Mutation<'gc>, &'gc Root<'gc, R>) -> T,
+    {
+        unsafe {
+            let mc: &'static Mutation<'static> = &*(&*self.mutation as *const Mutation<'static>);
+            let root: &'static Root<'static, R> = &*(&self.root as *const Root<'static, R>);
+            f(mc, root)
+        }
+    }
+
+    /// Like [`Arena::mutate`], but allows replacing parts of the root itself.
+    #[inline]
+    pub fn mutate_root<F, T>(&mut self, f: F) -> T
+    where
+        F: for<'gc> FnOnce(&'gc Mutation<'gc>, &'gc mut Root<'gc, R>) -> T,
+    {
+        unsafe {
+            let mc: &'static Mutation<'static> = &*(&*self.mutation as *const Mutation<'static>);
+            let root: &'static mut Root<'static, R> =
+                &mut *(&mut self.root as *mut Root<'static, R>);
+            f(mc, root)
+        }
+    }
+
+    #[inline]
+    pub fn metrics(&self) -> &Metrics {
+        &self.mutation.collector.metrics
+    }
+
+    /// Performs collection work proportional to the memory allocated since the last step, if the heap has
+    /// grown enough to warrant it, along with any work requested through [`Metrics`].
+    pub fn collect_debt(&mut self) {
+        let collector = &mut self.mutation.collector;
+        let metrics = &collector.metrics;
+        if metrics.full_collection_requested.replace(false) {
+            metrics.requested_work.set(0);
+            self.collect_all();
+            return;
+        }
+        let requested = metrics.requested_work.replace(0);
+        if requested == 0
+            && (!metrics.running.get()
+                || collector.phase.get() == Phase::Sleep
+                    && metrics.total.get() < metrics.threshold.get())
+        {
+            return;
+        }
+        let pacing = metrics.pacing.get();
+        let work = (metrics.debt.get() / 100).saturating_mul(pacing.step_multiplier as usize);
+        metrics.debt.set(0);
+        let work = work.max(pacing.min_step).max(requested);
+        self.step(work.min(isize::MAX as usize) as isize);
+    }
+
+    /// Finishes any cycle in progress, then runs a complete collection cycle.
+    pub fn collect_all(&mut self) {
+        if self.mutation.collector.phase.get() != Phase::Sleep {
+            self.step(isize::MAX);
+        }
+        self.step(isize::MAX);
+        self.mutation.collector.metrics.debt.set(0);
+    }
+
+    /// Performs up to `budget` bytes of work, returning true if a cycle was completed.
+    pub(crate) fn step(&mut self, mut budget: isize) -> bool {
+        loop {
+            let collector = &mut self.mutation.collector;
+            match collector.phase.get() {
+                Phase::Sleep => collector.start_cycle(&self.root),
+                Phase::Propagate => {
+                    if !collector.propagate(&mut budget) {
+                        return false;
+                    }
+                    collector.finish_marking(&self.root);
+                    collector.clear_weak(true);
+                    unsafe {
+                        let mc: &'static Mutation<'static> =
+                            &*(&*self.mutation as *const Mutation<'static>);
+                        let root: &'static Root<'static, R> =
+                            &*(&self.root as *const Root<'static, R>);
+                        R::finalize(&Finalization { mc }, root);
+                    }
+                    // Marks whatever was resurrected, and anything the root changed to hold.
+                    let collector = &mut self.mutation.collector;
+                    collector.finish_marking(&self.root);
+                    collector.clear_weak(false);
+                    collector.start_sweep();
+                }
+                Phase::Sweep => return collector.sweep(&mut budget),
+            }
+            if budget <= 0 {
+                return false;
+            }
+        }
+    }
+}
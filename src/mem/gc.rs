@@ -0,0 +1,318 @@
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+use super::{Managed, Mutation, Tracer};
+
+/// Marker making a lifetime invariant, so `'gc` branded pointers from one arena cannot be mixed with another.
+pub(crate) type Invariant<'gc> = PhantomData<Cell<&'gc ()>>;
+
+/// Object colors used by the incremental tri-color collector.
+///
+/// Two whites alternate between cycles so that, while sweeping, objects left unmarked by the cycle that just
+/// finished ("old white") can be told apart from objects that survived or were allocated since ("new white").
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Color {
+    White0 = 0,
+    White1 = 1,
+    /// Unmarked, but reached through a weak pointer: the value will be dropped without freeing the allocation.
+    WeakWhite = 2,
+    Gray = 3,
+    Black = 4,
+}
+
+const COLOR_MASK: u8 = 0b111;
+/// Set while the value has not been dropped yet.
+const LIVE: u8 = 0b1000;
+/// Set if the value holds managed pointers.
+const NEEDS_TRACE: u8 = 0b1_0000;
+
+pub(crate) struct VTable {
+    pub(crate) size: usize,
+    pub(crate) trace: unsafe fn(NonNull<GcHeader>, &mut Tracer),
+    pub(crate) clear_weak: unsafe fn(NonNull<GcHeader>, &Tracer, bool),
+    pub(crate) drop_value: unsafe fn(NonNull<GcHeader>),
+    pub(crate) heap_size: unsafe fn(NonNull<GcHeader>) -> usize,
+    pub(crate) dealloc: unsafe fn(NonNull<GcHeader>),
+}
+
+/// The header at the start of every managed allocation, linking it into the collector's object list.
+pub(crate) struct GcHeader {
+    pub(crate) next: Cell<Option<NonNull<GcHeader>>>,
+    flags: Cell<u8>,
+    /// Counts the allocations of the arena, wrapping around. It fits in the padding after `flags`
+    /// on 64-bit targets.
+    serial: u32,
+    pub(crate) vtable: &'static VTable,
+}
+
+impl GcHeader {
+    #[inline]
+    pub(crate) fn color(&self) -> Color {
+        match self.flags.get() & COLOR_MASK {
+            0 => Color::White0,
+            1 => Color::White1,
+            2 => Color::WeakWhite,
+            3 => Color::Gray,
+            _ => Color::Black,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn set_color(&self, color: Color) {
+        self.flags
+            .set((self.flags.get() & !COLOR_MASK) | color as u8);
+    }
+
+    #[inline]
+    pub(crate) fn is_live(&self) -> bool {
+        self.flags.get() & LIVE != 0
+    }
+
+    #[inline]
+    pub(crate) fn needs_trace(&self) -> bool {
+        self.flags.get() & NEEDS_TRACE != 0
+    }
+
+    /// The bytes owned by the value outside of its allocation, or 0 once it has been dropped.
+    pub(crate) fn heap_size(header: NonNull<GcHeader>) -> usize {
+        let h = unsafe { header.as_ref() };
+        match h.is_live() {
+            true => unsafe { (h.vtable.heap_size)(header) },
+            false => 0,
+        }
+    }
+
+    /// Drops the value in place while keeping the allocation, for objects still referenced weakly.
+    ///
+    /// # Safety
+    ///
+    /// No strong reference to the object may be used afterwards.
+    pub(crate) unsafe fn drop_value(header: NonNull<GcHeader>) {
+        let h = header.as_ref();
+        if h.is_live() {
+            h.flags.set(h.flags.get() & !LIVE);
+            (h.vtable.drop_value)(header);
+        }
+    }
+
+    /// Drops the value if it is still live and frees the allocation.
+    ///
+    /// # Safety
+    ///
+    /// The object must be unreachable, both strongly and weakly.
+    pub(crate) unsafe fn free(header: NonNull<GcHeader>) {
+        Self::drop_value(header);
+        (header.as_ref().vtable.dealloc)(header);
+    }
+}
+
+#[repr(C)]
+pub(crate) struct GcBox<T> {
+    header: GcHeader,
+    value: ManuallyDrop<T>,
+}
+
+impl<T: Managed> GcBox<T> {
+    const VTABLE: VTable = VTable {
+        size: std::mem::size_of::<GcBox<T>>(),
+        trace: Self::trace_value,
+        clear_weak: Self::clear_weak,
+        drop_value: Self::drop_value,
+        heap_size: Self::heap_size,
+        dealloc: Self::dealloc,
+    };
+
+    unsafe fn trace_value(header: NonNull<GcHeader>, tracer: &mut Tracer) {
+        header.cast::<GcBox<T>>().as_ref().value.trace(tracer);
+    }
+
+    unsafe fn clear_weak(header: NonNull<GcHeader>, tracer: &Tracer, before_finalization: bool) {
+        (*header.cast::<GcBox<T>>().as_ptr())
+            .value
+            .clear_weak(tracer, before_finalization);
+    }
+
+    unsafe fn drop_value(header: NonNull<GcHeader>) {
+        ManuallyDrop::drop(&mut (*header.cast::<GcBox<T>>().as_ptr()).value);
+    }
+
+    unsafe fn heap_size(header: NonNull<GcHeader>) -> usize {
+        header.cast::<GcBox<T>>().as_ref().value.heap_size()
+    }
+
+    unsafe fn dealloc(header: NonNull<GcHeader>) {
+        drop(Box::from_raw(header.cast::<GcBox<T>>().as_ptr()));
+    }
+}
+
+/// A copyable pointer to a value in the managed heap, valid for the `'gc` branded lifetime of a mutation.
+pub struct Gc<'gc, T: 'gc> {
+    ptr: NonNull<GcBox<T>>,
+    _marker: PhantomData<(&'gc T, Invariant<'gc>)>,
+}
+
+impl<'gc, T: 'gc> Copy for Gc<'gc, T> {}
+
+impl<'gc, T: 'gc> Clone for Gc<'gc, T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'gc, T: Managed + 'gc> Gc<'gc, T> {
+    /// Moves `value` into the managed heap.
+    pub fn new(mc: &Mutation<'gc>, value: T) -> Gc<'gc, T> {
+        let mut flags = LIVE | mc.collector().allocation_white() as u8;
+        if T::needs_trace() {
+            flags |= NEEDS_TRACE;
+        }
+        let size = GcBox::<T>::VTABLE.size + value.heap_size();
+        let boxed = Box::new(GcBox {
+            header: GcHeader {
+                next: Cell::new(None),
+                flags: Cell::new(flags),
+                serial: mc.collector().next_serial(),
+                vtable: &GcBox::<T>::VTABLE,
+            },
+            value: ManuallyDrop::new(value),
+        });
+        let ptr = unsafe { NonNull::new_unchecked(Box::into_raw(boxed)) };
+        mc.collector().link(ptr.cast(), size);
+        Gc {
+            ptr,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'gc, T: 'gc> Gc<'gc, T> {
+    /// Returns a reference to the value that lives for the whole mutation.
+    #[inline]
+    #[allow(clippy::should_implement_trait)]
+    pub fn as_ref(self) -> &'gc T {
+        unsafe { &(*self.ptr.as_ptr()).value }
+    }
+
+    /// Returns true if both pointers refer to the same allocation.
+    #[inline]
+    pub fn ptr_eq(this: Gc<'gc, T>, other: Gc<'gc, T>) -> bool {
+        this.ptr == other.ptr
+    }
+
+    /// Returns the address of the value, usable as an identity for hashing or debug output.
+    #[inline]
+    pub fn as_ptr(this: Gc<'gc, T>) -> *const T {
+        unsafe { std::ptr::addr_of!((*this.ptr.as_ptr()).value).cast() }
+    }
+
+    /// The order the value was allocated in, among the values of its arena: an identity that, unlike
+    /// the address, comes out the same every time a program makes the same allocations. It wraps
+    /// around after 2³² allocations.
+    #[inline]
+    pub(crate) fn serial(this: Gc<'gc, T>) -> u32 {
+        unsafe { this.header().as_ref() }.serial
+    }
+
+    /// Creates a weak pointer to the same allocation.
+    #[inline]
+    pub fn downgrade(this: Gc<'gc, T>) -> GcWeak<'gc, T> {
+        GcWeak { inner: this }
+    }
+
+    #[inline]
+    pub(crate) fn header(self) -> NonNull<GcHeader> {
+        self.ptr.cast()
+    }
+}
+
+impl<'gc, T: 'gc> Deref for Gc<'gc, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        self.as_ref()
+    }
+}
+
+impl<'gc, T: fmt::Debug + 'gc> fmt::Debug for Gc<'gc, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_ref(), f)
+    }
+}
+
+unsafe impl<'gc, T: 'gc> Managed for Gc<'gc, T> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.trace_header(self.header());
+    }
+}
+
+/// A pointer that does not keep its target alive.
+///
+/// The allocation of the target is kept around for as long as the weak pointer is reachable, so upgrading is
+/// always memory safe; it simply fails once the target has been collected.
+pub struct GcWeak<'gc, T: 'gc> {
+    inner: Gc<'gc, T>,
+}
+
+impl<'gc, T: 'gc> Copy for GcWeak<'gc, T> {}
+
+impl<'gc, T: 'gc> Clone for GcWeak<'gc, T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'gc, T: 'gc> GcWeak<'gc, T> {
+    /// Returns a strong pointer if the target has not been collected.
+    #[inline]
+    pub fn upgrade(self, mc: &Mutation<'gc>) -> Option<Gc<'gc, T>> {
+        if mc.collector().is_dead(self.inner.header()) {
+            None
+        } else {
+            Some(self.inner)
+        }
+    }
+
+    /// Returns true if the target has been (or is about to be) collected.
+    #[inline]
+    pub fn is_dropped(self, mc: &Mutation<'gc>) -> bool {
+        mc.collector().is_dead(self.inner.header())
+    }
+
+    #[inline]
+    pub(crate) fn header(self) -> NonNull<GcHeader> {
+        self.inner.header()
+    }
+
+    #[inline]
+    pub fn ptr_eq(this: GcWeak<'gc, T>, other: GcWeak<'gc, T>) -> bool {
+        Gc::ptr_eq(this.inner, other.inner)
+    }
+
+    /// Returns the address of the target, which stays valid as an identity even after collection.
+    #[inline]
+    pub fn as_ptr(this: GcWeak<'gc, T>) -> *const T {
+        unsafe { std::ptr::addr_of!((*this.inner.ptr.as_ptr()).value).cast() }
+    }
+}
+
+impl<'gc, T: 'gc> fmt::Debug for GcWeak<'gc, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GcWeak({:p})", self.inner.ptr)
+    }
+}
+
+unsafe impl<'gc, T: 'gc> Managed for GcWeak<'gc, T> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.trace_weak_header(self.inner.header());
+    }
+}
@@ -0,0 +1,156 @@
+use std::cell::{BorrowError, BorrowMutError, Cell, Ref, RefCell, RefMut};
+use std::fmt;
+
+use super::{Gc, Managed, Mutation, Tracer};
+
+/// A `Cell` for managed values: writing through a `Gc<Lock<T>>` requires a [`Mutation`] so the write barrier
+/// can be applied.
+#[derive(Default)]
+pub struct Lock<T: Copy>(Cell<T>);
+
+impl<T: Copy> Lock<T> {
+    #[inline]
+    pub fn new(value: T) -> Lock<T> {
+        Lock(Cell::new(value))
+    }
+
+    #[inline]
+    pub fn get(&self) -> T {
+        self.0.get()
+    }
+
+    /// Sets the value of a lock that is not yet (or no longer) reachable from the managed heap.
+    #[inline]
+    pub fn set_unbarriered(&mut self, value: T) {
+        self.0.set(value)
+    }
+
+    /// Sets the value of a lock that is a field of the object `owner`, applying the write barrier
+    /// to the owner.
+    #[inline]
+    pub(crate) fn set_in<'gc, O: 'gc>(&self, mc: &Mutation<'gc>, owner: Gc<'gc, O>, value: T) {
+        mc.backward_barrier(owner.header());
+        self.0.set(value);
+    }
+}
+
+impl<'gc, T: Copy + 'gc> Gc<'gc, Lock<T>> {
+    #[inline]
+    pub fn get(self) -> T {
+        self.as_ref().0.get()
+    }
+
+    #[inline]
+    pub fn set(self, mc: &Mutation<'gc>, value: T) {
+        mc.backward_barrier(self.header());
+        self.as_ref().0.set(value);
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for Lock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Lock").field(&self.0.get()).finish()
+    }
+}
+
+unsafe impl<T: Copy + Managed> Managed for Lock<T> {
+    #[inline]
+    fn needs_trace() -> bool {
+        T::needs_trace()
+    }
+
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.get().trace(tracer)
+    }
+}
+
+/// A `RefCell` for managed values: mutable borrows through a `Gc<RefLock<T>>` require a [`Mutation`] so the
+/// write barrier can be applied.
+#[derive(Default)]
+pub struct RefLock<T>(RefCell<T>);
+
+impl<T> RefLock<T> {
+    #[inline]
+    pub fn new(value: T) -> RefLock<T> {
+        RefLock(RefCell::new(value))
+    }
+
+    #[inline]
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.0.borrow()
+    }
+
+    #[inline]
+    pub fn try_borrow(&self) -> Result<Ref<'_, T>, BorrowError> {
+        self.0.try_borrow()
+    }
+
+    /// Mutably borrows a lock that is not yet (or no longer) reachable from the managed heap.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut()
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+}
+
+impl<'gc, T: 'gc> Gc<'gc, RefLock<T>> {
+    #[inline]
+    pub fn borrow(self) -> Ref<'gc, T> {
+        self.as_ref().0.borrow()
+    }
+
+    #[inline]
+    pub fn try_borrow(self) -> Result<Ref<'gc, T>, BorrowError> {
+        self.as_ref().0.try_borrow()
+    }
+
+    #[inline]
+    pub fn borrow_mut(self, mc: &Mutation<'gc>) -> RefMut<'gc, T> {
+        mc.backward_barrier(self.header());
+        self.as_ref().0.borrow_mut()
+    }
+
+    #[inline]
+    pub fn try_borrow_mut(self, mc: &Mutation<'gc>) -> Result<RefMut<'gc, T>, BorrowMutError> {
+        mc.backward_barrier(self.header());
+        self.as_ref().0.try_borrow_mut()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RefLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.try_borrow() {
+            Ok(value) => f.debug_tuple("RefLock").field(&*value).finish(),
+            Err(_) => f.write_str("RefLock(<borrowed>)"),
+        }
+    }
+}
+
+unsafe impl<T: Managed> Managed for RefLock<T> {
+    #[inline]
+    fn needs_trace() -> bool {
+        T::needs_trace()
+    }
+
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        // Tracing only ever happens between mutations, so no mutable borrow can be active here.
+        unsafe { (*self.0.as_ptr()).trace(tracer) }
+    }
+
+    #[inline]
+    fn clear_weak(&mut self, tracer: &Tracer, before_finalization: bool) {
+        self.0.get_mut().clear_weak(tracer, before_finalization)
+    }
+
+    #[inline]
+    fn heap_size(&self) -> usize {
+        // Asked for as the value is allocated and freed, when nothing can be borrowing it.
+        unsafe { (*self.0.as_ptr()).heap_size() }
+    }
+}
@@ -0,0 +1,250 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+#[cfg(not(feature = "send"))]
+use std::rc::Rc;
+
+use super::Tracer;
+use crate::MaybeSend;
+
+/// A type whose values can live in the managed heap, or be reachable from it.
+///
+/// The collector discovers the object graph exclusively through `trace`, so every `Gc` pointer a value owns
+/// (directly, or through ordinary Rust containers) must be reported there.
+///
+/// # Safety
+///
+/// - `trace` must report every `Gc` pointer held by `self`. A pointer that is not reported may be freed while
+///   it is still reachable.
+/// - `needs_trace` may only return `false` if values of the type can never hold a `Gc` pointer.
+/// - Types holding `Gc` pointers must not allow them to be changed through a shared reference except through
+///   [`Lock`](super::Lock) or [`RefLock`](super::RefLock), which apply the write barrier.
+/// - A `Drop` implementation on a managed type must never dereference a `Gc` pointer, since the object it
+///   points to may already have been freed by the time the destructor runs.
+/// - If `heap_size` can change while the value is in the heap, every change must be reported through
+///   [`Metrics`](super::Metrics), or the arena's count of its memory drifts.
+/// - With the `send` feature, a [`Lua`](crate::Lua) moves between threads with everything in its
+///   heap, so a managed type must not share anything with the thread it was made on, such as an
+///   `Rc` also held outside of the heap.
+pub unsafe trait Managed {
+    /// Returns `false` if values of this type never hold managed pointers, letting the collector skip them.
+    #[inline]
+    fn needs_trace() -> bool
+    where
+        Self: Sized,
+    {
+        true
+    }
+
+    /// Reports every managed pointer held by `self` to the tracer.
+    #[inline]
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    /// Drops the pointers to objects that marking didn't reach, for values that left some pointers
+    /// untraced and called [`Tracer::register_weak`] from `trace`.
+    ///
+    /// Called once marking is done and again after [`Rootable::finalize`](super::Rootable::finalize)
+    /// had the chance to resurrect objects, with `before_finalization` telling the two apart.
+    /// Nothing reported here may be freed yet, but every pointer not marked by the second call will be.
+    #[inline]
+    fn clear_weak(&mut self, _tracer: &Tracer, _before_finalization: bool) {}
+
+    /// The bytes the value owns outside of its own allocation, such as the contents of a box, which
+    /// count towards the arena's memory use. Only the value's own buffers are counted, not those of
+    /// what they hold in turn.
+    #[inline]
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+macro_rules! static_managed {
+    ($($ty:ty),* $(,)?) => {$(
+        unsafe impl Managed for $ty {
+            #[inline]
+            fn needs_trace() -> bool {
+                false
+            }
+        }
+    )*};
+}
+
+static_managed!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+);
+
+unsafe impl Managed for String {
+    #[inline]
+    fn needs_trace() -> bool {
+        false
+    }
+
+    #[inline]
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+unsafe impl Managed for str {}
+
+unsafe impl<T: ?Sized> Managed for PhantomData<T> {
+    #[inline]
+    fn needs_trace() -> bool {
+        false
+    }
+}
+
+unsafe impl<T: MaybeSend + 'static> Managed for Cell<T> {
+    #[inline]
+    fn needs_trace() -> bool {
+        false
+    }
+}
+
+unsafe impl<T: MaybeSend + 'static> Managed for RefCell<T> {
+    #[inline]
+    fn needs_trace() -> bool {
+        false
+    }
+}
+
+#[cfg(not(feature = "send"))]
+unsafe impl<T: ?Sized + 'static> Managed for Rc<T> {
+    #[inline]
+    fn needs_trace() -> bool {
+        false
+    }
+}
+
+unsafe impl<T: Managed> Managed for [T] {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        if T::needs_trace() {
+            for value in self {
+                value.trace(tracer);
+            }
+        }
+    }
+}
+
+unsafe impl<T: Managed, const N: usize> Managed for [T; N] {
+    #[inline]
+    fn needs_trace() -> bool {
+        T::needs_trace()
+    }
+
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        self.as_slice().trace(tracer)
+    }
+}
+
+unsafe impl<T: ?Sized + Managed> Managed for Box<T> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        (**self).trace(tracer)
+    }
+
+    #[inline]
+    fn heap_size(&self) -> usize {
+        std::mem::size_of_val(&**self)
+    }
+}
+
+unsafe impl<T: Managed> Managed for Vec<T> {
+    #[inline]
+    fn needs_trace() -> bool {
+        T::needs_trace()
+    }
+
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        self.as_slice().trace(tracer)
+    }
+}
+
+unsafe impl<T: Managed> Managed for VecDeque<T> {
+    #[inline]
+    fn needs_trace() -> bool {
+        T::needs_trace()
+    }
+
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        if T::needs_trace() {
+            for value in self {
+                value.trace(tracer);
+            }
+        }
+    }
+}
+
+unsafe impl<T: Managed> Managed for Option<T> {
+    #[inline]
+    fn needs_trace() -> bool {
+        T::needs_trace()
+    }
+
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(value) = self {
+            value.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<T: Managed, E: Managed> Managed for Result<T, E> {
+    #[inline]
+    fn needs_trace() -> bool {
+        T::needs_trace() || E::needs_trace()
+    }
+
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Ok(value) => value.trace(tracer),
+            Err(err) => err.trace(tracer),
+        }
+    }
+}
+
+macro_rules! tuple_managed {
+    ($($name:ident),+) => {
+        unsafe impl<$($name: Managed),+> Managed for ($($name,)+) {
+            #[inline]
+            fn needs_trace() -> bool {
+                false $(|| $name::needs_trace())+
+            }
+
+            #[inline]
+            #[allow(non_snake_case)]
+            fn trace(&self, tracer: &mut Tracer) {
+                let ($($name,)+) = self;
+                $($name.trace(tracer);)+
+            }
+        }
+    };
+}
+
+tuple_managed!(A);
+tuple_managed!(A, B);
+tuple_managed!(A, B, C);
+tuple_managed!(A, B, C, D);
+tuple_managed!(A, B, C, D, E);
+tuple_managed!(A, B, C, D, E, F);
@@ -0,0 +1,208 @@
+//! The managed heap: an incremental, tri-color mark and sweep garbage collector.
+//!
+//! Every object the interpreter creates lives in an [`Arena`]. Access to managed objects is branded with a
+//! `'gc` lifetime that only exists inside [`Arena::mutate`], and collection only ever runs between mutations,
+//! so the collector never has to scan the Rust stack: everything alive must be reachable from the arena root.
+
+mod arena;
+mod gc;
+mod lock;
+mod managed;
+mod weak_set;
+
+pub use self::arena::{Arena, Finalization, Metrics, Mutation, Pacing, Root, Rootable, Tracer};
+pub use self::gc::{Gc, GcWeak};
+pub use self::lock::{Lock, RefLock};
+pub use self::managed::Managed;
+pub use self::weak_set::WeakSet;
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    struct Node<'gc> {
+        next: Option<Gc<'gc, RefLock<Node<'gc>>>>,
+        drops: Rc<Cell<u32>>,
+    }
+
+    impl Drop for Node<'_> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    unsafe impl<'gc> Managed for Node<'gc> {
+        fn trace(&self, tracer: &mut Tracer) {
+            self.next.trace(tracer);
+        }
+    }
+
+    struct Roots;
+
+    impl<'a> Rootable<'a> for Roots {
+        type Root = RefLock<Vec<Gc<'a, RefLock<Node<'a>>>>>;
+    }
+
+    fn node<'gc>(mc: &Mutation<'gc>, drops: &Rc<Cell<u32>>) -> Gc<'gc, RefLock<Node<'gc>>> {
+        Gc::new(
+            mc,
+            RefLock::new(Node {
+                next: None,
+                drops: drops.clone(),
+            }),
+        )
+    }
+
+    #[test]
+    fn collects_unreachable_cycles() {
+        let drops = Rc::new(Cell::new(0));
+        let mut arena = Arena::<Roots>::new(|_| RefLock::default());
+        arena.mutate_root(|mc, root| {
+            let a = node(mc, &drops);
+            let b = node(mc, &drops);
+            a.borrow_mut(mc).next = Some(b);
+            b.borrow_mut(mc).next = Some(a);
+            let kept = node(mc, &drops);
+            root.get_mut().push(kept);
+        });
+        arena.collect_all();
+        assert_eq!(drops.get(), 2);
+        arena.mutate(|_, root| assert_eq!(root.borrow().len(), 1));
+        drop(arena);
+        assert_eq!(drops.get(), 3);
+    }
+
+    #[test]
+    fn barrier_keeps_objects_stored_mid_cycle() {
+        let drops = Rc::new(Cell::new(0));
+        let mut arena =
+            Arena::<Roots>::new(|mc| RefLock::new(vec![node(mc, &Rc::new(Cell::new(0)))]));
+        // Start a cycle and blacken the rooted node before storing a fresh object in it.
+        arena.step(0);
+        arena.step(1);
+        arena.mutate(|mc, root| {
+            let fresh = node(mc, &drops);
+            root.borrow()[0].borrow_mut(mc).next = Some(fresh);
+        });
+        arena.collect_all();
+        assert_eq!(drops.get(), 0);
+        arena.mutate(|_, root| assert!(root.borrow()[0].borrow().next.is_some()));
+    }
+
+    #[test]
+    fn finalization_resurrects_unreachable_objects() {
+        type NodeRef<'gc> = Gc<'gc, RefLock<Node<'gc>>>;
+
+        #[derive(Default)]
+        struct Queue<'gc> {
+            watched: Vec<GcWeak<'gc, RefLock<Node<'gc>>>>,
+            resurrected: Vec<NodeRef<'gc>>,
+        }
+
+        unsafe impl<'gc> Managed for Queue<'gc> {
+            fn trace(&self, tracer: &mut Tracer) {
+                self.watched.trace(tracer);
+                self.resurrected.trace(tracer);
+            }
+        }
+
+        struct QueueRoot;
+
+        impl<'a> Rootable<'a> for QueueRoot {
+            type Root = Gc<'a, RefLock<Queue<'a>>>;
+
+            fn finalize(fc: &Finalization<'a>, root: &'a Self::Root) {
+                let mut queue = root.borrow_mut(fc);
+                let (dead, alive) = std::mem::take(&mut queue.watched)
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|&w| fc.is_dead(w));
+                queue.watched = alive;
+                queue
+                    .resurrected
+                    .extend(dead.into_iter().map(|w| fc.resurrect(w)));
+            }
+        }
+
+        let drops = Rc::new(Cell::new(0));
+        let mut arena = Arena::<QueueRoot>::new(|mc| Gc::new(mc, RefLock::default()));
+        arena.mutate(|mc, root| {
+            // The watched node keeps the one after it alive once it is resurrected.
+            let a = node(mc, &drops);
+            a.borrow_mut(mc).next = Some(node(mc, &drops));
+            let kept = node(mc, &drops);
+            let mut queue = root.borrow_mut(mc);
+            queue.watched.push(Gc::downgrade(a));
+            queue.watched.push(Gc::downgrade(kept));
+            queue.resurrected.push(kept);
+        });
+        arena.collect_all();
+        assert_eq!(drops.get(), 0);
+        arena.mutate(|mc, root| {
+            let mut queue = root.borrow_mut(mc);
+            assert_eq!(queue.watched.len(), 1);
+            assert_eq!(queue.resurrected.len(), 2);
+            assert!(queue.resurrected[1].borrow().next.is_some());
+            queue.resurrected.clear();
+        });
+        arena.collect_all();
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn counts_owned_buffers() {
+        let mut arena = Arena::<Roots>::new(|_| RefLock::default());
+        let before = arena.metrics().total_allocation();
+        arena.mutate(|mc, _| {
+            Gc::new(mc, vec![0u8; 1000].into_boxed_slice());
+        });
+        assert!(arena.metrics().total_allocation() >= before + 1000);
+        arena.collect_all();
+        assert_eq!(arena.metrics().total_allocation(), before);
+    }
+
+    #[test]
+    fn weak_sets_drop_collected_objects() {
+        struct SetRoot;
+
+        impl<'a> Rootable<'a> for SetRoot {
+            type Root = (Gc<'a, RefLock<WeakSet<'a, u32>>>, RefLock<Vec<Gc<'a, u32>>>);
+        }
+
+        let mut arena =
+            Arena::<SetRoot>::new(|mc| (Gc::new(mc, RefLock::default()), RefLock::default()));
+        arena.mutate_root(|mc, (set, kept)| {
+            for n in 0..20 {
+                let object = Gc::new(mc, n);
+                set.borrow_mut(mc).insert(mc, n as u64 % 3, object);
+                if n % 2 == 0 {
+                    kept.get_mut().push(object);
+                }
+            }
+        });
+        arena.collect_all();
+        arena.mutate(|_, (set, _)| {
+            let set = set.borrow();
+            assert_eq!(set.len(), 10);
+            assert_eq!(set.get(1, |&n| n == 4).map(|n| *n), Some(4));
+            assert!(set.get(2, |&n| n == 5).is_none());
+        });
+    }
+
+    #[test]
+    fn weak_pointers_fail_after_collection() {
+        struct WeakRoot;
+
+        impl<'a> Rootable<'a> for WeakRoot {
+            type Root = RefLock<Option<GcWeak<'a, u32>>>;
+        }
+
+        let mut arena =
+            Arena::<WeakRoot>::new(|mc| RefLock::new(Some(Gc::downgrade(Gc::new(mc, 7u32)))));
+        arena.mutate(|mc, root| assert!(root.borrow().unwrap().upgrade(mc).is_some()));
+        arena.collect_all();
+        arena.mutate(|mc, root| assert!(root.borrow().unwrap().upgrade(mc).is_none()));
+    }
+}
@@ -0,0 +1,123 @@
+use std::mem;
+
+use super::{Gc, Managed, Mutation, Tracer};
+
+type Slot<'gc, T> = Option<(u64, Gc<'gc, T>)>;
+
+/// A set of managed objects that doesn't keep them alive: once a collection finds nothing else
+/// reaching an object, it leaves the set before it is freed.
+///
+/// Objects are found by a hash and an equality test the owner supplies, which suits intern tables
+/// and other caches that hand out one object per key for as long as it is in use anywhere. Only a
+/// set in an allocation of its own lets go of its objects; the arena root keeps them alive.
+pub struct WeakSet<'gc, T: 'gc> {
+    /// Open addressing with linear probing: a power of two in length, at most half full.
+    slots: Box<[Slot<'gc, T>]>,
+    len: usize,
+}
+
+impl<'gc, T: 'gc> Default for WeakSet<'gc, T> {
+    fn default() -> WeakSet<'gc, T> {
+        WeakSet::new()
+    }
+}
+
+impl<'gc, T: 'gc> WeakSet<'gc, T> {
+    const MIN_SLOTS: usize = 8;
+
+    pub fn new() -> WeakSet<'gc, T> {
+        WeakSet {
+            slots: Box::new([]),
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the object added under `hash` that `eq` accepts, if there is one.
+    pub fn get(&self, hash: u64, mut eq: impl FnMut(&T) -> bool) -> Option<Gc<'gc, T>> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let mask = self.slots.len() - 1;
+        let mut i = hash as usize & mask;
+        while let Some((h, object)) = self.slots[i] {
+            if h == hash && eq(&object) {
+                return Some(object);
+            }
+            i = (i + 1) & mask;
+        }
+        None
+    }
+
+    /// Adds `object` under `hash`, without looking for one that is equal to it.
+    pub fn insert(&mut self, mc: &Mutation<'gc>, hash: u64, object: Gc<'gc, T>) {
+        if (self.len + 1) * 2 > self.slots.len() {
+            let slots = (self.slots.len() * 2).max(Self::MIN_SLOTS);
+            let grown = (slots - self.slots.len()) * mem::size_of::<Slot<'gc, T>>();
+            mc.metrics().mark_external_allocation(grown);
+            self.rebuild(slots);
+        }
+        self.place(hash, object);
+        self.len += 1;
+    }
+
+    fn place(&mut self, hash: u64, object: Gc<'gc, T>) {
+        let mask = self.slots.len() - 1;
+        let mut i = hash as usize & mask;
+        while self.slots[i].is_some() {
+            i = (i + 1) & mask;
+        }
+        self.slots[i] = Some((hash, object));
+    }
+
+    /// Places the objects again in `slots` new slots.
+    fn rebuild(&mut self, slots: usize) {
+        let old = mem::replace(&mut self.slots, (0..slots).map(|_| None).collect());
+        for (hash, object) in old.into_vec().into_iter().flatten() {
+            self.place(hash, object);
+        }
+    }
+}
+
+unsafe impl<'gc, T: 'gc> Managed for WeakSet<'gc, T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if !tracer.register_weak() {
+            for (_, object) in self.slots.iter().flatten() {
+                object.trace(tracer);
+            }
+        }
+    }
+
+    fn clear_weak(&mut self, tracer: &Tracer, _before_finalization: bool) {
+        // Dropping an object a finalizer might resurrect is harmless: it is no longer handed out,
+        // and a new one is made in its place.
+        let live = self
+            .slots
+            .iter()
+            .flatten()
+            .filter(|&&(_, object)| tracer.is_marked(object))
+            .count();
+        if live < self.len {
+            for slot in self.slots.iter_mut() {
+                if slot.is_some_and(|(_, object)| !tracer.is_marked(object)) {
+                    *slot = None;
+                }
+            }
+            self.len = live;
+            self.rebuild(self.slots.len());
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        self.slots.len() * mem::size_of::<Slot<'gc, T>>()
+    }
+}
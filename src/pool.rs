@@ -0,0 +1,283 @@
+//! Running many scripts in parallel, each pool thread with a state of its own.
+//!
+//! States never leave the thread that made them, so a pool works with or without the `send`
+//! feature. Jobs are taken in the order they were submitted by whichever thread is free, and each
+//! runs in a state of its own, made for it on that thread, with the fuel and memory the pool grants
+//! each of them.
+
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use crate::{Context, Error, FromLuaMulti, IntoLuaMulti, Lua, LuaError, RuntimeError};
+
+/// A submitted job, which runs in a state made for it.
+type Job = Box<dyn FnOnce(&mut Lua) + Send>;
+
+type Setup = dyn Fn() -> Lua + Send + Sync;
+
+/// Configures a [`VmPool`].
+pub struct VmPoolBuilder {
+    threads: usize,
+    fuel: Option<u64>,
+    memory_limit: Option<usize>,
+    setup: Arc<Setup>,
+}
+
+impl VmPoolBuilder {
+    /// As many threads as the machine runs in parallel, each with a state from [`Lua::new`], and
+    /// no limits on the jobs.
+    pub fn new() -> VmPoolBuilder {
+        VmPoolBuilder {
+            threads: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            fuel: None,
+            memory_limit: None,
+            setup: Arc::new(Lua::new),
+        }
+    }
+
+    /// How many threads, and so jobs running at once, the pool has. At least one.
+    pub fn threads(mut self, threads: usize) -> VmPoolBuilder {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// The [fuel](crate::Context::set_fuel) each job starts with.
+    pub fn fuel(mut self, fuel: u64) -> VmPoolBuilder {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// The [memory limit](crate::Context::set_memory_limit) of each job's state.
+    pub fn memory_limit(mut self, bytes: usize) -> VmPoolBuilder {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Makes the states, one for each job, on the thread that runs it.
+    pub fn setup(mut self, setup: impl Fn() -> Lua + Send + Sync + 'static) -> VmPoolBuilder {
+        self.setup = Arc::new(setup);
+        self
+    }
+
+    /// Starts the threads.
+    pub fn build(self) -> VmPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..self.threads)
+            .map(|i| {
+                let worker = Worker {
+                    jobs: receiver.clone(),
+                    setup: self.setup.clone(),
+                    memory_limit: self.memory_limit,
+                };
+                thread::Builder::new()
+                    .name(format!("tei-pool-{i}"))
+                    .spawn(move || worker.run())
+                    .expect("failed to spawn a pool thread")
+            })
+            .collect();
+        VmPool {
+            sender: Some(sender),
+            workers,
+            fuel: self.fuel,
+        }
+    }
+}
+
+impl Default for VmPoolBuilder {
+    fn default() -> VmPoolBuilder {
+        VmPoolBuilder::new()
+    }
+}
+
+/// Threads that run chunks submitted from any thread.
+///
+/// Each job gets a fresh state from the pool's [setup](VmPoolBuilder::setup), which is dropped
+/// once it finishes, so no globals or registry values are left for the jobs after it.
+///
+/// Dropping the pool waits for the jobs already submitted to finish.
+pub struct VmPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    fuel: Option<u64>,
+}
+
+impl VmPool {
+    /// A pool built by [`VmPoolBuilder::new`].
+    pub fn new() -> VmPool {
+        VmPoolBuilder::new().build()
+    }
+
+    /// How many threads the pool has.
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Submits `chunk`, to be loaded and called with `args` on the next free thread, and converts
+    /// what it returns to an `R`.
+    pub fn spawn<A, R>(&self, chunk: impl Into<String>, args: A) -> JoinHandle<R>
+    where
+        A: for<'gc> IntoLuaMulti<'gc> + Send + 'static,
+        R: for<'gc> FromLuaMulti<'gc> + Send + 'static,
+    {
+        let chunk = chunk.into();
+        let fuel = self.fuel;
+        let (sender, receiver) = mpsc::channel();
+        let job: Job = Box::new(move |lua| {
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                lua.enter(|ctx| {
+                    ctx.set_fuel(fuel);
+                    call_chunk(ctx, &chunk, args).map_err(LuaError::into_detached)
+                })
+            }));
+            // The handle may have been dropped, which leaves no one to tell.
+            let _ = sender.send(outcome);
+        });
+        self.sender
+            .as_ref()
+            .expect("the sender is only taken on drop")
+            .send(job)
+            .expect("the pool threads outlive the pool");
+        JoinHandle { receiver }
+    }
+}
+
+impl Default for VmPool {
+    fn default() -> VmPool {
+        VmPool::new()
+    }
+}
+
+impl Drop for VmPool {
+    fn drop(&mut self) {
+        // The threads stop once the channel is closed and empty.
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The outcome of a job submitted to a [`VmPool`].
+pub struct JoinHandle<R> {
+    receiver: Receiver<thread::Result<Result<R, Error>>>,
+}
+
+impl<R> JoinHandle<R> {
+    /// Waits for the job to finish. A Lua error comes back with any error value other than a
+    /// message turned into its message, as the value stays in the pool's state. A panic in the job
+    /// is resumed here.
+    pub fn join(self) -> Result<R, Error> {
+        match self.receiver.recv() {
+            Ok(Ok(result)) => result,
+            Ok(Err(panic)) => panic::resume_unwind(panic),
+            Err(_) => {
+                Err(LuaError::from(RuntimeError::new("the pool thread stopped")).into_detached())
+            }
+        }
+    }
+}
+
+fn call_chunk<'gc, A, R>(ctx: Context<'gc>, chunk: &str, args: A) -> Result<R, LuaError<'gc>>
+where
+    A: IntoLuaMulti<'gc>,
+    R: FromLuaMulti<'gc>,
+{
+    ctx.load("=pool", chunk)?.call(ctx, args)
+}
+
+struct Worker {
+    jobs: Arc<Mutex<Receiver<Job>>>,
+    setup: Arc<Setup>,
+    memory_limit: Option<usize>,
+}
+
+impl Worker {
+    fn run(self) {
+        loop {
+            let job = self
+                .jobs
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .recv();
+            let Ok(job) = job else {
+                return;
+            };
+            job(&mut self.new_lua());
+        }
+    }
+
+    fn new_lua(&self) -> Lua {
+        let mut lua = (self.setup)();
+        lua.enter(|ctx| ctx.set_memory_limit(self.memory_limit));
+        lua
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OutOfFuel;
+
+    #[test]
+    fn runs_jobs() {
+        let pool = VmPoolBuilder::new()
+            .threads(3)
+            .fuel(100_000)
+            .memory_limit(1 << 20)
+            .build();
+        assert_eq!(pool.threads(), 3);
+        let sums: Vec<JoinHandle<i64>> = (0..20)
+            .map(|n| {
+                let chunk = "local n = ... local s = 0 for i = 1, n do s = s + i end return s";
+                pool.spawn(chunk, n)
+            })
+            .collect();
+        let sums: Vec<i64> = sums.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(sums, (0..20).map(|n| n * (n + 1) / 2).collect::<Vec<_>>());
+
+        let err = pool
+            .spawn::<_, ()>("while true do end", ())
+            .join()
+            .unwrap_err();
+        assert!(err.downcast_ref::<OutOfFuel>().is_some());
+        let err = pool
+            .spawn::<_, ()>("local s = ('x'):rep(1 << 22) return #s", ())
+            .join()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "not enough memory");
+        let err = pool
+            .spawn::<_, ()>("error({code = 1})", ())
+            .join()
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("(error object is a table value"));
+        let (a, b): (String, bool) = pool.spawn("return ..., true", "x").join().unwrap();
+        assert_eq!((a.as_str(), b), ("x", true));
+        let err = pool.spawn::<_, ()>("error('boom')", ()).join().unwrap_err();
+        assert_eq!(err.to_string(), "pool:1: boom");
+    }
+
+    #[test]
+    fn isolates_jobs() {
+        let pool = VmPoolBuilder::new()
+            .threads(1)
+            .setup(Lua::with_debug)
+            .build();
+        for _ in 0..3 {
+            let seen: (bool, bool) = pool
+                .spawn(
+                    "local g, r = leaked ~= nil, debug.getregistry().leaked ~= nil \
+                     leaked = true debug.getregistry().leaked = true return g, r",
+                    (),
+                )
+                .join()
+                .unwrap();
+            assert_eq!(seen, (false, false));
+        }
+    }
+}
@@ -0,0 +1,506 @@
+//! Measuring where scripts spend their time, through the state's hook.
+//!
+//! A [`Profiler`] is told of every call, return and, if asked, new line in every thread. It keeps
+//! the Lua functions each thread is in, and charges the time between two events to the innermost
+//! one. Native functions have no frames of their own, so all of them are counted together, under
+//! the Lua function that called them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::vm::{Address, Hook, HookMask, ReplacedHook};
+use crate::{Context, Function, NativeReturn, Thread, Value};
+
+/// Instructions between the count events that let the profiler notice functions an error has
+/// unwound, which return no other way.
+const HEARTBEAT: u32 = 1000;
+
+/// The call tree node above the outermost functions.
+const ROOT: usize = usize::MAX;
+
+/// The source all native functions are counted under.
+const NATIVE_SOURCE: &str = "[native]";
+
+/// Records how long the functions of a state run and how often its lines do, from when it is
+/// started until it is stopped.
+///
+/// Time spent in the hook itself is charged to the functions it interrupts, so the figures are
+/// only meaningful next to each other. A coroutine's functions are still running, as far as their
+/// inclusive time goes, while it is suspended.
+pub struct Profiler {
+    recorder: Arc<Mutex<Recorder>>,
+    /// The hook the profiler replaced, put back when it stops.
+    replaced: ReplacedHook,
+}
+
+impl Profiler {
+    /// Starts profiling every thread through [`Context::set_hook`], replacing the hook set there
+    /// until the profiler is stopped. With `count_lines`, it also counts how many times each line
+    /// starts running, which slows scripts down further.
+    ///
+    /// A thread's own hook, as `debug.sethook` sets, keeps the profiler from seeing it.
+    pub fn start(ctx: Context<'_>, count_lines: bool) -> Profiler {
+        let recorder = Arc::new(Mutex::new(Recorder::new()));
+        let hook = {
+            let recorder = Arc::clone(&recorder);
+            Function::from_fn(&ctx, move |_, stack| {
+                let line = match stack.get(1) {
+                    Value::Integer(line) => u32::try_from(line).ok(),
+                    _ => None,
+                };
+                if let Value::String(event) = stack.get(0) {
+                    lock(&recorder).event(stack.thread(), event.as_bytes(), line);
+                }
+                stack.clear();
+                Ok(NativeReturn::Return)
+            })
+        };
+        let replaced = ctx.replace_hook(Some(Hook {
+            function: hook.into(),
+            mask: HookMask {
+                call: true,
+                ret: true,
+                line: count_lines,
+            },
+            count: HEARTBEAT,
+        }));
+        Profiler { recorder, replaced }
+    }
+
+    /// What has been recorded so far.
+    pub fn profile(&self) -> Profile {
+        lock(&self.recorder).profile()
+    }
+
+    /// Stops profiling, putting back the hook the profiler replaced, and returns what it recorded.
+    pub fn stop(self, ctx: Context<'_>) -> Profile {
+        self.replaced.restore(ctx);
+        let mut recorder = lock(&self.recorder);
+        recorder.finish(Instant::now());
+        recorder.profile()
+    }
+}
+
+/// What a [`Profiler`] recorded.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// The functions that ran, those that ran the longest themselves first.
+    pub functions: Vec<FunctionProfile>,
+    /// The lines that ran, by chunk and line, if the profiler counted them.
+    pub lines: Vec<LineProfile>,
+    /// The call paths, as indices into `functions` from the outermost, and the time spent in the
+    /// last function of each.
+    pub stacks: Vec<(Vec<usize>, Duration)>,
+}
+
+/// How a function, or all native functions together, ran while profiled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// The name the function was first called by, if it had one.
+    pub name: Option<String>,
+    /// The chunk the function is defined in, or `"[native]"`.
+    pub source: String,
+    /// The line the function is defined at, 0 for a main chunk or native functions.
+    pub line_defined: u32,
+    pub calls: u64,
+    /// The time spent in the function and the functions it called.
+    pub inclusive: Duration,
+    /// The time spent in the function itself.
+    pub exclusive: Duration,
+}
+
+/// How many times a line started running while profiled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineProfile {
+    pub source: String,
+    pub line: u32,
+    pub hits: u64,
+}
+
+impl Profile {
+    /// The call paths in the folded format flame graph tools read: a line per path, with the
+    /// functions from the outermost separated by `;`, then the microseconds spent in the last one.
+    pub fn folded(&self) -> String {
+        let mut out = String::new();
+        for (path, time) in &self.stacks {
+            let names: Vec<_> = path
+                .iter()
+                .map(|&f| self.functions[f].to_string())
+                .collect();
+            out.push_str(&format!("{} {}\n", names.join(";"), time.as_micros()));
+        }
+        out
+    }
+}
+
+impl fmt::Display for Profile {
+    /// A table of the functions, and of the lines if they were counted.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>12} {:>12} {:>10}  function",
+            "exclusive", "inclusive", "calls"
+        )?;
+        for function in &self.functions {
+            writeln!(
+                f,
+                "{:>12.3?} {:>12.3?} {:>10}  {function}",
+                function.exclusive, function.inclusive, function.calls
+            )?;
+        }
+        if !self.lines.is_empty() {
+            writeln!(f, "\n{:>10}  line", "hits")?;
+            for line in &self.lines {
+                writeln!(f, "{:>10}  {}:{}", line.hits, line.source, line.line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FunctionProfile {
+    /// Names the function the way tracebacks do.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.name, self.line_defined) {
+            _ if self.source == NATIVE_SOURCE => f.write_str("native functions"),
+            (_, 0) => write!(f, "main chunk <{}>", self.source),
+            (Some(name), line) => write!(f, "function '{name}' <{}:{line}>", self.source),
+            (None, line) => write!(f, "function <{}:{line}>", self.source),
+        }
+    }
+}
+
+fn lock(recorder: &Mutex<Recorder>) -> MutexGuard<'_, Recorder> {
+    recorder.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct Recorder {
+    functions: Vec<FunctionProfile>,
+    /// The functions by chunk and the line they are defined at.
+    ids: HashMap<(String, u32), usize>,
+    /// Line hits by function and line.
+    lines: HashMap<(usize, u32), u64>,
+    /// The call tree, with the time spent at each node.
+    nodes: Vec<Node>,
+    children: HashMap<(usize, usize), usize>,
+    threads: HashMap<Address, Calls>,
+    /// The thread of the last event, which has been running since.
+    running: Option<Address>,
+    last: Instant,
+}
+
+struct Node {
+    parent: usize,
+    function: usize,
+    time: Duration,
+}
+
+/// The functions a thread is in, innermost last.
+#[derive(Default)]
+struct Calls {
+    entries: Vec<Entry>,
+    /// How many times each function is in `entries`, so that recursion isn't counted twice.
+    active: HashMap<usize, u32>,
+}
+
+struct Entry {
+    function: usize,
+    node: usize,
+    /// The thread's depth with the function running: for a native function, its caller's.
+    depth: usize,
+    native: bool,
+    start: Instant,
+}
+
+impl Recorder {
+    fn new() -> Recorder {
+        Recorder {
+            functions: Vec::new(),
+            ids: HashMap::new(),
+            lines: HashMap::new(),
+            nodes: Vec::new(),
+            children: HashMap::new(),
+            threads: HashMap::new(),
+            running: None,
+            last: Instant::now(),
+        }
+    }
+
+    fn event(&mut self, thread: Thread<'_>, event: &[u8], line: Option<u32>) {
+        let now = Instant::now();
+        self.charge(now);
+        let key = Address::from(thread.as_ptr());
+        self.running = Some(key);
+        let depth = thread.depth();
+        // A tail call has replaced the function at the same depth; a native one is called from
+        // the depth below.
+        let replaced = event == b"tail call"
+            && self
+                .threads
+                .get(&key)
+                .and_then(|calls| calls.innermost_lua())
+                == Some(depth);
+        self.unwind(key, now, |entry| entry.depth > depth);
+        match event {
+            b"call" | b"tail call" => {
+                if replaced {
+                    self.unwind(key, now, |entry| entry.depth >= depth);
+                }
+                let innermost = self
+                    .threads
+                    .get(&key)
+                    .and_then(|calls| calls.innermost_lua());
+                if innermost.map_or(depth > 0, |d| d < depth) {
+                    self.enter_lua(thread, key, depth, now);
+                } else {
+                    let function = self.function(NATIVE_SOURCE.to_owned(), 0, None);
+                    self.push(key, function, depth, true, now);
+                }
+            }
+            b"return" => {
+                let top = self
+                    .threads
+                    .get(&key)
+                    .and_then(|calls| calls.entries.last());
+                if top.is_some_and(|entry| entry.native || entry.depth == depth) {
+                    self.pop(key, now);
+                }
+            }
+            _ => {
+                // Lua code runs at this depth, so whatever native function it called is done.
+                self.unwind(key, now, |entry| entry.native && entry.depth == depth);
+                if let (Some(line), Some(entry)) = (line, self.innermost(key)) {
+                    *self.lines.entry((entry, line)).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    /// Charges the time since the last event to the innermost function of the thread that ran.
+    fn charge(&mut self, now: Instant) {
+        let elapsed = now - std::mem::replace(&mut self.last, now);
+        let top = self
+            .running
+            .and_then(|key| self.threads.get(&key)?.entries.last());
+        if let Some(entry) = top {
+            self.nodes[entry.node].time += elapsed;
+            self.functions[entry.function].exclusive += elapsed;
+        }
+    }
+
+    fn enter_lua(&mut self, thread: Thread<'_>, key: Address, depth: usize, now: Instant) {
+        let Some(info) = thread.frame_info(1) else {
+            return;
+        };
+        let proto = info.closure.proto();
+        let name = info.name.map(|(_, name)| name.to_string());
+        let function = self.function(proto.chunk_name.to_string(), proto.line_defined, name);
+        self.push(key, function, depth, false, now);
+    }
+
+    fn function(&mut self, source: String, line_defined: u32, name: Option<String>) -> usize {
+        let functions = &mut self.functions;
+        let id = *self
+            .ids
+            .entry((source.clone(), line_defined))
+            .or_insert_with(|| {
+                functions.push(FunctionProfile {
+                    name: None,
+                    source,
+                    line_defined,
+                    calls: 0,
+                    inclusive: Duration::ZERO,
+                    exclusive: Duration::ZERO,
+                });
+                functions.len() - 1
+            });
+        let function = &mut self.functions[id];
+        if function.name.is_none() {
+            function.name = name;
+        }
+        id
+    }
+
+    fn push(&mut self, key: Address, function: usize, depth: usize, native: bool, now: Instant) {
+        let calls = self.threads.entry(key).or_default();
+        let parent = calls.entries.last().map_or(ROOT, |entry| entry.node);
+        let nodes = &mut self.nodes;
+        let node = *self.children.entry((parent, function)).or_insert_with(|| {
+            nodes.push(Node {
+                parent,
+                function,
+                time: Duration::ZERO,
+            });
+            nodes.len() - 1
+        });
+        calls.entries.push(Entry {
+            function,
+            node,
+            depth,
+            native,
+            start: now,
+        });
+        *calls.active.entry(function).or_default() += 1;
+        self.functions[function].calls += 1;
+    }
+
+    fn pop(&mut self, key: Address, now: Instant) {
+        let Some(calls) = self.threads.get_mut(&key) else {
+            return;
+        };
+        let Some(entry) = calls.entries.pop() else {
+            return;
+        };
+        let active = calls
+            .active
+            .get_mut(&entry.function)
+            .expect("counted on entry");
+        *active -= 1;
+        if *active == 0 {
+            self.functions[entry.function].inclusive += now - entry.start;
+        }
+    }
+
+    /// Pops the innermost functions of the thread for as long as `done` says they have returned.
+    fn unwind(&mut self, key: Address, now: Instant, done: impl Fn(&Entry) -> bool) {
+        while self
+            .threads
+            .get(&key)
+            .and_then(|calls| calls.entries.last())
+            .is_some_and(&done)
+        {
+            self.pop(key, now);
+        }
+    }
+
+    fn innermost(&self, key: Address) -> Option<usize> {
+        let calls = self.threads.get(&key)?;
+        let entry = calls.entries.iter().rev().find(|entry| !entry.native)?;
+        Some(entry.function)
+    }
+
+    /// Ends every call still in progress.
+    fn finish(&mut self, now: Instant) {
+        self.charge(now);
+        let keys: Vec<_> = self.threads.keys().copied().collect();
+        for key in keys {
+            self.unwind(key, now, |_| true);
+        }
+        self.running = None;
+    }
+
+    fn profile(&self) -> Profile {
+        let mut order: Vec<usize> = (0..self.functions.len()).collect();
+        order.sort_by_key(|&f| std::cmp::Reverse(self.functions[f].exclusive));
+        let mut renumbered = vec![0; order.len()];
+        for (new, &old) in order.iter().enumerate() {
+            renumbered[old] = new;
+        }
+
+        let mut lines = BTreeMap::new();
+        for (&(function, line), &hits) in &self.lines {
+            let source = self.functions[function].source.clone();
+            *lines.entry((source, line)).or_default() += hits;
+        }
+
+        let stacks = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.time > Duration::ZERO)
+            .map(|(mut i, node)| {
+                let mut path = Vec::new();
+                while i != ROOT {
+                    path.push(renumbered[self.nodes[i].function]);
+                    i = self.nodes[i].parent;
+                }
+                path.reverse();
+                (path, node.time)
+            })
+            .collect();
+
+        Profile {
+            functions: order.iter().map(|&f| self.functions[f].clone()).collect(),
+            lines: lines
+                .into_iter()
+                .map(|((source, line), hits)| LineProfile { source, line, hits })
+                .collect(),
+            stacks,
+        }
+    }
+}
+
+impl Calls {
+    /// The depth of the innermost Lua function.
+    fn innermost_lua(&self) -> Option<usize> {
+        let entry = self.entries.iter().rev().find(|entry| !entry.native)?;
+        Some(entry.depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lua;
+
+    #[test]
+    fn records_calls_and_lines() {
+        let mut lua = Lua::new();
+        let profile = lua.enter(|ctx| {
+            let profiler = Profiler::start(ctx, true);
+            let source = "\
+local function f(n) local x = math.abs(n) return x end
+local function g() for i = 1, 10 do f(i) end end
+local function fact(n) if n <= 1 then return 1 end return n * fact(n - 1) end
+g() g()
+fact(5)
+pcall(function() error('x') end)
+return f(1)";
+            ctx.call(ctx.load("=t", source).unwrap(), &[]).unwrap();
+            profiler.stop(ctx)
+        });
+        let find = |line_defined| {
+            profile
+                .functions
+                .iter()
+                .position(|f| f.source == "t" && f.line_defined == line_defined)
+                .unwrap()
+        };
+        let (main, f, g, fact) = (find(0), find(1), find(2), find(3));
+        let calls = |i: usize| profile.functions[i].calls;
+        assert_eq!(
+            (calls(main), calls(f), calls(g), calls(fact)),
+            (1, 21, 2, 5)
+        );
+        assert_eq!(profile.functions[f].name.as_deref(), Some("f"));
+        assert_eq!(profile.functions[fact].to_string(), "function 'fact' <t:3>");
+        // Recursive calls count once towards inclusive time.
+        assert!(profile.functions[fact].inclusive <= profile.functions[main].inclusive);
+        for function in &profile.functions {
+            assert!(function.exclusive <= function.inclusive, "{function}");
+        }
+
+        let native = profile
+            .functions
+            .iter()
+            .position(|f| f.source == NATIVE_SOURCE)
+            .unwrap();
+        assert!(profile
+            .stacks
+            .iter()
+            .any(|(path, _)| path == &[main, g, f, native]));
+        let folded = profile.folded();
+        assert!(
+            folded.contains("main chunk <t>;function 'g' <t:2>;function 'f' <t:1> "),
+            "{folded}"
+        );
+
+        let hits = |line| profile.lines.iter().find(|l| l.line == line).unwrap().hits;
+        assert_eq!(hits(4), 1);
+        // Each iteration of the loop starts the line over.
+        assert!(hits(2) > 20);
+        assert!(profile.to_string().contains("function 'g' <t:2>"));
+    }
+}
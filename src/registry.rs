@@ -0,0 +1,171 @@
+//! The registry: a table only the host can reach, for keeping Lua values alive from Rust.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::{Context, Table, Value};
+
+/// A handle to a value stored in the registry, which keeps the value alive for as long as the
+/// handle exists, across any number of [`Lua::enter`](crate::Lua::enter) calls.
+///
+/// Dropping the handle releases the value: its registry slot is cleared the next time a key is
+/// created or the state is entered, whichever comes first.
+pub struct RegistryKey {
+    index: i64,
+    /// Where dropped keys leave their index, shared with the state they belong to.
+    dropped: Arc<Mutex<Vec<i64>>>,
+}
+
+impl RegistryKey {
+    /// Returns true if the key belongs to the state `ctx` is for.
+    pub fn belongs_to(&self, ctx: Context<'_>) -> bool {
+        Arc::ptr_eq(&self.dropped, &ctx.state().registry_slots().dropped)
+    }
+}
+
+impl fmt::Debug for RegistryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RegistryKey({})", self.index)
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        let mut dropped = self.dropped.lock().unwrap_or_else(PoisonError::into_inner);
+        dropped.push(self.index);
+    }
+}
+
+/// Hands out the integer slots of the registry table.
+#[derive(Default)]
+pub(crate) struct RegistrySlots {
+    /// Shared with the keys, which may be dropped on another thread than the state is on.
+    dropped: Arc<Mutex<Vec<i64>>>,
+    free: RefCell<Vec<i64>>,
+    /// The number of slots handed out so far, free ones included.
+    len: Cell<i64>,
+}
+
+impl<'gc> Context<'gc> {
+    /// The registry table. Code running in the state can't reach it, so the host is free to keep
+    /// whatever it likes there.
+    pub fn registry(self) -> Table<'gc> {
+        self.state().registry
+    }
+
+    /// Stores `value` in the registry, returning a key that keeps it there until dropped.
+    pub fn create_registry_value(self, value: impl Into<Value<'gc>>) -> RegistryKey {
+        self.expire_registry_values();
+        let slots = self.state().registry_slots();
+        let index = slots.free.borrow_mut().pop().unwrap_or_else(|| {
+            slots.len.set(slots.len.get() + 1);
+            slots.len.get()
+        });
+        self.registry()
+            .set(&self, index, value.into())
+            .expect("integer keys are always valid");
+        RegistryKey {
+            index,
+            dropped: slots.dropped.clone(),
+        }
+    }
+
+    /// Gets the value stored under `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` was created by another state.
+    pub fn registry_value(self, key: &RegistryKey) -> Value<'gc> {
+        assert!(
+            key.belongs_to(self),
+            "registry key used with the wrong state"
+        );
+        self.registry().get(key.index)
+    }
+
+    /// Replaces the value stored under `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` was created by another state.
+    pub fn replace_registry_value(self, key: &RegistryKey, value: impl Into<Value<'gc>>) {
+        assert!(
+            key.belongs_to(self),
+            "registry key used with the wrong state"
+        );
+        self.registry()
+            .set(&self, key.index, value.into())
+            .expect("integer keys are always valid");
+    }
+
+    /// Releases the value stored under `key` right away, rather than when the state is next
+    /// entered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` was created by another state.
+    pub fn remove_registry_value(self, key: RegistryKey) {
+        assert!(
+            key.belongs_to(self),
+            "registry key used with the wrong state"
+        );
+        drop(key);
+        self.expire_registry_values();
+    }
+
+    /// Clears the slots of dropped keys, so that their values can be collected.
+    pub fn expire_registry_values(self) {
+        let slots = self.state().registry_slots();
+        let mut dropped = slots.dropped.lock().unwrap_or_else(PoisonError::into_inner);
+        let dropped = std::mem::take(&mut *dropped);
+        for index in dropped {
+            self.registry()
+                .set(&self, index, Value::Nil)
+                .expect("integer keys are always valid");
+            slots.free.borrow_mut().push(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, Table, Value};
+
+    #[test]
+    fn values_outlive_entries() {
+        let mut lua = Lua::new();
+        let key = lua.enter(|ctx| {
+            let t = Table::new(&ctx);
+            t.set(&ctx, 1, 42).unwrap();
+            ctx.create_registry_value(t)
+        });
+        lua.collect_all();
+        lua.enter(|ctx| {
+            let Value::Table(t) = ctx.registry_value(&key) else {
+                panic!("expected a table");
+            };
+            assert_eq!(t.get(1), Value::Integer(42));
+            ctx.replace_registry_value(&key, 2);
+        });
+        let index = key.index;
+        drop(key);
+        lua.enter(|ctx| {
+            assert!(ctx.registry().get(index).is_nil());
+            // The slot of the dropped key is handed out again.
+            let again = ctx.create_registry_value(true);
+            assert_eq!(again.index, index);
+            ctx.remove_registry_value(again);
+            assert!(ctx.registry().get(index).is_nil());
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "registry key used with the wrong state")]
+    fn keys_belong_to_their_state() {
+        let key = Lua::new().enter(|ctx| ctx.create_registry_value(1));
+        Lua::new().enter(|ctx| {
+            ctx.registry_value(&key);
+        });
+    }
+}
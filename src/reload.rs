@@ -0,0 +1,207 @@
+//! Running a changed chunk again in a state that ran it before, so that scripts can be edited
+//! while the host keeps running.
+
+use crate::function::Closure;
+use crate::stdlib::loaded;
+use crate::{Context, Function, LuaError, LuaString, Table, Value};
+
+impl<'gc> Context<'gc> {
+    /// Loads `source` as the chunk `name` and runs it, keeping the state the globals held before.
+    /// Returns the chunk's results.
+    ///
+    /// The code comes from the new run and the data from the old one. After the chunk has run,
+    /// each global it changed is put back together from what it held before and what it holds
+    /// now:
+    ///
+    /// - a function is the new one, with the values of the old one's upvalues, matched by name, so
+    ///   that counters and caches kept in locals carry over;
+    /// - a table is the old one, so that everything else referring to it sees the change, with
+    ///   the new one's functions and any fields it didn't have copied in, and its tables merged the
+    ///   same way;
+    /// - any other value is the old one, unless there was none.
+    ///
+    /// Upvalues are merged the same way as globals. Frozen tables keep what they have.
+    pub fn reload(
+        self,
+        name: &str,
+        source: impl AsRef<[u8]>,
+    ) -> Result<Vec<Value<'gc>>, LuaError<'gc>> {
+        let function = self.load(name, source)?;
+        let globals = self.globals();
+        let before = entries(globals);
+        let results = self.call(function, &[])?;
+        Merge::new(self).globals(globals, before);
+        Ok(results)
+    }
+
+    /// Reloads the module `module` that `require` loaded before, from `source`, loaded as the
+    /// chunk `name` and called with the module's name and `name`, as `require` calls it. The table
+    /// it returns is merged into the one in `package.loaded`, which stays the module, and the
+    /// globals it changed are kept as [`reload`](Context::reload) keeps them. Returns the module.
+    pub fn reload_module(
+        self,
+        module: &str,
+        name: &str,
+        source: impl AsRef<[u8]>,
+    ) -> Result<Value<'gc>, LuaError<'gc>> {
+        let function = self.load(name, source)?;
+        let globals = self.globals();
+        let before = entries(globals);
+        let module = Value::String(LuaString::new(&self, module.as_bytes()));
+        let args = [
+            module,
+            Value::String(LuaString::new(&self, name.as_bytes())),
+        ];
+        let results = self.call(function, &args)?;
+        let mut merge = Merge::new(self);
+        merge.globals(globals, before);
+
+        let loaded = loaded(self);
+        let new = match results.first() {
+            Some(&value) if !value.is_nil() => value,
+            _ => Value::Boolean(true),
+        };
+        let kept = match loaded.get(module) {
+            Value::Nil => new,
+            old => merge.value(old, new),
+        };
+        // Fails only if `package.loaded` has been frozen, leaving the old module in place.
+        let _ = loaded.set(&self, module, kept);
+        Ok(loaded.get(module))
+    }
+}
+
+fn entries(table: Table<'_>) -> Vec<(Value<'_>, Value<'_>)> {
+    let mut entries = Vec::new();
+    let mut key = Value::Nil;
+    while let Ok(Some((k, v))) = table.next(key) {
+        entries.push((k, v));
+        key = k;
+    }
+    entries
+}
+
+struct Merge<'gc> {
+    ctx: Context<'gc>,
+    /// The old tables and functions merged so far, so that cycles are only followed once.
+    seen: Vec<*const ()>,
+}
+
+impl<'gc> Merge<'gc> {
+    fn new(ctx: Context<'gc>) -> Merge<'gc> {
+        Merge {
+            ctx,
+            seen: Vec::new(),
+        }
+    }
+
+    /// Merges each of the globals as it was `before` with what it is now.
+    fn globals(&mut self, globals: Table<'gc>, before: Vec<(Value<'gc>, Value<'gc>)>) {
+        for (key, old) in before {
+            let new = globals.get(key);
+            if new != old {
+                let kept = self.value(old, new);
+                let _ = globals.set(&self.ctx, key, kept);
+            }
+        }
+    }
+
+    /// The value to keep of `old`, from before the chunk ran, and `new`.
+    fn value(&mut self, old: Value<'gc>, new: Value<'gc>) -> Value<'gc> {
+        match (old, new) {
+            (Value::Function(Function::Closure(old)), Value::Function(Function::Closure(new))) => {
+                self.upvalues(old, new);
+                Value::Function(Function::Closure(new))
+            }
+            (Value::Table(old), Value::Table(new)) if old != new => {
+                self.table(old, new);
+                Value::Table(old)
+            }
+            (_, Value::Function(_)) | (Value::Nil, _) => new,
+            _ => old,
+        }
+    }
+
+    /// Copies the functions and the missing fields of `new` into `old`.
+    fn table(&mut self, old: Table<'gc>, new: Table<'gc>) {
+        if self.seen.contains(&old.as_ptr()) || old.is_frozen() {
+            return;
+        }
+        self.seen.push(old.as_ptr());
+        for (key, value) in entries(new) {
+            let kept = self.value(old.get(key), value);
+            let _ = old.set(&self.ctx, key, kept);
+        }
+        if old.metatable().is_none() {
+            old.set_metatable(&self.ctx, new.metatable());
+        }
+    }
+
+    /// Gives the upvalues of `new` the values of the upvalues of `old` with the same names.
+    fn upvalues(&mut self, old: Closure<'gc>, new: Closure<'gc>) {
+        if self.seen.contains(&old.as_ptr()) {
+            return;
+        }
+        self.seen.push(old.as_ptr());
+        let old_names = &old.proto().upvalue_names;
+        for (i, name) in new.proto().upvalue_names.iter().enumerate() {
+            if name.as_bytes() == b"_ENV" {
+                continue;
+            }
+            let Some(j) = old_names
+                .iter()
+                .position(|n| n.as_bytes() == name.as_bytes())
+            else {
+                continue;
+            };
+            let upvalue = new.upvalues()[i];
+            let kept = self.value(old.upvalues()[j].value(), upvalue.value());
+            upvalue.set_value(&self.ctx, kept);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, Value};
+
+    #[test]
+    fn keeps_state() {
+        let mut lua = Lua::new();
+        let first = "local count = 0
+            function bump() count = count + 1 return count end
+            settings = {speed = 1, names = {'a'}}
+            function settings.describe() return 'speed ' .. settings.speed end
+            score = 0";
+        let second = "local count = 0
+            function bump() count = count + 10 return count end
+            settings = {speed = 2, names = {}, size = 3}
+            function settings.describe() return 'now at ' .. settings.speed end
+            score = 0";
+        lua.enter(|ctx| {
+            ctx.reload("=game", first).unwrap();
+            ctx.eval("bump() bump() score = 7 held = settings").unwrap();
+            ctx.reload("=game", second).unwrap();
+            let out = ctx
+                .eval(
+                    "return bump(), score, settings == held, settings.describe(), settings.size, \
+                     #settings.names",
+                )
+                .unwrap();
+            let out: Vec<_> = out.iter().map(Value::to_string).collect();
+            assert_eq!(out, ["12", "7", "true", "now at 1", "3", "1"]);
+
+            let module = "local M = {calls = 0}
+                function M.call() M.calls = M.calls + 1 return M.calls end
+                return M";
+            ctx.reload_module("m", "=m", module).unwrap();
+            ctx.eval("m = require('m') m.call() m.call()").unwrap();
+            let changed = module.replace("+ 1", "+ 100");
+            let reloaded = ctx.reload_module("m", "=m", changed).unwrap();
+            let out = ctx.eval("return m.call(), require('m') == m").unwrap();
+            assert_eq!(reloaded, ctx.eval("return m").unwrap()[0]);
+            assert_eq!(out[0], Value::Integer(102));
+            assert_eq!(out[1], Value::Boolean(true));
+        });
+    }
+}
@@ -0,0 +1,403 @@
+//! Setting up a state for running code that isn't trusted, in one place: which libraries and
+//! functions it gets, whether it may load bytecode, whether it may change the globals, and whether
+//! it runs the same way every time.
+
+use std::collections::BTreeMap;
+
+use crate::{stdlib, Context, Lua, Table, Value};
+
+/// A library of the standard library, or one of the optional ones.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Library {
+    /// The global functions, such as `print` and `pcall`.
+    Base,
+    Package,
+    Coroutine,
+    Debug,
+    #[cfg(feature = "io")]
+    Io,
+    Math,
+    #[cfg(feature = "os")]
+    Os,
+    String,
+    Table,
+    Utf8,
+    /// The `inspect` global, a single function.
+    Inspect,
+    #[cfg(feature = "json")]
+    Json,
+}
+
+impl Library {
+    /// The global the library is set as, or `None` for the base library, whose functions are
+    /// globals themselves.
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self {
+            Library::Base => return None,
+            Library::Package => "package",
+            Library::Coroutine => "coroutine",
+            Library::Debug => "debug",
+            #[cfg(feature = "io")]
+            Library::Io => "io",
+            Library::Math => "math",
+            #[cfg(feature = "os")]
+            Library::Os => "os",
+            Library::String => "string",
+            Library::Table => "table",
+            Library::Utf8 => "utf8",
+            Library::Inspect => "inspect",
+            #[cfg(feature = "json")]
+            Library::Json => "json",
+        })
+    }
+
+    fn load(self, ctx: Context<'_>) {
+        match self {
+            Library::Base => stdlib::load_base(ctx),
+            Library::Package => stdlib::load_package(ctx),
+            Library::Coroutine => stdlib::load_coroutine(ctx),
+            Library::Debug => stdlib::load_debug(ctx),
+            #[cfg(feature = "io")]
+            Library::Io => stdlib::load_io(ctx),
+            Library::Math => stdlib::load_math(ctx),
+            #[cfg(feature = "os")]
+            Library::Os => stdlib::load_os(ctx),
+            Library::String => stdlib::load_string(ctx),
+            Library::Table => stdlib::load_table(ctx),
+            Library::Utf8 => stdlib::load_utf8(ctx),
+            Library::Inspect => stdlib::load_inspect(ctx),
+            #[cfg(feature = "json")]
+            Library::Json => stdlib::load_json(ctx),
+        }
+    }
+}
+
+/// Which functions of a library to keep.
+#[derive(Debug, Clone)]
+enum Functions {
+    All,
+    Only(Vec<String>),
+    Except(Vec<String>),
+}
+
+impl Functions {
+    fn keeps(&self, name: &[u8]) -> bool {
+        match self {
+            Functions::All => true,
+            Functions::Only(names) => names.iter().any(|n| n.as_bytes() == name),
+            Functions::Except(names) => !names.iter().any(|n| n.as_bytes() == name),
+        }
+    }
+}
+
+/// Composes the globals of a state for untrusted code.
+///
+/// It starts out with the libraries that can't reach outside of the state, with binary chunks
+/// refused and the globals frozen, and each method loosens or tightens that.
+#[derive(Debug, Clone)]
+pub struct SandboxBuilder {
+    libraries: BTreeMap<Library, Functions>,
+    binary_chunks: bool,
+    freeze: bool,
+    /// The seed of a deterministic state.
+    deterministic: Option<i64>,
+}
+
+impl SandboxBuilder {
+    /// The base, coroutine, math, string, table and utf8 libraries, and `os.difftime` alone of the
+    /// os library, as `OsOptions::sandboxed` has it.
+    pub fn new() -> SandboxBuilder {
+        let mut libraries = BTreeMap::new();
+        for library in [
+            Library::Base,
+            Library::Coroutine,
+            Library::Math,
+            Library::String,
+            Library::Table,
+            Library::Utf8,
+        ] {
+            libraries.insert(library, Functions::All);
+        }
+        #[cfg(feature = "os")]
+        libraries.insert(Library::Os, Functions::Only(vec!["difftime".to_owned()]));
+        SandboxBuilder {
+            libraries,
+            binary_chunks: false,
+            freeze: true,
+            deterministic: None,
+        }
+    }
+
+    /// Loads the whole of `library`.
+    pub fn library(mut self, library: Library) -> SandboxBuilder {
+        self.libraries.insert(library, Functions::All);
+        self
+    }
+
+    /// Leaves `library` out.
+    pub fn without_library(mut self, library: Library) -> SandboxBuilder {
+        self.libraries.remove(&library);
+        self
+    }
+
+    /// Loads `library` with only the fields named, which for the base library are globals.
+    pub fn only_functions(mut self, library: Library, names: &[&str]) -> SandboxBuilder {
+        let names = names.iter().map(|&n| n.to_owned()).collect();
+        self.libraries.insert(library, Functions::Only(names));
+        self
+    }
+
+    /// Loads `library` without the fields named, and without any left out before.
+    pub fn without_functions(mut self, library: Library, names: &[&str]) -> SandboxBuilder {
+        let names = names.iter().map(|&n| n.to_owned());
+        let functions = match self.libraries.remove(&library) {
+            Some(Functions::Only(mut only)) => {
+                only.retain(|n| !names.clone().any(|m| m == *n));
+                Functions::Only(only)
+            }
+            Some(Functions::Except(mut except)) => {
+                except.extend(names);
+                Functions::Except(except)
+            }
+            Some(Functions::All) | None => Functions::Except(names.collect()),
+        };
+        self.libraries.insert(library, functions);
+        self
+    }
+
+    /// Whether scripts and the host may load binary chunks. Refused by default; see
+    /// [`Context::set_binary_chunks`]. The chunks allowed are
+    /// [verified](Context::set_verify_bytecode) first.
+    pub fn binary_chunks(mut self, allowed: bool) -> SandboxBuilder {
+        self.binary_chunks = allowed;
+        self
+    }
+
+    /// Whether to [freeze](Table::freeze) the globals and the tables of the libraries, and lock
+    /// the string metatable, so that no script can change them for the others. Scripts then keep
+    /// their own state in locals, or in an environment of their own whose `__index` is the
+    /// globals. On by default.
+    pub fn freeze_globals(mut self, freeze: bool) -> SandboxBuilder {
+        self.freeze = freeze;
+        self
+    }
+
+    /// Makes scripts run to the same results on every run and every machine, for simulations and
+    /// games replaying their inputs: `math.random` starts out seeded with `seed` and
+    /// `math.randomseed()` with 0, `pairs` visits keys in [sorted
+    /// order](Context::set_sorted_iteration), and the os library's clocks stand at 0 until the
+    /// host moves them with [`Context::set_time`] and [`Context::set_clock`]. `os.getenv` and
+    /// `os.tmpname` are left out even if the rest of the os library is loaded.
+    ///
+    /// Tables, functions and the other objects used as keys are visited in the order they were
+    /// made, not by their addresses, which `tostring` still shows and which differ between runs.
+    pub fn deterministic(mut self, seed: i64) -> SandboxBuilder {
+        self.deterministic = Some(seed);
+        self
+    }
+
+    /// Loads the libraries into the globals of `ctx`, which should be empty, and returns them.
+    pub fn build<'gc>(&self, ctx: Context<'gc>) -> Table<'gc> {
+        let globals = ctx.globals();
+        let mut tables = Vec::new();
+        for (&library, functions) in &self.libraries {
+            let before = keys(globals);
+            library.load(ctx);
+            match library.name() {
+                None => {
+                    let added = keys(globals).into_iter().filter(|k| !before.contains(k));
+                    remove_unkept(ctx, globals, added, functions);
+                }
+                Some(name) => {
+                    if let Value::Table(t) = globals.get_str(name) {
+                        remove_unkept(ctx, t, keys(t), functions);
+                        tables.push(t);
+                    }
+                }
+            }
+        }
+
+        #[cfg(feature = "os")]
+        if let (Some(_), Value::Table(os)) = (self.deterministic, globals.get_str("os")) {
+            remove_unkept(
+                ctx,
+                os,
+                keys(os),
+                &Functions::Except(vec!["getenv".to_owned(), "tmpname".to_owned()]),
+            );
+        }
+        if let Some(seed) = self.deterministic {
+            ctx.set_random_seed(seed);
+            ctx.set_entropy(false);
+            ctx.set_sorted_iteration(true);
+            ctx.set_time(Some(0));
+            ctx.set_clock(Some(0.0));
+        }
+
+        ctx.set_binary_chunks(self.binary_chunks);
+        ctx.set_verify_bytecode(true);
+        if self.freeze {
+            for table in tables {
+                table.freeze(&ctx);
+            }
+            globals.freeze(&ctx);
+            ctx.lock_string_metatable();
+        }
+        globals
+    }
+
+    /// A new state set up by [`SandboxBuilder::build`].
+    pub fn build_lua(&self) -> Lua {
+        let mut lua = Lua::empty();
+        lua.enter(|ctx| {
+            self.build(ctx);
+        });
+        lua
+    }
+}
+
+impl Default for SandboxBuilder {
+    fn default() -> SandboxBuilder {
+        SandboxBuilder::new()
+    }
+}
+
+fn keys(table: Table<'_>) -> Vec<Value<'_>> {
+    let mut keys = Vec::new();
+    let mut key = Value::Nil;
+    while let Ok(Some((k, _))) = table.next(key) {
+        keys.push(k);
+        key = k;
+    }
+    keys
+}
+
+fn remove_unkept<'gc>(
+    ctx: Context<'gc>,
+    table: Table<'gc>,
+    keys: impl IntoIterator<Item = Value<'gc>>,
+    functions: &Functions,
+) {
+    for key in keys {
+        let kept = match key {
+            Value::String(s) => functions.keeps(s.as_bytes()),
+            _ => true,
+        };
+        if !kept {
+            table
+                .set(&ctx, key, Value::Nil)
+                .expect("the table isn't frozen yet");
+        }
+    }
+}
+
+#[cfg(all(test, feature = "os"))]
+mod tests {
+    use super::*;
+    use crate::stdlib::testing::run_in;
+
+    #[test]
+    fn locks_down_globals() {
+        let mut lua = SandboxBuilder::new().build_lua();
+        let checks = [
+            (
+                "type(io), type(debug), type(package), type(require)",
+                "nil, nil, nil, nil",
+            ),
+            ("type(os.difftime), type(os.getenv)", "function, nil"),
+            ("string.format('%d', 7), ('x'):rep(2)", "7, xx"),
+            ("x = 1", "attempt to modify a frozen table"),
+            ("string.len = nil", "attempt to modify a frozen table"),
+            ("rawset(_G, 'x', 1)", "attempt to modify a frozen table"),
+            ("table.insert(math, 1)", "attempt to modify a frozen table"),
+            (
+                "setmetatable(_G, {})",
+                "cannot change the metatable of a frozen table",
+            ),
+            (
+                "load(string.dump(function() end))",
+                "nil, attempt to load a binary chunk (binary chunks are disabled)",
+            ),
+            ("local t = {} t.x = 1 return t.x", "1"),
+        ];
+        for (source, expected) in checks {
+            let output = run_in(&mut lua, source);
+            assert!(output.ends_with(expected), "{source}: {output}");
+        }
+
+        let mut lua = SandboxBuilder::new()
+            .without_library(Library::Coroutine)
+            .library(Library::Os)
+            .without_functions(Library::Os, &["exit", "getenv"])
+            .only_functions(Library::Math, &["floor"])
+            .without_functions(Library::Base, &["load", "collectgarbage"])
+            .freeze_globals(false)
+            .build_lua();
+        let checks = [
+            (
+                "type(coroutine), type(load), type(collectgarbage)",
+                "nil, nil, nil",
+            ),
+            (
+                "type(os.exit), type(os.getenv), type(os.time)",
+                "nil, nil, function",
+            ),
+            ("math.floor(1.5), math.sqrt", "1, nil"),
+            ("x = 1 return x", "1"),
+        ];
+        for (source, expected) in checks {
+            let output = run_in(&mut lua, source);
+            assert!(output.ends_with(expected), "{source}: {output}");
+        }
+    }
+
+    #[test]
+    fn runs_deterministically() {
+        let source = "local t = {}
+            for i = 1, 20 do t['k' .. math.random(1000)] = i end
+            local keys = {}
+            for k in pairs(t) do keys[#keys + 1] = k end
+            math.randomseed()
+            return table.concat(keys, ' '), math.random(1000), os.time(), os.clock(),
+                os.date('!%Y'), type(os.getenv), type(os.remove)";
+        let builder = SandboxBuilder::new().library(Library::Os).deterministic(7);
+        let first = run_in(&mut builder.build_lua(), source);
+        assert_eq!(run_in(&mut builder.build_lua(), source), first);
+        assert!(first.ends_with(", 0, 0.0, 1970, nil, function"), "{first}");
+
+        // Tables made after others were freed may take their lower addresses, but are visited
+        // after the keys made before them.
+        let mut lua = SandboxBuilder::new()
+            .deterministic(7)
+            .freeze_globals(false)
+            .build_lua();
+        run_in(
+            &mut lua,
+            "junk = {} for i = 1, 1000 do junk[i] = {} end
+            first = {name = 'first'}
+            junk = nil",
+        );
+        lua.collect_all();
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "local t = {[first] = true}
+                for i = 1, 1000 do t[{name = i}] = true end
+                local names = {}
+                for k in pairs(t) do names[#names + 1] = k.name end
+                return names[1], names[2], names[1001]"
+            ),
+            "first, 1, 1000"
+        );
+
+        let mut lua = builder.build_lua();
+        lua.enter(|ctx| {
+            ctx.set_time(Some(86400 * 365));
+            ctx.set_clock(Some(1.5));
+        });
+        assert_eq!(
+            run_in(&mut lua, "return os.time(), os.clock()"),
+            "31536000, 1.5"
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! Lending borrowed data and non-`'static` closures to Lua for a limited time.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::mem;
+use std::rc::Rc;
+
+use crate::convert::{FromLuaMulti, IntoLuaMulti};
+use crate::function::typed_args;
+use crate::mem::Managed;
+use crate::userdata::Contents;
+use crate::vm::Stack;
+use crate::{
+    AnyUserData, Callback, CallbackFn, Context, Function, LuaError, NativeReturn, RuntimeError,
+    UserData,
+};
+
+type ScopedFnBox<'gc> =
+    Box<dyn Fn(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> + 'static>;
+
+/// The userdata and functions made with a [`Scope`], all of which stop working when it ends.
+///
+/// Scripts can keep hold of them, in globals or anywhere else, but using them afterwards is an
+/// error: a userdata can no longer be borrowed, and a function raises an error when called.
+pub struct Scope<'scope, 'env: 'scope, 'gc: 'env> {
+    ctx: Context<'gc>,
+    lent: RefCell<Vec<Lent<'gc>>>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+enum Lent<'gc> {
+    UserData(AnyUserData<'gc>),
+    Function(Rc<RefCell<Option<ScopedFnBox<'gc>>>>),
+}
+
+impl<'gc> Context<'gc> {
+    /// Runs `f` with a [`Scope`] for lending Lua things that borrow from the caller's stack frame.
+    ///
+    /// When `f` returns, or unwinds, everything it lent is taken back.
+    pub fn scope<'env, R>(
+        self,
+        f: impl for<'scope> FnOnce(&'scope Scope<'scope, 'env, 'gc>) -> R,
+    ) -> R
+    where
+        'gc: 'env,
+    {
+        let scope = Scope {
+            ctx: self,
+            lent: RefCell::new(Vec::new()),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let _expire = Expire(&scope.lent);
+        f(&scope)
+    }
+}
+
+impl<'scope, 'env, 'gc> Scope<'scope, 'env, 'gc> {
+    /// A userdata borrowing `value`, with the metatable [`UserData::register`] declares for `T`.
+    /// Methods can read it but not change it.
+    pub fn create_userdata_ref<T: UserData>(&'scope self, value: &'env T) -> AnyUserData<'gc> {
+        let value: &dyn Any = value;
+        self.lend_userdata::<T>(Contents::Shared(value))
+    }
+
+    /// A userdata mutably borrowing `value`, with the metatable [`UserData::register`] declares for
+    /// `T`.
+    pub fn create_userdata_ref_mut<T: UserData>(
+        &'scope self,
+        value: &'env mut T,
+    ) -> AnyUserData<'gc> {
+        let value: &mut dyn Any = value;
+        self.lend_userdata::<T>(Contents::Unique(value))
+    }
+
+    /// A function calling `f`, which can borrow anything that outlives the scope, Lua values
+    /// included.
+    pub fn create_function<F>(&'scope self, f: F) -> Function<'gc>
+    where
+        F: Fn(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> + 'scope,
+    {
+        let f: Box<dyn Fn(Context<'gc>, &mut Stack<'gc, '_>) -> _ + 'scope> = Box::new(f);
+        // SAFETY: the scope drops the closure before it ends, after which it can't be called.
+        let f: ScopedFnBox<'gc> = unsafe { mem::transmute(f) };
+        let slot = Rc::new(RefCell::new(Some(f)));
+        self.lent
+            .borrow_mut()
+            .push(Lent::Function(Rc::clone(&slot)));
+        Callback::new(&self.ctx, ScopedFn(slot)).into()
+    }
+
+    /// A function calling `f` with its arguments converted to `A`, like
+    /// [`Callback::from_typed_fn`].
+    pub fn create_typed_function<A, R, F>(&'scope self, f: F) -> Function<'gc>
+    where
+        A: FromLuaMulti<'gc>,
+        R: IntoLuaMulti<'gc>,
+        F: Fn(Context<'gc>, A) -> Result<R, LuaError<'gc>> + 'scope,
+    {
+        self.create_function(move |ctx, stack| {
+            let args = typed_args(ctx, stack)?;
+            let results = f(ctx, args)?.into_lua_multi(ctx)?;
+            stack.replace(&results);
+            Ok(NativeReturn::Return)
+        })
+    }
+
+    fn lend_userdata<T: UserData>(&self, contents: Contents) -> AnyUserData<'gc> {
+        let userdata = AnyUserData::with_contents::<T>(&self.ctx, contents);
+        userdata.set_metatable(&self.ctx, Some(self.ctx.userdata_metatable::<T>()));
+        self.lent.borrow_mut().push(Lent::UserData(userdata));
+        userdata
+    }
+}
+
+/// Takes back what a scope lent when dropped. It is separate from the scope, which is borrowed for
+/// all of its own lifetime.
+struct Expire<'a, 'gc>(&'a RefCell<Vec<Lent<'gc>>>);
+
+impl<'a, 'gc> Drop for Expire<'a, 'gc> {
+    fn drop(&mut self) {
+        for lent in self.0.borrow_mut().drain(..) {
+            match lent {
+                Lent::UserData(userdata) => userdata.expire(),
+                // Nothing lent by the scope can be running by now, as it would have been called
+                // from within the scope.
+                Lent::Function(slot) => *slot.borrow_mut() = None,
+            }
+        }
+    }
+}
+
+/// The callback of a scoped function, emptied when its scope ends.
+struct ScopedFn<'gc>(Rc<RefCell<Option<ScopedFnBox<'gc>>>>);
+
+// The closure may borrow Lua values, but only within a single `Lua::enter`, during which nothing is
+// collected.
+unsafe impl<'gc> Managed for ScopedFn<'gc> {}
+
+impl<'gc> CallbackFn<'gc> for ScopedFn<'gc> {
+    fn call(
+        &self,
+        ctx: Context<'gc>,
+        stack: &mut Stack<'gc, '_>,
+    ) -> Result<NativeReturn, LuaError<'gc>> {
+        let f = self.0.borrow();
+        let Some(f) = &*f else {
+            return Err(
+                RuntimeError::new("attempt to call a function whose scope has ended").into(),
+            );
+        };
+        f(ctx, stack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::{Context, Lua, LuaString, UserData, UserDataMethods, Value};
+
+    struct Counter(i64);
+
+    impl UserData for Counter {
+        fn register(methods: &mut UserDataMethods<Self>) {
+            methods.add_method("get", |_, this, ()| Ok(this.0));
+            methods.add_method_mut("add", |_, this, n: i64| {
+                this.0 += n;
+                Ok(())
+            });
+        }
+    }
+
+    fn set<'gc>(ctx: Context<'gc>, name: &str, value: Value<'gc>) {
+        let name = LuaString::new(&ctx, name.as_bytes());
+        ctx.globals().set(&ctx, name, value).unwrap();
+    }
+
+    #[test]
+    fn lends_until_the_end() {
+        let mut lua = Lua::new();
+        let mut counter = Counter(1);
+        let frozen = Counter(7);
+        let calls = Cell::new(0);
+        let results = lua.enter(|ctx| {
+            let run = |source: &str| match ctx.eval(source) {
+                Ok(values) => values.iter().map(|v| v.to_string()).collect(),
+                Err(err) => vec![err.to_string()],
+            };
+            let mut results = ctx.scope(|scope| {
+                set(
+                    ctx,
+                    "counter",
+                    Value::UserData(scope.create_userdata_ref_mut(&mut counter)),
+                );
+                set(
+                    ctx,
+                    "frozen",
+                    Value::UserData(scope.create_userdata_ref(&frozen)),
+                );
+                let count = scope.create_typed_function(|_, n: i64| {
+                    calls.set(calls.get() + n);
+                    Ok(calls.get())
+                });
+                set(ctx, "count", Value::Function(count));
+                let mut results = run("counter:add(10) count(2) return counter:get(), count(3)");
+                results.extend(run("return frozen:get()"));
+                results.extend(run("frozen:add(1)"));
+                results
+            });
+            results.extend(run("return counter:get()"));
+            results.extend(run("return count(1)"));
+            results
+        });
+        assert_eq!(
+            results,
+            [
+                "11",
+                "5",
+                "7",
+                "userdata is already borrowed",
+                "userdata is no longer available",
+                "attempt to call a function whose scope has ended",
+            ]
+        );
+        assert_eq!(counter.0, 11);
+        assert_eq!(calls.get(), 5);
+    }
+}
@@ -0,0 +1,1153 @@
+//! Serde support: a [`Serializer`] that builds Lua values out of Rust data, and a [`Deserializer`]
+//! that reads Rust data back out of them.
+//!
+//! Lua has one table type for both sequences and maps, and no value that stands for an explicit
+//! absence. Sequences serialized here are given the [`array_metatable`], so that even an empty one
+//! reads back as a sequence, and `None` can be written as the [`null`] userdata instead of nil to
+//! keep its place in a sequence or its entry in a map.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+use ::serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use ::serde::ser::{self, Serialize};
+
+use crate::vm::ops;
+use crate::{Context, LuaError, LuaString, RuntimeError, Table, UserData, UserDataMethods, Value};
+
+/// How deeply values may nest unless the options say otherwise.
+const MAX_DEPTH: usize = 128;
+
+/// Serializes `value` into a Lua value with the default options.
+pub fn to_value<'gc, T: Serialize + ?Sized>(
+    ctx: Context<'gc>,
+    value: &T,
+) -> Result<Value<'gc>, Error> {
+    to_value_with(ctx, value, SerializeOptions::default())
+}
+
+pub fn to_value_with<'gc, T: Serialize + ?Sized>(
+    ctx: Context<'gc>,
+    value: &T,
+    options: SerializeOptions,
+) -> Result<Value<'gc>, Error> {
+    value.serialize(Serializer::with_options(ctx, options))
+}
+
+/// Deserializes a `T` out of `value` with the default options.
+pub fn from_value<'gc, T: DeserializeOwned>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+) -> Result<T, Error> {
+    from_value_with(ctx, value, DeserializeOptions::default())
+}
+
+pub fn from_value_with<'gc, T: DeserializeOwned>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+    options: DeserializeOptions,
+) -> Result<T, Error> {
+    T::deserialize(Deserializer::with_options(ctx, value, options))
+}
+
+/// Converts `value` into JSON. Keys that are numbers become strings, as JSON's keys have to be, and
+/// [`null`] becomes `null`.
+#[cfg(feature = "json")]
+pub fn to_json<'gc>(ctx: Context<'gc>, value: Value<'gc>) -> Result<::serde_json::Value, Error> {
+    from_value(ctx, value)
+}
+
+/// Converts `json` into a Lua value, with arrays marked as such and `null` as [`null`], so that
+/// converting it back gives the same JSON.
+#[cfg(feature = "json")]
+pub fn from_json<'gc>(ctx: Context<'gc>, json: &::serde_json::Value) -> Result<Value<'gc>, Error> {
+    let options = SerializeOptions {
+        none_as_null: true,
+        ..SerializeOptions::default()
+    };
+    to_value_with(ctx, json, options)
+}
+
+/// The error of a failed serialization or deserialization.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    fn new(message: impl fmt::Display) -> Error {
+        Error {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::new(msg)
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::new(msg)
+    }
+}
+
+impl From<Error> for RuntimeError {
+    fn from(err: Error) -> RuntimeError {
+        RuntimeError::new(err.message)
+    }
+}
+
+impl<'gc> From<Error> for LuaError<'gc> {
+    fn from(err: Error) -> LuaError<'gc> {
+        RuntimeError::from(err).into()
+    }
+}
+
+/// The type of the [`null`] userdata.
+struct Null;
+
+impl UserData for Null {
+    fn register(methods: &mut UserDataMethods<Self>) {
+        methods.add_meta_method("__tostring", |_, _, ()| Ok("null"));
+    }
+}
+
+/// The value standing in for an explicit absence, such as JSON's `null`: a userdata that is the
+/// same for the whole state. It deserializes as `None` or `()`, the same as nil.
+pub fn null(ctx: Context<'_>) -> Value<'_> {
+    let registry = ctx.registry();
+    if let value @ Value::UserData(_) = registry.get_str("serde.null") {
+        return value;
+    }
+    let null = Value::UserData(ctx.create_userdata(Null));
+    registry
+        .set(&ctx, LuaString::new(&ctx, b"serde.null"), null)
+        .expect("string keys are always valid");
+    null
+}
+
+fn is_null(value: Value<'_>) -> bool {
+    matches!(value, Value::UserData(ud) if ud.is::<Null>())
+}
+
+/// The metatable marking a table as a sequence, whatever its contents. The serializer gives it to
+/// the sequences it builds, and scripts can set it on their own tables, an empty one especially,
+/// to have them read back as sequences.
+pub fn array_metatable(ctx: Context<'_>) -> Table<'_> {
+    let registry = ctx.registry();
+    if let Value::Table(metatable) = registry.get_str("serde.array") {
+        return metatable;
+    }
+    let metatable = Table::new(&ctx);
+    registry
+        .set(&ctx, LuaString::new(&ctx, b"serde.array"), metatable)
+        .expect("string keys are always valid");
+    metatable
+}
+
+/// How Rust data is turned into Lua values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerializeOptions {
+    /// Writes `None`, `()` and unit structs as [`null`] rather than nil. A nil leaves a hole in a
+    /// sequence and drops the entry of a map. Off by default.
+    pub none_as_null: bool,
+    /// Gives sequences the [`array_metatable`]. On by default.
+    pub mark_arrays: bool,
+    /// How many levels of sequences, maps and structs may be nested.
+    pub max_depth: usize,
+}
+
+impl Default for SerializeOptions {
+    fn default() -> SerializeOptions {
+        SerializeOptions {
+            none_as_null: false,
+            mark_arrays: true,
+            max_depth: MAX_DEPTH,
+        }
+    }
+}
+
+/// How Lua values are read as Rust data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeserializeOptions {
+    /// Reads a table without the [`array_metatable`] as a sequence when its keys are exactly
+    /// `1..=n` for some `n` of at least one. With this off, only marked tables are sequences to a
+    /// self-describing format; a type that asks for a sequence takes any table either way. On by
+    /// default.
+    pub detect_arrays: bool,
+    /// Visits the entries of maps in order of their keys, numbers first, rather than in
+    /// traversal order, so that the output doesn't vary from run to run. Off by default.
+    pub sort_keys: bool,
+    /// Fails on functions, threads and userdata other than [`null`]. When off, map entries
+    /// holding them are left out and elsewhere they read as `()`. On by default.
+    pub deny_unsupported_types: bool,
+    /// How many levels of tables may be nested. A table that contains itself fails regardless.
+    pub max_depth: usize,
+}
+
+impl Default for DeserializeOptions {
+    fn default() -> DeserializeOptions {
+        DeserializeOptions {
+            detect_arrays: true,
+            sort_keys: false,
+            deny_unsupported_types: true,
+            max_depth: MAX_DEPTH,
+        }
+    }
+}
+
+/// A serializer producing Lua values.
+#[derive(Clone, Copy)]
+pub struct Serializer<'gc> {
+    ctx: Context<'gc>,
+    options: SerializeOptions,
+    depth: usize,
+}
+
+impl<'gc> Serializer<'gc> {
+    pub fn new(ctx: Context<'gc>) -> Serializer<'gc> {
+        Serializer::with_options(ctx, SerializeOptions::default())
+    }
+
+    pub fn with_options(ctx: Context<'gc>, options: SerializeOptions) -> Serializer<'gc> {
+        Serializer {
+            ctx,
+            options,
+            depth: 0,
+        }
+    }
+
+    /// The serializer for the contents of a sequence, map or struct.
+    fn nested(self) -> Result<Serializer<'gc>, Error> {
+        if self.depth >= self.options.max_depth {
+            return Err(Error::new(format!(
+                "values nested more than {} levels deep",
+                self.options.max_depth
+            )));
+        }
+        Ok(Serializer {
+            depth: self.depth + 1,
+            ..self
+        })
+    }
+
+    fn none(self) -> Value<'gc> {
+        if self.options.none_as_null {
+            null(self.ctx)
+        } else {
+            Value::Nil
+        }
+    }
+
+    fn string(self, bytes: &[u8]) -> Value<'gc> {
+        Value::String(LuaString::new(&self.ctx, bytes))
+    }
+
+    /// A table holding `value` under the name of an enum variant.
+    fn variant(self, variant: &str, value: Value<'gc>) -> Result<Value<'gc>, Error> {
+        let table = Table::with_capacity(&self.ctx, 0, 1);
+        table
+            .set(&self.ctx, self.string(variant.as_bytes()), value)
+            .map_err(Error::new)?;
+        Ok(Value::Table(table))
+    }
+}
+
+impl<'gc> ser::Serializer for Serializer<'gc> {
+    type Ok = Value<'gc>;
+    type Error = Error;
+
+    type SerializeSeq = SeqSerializer<'gc>;
+    type SerializeTuple = SeqSerializer<'gc>;
+    type SerializeTupleStruct = SeqSerializer<'gc>;
+    type SerializeTupleVariant = VariantSerializer<'gc, SeqSerializer<'gc>>;
+    type SerializeMap = MapSerializer<'gc>;
+    type SerializeStruct = MapSerializer<'gc>;
+    type SerializeStructVariant = VariantSerializer<'gc, MapSerializer<'gc>>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value<'gc>, Error> {
+        Ok(Value::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value<'gc>, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value<'gc>, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value<'gc>, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value<'gc>, Error> {
+        Ok(Value::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value<'gc>, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value<'gc>, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value<'gc>, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    /// Integers beyond the range of Lua's become floats.
+    fn serialize_u64(self, v: u64) -> Result<Value<'gc>, Error> {
+        match i64::try_from(v) {
+            Ok(v) => Ok(Value::Integer(v)),
+            Err(_) => Ok(Value::Number(v as f64)),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value<'gc>, Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value<'gc>, Error> {
+        Ok(Value::Number(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value<'gc>, Error> {
+        Ok(self.string(v.encode_utf8(&mut [0; 4]).as_bytes()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value<'gc>, Error> {
+        Ok(self.string(v.as_bytes()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value<'gc>, Error> {
+        Ok(self.string(v))
+    }
+
+    fn serialize_none(self) -> Result<Value<'gc>, Error> {
+        Ok(self.none())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value<'gc>, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value<'gc>, Error> {
+        Ok(self.none())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value<'gc>, Error> {
+        Ok(self.none())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value<'gc>, Error> {
+        Ok(self.string(variant.as_bytes()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value<'gc>, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value<'gc>, Error> {
+        let value = value.serialize(self.nested()?)?;
+        self.variant(variant, value)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer<'gc>, Error> {
+        Ok(SeqSerializer {
+            table: Table::with_capacity(&self.ctx, len.unwrap_or(0), 0),
+            serializer: self.nested()?,
+            len: 0,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer<'gc>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer<'gc>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Ok(VariantSerializer {
+            serializer: self,
+            variant,
+            inner: self.nested()?.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<MapSerializer<'gc>, Error> {
+        Ok(MapSerializer {
+            table: Table::with_capacity(&self.ctx, 0, len.unwrap_or(0)),
+            serializer: self.nested()?,
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<MapSerializer<'gc>, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Ok(VariantSerializer {
+            serializer: self,
+            variant,
+            inner: self.nested()?.serialize_map(Some(len))?,
+        })
+    }
+}
+
+/// Builds the table of a sequence, tuple or tuple struct.
+pub struct SeqSerializer<'gc> {
+    table: Table<'gc>,
+    serializer: Serializer<'gc>,
+    len: i64,
+}
+
+impl<'gc> SeqSerializer<'gc> {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let value = value.serialize(self.serializer)?;
+        self.len += 1;
+        self.table
+            .set(&self.serializer.ctx, self.len, value)
+            .map_err(Error::new)
+    }
+
+    fn finish(self) -> Value<'gc> {
+        let Serializer { ctx, options, .. } = self.serializer;
+        if options.mark_arrays {
+            self.table.set_metatable(&ctx, Some(array_metatable(ctx)));
+        }
+        Value::Table(self.table)
+    }
+}
+
+impl<'gc> ser::SerializeSeq for SeqSerializer<'gc> {
+    type Ok = Value<'gc>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value<'gc>, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl<'gc> ser::SerializeTuple for SeqSerializer<'gc> {
+    type Ok = Value<'gc>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value<'gc>, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl<'gc> ser::SerializeTupleStruct for SeqSerializer<'gc> {
+    type Ok = Value<'gc>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value<'gc>, Error> {
+        Ok(self.finish())
+    }
+}
+
+/// Builds the table of a map or struct.
+pub struct MapSerializer<'gc> {
+    table: Table<'gc>,
+    serializer: Serializer<'gc>,
+    key: Option<Value<'gc>>,
+}
+
+impl<'gc> MapSerializer<'gc> {
+    fn insert(&mut self, key: Value<'gc>, value: Value<'gc>) -> Result<(), Error> {
+        self.table
+            .set(&self.serializer.ctx, key, value)
+            .map_err(|err| Error::new(format!("invalid map key ({err})")))
+    }
+}
+
+impl<'gc> ser::SerializeMap for MapSerializer<'gc> {
+    type Ok = Value<'gc>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key.serialize(self.serializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        let value = value.serialize(self.serializer)?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Value<'gc>, Error> {
+        Ok(Value::Table(self.table))
+    }
+}
+
+impl<'gc> ser::SerializeStruct for MapSerializer<'gc> {
+    type Ok = Value<'gc>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let value = value.serialize(self.serializer)?;
+        self.insert(self.serializer.string(key.as_bytes()), value)
+    }
+
+    fn end(self) -> Result<Value<'gc>, Error> {
+        Ok(Value::Table(self.table))
+    }
+}
+
+/// Builds a tuple or struct variant, which is a table holding the tuple or struct under the name
+/// of the variant.
+pub struct VariantSerializer<'gc, S> {
+    serializer: Serializer<'gc>,
+    variant: &'static str,
+    inner: S,
+}
+
+impl<'gc> ser::SerializeTupleVariant for VariantSerializer<'gc, SeqSerializer<'gc>> {
+    type Ok = Value<'gc>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.inner.push(value)
+    }
+
+    fn end(self) -> Result<Value<'gc>, Error> {
+        self.serializer.variant(self.variant, self.inner.finish())
+    }
+}
+
+impl<'gc> ser::SerializeStructVariant for VariantSerializer<'gc, MapSerializer<'gc>> {
+    type Ok = Value<'gc>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Value<'gc>, Error> {
+        self.serializer
+            .variant(self.variant, Value::Table(self.inner.table))
+    }
+}
+
+/// A deserializer reading from a Lua value.
+#[derive(Clone)]
+pub struct Deserializer<'gc> {
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+    options: DeserializeOptions,
+    /// The tables enclosing `value`, to catch one that contains itself.
+    enclosing: Rc<RefCell<Vec<*const ()>>>,
+}
+
+impl<'gc> Deserializer<'gc> {
+    pub fn new(ctx: Context<'gc>, value: Value<'gc>) -> Deserializer<'gc> {
+        Deserializer::with_options(ctx, value, DeserializeOptions::default())
+    }
+
+    pub fn with_options(
+        ctx: Context<'gc>,
+        value: Value<'gc>,
+        options: DeserializeOptions,
+    ) -> Deserializer<'gc> {
+        Deserializer {
+            ctx,
+            value,
+            options,
+            enclosing: Rc::default(),
+        }
+    }
+
+    /// The deserializer for a value inside the current one.
+    fn nested(&self, value: Value<'gc>) -> Deserializer<'gc> {
+        Deserializer {
+            value,
+            enclosing: self.enclosing.clone(),
+            ..*self
+        }
+    }
+
+    fn is_unsupported(&self, value: Value<'gc>) -> bool {
+        matches!(
+            value,
+            Value::Function(_) | Value::Thread(_) | Value::UserData(_)
+        ) && !is_null(value)
+    }
+
+    fn unsupported(&self) -> Error {
+        Error::new(format!("cannot represent a {}", self.value.type_name()))
+    }
+
+    /// Runs `f` with `table` entered, failing if it is already being read or too deeply nested.
+    fn enter<R>(
+        &self,
+        table: Table<'gc>,
+        f: impl FnOnce() -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        {
+            let mut enclosing = self.enclosing.borrow_mut();
+            if enclosing.contains(&table.as_ptr()) {
+                return Err(Error::new("cannot represent a table that contains itself"));
+            }
+            if enclosing.len() >= self.options.max_depth {
+                return Err(Error::new(format!(
+                    "tables nested more than {} levels deep",
+                    self.options.max_depth
+                )));
+            }
+            enclosing.push(table.as_ptr());
+        }
+        let result = f();
+        self.enclosing.borrow_mut().pop();
+        result
+    }
+
+    /// Whether a table read without a type to go by is a sequence.
+    fn is_array(&self, table: Table<'gc>) -> bool {
+        if table.metatable() == Some(array_metatable(self.ctx)) {
+            return true;
+        }
+        if !self.options.detect_arrays {
+            return false;
+        }
+        let len = table.length();
+        len > 0 && self.entries(table).len() == len
+    }
+
+    /// The entries of `table`, in the order the options ask for.
+    fn entries(&self, table: Table<'gc>) -> Vec<(Value<'gc>, Value<'gc>)> {
+        let mut entries = Vec::new();
+        let mut key = Value::Nil;
+        while let Ok(Some((k, v))) = table.next(key) {
+            entries.push((k, v));
+            key = k;
+        }
+        if self.options.sort_keys {
+            entries.sort_by(|(a, _), (b, _)| key_order(*a, *b));
+        }
+        entries
+    }
+
+    fn visit_seq<'de, V: Visitor<'de>>(
+        &self,
+        table: Table<'gc>,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.enter(table, || {
+            let mut seq = SeqDeserializer {
+                deserializer: self,
+                table,
+                index: 1,
+                len: table.length(),
+            };
+            let value = visitor.visit_seq(&mut seq)?;
+            if seq.index <= seq.len {
+                return Err(de::Error::invalid_length(seq.len, &"fewer elements"));
+            }
+            Ok(value)
+        })
+    }
+
+    fn visit_map<'de, V: Visitor<'de>>(
+        &self,
+        table: Table<'gc>,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.enter(table, || {
+            let mut entries = self.entries(table);
+            if !self.options.deny_unsupported_types {
+                entries.retain(|&(k, v)| !self.is_unsupported(k) && !self.is_unsupported(v));
+            }
+            visitor.visit_map(MapDeserializer {
+                deserializer: self,
+                entries: entries.into_iter(),
+                value: None,
+            })
+        })
+    }
+}
+
+/// Numbers in numeric order, then strings in byte order, then anything else.
+fn key_order(a: Value<'_>, b: Value<'_>) -> Ordering {
+    fn rank(value: Value<'_>) -> u8 {
+        match value {
+            Value::Integer(_) | Value::Number(_) => 0,
+            Value::String(_) => 1,
+            _ => 2,
+        }
+    }
+    match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) => a.cmp(&b),
+        (Value::String(a), Value::String(b)) => a.as_bytes().cmp(b.as_bytes()),
+        _ if rank(a) == 0 && rank(b) == 0 => {
+            let (a, b) = (a.to_number().unwrap(), b.to_number().unwrap());
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+impl<'de, 'gc> de::Deserializer<'de> for Deserializer<'gc> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Nil => visitor.visit_unit(),
+            Value::Boolean(b) => visitor.visit_bool(b),
+            Value::Integer(i) => visitor.visit_i64(i),
+            Value::Number(n) => visitor.visit_f64(n),
+            Value::String(s) => match s.to_str() {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => visitor.visit_bytes(s.as_bytes()),
+            },
+            Value::Table(t) if self.is_array(t) => self.visit_seq(t, visitor),
+            Value::Table(t) => self.visit_map(t, visitor),
+            value if is_null(value) => visitor.visit_unit(),
+            _ if self.options.deny_unsupported_types => Err(self.unsupported()),
+            _ => visitor.visit_unit(),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Nil => visitor.visit_none(),
+            value if is_null(value) => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    /// Numbers are read as strings too, as Lua converts them.
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Integer(i) => visitor.visit_string(i.to_string()),
+            Value::Number(n) => visitor.visit_string(ops::number_to_string(n)),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Table(t) => self.visit_seq(t, visitor),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Table(t) => self.visit_map(t, visitor),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// A unit variant is its name, any other a table with the name as its only key.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.value {
+            Value::String(s) => {
+                let variant = s.to_str().map_err(Error::new)?;
+                visitor.visit_enum(variant.into_deserializer())
+            }
+            Value::Table(t) => {
+                let mut entries = self.entries(t).into_iter();
+                match (entries.next(), entries.next()) {
+                    (Some((Value::String(variant), value)), None) => self.enter(t, || {
+                        visitor.visit_enum(EnumDeserializer {
+                            variant,
+                            value: self.nested(value),
+                        })
+                    }),
+                    _ => Err(Error::new(format!(
+                        "table for {name} must have the variant name as its only key"
+                    ))),
+                }
+            }
+            _ => Err(Error::new(format!(
+                "string or table expected for {name}, got {}",
+                self.value.type_name()
+            ))),
+        }
+    }
+
+    ::serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit unit_struct
+        identifier ignored_any
+    }
+}
+
+struct SeqDeserializer<'a, 'gc> {
+    deserializer: &'a Deserializer<'gc>,
+    table: Table<'gc>,
+    index: usize,
+    len: usize,
+}
+
+impl<'de, 'a, 'gc> de::SeqAccess<'de> for SeqDeserializer<'a, 'gc> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.index > self.len {
+            return Ok(None);
+        }
+        let value = self.table.get(self.index as i64);
+        self.index += 1;
+        seed.deserialize(self.deserializer.nested(value)).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len + 1 - self.index)
+    }
+}
+
+struct MapDeserializer<'a, 'gc> {
+    deserializer: &'a Deserializer<'gc>,
+    entries: std::vec::IntoIter<(Value<'gc>, Value<'gc>)>,
+    value: Option<Value<'gc>>,
+}
+
+impl<'de, 'a, 'gc> de::MapAccess<'de> for MapDeserializer<'a, 'gc> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some(value);
+        seed.deserialize(self.deserializer.nested(key)).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = self
+            .value
+            .take()
+            .expect("next_value_seed called before next_key_seed");
+        seed.deserialize(self.deserializer.nested(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct EnumDeserializer<'gc> {
+    variant: LuaString<'gc>,
+    value: Deserializer<'gc>,
+}
+
+impl<'de, 'gc> de::EnumAccess<'de> for EnumDeserializer<'gc> {
+    type Error = Error;
+    type Variant = Deserializer<'gc>;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Deserializer<'gc>), Error> {
+        let name = self.variant.to_str().map_err(Error::new)?;
+        let variant = seed.deserialize(name.into_deserializer())?;
+        Ok((variant, self.value))
+    }
+}
+
+impl<'de, 'gc> de::VariantAccess<'de> for Deserializer<'gc> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ::serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::Lua;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect { w: i64, h: i64 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Scene {
+        name: String,
+        shapes: Vec<Shape>,
+        tags: Vec<String>,
+        origin: (i64, i64),
+        parent: Option<String>,
+    }
+
+    #[test]
+    fn round_trip() {
+        Lua::new().enter(|ctx| {
+            let scene = Scene {
+                name: "demo".into(),
+                shapes: vec![Shape::Point, Shape::Circle(1.5), Shape::Rect { w: 2, h: 3 }],
+                tags: vec![],
+                origin: (0, -1),
+                parent: None,
+            };
+            let value = to_value(ctx, &scene).unwrap();
+            ctx.globals()
+                .set(&ctx, LuaString::new(&ctx, b"scene"), value)
+                .unwrap();
+            let described = ctx
+                .eval(
+                    "local s = scene.shapes
+                     return scene.name, s[1], s[2].Circle, s[3].Rect.h, #scene.tags, scene.parent",
+                )
+                .unwrap();
+            let described: Vec<_> = described.iter().map(|v| v.to_string()).collect();
+            assert_eq!(described, ["demo", "Point", "1.5", "3", "0", "nil"]);
+            assert_eq!(from_value::<Scene>(ctx, value).unwrap(), scene);
+        });
+    }
+
+    /// Enough of a self-describing value to see what tables read as.
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(untagged)]
+    enum Any {
+        Unit(()),
+        Int(i64),
+        Str(String),
+        Seq(Vec<Any>),
+        Map(BTreeMap<String, Any>),
+        Indexed(BTreeMap<i64, Any>),
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        Lua::new().enter(|ctx| {
+            let json = ::serde_json::json!({"a": [1, null, 2.5], "b": {}, "c": []});
+            let value = from_json(ctx, &json).unwrap();
+            assert_eq!(to_json(ctx, value).unwrap(), json);
+            let value = ctx.eval("return {[1] = 'x', [2.5] = false}").unwrap()[0];
+            assert_eq!(
+                to_json(ctx, value).unwrap(),
+                ::serde_json::json!({"1": "x", "2.5": false})
+            );
+        });
+    }
+
+    #[test]
+    fn options() {
+        Lua::new().enter(|ctx| {
+            let holes = vec![Some(1), None, Some(3)];
+            let options = SerializeOptions {
+                none_as_null: true,
+                ..SerializeOptions::default()
+            };
+            let value = to_value_with(ctx, &holes, options).unwrap();
+            ctx.globals()
+                .set(&ctx, LuaString::new(&ctx, b"holes"), value)
+                .unwrap();
+            let described = ctx.eval("return #holes, tostring(holes[2])").unwrap();
+            assert_eq!(described[0], Value::Integer(3));
+            assert_eq!(described[1].to_string(), "null");
+            assert_eq!(from_value::<Vec<Option<i64>>>(ctx, value).unwrap(), holes);
+
+            let read = |source: &str, options: DeserializeOptions| {
+                let value = ctx.eval(source).unwrap()[0];
+                from_value_with::<Any>(ctx, value, options).map_err(|err| err.to_string())
+            };
+            let defaults = DeserializeOptions::default();
+            let seq = |items: Vec<Any>| Ok(Any::Seq(items));
+            assert_eq!(
+                read("return {'a', 'b'}", defaults),
+                seq(vec![Any::Str("a".into()), Any::Str("b".into())])
+            );
+            assert_eq!(read("return {}", defaults), Ok(Any::Map(BTreeMap::new())));
+            let marked = to_value(ctx, &Vec::<i64>::new()).unwrap();
+            assert_eq!(
+                from_value::<Any>(ctx, marked),
+                seq(vec![]).map_err(Error::new)
+            );
+            let undetected = DeserializeOptions {
+                detect_arrays: false,
+                ..defaults
+            };
+            let map = read("return {10}", undetected).unwrap();
+            assert_eq!(map, Any::Indexed(BTreeMap::from([(1, Any::Int(10))])));
+
+            let lenient = DeserializeOptions {
+                deny_unsupported_types: false,
+                ..defaults
+            };
+            assert_eq!(
+                read("return {f = print}", defaults),
+                Err("cannot represent a function".into())
+            );
+            assert_eq!(
+                read("return {f = print}", lenient),
+                Ok(Any::Map(BTreeMap::new()))
+            );
+            assert_eq!(
+                read("local t = {}; t.t = t; return t", lenient),
+                Err("cannot represent a table that contains itself".into())
+            );
+            let shallow = DeserializeOptions {
+                max_depth: 2,
+                ..defaults
+            };
+            assert_eq!(
+                read("return {{{}}}", shallow),
+                Err("tables nested more than 2 levels deep".into())
+            );
+            let nested = vec![vec![vec![1]]];
+            let shallow = SerializeOptions {
+                max_depth: 2,
+                ..SerializeOptions::default()
+            };
+            assert_eq!(
+                to_value_with(ctx, &nested, shallow)
+                    .unwrap_err()
+                    .to_string(),
+                "values nested more than 2 levels deep"
+            );
+        });
+    }
+}
@@ -0,0 +1,104 @@
+//! Typed handles to Lua values that the host keeps between calls into the state.
+
+use crate::convert::{FromLua, IntoLua};
+use crate::{AnyUserData, Context, ConversionError, Function, RegistryKey, Table, Thread, Value};
+
+/// A Lua value that can be stashed: kept in the registry, behind a handle that lives outside of
+/// [`Lua::enter`](crate::Lua::enter).
+pub trait Stashable<'gc> {
+    type Stashed: Fetchable;
+
+    fn stash(self, ctx: Context<'gc>) -> Self::Stashed;
+}
+
+/// A handle to a stashed value, which gets the value back inside [`Lua::enter`](crate::Lua::enter).
+pub trait Fetchable {
+    type Fetched<'gc>;
+
+    /// # Panics
+    ///
+    /// If the value was stashed in another state.
+    fn fetch<'gc>(&self, ctx: Context<'gc>) -> Self::Fetched<'gc>;
+}
+
+impl<'gc> Stashable<'gc> for Value<'gc> {
+    type Stashed = RegistryKey;
+
+    fn stash(self, ctx: Context<'gc>) -> RegistryKey {
+        ctx.create_registry_value(self)
+    }
+}
+
+impl Fetchable for RegistryKey {
+    type Fetched<'gc> = Value<'gc>;
+
+    fn fetch<'gc>(&self, ctx: Context<'gc>) -> Value<'gc> {
+        ctx.registry_value(self)
+    }
+}
+
+macro_rules! stashed {
+    ($($(#[$doc:meta])* $stashed:ident => $ty:ident / $variant:ident),* $(,)?) => {$(
+        $(#[$doc])*
+        #[derive(Debug)]
+        pub struct $stashed(RegistryKey);
+
+        impl<'gc> Stashable<'gc> for $ty<'gc> {
+            type Stashed = $stashed;
+
+            fn stash(self, ctx: Context<'gc>) -> $stashed {
+                $stashed(ctx.create_registry_value(Value::$variant(self)))
+            }
+        }
+
+        impl Fetchable for $stashed {
+            type Fetched<'gc> = $ty<'gc>;
+
+            fn fetch<'gc>(&self, ctx: Context<'gc>) -> $ty<'gc> {
+                match ctx.registry_value(&self.0) {
+                    Value::$variant(v) => v,
+                    _ => unreachable!("the registry holds the stashed value"),
+                }
+            }
+        }
+
+        impl<'gc> FromLua<'gc> for $stashed {
+            fn from_lua(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, ConversionError> {
+                Ok($ty::from_lua(ctx, value)?.stash(ctx))
+            }
+        }
+
+        impl<'gc, 'a> IntoLua<'gc> for &'a $stashed {
+            fn into_lua(self, ctx: Context<'gc>) -> Result<Value<'gc>, ConversionError> {
+                Ok(Value::$variant(self.fetch(ctx)))
+            }
+        }
+    )*};
+}
+
+stashed! {
+    /// A stashed [`Table`].
+    StashedTable => Table / Table,
+    /// A stashed [`Function`], which [`Lua::call`](crate::Lua::call) calls.
+    StashedFunction => Function / Function,
+    /// A stashed [`Thread`].
+    StashedThread => Thread / Thread,
+    /// A stashed [`AnyUserData`].
+    StashedUserData => AnyUserData / UserData,
+}
+
+impl<'gc> Context<'gc> {
+    /// Keeps `value` alive behind a typed handle, until the handle is dropped.
+    pub fn stash<S: Stashable<'gc>>(self, value: S) -> S::Stashed {
+        value.stash(self)
+    }
+
+    /// The value behind a handle made with [`Context::stash`].
+    ///
+    /// # Panics
+    ///
+    /// If the value was stashed in another state.
+    pub fn fetch<F: Fetchable>(self, stashed: &F) -> F::Fetched<'gc> {
+        stashed.fetch(self)
+    }
+}
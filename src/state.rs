@@ -0,0 +1,526 @@
+use std::any::TypeId;
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::app_data::AppData;
+use crate::compiler::CompatLevel;
+use crate::executor::ExecutorSlot;
+use crate::mem::{
+    Finalization, Gc, GcWeak, Lock, Managed, Mutation, RefLock, Rootable, Tracer, WeakSet,
+};
+use crate::registry::RegistrySlots;
+use crate::stdlib::pattern::PatternCache;
+use crate::stdlib::random::{entropy_seed, Random};
+use crate::stdlib::{OpenFiles, Searcher};
+use crate::string::StringData;
+use crate::vm;
+use crate::{LuaError, LuaString, MaybeSend, RegistryKey, RuntimeError, Table, TableState};
+
+/// Everything a running Lua state keeps alive: the root of its arena.
+pub struct State<'gc> {
+    pub globals: Table<'gc>,
+    pub(crate) registry: Table<'gc>,
+    registry_slots: RegistrySlots,
+    finalizers: Gc<'gc, RefLock<Finalizers<'gc>>>,
+    string_metatable: Gc<'gc, Lock<Option<Table<'gc>>>>,
+    string_metatable_locked: Cell<bool>,
+    /// Whether precompiled chunks can be loaded.
+    binary_chunks: Cell<bool>,
+    /// Whether binary chunks are verified as they are loaded.
+    verify_bytecode: Cell<bool>,
+    /// Whether error positions give the column after the line.
+    error_columns: Cell<bool>,
+    compat: Cell<CompatLevel>,
+    /// The hook of threads without their own, and how many times it has been set.
+    hook: Gc<'gc, Lock<Option<vm::Hook<'gc>>>>,
+    hook_version: Cell<u32>,
+    /// How many re-entrant calls into the interpreter are in progress, across all threads.
+    nesting: Cell<usize>,
+    /// Whether `pairs` visits keys in sorted order.
+    sorted_iteration: Cell<bool>,
+    /// The host's clocks, which the os library reads in place of the system's when set.
+    time: Cell<Option<i64>>,
+    clock: Cell<Option<f64>>,
+    /// Whether `math.randomseed()` may seed from the system.
+    entropy: Cell<bool>,
+    max_call_depth: Cell<usize>,
+    native_stack_limit: Cell<usize>,
+    catch_panics: Cell<bool>,
+    fuel: vm::Fuel,
+    /// The message of the error raised for going over the memory limit, made up front so that
+    /// raising it needn't allocate.
+    memory_error: LuaString<'gc>,
+    /// The strings [`Context::intern`] has made, for as long as something else holds them.
+    pub(crate) interned: Gc<'gc, RefLock<WeakSet<'gc, StringData>>>,
+    /// An address on the native stack taken as the outermost call into the interpreter began.
+    stack_base: Cell<usize>,
+    pattern_cache: RefCell<PatternCache>,
+    /// The generator behind `math.random`.
+    random: Cell<Random>,
+    /// The streams behind the io library's open files.
+    files: RefCell<OpenFiles>,
+    /// The host's searchers for `require`, which the package library's refer to by index.
+    searchers: RefCell<Vec<Box<dyn Searcher>>>,
+    /// Where `print` and the io library's standard streams write, and where errors nothing catches
+    /// are reported.
+    stdout: OutputSink,
+    stderr: OutputSink,
+    /// The metatables of userdata types, built from their `register` methods as first needed.
+    userdata_metatables: RefCell<HashMap<TypeId, RegistryKey>>,
+    executor: RefCell<ExecutorSlot>,
+    /// Host values stored with `Context::set_app_data`.
+    app_data: AppData,
+}
+
+impl<'gc> State<'gc> {
+    pub fn new(mc: &Mutation<'gc>) -> State<'gc> {
+        State {
+            globals: Table::new(mc),
+            registry: Table::new(mc),
+            registry_slots: RegistrySlots::default(),
+            finalizers: Gc::new(mc, RefLock::default()),
+            string_metatable: Gc::new(mc, Lock::new(None)),
+            string_metatable_locked: Cell::new(false),
+            binary_chunks: Cell::new(true),
+            verify_bytecode: Cell::new(false),
+            error_columns: Cell::new(false),
+            compat: Cell::new(CompatLevel::default()),
+            hook: Gc::new(mc, Lock::new(None)),
+            hook_version: Cell::new(0),
+            nesting: Cell::new(0),
+            sorted_iteration: Cell::new(false),
+            time: Cell::new(None),
+            clock: Cell::new(None),
+            entropy: Cell::new(true),
+            max_call_depth: Cell::new(vm::DEFAULT_MAX_CALL_DEPTH),
+            native_stack_limit: Cell::new(vm::DEFAULT_NATIVE_STACK_LIMIT),
+            catch_panics: Cell::new(true),
+            fuel: vm::Fuel::default(),
+            memory_error: LuaString::new(mc, b"not enough memory"),
+            interned: Gc::new(mc, RefLock::default()),
+            stack_base: Cell::new(0),
+            pattern_cache: RefCell::default(),
+            random: {
+                let (n1, n2) = entropy_seed();
+                Cell::new(Random::new(n1, n2))
+            },
+            files: RefCell::default(),
+            searchers: RefCell::default(),
+            stdout: OutputSink::new(Box::new(io::stdout())),
+            stderr: OutputSink::new(Box::new(io::stderr())),
+            userdata_metatables: RefCell::default(),
+            executor: RefCell::default(),
+            app_data: AppData::default(),
+        }
+    }
+
+    pub(crate) fn nesting(&self) -> &Cell<usize> {
+        &self.nesting
+    }
+
+    pub(crate) fn stack_base(&self) -> &Cell<usize> {
+        &self.stack_base
+    }
+
+    pub(crate) fn registry_slots(&self) -> &RegistrySlots {
+        &self.registry_slots
+    }
+
+    pub(crate) fn random(&self) -> &Cell<Random> {
+        &self.random
+    }
+
+    pub(crate) fn files(&self) -> &RefCell<OpenFiles> {
+        &self.files
+    }
+
+    pub(crate) fn searchers(&self) -> &RefCell<Vec<Box<dyn Searcher>>> {
+        &self.searchers
+    }
+
+    pub(crate) fn stdout(&self) -> &OutputSink {
+        &self.stdout
+    }
+
+    pub(crate) fn stderr(&self) -> &OutputSink {
+        &self.stderr
+    }
+
+    pub(crate) fn userdata_metatables(&self) -> &RefCell<HashMap<TypeId, RegistryKey>> {
+        &self.userdata_metatables
+    }
+
+    pub(crate) fn executor(&self) -> &RefCell<ExecutorSlot> {
+        &self.executor
+    }
+
+    pub(crate) fn fuel(&self) -> &vm::Fuel {
+        &self.fuel
+    }
+
+    pub(crate) fn hook(&self) -> (Option<vm::Hook<'gc>>, u32) {
+        (self.hook.get(), self.hook_version.get())
+    }
+
+    pub(crate) fn set_hook(&self, mc: &Mutation<'gc>, hook: Option<vm::Hook<'gc>>) {
+        self.hook.set(mc, hook);
+        self.hook_version
+            .set(self.hook_version.get().wrapping_add(1));
+    }
+
+    pub(crate) fn memory_error(&self) -> LuaString<'gc> {
+        self.memory_error
+    }
+
+    pub(crate) fn app_data(&self) -> &AppData {
+        &self.app_data
+    }
+
+    pub(crate) fn finalizers(&self) -> Gc<'gc, RefLock<Finalizers<'gc>>> {
+        self.finalizers
+    }
+
+    /// Moves the marked tables that nothing else reaches to the pending queue, keeping them alive
+    /// until their finalizers have run.
+    fn finalize(&self, fc: &Finalization<'gc>) {
+        let mut finalizers = self.finalizers.borrow_mut(fc);
+        let (dead, alive) = std::mem::take(&mut finalizers.marked)
+            .into_iter()
+            .partition::<Vec<_>, _>(|&table| fc.is_dead(table));
+        finalizers.marked = alive;
+        // The most recently marked table is finalized first.
+        for table in dead.into_iter().rev() {
+            let table = Table::from_inner(fc.resurrect(table));
+            table.borrow_mut(fc).marked_for_finalization = false;
+            finalizers.pending.push_back(table);
+        }
+    }
+}
+
+unsafe impl<'gc> Managed for State<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.globals.trace(tracer);
+        self.registry.trace(tracer);
+        self.finalizers.trace(tracer);
+        self.string_metatable.trace(tracer);
+        self.hook.trace(tracer);
+        self.memory_error.trace(tracer);
+        self.interned.trace(tracer);
+    }
+}
+
+/// The tables with `__gc` metamethods.
+#[derive(Default)]
+pub(crate) struct Finalizers<'gc> {
+    /// Tables marked for finalization, in the order they were marked. They are only held weakly,
+    /// so the collector can tell when nothing else reaches them.
+    pub(crate) marked: Vec<GcWeak<'gc, RefLock<TableState<'gc>>>>,
+    /// Unreachable tables whose finalizers are yet to run, in the order to run them.
+    pub(crate) pending: VecDeque<Table<'gc>>,
+}
+
+unsafe impl<'gc> Managed for Finalizers<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.marked.trace(tracer);
+        self.pending.trace(tracer);
+    }
+}
+
+/// A writer the host can swap out, shared by everything that writes to it so that swapping it
+/// redirects them all at once.
+#[derive(Clone)]
+pub(crate) struct OutputSink(Rc<RefCell<Box<dyn Write>>>);
+
+// SAFETY: the clones of a sink all belong to one state, and the writers put in it are `MaybeSend`.
+#[cfg(feature = "send")]
+unsafe impl Send for OutputSink {}
+
+impl OutputSink {
+    fn new(writer: Box<dyn Write>) -> OutputSink {
+        OutputSink(Rc::new(RefCell::new(writer)))
+    }
+
+    pub(crate) fn write_all(&self, data: &[u8]) -> io::Result<()> {
+        self.0.borrow_mut().write_all(data)
+    }
+
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Names [`State`] as the root of an [`Arena`](crate::mem::Arena).
+pub struct StateRoot;
+
+impl<'a> Rootable<'a> for StateRoot {
+    type Root = State<'a>;
+
+    fn finalize(fc: &Finalization<'a>, root: &'a State<'a>) {
+        root.finalize(fc);
+    }
+}
+
+/// Everything needed to run Lua code during a mutation: the mutation handle and the state.
+#[derive(Copy, Clone)]
+pub struct Context<'gc> {
+    mutation: &'gc Mutation<'gc>,
+    state: &'gc State<'gc>,
+}
+
+impl<'gc> Context<'gc> {
+    pub fn new(mutation: &'gc Mutation<'gc>, state: &'gc State<'gc>) -> Context<'gc> {
+        Context { mutation, state }
+    }
+
+    #[inline]
+    pub fn mutation(self) -> &'gc Mutation<'gc> {
+        self.mutation
+    }
+
+    #[inline]
+    pub fn state(self) -> &'gc State<'gc> {
+        self.state
+    }
+
+    #[inline]
+    pub fn globals(self) -> Table<'gc> {
+        self.state.globals
+    }
+
+    /// A string of `bytes` that is the same allocation as any other interned with them and still
+    /// in use, so that comparing interned strings needn't read their bytes. Interning doesn't keep
+    /// a string alive.
+    pub fn intern(self, bytes: &[u8]) -> LuaString<'gc> {
+        LuaString::intern(self.mutation, self.state.interned, bytes)
+    }
+
+    /// Makes `pairs` visit numbers in ascending order, then strings in byte order, then everything
+    /// else by type and in the order it was made, so that what scripts print doesn't depend on how
+    /// tables are laid out or where objects were allocated. Meant for tests: each step of such a
+    /// traversal looks at the whole table.
+    pub fn set_sorted_iteration(self, sorted: bool) {
+        self.state.sorted_iteration.set(sorted);
+    }
+
+    pub fn sorted_iteration(self) -> bool {
+        self.state.sorted_iteration.get()
+    }
+
+    /// Limits how many Lua calls deep a thread can go. A call past the limit raises a catchable
+    /// "stack overflow" error. The default is 200,000.
+    pub fn set_max_call_depth(self, depth: usize) {
+        self.state.max_call_depth.set(depth);
+    }
+
+    pub fn max_call_depth(self) -> usize {
+        self.state.max_call_depth.get()
+    }
+
+    /// Allows or refuses loading binary chunks, whatever mode `load` or the host asks for. Unless
+    /// bytecode is [verified](Context::set_verify_bytecode), a crafted chunk can make the
+    /// interpreter panic, so a sandbox takes only source. Allowed by default.
+    pub fn set_binary_chunks(self, allowed: bool) {
+        self.state.binary_chunks.set(allowed);
+    }
+
+    pub fn binary_chunks(self) -> bool {
+        self.state.binary_chunks.get()
+    }
+
+    /// Runs [`bytecode::verify`](crate::bytecode::verify) on each binary chunk as it is loaded,
+    /// refusing the ones that could index outside their function. Off by default, as it costs a
+    /// pass over the code, and on in a sandbox.
+    pub fn set_verify_bytecode(self, verify: bool) {
+        self.state.verify_bytecode.set(verify);
+    }
+
+    pub fn verify_bytecode(self) -> bool {
+        self.state.verify_bytecode.get()
+    }
+
+    /// Writes the positions runtime errors and `error` prefix to messages as `chunk:line:column:`
+    /// rather than `chunk:line:`, for chunks compiled with column information. The column is where
+    /// the expression or statement that failed starts, which tells one of many on a line apart, as
+    /// in generated or minified code. Off by default, as scripts may parse the reference
+    /// implementation's messages.
+    pub fn set_error_columns(self, columns: bool) {
+        self.state.error_columns.set(columns);
+    }
+
+    pub fn error_columns(self) -> bool {
+        self.state.error_columns.get()
+    }
+
+    /// Compiles the chunks loaded from now on as written for the version of Lua `compat` names.
+    /// Libraries opened afterwards add the functions of that version that later ones dropped.
+    pub fn set_compat_level(self, compat: CompatLevel) {
+        self.state.compat.set(compat);
+    }
+
+    pub fn compat_level(self) -> CompatLevel {
+        self.state.compat.get()
+    }
+
+    /// Limits how many bytes of native stack the interpreter may use for calls that re-enter it,
+    /// such as metamethods and functions called back from native code. Each re-entry uses a few
+    /// kilobytes at most, so the default of 1 MiB leaves room to spare on a thread with 2 MiB of
+    /// stack; a host running Lua on a smaller stack should lower it.
+    ///
+    /// Past the limit, or past 200 nested re-entries, a call raises a "C stack overflow" error
+    /// rather than let the process crash.
+    pub fn set_native_stack_limit(self, bytes: usize) {
+        self.state.native_stack_limit.set(bytes);
+    }
+
+    pub fn native_stack_limit(self) -> usize {
+        self.state.native_stack_limit.get()
+    }
+
+    /// Caps the heap at `bytes`, or lifts the cap with `None`, as a state starts out. Counted are
+    /// the objects of the heap along with the contents of strings and the storage of tables.
+    ///
+    /// Once scripts have allocated past the cap, the next instruction raises a "not enough memory"
+    /// error that `pcall` can catch. In a function called with [`Lua::call`](crate::Lua::call), or
+    /// run by another [`Executor`](crate::Executor), going past the cap outside of any protected
+    /// call first stops the function for a full collection, and the error is only raised if the
+    /// heap is still over the cap afterwards.
+    ///
+    /// Elsewhere garbage is only collected once the outermost call into the state returns, so until
+    /// then the heap stays over the cap: code handling the error can run, but each instruction that
+    /// follows an allocation raises it again. The collection that follows is a full one, after which
+    /// the state can be used as before.
+    ///
+    /// Native functions are not stopped partway, so the heap may go past the cap by as much as one
+    /// of them allocates.
+    pub fn set_memory_limit(self, bytes: Option<usize>) {
+        self.metrics().set_memory_limit(bytes);
+    }
+
+    pub fn memory_limit(self) -> Option<usize> {
+        self.metrics().memory_limit()
+    }
+
+    /// Whether a panic in a native function is caught and raised as a Lua error carrying a
+    /// [`PanicError`](crate::PanicError), failing only the script call it happened in. This is the
+    /// default; with it off, the panic unwinds through the interpreter into the host.
+    ///
+    /// A state whose native function panicked may be left with a borrowed userdata or a half-done
+    /// change of the host's own, but the interpreter stays usable.
+    pub fn set_catch_panics(self, catch: bool) {
+        self.state.catch_panics.set(catch);
+    }
+
+    pub fn catch_panics(self) -> bool {
+        self.state.catch_panics.get()
+    }
+
+    /// The metatable all strings share. The string library sets one up whose `__index` is the
+    /// library itself, so that `s:upper()` calls `string.upper(s)`.
+    pub fn string_metatable(self) -> Option<Table<'gc>> {
+        self.state.string_metatable.get()
+    }
+
+    /// Replaces the metatable all strings share, or removes it. Fails once it has been locked.
+    pub fn set_string_metatable(self, metatable: Option<Table<'gc>>) -> Result<(), RuntimeError> {
+        if self.is_string_metatable_locked() {
+            return Err(RuntimeError::new("cannot change a protected metatable"));
+        }
+        self.state.string_metatable.set(self.mutation, metatable);
+        Ok(())
+    }
+
+    /// Locks the string metatable for the rest of the state's life. Since every script in the state
+    /// shares it, a sandbox locks it so that no script can change how strings behave for the
+    /// others: it can no longer be replaced, and scripts are never handed it to change its fields.
+    pub fn lock_string_metatable(self) {
+        self.state.string_metatable_locked.set(true);
+    }
+
+    pub fn is_string_metatable_locked(self) -> bool {
+        self.state.string_metatable_locked.get()
+    }
+
+    /// Seeds the generator behind `math.random` as `math.randomseed(seed)` does, so that a host can
+    /// make the numbers scripts draw reproducible. A state starts out with a seed that differs from
+    /// run to run, and scripts can still reseed it.
+    pub fn set_random_seed(self, seed: i64) {
+        self.state.random.set(Random::new(seed, 0));
+    }
+
+    /// Lets `math.randomseed()` without arguments pick a seed that differs from run to run, as it
+    /// does by default. Without entropy it seeds with 0, so that a run can be repeated exactly.
+    pub fn set_entropy(self, entropy: bool) {
+        self.state.entropy.set(entropy);
+    }
+
+    pub fn entropy(self) -> bool {
+        self.state.entropy.get()
+    }
+
+    /// Makes `os.time` and `os.date` take `seconds` since the epoch as the current time, in place
+    /// of the system clock or the os library's [`time_source`](crate::stdlib::OsOptions), or goes
+    /// back to those with `None`. The time stands still until the host sets it again, say once per
+    /// step of a simulation.
+    pub fn set_time(self, seconds: Option<i64>) {
+        self.state.time.set(seconds);
+    }
+
+    pub fn time(self) -> Option<i64> {
+        self.state.time.get()
+    }
+
+    /// Makes `os.clock` return `seconds`, as [`Context::set_time`] does for `os.time`.
+    pub fn set_clock(self, seconds: Option<f64>) {
+        self.state.clock.set(seconds);
+    }
+
+    pub fn clock(self) -> Option<f64> {
+        self.state.clock.get()
+    }
+
+    /// Sends what `print` writes, and what the io library's `io.stdout` writes unless it was opened
+    /// on another stream, to `out` instead of the process's standard output. This is how a GUI or
+    /// a test captures a script's output; `out` can be a buffer, or a type whose `write` hands the
+    /// bytes to a callback.
+    pub fn set_stdout(self, out: impl Write + MaybeSend + 'static) {
+        *self.state.stdout.0.borrow_mut() = Box::new(out);
+    }
+
+    /// Sends what `io.stderr` writes, unless it was opened on another stream, and the errors
+    /// reported by [`Context::report_error`] to `out` instead of the process's standard error.
+    pub fn set_stderr(self, out: impl Write + MaybeSend + 'static) {
+        *self.state.stderr.0.borrow_mut() = Box::new(out);
+    }
+
+    /// Writes an error that nothing caught, with its traceback, to the state's standard error.
+    /// Errors raised by `__gc` metamethods are reported this way, and a host running scripts can
+    /// do the same with the errors they end with. Failing to write is ignored.
+    pub fn report_error(self, err: &LuaError<'gc>) {
+        let stderr = &self.state.stderr;
+        let _ = stderr
+            .write_all(format!("{err:#}\n").as_bytes())
+            .and_then(|()| stderr.flush());
+    }
+
+    /// The compiled patterns of the string library, whose limits the host can change.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache is already borrowed; the string library only holds it while looking a
+    /// pattern up.
+    pub fn pattern_cache(self) -> RefMut<'gc, PatternCache> {
+        self.state.pattern_cache.borrow_mut()
+    }
+}
+
+impl<'gc> Deref for Context<'gc> {
+    type Target = Mutation<'gc>;
+
+    #[inline]
+    fn deref(&self) -> &Mutation<'gc> {
+        self.mutation
+    }
+}
@@ -0,0 +1,1443 @@
+//! The basic functions, set directly in the globals table.
+
+use std::cmp::Ordering;
+use std::future;
+
+use crate::compiler::lexer::trim;
+use crate::compiler::CompatLevel;
+use crate::executor::{self, AsyncResults};
+use crate::lua::{load_chunk, load_chunk_from};
+use crate::vm::{self, ops, Stack};
+use crate::{
+    Closure, Context, Function, LuaError, LuaString, NativeReturn, RuntimeError, Table, Value,
+};
+
+use super::{arg_error, check_any, check_integer, set_function, to_string, type_error};
+
+pub fn load_base(ctx: Context<'_>) {
+    let globals = ctx.globals();
+    set_function(ctx, globals, "assert", assert);
+    set_function(ctx, globals, "collectgarbage", collectgarbage);
+    set_function(ctx, globals, "error", error);
+    set_function(ctx, globals, "getmetatable", getmetatable);
+    set_function(ctx, globals, "ipairs", ipairs);
+    set_function(ctx, globals, "load", load);
+    set_function(ctx, globals, "next", next);
+    set_function(ctx, globals, "pairs", pairs);
+    set_function(ctx, globals, "pcall", pcall);
+    set_function(ctx, globals, "print", print);
+    set_function(ctx, globals, "rawequal", rawequal);
+    set_function(ctx, globals, "rawget", rawget);
+    set_function(ctx, globals, "rawlen", rawlen);
+    set_function(ctx, globals, "rawset", rawset);
+    set_function(ctx, globals, "select", select);
+    set_function(ctx, globals, "setmetatable", setmetatable);
+    set_function(ctx, globals, "tonumber", tonumber);
+    set_function(ctx, globals, "tostring", tostring);
+    set_function(ctx, globals, "type", type_);
+    // Lua 5.1 had `table.unpack` as a global, and plenty of scripts still call it that way.
+    set_function(ctx, globals, "unpack", super::table::unpack);
+    set_function(ctx, globals, "xpcall", xpcall);
+    let version: &[u8] = match ctx.compat_level() {
+        CompatLevel::Lua51 => {
+            set_function(ctx, globals, "getfenv", getfenv);
+            set_function(ctx, globals, "loadstring", load);
+            set_function(ctx, globals, "setfenv", setfenv);
+            b"Lua 5.1"
+        }
+        CompatLevel::Lua53 => b"Lua 5.3",
+        CompatLevel::Lua54 => b"Lua 5.4",
+    };
+    super::loaded(ctx)
+        .set(&ctx, LuaString::new(&ctx, b"_G"), globals)
+        .expect("string keys are always valid");
+    for (key, value) in [
+        ("_G", Value::Table(globals)),
+        ("_VERSION", Value::String(LuaString::new(&ctx, version))),
+    ] {
+        globals
+            .set(&ctx, LuaString::new(&ctx, key.as_bytes()), value)
+            .expect("string keys are always valid");
+    }
+}
+
+/// `assert(v [, message, ...])`: returns all its arguments if `v` is true, and otherwise raises
+/// `message`, `"assertion failed!"` by default.
+fn assert<'gc>(_: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    check_any(stack, 1, "assert")?;
+    if stack.get(0).to_bool() {
+        return Ok(NativeReturn::Return);
+    }
+    match stack.get(1) {
+        Value::Nil => Err(RuntimeError::new("assertion failed!").into()),
+        message => Err(LuaError::new(message)),
+    }
+}
+
+/// `collectgarbage([opt [, ...]])`: controls the collector, with `opt` one of `"collect"` (the
+/// default), `"step"`, `"count"`, `"stop"`, `"restart"`, `"isrunning"`, `"incremental"`,
+/// `"setpause"` or `"setstepmul"`.
+///
+/// Nothing can be collected while Lua code runs. At the level of the coroutine an
+/// [`Executor`](crate::Executor) runs, as [`Lua::call`](crate::Lua::call) runs functions in,
+/// `"collect"` and `"step"` suspend it for the executor to do the work, and `"step"` returns
+/// whether that finished a cycle. Deeper down, in a protected call or another coroutine,
+/// `"collect"` leaves the collection to the next point the executor's coroutine stops at, and
+/// `"step"`, which couldn't tell what it did, raises an error. Without an executor both raise one.
+fn collectgarbage<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let metrics = ctx.metrics();
+    let int_arg = |n: usize| match stack.get(n) {
+        Value::Nil => Ok(0),
+        v => v.to_integer().ok_or_else(|| {
+            RuntimeError::new(format!(
+                "bad argument #{} to 'collectgarbage' (number expected, got {})",
+                n + 1,
+                v.type_name()
+            ))
+        }),
+    };
+    let option = match stack.get(0) {
+        Value::Nil => LuaString::new(&ctx, b"collect"),
+        Value::String(s) => s,
+        v => {
+            return Err(RuntimeError::new(format!(
+                "bad argument #1 to 'collectgarbage' (string expected, got {})",
+                v.type_name()
+            ))
+            .into())
+        }
+    };
+    let option = option.as_bytes();
+    let result = match option {
+        b"collect" => {
+            let suspends = can_suspend(ctx, stack)?;
+            metrics.request_full_collection();
+            if suspends {
+                yield_to_collect(ctx, stack, Box::new(|_| Ok(vec![Value::Integer(0)])));
+                return Ok(NativeReturn::Yield);
+            }
+            Value::Integer(0)
+        }
+        b"step" => {
+            if !can_suspend(ctx, stack)? {
+                return Err(RuntimeError::new(
+                    "attempt to step the collector across a C-call boundary",
+                )
+                .into());
+            }
+            let kbytes = int_arg(1)?;
+            let bytes = if kbytes > 0 {
+                (kbytes as usize).saturating_mul(1024)
+            } else {
+                metrics.pacing().min_step
+            };
+            metrics.request_work(bytes);
+            let cycles = metrics.cycles();
+            let finished: AsyncResults =
+                Box::new(move |ctx| Ok(vec![Value::Boolean(ctx.metrics().cycles() > cycles)]));
+            yield_to_collect(ctx, stack, finished);
+            return Ok(NativeReturn::Yield);
+        }
+        b"count" => Value::Number(metrics.total_allocation() as f64 / 1024.0),
+        b"stop" | b"restart" => {
+            metrics.set_running(option == b"restart");
+            Value::Integer(0)
+        }
+        b"isrunning" => Value::Boolean(metrics.is_running()),
+        b"incremental" => {
+            let mut pacing = metrics.pacing();
+            let (pause, step_multiplier, step_size) = (int_arg(1)?, int_arg(2)?, int_arg(3)?);
+            if pause > 0 {
+                pacing.pause = pause.min(u32::MAX as i64) as u32;
+            }
+            if step_multiplier > 0 {
+                pacing.step_multiplier = step_multiplier.min(u32::MAX as i64) as u32;
+            }
+            if step_size > 0 {
+                // The step size is given as a power of two, as in the reference implementation.
+                pacing.min_step = 1 << step_size.min(40);
+            }
+            metrics.set_pacing(pacing);
+            Value::String(LuaString::new(&ctx, b"incremental"))
+        }
+        b"setpause" | b"setstepmul" => {
+            let value = int_arg(1)?.clamp(0, u32::MAX as i64) as u32;
+            let mut pacing = metrics.pacing();
+            let field = if option == b"setpause" {
+                &mut pacing.pause
+            } else {
+                &mut pacing.step_multiplier
+            };
+            let previous = std::mem::replace(field, value);
+            metrics.set_pacing(pacing);
+            Value::Integer(previous as i64)
+        }
+        _ => {
+            return Err(RuntimeError::new(format!(
+                "bad argument #1 to 'collectgarbage' (invalid option '{}')",
+                String::from_utf8_lossy(option)
+            ))
+            .into())
+        }
+    };
+    stack.replace(&[result]);
+    Ok(NativeReturn::Return)
+}
+
+/// Whether the caller is at the level of the coroutine an executor runs, where it can be suspended
+/// for the executor to collect. Raises an error if no executor is running.
+fn can_suspend<'gc>(ctx: Context<'gc>, stack: &Stack<'gc, '_>) -> Result<bool, RuntimeError> {
+    if ctx.state().executor().borrow().thread.is_none() {
+        return Err(RuntimeError::new(
+            "attempt to collect garbage outside of an executor",
+        ));
+    }
+    let thread = stack.thread();
+    Ok(executor::drives(ctx, thread) && thread.is_yieldable(ctx))
+}
+
+/// Suspends the calling coroutine, which [`can_suspend`], so that the executor collects before
+/// resuming it with `results`.
+fn yield_to_collect<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>, results: AsyncResults) {
+    ctx.state().executor().borrow_mut().pending = Some(Box::pin(future::ready(Ok(results))));
+    stack.clear();
+}
+
+/// `error(message [, level])`: raises `message`, prefixed with the position of the function
+/// `level` frames up if it is a string.
+fn error<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let value = stack.get(0);
+    let level = match stack.get(1) {
+        Value::Nil => 1,
+        v => v
+            .to_integer()
+            .ok_or_else(|| RuntimeError::new("bad argument #2 to 'error' (number expected)"))?,
+    };
+    if let (Value::String(message), true) = (value, level > 0) {
+        if let Some(location) = stack.thread().location(ctx, level as usize) {
+            let mut bytes = format!("{location} ").into_bytes();
+            bytes.extend_from_slice(message.as_bytes());
+            return Err(LuaError::new(Value::String(LuaString::from_vec(
+                &ctx, bytes,
+            ))));
+        }
+    }
+    Err(LuaError::new(value))
+}
+
+/// `load(chunk [, chunkname [, mode [, env]]])`: compiles `chunk`, a string or a function returning
+/// successive pieces of one, into a function whose `_ENV` is `env`, or the globals if it is absent.
+/// Binary chunks from `string.dump` are loaded as they are, unless `mode` doesn't contain `b`:
+/// passing `"t"` keeps untrusted code from handing the interpreter bytecode. Returns nil and a
+/// message if the chunk doesn't compile.
+fn load<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let chunk = stack.get(0);
+    let mode = match stack.get(2) {
+        Value::Nil => LuaString::new(&ctx, b"bt"),
+        Value::String(s) => s,
+        v => {
+            return Err(RuntimeError::new(format!(
+                "bad argument #3 to 'load' (string expected, got {})",
+                v.type_name()
+            ))
+            .into())
+        }
+    };
+    let env = if stack.len() >= 4 {
+        stack.get(3)
+    } else {
+        Value::Table(ctx.globals())
+    };
+
+    let mode = mode.as_bytes();
+    let name = |default: LuaString<'gc>| match stack.get(1) {
+        Value::String(s) => s,
+        _ => default,
+    };
+    let loaded = match chunk {
+        Value::String(s) => load_chunk(ctx, s.as_bytes(), name(s).as_bytes(), mode, env),
+        Value::Function(_) => {
+            // The chunk is compiled as the pieces come in. A failing reader ends the source, and
+            // its error is reported rather than whatever the truncated chunk would give.
+            let mut failure = None;
+            let reader = || {
+                read_piece(ctx, stack, chunk).unwrap_or_else(|err| {
+                    failure = Some(err);
+                    None
+                })
+            };
+            let name = name(LuaString::new(&ctx, b"=(load)"));
+            let loaded = load_chunk_from(ctx, reader, name.as_bytes(), mode, env);
+            if let Some(err) = failure {
+                stack.replace(&[Value::Nil, err]);
+                return Ok(NativeReturn::Return);
+            }
+            loaded
+        }
+        v => {
+            return Err(RuntimeError::new(format!(
+                "bad argument #1 to 'load' (string expected, got {})",
+                v.type_name()
+            ))
+            .into())
+        }
+    };
+    match loaded {
+        Ok(closure) => stack.replace(&[Value::Function(closure.into())]),
+        Err(message) => {
+            let message = Value::String(LuaString::new(&ctx, message.as_bytes()));
+            stack.replace(&[Value::Nil, message]);
+        }
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// Calls `reader` for the next piece of a chunk, `None` at the end. Errors are returned as the
+/// value to report.
+fn read_piece<'gc>(
+    ctx: Context<'gc>,
+    stack: &Stack<'gc, '_>,
+    reader: Value<'gc>,
+) -> Result<Option<Vec<u8>>, Value<'gc>> {
+    let results =
+        vm::protected_call(ctx, stack.thread(), reader, &[], None).map_err(|err| err.value(ctx))?;
+    match results.first().copied().unwrap_or_default() {
+        Value::Nil => Ok(None),
+        Value::String(s) => Ok(Some(s.as_bytes().to_vec())),
+        _ => {
+            let message = "reader function must return a string";
+            Err(Value::String(LuaString::new(&ctx, message.as_bytes())))
+        }
+    }
+}
+
+fn check_table<'gc>(value: Value<'gc>, n: usize, name: &str) -> Result<Table<'gc>, RuntimeError> {
+    match value {
+        Value::Table(t) => Ok(t),
+        v => Err(RuntimeError::new(format!(
+            "bad argument #{n} to '{name}' (table expected, got {})",
+            v.type_name()
+        ))),
+    }
+}
+
+/// The function argument 1 of `getfenv` or `setfenv` names: the function itself, or the Lua function
+/// that many levels up the stack, 1 by default. Level 0, the thread's environment, is `None`.
+fn fenv_target<'gc>(
+    stack: &Stack<'gc, '_>,
+    name: &str,
+) -> Result<Option<Function<'gc>>, RuntimeError> {
+    let level = match stack.get(0) {
+        Value::Function(f) => return Ok(Some(f)),
+        Value::Nil => 1,
+        _ => check_integer(stack, 1, name)?,
+    };
+    if level == 0 {
+        return Ok(None);
+    }
+    let frame = usize::try_from(level)
+        .ok()
+        .and_then(|level| stack.thread().frame_info(level));
+    match frame {
+        Some(frame) => Ok(Some(Function::Closure(frame.closure))),
+        None => Err(arg_error(1, name, "invalid level")),
+    }
+}
+
+/// The index of the `_ENV` upvalue of `f`, which stands in for the environment of Lua 5.1. A
+/// function that never names a global has none.
+fn env_upvalue(f: Function<'_>) -> Option<(Closure<'_>, usize)> {
+    let Function::Closure(closure) = f else {
+        return None;
+    };
+    let names = &closure.proto().upvalue_names;
+    let i = names.iter().position(|name| name.as_bytes() == b"_ENV")?;
+    Some((closure, i))
+}
+
+/// `getfenv([f])`, in Lua 5.1 mode: the value of the `_ENV` upvalue of `f`, or the globals for a
+/// function without one and for level 0.
+fn getfenv<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let env = match fenv_target(stack, "getfenv")?.and_then(env_upvalue) {
+        Some((closure, i)) => closure.upvalues()[i].value(),
+        None => Value::Table(ctx.globals()),
+    };
+    stack.replace(&[env]);
+    Ok(NativeReturn::Return)
+}
+
+/// `setfenv(f, table)`, in Lua 5.1 mode: sets the `_ENV` upvalue of `f` and returns `f`. Functions
+/// made by the same chunk share the upvalue, so they all see the change, unlike in Lua 5.1.
+fn setfenv<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let Value::Table(env) = stack.get(1) else {
+        return Err(type_error(stack, 2, "setfenv", "table").into());
+    };
+    let Some(f) = fenv_target(stack, "setfenv")? else {
+        return Err(
+            RuntimeError::new("'setfenv' cannot change the environment of a thread").into(),
+        );
+    };
+    match env_upvalue(f) {
+        Some((closure, i)) => closure.upvalues()[i].set_value(&ctx, Value::Table(env)),
+        None if !matches!(f, Function::Closure(_)) => {
+            let message = "'setfenv' cannot change the environment of given object";
+            return Err(RuntimeError::new(message).into());
+        }
+        None => {}
+    }
+    stack.replace(&[Value::Function(f)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `getmetatable(v)`: the metatable of `v`, or the value of its `__metatable` field if it has one.
+fn getmetatable<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    check_any(stack, 1, "getmetatable")?;
+    let result = match ops::metatable(ctx, stack.get(0)) {
+        Some(mt) => match mt.get_str("__metatable") {
+            Value::Nil => Value::Table(mt),
+            protected => protected,
+        },
+        None => Value::Nil,
+    };
+    stack.replace(&[result]);
+    Ok(NativeReturn::Return)
+}
+
+/// `ipairs(t)`: returns an iterator over `t[1]`, `t[2]`, ... up to the first nil, respecting
+/// `__index`.
+fn ipairs<'gc>(_: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    if stack.is_empty() {
+        return Err(RuntimeError::new(
+            "bad argument #1 to 'ipairs' (table expected, got no value)",
+        )
+        .into());
+    }
+    let t = stack.get(0);
+    stack.replace(&[
+        Value::Function(Function::Native(ipairs_step)),
+        t,
+        Value::Integer(0),
+    ]);
+    Ok(NativeReturn::Return)
+}
+
+fn ipairs_step<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let i = stack.get(1).to_integer().unwrap_or(0).wrapping_add(1);
+    let value = vm::index(ctx, stack.thread(), stack.get(0), Value::Integer(i))?;
+    if value.is_nil() {
+        stack.replace(&[Value::Nil]);
+    } else {
+        stack.replace(&[Value::Integer(i), value]);
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `next(t [, key])`: returns the entry of `t` after `key`, or its first entry if `key` is nil.
+fn next<'gc>(_: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let t = check_table(stack.get(0), 1, "next")?;
+    match ops::next(t, stack.get(1))? {
+        Some((key, value)) => stack.replace(&[key, value]),
+        None => stack.replace(&[Value::Nil]),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `pairs(t)`: returns `next, t, nil`, or the first three results of `t`'s `__pairs` metamethod
+/// called with `t`.
+fn pairs<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let t = stack.get(0);
+    let handler = ops::metamethod(ctx, t, "__pairs");
+    if !handler.is_nil() {
+        let mut results = vm::call(ctx, stack.thread(), handler, &[t])?;
+        results.resize(3, Value::Nil);
+        stack.replace(&results);
+        return Ok(NativeReturn::Return);
+    }
+    check_table(t, 1, "pairs")?;
+    let step: crate::NativeFn = if ctx.sorted_iteration() {
+        sorted_next
+    } else {
+        next
+    };
+    stack.replace(&[Value::Function(Function::Native(step)), t, Value::Nil]);
+    Ok(NativeReturn::Return)
+}
+
+/// Like `next`, but in the order [`Context::set_sorted_iteration`] describes: finds the smallest key
+/// after `key`, which doesn't depend on where entries are stored.
+fn sorted_next<'gc>(
+    _: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let t = check_table(stack.get(0), 1, "next")?;
+    let after = stack.get(1);
+    let mut best: Option<(Value<'gc>, Value<'gc>)> = None;
+    let mut key = Value::Nil;
+    while let Some((k, v)) = ops::next(t, key)? {
+        key = k;
+        let later = after.is_nil() || key_order(k, after) == Ordering::Greater;
+        if later && best.map_or(true, |(b, _)| key_order(k, b) == Ordering::Less) {
+            best = Some((k, v));
+        }
+    }
+    match best {
+        Some((key, value)) => stack.replace(&[key, value]),
+        None => stack.replace(&[Value::Nil]),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// A total order over table keys: booleans, then numbers, then strings, then the rest by type and
+/// the order they were made in. Rust functions, which aren't allocated, come before the other
+/// functions, ordered by address, which only the build decides.
+fn key_order(a: Value<'_>, b: Value<'_>) -> Ordering {
+    fn rank(v: Value<'_>) -> (u8, u32, *const ()) {
+        match v {
+            Value::Nil => (0, 0, std::ptr::null()),
+            Value::Boolean(_) => (1, 0, std::ptr::null()),
+            Value::Integer(_) | Value::Number(_) => (2, 0, std::ptr::null()),
+            Value::String(_) => (3, 0, std::ptr::null()),
+            Value::Table(t) => (4, t.serial(), t.as_ptr()),
+            Value::Function(f) => match f.serial() {
+                Some(serial) => (6, serial, f.as_ptr()),
+                None => (5, 0, f.as_ptr()),
+            },
+            Value::Thread(t) => (7, t.serial(), t.as_ptr()),
+            Value::UserData(u) => (8, u.serial(), u.as_ptr()),
+        }
+    }
+    match (a, b) {
+        (Value::Boolean(a), Value::Boolean(b)) => a.cmp(&b),
+        (Value::String(a), Value::String(b)) => a.as_bytes().cmp(b.as_bytes()),
+        (Value::Integer(_) | Value::Number(_), Value::Integer(_) | Value::Number(_)) => {
+            if ops::less_than(a, b) == Some(true) {
+                Ordering::Less
+            } else if ops::less_than(b, a) == Some(true) {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        }
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// `pcall(f, ...)`: calls `f`, returning `true` and its results, or `false` and the error value.
+fn pcall<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    if stack.is_empty() {
+        return Err(RuntimeError::new("bad argument #1 to 'pcall' (value expected)").into());
+    }
+    let args = stack[1..].to_vec();
+    let result = vm::protected_call(ctx, stack.thread(), stack.get(0), &args, None);
+    finish_protected(ctx, stack, result);
+    Ok(NativeReturn::Return)
+}
+
+/// `print(...)`: writes its arguments to the state's standard output, converted as `tostring` does, separated
+/// by tabs and followed by a newline.
+fn print<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let mut line = Vec::new();
+    for i in 0..stack.len() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        line.extend_from_slice(to_string(ctx, stack.thread(), stack.get(i))?.as_bytes());
+    }
+    line.push(b'\n');
+    // Like the reference implementation, `print` has no way to report a failed write.
+    let out = ctx.state().stdout();
+    let _ = out.write_all(&line).and_then(|()| out.flush());
+    stack.clear();
+    Ok(NativeReturn::Return)
+}
+
+/// `rawequal(a, b)`: compares two values without calling `__eq`.
+fn rawequal<'gc>(
+    _: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    check_any(stack, 1, "rawequal")?;
+    check_any(stack, 2, "rawequal")?;
+    let equal = stack.get(0) == stack.get(1);
+    stack.replace(&[Value::Boolean(equal)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `rawget(t, k)`: gets `t[k]` without calling `__index`.
+fn rawget<'gc>(_: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let t = check_table(stack.get(0), 1, "rawget")?;
+    check_any(stack, 2, "rawget")?;
+    let value = t.raw_get(stack.get(1));
+    stack.replace(&[value]);
+    Ok(NativeReturn::Return)
+}
+
+/// `rawlen(v)`: the length of a table or string, without calling `__len`.
+fn rawlen<'gc>(_: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let len = match stack.get(0) {
+        Value::Table(t) => t.raw_len(),
+        Value::String(s) => s.len(),
+        _ => {
+            return Err(
+                RuntimeError::new("bad argument #1 to 'rawlen' (table or string expected)").into(),
+            )
+        }
+    };
+    stack.replace(&[Value::Integer(len as i64)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `rawset(t, k, v)`: sets `t[k] = v` without calling `__newindex`, and returns `t`.
+fn rawset<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let t = check_table(stack.get(0), 1, "rawset")?;
+    check_any(stack, 2, "rawset")?;
+    check_any(stack, 3, "rawset")?;
+    t.raw_set(&ctx, stack.get(1), stack.get(2))
+        .map_err(|e| RuntimeError::new(e.to_string()))?;
+    stack.truncate(1);
+    Ok(NativeReturn::Return)
+}
+
+/// `select(n, ...)`: returns the arguments after the `n`th, counting from the end if `n` is
+/// negative, or their count if `n` is `'#'`.
+fn select<'gc>(_: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let count = stack.len().saturating_sub(1) as i64;
+    let n = match stack.get(0) {
+        Value::String(s) if s.as_bytes() == b"#" => {
+            stack.replace(&[Value::Integer(count)]);
+            return Ok(NativeReturn::Return);
+        }
+        v => v.to_integer().ok_or_else(|| {
+            RuntimeError::new(format!(
+                "bad argument #1 to 'select' (number expected, got {})",
+                v.type_name()
+            ))
+        })?,
+    };
+    let first = match n {
+        n if n < 0 && -n <= count => count + n,
+        n if n > 0 => (n - 1).min(count),
+        _ => {
+            return Err(
+                RuntimeError::new("bad argument #1 to 'select' (index out of range)").into(),
+            )
+        }
+    };
+    stack.copy_within(1 + first as usize.., 0);
+    stack.truncate((count - first) as usize);
+    Ok(NativeReturn::Return)
+}
+
+/// `setmetatable(t, mt)`: sets the metatable of the table `t`, or removes it if `mt` is nil, and
+/// returns `t`. A metatable with a `__metatable` field can't be changed.
+fn setmetatable<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let Value::Table(t) = stack.get(0) else {
+        return Err(type_error(stack, 1, "setmetatable", "table").into());
+    };
+    let mt = match stack.get(1) {
+        Value::Nil if stack.len() >= 2 => None,
+        Value::Table(mt) => Some(mt),
+        _ => return Err(type_error(stack, 2, "setmetatable", "nil or table").into()),
+    };
+    if t.metatable()
+        .is_some_and(|old| !old.get_str("__metatable").is_nil())
+    {
+        return Err(RuntimeError::new("cannot change a protected metatable").into());
+    }
+    if t.is_frozen() {
+        return Err(RuntimeError::new("cannot change the metatable of a frozen table").into());
+    }
+    ops::set_metatable(ctx, t, mt);
+    stack.truncate(1);
+    Ok(NativeReturn::Return)
+}
+
+/// `tonumber(v [, base])`: converts `v` to a number, or returns nil if it can't be. Without a base,
+/// numbers are returned as they are and strings convert like numerals in source. With one, `v` must
+/// be a string holding an integer written in that base, from 2 to 36.
+fn tonumber<'gc>(
+    _: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let result = match stack.get(1) {
+        Value::Nil => {
+            if stack.is_empty() {
+                return Err(
+                    RuntimeError::new("bad argument #1 to 'tonumber' (value expected)").into(),
+                );
+            }
+            ops::coerce_number(stack.get(0)).unwrap_or_default()
+        }
+        base => {
+            let base = ops::coerce_number(base)
+                .ok_or_else(|| {
+                    RuntimeError::new(format!(
+                        "bad argument #2 to 'tonumber' (number expected, got {})",
+                        base.type_name()
+                    ))
+                })?
+                .to_integer()
+                .ok_or_else(|| {
+                    RuntimeError::new(
+                        "bad argument #2 to 'tonumber' (number has no integer representation)",
+                    )
+                })?;
+            let Value::String(s) = stack.get(0) else {
+                return Err(RuntimeError::new(format!(
+                    "bad argument #1 to 'tonumber' (string expected, got {})",
+                    stack.get(0).type_name()
+                ))
+                .into());
+            };
+            if !(2..=36).contains(&base) {
+                return Err(
+                    RuntimeError::new("bad argument #2 to 'tonumber' (base out of range)").into(),
+                );
+            }
+            parse_int_in_base(s.as_bytes(), base as u32).map_or(Value::Nil, Value::Integer)
+        }
+    };
+    stack.replace(&[result]);
+    Ok(NativeReturn::Return)
+}
+
+/// Parses an optionally signed integer in `base`, with surrounding whitespace allowed. Like integer
+/// numerals in hexadecimal, values too large for an integer wrap around.
+fn parse_int_in_base(s: &[u8], base: u32) -> Option<i64> {
+    let s = trim(s);
+    let (negative, digits) = match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: i64 = 0;
+    for &c in digits {
+        let d = (c as char).to_digit(base)?;
+        n = n.wrapping_mul(base as i64).wrapping_add(d as i64);
+    }
+    Some(if negative { n.wrapping_neg() } else { n })
+}
+
+/// `tostring(v)`: converts any value to a string, with its `__tostring` metamethod if it has one.
+fn tostring<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    check_any(stack, 1, "tostring")?;
+    let s = to_string(ctx, stack.thread(), stack.get(0))?;
+    stack.replace(&[Value::String(s)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `type(v)`: the name of the type of `v`.
+fn type_<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    check_any(stack, 1, "type")?;
+    let name = stack.get(0).type_name();
+    stack.replace(&[Value::String(LuaString::new(&ctx, name.as_bytes()))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `xpcall(f, handler, ...)`: like `pcall`, but passes errors through `handler` before the stack
+/// unwinds, and returns what it returns in place of the error value.
+fn xpcall<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let handler = stack.get(1);
+    if !matches!(handler, Value::Function(_)) {
+        return Err(RuntimeError::new(format!(
+            "bad argument #2 to 'xpcall' (function expected, got {})",
+            handler.type_name()
+        ))
+        .into());
+    }
+    let args: Vec<_> = stack.iter().skip(2).copied().collect();
+    let result = vm::protected_call(ctx, stack.thread(), stack.get(0), &args, Some(handler));
+    finish_protected(ctx, stack, result);
+    Ok(NativeReturn::Return)
+}
+
+fn finish_protected<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    result: Result<Vec<Value<'gc>>, LuaError<'gc>>,
+) {
+    match result {
+        Ok(results) => {
+            stack.replace(&[Value::Boolean(true)]);
+            stack.extend(results);
+        }
+        Err(err) => stack.replace(&[Value::Boolean(false), err.value(ctx)]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stdlib::testing::{run, run_in};
+    use crate::Lua;
+
+    #[test]
+    fn pcall_and_error() {
+        assert_eq!(
+            run("return pcall(function(a) return a, 2 end, 1)"),
+            "true, 1, 2"
+        );
+        assert_eq!(run("return pcall(error, 'plain', 0)"), "false, plain");
+        assert_eq!(
+            run("return pcall(load([[local function f() error('boom') end f()]], '=test'))"),
+            "false, test:1: boom"
+        );
+        assert_eq!(
+            run("return pcall(load([[local function f()\n error('caller', 2)\n end\n f()]], '=test'))"),
+            "false, test:4: caller"
+        );
+        assert_eq!(
+            run("local t = {} local ok, e = pcall(error, t) return ok, e == t"),
+            "false, true"
+        );
+        assert_eq!(
+            run("return pcall(load('return nil + 1', '=test'))"),
+            "false, test:1: attempt to perform arithmetic on a nil value"
+        );
+        assert_eq!(
+            run("return pcall(pcall)"),
+            "false, bad argument #1 to 'pcall' (value expected)"
+        );
+        assert_eq!(run("error({})"), "error: (error object is a table value)");
+    }
+
+    #[test]
+    fn select_and_varargs() {
+        assert_eq!(run("return select('#')"), "0");
+        assert_eq!(run("return select('#', nil, nil)"), "2");
+        assert_eq!(run("return select(2, 'a', 'b', 'c')"), "b, c");
+        assert_eq!(run("return select(-1, 'a', 'b', 'c')"), "c");
+        assert_eq!(run("return select(5, 'a')"), "");
+        assert_eq!(
+            run("return select(-2, 'a')"),
+            "error: bad argument #1 to 'select' (index out of range)"
+        );
+        assert_eq!(
+            run("local function count(...) return select('#', ...) end
+                return count(), count(nil), count(1, nil, nil), count(count())"),
+            "0, 1, 3, 1"
+        );
+        assert_eq!(
+            run("local function sum(...)
+                    local s = 0
+                    for i = 1, select('#', ...) do s = s + select(i, ...) end
+                    return s
+                end
+                return sum(1, 2, 3, 4)"),
+            "10"
+        );
+    }
+
+    #[test]
+    fn xpcall_handlers() {
+        assert_eq!(
+            run("return xpcall(function() error({code = 1}) end, function(e) return e.code + 1 end)"),
+            "false, 2"
+        );
+        assert_eq!(
+            run("return xpcall(function(a) return a end, error, 3)"),
+            "true, 3"
+        );
+        assert_eq!(
+            run("return xpcall(error, function() error('again', 0) end, 'x')"),
+            "false, again"
+        );
+        // A nearer pcall catches the error before the outer handler sees it.
+        assert_eq!(
+            run("return xpcall(function() return pcall(error, 'inner', 0) end, function() return 'handled' end)"),
+            "true, false, inner"
+        );
+        // The handler runs before the stack unwinds, so `error` can still find the failing frame.
+        assert_eq!(
+            run("return xpcall(load('\\n local x = nil + 1', '=test'), function(e) error(e .. '!', 2) end)"),
+            "false, test:2: test:2: attempt to perform arithmetic on a nil value!"
+        );
+    }
+
+    #[test]
+    fn load_chunks() {
+        assert_eq!(run("return load('return 1 + ...')(2)"), "3");
+        assert_eq!(
+            run("return load('x = ')"),
+            "nil, [string \"x = \"]:1: unexpected symbol near <eof>"
+        );
+        assert_eq!(
+            run("return pcall(load('\\nerror(\\'e\\')', '=chunk'))"),
+            "false, chunk:2: e"
+        );
+        assert_eq!(
+            run("return pcall(load('\\nerror(\\'e\\')', '@file.lua'))"),
+            "false, file.lua:2: e"
+        );
+        assert_eq!(
+            run("local parts = {'return ', '4', ' * 2'} local i = 0
+                return load(function() i = i + 1 return parts[i] end)()"),
+            "8"
+        );
+        // Tokens, long strings included, can be split anywhere between pieces.
+        assert_eq!(
+            run(
+                "local s = 'local a = ... return a * 2, [==[long\\nstring]==]'
+                local i = 0
+                return load(function() i = i + 1 return s:sub(i, i) end, '=chars')(21)"
+            ),
+            "42, long\nstring"
+        );
+        assert_eq!(
+            run("return load(function() return 1 end)"),
+            "nil, reader function must return a string"
+        );
+        assert_eq!(
+            run("local n = 0
+                return load(function()
+                    n = n + 1
+                    if n == 1 then return 'return 1 +' end
+                    error('lost connection', 0)
+                end)"),
+            "nil, lost connection"
+        );
+        assert_eq!(
+            run("return load('return 1', 'x', 'b')"),
+            "nil, attempt to load a text chunk (mode is 'b')"
+        );
+    }
+
+    #[test]
+    fn iteration() {
+        assert_eq!(
+            run("local t, sum = {1, 2, 3, x = 4, y = 5}, 0
+                for k, v in pairs(t) do sum = sum + v end
+                -- Clearing fields as they are visited is allowed.
+                for k in pairs(t) do t[k] = nil end
+                return sum, next(t)"),
+            "15, nil"
+        );
+        assert_eq!(
+            run("local t = setmetatable({}, {__index = function(_, i) if i < 4 then return i * 2 end end})
+                t[1] = 'one'
+                local s = ''
+                for i, v in ipairs(t) do s = s .. i .. '=' .. v .. ' ' end
+                return s"),
+            "1=one 2=4 3=6 "
+        );
+        assert_eq!(
+            run(
+                "local t = setmetatable({}, {__pairs = function(t) return function(_, k)
+                    if not k then return 1, 'custom' end
+                end, t, nil end})
+                for k, v in pairs(t) do return k, v end"
+            ),
+            "1, custom"
+        );
+        assert_eq!(
+            run("return pcall(next, {}, 'missing')"),
+            "false, invalid key to 'next'"
+        );
+        assert_eq!(
+            run("return pcall(pairs, 1)"),
+            "false, bad argument #1 to 'pairs' (table expected, got number)"
+        );
+
+        let mut lua = Lua::new();
+        lua.enter(|ctx| ctx.set_sorted_iteration(true));
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "local t = {b = 1, a = 2, [10] = 3, [2.5] = 4, [true] = 5, 'first'}
+                local keys = {}
+                for k in pairs(t) do keys[#keys + 1] = k end
+                return keys[1], keys[2], keys[3], keys[4], keys[5], keys[6]"
+            ),
+            "true, 1, 2.5, 10, a, b"
+        );
+    }
+
+    #[test]
+    fn length() {
+        assert_eq!(
+            run("local t = {1, 2, 3} t[5] = 5 return #'abc', #{}, #{n = 1}, #t"),
+            "3, 0, 0, 3"
+        );
+        assert_eq!(
+            run(
+                "local t = setmetatable({1, 2}, {__len = function(t, u) return t == u and 10 end})
+                return #t, rawlen(t), rawlen('four')"
+            ),
+            "10, 2, 4"
+        );
+        assert_eq!(
+            run("local s = setmetatable({}, {__len = function() return 'meta' end}) return #s"),
+            "meta"
+        );
+        assert_eq!(
+            run("return pcall(load('return #5', '=test'))"),
+            "false, test:1: attempt to get length of a number value"
+        );
+        assert_eq!(
+            run("return pcall(rawlen, 5)"),
+            "false, bad argument #1 to 'rawlen' (table or string expected)"
+        );
+    }
+
+    #[test]
+    fn coercions() {
+        assert_eq!(
+            run("return '10' + 1, '3.0' + 1, '0x10' * 2, ' 2 ' ^ 2, -'2', '10' // '3', '7' % 4"),
+            "11, 4.0, 32, 4.0, -2, 3, 3"
+        );
+        assert_eq!(
+            run("return pcall(load([[return 'a' + 1]], '=test'))"),
+            "false, test:1: attempt to perform arithmetic on a string value (constant 'a')"
+        );
+        assert_eq!(
+            run("return tonumber('0x1p4'), tonumber('  10  '), tonumber('1e1'), tonumber('.5'), tonumber(7)"),
+            "16.0, 10, 10.0, 0.5, 7"
+        );
+        assert_eq!(
+            run("return tonumber('1e'), tonumber('0x'), tonumber('inf'), tonumber('1 2'), tonumber({}), tonumber(nil)"),
+            "nil, nil, nil, nil, nil, nil"
+        );
+        assert_eq!(
+            run("return tonumber('9223372036854775808') == 2^63, tonumber('0xffffffffffffffff')"),
+            "true, -1"
+        );
+        assert_eq!(
+            run("return tonumber('10', 16), tonumber('ff', 16), tonumber(' -ZZ ', 36), tonumber('8', 8), tonumber('', 10), tonumber('1.0', 10)"),
+            "16, 255, -1295, nil, nil, nil"
+        );
+        assert_eq!(
+            run("return pcall(tonumber, 10, 16)"),
+            "false, bad argument #1 to 'tonumber' (string expected, got number)"
+        );
+        assert_eq!(
+            run("return pcall(tonumber, '10', 99)"),
+            "false, bad argument #2 to 'tonumber' (base out of range)"
+        );
+        assert_eq!(
+            run("return pcall(tonumber)"),
+            "false, bad argument #1 to 'tonumber' (value expected)"
+        );
+    }
+
+    #[test]
+    fn raw_access() {
+        let prelude = "local log = ''
+            local mt = {
+                __index = function(_, k) log = log .. 'index;' return 'meta' end,
+                __newindex = function(_, k, v) log = log .. 'newindex;' end,
+                __eq = function() log = log .. 'eq;' return true end,
+            }
+            local t = setmetatable({}, mt)
+            ";
+        assert_eq!(
+            run(&(prelude.to_owned()
+                + "rawset(t, 'k', 1)
+                return rawget(t, 'k'), rawget(t, 'other'), t.other, rawset(t, 2, 2) == t, log")),
+            "1, nil, meta, true, index;"
+        );
+        assert_eq!(
+            run(&(prelude.to_owned()
+                + "local u = setmetatable({}, mt)
+                return t == u, rawequal(t, u), rawequal(t, t), rawequal(t, {}), rawequal(1, 1.0), rawequal('a', 'a'), log")),
+            "true, false, true, false, true, true, eq;"
+        );
+        assert_eq!(
+            run("return pcall(rawset, {}, nil, 1)"),
+            "false, index is nil"
+        );
+        assert_eq!(
+            run("return pcall(rawget, 'str', 1)"),
+            "false, bad argument #1 to 'rawget' (table expected, got string)"
+        );
+        assert_eq!(
+            run("return pcall(rawequal, 1)"),
+            "false, bad argument #2 to 'rawequal' (value expected)"
+        );
+        assert_eq!(
+            run("return pcall(rawset, {}, 1)"),
+            "false, bad argument #3 to 'rawset' (value expected)"
+        );
+    }
+
+    #[test]
+    fn metatables() {
+        assert_eq!(
+            run("local mt = {}
+                local t = setmetatable({}, mt)
+                local same = getmetatable(t) == mt
+                setmetatable(t, nil)
+                return same, getmetatable(t), getmetatable(1), getmetatable('').__index == string"),
+            "true, nil, nil, true"
+        );
+        assert_eq!(
+            run("local t = setmetatable({}, {__metatable = 'locked'})
+                return getmetatable(t), pcall(setmetatable, t, {})"),
+            "locked, false, cannot change a protected metatable"
+        );
+        assert_eq!(
+            run("return pcall(setmetatable, 1, {})"),
+            "false, bad argument #1 to 'setmetatable' (table expected, got number)"
+        );
+        assert_eq!(
+            run("return pcall(setmetatable, {}, 1)"),
+            "false, bad argument #2 to 'setmetatable' (nil or table expected, got number)"
+        );
+    }
+
+    #[test]
+    fn conversions_and_assertions() {
+        assert_eq!(
+            run("return type(nil), type(1), type('s'), type({}), type(print or type), pcall(type)"),
+            "nil, number, string, table, function, false, bad argument #1 to 'type' (value expected)"
+        );
+        assert_eq!(
+            run(
+                "local t = setmetatable({}, {__tostring = function() return 'custom' end})
+                local named = tostring(setmetatable({}, {__name = 'Point'}))
+                return tostring(1), tostring(1.5), tostring(nil), tostring(t),
+                    named:match('^Point: 0x') ~= nil"
+            ),
+            "1, 1.5, nil, custom, true"
+        );
+        assert_eq!(
+            run(
+                "return pcall(tostring, setmetatable({}, {__tostring = function() return {} end}))"
+            ),
+            "false, '__tostring' must return a string"
+        );
+        assert_eq!(run("return assert(1, 'unused', 3)"), "1, unused, 3");
+        assert_eq!(
+            run("return pcall(assert, false)"),
+            "false, assertion failed!"
+        );
+        assert_eq!(
+            run("local e = {} local ok, err = pcall(assert, nil, e) return ok, err == e"),
+            "false, true"
+        );
+        assert_eq!(
+            run("return _G == _ENV, _VERSION, unpack({1, 2, 3}, 2)"),
+            "true, Lua 5.4, 2, 3"
+        );
+    }
+
+    #[test]
+    fn binary_chunks() {
+        assert_eq!(
+            run("local function f(a, b) return a * b, 'k', x end
+                x = 'global'
+                return load(string.dump(f))(6, 7)"),
+            "42, k, global"
+        );
+        assert_eq!(
+            run("return load(string.dump(function() return 1 end, true))()"),
+            "1"
+        );
+        assert_eq!(
+            run("return load('\\27Lua', '=bin')"),
+            "nil, bin: bad binary format (truncated chunk)"
+        );
+        assert_eq!(
+            run("return load(string.dump(function() end), 'f', 't')"),
+            "nil, attempt to load a binary chunk (mode is 't')"
+        );
+        assert_eq!(
+            run("return pcall(string.dump, pcall)"),
+            "false, unable to dump given function"
+        );
+    }
+
+    #[test]
+    fn custom_environments() {
+        // The chunk only sees what it is given, and its globals land in its own table.
+        let source = "
+            local env = {tostring = 'mine'}
+            local f = load('x = 1 return tostring, pcall', 'sandbox', 't', env)
+            local a, b = f()
+            return a, b, env.x, x
+        ";
+        assert_eq!(run(source), "mine, nil, 1, nil");
+        assert_eq!(
+            run("local f = load('return y', '=c', 't', nil) return pcall(f)"),
+            "false, c:1: attempt to index a nil value (upvalue '_ENV')"
+        );
+        assert_eq!(
+            run("local function f() local _ENV = {z = 2} return z end
+                 z = 1
+                 return f(), z"),
+            "2, 1"
+        );
+        assert_eq!(
+            run("local _ENV = {print = 5} function g() return print end return g()"),
+            "5"
+        );
+    }
+
+    #[test]
+    fn to_be_closed_variables() {
+        let prelude = "
+            local log = ''
+            local function closable(name)
+                return setmetatable({}, {__close = function(_, err)
+                    log = log .. name .. (err and '(' .. err .. ')' or '') .. ';'
+                end})
+            end
+            local bad = setmetatable({}, {__close = function() error('close', 0) end})
+        ";
+        let exits = "
+            do
+                local a <close> = closable('a')
+                local b, c <close> = 1, closable('c')
+                local d <close> = nil
+            end
+            for i = 1, 3 do
+                local x <close> = closable('x' .. i)
+                if i == 2 then break end
+            end
+            -- The fourth value of a generic `for` is closed when the loop ends.
+            for _ in next, {1}, nil, closable('n') do end
+            for i in function(_, i) return i < 3 and i + 1 or nil end, nil, 0, closable('b') do
+                if i == 2 then break end
+            end
+            local function f() local r <close> = closable('r') return 'ret', 1 end
+            local v, n = f()
+            do local g <close> = closable('g') goto out end
+            ::out::
+            return log, v, n
+        ";
+        assert_eq!(
+            run(&(prelude.to_owned() + exits)),
+            "c;a;x1;x2;n;b;r;g;, ret, 1"
+        );
+        assert_eq!(
+            run("load('for _ in next, {}, nil, 42 do end', '=test')()"),
+            "error: test:1: variable '(for state)' got a non-closable value"
+        );
+
+        let errors = "
+            local function f()
+                local a <close> = closable('a')
+                local b <close> = closable('b')
+                error('boom', 0)
+            end
+            local function g()
+                local a <close> = closable('a')
+                local b <close> = bad
+                error('boom', 0)
+            end
+            local function h()
+                local a <close> = closable('a')
+                local b <close> = bad
+                return 1
+            end
+            local r1, e1 = pcall(f)
+            local r2, e2 = pcall(g)
+            local r3, e3 = pcall(h)
+            return log, e1, e2, e3
+        ";
+        // An error in `__close` replaces the one being unwound for the variables closed after it.
+        assert_eq!(
+            run(&(prelude.to_owned() + errors)),
+            "b(boom);a(boom);a(close);a(close);, boom, close, close"
+        );
+    }
+
+    #[test]
+    fn finalizers() {
+        let mut lua = Lua::new();
+        run_in(
+            &mut lua,
+            "log = ''
+            local mt = {__gc = function(o) log = log .. o.name .. ';' end}
+            setmetatable({name = 'a'}, mt)
+            setmetatable({name = 'b'}, mt)
+            kept = setmetatable({name = 'kept'}, mt)
+            -- Only a `__gc` present when the metatable is set counts.
+            local late = {}
+            setmetatable({name = 'late'}, late)
+            late.__gc = mt.__gc
+            setmetatable({}, {__gc = function(o) count = (count or 0) + 1 saved = o end})",
+        );
+        lua.collect_all();
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "local s = saved saved = nil return log, count, s ~= nil"
+            ),
+            "b;a;, 1, true"
+        );
+        // The resurrected table is collected for good this time, without being finalized again.
+        lua.collect_all();
+        assert_eq!(run_in(&mut lua, "return log, count"), "b;a;, 1");
+    }
+
+    #[test]
+    fn collectgarbage() {
+        let mut lua = Lua::new();
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "collectgarbage('stop')
+                local mt = {__gc = function() collected = true end}
+                setmetatable({}, mt)
+                return collectgarbage('isrunning'), collectgarbage('count') > 0,
+                    collectgarbage('incremental', 150, 300, 14), collectgarbage('setpause', 120)"
+            ),
+            "false, true, incremental, 150"
+        );
+        let pacing = lua.metrics().pacing();
+        assert_eq!(
+            (pacing.pause, pacing.step_multiplier, pacing.min_step),
+            (120, 300, 1 << 14)
+        );
+
+        // Stopped, nothing happens until a collection is asked for explicitly, which takes the
+        // coroutine of an executor.
+        lua.enter(|_| ());
+        assert_eq!(run_in(&mut lua, "return collected"), "nil");
+        assert_eq!(
+            run_in(&mut lua, "collectgarbage()"),
+            "error: attempt to collect garbage outside of an executor"
+        );
+        let collect = lua.enter(|ctx| {
+            let source = "return collectgarbage(), collected";
+            ctx.stash(ctx.load("=collect", source).unwrap())
+        });
+        let results: (i64, bool) = lua.call(&collect, ()).unwrap();
+        assert_eq!(results, (0, true));
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "collectgarbage('restart') return collectgarbage('isrunning')"
+            ),
+            "true"
+        );
+        assert_eq!(
+            run_in(&mut lua, "collectgarbage('bogus')"),
+            "error: bad argument #1 to 'collectgarbage' (invalid option 'bogus')"
+        );
+    }
+
+    #[test]
+    fn weak_tables() {
+        let mut lua = Lua::new();
+        let count = |lua: &mut Lua, name: &'static str| {
+            lua.enter(|ctx| {
+                let Value::Table(t) = ctx.globals().get_str(name) else {
+                    panic!("{name} is not a table");
+                };
+                let (mut key, mut n) = (Value::Nil, 0);
+                while let Some((k, _)) = t.next(key).unwrap() {
+                    key = k;
+                    n += 1;
+                }
+                n
+            })
+        };
+        run_in(
+            &mut lua,
+            "keys = setmetatable({}, {__mode = 'k'})
+            values = setmetatable({}, {__mode = 'v'})
+            both = setmetatable({}, {__mode = 'kv'})
+            kept = {}
+            keys[kept] = 1
+            keys[{}] = 2
+            -- A value referring back to its own key doesn't keep the entry.
+            local cycle = {}
+            keys[cycle] = {cycle}
+            -- Values reachable only through other entries are kept once their key is.
+            chain = {}
+            keys[chain] = {}
+            keys[keys[chain]] = 'reached'
+            values[1] = kept
+            values[2] = {}
+            values[3] = function() end
+            values.s = 'strings are values'
+            both[kept] = {}
+            both[{}] = kept
+            local obj = setmetatable({}, {__gc = function(o) seen = {values.obj, keys[o]} end})
+            values.obj = obj
+            keys[obj] = 'finalized'",
+        );
+        lua.collect_all();
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return seen[1], seen[2], keys[keys[chain]], values[1] == kept"
+            ),
+            "nil, finalized, reached, true"
+        );
+        assert_eq!(count(&mut lua, "values"), 2);
+        assert_eq!(count(&mut lua, "both"), 0);
+        lua.collect_all();
+        assert_eq!(count(&mut lua, "keys"), 3);
+    }
+
+    #[test]
+    fn tracebacks() {
+        Lua::new().enter(|ctx| {
+            let source =
+                "local function f() error('boom') end\nlocal function g()\n f()\n end\ng()";
+            let err = ctx
+                .call(ctx.load("=test", source).unwrap(), &[])
+                .unwrap_err();
+            assert_eq!(
+                err.traceback(),
+                [
+                    "test:1: in function <test:1>",
+                    "test:3: in function <test:2>",
+                    "test:5: in main chunk",
+                ]
+            );
+            assert_eq!(
+                format!("{err:#}"),
+                "test:1: boom\nstack traceback:\n\ttest:1: in function <test:1>\n\
+                 \ttest:3: in function <test:2>\n\ttest:5: in main chunk"
+            );
+        });
+    }
+}
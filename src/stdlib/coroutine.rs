@@ -0,0 +1,289 @@
+//! The coroutine library, set as the `coroutine` global.
+
+use crate::vm::Stack;
+use crate::{
+    Context, LuaError, LuaString, NativeClosure, NativeReturn, RuntimeError, Table, Thread,
+    ThreadStatus, Value,
+};
+
+use super::{set_function, set_library, type_error};
+
+pub fn load_coroutine(ctx: Context<'_>) {
+    let coroutine = Table::new(&ctx);
+    set_function(ctx, coroutine, "close", close);
+    set_function(ctx, coroutine, "create", create);
+    set_function(ctx, coroutine, "isyieldable", isyieldable);
+    set_function(ctx, coroutine, "resume", resume);
+    set_function(ctx, coroutine, "running", running);
+    set_function(ctx, coroutine, "status", status);
+    set_function(ctx, coroutine, "wrap", wrap);
+    set_function(ctx, coroutine, "yield", yield_);
+    set_library(ctx, "coroutine", coroutine);
+}
+
+/// Argument `n` to `name` as a coroutine.
+fn check_coroutine<'gc>(
+    stack: &Stack<'gc, '_>,
+    n: usize,
+    name: &str,
+) -> Result<Thread<'gc>, RuntimeError> {
+    match stack.get(n - 1) {
+        Value::Thread(thread) => Ok(thread),
+        _ => Err(type_error(stack, n, name, "coroutine")),
+    }
+}
+
+/// The status of `co` as the thread `current` sees it.
+fn status_name<'gc>(co: Thread<'gc>, current: Thread<'gc>) -> &'static str {
+    match co.status() {
+        ThreadStatus::Suspended | ThreadStatus::Preempted => "suspended",
+        ThreadStatus::Running if co == current => "running",
+        // It resumed the coroutine that is running, directly or not.
+        ThreadStatus::Running => "normal",
+        ThreadStatus::Dead => "dead",
+    }
+}
+
+/// `coroutine.close(co)`: kills a suspended or dead coroutine, closing its pending to-be-closed
+/// variables. Returns true, or false and the error the coroutine died with or that closing raised.
+fn close<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let co = check_coroutine(stack, 1, "close")?;
+    if co.status() == ThreadStatus::Running {
+        let status = status_name(co, stack.thread());
+        return Err(RuntimeError::new(format!("cannot close a {status} coroutine")).into());
+    }
+    match co.close(ctx) {
+        Ok(()) => stack.replace(&[Value::Boolean(true)]),
+        Err(err) => stack.replace(&[Value::Boolean(false), err.value(ctx)]),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `coroutine.create(f)`: a new coroutine running `f`, suspended until first resumed.
+fn create<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let Value::Function(f) = stack.get(0) else {
+        return Err(type_error(stack, 1, "create", "function").into());
+    };
+    stack.replace(&[Value::Thread(Thread::with_function(&ctx, f))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `coroutine.isyieldable([co])`: whether `co`, the running coroutine by default, can yield.
+fn isyieldable<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let current = stack.thread();
+    let yieldable = match stack.get(0) {
+        Value::Nil => current.is_yieldable(ctx),
+        _ => {
+            let co = check_coroutine(stack, 1, "isyieldable")?;
+            if co == current {
+                co.is_yieldable(ctx)
+            } else {
+                co.is_coroutine()
+            }
+        }
+    };
+    stack.replace(&[Value::Boolean(yieldable)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `coroutine.resume(co, ...)`: runs `co` until it yields or returns. Returns true and the values
+/// passed to `yield` or returned, or false and the error if it couldn't be resumed or raised one.
+fn resume<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let co = check_coroutine(stack, 1, "resume")?;
+    let args: Vec<_> = (1..stack.len()).map(|i| stack.get(i)).collect();
+    match co.resume(ctx, &args) {
+        Ok(results) => {
+            stack.replace(&[Value::Boolean(true)]);
+            stack.extend(results);
+        }
+        Err(err) => stack.replace(&[Value::Boolean(false), err.value(ctx)]),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `coroutine.running()`: the running coroutine, and whether it is a main thread rather than a
+/// coroutine.
+fn running<'gc>(
+    _ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let thread = stack.thread();
+    stack.replace(&[
+        Value::Thread(thread),
+        Value::Boolean(!thread.is_coroutine()),
+    ]);
+    Ok(NativeReturn::Return)
+}
+
+/// `coroutine.status(co)`: `"running"`, `"suspended"`, `"normal"` (it resumed the running one) or
+/// `"dead"`.
+fn status<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let co = check_coroutine(stack, 1, "status")?;
+    let status = status_name(co, stack.thread());
+    stack.replace(&[Value::String(LuaString::new(&ctx, status.as_bytes()))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `coroutine.wrap(f)`: a function that resumes a new coroutine running `f` each time it is
+/// called, returning what the coroutine yields or returns. An error in the coroutine kills it and
+/// is raised again in the caller.
+fn wrap<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let Value::Function(f) = stack.get(0) else {
+        return Err(type_error(stack, 1, "wrap", "function").into());
+    };
+    let co = Value::Thread(Thread::with_function(&ctx, f));
+    let wrapper = NativeClosure::new(&ctx, wrapped, &[co]);
+    stack.replace(&[Value::Function(wrapper.into())]);
+    Ok(NativeReturn::Return)
+}
+
+/// The function `coroutine.wrap` returns, with the coroutine as its upvalue.
+fn wrapped<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let Value::Thread(co) = stack.upvalue(0) else {
+        unreachable!("wrapped coroutines are kept as the upvalue");
+    };
+    let args: Vec<_> = (0..stack.len()).map(|i| stack.get(i)).collect();
+    let mut err = match co.resume(ctx, &args) {
+        Ok(results) => {
+            stack.replace(&results);
+            return Ok(NativeReturn::Return);
+        }
+        Err(err) => err,
+    };
+    let mut value = err.value(ctx);
+    if co.status() == ThreadStatus::Dead {
+        // Run the pending to-be-closed variables now, which may replace the error.
+        if let Err(err) = co.close(ctx) {
+            value = err.value(ctx);
+        }
+    }
+    // Like other errors, a message gets the position of the call; other values pass unchanged.
+    if let (Value::String(message), Some(location)) = (value, stack.thread().location(ctx, 1)) {
+        let mut bytes = format!("{location} ").into_bytes();
+        bytes.extend_from_slice(message.as_bytes());
+        value = Value::String(LuaString::from_vec(&ctx, bytes));
+    }
+    // The traceback of the coroutine goes on into the caller's.
+    err.set_value(value);
+    Err(err)
+}
+
+/// `coroutine.yield(...)`: suspends the running coroutine, passing the arguments to the `resume`
+/// that ran it. The values passed to the next `resume` become the results.
+fn yield_<'gc>(
+    _ctx: Context<'gc>,
+    _stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    Ok(NativeReturn::Yield)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::testing::run;
+
+    #[test]
+    fn resuming_and_yielding() {
+        assert_eq!(
+            run("local co = coroutine.create(function(a, b)
+                    local c = coroutine.yield(a + b)
+                    local d, e = coroutine.yield(c * 2)
+                    return d + e, 'done'
+                end)
+                local r1 = {coroutine.resume(co, 1, 2)}
+                local r2 = {coroutine.resume(co, 10)}
+                local r3 = {coroutine.resume(co, 3, 4)}
+                local r4 = {coroutine.resume(co)}
+                return r1[2], r2[2], r3[2], r3[3], r4[1], r4[2], coroutine.status(co)"),
+            "3, 20, 7, done, false, cannot resume dead coroutine, dead"
+        );
+        assert_eq!(
+            run("local outer
+                local inner = coroutine.create(function()
+                    return coroutine.status(outer), coroutine.status(coroutine.running()),
+                        coroutine.isyieldable(), coroutine.isyieldable(outer)
+                end)
+                outer = coroutine.create(function() return coroutine.resume(inner) end)
+                local _, _, a, b, c, d = coroutine.resume(outer)
+                local main, ismain = coroutine.running()
+                return a, b, c, d, coroutine.status(outer), coroutine.isyieldable(),
+                    ismain, coroutine.status(main)"),
+            "normal, running, true, true, dead, false, true, running"
+        );
+        assert_eq!(
+            run("local gen = coroutine.wrap(function()
+                    for i = 1, 3 do coroutine.yield(i) end
+                end)
+                return gen(), gen(), gen()"),
+            "1, 2, 3"
+        );
+        assert_eq!(
+            run("return coroutine.yield(1)"),
+            "error: attempt to yield from outside a coroutine"
+        );
+    }
+
+    #[test]
+    fn errors_and_closing() {
+        // Messages from wrapped coroutines get the position of the call added; other values pass
+        // through unchanged.
+        assert_eq!(
+            run("return pcall(load([[
+                local f = coroutine.wrap(function() error('boom') end)
+                f()]], '=t'))"),
+            "false, t:2: t:1: boom"
+        );
+        assert_eq!(
+            run("return load([[
+                local obj = {}
+                local f = coroutine.wrap(function() error(obj) end)
+                local ok, err = pcall(f)
+                return ok, err == obj, pcall(f)]], '=t')()"),
+            "false, true, false, t:4: cannot resume dead coroutine"
+        );
+        assert_eq!(
+            run(
+                "local co = coroutine.create(function() error('inside', 0) end)
+                local a, b = coroutine.resume(co)
+                return a, b, coroutine.close(co)"
+            ),
+            "false, inside, false, inside"
+        );
+        assert_eq!(
+            run(
+                "local co = coroutine.create(function() coroutine.yield() end)
+                coroutine.resume(co)
+                local ok = coroutine.close(co)
+                return ok, coroutine.status(co), coroutine.close(co), coroutine.resume(co)"
+            ),
+            "true, dead, true, false, cannot resume dead coroutine"
+        );
+        assert_eq!(
+            run("local co
+                co = coroutine.create(function() return coroutine.close(co) end)
+                return coroutine.resume(co)"),
+            "false, cannot close a running coroutine"
+        );
+        assert_eq!(
+            run("return coroutine.resume(42)"),
+            "error: bad argument #1 to 'resume' (coroutine expected, got number)"
+        );
+    }
+}
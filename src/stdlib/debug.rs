@@ -0,0 +1,626 @@
+//! The debug library, set as the `debug` global.
+//!
+//! Its functions reach into other functions' locals and upvalues and past `__metatable` fields, so
+//! no script can keep anything from one that has them. [`Lua::new`](crate::Lua::new) leaves the
+//! library out; [`Lua::with_debug`](crate::Lua::with_debug) loads it.
+
+use crate::error::write_traceback;
+use crate::vm::{ops, Hook, HookMask, Stack};
+use crate::{
+    Context, Function, LuaError, LuaString, NativeReturn, RuntimeError, Table, Thread, Value,
+};
+
+use super::{
+    arg_error, check_any, check_integer, opt_integer, set_function, set_library, type_error,
+};
+
+pub fn load_debug(ctx: Context<'_>) {
+    let debug = Table::new(&ctx);
+    set_function(ctx, debug, "gethook", gethook);
+    set_function(ctx, debug, "getinfo", getinfo);
+    set_function(ctx, debug, "getlocal", getlocal);
+    set_function(ctx, debug, "getmetatable", getmetatable);
+    set_function(ctx, debug, "getregistry", getregistry);
+    set_function(ctx, debug, "getupvalue", getupvalue);
+    set_function(ctx, debug, "getuservalue", getuservalue);
+    set_function(ctx, debug, "sethook", sethook);
+    set_function(ctx, debug, "setlocal", setlocal);
+    set_function(ctx, debug, "setmetatable", setmetatable);
+    set_function(ctx, debug, "setupvalue", setupvalue);
+    set_function(ctx, debug, "setuservalue", setuservalue);
+    set_function(ctx, debug, "traceback", traceback);
+    set_library(ctx, "debug", debug);
+}
+
+/// The thread most functions take as an optional first argument, and how many arguments that took
+/// up.
+fn thread_arg<'gc>(stack: &Stack<'gc, '_>) -> (Thread<'gc>, usize) {
+    match stack.get(0) {
+        Value::Thread(thread) => (thread, 1),
+        _ => (stack.thread(), 0),
+    }
+}
+
+/// Argument `n` to `name` as a level on `thread`'s stack, which must be a Lua function's.
+fn check_level(
+    stack: &Stack<'_, '_>,
+    thread: Thread<'_>,
+    n: usize,
+    name: &str,
+) -> Result<usize, RuntimeError> {
+    let level = check_integer(stack, n, name)?;
+    usize::try_from(level)
+        .ok()
+        .filter(|&level| thread.frame_info(level).is_some())
+        .ok_or_else(|| arg_error(n, name, "level out of range"))
+}
+
+fn set_field<'gc>(ctx: Context<'gc>, table: Table<'gc>, key: &str, value: impl Into<Value<'gc>>) {
+    table
+        .set(&ctx, LuaString::new(&ctx, key.as_bytes()), value.into())
+        .expect("string keys are always valid");
+}
+
+fn string(ctx: Context<'_>, s: impl AsRef<[u8]>) -> Value<'_> {
+    Value::String(LuaString::new(&ctx, s.as_ref()))
+}
+
+/// `debug.gethook([thread])`: the thread's hook function, its mask and its count, or nil if it has
+/// none.
+fn gethook<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (thread, _) = thread_arg(stack);
+    let Some(hook) = thread.hook() else {
+        stack.replace(&[Value::Nil]);
+        return Ok(NativeReturn::Return);
+    };
+    let mut mask = String::new();
+    for (set, c) in [
+        (hook.mask.call, 'c'),
+        (hook.mask.ret, 'r'),
+        (hook.mask.line, 'l'),
+    ] {
+        if set {
+            mask.push(c);
+        }
+    }
+    stack.replace(&[
+        hook.function,
+        string(ctx, mask),
+        Value::Integer(hook.count.into()),
+    ]);
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.getinfo([thread,] f [, what])`: a table describing a function, given directly or as a
+/// level on the stack, or nil for a level with no function. The letters in `what` pick the fields,
+/// as in the reference implementation; all of them by default.
+fn getinfo<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (thread, arg) = thread_arg(stack);
+    let what = match stack.get(arg + 1) {
+        Value::Nil => b"flnSrtu".to_vec(),
+        _ => super::check_string(ctx, stack, arg + 2, "getinfo")?
+            .as_bytes()
+            .to_vec(),
+    };
+    if !what.iter().all(|c| b"SlnrutfL".contains(c)) {
+        return Err(arg_error(arg + 2, "getinfo", "invalid option").into());
+    }
+    // Level 0 is `getinfo` itself on the running thread.
+    let (function, frame) = match stack.get(arg) {
+        Value::Function(f) => (Some(f), None),
+        Value::Integer(_) | Value::Number(_) | Value::String(_) => {
+            let level = check_integer(stack, arg + 1, "getinfo")?;
+            match usize::try_from(level) {
+                Ok(0) => (None, None),
+                Ok(level) => match thread.frame_info(level) {
+                    Some(frame) => (Some(frame.closure.into()), Some(frame)),
+                    None => {
+                        stack.replace(&[Value::Nil]);
+                        return Ok(NativeReturn::Return);
+                    }
+                },
+                Err(_) => {
+                    stack.replace(&[Value::Nil]);
+                    return Ok(NativeReturn::Return);
+                }
+            }
+        }
+        _ => return Err(type_error(stack, arg + 1, "getinfo", "function or level").into()),
+    };
+    let closure = match function {
+        Some(Function::Closure(closure)) => Some(closure),
+        _ => None,
+    };
+    let proto = closure.map(|closure| closure.proto());
+
+    let info = Table::new(&ctx);
+    for c in what {
+        match c {
+            b'S' => {
+                let (short_src, what, defined, last_defined) = match proto {
+                    Some(p) => {
+                        let what = if p.line_defined == 0 { "main" } else { "Lua" };
+                        (
+                            p.chunk_name,
+                            what,
+                            p.line_defined.into(),
+                            p.last_line_defined.into(),
+                        )
+                    }
+                    None => (LuaString::new(&ctx, b"[C]"), "C", -1, -1),
+                };
+                // Only the shortened name survives compilation, so `source` is made from it.
+                let mut source = b"=".to_vec();
+                source.extend_from_slice(short_src.as_bytes());
+                set_field(ctx, info, "source", LuaString::from_vec(&ctx, source));
+                set_field(ctx, info, "short_src", short_src);
+                set_field(ctx, info, "what", string(ctx, what));
+                set_field(ctx, info, "linedefined", Value::Integer(defined));
+                set_field(ctx, info, "lastlinedefined", Value::Integer(last_defined));
+            }
+            b'l' => {
+                let line = frame.and_then(|frame| frame.current_line);
+                let line = line.map_or(-1, i64::from);
+                set_field(ctx, info, "currentline", Value::Integer(line));
+            }
+            b'u' => {
+                let (nups, nparams, vararg) = match (function, proto) {
+                    (_, Some(p)) => (p.upvalues.len(), p.num_params.into(), p.is_vararg),
+                    (Some(Function::NativeClosure(c)), None) => (c.upvalues().len(), 0, true),
+                    _ => (0, 0, true),
+                };
+                set_field(ctx, info, "nups", Value::Integer(nups as i64));
+                set_field(ctx, info, "nparams", Value::Integer(nparams));
+                set_field(ctx, info, "isvararg", Value::Boolean(vararg));
+            }
+            b'n' => match frame.and_then(|frame| frame.name) {
+                Some((kind, name)) => {
+                    set_field(ctx, info, "name", name);
+                    set_field(ctx, info, "namewhat", string(ctx, kind));
+                }
+                None => set_field(ctx, info, "namewhat", string(ctx, "")),
+            },
+            b'r' => {
+                set_field(ctx, info, "ftransfer", Value::Integer(0));
+                set_field(ctx, info, "ntransfer", Value::Integer(0));
+            }
+            b't' => {
+                let tail_call = frame.is_some_and(|frame| frame.tail_call);
+                set_field(ctx, info, "istailcall", Value::Boolean(tail_call));
+            }
+            b'f' => {
+                let function = function.map_or(Value::Nil, Value::Function);
+                set_field(ctx, info, "func", function);
+            }
+            b'L' => {
+                if let Some(p) = proto {
+                    let lines = Table::new(&ctx);
+                    for &line in p.line_info.iter() {
+                        lines
+                            .set(&ctx, Value::Integer(line.into()), Value::Boolean(true))
+                            .expect("integer keys are always valid");
+                    }
+                    set_field(ctx, info, "activelines", lines);
+                }
+            }
+            _ => unreachable!("options were checked"),
+        }
+    }
+    stack.replace(&[Value::Table(info)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.getlocal([thread,] f, local)`: the name and value of a local of the function at level
+/// `f`. Given a function instead, only the name of one of its parameters.
+fn getlocal<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (thread, arg) = thread_arg(stack);
+    let n = check_integer(stack, arg + 2, "getlocal")?;
+    if let Value::Function(f) = stack.get(arg) {
+        let name = match f {
+            Function::Closure(closure) => {
+                let proto = closure.proto();
+                u32::try_from(n - 1)
+                    .ok()
+                    .filter(|&reg| reg < proto.num_params.into())
+                    .and_then(|reg| proto.local_name(reg, 0))
+            }
+            _ => None,
+        };
+        stack.replace(&[name.map_or(Value::Nil, Value::String)]);
+        return Ok(NativeReturn::Return);
+    }
+    let level = check_level(stack, thread, arg + 1, "getlocal")?;
+    match thread.local(&ctx, level, n) {
+        Some((name, value)) => stack.replace(&[Value::String(name), value]),
+        None => stack.replace(&[Value::Nil]),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.getmetatable(value)`: the value's metatable, regardless of any `__metatable` field.
+fn getmetatable<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let metatable = ops::metatable(ctx, stack.get(0));
+    stack.replace(&[metatable.map_or(Value::Nil, Value::Table)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.getregistry()`: the registry table.
+fn getregistry<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    stack.replace(&[Value::Table(ctx.registry())]);
+    Ok(NativeReturn::Return)
+}
+
+/// The upvalue argument `n` picks out of the function before it, if it has that many.
+fn upvalue_index<'gc>(
+    stack: &Stack<'gc, '_>,
+    n: usize,
+    name: &str,
+) -> Result<(Function<'gc>, Option<usize>), RuntimeError> {
+    let Value::Function(f) = stack.get(n - 2) else {
+        return Err(type_error(stack, n - 1, name, "function"));
+    };
+    let count = match f {
+        Function::Closure(closure) => closure.upvalues().len(),
+        Function::NativeClosure(closure) => closure.upvalues().len(),
+        Function::Native(_) | Function::Callback(_) => 0,
+    };
+    let up = check_integer(stack, n, name)?;
+    let index = usize::try_from(up - 1).ok().filter(|&i| i < count);
+    Ok((f, index))
+}
+
+/// The name of upvalue `i` of `f`. Native functions' upvalues have empty names.
+fn upvalue_name<'gc>(ctx: Context<'gc>, f: Function<'gc>, i: usize) -> Value<'gc> {
+    match f {
+        Function::Closure(closure) => match closure.proto().upvalue_names.get(i) {
+            Some(&name) => Value::String(name),
+            // Stripped from a binary chunk.
+            None => string(ctx, "(no name)"),
+        },
+        _ => string(ctx, ""),
+    }
+}
+
+/// `debug.getupvalue(f, up)`: the name and value of upvalue `up` of `f`, or nothing if there is no
+/// such upvalue.
+fn getupvalue<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (f, index) = upvalue_index(stack, 2, "getupvalue")?;
+    let Some(i) = index else {
+        stack.clear();
+        return Ok(NativeReturn::Return);
+    };
+    let value = match f {
+        Function::Closure(closure) => closure.upvalues()[i].value(),
+        Function::NativeClosure(closure) => closure.upvalues()[i].get(),
+        Function::Native(_) | Function::Callback(_) => {
+            unreachable!("native functions have no upvalues")
+        }
+    };
+    stack.replace(&[upvalue_name(ctx, f, i), value]);
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.sethook([thread,] hook, mask [, count])`: sets the function called on the events `mask`
+/// names with the letters `c`, `r` and `l` (calls, returns and new lines) and every `count`
+/// instructions if that isn't 0. Without a function, removes the hook.
+fn sethook<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (thread, arg) = thread_arg(stack);
+    let function = stack.get(arg);
+    let hook = if function.is_nil() {
+        None
+    } else {
+        if !matches!(function, Value::Function(_)) {
+            return Err(type_error(stack, arg + 1, "sethook", "function").into());
+        }
+        let mask = super::check_string(ctx, stack, arg + 2, "sethook")?;
+        let mask = mask.as_bytes();
+        let count = super::opt_integer(stack, arg + 3, "sethook", 0)?;
+        Some(Hook {
+            function,
+            mask: HookMask {
+                call: mask.contains(&b'c'),
+                ret: mask.contains(&b'r'),
+                line: mask.contains(&b'l'),
+            },
+            count: count.clamp(0, u32::MAX.into()) as u32,
+        })
+    };
+    thread.set_hook(&ctx, hook);
+    stack.clear();
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.setlocal([thread,] level, local, value)`: assigns to a local of the function at `level`,
+/// returning its name, or nil if there is no such local.
+fn setlocal<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (thread, arg) = thread_arg(stack);
+    let level = check_level(stack, thread, arg + 1, "setlocal")?;
+    let n = check_integer(stack, arg + 2, "setlocal")?;
+    let value = stack.get(arg + 2);
+    let name = thread.set_local(&ctx, level, n, value);
+    stack.replace(&[name.map_or(Value::Nil, Value::String)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.setmetatable(value, table)`: sets the metatable of a table or userdata, or the one all
+/// strings share, regardless of any `__metatable` field. Returns the value.
+fn setmetatable<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let value = stack.get(0);
+    let metatable = match stack.get(1) {
+        Value::Nil => None,
+        Value::Table(t) => Some(t),
+        _ => return Err(type_error(stack, 2, "setmetatable", "nil or table").into()),
+    };
+    match value {
+        Value::Table(t) => ops::set_metatable(ctx, t, metatable),
+        Value::UserData(u) => u.set_metatable(&ctx, metatable),
+        Value::String(_) => ctx.set_string_metatable(metatable)?,
+        _ => {
+            let message = format!("cannot set the metatable of a {} value", value.type_name());
+            return Err(RuntimeError::new(message).into());
+        }
+    }
+    stack.replace(&[value]);
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.getuservalue(u [, n])`: the user value of a userdata, and true. A userdata has a single
+/// user value, so any `n` but 1 gives nil and false; values other than userdata give nil.
+fn getuservalue<'gc>(
+    _ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let n = opt_integer(stack, 2, "getuservalue", 1)?;
+    match stack.get(0) {
+        Value::UserData(u) if n == 1 => stack.replace(&[u.user_value(), Value::Boolean(true)]),
+        Value::UserData(_) => stack.replace(&[Value::Nil, Value::Boolean(false)]),
+        _ => stack.replace(&[Value::Nil]),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.setuservalue(u, value [, n])`: sets the user value of a userdata, returning it, or nil if
+/// it has no user value `n`.
+fn setuservalue<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let Value::UserData(u) = stack.get(0) else {
+        return Err(type_error(stack, 1, "setuservalue", "userdata").into());
+    };
+    check_any(stack, 2, "setuservalue")?;
+    let n = opt_integer(stack, 3, "setuservalue", 1)?;
+    if n != 1 {
+        stack.replace(&[Value::Nil]);
+        return Ok(NativeReturn::Return);
+    }
+    u.set_user_value(&ctx, stack.get(1));
+    stack.replace(&[Value::UserData(u)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.setupvalue(f, up, value)`: assigns to upvalue `up` of `f`, returning its name, or nothing
+/// if there is no such upvalue.
+fn setupvalue<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (f, index) = upvalue_index(stack, 2, "setupvalue")?;
+    let Some(i) = index else {
+        stack.clear();
+        return Ok(NativeReturn::Return);
+    };
+    let value = stack.get(2);
+    match f {
+        Function::Closure(closure) => closure.upvalues()[i].set_value(&ctx, value),
+        Function::NativeClosure(closure) => closure.upvalues()[i].set(&ctx, value),
+        Function::Native(_) | Function::Callback(_) => {
+            unreachable!("native functions have no upvalues")
+        }
+    }
+    stack.replace(&[upvalue_name(ctx, f, i)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `debug.traceback([thread,] [message [, level]])`: the message followed by a traceback of the
+/// thread's stack from `level` on, 1 by default, or 0 for another thread. A message that is neither
+/// a string nor nil is returned as it is.
+fn traceback<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (thread, arg) = thread_arg(stack);
+    let message = stack.get(arg);
+    let mut text = match message {
+        Value::Nil => Vec::new(),
+        Value::String(s) => s.as_bytes().to_vec(),
+        Value::Integer(_) | Value::Number(_) => message.to_string().into_bytes(),
+        _ => {
+            stack.replace(&[message]);
+            return Ok(NativeReturn::Return);
+        }
+    };
+    let current = thread == stack.thread();
+    let level = super::opt_integer(stack, arg + 2, "traceback", current.into())?;
+    let mut entries = Vec::new();
+    if current && level == 0 {
+        entries.push("[C]: in function 'debug.traceback'".to_owned());
+    }
+    entries.extend(thread.traceback(usize::try_from(level).unwrap_or(usize::MAX)));
+    let mut out = String::new();
+    write_traceback(&mut out, &entries).expect("writing to a string can't fail");
+    // Without a message the traceback starts on the first line.
+    let out = if message.is_nil() { &out[1..] } else { &out };
+    text.extend_from_slice(out.as_bytes());
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, text))]);
+    Ok(NativeReturn::Return)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::testing::run_in;
+    use crate::Lua;
+
+    fn run(source: &str) -> String {
+        run_in(&mut Lua::with_debug(), source)
+    }
+
+    #[test]
+    fn introspection() {
+        assert!(Lua::new().enter(|ctx| ctx.eval("debug").unwrap()[0].is_nil()));
+        assert_eq!(
+            run("return load([[
+                function f(a, b, ...)
+                    local c = a + b
+                    local info = debug.getinfo(1)
+                    debug.setlocal(1, 3, c * 10)
+                    return info.name, info.namewhat, info.currentline, info.short_src,
+                        info.what, info.linedefined, info.nparams, info.isvararg,
+                        debug.getlocal(1, 1), select(2, debug.getlocal(1, 3)), debug.getlocal(1, -2)
+                end
+                local r = {f(1, 2, 'x', 'y')}
+                return table.unpack(r)]], '=t')()"),
+            "f, global, 3, t, Lua, 1, 2, true, a, 30, (vararg), y"
+        );
+        assert_eq!(
+            run("local a = 5
+                local function f() return a end
+                local name = debug.setupvalue(f, 1, 7)
+                return name, a, debug.getupvalue(f, 1), debug.getupvalue(f, 2)"),
+            "a, 7, a"
+        );
+        assert_eq!(
+            run("local info = debug.getinfo(string.gmatch('', ''), 'Su')
+                return info.what, info.short_src, info.linedefined, info.nups,
+                    debug.getupvalue(string.gmatch('x', 'y'), 1)"),
+            "C, [C], -1, 4, , x"
+        );
+        assert_eq!(
+            run("return load([[
+                local function g() local t = debug.traceback('oops', 1) return t end
+                local function f() local t = g() return t end
+                local t = f() return t]], '=t')()"),
+            "oops\nstack traceback:\n\tt:1: in function <t:1>\n\tt:2: in function <t:2>\n\t\
+             t:3: in main chunk"
+        );
+        assert_eq!(
+            run("local t = debug.setmetatable({}, {__metatable = 'locked'})
+                local mt = {}
+                debug.setmetatable(t, mt)
+                return debug.getmetatable(t) == mt, debug.getmetatable('').__index == string,
+                    debug.getinfo(100), pcall(debug.getlocal, 100, 1)"),
+            "true, true, nil, false, bad argument #1 to 'getlocal' (level out of range)"
+        );
+    }
+
+    #[test]
+    fn hooks() {
+        assert_eq!(
+            run("return load([[
+                local events = {}
+                local function f() return 1 end
+                debug.sethook(function(event, line)
+                    events[#events + 1] = event .. (line or '')
+                end, 'crl')
+                f()
+                debug.sethook()
+                return table.concat(events, ' ')]], '=t')()"),
+            "return line6 call line2 return line7 call"
+        );
+        assert_eq!(
+            run("local n = 0
+                debug.sethook(function(event) n = n + 1 end, '', 10)
+                for i = 1, 100 do end
+                local f, mask, count = debug.gethook()
+                debug.sethook()
+                return n > 0, mask, count, debug.gethook()"),
+            "true, , 10, nil"
+        );
+        // A hook sees the function it interrupted as level 2, and it isn't hooked itself.
+        assert_eq!(
+            run("return load([[
+                local seen = {}
+                debug.sethook(function(event, line)
+                    local x = line * 2
+                    seen[#seen + 1] = debug.getinfo(2, 'l').currentline
+                end, 'l')
+                local a = 1
+                debug.sethook()
+                return table.concat(seen, ',')]], '=t')()"),
+            "6,7"
+        );
+    }
+
+    #[test]
+    fn host_hooks() {
+        use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+        use std::sync::Arc;
+
+        use crate::vm::{Hook, HookMask};
+        use crate::{Function, RuntimeError};
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let counted = Arc::new(AtomicU32::new(0));
+        Lua::with_debug().enter(|ctx| {
+            let (stop_hook, counted_hook) = (stop.clone(), counted.clone());
+            let watchdog = Function::from_typed_fn(&ctx, move |_, event: String| {
+                assert_eq!(event, "count");
+                counted_hook.fetch_add(1, Ordering::Relaxed);
+                match stop_hook.load(Ordering::Relaxed) {
+                    true => Err(RuntimeError::new("interrupted").into()),
+                    false => Ok(()),
+                }
+            });
+            ctx.set_hook(Some(Hook {
+                function: watchdog.into(),
+                mask: HookMask::default(),
+                count: 100,
+            }));
+            assert!(ctx.hook().is_some());
+
+            // Every thread gets the hook, coroutines included.
+            ctx.eval("coroutine.wrap(function() for i = 1, 1000 do end end)()")
+                .unwrap();
+            assert!(counted.load(Ordering::Relaxed) >= 10);
+            stop.store(true, Ordering::Relaxed);
+            let err = ctx.eval("while true do end").unwrap_err();
+            assert_eq!(err.to_string(), "interrupted");
+
+            // A thread's own hook takes the place of the host's while it is set.
+            let own = ctx
+                .eval("debug.sethook(function() end, 'l') for i = 1, 1000 do end return 1")
+                .unwrap();
+            assert_eq!(own[0].to_string(), "1");
+
+            ctx.set_hook(None);
+            assert!(ctx.eval("for i = 1, 1000 do end").is_ok());
+        });
+    }
+}
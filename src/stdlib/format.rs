@@ -0,0 +1,576 @@
+//! `string.format`, which formats its arguments as C's `printf` would, with the conversions and
+//! flags Lua 5.4 allows for each.
+
+use crate::vm::{ops, Stack};
+use crate::{Context, LuaError, LuaString, NativeReturn, RuntimeError, Value};
+
+use super::{arg_error, check_integer, check_string, to_string, type_error};
+
+/// Conversion specifications this long or longer are refused, as in the reference implementation.
+const MAX_SPEC_LEN: usize = 22;
+
+/// A conversion specification: the flags, width and precision between a `%` and its conversion.
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// `string.format(fmt, ...)`: `fmt` with each conversion specification replaced by the next
+/// argument, formatted as the specification says.
+///
+/// Integer conversions (`%d`, `%i`, `%u`, `%c`, `%o`, `%x`, `%X`) need an argument with an exact
+/// integer value, and `%q` writes a value as a literal that reads back as the same value.
+pub(super) fn format<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let fmt = check_string(ctx, stack, 1, "format")?;
+    let fmt = fmt.as_bytes();
+    let mut out = Vec::with_capacity(fmt.len());
+    let mut arg = 1;
+    let mut i = 0;
+    while i < fmt.len() {
+        let Some(at) = fmt[i..].iter().position(|&b| b == b'%') else {
+            out.extend_from_slice(&fmt[i..]);
+            break;
+        };
+        out.extend_from_slice(&fmt[i..i + at]);
+        i += at + 1;
+        if fmt.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+
+        arg += 1;
+        if arg > stack.len() {
+            return Err(arg_error(arg, "format", "no value").into());
+        }
+        let value = stack.get(arg - 1);
+        // The specification runs up to and including the conversion letter.
+        let len = fmt[i..]
+            .iter()
+            .take_while(|b| b"-+ #0123456789.".contains(b))
+            .count();
+        if len + 1 >= MAX_SPEC_LEN {
+            return Err(RuntimeError::new("invalid format string to 'format'").into());
+        }
+        let form = &fmt[i..(i + len + 1).min(fmt.len())];
+        i += form.len();
+        let conversion = form.last().copied().filter(|_| form.len() == len + 1);
+        match conversion {
+            Some(b'c') => {
+                let spec = parse_spec(form, b"-", false)?;
+                let c = check_integer(stack, arg, "format")? as u8;
+                pad(&mut out, &spec, "", &[c], false);
+            }
+            Some(c @ (b'd' | b'i')) => {
+                let spec = parse_spec(form, b"-+ 0", true)?;
+                let n = check_integer(stack, arg, "format")?;
+                let sign = if n < 0 { "-" } else { sign_for(&spec) };
+                let digits = integer_digits(n.unsigned_abs(), c, &spec);
+                pad(
+                    &mut out,
+                    &spec,
+                    sign,
+                    digits.as_bytes(),
+                    spec.precision.is_none(),
+                );
+            }
+            Some(c @ (b'u' | b'o' | b'x' | b'X')) => {
+                let flags = if c == b'u' { &b"-0"[..] } else { b"-#0" };
+                let spec = parse_spec(form, flags, true)?;
+                let n = check_integer(stack, arg, "format")? as u64;
+                let mut digits = integer_digits(n, c, &spec);
+                let prefix = match c {
+                    b'o' if spec.alternate && !digits.starts_with('0') => {
+                        digits.insert(0, '0');
+                        ""
+                    }
+                    b'x' if spec.alternate && n != 0 => "0x",
+                    b'X' if spec.alternate && n != 0 => "0X",
+                    _ => "",
+                };
+                pad(
+                    &mut out,
+                    &spec,
+                    prefix,
+                    digits.as_bytes(),
+                    spec.precision.is_none(),
+                );
+            }
+            Some(c @ (b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G')) => {
+                let spec = parse_spec(form, b"-+ #0", true)?;
+                let n = ops::coerce_number(value)
+                    .and_then(Value::to_number)
+                    .ok_or_else(|| type_error(stack, arg, "format", "number"))?;
+                // Like glibc, a NaN shows its sign.
+                let sign = if n.is_sign_negative() {
+                    "-"
+                } else {
+                    sign_for(&spec)
+                };
+                if !n.is_finite() {
+                    let body = if n.is_nan() { "nan" } else { "inf" };
+                    let body = convert_case(body.to_owned(), c);
+                    pad(&mut out, &spec, sign, body.as_bytes(), false);
+                    continue;
+                }
+                let n = n.abs();
+                let (prefix, body) = match c.to_ascii_lowercase() {
+                    b'a' => ("0x", hex_float(n, spec.precision, spec.alternate)),
+                    b'e' => (
+                        "",
+                        exponential(n, spec.precision.unwrap_or(6), spec.alternate),
+                    ),
+                    b'f' => ("", fixed(n, spec.precision.unwrap_or(6), spec.alternate)),
+                    _ => ("", general(n, spec.precision.unwrap_or(6), spec.alternate)),
+                };
+                let prefix = convert_case(format!("{sign}{prefix}"), c);
+                pad(
+                    &mut out,
+                    &spec,
+                    &prefix,
+                    convert_case(body, c).as_bytes(),
+                    true,
+                );
+            }
+            Some(b'p') => {
+                let spec = parse_spec(form, b"-", false)?;
+                let address = match value {
+                    Value::String(s) => Some(s.as_bytes().as_ptr().cast()),
+                    Value::Table(t) => Some(t.as_ptr()),
+                    Value::Function(f) => Some(f.as_ptr()),
+                    Value::Thread(t) => Some(t.as_ptr()),
+                    Value::UserData(u) => Some(u.as_ptr()),
+                    _ => None,
+                };
+                let text = address.map_or_else(|| "(null)".to_owned(), |p| format!("{p:p}"));
+                pad(&mut out, &spec, "", text.as_bytes(), false);
+            }
+            Some(b'q') => {
+                if form.len() > 1 {
+                    return Err(RuntimeError::new("specifier '%q' cannot have modifiers").into());
+                }
+                quote(&mut out, value).map_err(|message| arg_error(arg, "format", message))?;
+            }
+            Some(b's') => {
+                let s = to_string(ctx, stack.thread(), value)?;
+                if form.len() == 1 {
+                    out.extend_from_slice(s.as_bytes());
+                    continue;
+                }
+                if s.as_bytes().contains(&0) {
+                    return Err(arg_error(arg, "format", "string contains zeros").into());
+                }
+                let spec = parse_spec(form, b"-", true)?;
+                let text = match spec.precision {
+                    Some(precision) => &s.as_bytes()[..precision.min(s.len())],
+                    None => s.as_bytes(),
+                };
+                pad(&mut out, &spec, "", text, false);
+            }
+            _ => return Err(invalid_conversion(form).into()),
+        }
+    }
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, out))]);
+    Ok(NativeReturn::Return)
+}
+
+fn invalid_conversion(form: &[u8]) -> RuntimeError {
+    RuntimeError::new(format!(
+        "invalid conversion '%{}' to 'format'",
+        String::from_utf8_lossy(form)
+    ))
+}
+
+/// Parses the specification `form`, which ends with its conversion letter, allowing only the given
+/// flags and, if `precision` is set, a precision. Widths and precisions have two digits at most.
+fn parse_spec(form: &[u8], flags: &[u8], precision: bool) -> Result<Spec, RuntimeError> {
+    let mut spec = Spec::default();
+    let mut i = 0;
+    while let Some(&flag) = form.get(i).filter(|b| flags.contains(b)) {
+        match flag {
+            b'-' => spec.left = true,
+            b'+' => spec.plus = true,
+            b' ' => spec.space = true,
+            b'#' => spec.alternate = true,
+            _ => spec.zero = true,
+        }
+        i += 1;
+    }
+    let two_digits = |i: &mut usize| {
+        let mut n = 0;
+        for _ in 0..2 {
+            match form.get(*i) {
+                Some(&d) if d.is_ascii_digit() => {
+                    n = n * 10 + (d - b'0') as usize;
+                    *i += 1;
+                }
+                _ => break,
+            }
+        }
+        n
+    };
+    // A width can't start with a zero, which would be a flag.
+    if form[i] != b'0' {
+        spec.width = two_digits(&mut i);
+        if form[i] == b'.' && precision {
+            i += 1;
+            spec.precision = Some(two_digits(&mut i));
+        }
+    }
+    if i + 1 != form.len() {
+        return Err(invalid_conversion(form));
+    }
+    Ok(spec)
+}
+
+fn sign_for(spec: &Spec) -> &'static str {
+    if spec.plus {
+        "+"
+    } else if spec.space {
+        " "
+    } else {
+        ""
+    }
+}
+
+/// The digits of `n` for the integer conversion `c`, at least as many as the precision asks for.
+/// A zero precision leaves a zero with no digits at all.
+fn integer_digits(n: u64, c: u8, spec: &Spec) -> String {
+    let digits = match c {
+        b'o' => format!("{n:o}"),
+        b'x' => format!("{n:x}"),
+        b'X' => format!("{n:X}"),
+        _ => n.to_string(),
+    };
+    match spec.precision {
+        Some(0) if n == 0 => String::new(),
+        Some(precision) => format!("{digits:0>precision$}"),
+        None => digits,
+    }
+}
+
+/// Appends `prefix` and `body` padded to the width, with zeros between them if the zero flag
+/// applies, and with spaces otherwise.
+fn pad(out: &mut Vec<u8>, spec: &Spec, prefix: &str, body: &[u8], zero_allowed: bool) {
+    let fill = spec.width.saturating_sub(prefix.len() + body.len());
+    if spec.left {
+        out.extend_from_slice(prefix.as_bytes());
+        out.extend_from_slice(body);
+        out.resize(out.len() + fill, b' ');
+    } else if spec.zero && zero_allowed {
+        out.extend_from_slice(prefix.as_bytes());
+        out.resize(out.len() + fill, b'0');
+        out.extend_from_slice(body);
+    } else {
+        out.resize(out.len() + fill, b' ');
+        out.extend_from_slice(prefix.as_bytes());
+        out.extend_from_slice(body);
+    }
+}
+
+/// Upper-cases `s` for the upper-case conversions.
+fn convert_case(s: String, c: u8) -> String {
+    if c.is_ascii_uppercase() {
+        s.to_ascii_uppercase()
+    } else {
+        s
+    }
+}
+
+/// `%f` of a non-negative finite float.
+fn fixed(n: f64, precision: usize, alternate: bool) -> String {
+    let mut s = format!("{n:.precision$}");
+    if alternate && precision == 0 {
+        s.push('.');
+    }
+    s
+}
+
+/// `%e` of a non-negative finite float.
+fn exponential(n: f64, precision: usize, alternate: bool) -> String {
+    let s = format!("{n:.precision$e}");
+    let (mantissa, exp) = s.split_once('e').expect("exponential notation");
+    let exp: i32 = exp.parse().expect("integer exponent");
+    let point = if alternate && precision == 0 { "." } else { "" };
+    let exp_sign = if exp < 0 { '-' } else { '+' };
+    format!("{mantissa}{point}e{exp_sign}{:02}", exp.abs())
+}
+
+/// `%g` of a non-negative finite float. The alternate form keeps trailing zeros.
+fn general(n: f64, precision: usize, alternate: bool) -> String {
+    if !alternate {
+        return ops::format_g(n, precision);
+    }
+    let precision = precision.max(1);
+    let exp_form = format!("{:.*e}", precision - 1, n);
+    let (_, exp) = exp_form.split_once('e').expect("exponential notation");
+    let exp: i32 = exp.parse().expect("integer exponent");
+    if -4 <= exp && exp < precision as i32 {
+        fixed(n, (precision as i32 - 1 - exp) as usize, true)
+    } else {
+        exponential(n, precision - 1, true)
+    }
+}
+
+/// `%a` of a non-negative finite float, without the `0x`: the hexadecimal digits of the mantissa
+/// and a binary exponent, with as many digits as needed unless a precision is given.
+fn hex_float(n: f64, precision: Option<usize>, alternate: bool) -> String {
+    const FRACTION_DIGITS: usize = 13;
+    let bits = n.to_bits();
+    let fraction = bits & ((1 << 52) - 1);
+    let (mut mantissa, exp) = match bits >> 52 {
+        0 if fraction == 0 => (0, 0),
+        // Subnormal numbers are written with a leading zero.
+        0 => (fraction, -1022),
+        biased => (1 << 52 | fraction, biased as i64 - 1023),
+    };
+    let digits = match precision {
+        Some(precision) if precision < FRACTION_DIGITS => {
+            // Rounds the dropped digits to nearest, ties to even.
+            let shift = 4 * (FRACTION_DIGITS - precision) as u32;
+            let dropped = mantissa & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+            mantissa >>= shift;
+            if dropped > half || (dropped == half && mantissa & 1 == 1) {
+                mantissa += 1;
+            }
+            precision
+        }
+        Some(precision) => {
+            mantissa <<= 4 * (precision - FRACTION_DIGITS);
+            precision
+        }
+        None => {
+            let trailing = (mantissa.trailing_zeros() as usize / 4).min(FRACTION_DIGITS);
+            mantissa >>= 4 * trailing;
+            FRACTION_DIGITS - trailing
+        }
+    };
+    let lead = mantissa >> (4 * digits);
+    let mut s = format!("{lead:x}");
+    if digits > 0 || alternate {
+        s.push('.');
+    }
+    if digits > 0 {
+        let fraction = mantissa & ((1 << (4 * digits)) - 1);
+        s.push_str(&format!("{fraction:0digits$x}"));
+    }
+    let exp_sign = if exp < 0 { '-' } else { '+' };
+    s.push_str(&format!("p{exp_sign}{}", exp.abs()));
+    s
+}
+
+/// Appends `value` as `%q` writes it: as a Lua literal that reads back as the same value.
+pub(super) fn quote(out: &mut Vec<u8>, value: Value<'_>) -> Result<(), &'static str> {
+    match value {
+        Value::String(s) => {
+            let bytes = s.as_bytes();
+            out.push(b'"');
+            for (i, &b) in bytes.iter().enumerate() {
+                match b {
+                    b'"' | b'\\' | b'\n' => out.extend_from_slice(&[b'\\', b]),
+                    _ if b.is_ascii_control() => {
+                        // Three digits keep a following digit from reading as part of the escape.
+                        let escape = match bytes.get(i + 1) {
+                            Some(next) if next.is_ascii_digit() => format!("\\{b:03}"),
+                            _ => format!("\\{b}"),
+                        };
+                        out.extend_from_slice(escape.as_bytes());
+                    }
+                    _ => out.push(b),
+                }
+            }
+            out.push(b'"');
+        }
+        // The smallest integer has no literal of its own: its magnitude reads as a float.
+        Value::Integer(i64::MIN) => out.extend_from_slice(b"0x8000000000000000"),
+        Value::Integer(i) => out.extend_from_slice(i.to_string().as_bytes()),
+        Value::Number(n) => {
+            let literal = if n.is_nan() {
+                "(0/0)".to_owned()
+            } else if n.is_infinite() {
+                let sign = if n < 0.0 { "-" } else { "" };
+                format!("{sign}1e9999")
+            } else {
+                let sign = if n.is_sign_negative() { "-" } else { "" };
+                format!("{sign}0x{}", hex_float(n.abs(), None, false))
+            };
+            out.extend_from_slice(literal.as_bytes());
+        }
+        Value::Nil | Value::Boolean(_) => out.extend_from_slice(value.to_string().as_bytes()),
+        _ => return Err("value has no literal form"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::testing::run;
+    use crate::vm::ops;
+    use crate::{Lua, LuaString, Value};
+
+    fn format(args: &str) -> String {
+        run(&format!("return string.format({args})"))
+    }
+
+    #[test]
+    fn conversions() {
+        let cases = [
+            (
+                "'%d|%5d|%-5d|%05d|%+d|% d'",
+                "1, 2, 3, 4, 5, 6",
+                "1|    2|3    |00004|+5| 6",
+            ),
+            ("'%.3d|%.0d|%i'", "7, 0, -8", "007||-8"),
+            (
+                "'%x|%X|%#x|%o|%#o|%u'",
+                "255, 255, 255, 8, 8, 3.0",
+                "ff|FF|0xff|10|010|3",
+            ),
+            ("'%x'", "-1", "ffffffffffffffff"),
+            ("'%c%c%-3c|'", "72, 105, 33", "Hi!  |"),
+            (
+                "'%f|%.2f|%10.3f|%-8.1f|'",
+                "1.5, 2.345, -3.14159, 2",
+                "1.500000|2.35|    -3.142|2.0     |",
+            ),
+            (
+                "'%e|%.2E|%#.0e'",
+                "12345.678, 0.000123, 5",
+                "1.234568e+04|1.23E-04|5.e+00",
+            ),
+            (
+                "'%g|%g|%g|%.3g|%#g'",
+                "100000, 1e20, 0.0001, 3.14159, 1",
+                "100000|1e+20|0.0001|3.14|1.00000",
+            ),
+            (
+                "'%a|%A|%.1a|%a'",
+                "1, 0.5, 1.96875, 0",
+                "0x1p+0|0X1P-1|0x2.0p+0|0x0p+0",
+            ),
+            (
+                "'%5.1f|%05.1f|%+.1f'",
+                "1/0, -1/0, -(0/0)",
+                "  inf| -inf|+nan",
+            ),
+            (
+                "'%s|%10s|%-4s|%.2s'",
+                "'x', 'right', 'l', 'cut'",
+                "x|     right|l   |cu",
+            ),
+            ("'%s %s %s'", "nil, true, 1.0", "nil true 1.0"),
+            ("'%5%'", "1", "error: invalid conversion '%5%' to 'format'"),
+            ("'100%%'", "", "100%"),
+        ];
+        for (fmt, args, expected) in cases {
+            let call = if args.is_empty() {
+                fmt.to_owned()
+            } else {
+                format!("{fmt}, {args}")
+            };
+            assert_eq!(format(&call), expected, "{fmt}");
+        }
+    }
+
+    #[test]
+    fn strings_of_other_values() {
+        Lua::new().enter(|ctx| {
+            let named = ctx.eval("return {}, {__name = 'Point'}").unwrap();
+            let shown = ctx.eval("return {}, {__tostring = function() return 'shown' end}");
+            for values in [named, shown.unwrap()] {
+                let (Value::Table(t), Value::Table(mt)) = (values[0], values[1]) else {
+                    panic!("expected tables");
+                };
+                ops::set_metatable(ctx, t, Some(mt));
+                ctx.globals()
+                    .set(&ctx, LuaString::new(&ctx, b"t"), t)
+                    .unwrap();
+                let s = ctx.eval("return string.format('%s|%.5s', t, t)").unwrap()[0];
+                let s = s.to_string();
+                assert!(
+                    s == "shown|shown" || (s.starts_with("Point: 0x") && s.ends_with("|Point")),
+                    "{s}"
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            format(r#"'%q', 'a "b"\n\0c\0001\r'"#),
+            "\"a \\\"b\\\"\\\n\\0c\\0001\\13\""
+        );
+        assert_eq!(format("'%q', 1/0"), "1e9999");
+        assert_eq!(format("'%q', -1/0"), "-1e9999");
+        assert_eq!(format("'%q', 0/0"), "(0/0)");
+        assert_eq!(format("'%q', 0.1"), "0x1.999999999999ap-4");
+        assert_eq!(format("'%q', -2^63 | 0"), "0x8000000000000000");
+        // Quoted values read back as themselves.
+        let round_trip = Lua::new().enter(|ctx| {
+            ctx.eval(
+                "local values = {'\\0\\1\\2x\\200\\n\\\\', 2^53 + 1, -0.0, 1e308, 5e-324, 17}
+                for _, v in ipairs(values) do
+                    local back = load('return ' .. string.format('%q', v))()
+                    if back ~= v then return false end
+                end
+                return true",
+            )
+            .unwrap()[0]
+                .to_string()
+        });
+        assert_eq!(round_trip, "true");
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            (
+                "'%d', 1.5",
+                "bad argument #2 to 'format' (number has no integer representation)",
+            ),
+            (
+                "'%d', 'x'",
+                "bad argument #2 to 'format' (number expected, got string)",
+            ),
+            (
+                "'%f', {}",
+                "bad argument #2 to 'format' (number expected, got table)",
+            ),
+            ("'%d %d', 1", "bad argument #3 to 'format' (no value)"),
+            ("'%100d', 1", "invalid conversion '%100d' to 'format'"),
+            ("'%1.100d', 1", "invalid conversion '%1.100d' to 'format'"),
+            ("'%010c', 1", "invalid conversion '%010c' to 'format'"),
+            ("'%.10c', 1", "invalid conversion '%.10c' to 'format'"),
+            ("'%#i', 1", "invalid conversion '%#i' to 'format'"),
+            ("'%t', 1", "invalid conversion '%t' to 'format'"),
+            ("'%', 1", "invalid conversion '%' to 'format'"),
+            ("'%5q', 1", "specifier '%q' cannot have modifiers"),
+            (
+                "'%q', {}",
+                "bad argument #2 to 'format' (value has no literal form)",
+            ),
+            (
+                "'%10s', '\\0'",
+                "bad argument #2 to 'format' (string contains zeros)",
+            ),
+            (
+                "'%0000000000000000000000d', 1",
+                "invalid format string to 'format'",
+            ),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(format(args), format!("error: {expected}"), "{args}");
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! A readable rendering of nested values, for logging and for showing results at a prompt, and the
+//! `inspect` global that gives scripts the same. [`Lua::new`](crate::Lua::new) leaves the global
+//! out; hosts that want it open it with [`load_inspect`].
+//!
+//! Tables are written as constructors, with their sequence first and then their other keys in
+//! order: numbers, strings, then everything else. A table met again inside itself is written as
+//! `<cycle>`, and its metatable, if it has one, as a last `<metatable>` field. Nothing is looked up
+//! through metamethods, so inspecting a value can't run any code of a script.
+
+use std::cmp::Ordering;
+
+use crate::compiler::lexer::is_name;
+use crate::vm::Stack;
+use crate::{Context, Function, LuaError, LuaString, NativeReturn, Table, Value};
+
+use super::format::quote;
+use super::{check_any, type_error};
+
+/// How much of a value [`inspect`] writes out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InspectOptions {
+    /// How many levels of nested tables to write; deeper ones are written as `{...}`.
+    pub depth: usize,
+    /// How many fields of each table to write, after which the rest are left as `...`.
+    pub width: usize,
+    /// Whether to write the metatables of tables.
+    pub metatables: bool,
+}
+
+impl Default for InspectOptions {
+    fn default() -> InspectOptions {
+        InspectOptions {
+            depth: 4,
+            width: 64,
+            metatables: true,
+        }
+    }
+}
+
+/// Writes `value` out as `options` allow. Strings are quoted as `%q` quotes them; bytes that aren't
+/// UTF-8 are replaced.
+pub fn inspect(value: Value<'_>, options: &InspectOptions) -> String {
+    let mut out = Vec::new();
+    Inspector {
+        options,
+        out: &mut out,
+        path: Vec::new(),
+    }
+    .value(value, 0);
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Opens the `inspect` global: `inspect(value [, options])`, where `options` can set the fields of
+/// [`InspectOptions`].
+pub fn load_inspect(ctx: Context<'_>) {
+    let name = LuaString::new(&ctx, b"inspect");
+    ctx.globals()
+        .set(&ctx, name, Function::Native(inspect_fn))
+        .expect("string keys are always valid");
+}
+
+struct Inspector<'a, 'gc> {
+    options: &'a InspectOptions,
+    out: &'a mut Vec<u8>,
+    /// The tables being written, from the outermost in.
+    path: Vec<Table<'gc>>,
+}
+
+impl<'a, 'gc> Inspector<'a, 'gc> {
+    fn value(&mut self, value: Value<'gc>, depth: usize) {
+        match value {
+            Value::String(_) => quote(self.out, value).expect("strings have a literal form"),
+            Value::Table(t) => self.table(t, depth),
+            Value::Function(_) | Value::Thread(_) | Value::UserData(_) => {
+                self.out.push(b'<');
+                self.out.extend_from_slice(value.to_string().as_bytes());
+                self.out.push(b'>');
+            }
+            _ => self.out.extend_from_slice(value.to_string().as_bytes()),
+        }
+    }
+
+    fn table(&mut self, table: Table<'gc>, depth: usize) {
+        if self.path.contains(&table) {
+            self.out.extend_from_slice(b"<cycle>");
+            return;
+        }
+        let metatable = table.metatable().filter(|_| self.options.metatables);
+        let (sequence, mut fields) = entries(table);
+        if sequence.is_empty() && fields.is_empty() && metatable.is_none() {
+            self.out.extend_from_slice(b"{}");
+            return;
+        }
+        if depth >= self.options.depth {
+            self.out.extend_from_slice(b"{...}");
+            return;
+        }
+        fields.sort_by(|a, b| key_order(a.0, b.0));
+
+        self.path.push(table);
+        self.out.push(b'{');
+        let mut written = 0;
+        let items = sequence.into_iter().map(|v| (None, v));
+        let fields = fields.into_iter().map(|(k, v)| (Some(k), v));
+        for (key, value) in items.chain(fields) {
+            if written > 0 {
+                self.out.extend_from_slice(b", ");
+            }
+            if written == self.options.width {
+                self.out.extend_from_slice(b"...");
+                break;
+            }
+            if let Some(key) = key {
+                self.key(key, depth);
+            }
+            self.value(value, depth + 1);
+            written += 1;
+        }
+        if let Some(metatable) = metatable {
+            if written > 0 {
+                self.out.extend_from_slice(b", ");
+            }
+            self.out.extend_from_slice(b"<metatable> = ");
+            self.table(metatable, depth + 1);
+        }
+        self.out.push(b'}');
+        self.path.pop();
+    }
+
+    fn key(&mut self, key: Value<'gc>, depth: usize) {
+        match key {
+            Value::String(s) if is_name(s.as_bytes()) => self.out.extend_from_slice(s.as_bytes()),
+            _ => {
+                self.out.push(b'[');
+                self.value(key, depth + 1);
+                self.out.push(b']');
+            }
+        }
+        self.out.extend_from_slice(b" = ");
+    }
+}
+
+/// The values of `table[1]` up to its first nil, and its other entries.
+#[allow(clippy::type_complexity)]
+fn entries(table: Table<'_>) -> (Vec<Value<'_>>, Vec<(Value<'_>, Value<'_>)>) {
+    let mut sequence = Vec::new();
+    loop {
+        let value = table.get(sequence.len() as i64 + 1);
+        if value.is_nil() {
+            break;
+        }
+        sequence.push(value);
+    }
+    let mut fields = Vec::new();
+    let mut key = Value::Nil;
+    while let Ok(Some((k, v))) = table.next(key) {
+        let in_sequence = matches!(k, Value::Integer(i) if i >= 1 && i as usize <= sequence.len());
+        if !in_sequence {
+            fields.push((k, v));
+        }
+        key = k;
+    }
+    (sequence, fields)
+}
+
+/// Numbers first, by value, then strings, bytewise, then everything else as traversal found it.
+fn key_order(a: Value<'_>, b: Value<'_>) -> Ordering {
+    let rank = |v: Value<'_>| match v {
+        Value::Integer(_) | Value::Number(_) => 0,
+        Value::String(_) => 1,
+        _ => 2,
+    };
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.as_bytes().cmp(b.as_bytes()),
+        (Value::Integer(a), Value::Integer(b)) => a.cmp(&b),
+        _ if rank(a) == 0 && rank(b) == 0 => {
+            let number = |v: Value<'_>| match v {
+                Value::Integer(i) => i as f64,
+                Value::Number(n) => n,
+                _ => unreachable!(),
+            };
+            number(a).total_cmp(&number(b))
+        }
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// `inspect(value [, options])`
+fn inspect_fn<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    check_any(stack, 1, "inspect")?;
+    let mut options = InspectOptions::default();
+    match stack.get(1) {
+        Value::Nil => {}
+        Value::Table(t) => {
+            let limit = |name: &str, default: usize| match t.get_str(name) {
+                Value::Integer(n) => n.max(0) as usize,
+                _ => default,
+            };
+            options.depth = limit("depth", options.depth);
+            options.width = limit("width", options.width);
+            if let Value::Boolean(b) = t.get_str("metatables") {
+                options.metatables = b;
+            }
+        }
+        _ => return Err(type_error(stack, 2, "inspect", "table").into()),
+    }
+    let text = inspect(stack.get(0), &options);
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, text.into_bytes()))]);
+    Ok(NativeReturn::Return)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::testing::run_in;
+    use crate::{stdlib, Lua};
+
+    fn run(source: &str) -> String {
+        let mut lua = Lua::new();
+        lua.enter(stdlib::load_inspect);
+        run_in(&mut lua, source)
+    }
+
+    #[test]
+    fn writes_tables() {
+        assert_eq!(
+            run("inspect({1, 'two', {3}, x = true, ['a b'] = 1.5, [10] = 0, [-1] = 0, ['end'] = 1})"),
+            r#"{1, "two", {3}, [-1] = 0, [10] = 0, ["a b"] = 1.5, ["end"] = 1, x = true}"#
+        );
+        assert_eq!(
+            run("inspect('a\\n\"b\"')"),
+            r#""a\
+\"b\"""#
+        );
+        assert_eq!(
+            run("local t = {} t.self = t return inspect(setmetatable(t, {__index = t}))"),
+            "{self = <cycle>, <metatable> = {__index = <cycle>}}"
+        );
+        assert_eq!(
+            run("inspect({{{{}}}, {{{1}}}, 1, 2, 3}, {depth = 2, width = 3})"),
+            "{{{...}}, {{...}}, 1, ...}"
+        );
+        assert_eq!(
+            run("inspect(setmetatable({}, {}), {metatables = false})"),
+            "{}"
+        );
+        assert!(run("inspect(print)").starts_with("<function: 0x"));
+    }
+}
@@ -0,0 +1,1403 @@
+//! The input and output library, set as the `io` global.
+//!
+//! Files are [`LuaStream`]s, and `io.open` asks a [`FileSystem`] for them by name. By default
+//! those are `std::fs` files, standard input, and the state's standard output and error, which
+//! [`Context::set_stdout`] and [`Context::set_stderr`] redirect; an embedder can give in-memory
+//! buffers or a virtual filesystem instead through [`IoOptions`].
+//!
+//! A file handle is a table with the `FILE*` metatable. The stream behind it is kept by the state,
+//! keyed by the handle, so scripts can't reach it other than through the library.
+
+#[cfg(not(feature = "send"))]
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+#[cfg(not(feature = "send"))]
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::compiler::lexer::{parse_number, Number};
+use crate::state::OutputSink;
+use crate::vm::{ops, Stack};
+use crate::{
+    Context, LuaError, LuaString, MaybeSend, NativeClosure, NativeReturn, RuntimeError, Table,
+    Value,
+};
+
+use super::{
+    arg_error, check_integer, check_string, io_error, opt_integer, path, set_function, set_library,
+    temporary_file, type_error,
+};
+
+/// A source or sink of bytes that a Lua file handle reads from or writes to.
+///
+/// Operations a stream can't do fail by default, so a stream only needs the ones it supports.
+pub trait LuaStream: MaybeSend {
+    /// Reads into `buf`, returning how many bytes were read: 0 at the end of the stream.
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(unsupported())
+    }
+
+    /// Writes all of `data`.
+    fn write(&mut self, _data: &[u8]) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Moves to a position, returning it as an offset from the start.
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(unsupported())
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "operation not supported by the stream",
+    )
+}
+
+impl LuaStream for fs::File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(self, buf)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        Write::write_all(self, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        Seek::seek(self, pos)
+    }
+}
+
+impl LuaStream for io::Cursor<Vec<u8>> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(self, buf)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        Write::write_all(self, data)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        Seek::seek(self, pos)
+    }
+}
+
+impl LuaStream for io::Stdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(self, buf)
+    }
+}
+
+impl LuaStream for io::Stdout {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        Write::write_all(self, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+}
+
+impl LuaStream for io::Stderr {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        Write::write_all(self, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+}
+
+impl LuaStream for OutputSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        OutputSink::flush(self)
+    }
+}
+
+/// A stream the host keeps a handle to, for example to look at what a script wrote into a buffer.
+#[cfg(not(feature = "send"))]
+impl<T: LuaStream> LuaStream for Rc<RefCell<T>> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.borrow_mut().read(buf)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.borrow_mut().write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.borrow_mut().flush()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.borrow_mut().seek(pos)
+    }
+}
+
+/// Like the impl for `Rc<RefCell<T>>`, for a stream the host keeps on another thread, or for a
+/// state that is [`Send`].
+impl<T: LuaStream> LuaStream for Arc<Mutex<T>> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        lock(self).read(buf)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        lock(self).write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        lock(self).flush()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        lock(self).seek(pos)
+    }
+}
+
+fn lock<T>(stream: &Mutex<T>) -> MutexGuard<'_, T> {
+    stream.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// How `io.open` asks for a file to be opened.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct OpenMode {
+    pub read: bool,
+    pub write: bool,
+    /// Whether every write goes to the end of the file.
+    pub append: bool,
+    /// Whether an existing file is emptied.
+    pub truncate: bool,
+    /// Whether a missing file is created.
+    pub create: bool,
+}
+
+impl OpenMode {
+    /// The mode `r`.
+    pub const READ: OpenMode = OpenMode {
+        read: true,
+        write: false,
+        append: false,
+        truncate: false,
+        create: false,
+    };
+
+    /// Parses a mode as `fopen` takes it: `r`, `w` or `a`, then an optional `+`, then any number of
+    /// `b`s, which change nothing.
+    pub fn parse(mode: &[u8]) -> Option<OpenMode> {
+        let (&first, rest) = mode.split_first()?;
+        let (update, rest) = match rest.strip_prefix(b"+") {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        if rest.iter().any(|&c| c != b'b') {
+            return None;
+        }
+        Some(match first {
+            b'r' => OpenMode {
+                read: true,
+                write: update,
+                ..OpenMode::default()
+            },
+            b'w' => OpenMode {
+                read: update,
+                write: true,
+                truncate: true,
+                create: true,
+                ..OpenMode::default()
+            },
+            b'a' => OpenMode {
+                read: update,
+                write: true,
+                append: true,
+                create: true,
+                ..OpenMode::default()
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// Where `io.open` and the functions taking file names find files.
+pub trait FileSystem: MaybeSend {
+    fn open(&mut self, name: &[u8], mode: OpenMode) -> io::Result<Box<dyn LuaStream>>;
+
+    /// A new file for reading and writing that goes away once closed, for `io.tmpfile`.
+    fn temporary(&mut self) -> io::Result<Box<dyn LuaStream>> {
+        Err(unsupported())
+    }
+}
+
+/// The files of the host, through `std::fs`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StdFileSystem;
+
+impl FileSystem for StdFileSystem {
+    fn open(&mut self, name: &[u8], mode: OpenMode) -> io::Result<Box<dyn LuaStream>> {
+        let file = fs::OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write && !mode.append)
+            .append(mode.append)
+            .truncate(mode.truncate)
+            .create(mode.create)
+            .open(path(name))?;
+        Ok(Box::new(file))
+    }
+
+    fn temporary(&mut self) -> io::Result<Box<dyn LuaStream>> {
+        let (path, file) = temporary_file()?;
+        // Where open files can be removed, the file is gone once the last handle is.
+        let _ = fs::remove_file(path);
+        Ok(Box::new(file))
+    }
+}
+
+/// What the io library works with.
+pub struct IoOptions {
+    pub file_system: Box<dyn FileSystem>,
+    pub stdin: Box<dyn LuaStream>,
+    /// The stream behind `io.stdout`, or `None` for the state's standard output, which `print`
+    /// writes to as well.
+    pub stdout: Option<Box<dyn LuaStream>>,
+    /// The stream behind `io.stderr`, or `None` for the state's standard error.
+    pub stderr: Option<Box<dyn LuaStream>>,
+}
+
+impl Default for IoOptions {
+    /// The host's files, the process's standard input and the state's standard output and error.
+    fn default() -> IoOptions {
+        IoOptions {
+            file_system: Box::new(StdFileSystem),
+            stdin: Box::new(io::stdin()),
+            stdout: None,
+            stderr: None,
+        }
+    }
+}
+
+/// How many bytes are read from a stream at a time.
+const BUFFER_SIZE: usize = 8192;
+
+/// The most formats `lines` takes.
+const MAX_LINE_FORMATS: usize = 250;
+
+/// The longest numeral the `n` format reads.
+const MAX_NUMERAL_LENGTH: usize = 200;
+
+/// The streams behind the file handles of a state that haven't been closed.
+pub(crate) struct OpenFiles {
+    file_system: Box<dyn FileSystem>,
+    files: HashMap<*const (), OpenFile>,
+}
+
+impl Default for OpenFiles {
+    fn default() -> OpenFiles {
+        OpenFiles {
+            file_system: Box::new(StdFileSystem),
+            files: HashMap::new(),
+        }
+    }
+}
+
+impl OpenFiles {
+    /// Whether the file `name` can be opened for reading, as `package.searchpath` checks.
+    pub(crate) fn is_readable(&mut self, name: &[u8]) -> bool {
+        self.file_system.open(name, OpenMode::READ).is_ok()
+    }
+
+    /// Flushes every open file, for `os.exit`, and with `close` closes all but the standard ones.
+    /// Errors are dropped, as there is no one left to report them to.
+    pub(crate) fn flush_all(&mut self, close: bool) {
+        for open in self.files.values_mut() {
+            let _ = open.stream.flush();
+        }
+        if close {
+            self.files.retain(|_, open| open.standard);
+        }
+    }
+
+    /// Reads all of the file `name`, for `require`.
+    pub(crate) fn read_file(&mut self, name: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = self.file_system.open(name, OpenMode::READ)?;
+        let mut bytes = Vec::new();
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            match stream.read(&mut buffer)? {
+                0 => return Ok(bytes),
+                n => bytes.extend_from_slice(&buffer[..n]),
+            }
+        }
+    }
+}
+
+struct OpenFile {
+    stream: Box<dyn LuaStream>,
+    /// Bytes read ahead of the script, which has consumed them up to `start`.
+    buffer: Vec<u8>,
+    start: usize,
+    /// Whether this is one of the standard streams, which scripts can't close.
+    standard: bool,
+}
+
+impl OpenFile {
+    fn new(stream: Box<dyn LuaStream>, standard: bool) -> OpenFile {
+        OpenFile {
+            stream,
+            buffer: Vec::new(),
+            start: 0,
+            standard,
+        }
+    }
+
+    /// The bytes read ahead and not consumed yet, reading more if there are none. Empty at the
+    /// end of the stream.
+    fn fill(&mut self) -> io::Result<&[u8]> {
+        if self.start == self.buffer.len() {
+            self.buffer.resize(BUFFER_SIZE, 0);
+            self.start = 0;
+            let read = loop {
+                match self.stream.read(&mut self.buffer) {
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    read => break read,
+                }
+            };
+            self.buffer.truncate(*read.as_ref().unwrap_or(&0));
+            read?;
+        }
+        Ok(&self.buffer[self.start..])
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.fill()?.first().copied())
+    }
+
+    /// Moves the stream back over the bytes read ahead, so that writing and seeking happen where
+    /// the script left off.
+    fn unread(&mut self) -> io::Result<()> {
+        let ahead = self.buffer.len() - self.start;
+        self.buffer.clear();
+        self.start = 0;
+        if ahead > 0 {
+            self.stream.seek(SeekFrom::Current(-(ahead as i64)))?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.unread()?;
+        self.stream.write(data)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.unread()?;
+        self.stream.seek(pos)
+    }
+
+    /// The next line, with its newline if `keep_newline` is set, or `None` at the end of the
+    /// stream.
+    fn read_line(&mut self, keep_newline: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        loop {
+            let buffered = self.fill()?;
+            if buffered.is_empty() {
+                return Ok(if line.is_empty() { None } else { Some(line) });
+            }
+            match buffered.iter().position(|&c| c == b'\n') {
+                Some(i) => {
+                    let end = if keep_newline { i + 1 } else { i };
+                    line.extend_from_slice(&buffered[..end]);
+                    self.start += i + 1;
+                    return Ok(Some(line));
+                }
+                None => {
+                    line.extend_from_slice(buffered);
+                    self.start = self.buffer.len();
+                }
+            }
+        }
+    }
+
+    /// Up to `count` bytes, or `None` at the end of the stream. A count of 0 reads nothing but
+    /// still tells whether the end was reached.
+    fn read_count(&mut self, count: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut bytes = Vec::new();
+        loop {
+            let buffered = self.fill()?;
+            if buffered.is_empty() {
+                return Ok(if bytes.is_empty() { None } else { Some(bytes) });
+            }
+            let n = buffered.len().min(count - bytes.len());
+            bytes.extend_from_slice(&buffered[..n]);
+            self.start += n;
+            if bytes.len() == count {
+                return Ok(Some(bytes));
+            }
+        }
+    }
+
+    /// Everything up to the end of the stream.
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        loop {
+            let buffered = self.fill()?;
+            if buffered.is_empty() {
+                return Ok(bytes);
+            }
+            bytes.extend_from_slice(buffered);
+            self.start = self.buffer.len();
+        }
+    }
+
+    /// A number, read as the reference implementation reads it: the longest prefix of the input
+    /// that could start a numeral, which is then converted if it is one.
+    fn read_number(&mut self) -> io::Result<Option<Number>> {
+        while self
+            .peek()?
+            .is_some_and(|c| c.is_ascii_whitespace() || c == b'\x0b')
+        {
+            self.start += 1;
+        }
+        let mut numeral = Vec::new();
+        let mut count = 0;
+        let mut hex = false;
+        self.take(&mut numeral, |c| c == b'-' || c == b'+')?;
+        if self.take(&mut numeral, |c| c == b'0')? {
+            if self.take(&mut numeral, |c| c == b'x' || c == b'X')? {
+                hex = true;
+            } else {
+                count = 1;
+            }
+        }
+        count += self.take_digits(&mut numeral, hex)?;
+        if self.take(&mut numeral, |c| c == b'.')? {
+            count += self.take_digits(&mut numeral, hex)?;
+        }
+        let exponent: &[u8] = if hex { b"pP" } else { b"eE" };
+        if count > 0 && self.take(&mut numeral, |c| exponent.contains(&c))? {
+            self.take(&mut numeral, |c| c == b'-' || c == b'+')?;
+            self.take_digits(&mut numeral, false)?;
+        }
+        if numeral.len() > MAX_NUMERAL_LENGTH {
+            return Ok(None);
+        }
+        Ok(parse_number(&numeral))
+    }
+
+    /// Moves the next byte onto `numeral` if `accept` takes it and the numeral isn't already too
+    /// long to be one.
+    fn take(&mut self, numeral: &mut Vec<u8>, accept: impl Fn(u8) -> bool) -> io::Result<bool> {
+        match self.peek()? {
+            Some(c) if accept(c) && numeral.len() <= MAX_NUMERAL_LENGTH => {
+                numeral.push(c);
+                self.start += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn take_digits(&mut self, numeral: &mut Vec<u8>, hex: bool) -> io::Result<usize> {
+        let mut count = 0;
+        while self.take(numeral, |c| {
+            if hex {
+                c.is_ascii_hexdigit()
+            } else {
+                c.is_ascii_digit()
+            }
+        })? {
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Opens the io library on the host's files and the default standard streams.
+pub fn load_io(ctx: Context<'_>) {
+    load_io_with(ctx, IoOptions::default());
+}
+
+/// Opens the io library on the given file system and standard streams.
+pub fn load_io_with(ctx: Context<'_>, options: IoOptions) {
+    let methods = Table::new(&ctx);
+    set_function(ctx, methods, "close", file_close);
+    set_function(ctx, methods, "flush", file_flush);
+    set_function(ctx, methods, "lines", file_lines);
+    set_function(ctx, methods, "read", file_read);
+    set_function(ctx, methods, "seek", file_seek);
+    set_function(ctx, methods, "setvbuf", file_setvbuf);
+    set_function(ctx, methods, "write", file_write);
+
+    let metatable = Table::new(&ctx);
+    set_function(ctx, metatable, "__close", file_gc);
+    set_function(ctx, metatable, "__gc", file_gc);
+    set_function(ctx, metatable, "__tostring", file_tostring);
+    metatable
+        .set(&ctx, LuaString::new(&ctx, b"__index"), methods)
+        .expect("string keys are always valid");
+    metatable
+        .set(
+            &ctx,
+            LuaString::new(&ctx, b"__name"),
+            LuaString::new(&ctx, b"FILE*"),
+        )
+        .expect("string keys are always valid");
+    let registry = ctx.registry();
+    registry
+        .set(&ctx, LuaString::new(&ctx, b"FILE*"), metatable)
+        .expect("string keys are always valid");
+
+    ctx.state().files().borrow_mut().file_system = options.file_system;
+    let state = ctx.state();
+    let stdout = options
+        .stdout
+        .unwrap_or_else(|| Box::new(state.stdout().clone()));
+    let stderr = options
+        .stderr
+        .unwrap_or_else(|| Box::new(state.stderr().clone()));
+    let io = Table::new(&ctx);
+    for (name, stream) in [
+        ("stdin", options.stdin),
+        ("stdout", stdout),
+        ("stderr", stderr),
+    ] {
+        let file = new_file(ctx, stream, true);
+        io.set(&ctx, LuaString::new(&ctx, name.as_bytes()), file)
+            .expect("string keys are always valid");
+        let default = match name {
+            "stdin" => INPUT,
+            "stdout" => OUTPUT,
+            _ => continue,
+        };
+        registry
+            .set(&ctx, LuaString::new(&ctx, default.as_bytes()), file)
+            .expect("string keys are always valid");
+    }
+
+    set_function(ctx, io, "close", close);
+    set_function(ctx, io, "flush", flush);
+    set_function(ctx, io, "input", input);
+    set_function(ctx, io, "lines", lines);
+    set_function(ctx, io, "open", open);
+    set_function(ctx, io, "output", output);
+    set_function(ctx, io, "popen", popen);
+    set_function(ctx, io, "read", read);
+    set_function(ctx, io, "tmpfile", tmpfile);
+    set_function(ctx, io, "type", type_);
+    set_function(ctx, io, "write", write);
+    set_library(ctx, "io", io);
+}
+
+/// The registry keys of the default input and output files.
+const INPUT: &str = "_IO_input";
+const OUTPUT: &str = "_IO_output";
+
+/// A handle to a newly opened stream.
+fn new_file(ctx: Context<'_>, stream: Box<dyn LuaStream>, standard: bool) -> Table<'_> {
+    let file = Table::new(&ctx);
+    ops::set_metatable(ctx, file, file_metatable(ctx));
+    ctx.state()
+        .files()
+        .borrow_mut()
+        .files
+        .insert(file.as_ptr(), OpenFile::new(stream, standard));
+    file
+}
+
+fn file_metatable(ctx: Context<'_>) -> Option<Table<'_>> {
+    match ctx.registry().get_str("FILE*") {
+        Value::Table(metatable) => Some(metatable),
+        _ => None,
+    }
+}
+
+/// `value` as a file handle, open or closed.
+fn as_file<'gc>(ctx: Context<'gc>, value: Value<'gc>) -> Option<Table<'gc>> {
+    match value {
+        Value::Table(table)
+            if table.metatable().is_some() && table.metatable() == file_metatable(ctx) =>
+        {
+            Some(table)
+        }
+        _ => None,
+    }
+}
+
+fn is_open(ctx: Context<'_>, file: Table<'_>) -> bool {
+    ctx.state()
+        .files()
+        .borrow()
+        .files
+        .contains_key(&file.as_ptr())
+}
+
+/// Argument `n` to `name` as an open file handle.
+fn check_file<'gc>(
+    ctx: Context<'gc>,
+    stack: &Stack<'gc, '_>,
+    n: usize,
+    name: &str,
+) -> Result<Table<'gc>, RuntimeError> {
+    let file = as_file(ctx, stack.get(n - 1)).ok_or_else(|| type_error(stack, n, name, "FILE*"))?;
+    if !is_open(ctx, file) {
+        return Err(RuntimeError::new("attempt to use a closed file"));
+    }
+    Ok(file)
+}
+
+/// Runs `f` on the stream behind an open file handle.
+fn with_file<R>(
+    ctx: Context<'_>,
+    file: Table<'_>,
+    f: impl FnOnce(&mut OpenFile) -> R,
+) -> Result<R, RuntimeError> {
+    let mut files = ctx.state().files().borrow_mut();
+    match files.files.get_mut(&file.as_ptr()) {
+        Some(open) => Ok(f(open)),
+        None => Err(RuntimeError::new("attempt to use a closed file")),
+    }
+}
+
+/// The default input or output file, which must be open.
+fn default_file<'gc>(ctx: Context<'gc>, key: &str) -> Result<Table<'gc>, RuntimeError> {
+    match as_file(ctx, ctx.registry().get_str(key)) {
+        Some(file) if is_open(ctx, file) => Ok(file),
+        _ => Err(RuntimeError::new(format!(
+            "default {} file is closed",
+            if key == INPUT { "input" } else { "output" }
+        ))),
+    }
+}
+
+/// Replaces the stack with `success` or, if `result` is an error, with fail, its message and its
+/// error number.
+fn file_result<'gc, T>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    result: io::Result<T>,
+    success: impl FnOnce(T) -> Value<'gc>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    match result {
+        Ok(value) => stack.replace(&[success(value)]),
+        Err(err) => stack.replace(&io_error(ctx, &err, None)),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// Opens the file `name` in `mode`, raising an error if that fails.
+fn open_checked<'gc>(
+    ctx: Context<'gc>,
+    name: LuaString<'gc>,
+    mode: &[u8],
+) -> Result<Table<'gc>, RuntimeError> {
+    let mode = OpenMode::parse(mode).expect("valid mode");
+    let opened = ctx
+        .state()
+        .files()
+        .borrow_mut()
+        .file_system
+        .open(name.as_bytes(), mode);
+    match opened {
+        Ok(stream) => Ok(new_file(ctx, stream, false)),
+        Err(err) => {
+            let [_, message, _] = io_error(ctx, &err, None);
+            Err(RuntimeError::new(format!(
+                "cannot open file '{name}' ({message})"
+            )))
+        }
+    }
+}
+
+/// Closes an open file handle, returning true or fail, a message and an error number.
+fn close_file<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    file: Table<'gc>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let mut files = ctx.state().files().borrow_mut();
+    let standard = files
+        .files
+        .get(&file.as_ptr())
+        .is_some_and(|open| open.standard);
+    if standard {
+        stack.replace(&[
+            Value::Nil,
+            Value::String(LuaString::new(&ctx, b"cannot close standard file")),
+        ]);
+        return Ok(NativeReturn::Return);
+    }
+    let result = match files.files.remove(&file.as_ptr()) {
+        Some(mut open) => open.stream.flush(),
+        None => return Err(RuntimeError::new("attempt to use a closed file").into()),
+    };
+    drop(files);
+    file_result(ctx, stack, result, |()| Value::Boolean(true))
+}
+
+/// What a format of `read` and `lines` asks for.
+#[derive(Copy, Clone)]
+enum Format {
+    /// Up to this many bytes.
+    Count(usize),
+    Number,
+    Line {
+        keep_newline: bool,
+    },
+    All,
+}
+
+impl Format {
+    /// Parses argument `n` to `name`: a count, or a string whose first letter after an optional
+    /// `*` says what to read.
+    fn check<'gc>(
+        ctx: Context<'gc>,
+        stack: &Stack<'gc, '_>,
+        n: usize,
+        name: &str,
+    ) -> Result<Format, RuntimeError> {
+        if let Value::Integer(_) | Value::Number(_) = stack.get(n - 1) {
+            let count = check_integer(stack, n, name)?;
+            return Ok(Format::Count(usize::try_from(count).unwrap_or(usize::MAX)));
+        }
+        let format = check_string(ctx, stack, n, name)?;
+        let format = format.as_bytes();
+        let format = format.strip_prefix(b"*").unwrap_or(format);
+        match format.first() {
+            Some(b'n') => Ok(Format::Number),
+            Some(b'l') => Ok(Format::Line {
+                keep_newline: false,
+            }),
+            Some(b'L') => Ok(Format::Line { keep_newline: true }),
+            Some(b'a') => Ok(Format::All),
+            _ => Err(arg_error(n, name, "invalid format")),
+        }
+    }
+}
+
+/// Reads the formats in order until one fails, returning their results with nil for the failed
+/// one.
+fn read_formats<'gc>(
+    ctx: Context<'gc>,
+    file: Table<'gc>,
+    formats: &[Format],
+) -> Result<io::Result<Vec<Value<'gc>>>, RuntimeError> {
+    with_file(ctx, file, |open| {
+        let mut results = Vec::with_capacity(formats.len());
+        for &format in formats {
+            let string = |bytes: Option<Vec<u8>>| match bytes {
+                Some(bytes) => Value::String(LuaString::from_vec(&ctx, bytes)),
+                None => Value::Nil,
+            };
+            let value = match format {
+                Format::Count(count) => string(open.read_count(count)?),
+                Format::Number => match open.read_number()? {
+                    Some(Number::Integer(i)) => Value::Integer(i),
+                    Some(Number::Float(n)) => Value::Number(n),
+                    None => Value::Nil,
+                },
+                Format::Line { keep_newline } => string(open.read_line(keep_newline)?),
+                Format::All => string(Some(open.read_all()?)),
+            };
+            results.push(value);
+            if value.is_nil() {
+                break;
+            }
+        }
+        Ok(results)
+    })
+}
+
+/// The `read` of files and of the io library, reading from `file` in the formats given from
+/// argument `first` on, a line by default.
+fn read_file<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    file: Table<'gc>,
+    first: usize,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let formats = if stack.len() < first {
+        vec![Format::Line {
+            keep_newline: false,
+        }]
+    } else {
+        (first..=stack.len())
+            .map(|n| Format::check(ctx, stack, n, "read"))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    match read_formats(ctx, file, &formats)? {
+        Ok(results) => stack.replace(&results),
+        Err(err) => stack.replace(&io_error(ctx, &err, None)),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// The `write` of files and of the io library, writing the strings and numbers from argument
+/// `first` on to `file`. Returns the file, or fail, a message and an error number.
+fn write_file<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    file: Table<'gc>,
+    first: usize,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let mut data = Vec::new();
+    for n in first..=stack.len() {
+        match stack.get(n - 1) {
+            Value::Integer(i) => data.extend_from_slice(i.to_string().as_bytes()),
+            // As C's `%.14g`, rather than the way `tostring` shows floats.
+            Value::Number(x) => data.extend_from_slice(ops::format_g(x, 14).as_bytes()),
+            _ => data.extend_from_slice(check_string(ctx, stack, n, "write")?.as_bytes()),
+        }
+    }
+    let result = with_file(ctx, file, |open| open.write(&data))?;
+    file_result(ctx, stack, result, |()| Value::Table(file))
+}
+
+/// The iterator that the `lines` of files and of the io library return, reading the formats
+/// from argument `first` on, a line by default. With `close`, the file is closed once it is read
+/// through.
+fn lines_iterator<'gc>(
+    ctx: Context<'gc>,
+    stack: &Stack<'gc, '_>,
+    file: Table<'gc>,
+    first: usize,
+    close: bool,
+) -> Result<Value<'gc>, RuntimeError> {
+    let count = (stack.len() + 1).saturating_sub(first);
+    if count > MAX_LINE_FORMATS {
+        return Err(arg_error(
+            MAX_LINE_FORMATS + 2,
+            "lines",
+            "too many arguments",
+        ));
+    }
+    let mut upvalues = vec![Value::Table(file), Value::Boolean(close)];
+    for n in first..first + count {
+        Format::check(ctx, stack, n, "lines")?;
+        upvalues.push(stack.get(n - 1));
+    }
+    let closure = NativeClosure::new(&ctx, lines_step, &upvalues);
+    Ok(Value::Function(closure.into()))
+}
+
+fn lines_step<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let Value::Table(file) = stack.upvalue(0) else {
+        unreachable!("lines iterators keep their file first");
+    };
+    if !is_open(ctx, file) {
+        return Err(RuntimeError::new("file is already closed").into());
+    }
+    let close = stack.upvalue(1) == Value::Boolean(true);
+    // The formats were checked when the iterator was made.
+    stack.clear();
+    let mut n = 2;
+    while !stack.upvalue(n).is_nil() {
+        stack.push(stack.upvalue(n));
+        n += 1;
+    }
+    let formats = if stack.is_empty() {
+        vec![Format::Line {
+            keep_newline: false,
+        }]
+    } else {
+        (1..=stack.len())
+            .map(|n| Format::check(ctx, stack, n, "lines"))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let results = match read_formats(ctx, file, &formats)? {
+        Ok(results) => results,
+        Err(err) => {
+            let [_, message, _] = io_error(ctx, &err, None);
+            return Err(RuntimeError::new(message.to_string()).into());
+        }
+    };
+    if results.first().is_some_and(|first| !first.is_nil()) {
+        stack.replace(&results);
+        return Ok(NativeReturn::Return);
+    }
+    if close {
+        close_file(ctx, stack, file)?;
+    }
+    stack.replace(&[Value::Nil]);
+    Ok(NativeReturn::Return)
+}
+
+/// `io.close([file])`: closes `file`, or the default output file.
+fn close<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    if stack.is_empty() {
+        match as_file(ctx, ctx.registry().get_str(OUTPUT)) {
+            Some(file) => stack.push(Value::Table(file)),
+            None => return Err(RuntimeError::new("attempt to use a closed file").into()),
+        }
+    }
+    file_close(ctx, stack)
+}
+
+/// `io.flush()`: flushes the default output file.
+fn flush<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let file = default_file(ctx, OUTPUT)?;
+    let result = with_file(ctx, file, |open| open.stream.flush())?;
+    file_result(ctx, stack, result, |()| Value::Table(file))
+}
+
+/// The `io.input` and `io.output` functions: sets the default file `key` to the file handle
+/// given or to the file named, opened in `mode`, then returns it.
+fn default_file_function<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    key: &str,
+    mode: &[u8],
+    name: &str,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let registry = ctx.registry();
+    match stack.get(0) {
+        Value::Nil => {}
+        Value::String(file_name) => {
+            let file = open_checked(ctx, file_name, mode)?;
+            registry
+                .set(&ctx, LuaString::new(&ctx, key.as_bytes()), file)
+                .expect("string keys are always valid");
+        }
+        _ => {
+            let file = check_file(ctx, stack, 1, name)?;
+            registry
+                .set(&ctx, LuaString::new(&ctx, key.as_bytes()), file)
+                .expect("string keys are always valid");
+        }
+    }
+    stack.replace(&[registry.get_str(key)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `io.input([file])`: the default input file, after setting it to `file` if given, a handle or
+/// the name of a file to open for reading.
+fn input<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    default_file_function(ctx, stack, INPUT, b"r", "input")
+}
+
+/// `io.output([file])`: the default output file, after setting it to `file` if given, a handle
+/// or the name of a file to open for writing.
+fn output<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    default_file_function(ctx, stack, OUTPUT, b"w", "output")
+}
+
+/// `io.lines([name, ...])`: an iterator over the file `name` in the formats given, which closes
+/// the file at its end, followed by two nils and the file to close in a generic `for`. Without a
+/// name, iterates over the default input file and leaves it open.
+fn lines<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    if stack.get(0).is_nil() {
+        let file = match as_file(ctx, ctx.registry().get_str(INPUT)) {
+            Some(file) if is_open(ctx, file) => file,
+            _ => return Err(RuntimeError::new("attempt to use a closed file").into()),
+        };
+        let iterator = lines_iterator(ctx, stack, file, 2, false)?;
+        stack.replace(&[iterator]);
+        return Ok(NativeReturn::Return);
+    }
+    let name = check_string(ctx, stack, 1, "lines")?;
+    let file = open_checked(ctx, name, b"r")?;
+    let iterator = lines_iterator(ctx, stack, file, 2, true)?;
+    stack.replace(&[iterator, Value::Nil, Value::Nil, Value::Table(file)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `io.open(name [, mode])`: opens the file `name` in `mode`, `"r"` by default. Returns the file
+/// handle, or fail, a message and an error number.
+fn open<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let name = check_string(ctx, stack, 1, "open")?;
+    let mode = match stack.get(1) {
+        Value::Nil => LuaString::new(&ctx, b"r"),
+        _ => check_string(ctx, stack, 2, "open")?,
+    };
+    let mode =
+        OpenMode::parse(mode.as_bytes()).ok_or_else(|| arg_error(2, "open", "invalid mode"))?;
+    let opened = ctx
+        .state()
+        .files()
+        .borrow_mut()
+        .file_system
+        .open(name.as_bytes(), mode);
+    match opened {
+        Ok(stream) => stack.replace(&[Value::Table(new_file(ctx, stream, false))]),
+        Err(err) => stack.replace(&io_error(ctx, &err, Some(name.as_bytes()))),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `io.popen(prog [, mode])`: not supported, since the library doesn't start processes.
+fn popen<'gc>(
+    _ctx: Context<'gc>,
+    _stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    Err(RuntimeError::new("'popen' not supported").into())
+}
+
+/// `io.read(...)`: reads from the default input file, as `file:read` does.
+fn read<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let file = default_file(ctx, INPUT)?;
+    read_file(ctx, stack, file, 1)
+}
+
+/// `io.tmpfile()`: a new file opened for reading and writing, which is removed once closed.
+fn tmpfile<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let opened = ctx.state().files().borrow_mut().file_system.temporary();
+    file_result(ctx, stack, opened, |stream| {
+        Value::Table(new_file(ctx, stream, false))
+    })
+}
+
+/// `io.type(value)`: `"file"` for an open file handle, `"closed file"` for a closed one, and
+/// fail for anything else.
+fn type_<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    if stack.is_empty() {
+        return Err(arg_error(1, "type", "value expected").into());
+    }
+    let result = match as_file(ctx, stack.get(0)) {
+        Some(file) if is_open(ctx, file) => Value::String(LuaString::new(&ctx, b"file")),
+        Some(_) => Value::String(LuaString::new(&ctx, b"closed file")),
+        None => Value::Nil,
+    };
+    stack.replace(&[result]);
+    Ok(NativeReturn::Return)
+}
+
+/// `io.write(...)`: writes to the default output file, as `file:write` does.
+fn write<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let file = default_file(ctx, OUTPUT)?;
+    write_file(ctx, stack, file, 1)
+}
+
+/// `file:close()`: closes the file. Returns true, or fail, a message and an error number. The
+/// standard files can't be closed.
+fn file_close<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let file = check_file(ctx, stack, 1, "close")?;
+    close_file(ctx, stack, file)
+}
+
+/// `file:flush()`: writes out what the stream holds back. Returns the file, or fail, a message
+/// and an error number.
+fn file_flush<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let file = check_file(ctx, stack, 1, "flush")?;
+    let result = with_file(ctx, file, |open| open.stream.flush())?;
+    file_result(ctx, stack, result, |()| Value::Table(file))
+}
+
+/// `file:lines(...)`: an iterator reading the file in the formats given, a line by default,
+/// until one fails. The file stays open.
+fn file_lines<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let file = check_file(ctx, stack, 1, "lines")?;
+    let iterator = lines_iterator(ctx, stack, file, 2, false)?;
+    stack.replace(&[iterator]);
+    Ok(NativeReturn::Return)
+}
+
+/// `file:read(...)`: reads in each format given until one fails, returning a value for each and
+/// fail for the one that failed. The formats are `"n"` for a number, `"l"` for a line, `"L"` for
+/// a line with its newline, `"a"` for the rest of the file, and a count of bytes.
+fn file_read<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let file = check_file(ctx, stack, 1, "read")?;
+    read_file(ctx, stack, file, 2)
+}
+
+/// `file:seek([whence [, offset]])`: moves to `offset` bytes from the start (`"set"`), the
+/// current position (`"cur"`, the default) or the end (`"end"`), returning the new position from
+/// the start.
+fn file_seek<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let file = check_file(ctx, stack, 1, "seek")?;
+    let whence = match stack.get(1) {
+        Value::Nil => LuaString::new(&ctx, b"cur"),
+        _ => check_string(ctx, stack, 2, "seek")?,
+    };
+    let whence = whence.as_bytes();
+    let offset = opt_integer(stack, 3, "seek", 0)?;
+    let pos = match whence {
+        b"set" => match u64::try_from(offset) {
+            Ok(offset) => SeekFrom::Start(offset),
+            Err(_) => {
+                let err = io::Error::from(io::ErrorKind::InvalidInput);
+                return file_result(ctx, stack, Err::<(), _>(err), |()| Value::Nil);
+            }
+        },
+        b"cur" => SeekFrom::Current(offset),
+        b"end" => SeekFrom::End(offset),
+        _ => {
+            let message = format!("invalid option '{}'", String::from_utf8_lossy(whence));
+            return Err(arg_error(2, "seek", message).into());
+        }
+    };
+    let result = with_file(ctx, file, |open| open.seek(pos))?;
+    file_result(ctx, stack, result, |pos| Value::Integer(pos as i64))
+}
+
+/// `file:setvbuf(mode [, size])`: accepted for compatibility with `"no"`, `"full"` and `"line"`.
+/// Streams buffer as they see fit, so the mode changes nothing beyond flushing the file.
+fn file_setvbuf<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let file = check_file(ctx, stack, 1, "setvbuf")?;
+    let mode = check_string(ctx, stack, 2, "setvbuf")?;
+    let mode = mode.as_bytes();
+    if !matches!(mode, b"no" | b"full" | b"line") {
+        let message = format!("invalid option '{}'", String::from_utf8_lossy(mode));
+        return Err(arg_error(2, "setvbuf", message).into());
+    }
+    opt_integer(stack, 3, "setvbuf", 0)?;
+    let result = with_file(ctx, file, |open| open.stream.flush())?;
+    file_result(ctx, stack, result, |()| Value::Boolean(true))
+}
+
+/// `file:write(...)`: writes each string or number. Returns the file, or fail, a message and an
+/// error number.
+fn file_write<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let file = check_file(ctx, stack, 1, "write")?;
+    write_file(ctx, stack, file, 2)
+}
+
+/// `__gc` and `__close` of file handles: closes the file if it is still open.
+fn file_gc<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    if let Some(file) = as_file(ctx, stack.get(0)) {
+        let mut files = ctx.state().files().borrow_mut();
+        if files
+            .files
+            .get(&file.as_ptr())
+            .is_some_and(|open| !open.standard)
+        {
+            if let Some(mut open) = files.files.remove(&file.as_ptr()) {
+                let _ = open.stream.flush();
+            }
+        }
+    }
+    stack.clear();
+    Ok(NativeReturn::Return)
+}
+
+/// `__tostring` of file handles.
+fn file_tostring<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let file =
+        as_file(ctx, stack.get(0)).ok_or_else(|| type_error(stack, 1, "tostring", "FILE*"))?;
+    let text = if is_open(ctx, file) {
+        format!("file ({:p})", file.as_ptr())
+    } else {
+        "file (closed)".to_owned()
+    };
+    stack.replace(&[Value::String(LuaString::new(&ctx, text.as_bytes()))]);
+    Ok(NativeReturn::Return)
+}
+
+#[cfg(all(test, feature = "io"))]
+mod tests {
+    use super::*;
+    use crate::stdlib::testing::run_in;
+    use crate::Lua;
+
+    #[test]
+    #[cfg(feature = "os")]
+    fn files() {
+        let mut lua = Lua::new();
+        let result = run_in(
+            &mut lua,
+            "local name = os.tmpname()
+            local f = io.open(name, 'w')
+            f:write('line one\\n', 42, ' ', 3.0, ' ', 0.1, '\\n', '  -0x1p4 1e2 junk\\n', 'last')
+            f:close()
+            local lines = {}
+            for l in io.lines(name) do lines[#lines + 1] = l end
+            f = io.open(name)
+            local a, b, c = f:read('l', 'n', '*n')
+            local d, e = f:read('n', 'n')
+            local rest = f:read('L', 'a')
+            local eof = f:read(0)
+            local open = io.type(f)
+            f:close()
+            os.remove(name)
+            return #lines, lines[2], a, b, c, d, e, rest, eof, open, io.type(f), io.type(1)",
+        );
+        assert_eq!(
+            result,
+            "4, 42 3 0.1, line one, 42, 3, 0.1, -16.0,  1e2 junk\n, nil, file, closed file, nil"
+        );
+        let result = run_in(
+            &mut lua,
+            "local f = io.tmpfile()
+            f:write('hello world')
+            local pos = f:seek('set', 6)
+            local word = f:read(3)
+            local here, size = f:seek(), f:seek('end')
+            f:seek('set', 2)
+            f:write('L')
+            f:seek('set')
+            return pos, word, here, size, f:read(1), f:read('a'), f:read(0), f:read('a')",
+        );
+        assert_eq!(result, "6, wor, 9, 11, h, eLlo world, nil, ");
+        let result = run_in(
+            &mut lua,
+            "local f = io.tmpfile()
+            f:write('abc\\n\\ndef')
+            f:seek('set')
+            local t = {}
+            for a, b in f:lines(1, 'l') do t[#t + 1] = a .. '|' .. b end
+            return table.concat(t, ',')",
+        );
+        assert_eq!(result, "a|bc,\n|def");
+    }
+
+    #[test]
+    fn errors() {
+        let mut lua = Lua::new();
+        assert_eq!(
+            run_in(&mut lua, "return io.open('/nonexistent/file')"),
+            "nil, /nonexistent/file: No such file or directory, 2"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return io.open('file', 'rw')"),
+            "error: bad argument #2 to 'open' (invalid mode)"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return io.lines('/nonexistent/file')"),
+            "error: cannot open file '/nonexistent/file' (No such file or directory)"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return io.stdout:close()"),
+            "nil, cannot close standard file"
+        );
+        assert_eq!(
+            run_in(&mut lua, "local f = io.tmpfile() f:close() return f:read()"),
+            "error: attempt to use a closed file"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return io.stdin:read('x')"),
+            "error: bad argument #2 to 'read' (invalid format)"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return io.stdout:seek('top')"),
+            "error: bad argument #2 to 'seek' (invalid option 'top')"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return io.read({})"),
+            "error: bad argument #1 to 'read' (string expected, got table)"
+        );
+    }
+
+    type Buffer = Arc<Mutex<io::Cursor<Vec<u8>>>>;
+
+    /// A file system of in-memory files.
+    #[derive(Default)]
+    struct Memory {
+        files: HashMap<Vec<u8>, Buffer>,
+    }
+
+    impl FileSystem for Memory {
+        fn open(&mut self, name: &[u8], mode: OpenMode) -> io::Result<Box<dyn LuaStream>> {
+            if !mode.create && !self.files.contains_key(name) {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            let file = self.files.entry(name.to_vec()).or_default().clone();
+            if mode.truncate {
+                file.lock().unwrap().get_mut().clear();
+            }
+            file.lock().unwrap().set_position(0);
+            Ok(Box::new(file))
+        }
+    }
+
+    /// A stream supporting nothing.
+    struct Inert;
+
+    impl LuaStream for Inert {}
+
+    #[test]
+    fn custom_streams() {
+        let stdout = Arc::new(Mutex::new(io::Cursor::new(Vec::new())));
+        let mut lua = Lua::empty();
+        lua.enter(|ctx| {
+            load_io_with(
+                ctx,
+                IoOptions {
+                    file_system: Box::<Memory>::default(),
+                    stdin: Box::new(io::Cursor::new(b"first\nsecond\n".to_vec())),
+                    stdout: Some(Box::new(stdout.clone())),
+                    stderr: Some(Box::new(Inert)),
+                },
+            )
+        });
+        let result = run_in(
+            &mut lua,
+            "io.write(io.read(), '!')
+            io.output('out.txt')
+            io.write('to a file')
+            io.close()
+            io.output(io.stdout)
+            for line in io.lines('out.txt') do io.write(' ', line) end
+            return io.read('L'), io.read(), io.open('missing'), io.stderr:write('x')",
+        );
+        assert_eq!(
+            result,
+            "second\n, nil, nil, nil, operation not supported by the stream, 0"
+        );
+        assert_eq!(stdout.lock().unwrap().get_ref(), b"first! to a file");
+    }
+}
@@ -0,0 +1,106 @@
+//! The JSON library, set as the `json` global. [`Lua::new`](crate::Lua::new) leaves it out; hosts
+//! that want it open it with [`load_json`].
+//!
+//! Decoding marks arrays and turns `null` into `json.null`, so that encoding the result gives back
+//! the same document. `json.array` marks a table of the script's own, for an empty one to encode
+//! as `[]` rather than `{}`.
+
+use crate::serde::{array_metatable, from_json, null, to_json};
+use crate::vm::Stack;
+use crate::{Context, LuaError, LuaString, NativeReturn, Table, Value};
+
+use super::{arg_error, check_any, check_string, set_function, set_library, type_error};
+
+/// Opens the JSON library.
+pub fn load_json(ctx: Context<'_>) {
+    let json = Table::new(&ctx);
+    set_function(ctx, json, "array", array);
+    set_function(ctx, json, "decode", decode);
+    set_function(ctx, json, "encode", encode);
+    json.set(&ctx, LuaString::new(&ctx, b"null"), null(ctx))
+        .expect("string keys are always valid");
+    set_library(ctx, "json", json);
+}
+
+/// `json.encode(value [, pretty])`
+fn encode<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    check_any(stack, 1, "encode")?;
+    let json = to_json(ctx, stack.get(0)).map_err(|err| arg_error(1, "encode", err))?;
+    let text = if stack.get(1).to_bool() {
+        ::serde_json::to_string_pretty(&json)
+    } else {
+        ::serde_json::to_string(&json)
+    };
+    let text = text.expect("JSON values always serialize");
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, text.into_bytes()))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `json.decode(s)`
+fn decode<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "decode")?;
+    let json: ::serde_json::Value =
+        ::serde_json::from_slice(s.as_bytes()).map_err(|err| arg_error(1, "decode", err))?;
+    let value = from_json(ctx, &json)?;
+    stack.replace(&[value]);
+    Ok(NativeReturn::Return)
+}
+
+/// `json.array([t])`: marks `t`, or a new table, as an array and returns it.
+fn array<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let table = match stack.get(0) {
+        Value::Table(table) => table,
+        Value::Nil => Table::new(&ctx),
+        _ => return Err(type_error(stack, 1, "array", "table").into()),
+    };
+    table.set_metatable(&ctx, Some(array_metatable(ctx)));
+    stack.replace(&[Value::Table(table)]);
+    Ok(NativeReturn::Return)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::testing::run_in;
+    use crate::{stdlib, Lua};
+
+    fn run(source: &str) -> String {
+        let mut lua = Lua::new();
+        lua.enter(stdlib::load_json);
+        run_in(&mut lua, source)
+    }
+
+    #[test]
+    fn encoding_and_decoding() {
+        assert_eq!(
+            run("return json.encode({name = 'x', list = {1, 2.5, true}, empty = json.array()})"),
+            r#"{"empty":[],"list":[1,2.5,true],"name":"x"}"#
+        );
+        assert_eq!(
+            run(r#"local v = json.decode('{"a": [1, null, "s"], "b": {}}')
+                   return #v.a, v.a[2] == json.null, json.encode(v)"#),
+            r#"3, true, {"a":[1,null,"s"],"b":{}}"#
+        );
+        assert_eq!(run("return json.encode({}, true)"), "{}");
+        assert_eq!(
+            run("return json.decode('[1,')"),
+            "error: bad argument #1 to 'decode' (EOF while parsing a value at line 1 column 3)"
+        );
+        assert_eq!(
+            run("return json.encode({f = print})"),
+            "error: bad argument #1 to 'encode' (cannot represent a function)"
+        );
+        assert_eq!(
+            run("return json.array(1)"),
+            "error: bad argument #1 to 'array' (table expected, got number)"
+        );
+    }
+}
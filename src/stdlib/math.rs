@@ -0,0 +1,400 @@
+//! The math library, set as the `math` global.
+//!
+//! Functions keep integers as integers where the reference implementation does: `abs`, `fmod`,
+//! `max` and `min` of integers are integers, and `floor` and `ceil` give integers whenever the
+//! result fits in one.
+
+use std::f64::consts::PI;
+
+use crate::vm::{ops, Stack};
+use crate::{Context, LuaError, LuaString, NativeReturn, RuntimeError, Table, Value};
+
+use super::random::{entropy_seed, Random};
+use super::{arg_error, check_integer, check_number, set_function, set_library};
+
+/// Opens the math library.
+pub fn load_math(ctx: Context<'_>) {
+    let math = Table::new(&ctx);
+    set_function(ctx, math, "abs", abs);
+    set_function(ctx, math, "acos", |ctx, stack| {
+        float_fn(ctx, stack, "acos", f64::acos)
+    });
+    set_function(ctx, math, "asin", |ctx, stack| {
+        float_fn(ctx, stack, "asin", f64::asin)
+    });
+    set_function(ctx, math, "atan", atan);
+    set_function(ctx, math, "ceil", |ctx, stack| {
+        rounding(ctx, stack, "ceil", f64::ceil)
+    });
+    set_function(ctx, math, "cos", |ctx, stack| {
+        float_fn(ctx, stack, "cos", f64::cos)
+    });
+    set_function(ctx, math, "deg", |ctx, stack| {
+        float_fn(ctx, stack, "deg", |x| x * (180.0 / PI))
+    });
+    set_function(ctx, math, "exp", |ctx, stack| {
+        float_fn(ctx, stack, "exp", f64::exp)
+    });
+    set_function(ctx, math, "floor", |ctx, stack| {
+        rounding(ctx, stack, "floor", f64::floor)
+    });
+    set_function(ctx, math, "fmod", fmod);
+    set_function(ctx, math, "log", log);
+    set_function(ctx, math, "max", |ctx, stack| {
+        extreme(ctx, stack, "max", true)
+    });
+    set_function(ctx, math, "min", |ctx, stack| {
+        extreme(ctx, stack, "min", false)
+    });
+    set_function(ctx, math, "modf", modf);
+    set_function(ctx, math, "rad", |ctx, stack| {
+        float_fn(ctx, stack, "rad", |x| x * (PI / 180.0))
+    });
+    set_function(ctx, math, "random", random);
+    set_function(ctx, math, "randomseed", randomseed);
+    set_function(ctx, math, "sin", |ctx, stack| {
+        float_fn(ctx, stack, "sin", f64::sin)
+    });
+    set_function(ctx, math, "sqrt", |ctx, stack| {
+        float_fn(ctx, stack, "sqrt", f64::sqrt)
+    });
+    set_function(ctx, math, "tan", |ctx, stack| {
+        float_fn(ctx, stack, "tan", f64::tan)
+    });
+    set_function(ctx, math, "tointeger", tointeger);
+    set_function(ctx, math, "type", type_);
+    set_function(ctx, math, "ult", ult);
+
+    let constants = [
+        ("huge", Value::Number(f64::INFINITY)),
+        ("maxinteger", Value::Integer(i64::MAX)),
+        ("mininteger", Value::Integer(i64::MIN)),
+        ("pi", Value::Number(PI)),
+    ];
+    for (name, value) in constants {
+        math.set(&ctx, LuaString::new(&ctx, name.as_bytes()), value)
+            .expect("string keys are always valid");
+    }
+    set_library(ctx, "math", math);
+}
+
+/// Argument `n` to `name` as a float.
+fn check_float(stack: &Stack<'_, '_>, n: usize, name: &str) -> Result<f64, RuntimeError> {
+    Ok(check_number(stack, n, name)?
+        .to_number()
+        .expect("numbers convert to floats"))
+}
+
+/// A function of one float.
+fn float_fn<'gc>(
+    _ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    name: &str,
+    f: fn(f64) -> f64,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let x = check_float(stack, 1, name)?;
+    stack.replace(&[Value::Number(f(x))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.floor(x)` and `math.ceil(x)`: integers stay as they are, and floats are rounded with
+/// `round`, to an integer if the result fits in one.
+fn rounding<'gc>(
+    _ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    name: &str,
+    round: fn(f64) -> f64,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let result = match stack.get(0) {
+        integer @ Value::Integer(_) => integer,
+        _ => {
+            let rounded = round(check_float(stack, 1, name)?);
+            match Value::Number(rounded).to_integer() {
+                Some(i) => Value::Integer(i),
+                None => Value::Number(rounded),
+            }
+        }
+    };
+    stack.replace(&[result]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.abs(x)`: the absolute value of `x`. The smallest integer is its own.
+fn abs<'gc>(_ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let result = match stack.get(0) {
+        Value::Integer(i) => Value::Integer(i.wrapping_abs()),
+        _ => Value::Number(check_float(stack, 1, "abs")?.abs()),
+    };
+    stack.replace(&[result]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.atan(y [, x])`: the arc tangent of `y / x` (`x` being 1 by default), in the quadrant of
+/// the point `(x, y)`.
+fn atan<'gc>(
+    _ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let y = check_float(stack, 1, "atan")?;
+    let x = match stack.get(1) {
+        Value::Nil => 1.0,
+        _ => check_float(stack, 2, "atan")?,
+    };
+    stack.replace(&[Value::Number(y.atan2(x))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.log(x [, base])`: the logarithm of `x` in `base`, `e` by default.
+fn log<'gc>(_ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let x = check_float(stack, 1, "log")?;
+    let result = match stack.get(1) {
+        Value::Nil => x.ln(),
+        _ => match check_float(stack, 2, "log")? {
+            2.0 => x.log2(),
+            10.0 => x.log10(),
+            base => x.ln() / base.ln(),
+        },
+    };
+    stack.replace(&[Value::Number(result)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.fmod(x, y)`: the remainder of `x / y` rounding the quotient towards zero, an integer if
+/// both are.
+fn fmod<'gc>(
+    _ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let result = match (stack.get(0), stack.get(1)) {
+        (Value::Integer(_), Value::Integer(0)) => {
+            return Err(arg_error(2, "fmod", "zero").into());
+        }
+        (Value::Integer(x), Value::Integer(y)) => Value::Integer(x.wrapping_rem(y)),
+        _ => Value::Number(check_float(stack, 1, "fmod")? % check_float(stack, 2, "fmod")?),
+    };
+    stack.replace(&[result]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.modf(x)`: the integral part of `x`, rounded towards zero, and its fractional part. The
+/// integral part of a float is a float.
+fn modf<'gc>(
+    _ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let results = match stack.get(0) {
+        integer @ Value::Integer(_) => [integer, Value::Number(0.0)],
+        _ => {
+            let x = check_float(stack, 1, "modf")?;
+            let integral = x.trunc();
+            // Infinities have no fractional part, rather than a NaN one.
+            let fraction = if x == integral { 0.0 } else { x - integral };
+            [Value::Number(integral), Value::Number(fraction)]
+        }
+    };
+    stack.replace(&results);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.max(x, ...)` and `math.min(x, ...)`: the largest or smallest argument, as it was given.
+fn extreme<'gc>(
+    _ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    name: &str,
+    largest: bool,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let mut best = check_number(stack, 1, name)?;
+    for n in 2..=stack.len() {
+        let value = check_number(stack, n, name)?;
+        let better = if largest {
+            ops::less_than(best, value)
+        } else {
+            ops::less_than(value, best)
+        };
+        if better == Some(true) {
+            best = value;
+        }
+    }
+    stack.replace(&[best]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.tointeger(x)`: `x` as an integer if it has an integer value, or else fail.
+fn tointeger<'gc>(
+    _ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    if stack.is_empty() {
+        return Err(arg_error(1, "tointeger", "value expected").into());
+    }
+    let result = ops::coerce_number(stack.get(0))
+        .and_then(|n| n.to_integer())
+        .map_or(Value::Nil, Value::Integer);
+    stack.replace(&[result]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.type(x)`: `"integer"` or `"float"` for a number, or else fail.
+fn type_<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    if stack.is_empty() {
+        return Err(arg_error(1, "type", "value expected").into());
+    }
+    let result = match stack.get(0) {
+        Value::Integer(_) => Value::String(LuaString::new(&ctx, b"integer")),
+        Value::Number(_) => Value::String(LuaString::new(&ctx, b"float")),
+        _ => Value::Nil,
+    };
+    stack.replace(&[result]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.ult(m, n)`: whether `m` is less than `n` when both are taken as unsigned.
+fn ult<'gc>(_ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let m = check_integer(stack, 1, "ult")?;
+    let n = check_integer(stack, 2, "ult")?;
+    stack.replace(&[Value::Boolean((m as u64) < (n as u64))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.random([m [, n]])`: a float in `[0, 1)` without arguments, or else an integer in
+/// `[m, n]`, `m` being 1 when only `n` is given. `math.random(0)` is an integer with all bits
+/// random.
+fn random<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let cell = ctx.state().random();
+    let mut random = cell.get();
+    let (low, up) = match stack.len() {
+        0 => {
+            let result = Value::Number(random.next_float());
+            cell.set(random);
+            stack.replace(&[result]);
+            return Ok(NativeReturn::Return);
+        }
+        1 => (1, check_integer(stack, 1, "random")?),
+        2 => (
+            check_integer(stack, 1, "random")?,
+            check_integer(stack, 2, "random")?,
+        ),
+        _ => return Err(RuntimeError::new("wrong number of arguments").into()),
+    };
+    let result = if stack.len() == 1 && up == 0 {
+        random.next_u64() as i64
+    } else if low <= up {
+        random.next_in(low, up)
+    } else {
+        return Err(arg_error(1, "random", "interval is empty").into());
+    };
+    cell.set(random);
+    stack.replace(&[Value::Integer(result)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `math.randomseed([n1 [, n2]])`: seeds the generator with the integers given, or with an
+/// unpredictable seed without arguments. Returns the two parts of the seed.
+fn randomseed<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (n1, n2) = if stack.is_empty() {
+        match ctx.entropy() {
+            true => entropy_seed(),
+            false => (0, 0),
+        }
+    } else {
+        let n1 = check_integer(stack, 1, "randomseed")?;
+        let n2 = match stack.get(1) {
+            Value::Nil => 0,
+            _ => check_integer(stack, 2, "randomseed")?,
+        };
+        (n1, n2)
+    };
+    ctx.state().random().set(Random::new(n1, n2));
+    stack.replace(&[Value::Integer(n1), Value::Integer(n2)]);
+    Ok(NativeReturn::Return)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::testing::run;
+    use crate::Lua;
+
+    #[test]
+    fn integers_and_floats() {
+        assert_eq!(
+            run("return math.floor(3.7), math.ceil(-3.7), math.floor(2^70), math.floor(5)"),
+            "3, -3, 1.1805916207174e+21, 5"
+        );
+        assert_eq!(
+            run("return math.abs(-3), math.abs(-2.5), math.abs(math.mininteger)"),
+            "3, 2.5, -9223372036854775808"
+        );
+        assert_eq!(
+            run("return math.fmod(7, -3), math.fmod(-7, 3), math.fmod(7.5, 2), math.fmod(math.mininteger, -1)"),
+            "1, -1, 1.5, 0"
+        );
+        assert_eq!(run("return math.modf(3.7)"), "3.0, 0.7");
+        assert_eq!(run("return math.modf(-math.huge)"), "-inf, 0.0");
+        assert_eq!(run("return math.modf(5)"), "5, 0.0");
+        assert_eq!(
+            run("return math.max(1, 2.5, 2), math.min(3, 1.0, 1), math.type(1), math.type(1.0), math.type('1')"),
+            "2.5, 1.0, integer, float, nil"
+        );
+        assert_eq!(
+            run("return math.tointeger(3.0), math.tointeger(3.5), math.tointeger('8'), math.ult(1, -1)"),
+            "3, nil, 8, true"
+        );
+        assert_eq!(
+            run("return math.maxinteger + 1 == math.mininteger, math.huge, math.pi"),
+            "true, inf, 3.1415926535898"
+        );
+        assert_eq!(
+            run("return math.log(8, 2), math.log(100, 10), math.exp(0)"),
+            "3.0, 2.0, 1.0"
+        );
+        assert_eq!(
+            run("return math.fmod(1, 0)"),
+            "error: bad argument #2 to 'fmod' (zero)"
+        );
+        assert_eq!(
+            run("return math.max()"),
+            "error: bad argument #1 to 'max' (number expected, got no value)"
+        );
+    }
+
+    #[test]
+    fn random_numbers() {
+        let mut lua = Lua::new();
+        let draw = |lua: &mut Lua| {
+            lua.enter(|ctx| {
+                ctx.set_random_seed(42);
+                let values = ctx
+                    .eval("return math.random(1, 100), math.random(), math.random(0)")
+                    .unwrap();
+                values.iter().map(|v| v.to_string()).collect::<Vec<_>>()
+            })
+        };
+        let first = draw(&mut lua);
+        assert_eq!(first[0], "50");
+        assert_eq!(first, draw(&mut lua));
+        assert_eq!(
+            run("math.randomseed(7, 1)
+                local a = {math.random(10), math.random(-5, 5)}
+                math.randomseed(7, 1)
+                return a[1] == math.random(10) and a[2] == math.random(-5, 5), select('#', math.randomseed())"),
+            "true, 2"
+        );
+        assert_eq!(
+            run("return math.random(2, 1)"),
+            "error: bad argument #1 to 'random' (interval is empty)"
+        );
+        assert_eq!(
+            run("return math.random(1, 2, 3)"),
+            "error: wrong number of arguments"
+        );
+    }
+}
@@ -0,0 +1,282 @@
+//! Lua's standard library, written as native functions.
+//!
+//! Each library is opened separately into a state's globals, so an embedder can leave out the ones
+//! a script shouldn't have.
+
+pub mod pack;
+pub mod pattern;
+pub mod random;
+
+mod base;
+mod coroutine;
+mod debug;
+mod format;
+mod inspect;
+#[cfg_attr(not(feature = "io"), allow(dead_code))]
+mod io;
+#[cfg(feature = "json")]
+mod json;
+mod math;
+#[cfg(feature = "os")]
+mod os;
+mod package;
+mod string;
+mod table;
+mod utf8;
+
+pub use self::base::load_base;
+pub use self::coroutine::load_coroutine;
+pub use self::debug::load_debug;
+pub use self::inspect::{inspect, load_inspect, InspectOptions};
+#[cfg(feature = "io")]
+pub use self::io::{load_io, load_io_with, IoOptions};
+pub use self::io::{FileSystem, LuaStream, OpenMode, StdFileSystem};
+#[cfg(feature = "json")]
+pub use self::json::load_json;
+pub use self::math::load_math;
+#[cfg(feature = "os")]
+pub use self::os::{load_os, load_os_with, OsOptions};
+pub use self::package::{load_package, load_package_with, Module, PackageOptions, Searcher};
+pub use self::string::load_string;
+pub use self::table::load_table;
+pub use self::utf8::load_utf8;
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+
+pub(crate) use self::io::OpenFiles;
+
+use crate::vm::{self, ops, Stack};
+use crate::{Context, Function, LuaError, LuaString, NativeFn, RuntimeError, Table, Thread, Value};
+
+/// Sets `table[name]` to a native function.
+fn set_function<'gc>(ctx: Context<'gc>, table: Table<'gc>, name: &str, f: NativeFn) {
+    let key = Value::String(LuaString::new(&ctx, name.as_bytes()));
+    table
+        .set(&ctx, key, Value::Function(Function::Native(f)))
+        .expect("string keys are always valid");
+}
+
+/// Sets a library's table as the global `name`, and as `package.loaded[name]` so that `require`
+/// finds it.
+fn set_library<'gc>(ctx: Context<'gc>, name: &str, library: Table<'gc>) {
+    let name = LuaString::new(&ctx, name.as_bytes());
+    for table in [ctx.globals(), loaded(ctx)] {
+        table
+            .set(&ctx, name, library)
+            .expect("string keys are always valid");
+    }
+}
+
+/// The table of loaded modules, `package.loaded`, which the registry keeps as `_LOADED`.
+pub(crate) fn loaded(ctx: Context<'_>) -> Table<'_> {
+    let registry = ctx.registry();
+    if let Value::Table(loaded) = registry.get_str("_LOADED") {
+        return loaded;
+    }
+    let loaded = Table::new(&ctx);
+    registry
+        .set(&ctx, LuaString::new(&ctx, b"_LOADED"), loaded)
+        .expect("string keys are always valid");
+    loaded
+}
+
+/// The error for a bad argument `n` (counting from 1) to the function `name`.
+fn arg_error(n: usize, name: &str, message: impl fmt::Display) -> RuntimeError {
+    RuntimeError::new(format!("bad argument #{n} to '{name}' ({message})"))
+}
+
+/// The error for argument `n` to `name` not being what the function expected.
+fn type_error(stack: &Stack<'_, '_>, n: usize, name: &str, expected: &str) -> RuntimeError {
+    let got = if n > stack.len() {
+        "no value"
+    } else {
+        stack.get(n - 1).type_name()
+    };
+    arg_error(n, name, format!("{expected} expected, got {got}"))
+}
+
+/// Argument `n` to `name` as a string. Numbers are converted, as the reference implementation does.
+fn check_string<'gc>(
+    ctx: Context<'gc>,
+    stack: &Stack<'gc, '_>,
+    n: usize,
+    name: &str,
+) -> Result<LuaString<'gc>, RuntimeError> {
+    match stack.get(n - 1) {
+        Value::String(s) => Ok(s),
+        v @ (Value::Integer(_) | Value::Number(_)) => {
+            let mut bytes = Vec::new();
+            ops::write_concat_operand(&mut bytes, v);
+            Ok(LuaString::from_vec(&ctx, bytes))
+        }
+        _ => Err(type_error(stack, n, name, "string")),
+    }
+}
+
+/// Argument `n` to `name` as an integer. Floats with an integer value and strings holding one are
+/// accepted too.
+fn check_integer(stack: &Stack<'_, '_>, n: usize, name: &str) -> Result<i64, RuntimeError> {
+    match ops::coerce_number(stack.get(n - 1)) {
+        Some(v) => v
+            .to_integer()
+            .ok_or_else(|| arg_error(n, name, "number has no integer representation")),
+        None => Err(type_error(stack, n, name, "number")),
+    }
+}
+
+/// Argument `n` to `name` as a number, an integer or a float. Strings holding one are accepted too.
+fn check_number<'gc>(
+    stack: &Stack<'gc, '_>,
+    n: usize,
+    name: &str,
+) -> Result<Value<'gc>, RuntimeError> {
+    ops::coerce_number(stack.get(n - 1)).ok_or_else(|| type_error(stack, n, name, "number"))
+}
+
+/// Raises the error for a missing argument `n` (counting from 1) to `name`, if it is missing.
+fn check_any(stack: &Stack<'_, '_>, n: usize, name: &str) -> Result<(), RuntimeError> {
+    if stack.len() < n {
+        return Err(arg_error(n, name, "value expected"));
+    }
+    Ok(())
+}
+
+/// Like [`check_integer`], but with a default for an absent or nil argument.
+fn opt_integer(
+    stack: &Stack<'_, '_>,
+    n: usize,
+    name: &str,
+    default: i64,
+) -> Result<i64, RuntimeError> {
+    if stack.get(n - 1).is_nil() {
+        Ok(default)
+    } else {
+        check_integer(stack, n, name)
+    }
+}
+
+/// The results a library function gives for a failed operation on a file: nil, a message with the
+/// file's name if there is one, and the error number, or 0 if the error didn't come from the
+/// operating system.
+fn io_error<'gc>(ctx: Context<'gc>, err: &std::io::Error, name: Option<&[u8]>) -> [Value<'gc>; 3] {
+    let mut message = Vec::new();
+    if let Some(name) = name {
+        message.extend_from_slice(name);
+        message.extend_from_slice(b": ");
+    }
+    let text = err.to_string();
+    // Keep only the system's description, as `strerror` gives it.
+    let text = match text.rfind(" (os error ") {
+        Some(end) => &text[..end],
+        None => &text,
+    };
+    message.extend_from_slice(text.as_bytes());
+    [
+        Value::Nil,
+        Value::String(LuaString::from_vec(&ctx, message)),
+        Value::Integer(err.raw_os_error().unwrap_or(0) as i64),
+    ]
+}
+
+/// The path a script names with `name`. Where paths aren't made of bytes, invalid UTF-8 in the name
+/// is replaced.
+fn path(name: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(std::ffi::OsStr::from_bytes(name))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(name).into_owned())
+    }
+}
+
+/// Whether the target has a clock and a temporary directory. `wasm32-unknown-unknown` has
+/// neither, and the standard library panics when asked for them there.
+const HOSTED: bool = !cfg!(all(target_arch = "wasm32", target_os = "unknown"));
+
+/// Creates a new, empty file in the temporary directory, open for reading and writing.
+fn temporary_file() -> std::io::Result<(PathBuf, fs::File)> {
+    if !HOSTED {
+        return Err(std::io::ErrorKind::Unsupported.into());
+    }
+    let dir = std::env::temp_dir();
+    let mut last_error = None;
+    for _ in 0..100 {
+        let suffix = RandomState::new().build_hasher().finish() & 0xff_ffff;
+        let path = dir.join(format!("lua_{suffix:06x}"));
+        let created = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path);
+        match created {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => last_error = Some(err),
+            Err(err) => return Err(err),
+        }
+    }
+    Err(last_error.expect("at least one attempt was made"))
+}
+
+/// Converts any value to a string as `tostring` does: with its `__tostring` metamethod if it has
+/// one, or else as its type and address, the type named by the `__name` field of its metatable if
+/// that is a string.
+fn to_string<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    value: Value<'gc>,
+) -> Result<LuaString<'gc>, LuaError<'gc>> {
+    let handler = ops::metamethod(ctx, value, "__tostring");
+    let value = if handler.is_nil() {
+        value
+    } else {
+        let result = vm::call(ctx, thread, handler, &[value])?;
+        match result.first().copied().unwrap_or_default() {
+            result @ (Value::String(_) | Value::Integer(_) | Value::Number(_)) => result,
+            _ => return Err(RuntimeError::new("'__tostring' must return a string").into()),
+        }
+    };
+    let address = match value {
+        Value::String(s) => return Ok(s),
+        Value::Table(t) => t.as_ptr(),
+        Value::Function(f) => f.as_ptr(),
+        Value::Thread(t) => t.as_ptr(),
+        Value::UserData(u) => u.as_ptr(),
+        _ => return Ok(LuaString::new(&ctx, value.to_string().as_bytes())),
+    };
+    let text = match ops::metamethod(ctx, value, "__name") {
+        Value::String(name) => format!("{name}: {address:p}"),
+        _ => value.to_string(),
+    };
+    Ok(LuaString::new(&ctx, text.as_bytes()))
+}
+
+/// Running scripts in the tests of the libraries.
+#[cfg(test)]
+pub(crate) mod testing {
+    use crate::Lua;
+
+    /// Runs `source` in a state with the standard library, returning its results separated by
+    /// commas, or the error message after `error: `.
+    pub(crate) fn run(source: &str) -> String {
+        run_in(&mut Lua::new(), source)
+    }
+
+    /// Like [`run`], in `lua`.
+    pub(crate) fn run_in(lua: &mut Lua, source: &str) -> String {
+        lua.enter(|ctx| match ctx.eval(source) {
+            Ok(values) => values
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            Err(err) => format!("error: {err}"),
+        })
+    }
+}
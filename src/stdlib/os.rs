@@ -0,0 +1,840 @@
+//! The operating system library, set as the `os` global.
+//!
+//! Each function that reaches outside the state can be left out with [`OsOptions`], so that a
+//! sandbox can deny scripts the clock, the environment or the filesystem.
+//!
+//! Dates are worked out without the C library. Local time is UTC shifted by a fixed offset the
+//! host gives, so there is no daylight saving time and `isdst` is always false. `os.clock` has no
+//! portable measure of processor time to go by, and counts the seconds elapsed since the library
+//! was first opened instead.
+//!
+//! On `wasm32-unknown-unknown` there is no clock at all unless the host gives one through
+//! [`OsOptions::clock_source`] and [`OsOptions::time_source`]; without them, the functions that
+//! need the time raise an error.
+
+use std::fs;
+use std::io;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::vm::{self, ops, Stack};
+use crate::{Context, Function, LuaError, LuaString, NativeReturn, RuntimeError, Table, Value};
+
+use super::{
+    arg_error, check_integer, check_string, io_error, path, set_function, set_library,
+    temporary_file, type_error, HOSTED,
+};
+
+/// Which functions of the os library to open, and how to tell local time.
+#[derive(Debug, Copy, Clone)]
+pub struct OsOptions {
+    pub clock: bool,
+    pub date: bool,
+    pub exit: bool,
+    pub getenv: bool,
+    pub remove: bool,
+    pub rename: bool,
+    pub time: bool,
+    pub tmpname: bool,
+    /// Seconds east of UTC that local time is.
+    pub utc_offset: i64,
+    /// What `os.clock` returns, in seconds, in place of the time since the library was opened.
+    pub clock_source: Option<fn() -> f64>,
+    /// The current time in seconds since the epoch, for `os.time` and `os.date`, in place of the
+    /// system clock.
+    pub time_source: Option<fn() -> i64>,
+}
+
+impl OsOptions {
+    /// Options leaving out every function that reads the clock or the environment, touches the
+    /// filesystem, or ends the process. Only `os.difftime` remains.
+    pub fn sandboxed() -> OsOptions {
+        OsOptions {
+            clock: false,
+            date: false,
+            exit: false,
+            getenv: false,
+            remove: false,
+            rename: false,
+            time: false,
+            tmpname: false,
+            utc_offset: 0,
+            clock_source: None,
+            time_source: None,
+        }
+    }
+}
+
+impl Default for OsOptions {
+    /// All functions, with local time being UTC.
+    fn default() -> OsOptions {
+        OsOptions {
+            clock: true,
+            date: true,
+            exit: true,
+            getenv: true,
+            remove: true,
+            rename: true,
+            time: true,
+            tmpname: true,
+            utc_offset: 0,
+            clock_source: None,
+            time_source: None,
+        }
+    }
+}
+
+/// When `os.clock` started counting.
+static CLOCK_START: OnceLock<Instant> = OnceLock::new();
+
+/// Opens the os library with all its functions, local time being UTC.
+pub fn load_os(ctx: Context<'_>) {
+    load_os_with(ctx, OsOptions::default());
+}
+
+/// Opens the os library with the functions `options` allows.
+pub fn load_os_with(ctx: Context<'_>, options: OsOptions) {
+    let os = Table::new(&ctx);
+    set_function(ctx, os, "difftime", difftime);
+    let mut functions = Vec::new();
+    if options.clock {
+        let source = options.clock_source;
+        if source.is_none() && HOSTED {
+            CLOCK_START.get_or_init(Instant::now);
+        }
+        functions.push((
+            "clock",
+            Function::from_fn(&ctx, move |ctx, stack| clock(ctx, stack, source)),
+        ));
+    }
+    let local = LocalTime {
+        offset: options.utc_offset,
+        source: options.time_source,
+    };
+    if options.date {
+        functions.push((
+            "date",
+            Function::from_fn(&ctx, move |ctx, stack| date(ctx, stack, local)),
+        ));
+    }
+    if options.time {
+        functions.push((
+            "time",
+            Function::from_fn(&ctx, move |ctx, stack| time(ctx, stack, local)),
+        ));
+    }
+    for (name, f) in functions {
+        os.set(&ctx, LuaString::new(&ctx, name.as_bytes()), f)
+            .expect("string keys are always valid");
+    }
+    if options.exit {
+        set_function(ctx, os, "exit", exit);
+    }
+    if options.getenv {
+        set_function(ctx, os, "getenv", getenv);
+    }
+    if options.remove {
+        set_function(ctx, os, "remove", remove);
+    }
+    if options.rename {
+        set_function(ctx, os, "rename", rename);
+    }
+    if options.tmpname {
+        set_function(ctx, os, "tmpname", tmpname);
+    }
+    set_library(ctx, "os", os);
+}
+
+/// `os.clock()`: the seconds elapsed since the library was first opened, or what the host's
+/// `source` or [`Context::set_clock`] says, as a float.
+fn clock<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    source: Option<fn() -> f64>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let seconds = match (ctx.clock(), source) {
+        (Some(seconds), _) => seconds,
+        (None, Some(source)) => source(),
+        (None, None) if HOSTED => CLOCK_START
+            .get_or_init(Instant::now)
+            .elapsed()
+            .as_secs_f64(),
+        (None, None) => {
+            return Err(RuntimeError::new("the clock is unavailable on this platform").into())
+        }
+    };
+    stack.replace(&[Value::Number(seconds)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `os.difftime(t2, t1)`: the seconds from `t1` to `t2`, as a float.
+fn difftime<'gc>(
+    _ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let t2 = check_integer(stack, 1, "difftime")?;
+    let t1 = check_integer(stack, 2, "difftime")?;
+    stack.replace(&[Value::Number(t2 as f64 - t1 as f64)]);
+    Ok(NativeReturn::Return)
+}
+
+/// How `os.time` and `os.date` tell the time.
+#[derive(Copy, Clone)]
+struct LocalTime {
+    /// Seconds east of UTC that local time is.
+    offset: i64,
+    source: Option<fn() -> i64>,
+}
+
+impl LocalTime {
+    /// The current time, in seconds since the epoch.
+    fn now(self, ctx: Context<'_>) -> Result<i64, RuntimeError> {
+        if let Some(time) = ctx.time() {
+            return Ok(time);
+        }
+        if let Some(source) = self.source {
+            return Ok(source());
+        }
+        if !HOSTED {
+            return Err(RuntimeError::new(
+                "the time is unavailable on this platform",
+            ));
+        }
+        Ok(match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        })
+    }
+}
+
+/// `os.time([t])`: the current time, or the time the date table `t` describes, in seconds since
+/// the epoch. The fields of `t` are normalized in place, so `{year = 2000, month = 13, day = 1}`
+/// becomes the first of January 2001.
+fn time<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    local: LocalTime,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let offset = local.offset;
+    let table = match stack.get(0) {
+        Value::Nil => {
+            stack.replace(&[Value::Integer(local.now(ctx)?)]);
+            return Ok(NativeReturn::Return);
+        }
+        table @ Value::Table(_) => table,
+        _ => return Err(type_error(stack, 1, "time", "table").into()),
+    };
+    let thread = stack.thread();
+    let field = |name: &str, default: Option<i64>, delta: i64| -> Result<i64, LuaError<'gc>> {
+        let key = Value::String(LuaString::new(&ctx, name.as_bytes()));
+        let value = vm::index(ctx, thread, table, key)?;
+        match ops::coerce_number(value).and_then(|n| n.to_integer()) {
+            Some(n) => {
+                // The reference implementation keeps fields in C `int`s.
+                let fits = if n >= 0 {
+                    n - delta <= i32::MAX as i64
+                } else {
+                    i32::MIN as i64 + delta <= n
+                };
+                match fits {
+                    true => Ok(n - delta),
+                    false => {
+                        Err(RuntimeError::new(format!("field '{name}' is out-of-bound")).into())
+                    }
+                }
+            }
+            None if !value.is_nil() => {
+                Err(RuntimeError::new(format!("field '{name}' is not an integer")).into())
+            }
+            None => default.ok_or_else(|| {
+                RuntimeError::new(format!("field '{name}' missing in date table")).into()
+            }),
+        }
+    };
+    let year = field("year", None, 1900)? + 1900;
+    let month = field("month", None, 1)?;
+    let day = field("day", None, 0)?;
+    let hour = field("hour", Some(12), 0)?;
+    let min = field("min", Some(0), 0)?;
+    let sec = field("sec", Some(0), 0)?;
+
+    let days = days_from_civil(year + month.div_euclid(12), month.rem_euclid(12) + 1, 1) + day - 1;
+    let t = days * 86400 + hour * 3600 + min * 60 + sec - offset;
+    let date = DateTime::new(t, offset).ok_or_else(|| {
+        RuntimeError::new("time result cannot be represented in this installation")
+    })?;
+    for (name, value) in date.fields() {
+        let key = Value::String(LuaString::new(&ctx, name.as_bytes()));
+        vm::new_index(ctx, thread, table, key, value)?;
+    }
+    stack.replace(&[Value::Integer(t)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `os.date([format [, time]])`: `time` (now by default) formatted as `strftime` does in the C
+/// locale, `"%c"` by default. A format starting with `!` gives UTC rather than local time, and
+/// with `*t` after that a date table is returned instead.
+fn date<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    local: LocalTime,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let format = match stack.get(0) {
+        Value::Nil => LuaString::new(&ctx, b"%c"),
+        _ => check_string(ctx, stack, 1, "date")?,
+    };
+    let t = match stack.get(1) {
+        Value::Nil => local.now(ctx)?,
+        _ => check_integer(stack, 2, "date")?,
+    };
+    let format = format.as_bytes();
+    let (utc, format) = match format.strip_prefix(b"!") {
+        Some(rest) => (true, rest),
+        None => (false, format),
+    };
+    let offset = if utc { 0 } else { local.offset };
+    let date = DateTime::new(t, offset).ok_or_else(|| {
+        RuntimeError::new("date result cannot be represented in this installation")
+    })?;
+
+    if format == b"*t" {
+        let table = Table::with_capacity(&ctx, 0, 9);
+        for (name, value) in date.fields() {
+            table
+                .set(&ctx, LuaString::new(&ctx, name.as_bytes()), value)
+                .expect("string keys are always valid");
+        }
+        stack.replace(&[Value::Table(table)]);
+        return Ok(NativeReturn::Return);
+    }
+
+    let mut out = Vec::new();
+    let mut rest = format;
+    while let Some(pos) = rest.iter().position(|&c| c == b'%') {
+        out.extend_from_slice(&rest[..pos]);
+        let spec = &rest[pos + 1..];
+        let len = conversion_length(spec).ok_or_else(|| {
+            arg_error(
+                1,
+                "date",
+                format!(
+                    "invalid conversion specifier '%{}'",
+                    String::from_utf8_lossy(spec)
+                ),
+            )
+        })?;
+        // The `E` and `O` modifiers ask for alternative forms, which the C locale doesn't have.
+        let conversion = spec[len - 1];
+        date.format(conversion, utc, &mut out);
+        rest = &spec[len..];
+    }
+    out.extend_from_slice(rest);
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, out))]);
+    Ok(NativeReturn::Return)
+}
+
+/// The length of the conversion at the start of `spec`, just after a `%`, if it is one C99 has.
+fn conversion_length(spec: &[u8]) -> Option<usize> {
+    const SINGLE: &[u8] = b"aAbBcCdDeFgGhHIjmMnprRStTuUVwWxXyYzZ%";
+    const WITH_E: &[u8] = b"cCxXyY";
+    const WITH_O: &[u8] = b"deHImMSuUVwWy";
+    match spec {
+        [c, ..] if SINGLE.contains(c) => Some(1),
+        [b'E', c, ..] if WITH_E.contains(c) => Some(2),
+        [b'O', c, ..] if WITH_O.contains(c) => Some(2),
+        _ => None,
+    }
+}
+
+/// The day number, counting from the epoch, of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The year, month and day of a day number counting from the epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// A broken-down time, as C's `struct tm` holds it.
+struct DateTime {
+    year: i64,
+    /// From 1.
+    month: i64,
+    /// From 1.
+    day: i64,
+    hour: i64,
+    min: i64,
+    sec: i64,
+    /// From 0, Sunday.
+    weekday: i64,
+    /// From 0.
+    yearday: i64,
+    /// Seconds east of UTC.
+    offset: i64,
+}
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+impl DateTime {
+    /// The date and time `offset` seconds east of UTC at `t` seconds since the epoch, or `None` if
+    /// the year doesn't fit in a C `int` as the reference implementation needs.
+    fn new(t: i64, offset: i64) -> Option<DateTime> {
+        let local = t.checked_add(offset)?;
+        let days = local.div_euclid(86400);
+        let secs = local.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        if year - 1900 > i32::MAX as i64 || year - 1900 < i32::MIN as i64 {
+            return None;
+        }
+        Some(DateTime {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            min: secs / 60 % 60,
+            sec: secs % 60,
+            // The epoch was a Thursday.
+            weekday: (days + 4).rem_euclid(7),
+            yearday: days - days_from_civil(year, 1, 1),
+            offset,
+        })
+    }
+
+    /// The fields of a date table.
+    fn fields<'gc>(&self) -> [(&'static str, Value<'gc>); 9] {
+        [
+            ("year", Value::Integer(self.year)),
+            ("month", Value::Integer(self.month)),
+            ("day", Value::Integer(self.day)),
+            ("hour", Value::Integer(self.hour)),
+            ("min", Value::Integer(self.min)),
+            ("sec", Value::Integer(self.sec)),
+            ("yday", Value::Integer(self.yearday + 1)),
+            ("wday", Value::Integer(self.weekday + 1)),
+            ("isdst", Value::Boolean(false)),
+        ]
+    }
+
+    /// The ISO 8601 week-based year and week number.
+    fn iso_week(&self) -> (i64, i64) {
+        fn weeks_in(year: i64) -> i64 {
+            let p = |y: i64| (y + y.div_euclid(4) - y.div_euclid(100) + y.div_euclid(400)) % 7;
+            if p(year).rem_euclid(7) == 4 || p(year - 1).rem_euclid(7) == 3 {
+                53
+            } else {
+                52
+            }
+        }
+        let monday_based = (self.weekday + 6) % 7;
+        let week = (self.yearday - monday_based + 10) / 7;
+        if week < 1 {
+            (self.year - 1, weeks_in(self.year - 1))
+        } else if week > weeks_in(self.year) {
+            (self.year + 1, 1)
+        } else {
+            (self.year, week)
+        }
+    }
+
+    /// Appends the `conversion` of this date, as `strftime` does in the C locale.
+    fn format(&self, conversion: u8, utc: bool, out: &mut Vec<u8>) {
+        use std::io::Write;
+
+        let hour12 = if self.hour % 12 == 0 {
+            12
+        } else {
+            self.hour % 12
+        };
+        let _ = match conversion {
+            b'a' => write!(out, "{}", &WEEKDAYS[self.weekday as usize][..3]),
+            b'A' => write!(out, "{}", WEEKDAYS[self.weekday as usize]),
+            b'b' | b'h' => write!(out, "{}", &MONTHS[self.month as usize - 1][..3]),
+            b'B' => write!(out, "{}", MONTHS[self.month as usize - 1]),
+            b'c' => {
+                for c in *b"a b e H:M:S Y" {
+                    self.format_or_literal(c, utc, out);
+                }
+                Ok(())
+            }
+            b'C' => write!(out, "{:02}", self.year.div_euclid(100)),
+            b'd' => write!(out, "{:02}", self.day),
+            b'D' | b'x' => write!(
+                out,
+                "{:02}/{:02}/{:02}",
+                self.month,
+                self.day,
+                self.year.rem_euclid(100)
+            ),
+            b'e' => write!(out, "{:2}", self.day),
+            b'F' => write!(out, "{}-{:02}-{:02}", self.year, self.month, self.day),
+            b'g' => write!(out, "{:02}", self.iso_week().0.rem_euclid(100)),
+            b'G' => write!(out, "{}", self.iso_week().0),
+            b'H' => write!(out, "{:02}", self.hour),
+            b'I' => write!(out, "{hour12:02}"),
+            b'j' => write!(out, "{:03}", self.yearday + 1),
+            b'm' => write!(out, "{:02}", self.month),
+            b'M' => write!(out, "{:02}", self.min),
+            b'n' => writeln!(out),
+            b'p' => write!(out, "{}", if self.hour < 12 { "AM" } else { "PM" }),
+            b'r' => write!(
+                out,
+                "{hour12:02}:{:02}:{:02} {}",
+                self.min,
+                self.sec,
+                if self.hour < 12 { "AM" } else { "PM" }
+            ),
+            b'R' => write!(out, "{:02}:{:02}", self.hour, self.min),
+            b'S' => write!(out, "{:02}", self.sec),
+            b't' => write!(out, "\t"),
+            b'T' | b'X' => write!(out, "{:02}:{:02}:{:02}", self.hour, self.min, self.sec),
+            b'u' => write!(out, "{}", if self.weekday == 0 { 7 } else { self.weekday }),
+            b'U' => write!(out, "{:02}", (self.yearday + 7 - self.weekday) / 7),
+            b'V' => write!(out, "{:02}", self.iso_week().1),
+            b'w' => write!(out, "{}", self.weekday),
+            b'W' => write!(
+                out,
+                "{:02}",
+                (self.yearday + 7 - (self.weekday + 6) % 7) / 7
+            ),
+            b'y' => write!(out, "{:02}", self.year.rem_euclid(100)),
+            b'Y' => write!(out, "{}", self.year),
+            b'z' => {
+                let sign = if self.offset < 0 { '-' } else { '+' };
+                let minutes = self.offset.abs() / 60;
+                write!(out, "{sign}{:02}{:02}", minutes / 60, minutes % 60)
+            }
+            b'Z' if utc => write!(out, "GMT"),
+            b'Z' if self.offset == 0 => write!(out, "UTC"),
+            b'Z' => {
+                self.format(b'z', utc, out);
+                Ok(())
+            }
+            _ => write!(out, "%"),
+        };
+    }
+
+    /// Formats the letters of `c` as conversions and copies anything else, for the formats made of
+    /// others.
+    fn format_or_literal(&self, c: u8, utc: bool, out: &mut Vec<u8>) {
+        if c.is_ascii_alphabetic() {
+            self.format(c, utc, out);
+        } else {
+            out.push(c);
+        }
+    }
+}
+
+/// `os.exit([code [, close]])`: ends the process with `code`, which is `true` (the default) for
+/// success, `false` for failure, or a number. Open files and the state's output are flushed first,
+/// and with `close` the files other than the standard ones are closed.
+fn exit<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let code = match stack.get(0) {
+        Value::Boolean(true) | Value::Nil => 0,
+        Value::Boolean(false) => 1,
+        _ => check_integer(stack, 1, "exit")? as i32,
+    };
+    let close = stack.get(1).to_bool();
+    let state = ctx.state();
+    state.files().borrow_mut().flush_all(close);
+    let _ = state.stdout().flush();
+    let _ = state.stderr().flush();
+    std::process::exit(code)
+}
+
+/// `os.getenv(name)`: the value of the environment variable `name`, or fail if it isn't set.
+fn getenv<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let name = check_string(ctx, stack, 1, "getenv")?;
+    let value = match std::env::var_os(path(name.as_bytes())) {
+        Some(value) => Value::String(LuaString::from_vec(&ctx, value.into_encoded_bytes())),
+        None => Value::Nil,
+    };
+    stack.replace(&[value]);
+    Ok(NativeReturn::Return)
+}
+
+/// `os.remove(name)`: deletes a file or an empty directory. Returns true, or fail, a message and
+/// an error number.
+fn remove<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let name = check_string(ctx, stack, 1, "remove")?;
+    let path = path(name.as_bytes());
+    let result = match fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir(&path),
+        _ => fs::remove_file(&path),
+    };
+    file_result(ctx, stack, result, name)
+}
+
+/// `os.rename(old, new)`: renames a file or directory. Returns true, or fail, a message and an
+/// error number.
+fn rename<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let from = check_string(ctx, stack, 1, "rename")?;
+    let to = check_string(ctx, stack, 2, "rename")?;
+    let result = fs::rename(path(from.as_bytes()), path(to.as_bytes()));
+    file_result(ctx, stack, result, from)
+}
+
+fn file_result<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    result: io::Result<()>,
+    name: LuaString<'gc>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    match result {
+        Ok(()) => stack.replace(&[Value::Boolean(true)]),
+        Err(err) => stack.replace(&io_error(ctx, &err, Some(name.as_bytes()))),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `os.tmpname()`: the name of a new, empty file in the temporary directory, for the script to
+/// use and remove.
+fn tmpname<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (path, _) =
+        temporary_file().map_err(|_| RuntimeError::new("unable to generate a unique filename"))?;
+    let name = path.into_os_string().into_encoded_bytes();
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, name))]);
+    Ok(NativeReturn::Return)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stdlib::testing::run_in;
+    use crate::Lua;
+
+    #[test]
+    fn dates() {
+        let mut lua = Lua::new();
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.date('!%Y-%m-%d %H:%M:%S %j %a %b %p', 0)"
+            ),
+            "1970-01-01 00:00:00 001 Thu Jan AM"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.date('!%c|%x|%X|%D|%e|%I|%y|%C|%%', 951782400 + 13 * 3600)"
+            ),
+            "Tue Feb 29 13:00:00 2000|02/29/00|13:00:00|02/29/00|29|01|00|20|%"
+        );
+        // 2021-01-03 is a Sunday in the last ISO week of 2020.
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.date('!%G-W%V-%u %U %W %w', 1609632000)"
+            ),
+            "2020-W53-7 01 00 0"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.time({year = 2000, month = 1, day = 1, hour = 0})"
+            ),
+            "946684800"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "local t = {year = 2000, month = 13, day = 32, hour = 25}
+                local time = os.time(t)
+                local back = os.date('!*t', time)
+                return t.year, t.month, t.day, t.hour, t.yday, t.wday, back.day, back.isdst"
+            ),
+            "2001, 2, 2, 1, 33, 6, 2, false"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.time() - os.time(os.date('*t')) <= 1, os.difftime(10, 4)"
+            ),
+            "true, 6.0"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return os.date('%Ez')"),
+            "error: bad argument #1 to 'date' (invalid conversion specifier '%Ez')"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return os.time({year = 2000})"),
+            "error: field 'month' missing in date table"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.time({year = 2000, month = 'x', day = 1})"
+            ),
+            "error: field 'month' is not an integer"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.date('!%h|%r|%R|%T|%F|%g|%Oy|%EY|%A %B|%n|%t', 1700000000)"
+            ),
+            "Nov|10:13:20 PM|22:13|22:13:20|2023-11-14|23|23|2023|Tuesday November|\n|\t"
+        );
+        // Fields below their range borrow from the next larger one: month 0 is December of the
+        // year before, and day 0 the last day of the month before.
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "local t = {year = 2024, month = 0, day = 0, hour = 0, min = -1, sec = 61}
+                local time = os.time(t)
+                return time, t.year, t.month, t.day, t.hour, t.min, t.sec, t.yday, t.wday"
+            ),
+            "1701302401, 2023, 11, 30, 0, 0, 1, 334, 5"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.date('!*t', os.time({year = 2024, month = 3, day = 1.0})).day, \
+                 os.date('!x*t', 0), pcall(os.time, {year = 2024, month = 1, day = 1.5})"
+            ),
+            "1, x*t, false, field 'day' is not an integer"
+        );
+        // Dates before the epoch work too.
+        assert_eq!(
+            run_in(&mut lua, "return os.date('!%Y-%m-%d', -86400 * 366)"),
+            "1968-12-31"
+        );
+    }
+
+    #[test]
+    fn local_time_and_sandboxing() {
+        let mut lua = Lua::empty();
+        lua.enter(|ctx| {
+            load_os_with(
+                ctx,
+                OsOptions {
+                    utc_offset: -5 * 3600 - 30 * 60,
+                    ..OsOptions::sandboxed()
+                },
+            );
+        });
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.time, os.clock, os.remove, os.getenv, os.exit"
+            ),
+            "nil, nil, nil, nil, nil"
+        );
+        assert_eq!(run_in(&mut lua, "return os.difftime(3, 1)"), "2.0");
+
+        let mut lua = Lua::empty();
+        lua.enter(|ctx| {
+            load_os_with(
+                ctx,
+                OsOptions {
+                    utc_offset: -5 * 3600 - 30 * 60,
+                    ..OsOptions::default()
+                },
+            );
+        });
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.date('%Y-%m-%d %H:%M %z %Z', 0), os.date('!%H:%M %Z', 0)"
+            ),
+            "1969-12-31 18:30 -0530 -0530, 00:00 GMT"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return os.time({year = 1970, month = 1, day = 1, hour = 0})"
+            ),
+            "19800"
+        );
+
+        let mut lua = Lua::empty();
+        lua.enter(|ctx| {
+            load_os_with(
+                ctx,
+                OsOptions {
+                    clock_source: Some(|| 2.5),
+                    time_source: Some(|| 86400),
+                    ..OsOptions::default()
+                },
+            );
+        });
+        assert_eq!(
+            run_in(&mut lua, "return os.clock(), os.time(), os.date('!%F')"),
+            "2.5, 86400, 1970-01-02"
+        );
+    }
+
+    #[test]
+    fn files() {
+        let mut lua = Lua::new();
+        let result = run_in(
+            &mut lua,
+            "local name = os.tmpname()
+            local renamed = name .. '.renamed'
+            local ok = os.rename(name, renamed)
+            return ok, os.remove(renamed), os.remove(renamed) == nil, select(3, os.remove(renamed))",
+        );
+        assert_eq!(result, "true, true, true, 2");
+        let result = run_in(&mut lua, "return os.remove('/nonexistent/file')");
+        assert_eq!(
+            result,
+            "nil, /nonexistent/file: No such file or directory, 2"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return os.getenv('TEI_SURELY_UNSET')"),
+            "nil"
+        );
+    }
+}
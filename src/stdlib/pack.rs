@@ -0,0 +1,660 @@
+//! Binary packing: the format language of `string.pack`, `string.unpack` and `string.packsize`,
+//! usable from Rust on its own.
+//!
+//! A format is a sequence of options, each packing one value:
+//!
+//! - `b`/`B`, `h`/`H`, `i[n]`/`I[n]`, `l`/`L`, `j`/`J` and `T`: signed and unsigned integers of 1,
+//!   2, `n` (4 by default), 8, 8 and 8 bytes, with `n` from 1 to 16;
+//! - `f`, `d` and `n`: floats of 4, 8 and 8 bytes;
+//! - `s[n]`: a string preceded by its length in `n` bytes (8 by default);
+//! - `z`: a zero-terminated string;
+//! - `cn`: a string of exactly `n` bytes, padded with zeros when packed.
+//!
+//! And some only control how the others are laid out: `<`, `>` and `=` switch to little, big and
+//! native endianness, `![n]` sets the largest alignment (8 by default, 1 until set), `x` is a byte
+//! of padding, `Xop` pads to the alignment of option `op` without packing it, and spaces are
+//! ignored. An option is aligned to its own size, up to the largest alignment.
+//!
+//! Sizes and error messages follow the reference implementation on a 64-bit platform, so packed
+//! data is compatible with it.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+/// The largest integer size, in bytes.
+const MAX_INT_SIZE: usize = 16;
+/// The size of a Lua integer, and of C's `long` and `size_t` on the platforms that matter.
+const INT_SIZE: usize = 8;
+/// The alignment `!` sets without a size: that of the most demanding native type.
+const NATIVE_ALIGN: usize = 8;
+/// The largest total size `packsize` allows, and the largest size a format can give.
+const MAX_SIZE: usize = i32::MAX as usize;
+
+/// Why a format couldn't be used, or values couldn't be packed or unpacked with it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PackError {
+    /// A character that isn't an option; holds the character.
+    InvalidOption(u8),
+    /// A `c` without a size.
+    MissingSize,
+    /// An integer size or alignment outside 1 to 16; holds the size given.
+    SizeOutOfLimits(usize),
+    /// An `X` followed by nothing, by `c`, or by an option without alignment.
+    InvalidNextOption,
+    AlignmentNotPowerOfTwo,
+    /// `packsize` of a format with `s` or `z`.
+    VariableLength,
+    /// `packsize` of a format whose size doesn't fit in 31 bits.
+    ResultTooLarge,
+    /// Value `index` (counting from 0) is an integer too large for its signed size.
+    IntegerOverflow {
+        index: usize,
+    },
+    /// Value `index` is an integer too large for its unsigned size, or negative.
+    UnsignedOverflow {
+        index: usize,
+    },
+    /// Value `index` is a string too long for its `c` option.
+    StringTooLong {
+        index: usize,
+    },
+    /// The length of value `index` doesn't fit in the size of its `s` option.
+    LengthDoesNotFit {
+        index: usize,
+    },
+    /// Value `index`, packed with `z`, contains a zero byte.
+    ContainsZeros {
+        index: usize,
+    },
+    /// Value `index` isn't the kind the format asks for there.
+    UnexpectedValue {
+        index: usize,
+        expected: ValueKind,
+    },
+    /// Unpacking starts past the end of the data.
+    InitialPositionOutOfString,
+    DataTooShort,
+    /// A `z` string runs to the end of the data.
+    UnfinishedString,
+    /// An integer of more than 8 bytes doesn't fit in 64 bits; holds its size.
+    IntegerDoesNotFit(usize),
+}
+
+impl PackError {
+    /// The argument of `string.pack` or `string.unpack` the error is about, counting from 1, for
+    /// the errors the reference implementation reports as bad arguments.
+    pub fn argument(&self) -> Option<usize> {
+        match *self {
+            PackError::InvalidNextOption
+            | PackError::AlignmentNotPowerOfTwo
+            | PackError::VariableLength
+            | PackError::ResultTooLarge => Some(1),
+            PackError::IntegerOverflow { index }
+            | PackError::UnsignedOverflow { index }
+            | PackError::StringTooLong { index }
+            | PackError::LengthDoesNotFit { index }
+            | PackError::ContainsZeros { index }
+            | PackError::UnexpectedValue { index, .. } => Some(index + 2),
+            PackError::DataTooShort | PackError::UnfinishedString => Some(2),
+            PackError::InitialPositionOutOfString => Some(3),
+            PackError::InvalidOption(_)
+            | PackError::MissingSize
+            | PackError::SizeOutOfLimits(_)
+            | PackError::IntegerDoesNotFit(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            PackError::InvalidOption(c) => {
+                write!(f, "invalid format option '{}'", c.escape_ascii())
+            }
+            PackError::MissingSize => f.write_str("missing size for format option 'c'"),
+            PackError::SizeOutOfLimits(size) => {
+                write!(f, "integral size ({size}) out of limits [1,{MAX_INT_SIZE}]")
+            }
+            PackError::InvalidNextOption => f.write_str("invalid next option for option 'X'"),
+            PackError::AlignmentNotPowerOfTwo => {
+                f.write_str("format asks for alignment not power of 2")
+            }
+            PackError::VariableLength => f.write_str("variable-length format"),
+            PackError::ResultTooLarge => f.write_str("format result too large"),
+            PackError::IntegerOverflow { .. } => f.write_str("integer overflow"),
+            PackError::UnsignedOverflow { .. } => f.write_str("unsigned overflow"),
+            PackError::StringTooLong { .. } => f.write_str("string longer than given size"),
+            PackError::LengthDoesNotFit { .. } => {
+                f.write_str("string length does not fit in given size")
+            }
+            PackError::ContainsZeros { .. } => f.write_str("string contains zeros"),
+            PackError::UnexpectedValue { expected, .. } => write!(f, "{expected} expected"),
+            PackError::InitialPositionOutOfString => f.write_str("initial position out of string"),
+            PackError::DataTooShort => f.write_str("data string too short"),
+            PackError::UnfinishedString => f.write_str("unfinished string for format 'z'"),
+            PackError::IntegerDoesNotFit(size) => {
+                write!(f, "{size}-byte integer does not fit into Lua Integer")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PackError {}
+
+/// The kinds of value a format packs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ValueKind {
+    Integer,
+    Float,
+    String,
+}
+
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ValueKind::Integer => "integer",
+            ValueKind::Float => "number",
+            ValueKind::String => "string",
+        })
+    }
+}
+
+/// A value to pack, or one unpacked.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PackValue<'a> {
+    Integer(i64),
+    Float(f64),
+    String(&'a [u8]),
+}
+
+/// What an option does.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Int {
+        signed: bool,
+    },
+    Float,
+    Double,
+    /// `c`: a string of exactly the option's size.
+    Fixed,
+    /// `s`: a string preceded by its length.
+    Prefixed,
+    /// `z`
+    ZeroTerminated,
+    /// `x`
+    Padding,
+    /// `X`
+    PadToAlignment,
+    /// Options that only change the state of the reader.
+    Nop,
+}
+
+impl Kind {
+    fn value_kind(self) -> Option<ValueKind> {
+        match self {
+            Kind::Int { .. } => Some(ValueKind::Integer),
+            Kind::Float | Kind::Double => Some(ValueKind::Float),
+            Kind::Fixed | Kind::Prefixed | Kind::ZeroTerminated => Some(ValueKind::String),
+            Kind::Padding | Kind::PadToAlignment | Kind::Nop => None,
+        }
+    }
+}
+
+/// An option read from a format, with the padding that aligns it.
+struct Item {
+    kind: Kind,
+    size: usize,
+    padding: usize,
+}
+
+/// Reads the options of a format, keeping track of its endianness and largest alignment.
+struct Reader<'a> {
+    format: &'a [u8],
+    pos: usize,
+    little: bool,
+    max_align: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(format: &'a [u8]) -> Reader<'a> {
+        Reader {
+            format,
+            pos: 0,
+            little: cfg!(target_endian = "little"),
+            max_align: 1,
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.format.len()
+    }
+
+    /// Reads a size, or returns `default` if there is none.
+    fn number(&mut self, default: usize) -> usize {
+        let digits = self.format[self.pos..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit());
+        let mut n: usize = 0;
+        let mut any = false;
+        for &d in digits {
+            if any && n > (MAX_SIZE - 9) / 10 {
+                break;
+            }
+            n = n * 10 + (d - b'0') as usize;
+            any = true;
+            self.pos += 1;
+        }
+        if any {
+            n
+        } else {
+            default
+        }
+    }
+
+    /// Reads an integer size or alignment, from 1 to 16.
+    fn limited_number(&mut self, default: usize) -> Result<usize, PackError> {
+        let n = self.number(default);
+        if n == 0 || n > MAX_INT_SIZE {
+            return Err(PackError::SizeOutOfLimits(n));
+        }
+        Ok(n)
+    }
+
+    /// Reads the next option and its size.
+    fn option(&mut self) -> Result<(Kind, usize), PackError> {
+        let c = self.format[self.pos];
+        self.pos += 1;
+        let int = |signed, size| Ok((Kind::Int { signed }, size));
+        match c {
+            b'b' => int(true, 1),
+            b'B' => int(false, 1),
+            b'h' => int(true, 2),
+            b'H' => int(false, 2),
+            b'l' | b'j' => int(true, INT_SIZE),
+            b'L' | b'J' | b'T' => int(false, INT_SIZE),
+            b'i' => int(true, self.limited_number(4)?),
+            b'I' => int(false, self.limited_number(4)?),
+            b'f' => Ok((Kind::Float, 4)),
+            b'd' | b'n' => Ok((Kind::Double, 8)),
+            b's' => Ok((Kind::Prefixed, self.limited_number(INT_SIZE)?)),
+            b'c' => match self.number(usize::MAX) {
+                usize::MAX => Err(PackError::MissingSize),
+                size => Ok((Kind::Fixed, size)),
+            },
+            b'z' => Ok((Kind::ZeroTerminated, 0)),
+            b'x' => Ok((Kind::Padding, 1)),
+            b'X' => Ok((Kind::PadToAlignment, 0)),
+            b' ' => Ok((Kind::Nop, 0)),
+            b'<' | b'>' | b'=' => {
+                self.little = match c {
+                    b'<' => true,
+                    b'>' => false,
+                    _ => cfg!(target_endian = "little"),
+                };
+                Ok((Kind::Nop, 0))
+            }
+            b'!' => {
+                self.max_align = self.limited_number(NATIVE_ALIGN)?;
+                Ok((Kind::Nop, 0))
+            }
+            c => Err(PackError::InvalidOption(c)),
+        }
+    }
+
+    /// Reads the next option, working out the padding that aligns it at `offset`.
+    fn item(&mut self, offset: usize) -> Result<Item, PackError> {
+        let (kind, size) = self.option()?;
+        let mut align = size;
+        if kind == Kind::PadToAlignment {
+            if self.at_end() {
+                return Err(PackError::InvalidNextOption);
+            }
+            let (next, next_size) = self.option()?;
+            if next == Kind::Fixed || next_size == 0 {
+                return Err(PackError::InvalidNextOption);
+            }
+            align = next_size;
+        }
+        let mut padding = 0;
+        if align > 1 && kind != Kind::Fixed {
+            align = align.min(self.max_align);
+            if !align.is_power_of_two() {
+                return Err(PackError::AlignmentNotPowerOfTwo);
+            }
+            padding = (align - (offset & (align - 1))) & (align - 1);
+        }
+        Ok(Item {
+            kind,
+            size,
+            padding,
+        })
+    }
+}
+
+/// Packs values as `format` says, getting each from `value` with its index, counting from 0, and
+/// the kind of value the format asks for.
+///
+/// Integers are accepted where floats are asked for. Errors from `value` are passed on.
+pub fn pack<'v, E: From<PackError>>(
+    format: &[u8],
+    mut value: impl FnMut(usize, ValueKind) -> Result<PackValue<'v>, E>,
+) -> Result<Vec<u8>, E> {
+    let mut reader = Reader::new(format);
+    let mut out = Vec::new();
+    let mut index = 0;
+    while !reader.at_end() {
+        let item = reader.item(out.len())?;
+        out.resize(out.len() + item.padding, 0);
+        let Some(kind) = item.kind.value_kind() else {
+            if item.kind == Kind::Padding {
+                out.push(0);
+            }
+            continue;
+        };
+        let v = value(index, kind)?;
+        let unexpected = PackError::UnexpectedValue {
+            index,
+            expected: kind,
+        };
+        match (item.kind, v) {
+            (Kind::Int { signed }, PackValue::Integer(n)) => {
+                if item.size < INT_SIZE {
+                    let bits = 8 * item.size as u32;
+                    let fits = if signed {
+                        let limit = 1i64 << (bits - 1);
+                        -limit <= n && n < limit
+                    } else {
+                        (n as u64) < 1 << bits
+                    };
+                    if !fits && signed {
+                        return Err(PackError::IntegerOverflow { index }.into());
+                    } else if !fits {
+                        return Err(PackError::UnsignedOverflow { index }.into());
+                    }
+                }
+                pack_int(&mut out, n as u64, n < 0, item.size, reader.little);
+            }
+            (Kind::Float | Kind::Double, PackValue::Integer(_) | PackValue::Float(_)) => {
+                let n = match v {
+                    PackValue::Integer(i) => i as f64,
+                    PackValue::Float(n) => n,
+                    PackValue::String(_) => unreachable!(),
+                };
+                match (item.kind, reader.little) {
+                    (Kind::Float, true) => out.extend((n as f32).to_le_bytes()),
+                    (Kind::Float, false) => out.extend((n as f32).to_be_bytes()),
+                    (_, true) => out.extend(n.to_le_bytes()),
+                    (_, false) => out.extend(n.to_be_bytes()),
+                }
+            }
+            (Kind::Fixed, PackValue::String(s)) => {
+                if s.len() > item.size {
+                    return Err(PackError::StringTooLong { index }.into());
+                }
+                out.extend_from_slice(s);
+                out.resize(out.len() + item.size - s.len(), 0);
+            }
+            (Kind::Prefixed, PackValue::String(s)) => {
+                if item.size < INT_SIZE && s.len() as u64 >= 1 << (8 * item.size) {
+                    return Err(PackError::LengthDoesNotFit { index }.into());
+                }
+                pack_int(&mut out, s.len() as u64, false, item.size, reader.little);
+                out.extend_from_slice(s);
+            }
+            (Kind::ZeroTerminated, PackValue::String(s)) => {
+                if s.contains(&0) {
+                    return Err(PackError::ContainsZeros { index }.into());
+                }
+                out.extend_from_slice(s);
+                out.push(0);
+            }
+            _ => return Err(unexpected.into()),
+        }
+        index += 1;
+    }
+    Ok(out)
+}
+
+/// Appends the `size` lowest bytes of `n`, extended with the sign if `size` is more than 8.
+fn pack_int(out: &mut Vec<u8>, n: u64, negative: bool, size: usize, little: bool) {
+    let extension = if negative { 0xff } else { 0 };
+    let bytes = (0..size).map(|i| match i {
+        0..=7 => (n >> (8 * i)) as u8,
+        _ => extension,
+    });
+    if little {
+        out.extend(bytes);
+    } else {
+        let start = out.len();
+        out.extend(bytes);
+        out[start..].reverse();
+    }
+}
+
+/// Unpacks the values `format` describes from `data`, starting at byte offset `pos`. Returns the
+/// values and the offset just past them.
+pub fn unpack<'d>(
+    format: &[u8],
+    data: &'d [u8],
+    mut pos: usize,
+) -> Result<(Vec<PackValue<'d>>, usize), PackError> {
+    if pos > data.len() {
+        return Err(PackError::InitialPositionOutOfString);
+    }
+    let mut reader = Reader::new(format);
+    let mut values = Vec::new();
+    while !reader.at_end() {
+        let item = reader.item(pos)?;
+        if item.padding + item.size > data.len() - pos {
+            return Err(PackError::DataTooShort);
+        }
+        pos += item.padding;
+        let bytes = &data[pos..pos + item.size];
+        match item.kind {
+            Kind::Int { signed } => {
+                let n = unpack_int(bytes, signed, reader.little)?;
+                values.push(PackValue::Integer(n));
+            }
+            Kind::Float => {
+                let bytes = bytes.try_into().expect("4 bytes");
+                let n = match reader.little {
+                    true => f32::from_le_bytes(bytes),
+                    false => f32::from_be_bytes(bytes),
+                };
+                values.push(PackValue::Float(n as f64));
+            }
+            Kind::Double => {
+                let bytes = bytes.try_into().expect("8 bytes");
+                let n = match reader.little {
+                    true => f64::from_le_bytes(bytes),
+                    false => f64::from_be_bytes(bytes),
+                };
+                values.push(PackValue::Float(n));
+            }
+            Kind::Fixed => values.push(PackValue::String(bytes)),
+            Kind::Prefixed => {
+                let len = unpack_int(bytes, false, reader.little)? as u64;
+                let start = pos + item.size;
+                if len > (data.len() - start) as u64 {
+                    return Err(PackError::DataTooShort);
+                }
+                let len = len as usize;
+                values.push(PackValue::String(&data[start..start + len]));
+                pos += len;
+            }
+            Kind::ZeroTerminated => {
+                let len = data[pos..]
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or(PackError::UnfinishedString)?;
+                values.push(PackValue::String(&data[pos..pos + len]));
+                pos += len + 1;
+            }
+            Kind::Padding | Kind::PadToAlignment | Kind::Nop => {}
+        }
+        pos += item.size;
+    }
+    Ok((values, pos))
+}
+
+/// Reads an integer from `bytes`, which can be more than 8 of them as long as the extra bytes
+/// only extend the sign.
+fn unpack_int(bytes: &[u8], signed: bool, little: bool) -> Result<i64, PackError> {
+    // The bytes from the least significant up.
+    let byte = |i: usize| {
+        if little {
+            bytes[i]
+        } else {
+            bytes[bytes.len() - 1 - i]
+        }
+    };
+    let size = bytes.len();
+    let mut n: u64 = 0;
+    for i in (0..size.min(INT_SIZE)).rev() {
+        n = n << 8 | byte(i) as u64;
+    }
+    match size.cmp(&INT_SIZE) {
+        Ordering::Less if signed => {
+            let sign = 1 << (8 * size - 1);
+            n = (n ^ sign).wrapping_sub(sign);
+        }
+        Ordering::Greater => {
+            let extension = if signed && (n as i64) < 0 { 0xff } else { 0 };
+            if (INT_SIZE..size).any(|i| byte(i) != extension) {
+                return Err(PackError::IntegerDoesNotFit(size));
+            }
+        }
+        _ => {}
+    }
+    Ok(n as i64)
+}
+
+/// The number of bytes `format` packs into, which must not have variable-length options.
+pub fn packsize(format: &[u8]) -> Result<usize, PackError> {
+    let mut reader = Reader::new(format);
+    let mut total = 0;
+    while !reader.at_end() {
+        let item = reader.item(total)?;
+        if let Kind::Prefixed | Kind::ZeroTerminated = item.kind {
+            return Err(PackError::VariableLength);
+        }
+        let size = item.padding + item.size;
+        if size > MAX_SIZE - total {
+            return Err(PackError::ResultTooLarge);
+        }
+        total += size;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack_all(format: &str, values: &[PackValue<'_>]) -> Result<Vec<u8>, PackError> {
+        pack(format.as_bytes(), |i, _| Ok(values[i]))
+    }
+
+    #[test]
+    fn round_trips() {
+        use PackValue::{Float, Integer, String};
+        let cases: &[(&str, &[PackValue<'_>], &[u8])] = &[
+            ("<i2 >i2", &[Integer(1), Integer(-2)], &[1, 0, 0xff, 0xfe]),
+            ("<I3", &[Integer(0x010203)], &[3, 2, 1]),
+            (">j", &[Integer(-1)], &[0xff; 8]),
+            ("<i16", &[Integer(-2)], &[&[0xfe][..], &[0xff; 15]].concat()),
+            (">d", &[Float(1.5)], &[0x3f, 0xf8, 0, 0, 0, 0, 0, 0]),
+            ("<f", &[Float(-2.0)], &[0, 0, 0, 0xc0]),
+            ("<s1z", &[String(b"ab"), String(b"cd")], b"\x02abcd\0"),
+            ("c4", &[String(b"abcd")], b"abcd"),
+            (
+                "!<b Xi4 i4",
+                &[Integer(1), Integer(2)],
+                &[1, 0, 0, 0, 2, 0, 0, 0],
+            ),
+            (
+                "!2<b h x B",
+                &[Integer(1), Integer(2), Integer(3)],
+                &[1, 0, 2, 0, 0, 3],
+            ),
+        ];
+        for &(format, values, bytes) in cases {
+            assert_eq!(pack_all(format, values).as_deref(), Ok(bytes), "{format}");
+            let (unpacked, end) = unpack(format.as_bytes(), bytes, 0).unwrap();
+            assert_eq!(unpacked, values, "{format}");
+            assert_eq!(end, bytes.len());
+        }
+        assert_eq!(packsize(b"!8 b d"), Ok(16));
+        assert_eq!(packsize(b"i3 c10 x"), Ok(14));
+        assert_eq!(
+            pack_all("c4", &[String(b"ab")]).as_deref(),
+            Ok(&b"ab\0\0"[..])
+        );
+        // Floats are asked for where integers may do.
+        assert_eq!(pack_all("<d", &[Integer(2)]), pack_all("<d", &[Float(2.0)]));
+    }
+
+    #[test]
+    fn errors() {
+        use PackValue::{Integer, String};
+        let cases: &[(&str, &[PackValue<'_>], PackError)] = &[
+            ("y", &[], PackError::InvalidOption(b'y')),
+            ("c", &[], PackError::MissingSize),
+            ("i17", &[], PackError::SizeOutOfLimits(17)),
+            ("X", &[], PackError::InvalidNextOption),
+            ("Xc1", &[], PackError::InvalidNextOption),
+            ("!3 i3", &[Integer(0)], PackError::AlignmentNotPowerOfTwo),
+            (
+                "i1",
+                &[Integer(128)],
+                PackError::IntegerOverflow { index: 0 },
+            ),
+            (
+                "b I1",
+                &[Integer(0), Integer(-1)],
+                PackError::UnsignedOverflow { index: 1 },
+            ),
+            (
+                "c1",
+                &[String(b"ab")],
+                PackError::StringTooLong { index: 0 },
+            ),
+            (
+                "s1",
+                &[String(&[0; 256])],
+                PackError::LengthDoesNotFit { index: 0 },
+            ),
+            (
+                "z",
+                &[String(b"a\0")],
+                PackError::ContainsZeros { index: 0 },
+            ),
+            (
+                "i",
+                &[String(b"1")],
+                PackError::UnexpectedValue {
+                    index: 0,
+                    expected: ValueKind::Integer,
+                },
+            ),
+        ];
+        for &(format, values, err) in cases {
+            assert_eq!(pack_all(format, values), Err(err), "{format}");
+        }
+        assert_eq!(unpack(b"i4", b"abc", 0), Err(PackError::DataTooShort));
+        assert_eq!(unpack(b"z", b"abc", 0), Err(PackError::UnfinishedString));
+        assert_eq!(
+            unpack(b"b", b"a", 2),
+            Err(PackError::InitialPositionOutOfString)
+        );
+        assert_eq!(
+            unpack(b"<i9", &[0, 0, 0, 0, 0, 0, 0, 0, 1], 0),
+            Err(PackError::IntegerDoesNotFit(9))
+        );
+        assert_eq!(packsize(b"s"), Err(PackError::VariableLength));
+        assert_eq!(
+            packsize("c200000000".repeat(11).as_bytes()),
+            Err(PackError::ResultTooLarge)
+        );
+    }
+}
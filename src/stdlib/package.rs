@@ -0,0 +1,593 @@
+//! The package library: `require` and the `package` global.
+//!
+//! `require` asks the functions in `package.searchers` in turn for a loader of the module. The first
+//! two are the reference implementation's: one for `package.preload`, and one that looks for a Lua
+//! file along `package.path`, opened through the io library's [`FileSystem`](super::FileSystem).
+//! The host's own [`Searcher`]s follow, so modules can come from anywhere it likes.
+
+use std::path::MAIN_SEPARATOR;
+
+use crate::lua::load_chunk;
+use crate::vm::{self, Stack};
+use crate::{
+    Context, Function, LuaError, LuaString, MaybeSend, NativeClosure, NativeFn, NativeReturn,
+    RuntimeError, Table, Value,
+};
+
+use super::{check_string, loaded, set_function, set_library};
+
+/// Where the reference implementation looks for Lua modules, unless the environment says otherwise.
+const DEFAULT_PATH: &str = "/usr/local/share/lua/5.4/?.lua;/usr/local/share/lua/5.4/?/init.lua;\
+    /usr/local/lib/lua/5.4/?.lua;/usr/local/lib/lua/5.4/?/init.lua;./?.lua;./?/init.lua";
+
+/// A module a [`Searcher`] found.
+#[derive(Debug, Clone)]
+pub enum Module {
+    /// Lua source or a binary chunk, loaded with the given chunk name and run to get the module.
+    Source { chunk_name: String, source: Vec<u8> },
+    /// A native function that sets the module up. It is called with the module's name and returns
+    /// the module.
+    Native(NativeFn),
+}
+
+/// Finds modules for `require` in places of the host's choosing, like sources embedded in the
+/// executable or a virtual file system.
+pub trait Searcher: MaybeSend {
+    /// Looks for the module `name`, as passed to `require`. If it isn't there, returns a line for
+    /// the error `require` raises about places it looked, like `no embedded module 'name'`.
+    fn search(&mut self, name: &[u8]) -> Result<Module, String>;
+}
+
+impl<F: FnMut(&[u8]) -> Result<Module, String> + MaybeSend> Searcher for F {
+    fn search(&mut self, name: &[u8]) -> Result<Module, String> {
+        self(name)
+    }
+}
+
+/// How `require` finds modules.
+pub struct PackageOptions {
+    /// The initial `package.path`: templates separated by `;`, in which `?` stands for the module
+    /// name with its dots turned into directory separators.
+    pub path: String,
+    /// Tried in order after `package.preload` and `package.path`.
+    pub searchers: Vec<Box<dyn Searcher>>,
+}
+
+impl Default for PackageOptions {
+    /// The path from `LUA_PATH_5_4` or `LUA_PATH`, as in the reference implementation, and no
+    /// searchers of the host's.
+    fn default() -> PackageOptions {
+        let path = match std::env::var("LUA_PATH_5_4").or_else(|_| std::env::var("LUA_PATH")) {
+            // A `;;` stands for the default path.
+            Ok(path) => path.replacen(";;", &format!(";{DEFAULT_PATH};"), 1),
+            Err(_) => DEFAULT_PATH.to_owned(),
+        };
+        PackageOptions {
+            path,
+            searchers: Vec::new(),
+        }
+    }
+}
+
+pub fn load_package(ctx: Context<'_>) {
+    load_package_with(ctx, PackageOptions::default());
+}
+
+pub fn load_package_with<'gc>(ctx: Context<'gc>, options: PackageOptions) {
+    let package = Table::new(&ctx);
+    let set = |key: &str, value: Value<'gc>| {
+        package
+            .set(&ctx, LuaString::new(&ctx, key.as_bytes()), value)
+            .expect("string keys are always valid");
+    };
+    let string = |s: &str| Value::String(LuaString::new(&ctx, s.as_bytes()));
+    set("config", string(&format!("{MAIN_SEPARATOR}\n;\n?\n!\n-\n")));
+    set("cpath", string(""));
+    set("loaded", Value::Table(loaded(ctx)));
+    set("path", string(&options.path));
+    set("preload", Value::Table(preload(ctx)));
+
+    let searchers = Table::new(&ctx);
+    let add_searcher = |searcher: Value<'gc>| {
+        let n = searchers.length() as i64 + 1;
+        searchers
+            .set(&ctx, Value::Integer(n), searcher)
+            .expect("integer keys are always valid");
+    };
+    let with_package =
+        |f: NativeFn| Value::Function(NativeClosure::new(&ctx, f, &[Value::Table(package)]).into());
+    add_searcher(with_package(search_preload));
+    add_searcher(with_package(search_path));
+    let mut host_searchers = ctx.state().searchers().borrow_mut();
+    for searcher in options.searchers {
+        let index = Value::Integer(host_searchers.len() as i64);
+        host_searchers.push(searcher);
+        add_searcher(Value::Function(
+            NativeClosure::new(&ctx, search_host, &[index]).into(),
+        ));
+    }
+    drop(host_searchers);
+    set("searchers", Value::Table(searchers));
+
+    set_function(ctx, package, "loadlib", loadlib);
+    set_function(ctx, package, "searchpath", searchpath);
+    set_library(ctx, "package", package);
+    ctx.globals()
+        .set(
+            &ctx,
+            LuaString::new(&ctx, b"require"),
+            with_package(require),
+        )
+        .expect("string keys are always valid");
+}
+
+/// The table of loaders for `package.preload`, which the registry keeps as `_PRELOAD` so that the
+/// host can add to it before the package library is open.
+fn preload(ctx: Context<'_>) -> Table<'_> {
+    let registry = ctx.registry();
+    if let Value::Table(preload) = registry.get_str("_PRELOAD") {
+        return preload;
+    }
+    let preload = Table::new(&ctx);
+    registry
+        .set(&ctx, LuaString::new(&ctx, b"_PRELOAD"), preload)
+        .expect("string keys are always valid");
+    preload
+}
+
+impl<'gc> Context<'gc> {
+    /// Makes `require(name)` return the table `loader` makes, through `package.preload`, without
+    /// searching for it anywhere. `loader` runs at the first `require` and not before; the table
+    /// is kept in `package.loaded` for the ones after, and an error it returns is raised by the
+    /// `require` that called it, leaving the next one to try again.
+    pub fn preload_module<F>(self, name: &str, loader: F)
+    where
+        F: for<'a> Fn(Context<'a>) -> Result<Table<'a>, LuaError<'a>> + MaybeSend + 'static,
+    {
+        let loader = Function::from_fn(&self, move |ctx, stack| {
+            let module = loader(ctx)?;
+            stack.replace(&[Value::Table(module)]);
+            Ok(NativeReturn::Return)
+        });
+        preload(self)
+            .set(&self, LuaString::new(&self, name.as_bytes()), loader)
+            .expect("string keys are always valid");
+    }
+}
+
+/// The field `key` of `package`, which must be of the type `expected` names.
+fn package_field<'gc>(
+    stack: &Stack<'gc, '_>,
+    key: &str,
+    expected: &str,
+) -> Result<Value<'gc>, RuntimeError> {
+    let Value::Table(package) = stack.upvalue(0) else {
+        unreachable!("the package table is the upvalue");
+    };
+    let value = package.get_str(key);
+    if value.type_name() != expected {
+        return Err(RuntimeError::new(format!(
+            "'package.{key}' must be a {expected}"
+        )));
+    }
+    Ok(value)
+}
+
+/// `require(name)`: loads the module `name` unless `package.loaded` has it already, and returns
+/// `package.loaded[name]` along with what the searcher that found it said about where it was.
+fn require<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let name = check_string(ctx, stack, 1, "require")?;
+    let loaded = loaded(ctx);
+    let module = loaded.get(name);
+    if module.to_bool() {
+        stack.replace(&[module]);
+        return Ok(NativeReturn::Return);
+    }
+
+    let Value::Table(searchers) = package_field(stack, "searchers", "table")? else {
+        unreachable!("the field was checked to be a table");
+    };
+    let mut message = format!("module '{name}' not found:").into_bytes();
+    let mut i = 1;
+    let (loader, data) = loop {
+        let searcher = searchers.get(Value::Integer(i));
+        if searcher.is_nil() {
+            return Err(LuaError::new(Value::String(LuaString::from_vec(
+                &ctx, message,
+            ))));
+        }
+        i += 1;
+        let results = vm::call(ctx, stack.thread(), searcher, &[Value::String(name)])?;
+        match results.first().copied().unwrap_or_default() {
+            Value::Function(loader) => break (loader, results.get(1).copied().unwrap_or_default()),
+            Value::String(note) if !note.is_empty() => {
+                message.extend_from_slice(b"\n\t");
+                message.extend_from_slice(note.as_bytes());
+            }
+            _ => {}
+        }
+    };
+
+    let results = vm::call(
+        ctx,
+        stack.thread(),
+        Value::Function(loader),
+        &[Value::String(name), data],
+    )?;
+    let result = results.first().copied().unwrap_or_default();
+    if !result.is_nil() {
+        loaded
+            .set(&ctx, name, result)
+            .expect("string keys are always valid");
+    }
+    if loaded.get(name).is_nil() {
+        // A module that returns nothing is still loaded.
+        loaded
+            .set(&ctx, name, Value::Boolean(true))
+            .expect("string keys are always valid");
+    }
+    stack.replace(&[loaded.get(name), data]);
+    Ok(NativeReturn::Return)
+}
+
+/// The searcher for `package.preload`, whose entries are the loaders of their modules.
+fn search_preload<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let name = check_string(ctx, stack, 1, "require")?;
+    let Value::Table(preload) = package_field(stack, "preload", "table")? else {
+        unreachable!("the field was checked to be a table");
+    };
+    match preload.get(name) {
+        Value::Nil => {
+            let note = format!("no field package.preload['{name}']");
+            stack.replace(&[Value::String(LuaString::new(&ctx, note.as_bytes()))]);
+        }
+        loader => stack.replace(&[loader, Value::String(LuaString::new(&ctx, b":preload:"))]),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// The searcher for Lua files along `package.path`. The loader it finds is the compiled file, which
+/// gets the file name as its second argument.
+fn search_path<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let name = check_string(ctx, stack, 1, "require")?;
+    let Value::String(path) = package_field(stack, "path", "string")? else {
+        unreachable!("the field was checked to be a string");
+    };
+    let sep = MAIN_SEPARATOR.to_string();
+    let file_name = match search(ctx, name.as_bytes(), path.as_bytes(), b".", sep.as_bytes()) {
+        Ok(file_name) => file_name,
+        Err(note) => {
+            stack.replace(&[Value::String(LuaString::from_vec(&ctx, note))]);
+            return Ok(NativeReturn::Return);
+        }
+    };
+    let loaded = ctx.state().files().borrow_mut().read_file(&file_name);
+    let mut chunk_name = b"@".to_vec();
+    chunk_name.extend_from_slice(&file_name);
+    let loader = loaded
+        .map_err(|err| err.to_string())
+        .and_then(|source| {
+            let env = Value::Table(ctx.globals());
+            load_chunk(ctx, &source, &chunk_name, b"bt", env)
+        })
+        .map_err(|err| {
+            RuntimeError::new(format!(
+                "error loading module '{name}' from file '{}':\n\t{err}",
+                String::from_utf8_lossy(&file_name)
+            ))
+        })?;
+    stack.replace(&[
+        Value::Function(loader.into()),
+        Value::String(LuaString::from_vec(&ctx, file_name)),
+    ]);
+    Ok(NativeReturn::Return)
+}
+
+/// A searcher of the host's, whose index into the state's list is the upvalue.
+fn search_host<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let name = check_string(ctx, stack, 1, "require")?;
+    let Value::Integer(index) = stack.upvalue(0) else {
+        unreachable!("the searcher's index is the upvalue");
+    };
+    let found = ctx.state().searchers().borrow_mut()[index as usize].search(name.as_bytes());
+    match found {
+        Ok(Module::Source { chunk_name, source }) => {
+            let env = Value::Table(ctx.globals());
+            let loader =
+                load_chunk(ctx, &source, chunk_name.as_bytes(), b"bt", env).map_err(|err| {
+                    RuntimeError::new(format!("error loading module '{name}':\n\t{err}"))
+                })?;
+            let chunk_name = LuaString::new(&ctx, chunk_name.as_bytes());
+            stack.replace(&[Value::Function(loader.into()), Value::String(chunk_name)]);
+        }
+        Ok(Module::Native(f)) => stack.replace(&[Value::Function(Function::Native(f))]),
+        Err(note) => stack.replace(&[Value::String(LuaString::new(&ctx, note.as_bytes()))]),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// Looks along `path` for a file that can be opened for `name`, with each `sep` in the name
+/// replaced by `rep`. Returns the file name, or a note listing the files tried.
+fn search(
+    ctx: Context<'_>,
+    name: &[u8],
+    path: &[u8],
+    sep: &[u8],
+    rep: &[u8],
+) -> Result<Vec<u8>, Vec<u8>> {
+    let name = if sep.is_empty() {
+        name.to_vec()
+    } else {
+        replace(name, sep, rep)
+    };
+    let mut note = Vec::new();
+    for template in path.split(|&c| c == b';').filter(|t| !t.is_empty()) {
+        let file_name = replace(template, b"?", &name);
+        if ctx.state().files().borrow_mut().is_readable(&file_name) {
+            return Ok(file_name);
+        }
+        if !note.is_empty() {
+            note.extend_from_slice(b"\n\t");
+        }
+        note.extend_from_slice(b"no file '");
+        note.extend_from_slice(&file_name);
+        note.push(b'\'');
+    }
+    Err(note)
+}
+
+/// `s` with every occurrence of `from` replaced by `to`.
+fn replace(s: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut rest = s;
+    while !rest.is_empty() {
+        if rest.starts_with(from) {
+            out.extend_from_slice(to);
+            rest = &rest[from.len()..];
+        } else {
+            out.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+    out
+}
+
+/// `package.searchpath(name, path [, sep [, rep]])`: the first file along `path` that can be opened
+/// for `name`, with each `sep` (`.` by default) in the name replaced by `rep` (the directory
+/// separator by default). Returns nil and the files tried if there is none.
+fn searchpath<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let name = check_string(ctx, stack, 1, "searchpath")?;
+    let path = check_string(ctx, stack, 2, "searchpath")?;
+    let sep = match stack.get(2) {
+        Value::Nil => LuaString::new(&ctx, b"."),
+        _ => check_string(ctx, stack, 3, "searchpath")?,
+    };
+    let rep = match stack.get(3) {
+        Value::Nil => LuaString::new(&ctx, MAIN_SEPARATOR.to_string().as_bytes()),
+        _ => check_string(ctx, stack, 4, "searchpath")?,
+    };
+    match search(
+        ctx,
+        name.as_bytes(),
+        path.as_bytes(),
+        sep.as_bytes(),
+        rep.as_bytes(),
+    ) {
+        Ok(file_name) => stack.replace(&[Value::String(LuaString::from_vec(&ctx, file_name))]),
+        Err(note) => stack.replace(&[Value::Nil, Value::String(LuaString::from_vec(&ctx, note))]),
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `package.loadlib(path, funcname)`: always fails, as native libraries can't be loaded.
+fn loadlib<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let message = "dynamic libraries not enabled; check your Lua installation";
+    stack.replace(&[
+        Value::Nil,
+        Value::String(LuaString::new(&ctx, message.as_bytes())),
+        Value::String(LuaString::new(&ctx, b"absent")),
+    ]);
+    Ok(NativeReturn::Return)
+}
+
+#[cfg(all(test, feature = "io"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{self, Cursor};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::stdlib::testing::run_in;
+    use crate::stdlib::{self, load_io_with, FileSystem, IoOptions, LuaStream, OpenMode};
+    use crate::Lua;
+
+    /// Files that only exist in memory.
+    struct Files(HashMap<&'static str, &'static str>);
+
+    impl FileSystem for Files {
+        fn open(&mut self, name: &[u8], _: OpenMode) -> io::Result<Box<dyn LuaStream>> {
+            let name = std::str::from_utf8(name).map_err(|_| io::ErrorKind::NotFound)?;
+            let source = self.0.get(name).ok_or(io::ErrorKind::NotFound)?;
+            Ok(Box::new(Cursor::new(source.as_bytes().to_vec())))
+        }
+    }
+
+    fn lua_with(files: &[(&'static str, &'static str)], options: PackageOptions) -> Lua {
+        let mut lua = Lua::new();
+        let files = Files(files.iter().copied().collect());
+        lua.enter(|ctx| {
+            load_io_with(
+                ctx,
+                IoOptions {
+                    file_system: Box::new(files),
+                    ..IoOptions::default()
+                },
+            );
+            load_package_with(ctx, options);
+        });
+        lua
+    }
+
+    #[test]
+    fn native_modules() {
+        let mut lua = Lua::new();
+        let loads = Arc::new(AtomicUsize::new(0));
+        lua.preload_module("greet", {
+            let loads = loads.clone();
+            move |ctx| {
+                loads.fetch_add(1, Ordering::Relaxed);
+                let module = ctx.eval("{hello = function(name) return 'hello ' .. name end}")?;
+                match module[0] {
+                    Value::Table(module) => Ok(module),
+                    _ => unreachable!(),
+                }
+            }
+        });
+        lua.preload_module("broken", |_| Err(RuntimeError::new("not today").into()));
+        assert_eq!(loads.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "local greet, data = require('greet')
+                return greet.hello('you'), data, require('greet') == package.loaded.greet"
+            ),
+            "hello you, :preload:, true"
+        );
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            run_in(&mut lua, "return pcall(require, 'broken')"),
+            "false, not today"
+        );
+
+        // Modules can be preloaded before the package library is open.
+        let mut lua = Lua::empty();
+        lua.preload_module("m", |ctx| Ok(Table::new(&ctx)));
+        lua.enter(|ctx| {
+            stdlib::load_base(ctx);
+            stdlib::load_package(ctx);
+        });
+        assert_eq!(run_in(&mut lua, "return type(require('m'))"), "table");
+    }
+
+    #[test]
+    fn preload_and_path() {
+        let options = PackageOptions {
+            path: "lib/?.lua;lib/?/init.lua".to_owned(),
+            searchers: Vec::new(),
+        };
+        let mut lua = lua_with(
+            &[
+                (
+                    "lib/a.lua",
+                    "count = (count or 0) + 1 return {name = ..., file = select(2, ...)}",
+                ),
+                ("lib/b/c/init.lua", "return 'nested'"),
+                ("lib/empty.lua", ""),
+                ("lib/broken.lua", "return +"),
+            ],
+            options,
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "local a, file = require('a')
+                local again = require('a')
+                return a.name, a.file, file, a == again, count, package.loaded.a == a"
+            ),
+            "a, lib/a.lua, lib/a.lua, true, 1, true"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return require('b.c'), require('empty'), package.loaded.string == string"
+            ),
+            "nested, true, true"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "package.preload.p = function(name, data) return name .. data end
+                return require('p')"
+            ),
+            "p:preload:, :preload:"
+        );
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "return package.searchpath('x.y', 'a/?.lua;b/?.lua')"
+            ),
+            "nil, no file 'a/x/y.lua'\n\tno file 'b/x/y.lua'"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return require('missing')"),
+            "error: module 'missing' not found:\n\tno field package.preload['missing']\n\t\
+             no file 'lib/missing.lua'\n\tno file 'lib/missing/init.lua'"
+        );
+        let broken = run_in(&mut lua, "return require('broken')");
+        assert!(
+            broken.starts_with("error: error loading module 'broken' from file 'lib/broken.lua':"),
+            "{broken}"
+        );
+    }
+
+    #[test]
+    fn host_searchers() {
+        fn native<'gc>(
+            ctx: Context<'gc>,
+            stack: &mut Stack<'gc, '_>,
+        ) -> Result<NativeReturn, LuaError<'gc>> {
+            let module = Table::new(&ctx);
+            module.set(&ctx, Value::Integer(1), stack.get(0)).unwrap();
+            stack.replace(&[Value::Table(module)]);
+            Ok(NativeReturn::Return)
+        }
+        let embedded = |name: &[u8]| match name {
+            b"embedded" => Ok(Module::Source {
+                chunk_name: "=embedded".to_owned(),
+                source: b"return 'from the binary'".to_vec(),
+            }),
+            b"native" => Ok(Module::Native(native)),
+            _ => Err(format!(
+                "no embedded module '{}'",
+                String::from_utf8_lossy(name)
+            )),
+        };
+        let options = PackageOptions {
+            path: String::new(),
+            searchers: vec![Box::new(embedded)],
+        };
+        let mut lua = lua_with(&[], options);
+        assert_eq!(
+            run_in(
+                &mut lua,
+                "local m, where = require('embedded')
+                return m, where, require('native')[1]"
+            ),
+            "from the binary, =embedded, native"
+        );
+        assert_eq!(
+            run_in(&mut lua, "return require('other')"),
+            "error: module 'other' not found:\n\tno field package.preload['other']\n\t\
+             no embedded module 'other'"
+        );
+    }
+}
@@ -0,0 +1,1121 @@
+//! Lua patterns: the matching engine behind `string.find`, `string.match`, `string.gmatch` and
+//! `string.gsub`, usable from Rust on its own.
+//!
+//! Patterns match bytes by backtracking, as in the reference implementation, whose error messages
+//! these are. Character classes such as `%a` follow the C locale, so only ASCII letters are
+//! letters.
+//!
+//! A pattern is first compiled to a [`Pattern`], a list of items with every character class
+//! turned into a set of bytes. Each state keeps a [`PatternCache`] of the patterns its scripts
+//! use, so that a loop calling `string.gsub` with the same pattern doesn't parse it every time.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::table::hash_bytes;
+use crate::{LuaError, LuaString, RuntimeError};
+
+/// The most captures a pattern can have.
+pub const MAX_CAPTURES: usize = 32;
+/// How deeply matching can nest before a pattern counts as too complex.
+const MAX_MATCH_DEPTH: usize = 200;
+const ESCAPE: u8 = b'%';
+
+/// Why a pattern or a replacement string couldn't be used.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PatternError {
+    /// The pattern ends with a lone `%`.
+    EndsWithEscape,
+    /// A `[` set is never closed.
+    MissingBracket,
+    /// A `%b` isn't followed by two characters.
+    MissingBalanceArguments,
+    MissingFrontierSet,
+    /// A `)` doesn't close any capture.
+    InvalidCapture,
+    /// A back-reference or replacement names a capture that doesn't exist (yet); holds the index
+    /// given.
+    InvalidCaptureIndex(usize),
+    /// A capture is still open where the pattern ends.
+    UnfinishedCapture,
+    TooManyCaptures,
+    TooComplex,
+    /// A `%` in a replacement string is followed by neither a digit nor another `%`.
+    InvalidReplacement,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternError::EndsWithEscape => f.write_str("malformed pattern (ends with '%')"),
+            PatternError::MissingBracket => f.write_str("malformed pattern (missing ']')"),
+            PatternError::MissingBalanceArguments => {
+                f.write_str("malformed pattern (missing arguments to '%b')")
+            }
+            PatternError::MissingFrontierSet => f.write_str("missing '[' after '%f' in pattern"),
+            PatternError::InvalidCapture => f.write_str("invalid pattern capture"),
+            PatternError::InvalidCaptureIndex(n) => write!(f, "invalid capture index %{n}"),
+            PatternError::UnfinishedCapture => f.write_str("unfinished capture"),
+            PatternError::TooManyCaptures => f.write_str("too many captures"),
+            PatternError::TooComplex => f.write_str("pattern too complex"),
+            PatternError::InvalidReplacement => {
+                f.write_str("invalid use of '%' in replacement string")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+impl From<PatternError> for RuntimeError {
+    fn from(err: PatternError) -> RuntimeError {
+        RuntimeError::new(err.to_string())
+    }
+}
+
+impl<'gc> From<PatternError> for LuaError<'gc> {
+    fn from(err: PatternError) -> LuaError<'gc> {
+        RuntimeError::from(err).into()
+    }
+}
+
+/// Something a pattern captured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Capture {
+    /// The part of the subject at this byte range.
+    Span(Range<usize>),
+    /// An empty capture `()`, which captures the byte offset it matched at. Lua reports it
+    /// counting from 1.
+    Position(usize),
+}
+
+/// A match of a pattern in a subject.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    range: Range<usize>,
+    /// Where each capture starts, and its length if it was closed.
+    captures: Vec<(usize, CaptureLen)>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CaptureLen {
+    Unfinished,
+    Position,
+    Closed(usize),
+}
+
+impl Match {
+    /// Where the whole match is in the subject.
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// The number of captures in the pattern.
+    pub fn num_captures(&self) -> usize {
+        self.captures.len()
+    }
+
+    /// Capture `n`, counting from 1 as `%1` in a replacement does. A pattern without captures has
+    /// the whole match as capture 1.
+    ///
+    /// Fails for a capture the pattern doesn't have, and for one the pattern never closes.
+    pub fn capture(&self, n: usize) -> Result<Capture, PatternError> {
+        match self.captures.get(n.wrapping_sub(1)) {
+            Some(&(start, len)) => match len {
+                CaptureLen::Unfinished => Err(PatternError::UnfinishedCapture),
+                CaptureLen::Position => Ok(Capture::Position(start)),
+                CaptureLen::Closed(len) => Ok(Capture::Span(start..start + len)),
+            },
+            None if n == 1 => Ok(Capture::Span(self.range())),
+            None => Err(PatternError::InvalidCaptureIndex(n)),
+        }
+    }
+
+    /// The pattern's captures, as `string.find` returns them after the match's position.
+    pub fn captures(&self) -> Result<Vec<Capture>, PatternError> {
+        (1..=self.captures.len()).map(|n| self.capture(n)).collect()
+    }
+
+    /// The captures, or the whole match if the pattern has none, as `string.match` returns them.
+    pub fn results(&self) -> Result<Vec<Capture>, PatternError> {
+        (1..=self.captures.len().max(1))
+            .map(|n| self.capture(n))
+            .collect()
+    }
+
+    /// Appends `replacement` to `out` the way `string.gsub` uses a replacement string: `%1` to
+    /// `%9` stand for captures, `%0` for the whole match and `%%` for a `%`.
+    pub fn expand(
+        &self,
+        subject: &[u8],
+        replacement: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), PatternError> {
+        let mut rest = replacement;
+        while let Some(i) = rest.iter().position(|&b| b == ESCAPE) {
+            out.extend_from_slice(&rest[..i]);
+            match rest.get(i + 1).copied() {
+                Some(ESCAPE) => out.push(ESCAPE),
+                Some(b'0') => out.extend_from_slice(&subject[self.range()]),
+                Some(d) if d.is_ascii_digit() => match self.capture((d - b'0') as usize)? {
+                    Capture::Span(range) => out.extend_from_slice(&subject[range]),
+                    Capture::Position(at) => out.extend_from_slice((at + 1).to_string().as_bytes()),
+                },
+                _ => return Err(PatternError::InvalidReplacement),
+            }
+            rest = &rest[i + 2..];
+        }
+        out.extend_from_slice(rest);
+        Ok(())
+    }
+}
+
+/// Returns true if `pattern` uses any of the characters that make a pattern more than a plain
+/// string, so that a search for it can't be done as a substring search.
+pub fn has_specials(pattern: &[u8]) -> bool {
+    pattern.iter().any(|b| b"^$*+?.([%-".contains(b))
+}
+
+/// A compiled pattern.
+///
+/// Compiling never fails. A malformed part of the pattern becomes an error that matching reports
+/// if it gets that far, as the reference implementation does when it comes across it: looking for
+/// `a(` in `"b"` finds nothing rather than failing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    /// Whether the pattern only matches where the search starts.
+    anchored: bool,
+    items: Box<[Item]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    /// A single character class, repeated as its suffix says.
+    Single(ByteSet, Repeat),
+    /// `(`.
+    OpenCapture,
+    /// `()`, capturing the position.
+    PositionCapture,
+    /// `)`.
+    CloseCapture,
+    /// `$` at the end of the pattern.
+    End,
+    /// `%bxy`.
+    Balance(u8, u8),
+    /// `%f[set]`.
+    Frontier(ByteSet),
+    /// `%1` to `%9`, or `%0`, which is always an invalid index.
+    BackReference(u8),
+    /// A malformed part of the pattern. Nothing after it is compiled.
+    Error(PatternError),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Repeat {
+    One,
+    /// `?`
+    Optional,
+    /// `*`
+    Longest,
+    /// `+`
+    AtLeastOne,
+    /// `-`
+    Shortest,
+}
+
+/// A set of bytes, one bit each.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    fn from_fn(mut contains: impl FnMut(u8) -> bool) -> ByteSet {
+        let mut bits = [0; 4];
+        for c in 0..=u8::MAX {
+            if contains(c) {
+                bits[(c >> 6) as usize] |= 1 << (c & 63);
+            }
+        }
+        ByteSet(bits)
+    }
+
+    fn contains(&self, c: u8) -> bool {
+        self.0[(c >> 6) as usize] & (1 << (c & 63)) != 0
+    }
+}
+
+impl Pattern {
+    /// Compiles `pattern`, in which a leading `^` anchors matches to where the search starts.
+    pub fn new(pattern: &[u8]) -> Pattern {
+        let anchored = pattern.first() == Some(&b'^');
+        Pattern {
+            anchored,
+            items: compile(pattern, anchored as usize),
+        }
+    }
+
+    /// Compiles `pattern` with a leading `^` as an ordinary character, as `string.gmatch` has it.
+    pub fn without_anchor(pattern: &[u8]) -> Pattern {
+        Pattern {
+            anchored: false,
+            items: compile(pattern, 0),
+        }
+    }
+
+    /// Whether the pattern only matches where the search starts.
+    pub fn is_anchored(&self) -> bool {
+        self.anchored
+    }
+
+    /// Finds the first match in `subject` that starts at byte offset `init` or later.
+    pub fn find(&self, subject: &[u8], init: usize) -> Result<Option<Match>, PatternError> {
+        if init > subject.len() {
+            return Ok(None);
+        }
+        let mut matcher = Matcher::new(subject, &self.items);
+        let mut s = init;
+        loop {
+            if let Some(e) = matcher.start(s)? {
+                return Ok(Some(matcher.finish(s, e)));
+            }
+            s += 1;
+            if self.anchored || s > subject.len() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Iterates over the matches in `subject`, as `string.gmatch` does.
+    pub fn gmatch<'a>(&'a self, subject: &'a [u8]) -> GMatch<'a> {
+        GMatch {
+            subject,
+            pattern: self,
+            state: Some(GMatchState::new(0)),
+        }
+    }
+
+    /// Replaces the matches in `subject`, up to `max` of them if given, as `string.gsub` does.
+    /// `replace` appends the replacement for each match to the output it is given. Returns the
+    /// result and the number of matches replaced.
+    pub fn gsub<E: From<PatternError>>(
+        &self,
+        subject: &[u8],
+        max: Option<usize>,
+        mut replace: impl FnMut(&Match, &mut Vec<u8>) -> Result<(), E>,
+    ) -> Result<(Vec<u8>, usize), E> {
+        let mut matcher = Matcher::new(subject, &self.items);
+        let mut out = Vec::with_capacity(subject.len());
+        let (mut s, mut last_match, mut count) = (0, None, 0);
+        while max != Some(count) {
+            match matcher.start(s)? {
+                Some(e) if Some(e) != last_match => {
+                    count += 1;
+                    replace(&matcher.finish(s, e), &mut out)?;
+                    s = e;
+                    last_match = Some(e);
+                }
+                _ if s < subject.len() => {
+                    out.push(subject[s]);
+                    s += 1;
+                }
+                _ => break,
+            }
+            if self.anchored {
+                break;
+            }
+        }
+        out.extend_from_slice(&subject[s..]);
+        Ok((out, count))
+    }
+}
+
+/// The iterator returned by [`Pattern::gmatch`]. It ends after an error.
+pub struct GMatch<'a> {
+    subject: &'a [u8],
+    pattern: &'a Pattern,
+    state: Option<GMatchState>,
+}
+
+impl<'a> Iterator for GMatch<'a> {
+    type Item = Result<Match, PatternError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let state = self.state.as_mut()?;
+        match state.next_match(self.subject, self.pattern) {
+            Ok(found) => found.map(Ok),
+            Err(err) => {
+                self.state = None;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// How far a `gmatch` traversal has got, kept apart from the subject and pattern so that it can be
+/// stored between calls.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GMatchState {
+    /// The byte offset to search from next.
+    pub position: usize,
+    /// Where the last match ended. A match can't end there again, which keeps an empty match from
+    /// turning up right after another match.
+    pub last_match: Option<usize>,
+}
+
+impl GMatchState {
+    pub fn new(init: usize) -> GMatchState {
+        GMatchState {
+            position: init,
+            last_match: None,
+        }
+    }
+
+    /// Finds the next match and moves past it. The pattern is never anchored here, however it
+    /// was compiled.
+    pub fn next_match(
+        &mut self,
+        subject: &[u8],
+        pattern: &Pattern,
+    ) -> Result<Option<Match>, PatternError> {
+        let mut matcher = Matcher::new(subject, &pattern.items);
+        for s in self.position..=subject.len() {
+            match matcher.start(s)? {
+                Some(e) if Some(e) != self.last_match => {
+                    self.position = e;
+                    self.last_match = Some(e);
+                    return Ok(Some(matcher.finish(s, e)));
+                }
+                _ => {}
+            }
+        }
+        self.position = subject.len() + 1;
+        Ok(None)
+    }
+}
+
+/// The number of patterns a new [`PatternCache`] holds.
+pub const DEFAULT_CACHE_CAPACITY: usize = 64;
+/// The length of the longest pattern a new [`PatternCache`] keeps.
+pub const DEFAULT_CACHE_MAX_PATTERN_LEN: usize = 256;
+
+/// Compiled patterns, looked up by their source.
+///
+/// Strings aren't interned, so the cache is keyed by the hash of the pattern's bytes rather than by
+/// the string holding them, which for a Lua string is kept with it. Of two patterns with the same
+/// hash, only the one used last is kept. When it is full, the pattern used least recently makes way. Patterns
+/// longer than [`max_pattern_len`](Self::max_pattern_len) are compiled afresh each time: they
+/// tend to be built on the fly and used once.
+pub struct PatternCache {
+    entries: HashMap<u64, CacheEntry, BuildHasherDefault<HashedHasher>>,
+    capacity: usize,
+    max_pattern_len: usize,
+    /// Counts lookups, to tell which entry was used least recently.
+    clock: u64,
+}
+
+struct CacheEntry {
+    pattern: Box<[u8]>,
+    /// The pattern compiled with and without a leading `^` anchoring it.
+    compiled: [Option<Rc<Pattern>>; 2],
+    last_used: u64,
+}
+
+impl Default for PatternCache {
+    fn default() -> PatternCache {
+        PatternCache::new(DEFAULT_CACHE_CAPACITY)
+    }
+}
+
+impl PatternCache {
+    /// Creates a cache holding up to `capacity` patterns. A capacity of zero turns caching off.
+    pub fn new(capacity: usize) -> PatternCache {
+        PatternCache {
+            entries: HashMap::default(),
+            capacity,
+            max_pattern_len: DEFAULT_CACHE_MAX_PATTERN_LEN,
+            clock: 0,
+        }
+    }
+
+    /// The compiled form of `pattern`, as [`Pattern::new`] compiles it.
+    pub fn get(&mut self, pattern: &[u8]) -> Rc<Pattern> {
+        self.lookup(hash_bytes(pattern), pattern, true)
+    }
+
+    /// The compiled form of `pattern`, as [`Pattern::without_anchor`] compiles it.
+    pub fn get_without_anchor(&mut self, pattern: &[u8]) -> Rc<Pattern> {
+        self.lookup(hash_bytes(pattern), pattern, false)
+    }
+
+    /// Same as [`get`](Self::get), or [`get_without_anchor`](Self::get_without_anchor) without
+    /// `anchoring`, using the hash kept with the string.
+    pub(crate) fn get_string(&mut self, pattern: LuaString<'_>, anchoring: bool) -> Rc<Pattern> {
+        self.lookup(pattern.hash_code(), pattern.as_bytes(), anchoring)
+    }
+
+    fn lookup(&mut self, hash: u64, pattern: &[u8], anchoring: bool) -> Rc<Pattern> {
+        let compile = || {
+            Rc::new(if anchoring {
+                Pattern::new(pattern)
+            } else {
+                Pattern::without_anchor(pattern)
+            })
+        };
+        if self.capacity == 0 || pattern.len() > self.max_pattern_len {
+            return compile();
+        }
+        self.clock += 1;
+        if !self.entries.contains_key(&hash) && self.entries.len() >= self.capacity {
+            self.evict(self.entries.len() + 1 - self.capacity);
+        }
+        let entry = self.entries.entry(hash).or_insert_with(|| CacheEntry {
+            pattern: pattern.into(),
+            compiled: [None, None],
+            last_used: 0,
+        });
+        if *entry.pattern != *pattern {
+            *entry = CacheEntry {
+                pattern: pattern.into(),
+                compiled: [None, None],
+                last_used: 0,
+            };
+        }
+        entry.last_used = self.clock;
+        entry.compiled[anchoring as usize]
+            .get_or_insert_with(compile)
+            .clone()
+    }
+
+    /// Removes the `count` entries used least recently.
+    fn evict(&mut self, count: usize) {
+        let mut ages = self
+            .entries
+            .values()
+            .map(|entry| entry.last_used)
+            .collect::<Vec<_>>();
+        ages.sort_unstable();
+        if let Some(&newest_evicted) = ages.get(count.wrapping_sub(1)) {
+            self.entries
+                .retain(|_, entry| entry.last_used > newest_evicted);
+        }
+    }
+
+    /// The number of patterns cached.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forgets every cached pattern.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Sets how many patterns the cache holds, dropping the ones used least recently if it holds
+    /// more. Zero turns caching off.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        if self.entries.len() > capacity {
+            self.evict(self.entries.len() - capacity);
+        }
+    }
+
+    pub fn max_pattern_len(&self) -> usize {
+        self.max_pattern_len
+    }
+
+    /// Sets the length of the longest pattern worth caching, in bytes.
+    pub fn set_max_pattern_len(&mut self, len: usize) {
+        self.max_pattern_len = len;
+        self.entries.retain(|_, entry| entry.pattern.len() <= len);
+    }
+}
+
+/// Hashes the hash a [`PatternCache`] is keyed by to itself.
+#[derive(Default)]
+struct HashedHasher(u64);
+
+impl Hasher for HashedHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 << 8) | b as u64;
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+}
+
+/// Compiles the pattern from `p` on.
+fn compile(pat: &[u8], mut p: usize) -> Box<[Item]> {
+    let mut items = Vec::new();
+    while p < pat.len() {
+        let item = match (pat[p], pat.get(p + 1).copied()) {
+            (b'(', Some(b')')) => {
+                p += 2;
+                Item::PositionCapture
+            }
+            (b'(', _) => {
+                p += 1;
+                Item::OpenCapture
+            }
+            (b')', _) => {
+                p += 1;
+                Item::CloseCapture
+            }
+            (b'$', None) => {
+                p += 1;
+                Item::End
+            }
+            (ESCAPE, Some(b'b')) if p + 3 >= pat.len() => {
+                Item::Error(PatternError::MissingBalanceArguments)
+            }
+            (ESCAPE, Some(b'b')) => {
+                p += 4;
+                Item::Balance(pat[p - 2], pat[p - 1])
+            }
+            (ESCAPE, Some(b'f')) => {
+                p += 2;
+                if pat.get(p) != Some(&b'[') {
+                    Item::Error(PatternError::MissingFrontierSet)
+                } else {
+                    match class_end(pat, p) {
+                        Ok(ep) => {
+                            let set = ByteSet::from_fn(|c| match_set(pat, c, p, ep - 1));
+                            p = ep;
+                            Item::Frontier(set)
+                        }
+                        Err(err) => Item::Error(err),
+                    }
+                }
+            }
+            (ESCAPE, Some(d)) if d.is_ascii_digit() => {
+                p += 2;
+                Item::BackReference(d - b'0')
+            }
+            _ => match class_end(pat, p) {
+                Ok(ep) => {
+                    let set = match pat[p] {
+                        b'.' => ByteSet([u64::MAX; 4]),
+                        ESCAPE => ByteSet::from_fn(|c| match_class(c, pat[p + 1])),
+                        b'[' => ByteSet::from_fn(|c| match_set(pat, c, p, ep - 1)),
+                        literal => ByteSet::from_fn(|c| c == literal),
+                    };
+                    let repeat = match pat.get(ep) {
+                        Some(b'?') => Repeat::Optional,
+                        Some(b'*') => Repeat::Longest,
+                        Some(b'+') => Repeat::AtLeastOne,
+                        Some(b'-') => Repeat::Shortest,
+                        _ => Repeat::One,
+                    };
+                    p = if repeat == Repeat::One { ep } else { ep + 1 };
+                    Item::Single(set, repeat)
+                }
+                Err(err) => Item::Error(err),
+            },
+        };
+        let malformed = matches!(item, Item::Error(_));
+        items.push(item);
+        if malformed {
+            break;
+        }
+    }
+    items.into_boxed_slice()
+}
+
+/// Returns the index just past the single character class at `p`.
+fn class_end(pat: &[u8], mut p: usize) -> Result<usize, PatternError> {
+    let c = pat[p];
+    p += 1;
+    match c {
+        ESCAPE if p == pat.len() => Err(PatternError::EndsWithEscape),
+        ESCAPE => Ok(p + 1),
+        b'[' => {
+            if pat.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // The first character is part of the set even if it is a `]`.
+            loop {
+                if p == pat.len() {
+                    return Err(PatternError::MissingBracket);
+                }
+                let c = pat[p];
+                p += 1;
+                if c == ESCAPE && p < pat.len() {
+                    p += 1;
+                }
+                if pat.get(p) == Some(&b']') {
+                    return Ok(p + 1);
+                }
+            }
+        }
+        _ => Ok(p),
+    }
+}
+
+/// Whether `c` is in the set from the `[` at `p` to the `]` at `end`.
+fn match_set(pat: &[u8], c: u8, mut p: usize, end: usize) -> bool {
+    let mut found = true;
+    if pat[p + 1] == b'^' {
+        found = false;
+        p += 1;
+    }
+    loop {
+        p += 1;
+        if p >= end {
+            return !found;
+        }
+        if pat[p] == ESCAPE {
+            p += 1;
+            if match_class(c, pat[p]) {
+                return found;
+            }
+        } else if pat[p + 1] == b'-' && p + 2 < end {
+            p += 2;
+            if pat[p - 2] <= c && c <= pat[p] {
+                return found;
+            }
+        } else if pat[p] == c {
+            return found;
+        }
+    }
+}
+
+/// The state of one attempt to match a pattern at some position: the captures so far, and how
+/// much deeper matching can go.
+struct Matcher<'a> {
+    src: &'a [u8],
+    items: &'a [Item],
+    depth: usize,
+    level: usize,
+    captures: [(usize, CaptureLen); MAX_CAPTURES],
+}
+
+impl<'a> Matcher<'a> {
+    fn new(src: &'a [u8], items: &'a [Item]) -> Matcher<'a> {
+        Matcher {
+            src,
+            items,
+            depth: MAX_MATCH_DEPTH,
+            level: 0,
+            captures: [(0, CaptureLen::Unfinished); MAX_CAPTURES],
+        }
+    }
+
+    /// Matches the whole pattern against the subject from `s`, afresh.
+    fn start(&mut self, s: usize) -> Result<Option<usize>, PatternError> {
+        self.level = 0;
+        self.depth = MAX_MATCH_DEPTH;
+        self.do_match(s, 0)
+    }
+
+    fn finish(&self, s: usize, e: usize) -> Match {
+        Match {
+            range: s..e,
+            captures: self.captures[..self.level].to_vec(),
+        }
+    }
+
+    /// Returns where the match of the pattern from item `i` against the subject from `s` ends, if
+    /// it does match.
+    fn do_match(&mut self, mut s: usize, mut i: usize) -> Result<Option<usize>, PatternError> {
+        if self.depth == 0 {
+            return Err(PatternError::TooComplex);
+        }
+        self.depth -= 1;
+        let items = self.items;
+        // Tail calls go around the loop instead.
+        let end = loop {
+            let Some(item) = items.get(i) else {
+                break Some(s);
+            };
+            match *item {
+                Item::PositionCapture => {
+                    break self.start_capture(s, i + 1, CaptureLen::Position)?
+                }
+                Item::OpenCapture => break self.start_capture(s, i + 1, CaptureLen::Unfinished)?,
+                Item::CloseCapture => break self.end_capture(s, i + 1)?,
+                Item::End => break (s == self.src.len()).then_some(s),
+                Item::Balance(open, close) => match self.match_balance(s, open, close) {
+                    Some(e) => {
+                        s = e;
+                        i += 1;
+                    }
+                    None => break None,
+                },
+                Item::Frontier(ref set) => {
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if set.contains(previous) || !set.contains(current) {
+                        break None;
+                    }
+                    i += 1;
+                }
+                Item::BackReference(n) => match self.match_capture(s, n)? {
+                    Some(e) => {
+                        s = e;
+                        i += 1;
+                    }
+                    None => break None,
+                },
+                Item::Error(err) => return Err(err),
+                Item::Single(ref set, repeat) => {
+                    if !self.single_match(s, set) {
+                        if let Repeat::Optional | Repeat::Longest | Repeat::Shortest = repeat {
+                            i += 1;
+                            continue;
+                        }
+                        break None;
+                    }
+                    match repeat {
+                        Repeat::Optional => {
+                            if let Some(e) = self.do_match(s + 1, i + 1)? {
+                                break Some(e);
+                            }
+                            i += 1;
+                        }
+                        Repeat::AtLeastOne => break self.max_expand(s + 1, set, i)?,
+                        Repeat::Longest => break self.max_expand(s, set, i)?,
+                        Repeat::Shortest => break self.min_expand(s, set, i)?,
+                        Repeat::One => {
+                            s += 1;
+                            i += 1;
+                        }
+                    }
+                }
+            }
+        };
+        self.depth += 1;
+        Ok(end)
+    }
+
+    /// Whether the subject at `s` is in `set`.
+    fn single_match(&self, s: usize, set: &ByteSet) -> bool {
+        self.src.get(s).is_some_and(|&c| set.contains(c))
+    }
+
+    /// Matches `%b` with the given delimiters.
+    fn match_balance(&self, s: usize, open: u8, close: u8) -> Option<usize> {
+        if self.src.get(s) != Some(&open) {
+            return None;
+        }
+        let mut depth = 1;
+        for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+        None
+    }
+
+    /// Matches as many repetitions of `set`, the class of item `i`, as the rest of the pattern
+    /// allows.
+    fn max_expand(
+        &mut self,
+        s: usize,
+        set: &ByteSet,
+        i: usize,
+    ) -> Result<Option<usize>, PatternError> {
+        let mut count = 0;
+        while self.single_match(s + count, set) {
+            count += 1;
+        }
+        loop {
+            if let Some(e) = self.do_match(s + count, i + 1)? {
+                return Ok(Some(e));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    /// Matches as few repetitions of `set`, the class of item `i`, as the rest of the pattern
+    /// allows.
+    fn min_expand(
+        &mut self,
+        mut s: usize,
+        set: &ByteSet,
+        i: usize,
+    ) -> Result<Option<usize>, PatternError> {
+        loop {
+            if let Some(e) = self.do_match(s, i + 1)? {
+                return Ok(Some(e));
+            }
+            if !self.single_match(s, set) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(
+        &mut self,
+        s: usize,
+        i: usize,
+        len: CaptureLen,
+    ) -> Result<Option<usize>, PatternError> {
+        if self.level >= MAX_CAPTURES {
+            return Err(PatternError::TooManyCaptures);
+        }
+        self.captures[self.level] = (s, len);
+        self.level += 1;
+        let end = self.do_match(s, i)?;
+        if end.is_none() {
+            self.level -= 1;
+        }
+        Ok(end)
+    }
+
+    fn end_capture(&mut self, s: usize, i: usize) -> Result<Option<usize>, PatternError> {
+        let open = (0..self.level)
+            .rev()
+            .find(|&l| self.captures[l].1 == CaptureLen::Unfinished)
+            .ok_or(PatternError::InvalidCapture)?;
+        self.captures[open].1 = CaptureLen::Closed(s - self.captures[open].0);
+        let end = self.do_match(s, i)?;
+        if end.is_none() {
+            self.captures[open].1 = CaptureLen::Unfinished;
+        }
+        Ok(end)
+    }
+
+    /// Matches the back-reference to capture `n` against the subject at `s`.
+    fn match_capture(&self, s: usize, n: u8) -> Result<Option<usize>, PatternError> {
+        let n = n as usize;
+        let (start, len) = match n.checked_sub(1).map(|l| (l, self.captures.get(l))) {
+            Some((l, Some(&(start, len)))) if l < self.level && len != CaptureLen::Unfinished => {
+                (start, len)
+            }
+            _ => return Err(PatternError::InvalidCaptureIndex(n)),
+        };
+        // A position capture stands for no text, and never matches.
+        let CaptureLen::Closed(len) = len else {
+            return Ok(None);
+        };
+        let found = self.src[s..].starts_with(&self.src[start..start + len]);
+        Ok(found.then_some(s + len))
+    }
+}
+
+/// Whether `c` is in the class named by the letter after a `%`, which is the complement of the
+/// class if upper case. Any other character stands for itself.
+fn match_class(c: u8, class: u8) -> bool {
+    let found = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        // C's `isspace` counts the vertical tab, which `is_ascii_whitespace` doesn't.
+        b's' => c == b' ' || (b'\t'..=b'\r').contains(&c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+    found != class.is_ascii_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The whole match and each capture as text, or the error.
+    fn find_str(subject: &str, pattern: &str) -> Result<Option<Vec<String>>, PatternError> {
+        let Some(m) = Pattern::new(pattern.as_bytes()).find(subject.as_bytes(), 0)? else {
+            return Ok(None);
+        };
+        let mut parts = vec![subject[m.range()].to_owned()];
+        for capture in m.captures()? {
+            parts.push(match capture {
+                Capture::Span(range) => subject[range].to_owned(),
+                Capture::Position(at) => format!("@{}", at + 1),
+            });
+        }
+        Ok(Some(parts))
+    }
+
+    #[test]
+    fn matching() {
+        let cases = [
+            ("hello world", "o w", Some(vec!["o w"])),
+            ("hello world", "^world", None),
+            ("hello world", "world$", Some(vec!["world"])),
+            ("a$b", "$b", Some(vec!["$b"])),
+            (
+                "  key = value ",
+                "(%w+)%s*=%s*(%w+)",
+                Some(vec!["key = value", "key", "value"]),
+            ),
+            ("aaab", "a-b", Some(vec!["aaab"])),
+            ("aaa", "a-", Some(vec![""])),
+            ("aaa", "^a*$", Some(vec!["aaa"])),
+            ("ab", "a?b?c?", Some(vec!["ab"])),
+            ("x = f(a(b)c) + 1", "%b()", Some(vec!["(a(b)c)"])),
+            ("THE (quick) fox", "%f[%a]%a+", Some(vec!["THE"])),
+            ("THE (quick) fox", "%f[%l]%a+", Some(vec!["quick"])),
+            ("hello", "()ll()", Some(vec!["ll", "@3", "@5"])),
+            ("abcabc", "(abc)%1", Some(vec!["abcabc", "abc"])),
+            ("a]b", "[]]", Some(vec!["]"])),
+            ("a-z", "[a%-]+", Some(vec!["a-"])),
+            ("x9Z", "[^%l]", Some(vec!["9"])),
+            ("\u{b}", "%s", Some(vec!["\u{b}"])),
+            (
+                "2024-01-31",
+                "(%d+)-(%d%d)-(%d%d)",
+                Some(vec!["2024-01-31", "2024", "01", "31"]),
+            ),
+        ];
+        for (subject, pattern, expected) in cases {
+            let expected = expected.map(|v| v.into_iter().map(String::from).collect());
+            assert_eq!(find_str(subject, pattern), Ok(expected), "{pattern}");
+        }
+        let m = Pattern::new(b"b").find(b"abc", 2).unwrap();
+        assert_eq!(m, None);
+        let m = Pattern::new(b"").find(b"abc", 3).unwrap().unwrap();
+        assert_eq!(m.range(), 3..3);
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("%", PatternError::EndsWithEscape),
+            ("[a", PatternError::MissingBracket),
+            ("[]", PatternError::MissingBracket),
+            ("%b(", PatternError::MissingBalanceArguments),
+            ("%fx", PatternError::MissingFrontierSet),
+            ("a)", PatternError::InvalidCapture),
+            ("(a)%2", PatternError::InvalidCaptureIndex(2)),
+            ("%0", PatternError::InvalidCaptureIndex(0)),
+            ("(a", PatternError::UnfinishedCapture),
+        ];
+        for (pattern, err) in cases {
+            assert_eq!(find_str("a", pattern), Err(err), "{pattern}");
+        }
+        assert_eq!(
+            find_str("", &"()".repeat(MAX_CAPTURES + 1)),
+            Err(PatternError::TooManyCaptures)
+        );
+        assert_eq!(
+            find_str(&"a".repeat(300), &"a?".repeat(300)),
+            Err(PatternError::TooComplex)
+        );
+        // Errors come up as matching gets to them, as in the reference implementation.
+        assert_eq!(find_str("b", "a("), Ok(None));
+        assert_eq!(
+            PatternError::InvalidCaptureIndex(3).to_string(),
+            "invalid capture index %3"
+        );
+    }
+
+    #[test]
+    fn iteration() {
+        let words = |subject: &str, pattern: &str| {
+            Pattern::without_anchor(pattern.as_bytes())
+                .gmatch(subject.as_bytes())
+                .map(|m| subject[m.unwrap().range()].to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(words("one two  three", "%a+"), ["one", "two", "three"]);
+        // An empty match right where the last one ended is skipped.
+        assert_eq!(words("abc", "%a*"), ["abc"]);
+        assert_eq!(words("a,b", "[^,]*"), ["a", "b"]);
+        assert_eq!(words("^a^", "^a"), ["^a"]);
+
+        let pattern = Pattern::without_anchor(b"(%w+)=(%w+)");
+        let pairs = pattern
+            .gmatch(b"k=v, x=y")
+            .map(|m| m.unwrap().results().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(pairs[1], [Capture::Span(5..6), Capture::Span(7..8)]);
+        let malformed = Pattern::without_anchor(b"a%");
+        let mut errors = malformed.gmatch(b"aa");
+        assert_eq!(errors.next(), Some(Err(PatternError::EndsWithEscape)));
+        assert_eq!(errors.next(), None);
+    }
+
+    #[test]
+    fn substitution() {
+        let sub = |subject: &str, pattern: &str, replacement: &str, max: Option<usize>| {
+            let pattern = Pattern::new(pattern.as_bytes());
+            let (out, n) = pattern.gsub(subject.as_bytes(), max, |m, out| {
+                m.expand(subject.as_bytes(), replacement.as_bytes(), out)
+            })?;
+            Ok::<_, PatternError>((String::from_utf8(out).unwrap(), n))
+        };
+        assert_eq!(
+            sub("hello world", "o", "0", None),
+            Ok(("hell0 w0rld".into(), 2))
+        );
+        assert_eq!(
+            sub("hello world", "(%w+)", "<%1>", Some(1)),
+            Ok(("<hello> world".into(), 1))
+        );
+        assert_eq!(sub("abc", "%w", "%0%0", None), Ok(("aabbcc".into(), 3)));
+        assert_eq!(sub("abc", "", "-", None), Ok(("-a-b-c-".into(), 4)));
+        assert_eq!(sub("abc", "b*", "-", None), Ok(("-a-c-".into(), 3)));
+        assert_eq!(sub("aaa", "^a", "b", None), Ok(("baa".into(), 1)));
+        assert_eq!(sub("x", "()", "%1", None), Ok(("1x2".into(), 2)));
+        assert_eq!(sub("50", "%d+", "%1%%", None), Ok(("50%".into(), 1)));
+        assert_eq!(
+            sub("a", "a", "%", None),
+            Err(PatternError::InvalidReplacement)
+        );
+        assert_eq!(
+            sub("a", "a", "%2", None),
+            Err(PatternError::InvalidCaptureIndex(2))
+        );
+        // Captures only need to be finished if the replacement uses them.
+        assert_eq!(sub("a", "(a", "b", None), Ok(("b".into(), 1)));
+    }
+
+    #[test]
+    fn cache() {
+        let mut cache = PatternCache::new(2);
+        let a = cache.get(b"^a");
+        assert!(Rc::ptr_eq(&a, &cache.get(b"^a")));
+        assert!(a.is_anchored());
+        assert!(!cache.get_without_anchor(b"^a").is_anchored());
+        assert_eq!(cache.len(), 1);
+        cache.get(b"b");
+        cache.get(b"^a");
+        // `b` is the least recently used, so it makes way for `c`.
+        cache.get(b"c");
+        assert_eq!(cache.len(), 2);
+        assert!(Rc::ptr_eq(&a, &cache.get(b"^a")));
+        crate::Lua::new().enter(|ctx| {
+            let p = LuaString::new(&ctx, b"^a");
+            assert!(Rc::ptr_eq(&a, &cache.get_string(p, true)));
+        });
+
+        cache.set_max_pattern_len(1);
+        assert_eq!(cache.len(), 1);
+        let long = cache.get(b"long");
+        assert!(!Rc::ptr_eq(&long, &cache.get(b"long")));
+        cache.set_capacity(0);
+        assert!(cache.is_empty());
+        assert_eq!(*cache.get(b"x"), Pattern::new(b"x"));
+    }
+}
@@ -0,0 +1,110 @@
+//! The pseudo-random generator behind `math.random`.
+//!
+//! It is xoshiro256**, seeded the way the reference implementation seeds it, so a seed gives the
+//! same numbers here as there.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A xoshiro256** generator.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Random {
+    state: [u64; 4],
+}
+
+impl Random {
+    /// A generator seeded with two integers, as `math.randomseed(n1, n2)` seeds it.
+    pub fn new(n1: i64, n2: i64) -> Random {
+        let mut random = Random {
+            state: [n1 as u64, 0xff, n2 as u64, 0],
+        };
+        // Discard the first values, which still show the seed through.
+        for _ in 0..16 {
+            random.next_u64();
+        }
+        random
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    /// A float uniformly distributed in `[0, 1)`, from the top 53 bits of the next value.
+    pub fn next_float(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 * (0.5 / (1u64 << 52) as f64)
+    }
+
+    /// An integer uniformly distributed in `[low, up]`, which must not be empty.
+    pub fn next_in(&mut self, low: i64, up: i64) -> i64 {
+        debug_assert!(low <= up);
+        let n = (up as u64).wrapping_sub(low as u64);
+        let mut value = self.next_u64();
+        if n & n.wrapping_add(1) == 0 {
+            // The interval's size is a power of two, so masking has no bias.
+            value &= n;
+        } else {
+            // Keep drawing from the smallest power-of-two range that holds the interval until a
+            // value lands in it.
+            let mask = u64::MAX >> n.leading_zeros();
+            loop {
+                value &= mask;
+                if value <= n {
+                    break;
+                }
+                value = self.next_u64();
+            }
+        }
+        low.wrapping_add(value as i64)
+    }
+}
+
+/// A seed that differs from one run to the next, from the clock and the process's hash keys. A
+/// target without a clock has the hash keys alone, which may not differ at all.
+pub fn entropy_seed() -> (i64, i64) {
+    let time = if super::HOSTED {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as i64)
+    } else {
+        0
+    };
+    let hashed = RandomState::new().build_hasher().finish() as i64;
+    (time, hashed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequences() {
+        // The same seed gives the same numbers, as in the reference implementation.
+        let mut random = Random::new(42, 0);
+        assert_eq!(random.next_in(1, 100), 50);
+        assert_eq!(random, {
+            let mut other = Random::new(42, 0);
+            other.next_in(1, 100);
+            other
+        });
+        for _ in 0..1000 {
+            let n = random.next_in(-3, 3);
+            assert!((-3..=3).contains(&n));
+            let f = random.next_float();
+            assert!((0.0..1.0).contains(&f));
+        }
+        assert_eq!(Random::new(7, 0).next_in(i64::MIN, i64::MAX), {
+            let mut r = Random::new(7, 0);
+            (r.next_u64() as i64).wrapping_add(i64::MIN)
+        });
+    }
+}
@@ -0,0 +1,665 @@
+//! The string library, set as the `string` global.
+//!
+//! Strings are byte strings: lengths and positions count bytes, and `upper`, `lower` and the
+//! pattern classes only know about ASCII.
+
+use std::cell::OnceCell;
+
+use crate::bytecode;
+use crate::compiler::CompatLevel;
+use crate::vm::{self, ops, Stack};
+use crate::{
+    Context, Function, LuaError, LuaString, NativeClosure, NativeReturn, RuntimeError, Table, Value,
+};
+
+use super::format;
+use super::pack::{self, PackError, PackValue, ValueKind};
+use super::pattern::{self, Capture, GMatchState, Match};
+use super::{
+    arg_error, check_integer, check_string, opt_integer, set_function, set_library, type_error,
+};
+
+/// The longest string `string.rep` builds.
+const MAX_STRING_SIZE: usize = isize::MAX as usize;
+
+/// Opens the string library, and gives strings a metatable that looks methods up in it, unless the
+/// string metatable is locked.
+pub fn load_string(ctx: Context<'_>) {
+    let string = Table::new(&ctx);
+    set_function(ctx, string, "byte", byte);
+    set_function(ctx, string, "char", char);
+    set_function(ctx, string, "dump", dump);
+    set_function(ctx, string, "find", find);
+    set_function(ctx, string, "format", format::format);
+    set_function(ctx, string, "gmatch", gmatch);
+    set_function(ctx, string, "gsub", gsub);
+    set_function(ctx, string, "len", len);
+    set_function(ctx, string, "lower", lower);
+    set_function(ctx, string, "match", match_);
+    set_function(ctx, string, "pack", pack);
+    set_function(ctx, string, "packsize", packsize);
+    set_function(ctx, string, "rep", rep);
+    set_function(ctx, string, "reverse", reverse);
+    set_function(ctx, string, "sub", sub);
+    set_function(ctx, string, "unpack", unpack);
+    set_function(ctx, string, "upper", upper);
+    if ctx.compat_level() == CompatLevel::Lua51 {
+        set_function(ctx, string, "gfind", gmatch);
+    }
+    set_library(ctx, "string", string);
+
+    let metatable = Table::new(&ctx);
+    metatable
+        .set(&ctx, LuaString::new(&ctx, b"__index"), string)
+        .expect("string keys are always valid");
+    let _ = ctx.set_string_metatable(Some(metatable));
+}
+
+/// `string.dump(f [, strip])`: returns a binary chunk that `load` turns back into a copy of the Lua
+/// function `f`, without debug information if `strip` is true. The copy's upvalues start out nil.
+fn dump<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let closure = match stack.get(0) {
+        Value::Function(Function::Closure(c)) => c,
+        Value::Function(_) => return Err(RuntimeError::new("unable to dump given function").into()),
+        v => {
+            return Err(RuntimeError::new(format!(
+                "bad argument #1 to 'dump' (function expected, got {})",
+                v.type_name()
+            ))
+            .into())
+        }
+    };
+    let chunk = bytecode::dump(&closure.proto(), stack.get(1).to_bool());
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, chunk))]);
+    Ok(NativeReturn::Return)
+}
+
+/// Turns a position given to a string function into one counting from 1, where a negative
+/// position counts back from the end and one before the start is taken as the start.
+fn start_position(pos: i64, len: usize) -> usize {
+    if pos > 0 {
+        pos as usize
+    } else if pos == 0 || pos < -(len as i64) {
+        1
+    } else {
+        (len as i64 + pos + 1) as usize
+    }
+}
+
+/// Like [`start_position`] for the end of a range, which is kept within the string.
+fn end_position(pos: i64, len: usize) -> usize {
+    if pos > len as i64 {
+        len
+    } else if pos >= 0 {
+        pos as usize
+    } else if pos < -(len as i64) {
+        0
+    } else {
+        (len as i64 + pos + 1) as usize
+    }
+}
+
+/// `string.byte(s [, i [, j]])`: the bytes of `s` from `i` (1 by default) to `j` (`i` by default)
+/// as integers.
+fn byte<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "byte")?;
+    let start = start_position(opt_integer(stack, 2, "byte", 1)?, s.len());
+    let end = end_position(opt_integer(stack, 3, "byte", start as i64)?, s.len());
+    stack.clear();
+    if start <= end {
+        if end - start >= i32::MAX as usize {
+            return Err(RuntimeError::new("string slice too long").into());
+        }
+        let bytes = &s.as_bytes()[start - 1..end];
+        stack.extend(bytes.iter().map(|&b| Value::Integer(b as i64)));
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `string.char(...)`: a string of the bytes given as integers.
+fn char<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let mut bytes = Vec::with_capacity(stack.len());
+    for n in 1..=stack.len() {
+        let c = check_integer(stack, n, "char")?;
+        let c = u8::try_from(c).map_err(|_| arg_error(n, "char", "value out of range"))?;
+        bytes.push(c);
+    }
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, bytes))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `string.len(s)`: the length of `s` in bytes.
+fn len<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "len")?;
+    stack.replace(&[Value::Integer(s.len() as i64)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `string.lower(s)`: `s` with ASCII letters in lower case.
+fn lower<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "lower")?;
+    let lowered = s.as_bytes().to_ascii_lowercase();
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, lowered))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `string.upper(s)`: `s` with ASCII letters in upper case.
+fn upper<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "upper")?;
+    let raised = s.as_bytes().to_ascii_uppercase();
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, raised))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `string.rep(s, n [, sep])`: `n` copies of `s`, separated by `sep` if given.
+fn rep<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "rep")?;
+    let n = check_integer(stack, 2, "rep")?;
+    let sep = match stack.get(2) {
+        Value::Nil => LuaString::new(&ctx, b""),
+        _ => check_string(ctx, stack, 3, "rep")?,
+    };
+    let sep = sep.as_bytes();
+    if n <= 0 {
+        stack.replace(&[Value::String(LuaString::new(&ctx, b""))]);
+        return Ok(NativeReturn::Return);
+    }
+    let total = (s.len() + sep.len())
+        .checked_mul(n as usize)
+        .map(|total| total - sep.len())
+        .filter(|&total| total <= MAX_STRING_SIZE)
+        .ok_or_else(|| RuntimeError::new("resulting string too large"))?;
+    let mut bytes = Vec::new();
+    bytes
+        .try_reserve_exact(total)
+        .map_err(|_| RuntimeError::new("not enough memory"))?;
+    for i in 0..n {
+        if i > 0 {
+            bytes.extend_from_slice(sep);
+        }
+        bytes.extend_from_slice(s.as_bytes());
+    }
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, bytes))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `string.reverse(s)`: `s` with its bytes in reverse order.
+fn reverse<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "reverse")?;
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.reverse();
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, bytes))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `string.sub(s [, i [, j]])`: the part of `s` from `i` (1 by default) to `j` (the end by
+/// default), where negative positions count back from the end.
+fn sub<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "sub")?;
+    let start = start_position(opt_integer(stack, 2, "sub", 1)?, s.len());
+    let end = end_position(opt_integer(stack, 3, "sub", -1)?, s.len());
+    let result = if start > end {
+        LuaString::new(&ctx, b"")
+    } else if start == 1 && end == s.len() {
+        s
+    } else {
+        LuaString::new(&ctx, &s.as_bytes()[start - 1..end])
+    };
+    stack.replace(&[Value::String(result)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `string.find(s, pattern [, init [, plain]])`: the start and end of the first match of `pattern`
+/// in `s` from `init` on, followed by its captures, or nil. With `plain` set, or a pattern without
+/// special characters, this is a plain substring search.
+fn find<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    find_or_match(ctx, stack, true)
+}
+
+/// `string.match(s, pattern [, init])`: the captures of the first match of `pattern` in `s` from
+/// `init` on, or the whole match if it has none, or nil.
+fn match_<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    find_or_match(ctx, stack, false)
+}
+
+fn find_or_match<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    find: bool,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let name = if find { "find" } else { "match" };
+    let s = check_string(ctx, stack, 1, name)?;
+    let p = check_string(ctx, stack, 2, name)?;
+    let init = start_position(opt_integer(stack, 3, name, 1)?, s.len());
+    if init > s.len() + 1 {
+        stack.replace(&[Value::Nil]);
+        return Ok(NativeReturn::Return);
+    }
+    let subject = s.as_bytes();
+    if find && (stack.get(3).to_bool() || !pattern::has_specials(p.as_bytes())) {
+        match find_bytes(&subject[init - 1..], p.as_bytes()) {
+            Some(at) => {
+                let start = (init + at) as i64;
+                stack.replace(&[
+                    Value::Integer(start),
+                    Value::Integer(start + p.len() as i64 - 1),
+                ]);
+            }
+            None => stack.replace(&[Value::Nil]),
+        }
+        return Ok(NativeReturn::Return);
+    }
+
+    let pattern = ctx.pattern_cache().get_string(p, true);
+    let Some(m) = pattern.find(subject, init - 1)? else {
+        stack.replace(&[Value::Nil]);
+        return Ok(NativeReturn::Return);
+    };
+    stack.clear();
+    if find {
+        let range = m.range();
+        stack.push(Value::Integer(range.start as i64 + 1));
+        stack.push(Value::Integer(range.end as i64));
+        stack.extend(capture_values(ctx, s, m.captures()?));
+    } else {
+        stack.extend(capture_values(ctx, s, m.results()?));
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// Where `needle` first occurs in `haystack`.
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The values of captures of a match in `s`: strings, or positions counting from 1.
+fn capture_values<'gc>(
+    ctx: Context<'gc>,
+    s: LuaString<'gc>,
+    captures: Vec<Capture>,
+) -> impl Iterator<Item = Value<'gc>> {
+    captures.into_iter().map(move |capture| match capture {
+        Capture::Span(range) => Value::String(LuaString::new(&ctx, &s.as_bytes()[range])),
+        Capture::Position(at) => Value::Integer(at as i64 + 1),
+    })
+}
+
+/// `string.gmatch(s, pattern [, init])`: an iterator over the matches of `pattern` in `s` from
+/// `init` on, returning the captures of each, or the whole match. A `^` doesn't anchor the pattern
+/// here.
+fn gmatch<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "gmatch")?;
+    let p = check_string(ctx, stack, 2, "gmatch")?;
+    let init = start_position(opt_integer(stack, 3, "gmatch", 1)?, s.len()).min(s.len() + 1);
+    // The upvalues are the subject, the pattern, and the [`GMatchState`] as the position and the
+    // end of the last match.
+    let upvalues = [
+        Value::String(s),
+        Value::String(p),
+        Value::Integer(init as i64 - 1),
+        Value::Nil,
+    ];
+    let iterator = NativeClosure::new(&ctx, gmatch_step, &upvalues);
+    stack.replace(&[Value::Function(iterator.into())]);
+    Ok(NativeReturn::Return)
+}
+
+fn gmatch_step<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (Value::String(s), Value::String(p)) = (stack.upvalue(0), stack.upvalue(1)) else {
+        unreachable!("gmatch iterators are made with a subject and a pattern");
+    };
+    let mut state = GMatchState {
+        position: stack.upvalue(2).to_integer().unwrap_or(0) as usize,
+        last_match: stack.upvalue(3).to_integer().map(|end| end as usize),
+    };
+    let pattern = ctx.pattern_cache().get_string(p, false);
+    let found = state.next_match(s.as_bytes(), &pattern)?;
+    stack.set_upvalue(&ctx, 2, Value::Integer(state.position as i64));
+    let last_match = state
+        .last_match
+        .map_or(Value::Nil, |end| Value::Integer(end as i64));
+    stack.set_upvalue(&ctx, 3, last_match);
+    stack.clear();
+    if let Some(m) = found {
+        stack.extend(capture_values(ctx, s, m.results()?));
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `string.gsub(s, pattern, repl [, n])`: `s` with the first `n` matches of `pattern` (all of them
+/// by default) replaced, and the number of matches. `repl` is a string in which `%1` to `%9` stand
+/// for captures and `%0` for the whole match, or a table indexed with the first capture, or a
+/// function called with the captures. If the table or function gives false or nil, the match is
+/// left as it is.
+fn gsub<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "gsub")?;
+    let p = check_string(ctx, stack, 2, "gsub")?;
+    let repl = stack.get(2);
+    if !matches!(
+        repl,
+        Value::String(_)
+            | Value::Integer(_)
+            | Value::Number(_)
+            | Value::Table(_)
+            | Value::Function(_)
+    ) {
+        return Err(type_error(stack, 3, "gsub", "string/function/table").into());
+    }
+    let max = opt_integer(stack, 4, "gsub", s.len() as i64 + 1)?;
+    let max = usize::try_from(max.max(0)).unwrap_or(usize::MAX);
+
+    let subject = s.as_bytes();
+    let thread = stack.thread();
+    let replace = |m: &Match, out: &mut Vec<u8>| -> Result<(), LuaError<'gc>> {
+        let value = match repl {
+            Value::String(r) => return Ok(m.expand(subject, r.as_bytes(), out)?),
+            Value::Integer(_) | Value::Number(_) => {
+                ops::write_concat_operand(out, repl);
+                return Ok(());
+            }
+            Value::Table(_) => {
+                let key = capture_values(ctx, s, vec![m.capture(1)?]).next();
+                vm::index(ctx, thread, repl, key.unwrap_or_default())?
+            }
+            _ => {
+                let args = capture_values(ctx, s, m.results()?).collect::<Vec<_>>();
+                let results = vm::call(ctx, thread, repl, &args)?;
+                results.first().copied().unwrap_or_default()
+            }
+        };
+        if !value.to_bool() {
+            out.extend_from_slice(&subject[m.range()]);
+        } else if !ops::write_concat_operand(out, value) {
+            return Err(RuntimeError::new(format!(
+                "invalid replacement value (a {})",
+                value.type_name()
+            ))
+            .into());
+        }
+        Ok(())
+    };
+    let pattern = ctx.pattern_cache().get_string(p, true);
+    let (out, count) = pattern.gsub(subject, Some(max), replace)?;
+    stack.replace(&[
+        Value::String(LuaString::from_vec(&ctx, out)),
+        Value::Integer(count as i64),
+    ]);
+    Ok(NativeReturn::Return)
+}
+
+/// A packing error as the function `name` reports it.
+fn pack_error(err: PackError, name: &str) -> RuntimeError {
+    match err.argument() {
+        Some(n) => arg_error(n, name, err),
+        None => RuntimeError::new(err.to_string()),
+    }
+}
+
+/// Why `string.pack` failed: the format, or an argument not being the value it asks for.
+enum PackFailure {
+    Pack(PackError),
+    Argument(RuntimeError),
+}
+
+impl From<PackError> for PackFailure {
+    fn from(err: PackError) -> PackFailure {
+        PackFailure::Pack(err)
+    }
+}
+
+impl From<RuntimeError> for PackFailure {
+    fn from(err: RuntimeError) -> PackFailure {
+        PackFailure::Argument(err)
+    }
+}
+
+/// `string.pack(fmt, ...)`: the values packed into a binary string as `fmt` says.
+fn pack<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let format = check_string(ctx, stack, 1, "pack")?;
+    // The string arguments, kept here so that the bytes packed can borrow from them.
+    let strings: Vec<OnceCell<LuaString<'gc>>> = vec![OnceCell::new(); stack.len()];
+    let value = |index: usize, kind| -> Result<PackValue<'_>, PackFailure> {
+        let n = index + 2;
+        let value = match kind {
+            ValueKind::Integer => PackValue::Integer(check_integer(stack, n, "pack")?),
+            ValueKind::Float => match ops::coerce_number(stack.get(n - 1)) {
+                Some(Value::Integer(i)) => PackValue::Integer(i),
+                Some(Value::Number(n)) => PackValue::Float(n),
+                _ => return Err(type_error(stack, n, "pack", "number").into()),
+            },
+            ValueKind::String => {
+                // Each argument is asked for once, and one that was given has a cell.
+                let cell = &strings[n - 1];
+                let _ = cell.set(check_string(ctx, stack, n, "pack")?);
+                PackValue::String(cell.get().unwrap().as_bytes())
+            }
+        };
+        Ok(value)
+    };
+    let bytes = pack::pack(format.as_bytes(), value).map_err(|err| match err {
+        PackFailure::Pack(err) => pack_error(err, "pack"),
+        PackFailure::Argument(err) => err,
+    })?;
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, bytes))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `string.packsize(fmt)`: the length of the strings `string.pack` makes with `fmt`, which must
+/// not have variable-length options.
+fn packsize<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let format = check_string(ctx, stack, 1, "packsize")?;
+    let size = pack::packsize(format.as_bytes()).map_err(|err| pack_error(err, "packsize"))?;
+    stack.replace(&[Value::Integer(size as i64)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `string.unpack(fmt, s [, pos])`: the values packed in `s` from `pos` (1 by default) as `fmt`
+/// says, and the position just past them.
+fn unpack<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let format = check_string(ctx, stack, 1, "unpack")?;
+    let data = check_string(ctx, stack, 2, "unpack")?;
+    let pos = start_position(opt_integer(stack, 3, "unpack", 1)?, data.len()) - 1;
+    let (values, end) = pack::unpack(format.as_bytes(), data.as_bytes(), pos)
+        .map_err(|err| pack_error(err, "unpack"))?;
+    stack.clear();
+    stack.extend(values.into_iter().map(|v| match v {
+        PackValue::Integer(i) => Value::Integer(i),
+        PackValue::Float(n) => Value::Number(n),
+        PackValue::String(s) => Value::String(LuaString::new(&ctx, s)),
+    }));
+    stack.push(Value::Integer(end as i64 + 1));
+    Ok(NativeReturn::Return)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::testing::{run, run_in};
+    use crate::{Lua, LuaString, Table};
+
+    #[test]
+    fn string_methods() {
+        let mut lua = Lua::new();
+        let mut eval = |source: &str| run_in(&mut lua, source);
+        assert_eq!(eval("function string.twice(s) return s .. s end"), "");
+        assert_eq!(
+            eval("('ab'):twice(), ('x').twice == string.twice"),
+            "abab, true"
+        );
+        assert_eq!(eval("local s = 'cd' return s:twice()"), "cdcd");
+        assert!(eval("('x'):nope()").ends_with("attempt to call a nil value (method 'nope')"));
+
+        // A host can swap in its own metatable, until it locks it.
+        lua.enter(|ctx| {
+            let metatable = Table::new(&ctx);
+            let methods = ctx
+                .eval("return {len = function(s) return #s end}")
+                .unwrap()[0];
+            metatable
+                .set(&ctx, LuaString::new(&ctx, b"__index"), methods)
+                .unwrap();
+            ctx.set_string_metatable(Some(metatable)).unwrap();
+        });
+        assert_eq!(run_in(&mut lua, "('abc'):len(), ('abc').twice"), "3, nil");
+        lua.enter(|ctx| {
+            let metatable = ctx.string_metatable();
+            ctx.lock_string_metatable();
+            assert_eq!(
+                ctx.set_string_metatable(None).unwrap_err().message(),
+                "cannot change a protected metatable"
+            );
+            assert_eq!(ctx.string_metatable(), metatable);
+        });
+
+        Lua::empty().enter(|ctx| {
+            assert!(ctx.string_metatable().is_none());
+            let err = ctx.eval("('x'):len()").unwrap_err().to_string();
+            assert!(
+                err.ends_with("attempt to index a string value (constant 'x')"),
+                "{err}"
+            );
+        });
+    }
+
+    #[test]
+    fn basics() {
+        assert_eq!(
+            run("return ('hello'):sub(2, -2), ('hello'):sub(-3), ('hello'):sub(4, 2)"),
+            "ell, llo, "
+        );
+        assert_eq!(
+            run("return ('ab'):rep(3, ', '), ('x'):rep(-1)"),
+            "ab, ab, ab, "
+        );
+        assert_eq!(
+            run("return ('MiXed 1'):upper(), ('MiXed 1'):lower()"),
+            "MIXED 1, mixed 1"
+        );
+        assert_eq!(run("return ('abc'):byte(-2, -1)"), "98, 99");
+        assert_eq!(run("return string.char(104, 0, 105):len()"), "3");
+        assert_eq!(
+            run("return ('a\0b'):reverse() == 'b\0a', #string.rep(12.5, 2)"),
+            "true, 8"
+        );
+        assert_eq!(
+            run("return string.char(256)"),
+            "error: bad argument #1 to 'char' (value out of range)"
+        );
+        assert_eq!(
+            run("return string.sub()"),
+            "error: bad argument #1 to 'sub' (string expected, got no value)"
+        );
+        assert_eq!(
+            run("return ('x'):rep(1.5)"),
+            "error: bad argument #2 to 'rep' (number has no integer representation)"
+        );
+        assert_eq!(
+            run("return ('xx'):rep(1 << 62)"),
+            "error: resulting string too large"
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(run("return ('hello world'):find('o', 6)"), "8, 8");
+        assert_eq!(run("return ('a+b'):find('+', 1, true)"), "2, 2");
+        assert_eq!(
+            run("return ('key=val'):find('(%w+)=(%w+)')"),
+            "1, 7, key, val"
+        );
+        assert_eq!(run("return ('abc'):find('', 10)"), "nil");
+        assert_eq!(run("return ('hello'):match('()ll()')"), "3, 5");
+        assert_eq!(run("return ('  trim  '):match('^%s*(.-)%s*$')"), "trim");
+        assert_eq!(
+            run("local words = {}
+                for w in ('one two three'):gmatch('%a+') do words[#words + 1] = w end
+                return words[1] .. words[3], #words"),
+            "onethree, 3"
+        );
+        assert_eq!(
+            run("local it = ('k=v, x=y'):gmatch('(%w)=(%w)', 3) return it()"),
+            "x, y"
+        );
+        assert_eq!(
+            run("return ('hello world'):gsub('(%w+)', '<%1>')"),
+            "<hello> <world>, 2"
+        );
+        assert_eq!(
+            run("return ('$a $b'):gsub('%$(%w+)', {a = 1, b = false})"),
+            "1 $b, 2"
+        );
+        assert_eq!(
+            run("return ('abc'):gsub('%w', function(c) return c:upper() .. '.' end, 2)"),
+            "A.B.c, 2"
+        );
+        assert_eq!(
+            run("return ('abc'):gsub('b', {b = {}})"),
+            "error: invalid replacement value (a table)"
+        );
+        assert_eq!(
+            run("return ('abc'):find('%')"),
+            "error: malformed pattern (ends with '%')"
+        );
+        assert_eq!(
+            run("return ('abc'):gsub('b')"),
+            "error: bad argument #3 to 'gsub' (string/function/table expected, got no value)"
+        );
+    }
+
+    #[test]
+    fn packing() {
+        assert_eq!(
+            run("local s = string.pack('<i4 s1 z d', 7, 'hi', 'zed', 0.5)
+                return #s, string.unpack('<i4 s1 z d', s)"),
+            "19, 7, hi, zed, 0.5, 20"
+        );
+        assert_eq!(
+            run("return string.packsize('!8 b d'), string.unpack('>H', '\\1\\2\\3', 2)"),
+            "16, 515, 4"
+        );
+        assert_eq!(
+            run("return string.pack('i1', 200)"),
+            "error: bad argument #2 to 'pack' (integer overflow)"
+        );
+        assert_eq!(
+            run("return string.pack('i', 'x')"),
+            "error: bad argument #2 to 'pack' (number expected, got string)"
+        );
+        assert_eq!(
+            run("return string.unpack('i4', 'abc')"),
+            "error: bad argument #2 to 'unpack' (data string too short)"
+        );
+        assert_eq!(
+            run("return string.pack('y')"),
+            "error: invalid format option 'y'"
+        );
+    }
+}
@@ -0,0 +1,632 @@
+//! The table library, set as the `table` global.
+//!
+//! Like the reference implementation, these functions go through `__index`, `__newindex` and
+//! `__len`, and accept any value with those metamethods in place of a table. A table without a
+//! metatable has nothing to intercept its accesses, so then they work on its entries directly,
+//! moving whole stretches of its array part at once.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::vm::ops::{self, CompareOp, MetaResult};
+use crate::vm::{self, Stack};
+use crate::{
+    Context, LuaError, LuaString, LuaStringBuilder, NativeReturn, RuntimeError, Table, Thread,
+    Value,
+};
+
+use super::{
+    arg_error, check_integer, check_string, opt_integer, set_function, set_library, type_error,
+};
+
+/// The most values `table.unpack` returns.
+const MAX_UNPACK: i64 = 1_000_000;
+/// Intervals at least this long are sorted around a random pivot once sorting turns out slow.
+const RANDOM_PIVOT_LIMIT: usize = 100;
+
+/// Opens the table library.
+pub fn load_table(ctx: Context<'_>) {
+    let table = Table::new(&ctx);
+    set_function(ctx, table, "concat", concat);
+    set_function(ctx, table, "freeze", freeze);
+    set_function(ctx, table, "insert", insert);
+    set_function(ctx, table, "isfrozen", isfrozen);
+    set_function(ctx, table, "move", move_);
+    set_function(ctx, table, "pack", pack);
+    set_function(ctx, table, "remove", remove);
+    set_function(ctx, table, "sort", sort);
+    set_function(ctx, table, "unpack", unpack);
+    set_library(ctx, "table", table);
+}
+
+/// A table argument, or a value that stands in for one with metamethods.
+#[derive(Copy, Clone)]
+struct Sequence<'gc> {
+    value: Value<'gc>,
+    thread: Thread<'gc>,
+}
+
+impl<'gc> Sequence<'gc> {
+    /// Argument `n` to `name`, which must be a table unless its metatable has each of
+    /// `metamethods`.
+    fn check(
+        ctx: Context<'gc>,
+        stack: &Stack<'gc, '_>,
+        n: usize,
+        name: &str,
+        metamethods: &[&str],
+    ) -> Result<Sequence<'gc>, RuntimeError> {
+        let value = stack.get(n - 1);
+        if !matches!(value, Value::Table(_)) {
+            let has_all = ops::metatable(ctx, value)
+                .is_some_and(|mt| metamethods.iter().all(|m| !mt.get_str(m).is_nil()));
+            if !has_all {
+                return Err(type_error(stack, n, name, "table"));
+            }
+        }
+        Ok(Sequence {
+            value,
+            thread: stack.thread(),
+        })
+    }
+
+    /// The table, when it has no metatable to intercept accesses to it and isn't frozen.
+    fn plain(self) -> Option<Table<'gc>> {
+        match self.value {
+            Value::Table(t) if t.metatable().is_none() && !t.is_frozen() => Some(t),
+            _ => None,
+        }
+    }
+
+    fn get(self, ctx: Context<'gc>, i: i64) -> Result<Value<'gc>, LuaError<'gc>> {
+        match self.plain() {
+            Some(t) => Ok(t.get(i)),
+            None => vm::index(ctx, self.thread, self.value, Value::Integer(i)),
+        }
+    }
+
+    fn set(self, ctx: Context<'gc>, i: i64, value: Value<'gc>) -> Result<(), LuaError<'gc>> {
+        match self.plain() {
+            Some(t) => {
+                t.set(&ctx, i, value)
+                    .expect("integer keys are always valid");
+                Ok(())
+            }
+            None => vm::new_index(ctx, self.thread, self.value, Value::Integer(i), value),
+        }
+    }
+
+    fn len(self, ctx: Context<'gc>) -> Result<i64, LuaError<'gc>> {
+        let len = vm::len(ctx, self.thread, self.value)?;
+        match ops::coerce_number(len).and_then(|n| n.to_integer()) {
+            Some(len) => Ok(len),
+            None => Err(RuntimeError::new("object length is not an integer").into()),
+        }
+    }
+}
+
+/// `table.insert(t, [pos,] value)`: inserts `value` at `pos`, the end by default, moving up the
+/// elements after it.
+fn insert<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let seq = Sequence::check(ctx, stack, 1, "insert", &["__index", "__newindex", "__len"])?;
+    // The first empty position.
+    let end = seq.len(ctx)?.wrapping_add(1);
+    let (pos, value) = match stack.len() {
+        2 => (end, stack.get(1)),
+        3 => {
+            let pos = check_integer(stack, 2, "insert")?;
+            if (pos as u64).wrapping_sub(1) >= end as u64 {
+                return Err(arg_error(2, "insert", "position out of bounds").into());
+            }
+            (pos, stack.get(2))
+        }
+        _ => return Err(RuntimeError::new("wrong number of arguments to 'insert'").into()),
+    };
+    stack.clear();
+    if pos < end {
+        if let Some(t) = seq.plain() {
+            let mut state = t.borrow_mut(&ctx);
+            let array = state.entries.array_mut();
+            if end as u64 <= array.len() as u64 + 1 {
+                let (pos, end) = (pos as usize, end as usize);
+                let last = array[end - 2];
+                array[pos - 1..end - 1].rotate_right(1);
+                array[pos - 1] = value;
+                state
+                    .entries
+                    .set(Value::Integer(end as i64), last)
+                    .expect("integer keys are always valid");
+                return Ok(NativeReturn::Return);
+            }
+        }
+        for i in (pos + 1..=end).rev() {
+            let moved = seq.get(ctx, i - 1)?;
+            seq.set(ctx, i, moved)?;
+        }
+    }
+    seq.set(ctx, pos, value)?;
+    Ok(NativeReturn::Return)
+}
+
+/// `table.remove(t [, pos])`: removes and returns the element at `pos`, the last one by default,
+/// moving down the elements after it.
+fn remove<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let seq = Sequence::check(ctx, stack, 1, "remove", &["__index", "__newindex", "__len"])?;
+    let size = seq.len(ctx)?;
+    let mut pos = opt_integer(stack, 2, "remove", size)?;
+    // Any position from 1 to just past the end can be given, and 0 for an empty table.
+    if pos != size && (pos as u64).wrapping_sub(1) > size as u64 {
+        return Err(arg_error(2, "remove", "position out of bounds").into());
+    }
+    if let Some(t) = seq.plain() {
+        let mut state = t.borrow_mut(&ctx);
+        let array = state.entries.array_mut();
+        if 1 <= pos && pos <= size && size as u64 <= array.len() as u64 {
+            let (pos, size) = (pos as usize, size as usize);
+            let removed = array[pos - 1];
+            array[pos - 1..size].rotate_left(1);
+            array[size - 1] = Value::Nil;
+            stack.replace(&[removed]);
+            return Ok(NativeReturn::Return);
+        }
+    }
+    let removed = seq.get(ctx, pos)?;
+    while pos < size {
+        let moved = seq.get(ctx, pos + 1)?;
+        seq.set(ctx, pos, moved)?;
+        pos += 1;
+    }
+    seq.set(ctx, pos, Value::Nil)?;
+    stack.replace(&[removed]);
+    Ok(NativeReturn::Return)
+}
+
+/// `table.concat(t [, sep [, i [, j]]])`: the strings and numbers from `t[i]` (1 by default) to
+/// `t[j]` (`#t` by default) joined with `sep` in between.
+fn concat<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let seq = Sequence::check(ctx, stack, 1, "concat", &["__index", "__len"])?;
+    let len = seq.len(ctx)?;
+    let sep = match stack.get(1) {
+        Value::Nil => LuaString::new(&ctx, b""),
+        _ => check_string(ctx, stack, 2, "concat")?,
+    };
+    let sep = sep.as_bytes();
+    let first = opt_integer(stack, 3, "concat", 1)?;
+    let last = opt_integer(stack, 4, "concat", len)?;
+    let mut out = LuaStringBuilder::new();
+    let mut i = first;
+    while i <= last {
+        let value = seq.get(ctx, i)?;
+        if !out.push_value(value) {
+            return Err(RuntimeError::new(format!(
+                "invalid value (at index {i}) in table for 'concat'"
+            ))
+            .into());
+        }
+        if i == last {
+            break;
+        }
+        out.push(sep);
+        i += 1;
+    }
+    stack.replace(&[Value::String(out.finish(&ctx))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `table.pack(...)`: a new table of the arguments, with their number as its field `n`.
+fn pack<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let table = Table::with_capacity(&ctx, stack.len(), 1);
+    for i in 0..stack.len() {
+        table
+            .set(&ctx, i as i64 + 1, stack.get(i))
+            .expect("integer keys are always valid");
+    }
+    table
+        .set(&ctx, LuaString::new(&ctx, b"n"), stack.len() as i64)
+        .expect("string keys are always valid");
+    stack.replace(&[Value::Table(table)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `table.freeze(t)`: makes `t` read-only and returns it. A table whose metatable is protected
+/// belongs to whoever protected it, and can't be frozen.
+fn freeze<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let Value::Table(t) = stack.get(0) else {
+        return Err(type_error(stack, 1, "freeze", "table").into());
+    };
+    if t.metatable()
+        .is_some_and(|mt| !mt.get_str("__metatable").is_nil())
+    {
+        return Err(arg_error(1, "freeze", "table has a protected metatable").into());
+    }
+    t.freeze(&ctx);
+    stack.replace(&[Value::Table(t)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `table.isfrozen(t)`: whether `t` was frozen.
+fn isfrozen<'gc>(
+    _: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let Value::Table(t) = stack.get(0) else {
+        return Err(type_error(stack, 1, "isfrozen", "table").into());
+    };
+    stack.replace(&[Value::Boolean(t.is_frozen())]);
+    Ok(NativeReturn::Return)
+}
+
+/// `table.unpack(t [, i [, j]])`: the values from `t[i]` (1 by default) to `t[j]` (`#t` by
+/// default).
+pub(super) fn unpack<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let seq = Sequence {
+        value: stack.get(0),
+        thread: stack.thread(),
+    };
+    let first = opt_integer(stack, 2, "unpack", 1)?;
+    let last = match stack.get(2) {
+        Value::Nil => seq.len(ctx)?,
+        _ => check_integer(stack, 3, "unpack")?,
+    };
+    stack.clear();
+    if first > last {
+        return Ok(NativeReturn::Return);
+    }
+    if (last as u64).wrapping_sub(first as u64) >= MAX_UNPACK as u64 {
+        return Err(RuntimeError::new("too many results to unpack").into());
+    }
+    if let Some(t) = seq.plain() {
+        let state = t.borrow();
+        let array = state.entries.array();
+        if first >= 1 && last as u64 <= array.len() as u64 {
+            stack.extend(array[first as usize - 1..last as usize].iter().copied());
+            return Ok(NativeReturn::Return);
+        }
+    }
+    for i in first..=last {
+        let value = seq.get(ctx, i)?;
+        stack.push(value);
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `table.move(a1, f, e, t [, a2])`: copies `a1[f..=e]` to `a2[t..]`, `a2` being `a1` by default,
+/// in whichever order keeps overlapping ranges intact. Returns `a2`.
+fn move_<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let first = check_integer(stack, 2, "move")?;
+    let last = check_integer(stack, 3, "move")?;
+    let to = check_integer(stack, 4, "move")?;
+    let dest_arg = if stack.get(4).is_nil() { 1 } else { 5 };
+    let src = Sequence::check(ctx, stack, 1, "move", &["__index"])?;
+    let dest = Sequence::check(ctx, stack, dest_arg, "move", &["__newindex"])?;
+    if last >= first {
+        if !(first > 0 || last < i64::MAX + first) {
+            return Err(arg_error(3, "move", "too many elements to move").into());
+        }
+        let n = last - first + 1;
+        if to > i64::MAX - n + 1 {
+            return Err(arg_error(4, "move", "destination wrap around").into());
+        }
+        if to > last || to <= first || dest_arg != 1 && src.value != dest.value {
+            for i in 0..n {
+                let value = src.get(ctx, first + i)?;
+                dest.set(ctx, to + i, value)?;
+            }
+        } else {
+            for i in (0..n).rev() {
+                let value = src.get(ctx, first + i)?;
+                dest.set(ctx, to + i, value)?;
+            }
+        }
+    }
+    stack.replace(&[dest.value]);
+    Ok(NativeReturn::Return)
+}
+
+/// `table.sort(t [, comp])`: sorts `t[1..=#t]` in place, with `comp(a, b)` saying whether `a` goes
+/// before `b`, or the `<` operator by default.
+///
+/// The sort isn't stable, and a `comp` that isn't a strict order may raise an "invalid order
+/// function for sorting" error.
+fn sort<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let seq = Sequence::check(ctx, stack, 1, "sort", &["__index", "__newindex", "__len"])?;
+    let n = seq.len(ctx)?;
+    if n <= 1 {
+        stack.clear();
+        return Ok(NativeReturn::Return);
+    }
+    if n >= i32::MAX as i64 {
+        return Err(arg_error(1, "sort", "array too big").into());
+    }
+    let comp = match stack.get(1) {
+        Value::Nil => None,
+        f @ Value::Function(_) => Some(f),
+        _ => return Err(type_error(stack, 2, "sort", "function").into()),
+    };
+    stack.clear();
+    let n = n as usize;
+
+    // The values are sorted apart from the table, then put back.
+    let plain_array = |seq: Sequence<'gc>| {
+        seq.plain()
+            .filter(|t| n <= t.borrow().entries.array().len())
+    };
+    let mut values = match plain_array(seq) {
+        Some(t) => t.borrow().entries.array()[..n].to_vec(),
+        None => (1..=n as i64)
+            .map(|i| seq.get(ctx, i))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    let thread = seq.thread;
+    let mut less_than = |a: Value<'gc>, b: Value<'gc>| -> Result<bool, LuaError<'gc>> {
+        if let Some(comp) = comp {
+            let results = vm::call(ctx, thread, comp, &[a, b])?;
+            return Ok(results.first().is_some_and(|v| v.to_bool()));
+        }
+        match ops::compare_meta(ctx, CompareOp::Lt, a, b)? {
+            MetaResult::Value(v) => Ok(v.to_bool()),
+            MetaResult::Call(f, args) => {
+                let results = vm::call(ctx, thread, Value::Function(f), &args)?;
+                Ok(results.first().is_some_and(|v| v.to_bool()))
+            }
+        }
+    };
+    sort_range(&mut values, 1, n, 0, &mut less_than)?;
+    match plain_array(seq) {
+        Some(t) => t.borrow_mut(&ctx).entries.array_mut()[..n].copy_from_slice(&values),
+        None => {
+            for (i, value) in values.into_iter().enumerate() {
+                seq.set(ctx, i as i64 + 1, value)?;
+            }
+        }
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// Sorts positions `lo..=up` of `a`, counting from 1, with the reference implementation's
+/// quicksort, so that a comparison that isn't a strict order is caught the way it is there.
+///
+/// The middle element is the pivot until an interval is found to split badly, after which a
+/// pseudo-random one, picked by `rnd`, is used for long intervals.
+fn sort_range<'gc>(
+    a: &mut [Value<'gc>],
+    mut lo: usize,
+    mut up: usize,
+    mut rnd: usize,
+    less_than: &mut impl FnMut(Value<'gc>, Value<'gc>) -> Result<bool, LuaError<'gc>>,
+) -> Result<(), LuaError<'gc>> {
+    while lo < up {
+        if less_than(a[up - 1], a[lo - 1])? {
+            a.swap(lo - 1, up - 1);
+        }
+        if up - lo == 1 {
+            break;
+        }
+        let mut p = if up - lo < RANDOM_PIVOT_LIMIT || rnd == 0 {
+            (lo + up) / 2
+        } else {
+            let quarter = (up - lo) / 4;
+            rnd % (quarter * 2) + lo + quarter
+        };
+        if less_than(a[p - 1], a[lo - 1])? {
+            a.swap(p - 1, lo - 1);
+        } else if less_than(a[up - 1], a[p - 1])? {
+            a.swap(p - 1, up - 1);
+        }
+        if up - lo == 2 {
+            break;
+        }
+        let pivot = a[p - 1];
+        a.swap(p - 1, up - 2);
+        p = partition(a, lo, up, pivot, less_than)?;
+        // Recurse into the smaller side and loop on the larger one.
+        let smaller;
+        if p - lo < up - p {
+            sort_range(a, lo, p - 1, rnd, less_than)?;
+            smaller = p - lo;
+            lo = p + 1;
+        } else {
+            sort_range(a, p + 1, up, rnd, less_than)?;
+            smaller = up - p;
+            up = p - 1;
+        }
+        if up.wrapping_sub(lo) / 128 > smaller {
+            rnd = RandomState::new().build_hasher().finish() as usize;
+        }
+    }
+    Ok(())
+}
+
+/// Splits `a[lo..=up]` around `pivot`, which is at `up - 1`, returning where the pivot ends up.
+fn partition<'gc>(
+    a: &mut [Value<'gc>],
+    lo: usize,
+    up: usize,
+    pivot: Value<'gc>,
+    less_than: &mut impl FnMut(Value<'gc>, Value<'gc>) -> Result<bool, LuaError<'gc>>,
+) -> Result<usize, LuaError<'gc>> {
+    let invalid = || RuntimeError::new("invalid order function for sorting");
+    let (mut i, mut j) = (lo, up - 1);
+    loop {
+        i += 1;
+        while less_than(a[i - 1], pivot)? {
+            if i == up - 1 {
+                return Err(invalid().into());
+            }
+            i += 1;
+        }
+        j -= 1;
+        while less_than(pivot, a[j - 1])? {
+            if j < i {
+                return Err(invalid().into());
+            }
+            j -= 1;
+        }
+        if j < i {
+            a.swap(up - 2, i - 1);
+            return Ok(i);
+        }
+        a.swap(i - 1, j - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::testing::run;
+    use crate::vm::ops;
+    use crate::{Lua, Value};
+
+    #[test]
+    fn sequences() {
+        assert_eq!(
+            run("local t = {1, 2, 3}
+                table.insert(t, 4)
+                table.insert(t, 1, 0)
+                local removed = table.remove(t, 2)
+                return table.concat(t, ','), removed, table.remove(t), #t"),
+            "0,2,3,4, 1, 4, 3"
+        );
+        assert_eq!(
+            run("local t = table.pack(1, nil, 3)
+                return t.n, select('#', table.unpack(t, 1, t.n))"),
+            "3, 3"
+        );
+        assert_eq!(
+            run(
+                "local t = table.move({1, 2, 3, 4, 5}, 1, 3, 3)
+                return table.concat(t, ' '), table.concat(table.move({1, 2}, 1, 2, 2, {}), ' ', 2, 3)"
+            ),
+            "1 2 1 2 3, 1 2"
+        );
+        assert_eq!(
+            run("return table.concat({1, 2.5, 'x'}, ', ', 2, 3)"),
+            "2.5, x"
+        );
+        assert_eq!(run("return table.remove({}), #table.pack()"), "nil, 0");
+        assert_eq!(
+            run("return table.concat({1, {}, 3})"),
+            "error: invalid value (at index 2) in table for 'concat'"
+        );
+        assert_eq!(
+            run("table.insert({1}, 5, 2)"),
+            "error: bad argument #2 to 'insert' (position out of bounds)"
+        );
+        assert_eq!(
+            run("table.insert({}, 1, 2, 3)"),
+            "error: wrong number of arguments to 'insert'"
+        );
+        assert_eq!(
+            run("table.insert(nil, 1)"),
+            "error: bad argument #1 to 'insert' (table expected, got nil)"
+        );
+    }
+
+    #[test]
+    fn metamethods() {
+        Lua::new().enter(|ctx| {
+            // A proxy whose metamethods forward to another table stands in for it.
+            ctx.eval(
+                "inner, proxy = {}, {}
+                mt = {__index = inner, __newindex = inner, __len = function() return #inner end}",
+            )
+            .unwrap();
+            let (Value::Table(proxy), Value::Table(mt)) =
+                (ctx.globals().get_str("proxy"), ctx.globals().get_str("mt"))
+            else {
+                unreachable!();
+            };
+            ops::set_metatable(ctx, proxy, Some(mt));
+            let results = ctx
+                .eval(
+                    "table.insert(proxy, 'a')
+                    table.insert(proxy, 1, 'b')
+                    table.sort(proxy)
+                    return table.concat(proxy, ','), rawlen(proxy), #inner, table.unpack(proxy)",
+                )
+                .unwrap();
+            let results = results.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+            assert_eq!(results, ["a,b", "0", "2", "a", "b"]);
+        });
+    }
+
+    #[test]
+    fn frozen_tables() {
+        let frozen = "local t = table.freeze({3, 1, 2, x = 1}) ";
+        assert_eq!(
+            run(&format!(
+                "{frozen} return table.isfrozen(t), table.isfrozen({{}}), t.x, #t"
+            )),
+            "true, false, 1, 3"
+        );
+        for (change, message) in [
+            ("t.y = 1", "attempt to modify a frozen table"),
+            ("t[1] = nil", "attempt to modify a frozen table"),
+            ("rawset(t, 'x', 2)", "attempt to modify a frozen table"),
+            ("table.insert(t, 4)", "attempt to modify a frozen table"),
+            ("table.sort(t)", "attempt to modify a frozen table"),
+            (
+                "setmetatable(t, {})",
+                "cannot change the metatable of a frozen table",
+            ),
+        ] {
+            let output = run(&format!("{frozen}{change}"));
+            assert!(output.ends_with(message), "{change}: {output}");
+        }
+        assert_eq!(
+            run("table.freeze(setmetatable({}, {__metatable = false}))"),
+            "error: bad argument #1 to 'freeze' (table has a protected metatable)"
+        );
+    }
+
+    #[test]
+    fn sorting() {
+        assert_eq!(
+            run("local t = {5, 2, 8, 1, 9, 3}
+                table.sort(t)
+                local u = {'b', 'c', 'a'}
+                table.sort(u, function(a, b) return a > b end)
+                return table.concat(t, ' '), table.concat(u)"),
+            "1 2 3 5 8 9, cba"
+        );
+        assert_eq!(
+            run("local t = {}
+                for i = 1, 1000 do t[i] = (i * 7919) % 1000 end
+                table.sort(t)
+                for i = 2, #t do if t[i - 1] > t[i] then return false end end
+                return true"),
+            "true"
+        );
+        assert_eq!(
+            run("local t = {}
+                for i = 1, 200 do t[i] = i % 3 end
+                table.sort(t, function(a, b) return true end)"),
+            "error: invalid order function for sorting"
+        );
+        assert_eq!(
+            run("table.sort({1, 'x', 2})"),
+            "error: attempt to compare string with number"
+        );
+        assert_eq!(
+            run("table.sort({3, 2, 1}, 1)"),
+            "error: bad argument #2 to 'sort' (function expected, got number)"
+        );
+    }
+}
@@ -0,0 +1,352 @@
+//! The UTF-8 library, set as the `utf8` global.
+//!
+//! Strings are decoded as the reference implementation does: by default only code points up to
+//! U+10FFFF that aren't surrogates are valid, while the lax variants, asked for with a true last
+//! argument, accept the original encoding of up to 31 bits, surrogates included. Overlong
+//! encodings are always invalid.
+
+use crate::compiler::lexer::encode_utf8;
+use crate::vm::{ops, Stack};
+use crate::{
+    Context, Function, LuaError, LuaString, NativeFn, NativeReturn, RuntimeError, Table, Value,
+};
+
+use super::{arg_error, check_integer, check_string, opt_integer, set_function, set_library};
+
+/// The largest code point strict decoding accepts.
+const MAX_UNICODE: u64 = 0x10ffff;
+/// The largest code point the original UTF-8 encoding can hold.
+const MAX_UTF: u64 = 0x7fff_ffff;
+/// A pattern matching exactly one encoded code point, if the subject is valid.
+const CHAR_PATTERN: &[u8] = b"[\0-\x7f\xc2-\xfd][\x80-\xbf]*";
+
+/// Opens the UTF-8 library.
+pub fn load_utf8(ctx: Context<'_>) {
+    let utf8 = Table::new(&ctx);
+    set_function(ctx, utf8, "char", char);
+    set_function(ctx, utf8, "codepoint", codepoint);
+    set_function(ctx, utf8, "codes", codes);
+    set_function(ctx, utf8, "len", len);
+    set_function(ctx, utf8, "offset", offset);
+    utf8.set(
+        &ctx,
+        LuaString::new(&ctx, b"charpattern"),
+        LuaString::new(&ctx, CHAR_PATTERN),
+    )
+    .expect("string keys are always valid");
+    set_library(ctx, "utf8", utf8);
+}
+
+/// Decodes the code point `s` starts with, returning it and the length of its encoding, or `None`
+/// if it isn't validly encoded. Bytes past the end of `s` are read as zeros.
+fn decode(s: &[u8], strict: bool) -> Option<(u64, usize)> {
+    /// The smallest code point that needs each number of continuation bytes.
+    const LIMITS: [u64; 6] = [u64::MAX, 0x80, 0x800, 0x10000, 0x200000, 0x4000000];
+
+    let byte = |i: usize| s.get(i).copied().unwrap_or(0) as u64;
+    let mut c = byte(0);
+    let (code, len) = if c < 0x80 {
+        (c, 1)
+    } else {
+        // Each bit set after the leading one in the first byte stands for a continuation byte.
+        let mut count = 0;
+        let mut code = 0;
+        while c & 0x40 != 0 {
+            count += 1;
+            let cc = byte(count);
+            if cc & 0xc0 != 0x80 {
+                return None;
+            }
+            code = code << 6 | (cc & 0x3f);
+            c <<= 1;
+        }
+        code |= (c & 0x7f) << (count * 5);
+        if count > 5 || code > MAX_UTF || code < LIMITS[count] {
+            return None;
+        }
+        (code, count + 1)
+    };
+    if strict && (code > MAX_UNICODE || (0xd800..=0xdfff).contains(&code)) {
+        return None;
+    }
+    Some((code, len))
+}
+
+fn is_continuation(s: &[u8], pos: usize) -> bool {
+    s.get(pos).is_some_and(|b| b & 0xc0 == 0x80)
+}
+
+/// Turns a position counting back from the end when negative into one counting from 1, or 0 if it
+/// is before the start.
+fn relative_position(pos: i64, len: usize) -> i64 {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        len as i64 + pos + 1
+    }
+}
+
+/// `utf8.char(...)`: a string of the code points given as integers.
+fn char<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let mut bytes = Vec::with_capacity(stack.len());
+    for n in 1..=stack.len() {
+        let code = check_integer(stack, n, "char")?;
+        if code as u64 > MAX_UTF {
+            return Err(arg_error(n, "char", "value out of range").into());
+        }
+        encode_utf8(code as u32, &mut bytes);
+    }
+    stack.replace(&[Value::String(LuaString::from_vec(&ctx, bytes))]);
+    Ok(NativeReturn::Return)
+}
+
+/// `utf8.codepoint(s [, i [, j [, lax]]])`: the code points of the characters starting between
+/// bytes `i` (1 by default) and `j` (`i` by default) of `s`.
+fn codepoint<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "codepoint")?;
+    let start = relative_position(opt_integer(stack, 2, "codepoint", 1)?, s.len());
+    let end = relative_position(opt_integer(stack, 3, "codepoint", start)?, s.len());
+    let strict = !stack.get(3).to_bool();
+    if start < 1 {
+        return Err(arg_error(2, "codepoint", "out of bounds").into());
+    }
+    if end > s.len() as i64 {
+        return Err(arg_error(3, "codepoint", "out of bounds").into());
+    }
+    stack.clear();
+    if start > end {
+        return Ok(NativeReturn::Return);
+    }
+    if end - start >= i32::MAX as i64 {
+        return Err(RuntimeError::new("string slice too long").into());
+    }
+    let bytes = s.as_bytes();
+    let mut pos = start as usize - 1;
+    while pos < end as usize {
+        let (code, len) =
+            decode(&bytes[pos..], strict).ok_or_else(|| RuntimeError::new("invalid UTF-8 code"))?;
+        stack.push(Value::Integer(code as i64));
+        pos += len;
+    }
+    Ok(NativeReturn::Return)
+}
+
+/// `utf8.len(s [, i [, j [, lax]]])`: the number of characters starting between bytes `i` (1 by
+/// default) and `j` (-1 by default) of `s`, or fail and the position of the first invalid byte.
+fn len<'gc>(ctx: Context<'gc>, stack: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "len")?;
+    let start = relative_position(opt_integer(stack, 2, "len", 1)?, s.len());
+    let end = relative_position(opt_integer(stack, 3, "len", -1)?, s.len());
+    let strict = !stack.get(3).to_bool();
+    if start < 1 || start - 1 > s.len() as i64 {
+        return Err(arg_error(2, "len", "initial position out of bounds").into());
+    }
+    if end > s.len() as i64 {
+        return Err(arg_error(3, "len", "final position out of bounds").into());
+    }
+    let bytes = s.as_bytes();
+    let mut pos = start - 1;
+    let mut count = 0;
+    while pos < end {
+        let Some((_, len)) = decode(&bytes[pos as usize..], strict) else {
+            stack.replace(&[Value::Nil, Value::Integer(pos + 1)]);
+            return Ok(NativeReturn::Return);
+        };
+        pos += len as i64;
+        count += 1;
+    }
+    stack.replace(&[Value::Integer(count)]);
+    Ok(NativeReturn::Return)
+}
+
+/// `utf8.offset(s, n [, i])`: the position of the `n`th character of `s` counting from the one at
+/// byte `i`, backwards if `n` is negative, or fail if there is none. `i` defaults to 1, or to just
+/// past the end for a negative `n`, and with an `n` of 0 the start of the character holding byte
+/// `i` is found.
+fn offset<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "offset")?;
+    let mut n = check_integer(stack, 2, "offset")?;
+    let default = if n >= 0 { 1 } else { s.len() as i64 + 1 };
+    let pos = relative_position(opt_integer(stack, 3, "offset", default)?, s.len());
+    if pos < 1 || pos - 1 > s.len() as i64 {
+        return Err(arg_error(3, "offset", "position out of bounds").into());
+    }
+    let bytes = s.as_bytes();
+    let mut pos = pos as usize - 1;
+    if n == 0 {
+        while pos > 0 && is_continuation(bytes, pos) {
+            pos -= 1;
+        }
+    } else if is_continuation(bytes, pos) {
+        return Err(RuntimeError::new("initial position is a continuation byte").into());
+    } else if n < 0 {
+        while n < 0 && pos > 0 {
+            pos -= 1;
+            while pos > 0 && is_continuation(bytes, pos) {
+                pos -= 1;
+            }
+            n += 1;
+        }
+    } else {
+        // The first character is the one at `pos` itself.
+        n -= 1;
+        while n > 0 && pos < bytes.len() {
+            pos += 1;
+            while is_continuation(bytes, pos) {
+                pos += 1;
+            }
+            n -= 1;
+        }
+    }
+    let result = if n == 0 {
+        Value::Integer(pos as i64 + 1)
+    } else {
+        Value::Nil
+    };
+    stack.replace(&[result]);
+    Ok(NativeReturn::Return)
+}
+
+/// `utf8.codes(s [, lax])`: an iterator over the positions and code points of the characters of
+/// `s`, for a generic `for`.
+fn codes<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "codes")?;
+    if is_continuation(s.as_bytes(), 0) {
+        return Err(arg_error(1, "codes", "invalid UTF-8 code").into());
+    }
+    let step: NativeFn = if stack.get(1).to_bool() {
+        codes_step_lax
+    } else {
+        codes_step_strict
+    };
+    stack.replace(&[
+        Value::Function(Function::Native(step)),
+        Value::String(s),
+        Value::Integer(0),
+    ]);
+    Ok(NativeReturn::Return)
+}
+
+fn codes_step_strict<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    codes_step(ctx, stack, true)
+}
+
+fn codes_step_lax<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    codes_step(ctx, stack, false)
+}
+
+/// One step of a `utf8.codes` iterator, given the string and the position of the last character,
+/// counting from 1, or 0 to start.
+fn codes_step<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    strict: bool,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let s = check_string(ctx, stack, 1, "for iterator")?;
+    let bytes = s.as_bytes();
+    let last = ops::coerce_number(stack.get(1)).and_then(|v| v.to_integer());
+    // A negative position wraps around past the end, and so ends the loop.
+    let mut pos = last.unwrap_or(0) as u64 as usize;
+    while pos < bytes.len() && is_continuation(bytes, pos) {
+        pos += 1;
+    }
+    stack.clear();
+    if pos >= bytes.len() {
+        return Ok(NativeReturn::Return);
+    }
+    match decode(&bytes[pos..], strict) {
+        Some((code, len)) if !is_continuation(bytes, pos + len) => {
+            stack.extend([Value::Integer(pos as i64 + 1), Value::Integer(code as i64)]);
+            Ok(NativeReturn::Return)
+        }
+        _ => Err(RuntimeError::new("invalid UTF-8 code").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::testing::run;
+
+    #[test]
+    fn decoding() {
+        assert_eq!(
+            run("return utf8.char(72, 0x20ac, 0x10348) == 'H\\u{20AC}\\u{10348}'"),
+            "true"
+        );
+        assert_eq!(
+            run("return utf8.codepoint('h\\u{e9}!', 1, -1)"),
+            "104, 233, 33"
+        );
+        assert_eq!(
+            run("return utf8.len('h\\u{e9}!'), utf8.len('\\u{e9}', 2)"),
+            "3, nil, 2"
+        );
+        assert_eq!(
+            run("local s = 'a\\u{e9}\\u{20ac}'
+                return utf8.offset(s, 3), utf8.offset(s, -1), utf8.offset(s, 0, 3), utf8.offset(s, 5)"),
+            "4, 4, 2, nil"
+        );
+        assert_eq!(
+            run("local t = {}
+                for p, c in utf8.codes('a\\u{e9}b') do t[#t + 1] = p .. ':' .. c end
+                return t[1] .. ' ' .. t[2] .. ' ' .. t[3]"),
+            "1:97 2:233 4:98"
+        );
+        assert_eq!(
+            run("return #string.match('\\u{20ac}x', utf8.charpattern)"),
+            "3"
+        );
+    }
+
+    #[test]
+    fn strictness() {
+        // Surrogates and code points past U+10FFFF only pass in lax mode.
+        assert_eq!(
+            run("return utf8.len('\\u{d800}'), utf8.len('\\u{d800}', 1, -1, true)"),
+            "nil, 1"
+        );
+        assert_eq!(
+            run("return utf8.codepoint('\\u{7fffffff}', 1, 1, true)"),
+            "2147483647"
+        );
+        assert_eq!(
+            run("return utf8.codepoint('\\u{110000}')"),
+            "error: invalid UTF-8 code"
+        );
+        // Overlong encodings never do.
+        assert_eq!(run("return utf8.len('\\xc0\\x80', 1, -1, true)"), "nil, 1");
+        assert_eq!(
+            run("for _ in utf8.codes('a\\xff') do end"),
+            "error: invalid UTF-8 code"
+        );
+        assert_eq!(
+            run("return utf8.char(-1)"),
+            "error: bad argument #1 to 'char' (value out of range)"
+        );
+        assert_eq!(
+            run("return utf8.len('abc', 5)"),
+            "error: bad argument #2 to 'len' (initial position out of bounds)"
+        );
+        assert_eq!(
+            run("return utf8.offset('\\u{e9}', 1, 2)"),
+            "error: initial position is a continuation byte"
+        );
+    }
+}
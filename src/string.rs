@@ -0,0 +1,607 @@
+use std::cell::Cell;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::str::Utf8Error;
+
+use crate::mem::{Gc, Managed, Mutation, RefLock, Tracer, WeakSet};
+use crate::table::hash_bytes;
+use crate::vm::ops;
+use crate::Value;
+
+/// An immutable, binary-safe Lua string.
+///
+/// Strings are allocated, and carry their hash with them. With the `inline-strings` feature,
+/// strings of up to [`INLINE_LEN`](Self::INLINE_LEN) bytes are kept in the `LuaString` itself
+/// instead, in the word that would otherwise point at an allocation, so that characters and short
+/// keys cost no allocation at all.
+#[derive(Copy, Clone)]
+pub struct LuaString<'gc>(Repr<'gc>);
+
+const WORD: usize = std::mem::size_of::<usize>();
+
+/// Either a pointer to the allocation, or the bytes of an inline string. Allocations are aligned,
+/// so the lowest bit of a pointer is clear. An inline string sets it in the byte that is lowest in
+/// the word, which holds its length above that bit, and its bytes fill the rest of the word.
+#[derive(Copy, Clone)]
+union Repr<'gc> {
+    heap: Gc<'gc, StringData>,
+    inline: [u8; WORD],
+}
+
+/// The index of the byte holding an inline string's length, and of the first of its bytes.
+const LEN_AT: usize = if cfg!(target_endian = "little") {
+    0
+} else {
+    WORD - 1
+};
+#[cfg(feature = "inline-strings")]
+const BYTES_AT: usize = if cfg!(target_endian = "little") { 1 } else { 0 };
+
+/// The allocation behind a [`LuaString`]: its bytes, and their hash once something asked for it.
+pub(crate) struct StringData {
+    /// 0 until the hash is first needed. A string whose hash is 0 is hashed again each time.
+    hash: Cell<u64>,
+    /// A boxed slice of the string's own, or the start of `buffer`.
+    bytes: NonNull<[u8]>,
+    buffer: Option<Rc<Buffer>>,
+}
+
+impl StringData {
+    fn new(hash: u64, bytes: Box<[u8]>) -> StringData {
+        StringData {
+            hash: Cell::new(hash),
+            bytes: NonNull::from(Box::leak(bytes)),
+            buffer: None,
+        }
+    }
+
+    #[inline]
+    fn bytes(&self) -> &[u8] {
+        // Nothing writes to the bytes of a string once it is made.
+        unsafe { self.bytes.as_ref() }
+    }
+}
+
+impl Drop for StringData {
+    fn drop(&mut self) {
+        if self.buffer.is_none() {
+            drop(unsafe { Box::from_raw(self.bytes.as_ptr()) });
+        }
+    }
+}
+
+unsafe impl Managed for StringData {
+    #[inline]
+    fn needs_trace() -> bool {
+        false
+    }
+
+    /// The string's own length, even when it shares a buffer: at most half the buffer is spare.
+    #[inline]
+    fn heap_size(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+/// The shortest string that appending to makes a shared [`Buffer`] for. Shorter ones are copied.
+const SHARED_MIN: usize = 64;
+
+/// The bytes of strings made by appending to one another, as `s = s .. x` does in a loop. Each
+/// string in it is a prefix of it. Appending to the longest one writes after it, in place, and a
+/// full buffer is copied into one twice the size, so building a string a piece at a time takes time
+/// in proportion to its length rather than to its square.
+struct Buffer {
+    bytes: NonNull<[u8]>,
+    /// The length of the longest string in the buffer. The bytes after it are free.
+    used: Cell<usize>,
+}
+
+impl Buffer {
+    fn new(prefix: &[u8], suffix: &[u8], capacity: usize) -> Buffer {
+        let mut bytes = Vec::with_capacity(capacity);
+        bytes.extend_from_slice(prefix);
+        bytes.extend_from_slice(suffix);
+        let used = Cell::new(bytes.len());
+        bytes.resize(capacity, 0);
+        Buffer {
+            bytes: NonNull::from(Box::leak(bytes.into_boxed_slice())),
+            used,
+        }
+    }
+
+    /// Writes `suffix` after the string of length `len`, if that is the longest one in the buffer
+    /// and there is room for it.
+    fn append(&self, len: usize, suffix: &[u8]) -> bool {
+        if self.used.get() != len || self.bytes.len() - len < suffix.len() {
+            return false;
+        }
+        // The bytes written are past the end of every string in the buffer, which are all that is
+        // read from it.
+        unsafe {
+            let end = self.bytes.as_ptr().cast::<u8>().add(len);
+            std::ptr::copy_nonoverlapping(suffix.as_ptr(), end, suffix.len());
+        }
+        self.used.set(len + suffix.len());
+        true
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.bytes.as_ptr()) });
+    }
+}
+
+impl<'gc> LuaString<'gc> {
+    /// The longest string kept inline: all of the word but the byte holding the length.
+    #[cfg(feature = "inline-strings")]
+    pub const INLINE_LEN: usize = WORD - 1;
+
+    pub fn new(mc: &Mutation<'gc>, bytes: &[u8]) -> LuaString<'gc> {
+        match LuaString::inline(bytes) {
+            Some(s) => s,
+            None => LuaString::from_boxed(mc, bytes.into()),
+        }
+    }
+
+    pub fn from_vec(mc: &Mutation<'gc>, bytes: Vec<u8>) -> LuaString<'gc> {
+        match LuaString::inline(&bytes) {
+            Some(s) => s,
+            None => LuaString::from_boxed(mc, bytes.into_boxed_slice()),
+        }
+    }
+
+    /// `bytes` as an inline string, if they are short enough to be one.
+    #[cfg(feature = "inline-strings")]
+    #[inline]
+    fn inline(bytes: &[u8]) -> Option<LuaString<'gc>> {
+        if bytes.len() > LuaString::INLINE_LEN {
+            return None;
+        }
+        let mut inline = [0; WORD];
+        inline[LEN_AT] = (bytes.len() as u8) << 1 | 1;
+        inline[BYTES_AT..BYTES_AT + bytes.len()].copy_from_slice(bytes);
+        Some(LuaString(Repr { inline }))
+    }
+
+    #[cfg(not(feature = "inline-strings"))]
+    #[inline]
+    fn inline(_: &[u8]) -> Option<LuaString<'gc>> {
+        None
+    }
+
+    /// `bytes` as the allocated string in `strings` with them, or a new one added there.
+    pub(crate) fn intern(
+        mc: &Mutation<'gc>,
+        strings: Gc<'gc, RefLock<WeakSet<'gc, StringData>>>,
+        bytes: &[u8],
+    ) -> LuaString<'gc> {
+        if let Some(s) = LuaString::inline(bytes) {
+            return s;
+        }
+        let hash = hash_bytes(bytes);
+        let found = strings.borrow().get(hash, |data| data.bytes() == bytes);
+        let heap = found.unwrap_or_else(|| {
+            let data = Gc::new(mc, StringData::new(hash, bytes.into()));
+            strings.borrow_mut(mc).insert(mc, hash, data);
+            data
+        });
+        LuaString(Repr { heap })
+    }
+
+    fn from_boxed(mc: &Mutation<'gc>, bytes: Box<[u8]>) -> LuaString<'gc> {
+        LuaString(Repr {
+            heap: Gc::new(mc, StringData::new(0, bytes)),
+        })
+    }
+
+    /// The string followed by `suffix`. Appending to a long string keeps the result in a buffer
+    /// with room to append to it in place.
+    pub(crate) fn append(self, mc: &Mutation<'gc>, suffix: &[u8]) -> LuaString<'gc> {
+        let Some(data) = self.heap().filter(|data| data.bytes.len() >= SHARED_MIN) else {
+            let mut bytes = Vec::with_capacity(self.len() + suffix.len());
+            bytes.extend_from_slice(self.as_bytes());
+            bytes.extend_from_slice(suffix);
+            return LuaString::from_vec(mc, bytes);
+        };
+        let data = Gc::as_ref(data);
+        let len = data.bytes.len() + suffix.len();
+        let buffer = match &data.buffer {
+            Some(buffer) if buffer.append(data.bytes.len(), suffix) => buffer.clone(),
+            _ => Rc::new(Buffer::new(data.bytes(), suffix, 2 * len)),
+        };
+        let data = StringData {
+            hash: Cell::new(0),
+            bytes: NonNull::slice_from_raw_parts(buffer.bytes.cast::<u8>(), len),
+            buffer: Some(buffer),
+        };
+        LuaString(Repr {
+            heap: Gc::new(mc, data),
+        })
+    }
+
+    /// The whole word, which tells apart any two strings that aren't both allocated.
+    #[inline]
+    fn word(self) -> [u8; WORD] {
+        // Both fields are a word of initialized bytes.
+        unsafe { self.0.inline }
+    }
+
+    /// The allocation, unless the string is inline.
+    #[inline]
+    fn heap(self) -> Option<Gc<'gc, StringData>> {
+        if cfg!(feature = "inline-strings") && self.word()[LEN_AT] & 1 == 1 {
+            None
+        } else {
+            // The bit is clear only in pointers.
+            Some(unsafe { self.0.heap })
+        }
+    }
+
+    /// Whether the string is kept inline rather than allocated.
+    #[inline]
+    pub fn is_inline(self) -> bool {
+        self.heap().is_none()
+    }
+
+    /// The string's bytes. Those of an inline string are in the `LuaString` itself, so they are
+    /// only borrowed for as long as it is.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        match self.heap() {
+            Some(data) => Gc::as_ref(data).bytes(),
+            None => self.inline_bytes(),
+        }
+    }
+
+    /// The bytes of an inline string.
+    #[cfg(feature = "inline-strings")]
+    #[inline]
+    fn inline_bytes(&self) -> &[u8] {
+        // Both fields are a word of initialized bytes.
+        let word = unsafe { &self.0.inline };
+        let len = (word[LEN_AT] >> 1) as usize;
+        &word[BYTES_AT..BYTES_AT + len]
+    }
+
+    #[cfg(not(feature = "inline-strings"))]
+    #[inline]
+    fn inline_bytes(&self) -> &[u8] {
+        unreachable!("inline string without the inline-strings feature")
+    }
+
+    /// The hash tables place the string by, the same as [`hash_bytes`] of its bytes. For allocated
+    /// strings it is worked out the first time and kept with the string after that.
+    #[inline]
+    pub(crate) fn hash_code(self) -> u64 {
+        let Some(data) = self.heap() else {
+            return hash_bytes(self.as_bytes());
+        };
+        let data = Gc::as_ref(data);
+        match data.hash.get() {
+            0 => {
+                let hash = hash_bytes(data.bytes());
+                data.hash.set(hash);
+                hash
+            }
+            hash => hash,
+        }
+    }
+
+    #[inline]
+    pub fn len(self) -> usize {
+        self.as_bytes().len()
+    }
+
+    #[inline]
+    pub fn is_empty(self) -> bool {
+        self.as_bytes().is_empty()
+    }
+
+    pub fn to_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(self.as_bytes())
+    }
+
+    /// Converts to a Rust string, replacing invalid UTF-8 sequences.
+    pub fn to_string_lossy(self) -> String {
+        String::from_utf8_lossy(self.as_bytes()).into_owned()
+    }
+
+    /// Whether both are the same allocation, or inline strings with the same bytes.
+    #[inline]
+    pub fn ptr_eq(self, other: LuaString<'gc>) -> bool {
+        self.word() == other.word()
+    }
+}
+
+impl<'gc> PartialEq for LuaString<'gc> {
+    #[inline]
+    fn eq(&self, other: &LuaString<'gc>) -> bool {
+        if self.ptr_eq(*other) {
+            return true;
+        }
+        // Short strings are always inline, so an inline string only equals itself.
+        let (Some(x), Some(y)) = (self.heap(), other.heap()) else {
+            return false;
+        };
+        let (a, b) = (x.bytes(), y.bytes());
+        if a.len() != b.len() {
+            return false;
+        }
+        // A word settles the shorter ones. Longer strings with different hashes, if both are
+        // known, differ without reading their bytes.
+        if a.len() <= 8 {
+            return first_word(a) == first_word(b);
+        }
+        let (x, y) = (x.hash.get(), y.hash.get());
+        if x != 0 && y != 0 && x != y {
+            return false;
+        }
+        first_word(a) == first_word(b) && a == b
+    }
+}
+
+impl<'gc> Eq for LuaString<'gc> {}
+
+impl<'gc> PartialEq<[u8]> for LuaString<'gc> {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.as_bytes() == other
+    }
+}
+
+impl<'gc> PartialEq<str> for LuaString<'gc> {
+    fn eq(&self, other: &str) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl<'gc> PartialOrd for LuaString<'gc> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'gc> Ord for LuaString<'gc> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl<'gc> Hash for LuaString<'gc> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash_code())
+    }
+}
+
+impl<'gc> fmt::Debug for LuaString<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&String::from_utf8_lossy(self.as_bytes()), f)
+    }
+}
+
+impl<'gc> fmt::Display for LuaString<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
+unsafe impl<'gc> Managed for LuaString<'gc> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(data) = self.heap() {
+            data.trace(tracer)
+        }
+    }
+}
+
+/// Builds a [`LuaString`] from pieces in one buffer, so that joining many of them takes time in
+/// proportion to the length of the result. The string is only made, in one allocation, once it is
+/// [finished](Self::finish).
+#[derive(Debug, Clone, Default)]
+pub struct LuaStringBuilder {
+    bytes: Vec<u8>,
+}
+
+impl LuaStringBuilder {
+    pub fn new() -> LuaStringBuilder {
+        LuaStringBuilder::default()
+    }
+
+    /// A builder with room for `capacity` bytes before it grows.
+    pub fn with_capacity(capacity: usize) -> LuaStringBuilder {
+        LuaStringBuilder {
+            bytes: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Appends a string or number the way `..` writes it. Returns false, appending nothing, for
+    /// any other value.
+    pub fn push_value(&mut self, value: Value<'_>) -> bool {
+        ops::write_concat_operand(&mut self.bytes, value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The string built.
+    pub fn finish<'gc>(self, mc: &Mutation<'gc>) -> LuaString<'gc> {
+        LuaString::from_vec(mc, self.bytes)
+    }
+}
+
+impl fmt::Write for LuaStringBuilder {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Up to the first 8 bytes of `bytes` as a word, padded with zeros.
+#[inline]
+fn first_word(bytes: &[u8]) -> u64 {
+    let mut word = [0; 8];
+    let len = bytes.len().min(8);
+    word[..len].copy_from_slice(&bytes[..len]);
+    u64::from_le_bytes(word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lua;
+
+    #[test]
+    fn equality_and_hashes() {
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let s = |bytes: &[u8]| LuaString::new(&ctx, bytes);
+            assert_eq!(s(b"key"), s(b"key"));
+            assert_ne!(s(b"key"), s(b"kez"));
+            assert_ne!(s(b"key\0"), s(b"key"));
+
+            let (long, same, other) = (s(b"a longer key"), s(b"a longer key"), s(b"a longer kez"));
+            assert!(long == same && long != other);
+            // Once hashed, strings compare the same way.
+            assert_eq!(long.hash_code(), hash_bytes(b"a longer key"));
+            assert_eq!(long.hash_code(), same.hash_code());
+            other.hash_code();
+            assert!(long == same && long != other);
+        });
+    }
+
+    #[test]
+    fn builds_strings() {
+        use std::fmt::Write;
+
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let mut builder = LuaStringBuilder::new();
+            builder.push(b"n = ");
+            assert!(builder.push_value(Value::Integer(-12)));
+            assert!(builder.push_value(Value::Number(0.5)));
+            assert!(!builder.push_value(Value::Boolean(true)));
+            let name = 'x';
+            write!(builder, ", {name}").unwrap();
+            assert_eq!(builder.len(), 13);
+            assert_eq!(builder.finish(&ctx), LuaString::new(&ctx, b"n = -120.5, x"));
+            assert!(LuaStringBuilder::new().finish(&ctx).is_empty());
+        });
+    }
+
+    #[test]
+    fn appends_in_place() {
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let start = LuaString::new(&ctx, &[b'a'; SHARED_MIN]);
+            let mut s = start;
+            let mut prefixes = Vec::new();
+            for i in 0..1000 {
+                s = s.append(&ctx, &[b'0' + i as u8 % 10]);
+                prefixes.push(s);
+            }
+            // The strings share buffers, each twice the length of the first string in it.
+            let buffers = prefixes
+                .windows(2)
+                .filter(|w| w[0].as_bytes().as_ptr() != w[1].as_bytes().as_ptr())
+                .count();
+            assert_eq!(buffers, 4);
+            for (i, prefix) in prefixes.iter().enumerate() {
+                assert_eq!(prefix.len(), SHARED_MIN + i + 1);
+                assert_eq!(prefix.as_bytes()[SHARED_MIN + i], b'0' + i as u8 % 10);
+            }
+
+            // Appending to a string that was already appended to leaves the first result alone.
+            let (x, y) = (
+                prefixes[10].append(&ctx, b"x"),
+                prefixes[10].append(&ctx, b"y"),
+            );
+            assert_eq!(x.as_bytes()[..x.len() - 1], y.as_bytes()[..y.len() - 1]);
+            assert_eq!(
+                (x.as_bytes().last(), y.as_bytes().last()),
+                (Some(&b'x'), Some(&b'y'))
+            );
+            assert_eq!(prefixes[11].as_bytes().last(), Some(&b'1'));
+            assert_eq!(
+                start.append(&ctx, b"0123"),
+                LuaString::new(&ctx, &s.as_bytes()[..SHARED_MIN + 4])
+            );
+
+            let built = ctx.eval("local s = '' for i = 1, 2000 do s = s .. i .. ',' end return s");
+            let Value::String(built) = built.unwrap()[0] else {
+                unreachable!();
+            };
+            let expected: Vec<_> = (1..=2000).map(|i| i.to_string()).collect();
+            assert_eq!(built, *format!("{},", expected.join(",")).as_str());
+        });
+    }
+
+    #[test]
+    fn interns_strings() {
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let a = ctx.intern(b"an interned string");
+            assert!(a.ptr_eq(ctx.intern(b"an interned string")));
+            assert!(!a.ptr_eq(LuaString::new(&ctx, b"an interned string")));
+            #[cfg(feature = "inline-strings")]
+            assert!(ctx.intern(b"abc").is_inline());
+        });
+        lua.collect_all();
+        lua.enter(|ctx| assert!(ctx.state().interned.borrow().is_empty()));
+    }
+
+    #[cfg(feature = "inline-strings")]
+    #[test]
+    fn short_strings_are_inline() {
+        assert_eq!(
+            std::mem::size_of::<LuaString>(),
+            std::mem::size_of::<usize>()
+        );
+        assert_eq!(std::mem::size_of::<crate::Value>(), 16);
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let before = ctx.metrics().total_allocation();
+            let short: Vec<_> = (0..=LuaString::INLINE_LEN)
+                .map(|len| LuaString::new(&ctx, &b"abcdefghij"[..len]))
+                .collect();
+            assert_eq!(ctx.metrics().total_allocation(), before);
+            for (len, s) in short.iter().enumerate() {
+                assert!(s.is_inline());
+                assert_eq!(s.as_bytes(), &b"abcdefghij"[..len]);
+                assert_eq!(s.hash_code(), hash_bytes(s.as_bytes()));
+                assert_eq!(*s, LuaString::from_vec(&ctx, s.as_bytes().to_vec()));
+            }
+            assert_ne!(short[1], short[2]);
+            let bytes = LuaString::new(&ctx, b"\xff\0\x01");
+            assert_eq!(bytes.as_bytes(), b"\xff\0\x01");
+            assert!(LuaString::new(&ctx, b"\0\0") != LuaString::new(&ctx, b"\0"));
+
+            let long = LuaString::new(&ctx, &b"abcdefghij"[..LuaString::INLINE_LEN + 1]);
+            assert!(!long.is_inline());
+            assert!(ctx.metrics().total_allocation() > before);
+
+            let t = crate::Table::new(&ctx);
+            t.set(&ctx, short[3], 1).unwrap();
+            assert_eq!(
+                t.get(LuaString::new(&ctx, b"abc")),
+                crate::Value::Integer(1)
+            );
+            let found = ctx.eval("local t = {} for i = 1, 100 do t['k' .. i] = i end return t.k42");
+            assert_eq!(found.unwrap()[0], crate::Value::Integer(42));
+        });
+    }
+}
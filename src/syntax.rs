@@ -0,0 +1,14 @@
+//! Lua source as tei reads it, for formatters, linters and other tools that would otherwise need
+//! a Lua parser of their own: the [syntax tree](Block), the parsers that build it, and
+//! [visitors](Visit) over it.
+//!
+//! These are the same types the compiler works from, gathered here as a supported API. What else
+//! is in [`compiler`](crate::compiler) changes along with the compiler.
+
+pub use crate::compiler::ast::{
+    Attrib, BinOp, Block, Expr, FuncName, FunctionBody, LocalName, Name, Return, Stat, TableField,
+    UnOp,
+};
+pub use crate::compiler::parser::{parse, parse_recovering, parse_with, Diagnostic, Severity};
+pub use crate::compiler::visit::*;
+pub use crate::compiler::{CompatLevel, CompileError, Span};
@@ -0,0 +1,473 @@
+//! Lua tables.
+
+mod raw;
+mod typed;
+
+use std::cell::{Cell, Ref, RefMut};
+use std::fmt;
+
+use crate::mem::{Gc, GcWeak, Managed, Mutation, RefLock, Tracer};
+use crate::Value;
+
+pub(crate) use self::raw::hash_bytes;
+pub use self::raw::{InvalidTableKey, RawTable};
+pub use self::typed::TablePairs;
+
+/// The contents of a table: its entries and metatable.
+#[derive(Debug, Default)]
+pub struct TableState<'gc> {
+    pub entries: RawTable<'gc>,
+    pub metatable: Option<Table<'gc>>,
+    /// Whether the table is waiting to be found unreachable so its `__gc` metamethod can run.
+    pub(crate) marked_for_finalization: bool,
+    pub(crate) frozen: bool,
+    /// A bit for each [`MetaEvent`] the table, as a metatable, is known to lack. Cleared whenever
+    /// its entries may change.
+    absent_metamethods: Cell<u8>,
+}
+
+/// The metamethods a metatable remembers the absence of, so that the operations looking them up
+/// cost plain tables nothing past the first time, as in the reference implementation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum MetaEvent {
+    Index,
+    NewIndex,
+    Gc,
+    Mode,
+    Len,
+    Eq,
+}
+
+impl MetaEvent {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            MetaEvent::Index => "__index",
+            MetaEvent::NewIndex => "__newindex",
+            MetaEvent::Gc => "__gc",
+            MetaEvent::Mode => "__mode",
+            MetaEvent::Len => "__len",
+            MetaEvent::Eq => "__eq",
+        }
+    }
+}
+
+impl<'gc> TableState<'gc> {
+    /// Whether the keys and the values of the table are weak, going by the `__mode` field of its
+    /// metatable.
+    pub fn weak_mode(&self) -> (bool, bool) {
+        match self.metatable.map(|mt| mt.metamethod(MetaEvent::Mode)) {
+            Some(Value::String(mode)) => {
+                let mode = mode.as_bytes();
+                (mode.contains(&b'k'), mode.contains(&b'v'))
+            }
+            _ => (false, false),
+        }
+    }
+}
+
+unsafe impl<'gc> Managed for TableState<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.metatable.trace(tracer);
+        match self.weak_mode() {
+            (false, false) => self.entries.trace(tracer),
+            (weak_keys, weak_values) => {
+                tracer.register_weak();
+                if !self.entries.trace_weak(tracer, weak_keys, weak_values) {
+                    tracer.register_ephemeron();
+                }
+            }
+        }
+    }
+
+    fn clear_weak(&mut self, tracer: &Tracer, before_finalization: bool) {
+        self.entries.clear_weak(tracer, before_finalization);
+    }
+
+    fn heap_size(&self) -> usize {
+        self.entries.heap_size()
+    }
+}
+
+/// A handle to a Lua table. Tables compare and hash by identity.
+#[derive(Copy, Clone)]
+pub struct Table<'gc>(Gc<'gc, RefLock<TableState<'gc>>>);
+
+impl<'gc> Table<'gc> {
+    pub fn new(mc: &Mutation<'gc>) -> Table<'gc> {
+        Table(Gc::new(mc, RefLock::default()))
+    }
+
+    /// Creates a table with preallocated space for `array` sequence entries and `hash` other entries.
+    pub fn with_capacity(mc: &Mutation<'gc>, array: usize, hash: usize) -> Table<'gc> {
+        Table(Gc::new(
+            mc,
+            RefLock::new(TableState {
+                entries: RawTable::with_capacity(array, hash),
+                metatable: None,
+                marked_for_finalization: false,
+                frozen: false,
+                absent_metamethods: Cell::new(0),
+            }),
+        ))
+    }
+
+    /// Gets a value without invoking metamethods.
+    pub fn get(self, key: impl Into<Value<'gc>>) -> Value<'gc> {
+        self.0.borrow().entries.get(key.into())
+    }
+
+    /// Gets the value of a string key without allocating a string.
+    pub fn get_str(self, key: &str) -> Value<'gc> {
+        self.0.borrow().entries.get_str(key.as_bytes())
+    }
+
+    /// Sets a value without invoking metamethods. Fails for a frozen table.
+    pub fn set(
+        self,
+        mc: &Mutation<'gc>,
+        key: impl Into<Value<'gc>>,
+        value: impl Into<Value<'gc>>,
+    ) -> Result<(), InvalidTableKey> {
+        let mut state = self.borrow_mut(mc);
+        if state.frozen {
+            return Err(InvalidTableKey::Frozen);
+        }
+        let result = state.entries.set(key.into(), value.into());
+        state.entries.recount(mc.metrics());
+        result
+    }
+
+    /// Same as [`Table::get`], with a hint of where the key is from an [`InlineCache`].
+    ///
+    /// [`InlineCache`]: crate::bytecode::InlineCache
+    pub(crate) fn get_hinted(self, key: Value<'gc>, hint: &Cell<u32>) -> Value<'gc> {
+        self.0.borrow().entries.get_hinted(key, hint)
+    }
+
+    /// Same as [`Table::set`], with a hint of where the key is from an [`InlineCache`].
+    ///
+    /// [`InlineCache`]: crate::bytecode::InlineCache
+    pub(crate) fn set_hinted(
+        self,
+        mc: &Mutation<'gc>,
+        key: Value<'gc>,
+        value: Value<'gc>,
+        hint: &Cell<u32>,
+    ) -> Result<(), InvalidTableKey> {
+        let mut state = self.borrow_mut(mc);
+        if state.frozen {
+            return Err(InvalidTableKey::Frozen);
+        }
+        let result = state.entries.set_hinted(key, value, hint);
+        state.entries.recount(mc.metrics());
+        result
+    }
+
+    /// The border of the table, without invoking metamethods.
+    pub fn length(self) -> usize {
+        self.0.borrow().entries.length()
+    }
+
+    /// Same as [`Table::get`], for code that wants it on record that `__index` is bypassed, such
+    /// as a sandbox inspecting tables handed to it by scripts.
+    #[inline]
+    pub fn raw_get(self, key: impl Into<Value<'gc>>) -> Value<'gc> {
+        self.get(key)
+    }
+
+    /// Same as [`Table::set`]: `__newindex` is never called.
+    #[inline]
+    pub fn raw_set(
+        self,
+        mc: &Mutation<'gc>,
+        key: impl Into<Value<'gc>>,
+        value: impl Into<Value<'gc>>,
+    ) -> Result<(), InvalidTableKey> {
+        self.set(mc, key, value)
+    }
+
+    /// Same as [`Table::length`]: `__len` is never called.
+    #[inline]
+    pub fn raw_len(self) -> usize {
+        self.length()
+    }
+
+    /// Whether `self` and `other` are the same table, which is all `rawequal` compares; `__eq` is
+    /// never called.
+    #[inline]
+    pub fn raw_equal(self, other: Table<'gc>) -> bool {
+        Gc::ptr_eq(self.0, other.0)
+    }
+
+    /// Returns the entry following `key` in traversal order; see [`RawTable::next`].
+    #[allow(clippy::result_unit_err)]
+    pub fn next(self, key: Value<'gc>) -> Result<Option<(Value<'gc>, Value<'gc>)>, ()> {
+        self.0.borrow().entries.next(key)
+    }
+
+    pub fn metatable(self) -> Option<Table<'gc>> {
+        self.0.borrow().metatable
+    }
+
+    /// The field `event` names, for the table as a metatable, remembering when it is nil.
+    pub(crate) fn metamethod(self, event: MetaEvent) -> Value<'gc> {
+        let state = self.0.borrow();
+        let bit = 1 << event as u8;
+        let absent = state.absent_metamethods.get();
+        if absent & bit != 0 {
+            return Value::Nil;
+        }
+        let value = state.entries.get_str(event.name().as_bytes());
+        if value.is_nil() {
+            state.absent_metamethods.set(absent | bit);
+        }
+        value
+    }
+
+    /// Replaces the metatable, returning the previous one.
+    pub fn set_metatable(
+        self,
+        mc: &Mutation<'gc>,
+        metatable: Option<Table<'gc>>,
+    ) -> Option<Table<'gc>> {
+        std::mem::replace(&mut self.0.borrow_mut(mc).metatable, metatable)
+    }
+
+    /// Makes the table read-only for the rest of its life: [`Table::set`] fails, and so does any
+    /// assignment, `rawset` or `setmetatable` of a script. The host can still change the entries
+    /// through [`Table::borrow_mut`].
+    pub fn freeze(self, mc: &Mutation<'gc>) {
+        self.0.borrow_mut(mc).frozen = true;
+    }
+
+    pub fn is_frozen(self) -> bool {
+        self.0.borrow().frozen
+    }
+
+    pub(crate) fn from_inner(inner: Gc<'gc, RefLock<TableState<'gc>>>) -> Table<'gc> {
+        Table(inner)
+    }
+
+    pub(crate) fn downgrade(self) -> GcWeak<'gc, RefLock<TableState<'gc>>> {
+        Gc::downgrade(self.0)
+    }
+
+    pub fn borrow(self) -> Ref<'gc, TableState<'gc>> {
+        self.0.borrow()
+    }
+
+    pub fn borrow_mut(self, mc: &Mutation<'gc>) -> RefMut<'gc, TableState<'gc>> {
+        let state = self.0.borrow_mut(mc);
+        state.absent_metamethods.set(0);
+        state
+    }
+
+    #[inline]
+    pub fn as_ptr(self) -> *const () {
+        Gc::as_ptr(self.0).cast()
+    }
+
+    /// The order the table was made in; see [`Gc::serial`].
+    #[inline]
+    pub(crate) fn serial(self) -> u32 {
+        Gc::serial(self.0)
+    }
+
+    /// Returns true if the current collection has marked the table so far.
+    #[inline]
+    pub(crate) fn is_marked(self, tracer: &Tracer) -> bool {
+        tracer.is_marked(self.0)
+    }
+}
+
+impl<'gc> PartialEq for Table<'gc> {
+    #[inline]
+    fn eq(&self, other: &Table<'gc>) -> bool {
+        Gc::ptr_eq(self.0, other.0)
+    }
+}
+
+impl<'gc> Eq for Table<'gc> {}
+
+impl<'gc> fmt::Debug for Table<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Table({:p})", self.as_ptr())
+    }
+}
+
+unsafe impl<'gc> Managed for Table<'gc> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.trace(tracer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{Arena, Rootable};
+    use crate::LuaString;
+
+    struct NoRoot;
+
+    impl<'a> Rootable<'a> for NoRoot {
+        type Root = ();
+    }
+
+    #[test]
+    fn array_and_hash_parts() {
+        let arena = Arena::<NoRoot>::new(|_| ());
+        arena.mutate(|mc, _| {
+            let t = Table::new(mc);
+            for i in 1..=10i64 {
+                t.set(mc, i, i * 10).unwrap();
+            }
+            t.set(mc, 2.0, 3i64).unwrap();
+            let key = LuaString::new(mc, b"key");
+            t.set(mc, key, true).unwrap();
+            assert_eq!(t.length(), 10);
+            assert_eq!(t.get(2i64), Value::Integer(3));
+            assert_eq!(t.get(LuaString::new(mc, b"key")), Value::Boolean(true));
+            assert_eq!(t.get_str("key"), Value::Boolean(true));
+            assert_eq!(t.set(mc, Value::Nil, 1i64), Err(InvalidTableKey::IsNil));
+            assert_eq!(t.set(mc, f64::NAN, 1i64), Err(InvalidTableKey::IsNaN));
+
+            // Keys inserted out of order are migrated to the array part once the gap closes.
+            let u = Table::new(mc);
+            u.set(mc, 3i64, 3i64).unwrap();
+            u.set(mc, 2i64, 2i64).unwrap();
+            assert_eq!(u.length(), 0);
+            u.set(mc, 1i64, 1i64).unwrap();
+            assert_eq!(u.length(), 3);
+            assert!(u.raw_equal(u) && !u.raw_equal(t));
+
+            u.freeze(mc);
+            assert_eq!(u.set(mc, 4i64, 4i64), Err(InvalidTableKey::Frozen));
+            u.borrow_mut(mc)
+                .entries
+                .set(Value::Integer(4), Value::Integer(4))
+                .unwrap();
+            assert_eq!(u.length(), 4);
+        });
+    }
+
+    #[test]
+    fn array_part_sizes() {
+        let arena = Arena::<NoRoot>::new(|_| ());
+        arena.mutate(|mc, _| {
+            // Without key 1 nothing is ever appended, but the keys fill most of an array part.
+            let t = Table::new(mc);
+            for i in 2..=100i64 {
+                t.set(mc, i, i).unwrap();
+            }
+            assert!(t.borrow().entries.array().len() >= 100);
+            assert!((2..=100i64).all(|i| t.get(i) == Value::Integer(i)));
+            t.set(mc, 1i64, 1i64).unwrap();
+            assert_eq!(t.length(), 100);
+
+            // Keys too sparse for an array part stay in the hash part.
+            let sparse = Table::new(mc);
+            for i in 1..=20i64 {
+                sparse.set(mc, i * 1000, i).unwrap();
+            }
+            assert!(sparse.borrow().entries.array().is_empty());
+
+            // Emptied, the array part gives way once the hash part next grows.
+            for i in 1..=100i64 {
+                t.set(mc, i, Value::Nil).unwrap();
+            }
+            t.set(mc, 1000i64, true).unwrap();
+            for i in 0..20 {
+                t.set(mc, LuaString::new(mc, format!("k{i}").as_bytes()), i as i64)
+                    .unwrap();
+            }
+            assert!(t.borrow().entries.array().len() < 128);
+            assert_eq!(t.get(1000i64), Value::Boolean(true));
+            assert_eq!(t.get_str("k7"), Value::Integer(7));
+        });
+    }
+
+    #[test]
+    fn absent_metamethods() {
+        let mut lua = crate::Lua::new();
+        lua.enter(|ctx| {
+            let source = "local mt = {}
+                local t = setmetatable({}, mt)
+                local log = {tostring(t.x), tostring(#t)}
+                mt.__index = {x = 1}
+                log[#log + 1] = t.x
+                rawset(mt, '__len', function() return 7 end)
+                log[#log + 1] = #t
+                mt.__index = nil
+                log[#log + 1] = tostring(t.x)
+                return mt, table.concat(log, ' ')";
+            let results = ctx.eval(source).unwrap();
+            assert_eq!(results[1].to_string(), "nil 0 1 7 nil");
+            let Value::Table(mt) = results[0] else {
+                unreachable!();
+            };
+            assert!(mt.metamethod(MetaEvent::Eq).is_nil());
+            let name = Value::String(LuaString::new(&ctx, b"__eq"));
+            mt.borrow_mut(&ctx)
+                .entries
+                .set(name, Value::Boolean(true))
+                .unwrap();
+            assert_eq!(mt.metamethod(MetaEvent::Eq), Value::Boolean(true));
+        });
+    }
+
+    #[test]
+    fn borders() {
+        let arena = Arena::<NoRoot>::new(|_| ());
+        arena.mutate(|mc, _| {
+            let is_border = |t: Table<'_>, n: usize| {
+                (n == 0 || !t.get(n as i64).is_nil()) && t.get(n as i64 + 1).is_nil()
+            };
+            let t = Table::new(mc);
+            for i in 1..=100i64 {
+                t.set(mc, i, i).unwrap();
+            }
+            for hole in [100i64, 50, 7, 1] {
+                t.set(mc, hole, Value::Nil).unwrap();
+                assert!(is_border(t, t.length()), "hole at {hole}");
+            }
+
+            // A sequence that only lives in the hash part is found by doubling the index.
+            let h = Table::new(mc);
+            for i in (1..=1000i64).rev() {
+                h.set(mc, i, i).unwrap();
+            }
+            h.set(mc, 1i64, Value::Nil).unwrap();
+            h.set(mc, 1i64, 1i64).unwrap();
+            assert_eq!(h.length(), 1000);
+            h.set(mc, 1001i64, 1001i64).unwrap();
+            h.set(mc, 1003i64, 1003i64).unwrap();
+            assert!(is_border(h, h.length()));
+        });
+    }
+
+    #[test]
+    fn traversal_survives_clearing_fields() {
+        let arena = Arena::<NoRoot>::new(|_| ());
+        arena.mutate(|mc, _| {
+            let t = Table::new(mc);
+            for i in 0..20 {
+                t.set(
+                    mc,
+                    LuaString::from_vec(mc, format!("k{i}").into_bytes()),
+                    i as i64,
+                )
+                .unwrap();
+            }
+            let mut key = Value::Nil;
+            let mut seen = 0;
+            while let Some((k, _)) = t.next(key).unwrap() {
+                t.set(mc, k, Value::Nil).unwrap();
+                seen += 1;
+                key = k;
+            }
+            assert_eq!(seen, 20);
+            assert!(t.next(Value::Nil).unwrap().is_none());
+        });
+    }
+}
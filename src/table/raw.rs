@@ -0,0 +1,594 @@
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::mem;
+
+use crate::mem::{Managed, Metrics, Tracer};
+use crate::value::f64_to_i64;
+use crate::{Function, Value};
+
+/// The reasons a table can't take an entry: the key can't be used as one, or the table is frozen.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InvalidTableKey {
+    IsNil,
+    IsNaN,
+    /// The table was [frozen](crate::Table::freeze).
+    Frozen,
+}
+
+impl fmt::Display for InvalidTableKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidTableKey::IsNil => f.write_str("index is nil"),
+            InvalidTableKey::IsNaN => f.write_str("index is NaN"),
+            InvalidTableKey::Frozen => f.write_str("attempt to modify a frozen table"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidTableKey {}
+
+const MULTIPLIER: u64 = 0x9e37_79b9_7f4a_7c15;
+
+#[inline]
+fn mix(x: u64) -> u64 {
+    let x = (x ^ (x >> 31)).wrapping_mul(MULTIPLIER);
+    x ^ (x >> 29)
+}
+
+pub(crate) fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut h = bytes.len() as u64;
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+        let word = u64::from_le_bytes(chunk.try_into().unwrap());
+        h = (h.rotate_left(5) ^ word).wrapping_mul(MULTIPLIER);
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        let mut word = [0; 8];
+        word[..rest.len()].copy_from_slice(rest);
+        h = (h.rotate_left(5) ^ u64::from_le_bytes(word)).wrapping_mul(MULTIPLIER);
+    }
+    mix(h)
+}
+
+/// Hashes a normalized key.
+fn hash_key(key: Value<'_>) -> u64 {
+    match key {
+        Value::Nil => 0,
+        Value::Boolean(b) => mix(b as u64 + 1),
+        Value::Integer(i) => mix(i as u64),
+        Value::Number(n) => mix(n.to_bits()),
+        Value::String(s) => s.hash_code(),
+        Value::Table(t) => mix(t.as_ptr() as usize as u64),
+        Value::Function(f) => mix(f.as_ptr() as usize as u64),
+        Value::Thread(t) => mix(t.as_ptr() as usize as u64),
+        Value::UserData(u) => mix(u.as_ptr() as usize as u64),
+    }
+}
+
+/// Converts floats with an integral value to integers, so that `t[1]` and `t[1.0]` name the same slot.
+#[inline]
+fn normalize_key(key: Value<'_>) -> Value<'_> {
+    match key {
+        Value::Number(n) => match f64_to_i64(n) {
+            Some(i) => Value::Integer(i),
+            None => key,
+        },
+        _ => key,
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Entry<'gc> {
+    /// `Nil` marks a slot that has never been used. A slot whose value is `Nil` but whose key is set is a
+    /// removed entry, kept so that an ongoing traversal can continue from it. A NaN key, which no lookup
+    /// can match, stands in for a weak key that has been collected.
+    key: Value<'gc>,
+    value: Value<'gc>,
+}
+
+/// The storage of a table: an array part for the keys `1..=n` and an open-addressing hash part for the rest.
+#[derive(Debug, Default)]
+pub struct RawTable<'gc> {
+    array: Vec<Value<'gc>>,
+    hash: Vec<Entry<'gc>>,
+    /// The number of hash slots with a key, including removed entries.
+    hash_used: usize,
+    /// The size of the storage as last reported to the arena.
+    counted: usize,
+}
+
+impl<'gc> RawTable<'gc> {
+    pub fn new() -> RawTable<'gc> {
+        RawTable::default()
+    }
+
+    pub fn with_capacity(array: usize, hash: usize) -> RawTable<'gc> {
+        let mut table = RawTable {
+            array: Vec::with_capacity(array),
+            ..RawTable::default()
+        };
+        if hash > 0 {
+            table.hash = vec![Entry::default(); (hash * 4 / 3 + 1).next_power_of_two()];
+        }
+        table.counted = table.storage_size();
+        table
+    }
+
+    pub fn get(&self, key: Value<'gc>) -> Value<'gc> {
+        let key = normalize_key(key);
+        if let Value::Integer(i) = key {
+            if let Some(value) = self.array_index(i).map(|i| self.array[i]) {
+                return value;
+            }
+        }
+        match self.find(key) {
+            Some(slot) => self.hash[slot].value,
+            None => Value::Nil,
+        }
+    }
+
+    /// Looks up a string key without allocating a Lua string for it.
+    pub fn get_str(&self, key: &[u8]) -> Value<'gc> {
+        if self.hash.is_empty() {
+            return Value::Nil;
+        }
+        let mask = self.hash.len() - 1;
+        let mut slot = hash_bytes(key) as usize & mask;
+        loop {
+            let entry = &self.hash[slot];
+            match entry.key {
+                Value::Nil => return Value::Nil,
+                Value::String(s) if s.as_bytes() == key => return entry.value,
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+
+    pub fn set(&mut self, key: Value<'gc>, value: Value<'gc>) -> Result<(), InvalidTableKey> {
+        let key = normalize_key(key);
+        match key {
+            Value::Nil => return Err(InvalidTableKey::IsNil),
+            Value::Number(n) if n.is_nan() => return Err(InvalidTableKey::IsNaN),
+            Value::Integer(i) => {
+                if let Some(index) = self.array_index(i) {
+                    self.array[index] = value;
+                    return Ok(());
+                }
+                if i as u64 == self.array.len() as u64 + 1 && !value.is_nil() {
+                    if let Some(slot) = self.find(key) {
+                        self.hash[slot].value = Value::Nil;
+                    }
+                    self.array.push(value);
+                    self.migrate_from_hash();
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(slot) = self.find(key) {
+            self.hash[slot].value = value;
+        } else if !value.is_nil() {
+            self.insert_new(key, value);
+        }
+        Ok(())
+    }
+
+    /// Same as [`RawTable::get`], trying the hash slot `hint` first for a string key and leaving
+    /// in it the slot the key was found at.
+    pub(crate) fn get_hinted(&self, key: Value<'gc>, hint: &Cell<u32>) -> Value<'gc> {
+        if !matches!(key, Value::String(_)) {
+            return self.get(key);
+        }
+        if let Some(entry) = self.hash.get(hint.get() as usize) {
+            if entry.key == key {
+                return entry.value;
+            }
+        }
+        match self.find(key) {
+            Some(slot) => {
+                hint.set(slot as u32);
+                self.hash[slot].value
+            }
+            None => Value::Nil,
+        }
+    }
+
+    /// Same as [`RawTable::set`], trying the hash slot `hint` first for a string key and leaving
+    /// in it the slot the key was found at.
+    pub(crate) fn set_hinted(
+        &mut self,
+        key: Value<'gc>,
+        value: Value<'gc>,
+        hint: &Cell<u32>,
+    ) -> Result<(), InvalidTableKey> {
+        if !matches!(key, Value::String(_)) {
+            return self.set(key, value);
+        }
+        if let Some(entry) = self.hash.get_mut(hint.get() as usize) {
+            if entry.key == key {
+                entry.value = value;
+                return Ok(());
+            }
+        }
+        self.set(key, value)?;
+        if let Some(slot) = self.find(key) {
+            hint.set(slot as u32);
+        }
+        Ok(())
+    }
+
+    /// Reports to `metrics` how much the storage has grown or shrunk since the last time.
+    pub(crate) fn recount(&mut self, metrics: &Metrics) {
+        let size = self.storage_size();
+        match size.cmp(&self.counted) {
+            Ordering::Greater => metrics.mark_external_allocation(size - self.counted),
+            Ordering::Less => metrics.mark_external_deallocation(self.counted - size),
+            Ordering::Equal => return,
+        }
+        self.counted = size;
+    }
+
+    fn storage_size(&self) -> usize {
+        self.array.capacity() * mem::size_of::<Value>() + self.hash.len() * mem::size_of::<Entry>()
+    }
+
+    /// Returns a border of the table: an index `n` such that `t[n]` is not nil and `t[n + 1]` is nil, or zero
+    /// if `t[1]` is nil.
+    ///
+    /// A table with holes has several borders, and which one is found depends on how its entries are
+    /// stored. When the array part ends in nil, one is binary searched for there. Otherwise the
+    /// search continues into the hash part, doubling the index until it finds a nil.
+    pub fn length(&self) -> usize {
+        let len = self.array.len();
+        if len > 0 && self.array[len - 1].is_nil() {
+            // `lo` is zero or non-nil, `hi` is nil.
+            let (mut lo, mut hi) = (0, len);
+            while hi - lo > 1 {
+                let mid = lo + (hi - lo) / 2;
+                if self.array[mid - 1].is_nil() {
+                    hi = mid;
+                } else {
+                    lo = mid;
+                }
+            }
+            return lo;
+        }
+        if self.hash.is_empty() {
+            return len;
+        }
+        let present = |n: usize| !self.get(Value::Integer(n as i64)).is_nil();
+        let (mut lo, mut hi) = (len, len + 1);
+        while present(hi) {
+            lo = hi;
+            if hi > i64::MAX as usize / 2 {
+                // Only a table built to defeat the search gets here; fall back to a linear one.
+                let mut n = 1;
+                while present(n + 1) {
+                    n += 1;
+                }
+                return n;
+            }
+            hi *= 2;
+        }
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if present(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    /// The array part: the values of the keys from 1 up to its length, some of which may be nil.
+    pub fn array(&self) -> &[Value<'gc>] {
+        &self.array
+    }
+
+    /// The array part, to change its values in place. Its length stays as it is.
+    pub fn array_mut(&mut self) -> &mut [Value<'gc>] {
+        &mut self.array
+    }
+
+    /// Returns the entry following `key` in traversal order, or the first entry if `key` is nil.
+    ///
+    /// Returns `Err(())` if `key` is not present in the table.
+    #[allow(clippy::result_unit_err)]
+    pub fn next(&self, key: Value<'gc>) -> Result<Option<(Value<'gc>, Value<'gc>)>, ()> {
+        let key = normalize_key(key);
+        let start = match key {
+            Value::Nil => 0,
+            Value::Integer(i) if self.array_index(i).is_some() => i as usize,
+            _ => self.array.len() + self.find(key).ok_or(())? + 1,
+        };
+
+        for index in start..self.array.len() {
+            let value = self.array[index];
+            if !value.is_nil() {
+                return Ok(Some((Value::Integer(index as i64 + 1), value)));
+            }
+        }
+        for entry in &self.hash[start.saturating_sub(self.array.len())..] {
+            if !entry.value.is_nil() {
+                return Ok(Some((entry.key, entry.value)));
+            }
+        }
+        Ok(None)
+    }
+
+    #[inline]
+    fn array_index(&self, key: i64) -> Option<usize> {
+        if key >= 1 && (key as u64) <= self.array.len() as u64 {
+            Some(key as usize - 1)
+        } else {
+            None
+        }
+    }
+
+    fn find(&self, key: Value<'gc>) -> Option<usize> {
+        if self.hash.is_empty() {
+            return None;
+        }
+        let mask = self.hash.len() - 1;
+        let mut slot = hash_key(key) as usize & mask;
+        loop {
+            let entry = &self.hash[slot];
+            if entry.key.is_nil() {
+                return None;
+            }
+            if entry.key == key {
+                return Some(slot);
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+
+    fn insert_new(&mut self, key: Value<'gc>, value: Value<'gc>) {
+        if (self.hash_used + 1) * 4 > self.hash.len() * 3 {
+            self.rehash(key);
+            // The array part may have grown to take the key.
+            if let Some(index) = self.array_index_of(key) {
+                self.array[index] = value;
+                return;
+            }
+        }
+        self.insert_slot(key, value);
+    }
+
+    /// Puts a key known to be missing into the hash part, which must have room for it.
+    fn insert_slot(&mut self, key: Value<'gc>, value: Value<'gc>) {
+        let mask = self.hash.len() - 1;
+        let mut slot = hash_key(key) as usize & mask;
+        while !self.hash[slot].key.is_nil() {
+            slot = (slot + 1) & mask;
+        }
+        self.hash[slot] = Entry { key, value };
+        self.hash_used += 1;
+    }
+
+    /// Resizes both parts for their entries and `incoming`, a key about to be inserted, as the
+    /// reference implementation does when the hash part is full.
+    ///
+    /// The array part becomes the largest power of two more than half of whose slots would hold
+    /// values, whether it is growing to take integer keys from the hash part or shrinking to give
+    /// them back. The hash part is rebuilt without removed entries for the rest, at most half full.
+    fn rehash(&mut self, incoming: Value<'gc>) {
+        // `counts[b]` is how many integer keys are in `2^(b-1) < k <= 2^b`.
+        let mut counts = [0usize; MAX_ARRAY_BITS as usize + 1];
+        let mut total = 0;
+        let mut count = |key: Value<'gc>| {
+            if let Some(bucket) = array_bucket(key) {
+                counts[bucket] += 1;
+                total += 1;
+            }
+        };
+        for (i, value) in self.array.iter().enumerate() {
+            if !value.is_nil() {
+                count(Value::Integer(i as i64 + 1));
+            }
+        }
+        for entry in &self.hash {
+            if !entry.value.is_nil() {
+                count(entry.key);
+            }
+        }
+        count(incoming);
+
+        let array_len = array_size(&counts, total);
+        let mut moved = Vec::new();
+        if array_len < self.array.len() {
+            for (i, value) in self.array.drain(array_len..).enumerate() {
+                if !value.is_nil() {
+                    moved.push(Entry {
+                        key: Value::Integer((array_len + i) as i64 + 1),
+                        value,
+                    });
+                }
+            }
+            self.array.shrink_to_fit();
+        } else {
+            self.array.resize(array_len, Value::Nil);
+        }
+
+        let old = std::mem::take(&mut self.hash);
+        let mut live = moved.len();
+        for entry in &old {
+            if entry.value.is_nil() {
+                continue;
+            }
+            match self.array_index_of(entry.key) {
+                Some(index) => self.array[index] = entry.value,
+                None => live += 1,
+            }
+        }
+        let incoming = usize::from(self.array_index_of(incoming).is_none());
+        self.hash_used = 0;
+        if live + incoming > 0 {
+            let capacity = ((live + incoming) * 2).next_power_of_two().max(4);
+            self.hash = vec![Entry::default(); capacity];
+        }
+        let in_array = |key: Value<'gc>| matches!(key, Value::Integer(i) if i >= 1 && i as u64 <= array_len as u64);
+        let kept = old
+            .into_iter()
+            .filter(|entry| !entry.value.is_nil() && !in_array(entry.key));
+        for entry in moved.into_iter().chain(kept) {
+            self.insert_slot(entry.key, entry.value);
+        }
+    }
+
+    /// The index into the array part of `key`, if it is an integer that belongs there.
+    #[inline]
+    fn array_index_of(&self, key: Value<'gc>) -> Option<usize> {
+        match key {
+            Value::Integer(i) => self.array_index(i),
+            _ => None,
+        }
+    }
+
+    /// Moves the integer keys directly following the array part from the hash part into the array.
+    fn migrate_from_hash(&mut self) {
+        loop {
+            let key = Value::Integer(self.array.len() as i64 + 1);
+            match self.find(key) {
+                Some(slot) if !self.hash[slot].value.is_nil() => {
+                    let value = std::mem::take(&mut self.hash[slot].value);
+                    self.array.push(value);
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+/// The array part holds at most `2^MAX_ARRAY_BITS` values.
+const MAX_ARRAY_BITS: u32 = 31;
+
+/// Which of the buckets [`RawTable::rehash`] counts the integer keys in that `key` falls in: `b`
+/// for `2^(b-1) < key <= 2^b`, or `None` if it couldn't go in the array part.
+fn array_bucket(key: Value<'_>) -> Option<usize> {
+    match key {
+        Value::Integer(i) if i >= 1 && i as u64 <= 1 << MAX_ARRAY_BITS => {
+            Some((u64::BITS - (i as u64 - 1).leading_zeros()) as usize)
+        }
+        _ => None,
+    }
+}
+
+/// The size of the array part for `total` integer keys, `counts[b]` of them in bucket `b`: the
+/// largest power of two more than half of whose slots would be used, or 0 if there is none.
+fn array_size(counts: &[usize], total: usize) -> usize {
+    let (mut used, mut size) = (0, 0);
+    for (bucket, &count) in counts.iter().enumerate() {
+        let slots = 1usize << bucket;
+        // Past this, no size can be more than half used.
+        if total <= slots / 2 {
+            break;
+        }
+        used += count;
+        if used > slots / 2 {
+            size = slots;
+        }
+    }
+    size
+}
+
+/// Returns true if `value` can be held weakly: strings and other values that can be recreated at will
+/// are never removed from weak tables.
+fn is_weak(value: Value<'_>) -> bool {
+    matches!(
+        value,
+        Value::Table(_)
+            | Value::Function(
+                Function::Closure(_) | Function::NativeClosure(_) | Function::Callback(_)
+            )
+            | Value::Thread(_)
+            | Value::UserData(_)
+    )
+}
+
+/// Returns true if `value` is held weakly and marking hasn't reached it.
+fn is_unmarked(value: Value<'_>, tracer: &Tracer) -> bool {
+    match value {
+        Value::Table(t) => !t.is_marked(tracer),
+        Value::Function(Function::Closure(c)) => !c.is_marked(tracer),
+        Value::Function(Function::NativeClosure(c)) => !c.is_marked(tracer),
+        Value::Function(Function::Callback(c)) => !c.is_marked(tracer),
+        Value::Thread(t) => !t.is_marked(tracer),
+        Value::UserData(u) => !u.is_marked(tracer),
+        _ => false,
+    }
+}
+
+impl<'gc> RawTable<'gc> {
+    /// Traces the entries of a table with weak keys, weak values or both, leaving out the weak
+    /// references.
+    ///
+    /// With only the keys weak, the table is an ephemeron table: a value is traced once its key has
+    /// been marked. Returns false if some value had to be left out because its key isn't marked yet.
+    pub(crate) fn trace_weak(
+        &self,
+        tracer: &mut Tracer,
+        weak_keys: bool,
+        weak_values: bool,
+    ) -> bool {
+        let trace = |value: Value<'gc>, weak: bool, tracer: &mut Tracer| {
+            if !(weak && is_weak(value)) {
+                value.trace(tracer);
+            }
+        };
+        for &value in &self.array {
+            trace(value, weak_values, tracer);
+        }
+        let mut converged = true;
+        for entry in &self.hash {
+            if weak_keys && !weak_values && !entry.value.is_nil() && is_unmarked(entry.key, tracer)
+            {
+                converged = false;
+                continue;
+            }
+            trace(entry.key, weak_keys, tracer);
+            trace(entry.value, weak_values, tracer);
+        }
+        converged
+    }
+
+    /// Removes the entries whose weak key or value wasn't marked.
+    ///
+    /// Before finalization only entries whose value is gone are removed, the way Lua clears weak values
+    /// before resurrecting objects with finalizers but keeps their keys around until they are collected.
+    pub(crate) fn clear_weak(&mut self, tracer: &Tracer, before_finalization: bool) {
+        for value in &mut self.array {
+            if is_unmarked(*value, tracer) {
+                *value = Value::Nil;
+            }
+        }
+        for entry in &mut self.hash {
+            if is_unmarked(entry.key, tracer) {
+                if !before_finalization {
+                    entry.key = Value::Number(f64::NAN);
+                    entry.value = Value::Nil;
+                }
+            } else if is_unmarked(entry.value, tracer) {
+                entry.value = Value::Nil;
+            }
+        }
+    }
+}
+
+unsafe impl<'gc> Managed for RawTable<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.array.trace(tracer);
+        for entry in &self.hash {
+            entry.key.trace(tracer);
+            entry.value.trace(tracer);
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        self.counted
+    }
+}
@@ -0,0 +1,196 @@
+//! Table access for the host, converting keys and values to and from Rust types.
+//!
+//! [`Table::get`] and [`Table::set`] work with plain [`Value`]s and never call metamethods. The
+//! methods here take a [`Context`] instead, so that they can convert, and behave as indexing does
+//! in Lua unless they are `raw_`, `__index`, `__newindex` and `__len` included.
+
+use std::marker::PhantomData;
+
+use crate::convert::{FromLua, IntoLua};
+use crate::vm::ops::{self, MetaResult};
+use crate::{Context, ConversionError, LuaError, RuntimeError, Table, Value};
+
+impl<'gc> Table<'gc> {
+    /// Gets `table[key]` as a `V`, calling `__index` if the key is absent.
+    pub fn get_as<K, V>(self, ctx: Context<'gc>, key: K) -> Result<V, LuaError<'gc>>
+    where
+        K: IntoLua<'gc>,
+        V: FromLua<'gc>,
+    {
+        let key = key.into_lua(ctx)?;
+        let value = match ops::index(ctx, Value::Table(self), key)? {
+            MetaResult::Value(value) => value,
+            MetaResult::Call(f, args) => first(ctx.call(f, &args)?),
+        };
+        Ok(V::from_lua(ctx, value)?)
+    }
+
+    /// Sets `table[key] = value`, calling `__newindex` if the key is absent.
+    pub fn set_as<K, V>(self, ctx: Context<'gc>, key: K, value: V) -> Result<(), LuaError<'gc>>
+    where
+        K: IntoLua<'gc>,
+        V: IntoLua<'gc>,
+    {
+        let key = key.into_lua(ctx)?;
+        let value = value.into_lua(ctx)?;
+        if let Some((f, args)) = ops::new_index(ctx, Value::Table(self), key, value)? {
+            ctx.call(f, &args)?;
+        }
+        Ok(())
+    }
+
+    /// Gets `table[key]` as a `V` without invoking metamethods.
+    pub fn raw_get_as<K, V>(self, ctx: Context<'gc>, key: K) -> Result<V, ConversionError>
+    where
+        K: IntoLua<'gc>,
+        V: FromLua<'gc>,
+    {
+        let key = key.into_lua(ctx)?;
+        V::from_lua(ctx, self.get(key))
+    }
+
+    /// Sets `table[key] = value` without invoking metamethods.
+    pub fn raw_set_as<K, V>(self, ctx: Context<'gc>, key: K, value: V) -> Result<(), LuaError<'gc>>
+    where
+        K: IntoLua<'gc>,
+        V: IntoLua<'gc>,
+    {
+        let key = key.into_lua(ctx)?;
+        let value = value.into_lua(ctx)?;
+        self.set(&ctx, key, value)
+            .map_err(|err| RuntimeError::new(err.to_string()).into())
+    }
+
+    /// Whether the table has a value for `key` of its own, not counting `__index`.
+    pub fn contains_key<K: IntoLua<'gc>>(
+        self,
+        ctx: Context<'gc>,
+        key: K,
+    ) -> Result<bool, ConversionError> {
+        Ok(!self.get(key.into_lua(ctx)?).is_nil())
+    }
+
+    /// The length `#table` has in Lua: what `__len` returns, if the metatable has it, or else the
+    /// border.
+    pub fn len(self, ctx: Context<'gc>) -> Result<i64, LuaError<'gc>> {
+        let value = match ops::len_meta(ctx, Value::Table(self))? {
+            MetaResult::Value(value) => value,
+            MetaResult::Call(f, args) => first(ctx.call(f, &args)?),
+        };
+        match value {
+            Value::Integer(n) => Ok(n),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => Ok(n as i64),
+            _ => Err(RuntimeError::new("object length is not an integer").into()),
+        }
+    }
+
+    /// Iterates over the entries of the table in traversal order, converting each key to a `K`
+    /// and each value to a `V`. `__pairs` is not called.
+    ///
+    /// Entries may be cleared while iterating, but adding new ones ends the iteration early.
+    pub fn pairs<K, V>(self, ctx: Context<'gc>) -> TablePairs<'gc, K, V>
+    where
+        K: FromLua<'gc>,
+        V: FromLua<'gc>,
+    {
+        TablePairs {
+            ctx,
+            table: self,
+            key: Some(Value::Nil),
+            _marker: PhantomData,
+        }
+    }
+}
+
+fn first(results: Vec<Value<'_>>) -> Value<'_> {
+    results.into_iter().next().unwrap_or(Value::Nil)
+}
+
+/// The iterator [`Table::pairs`] returns. An entry that doesn't convert is yielded as an error,
+/// and iteration goes on past it.
+pub struct TablePairs<'gc, K, V> {
+    ctx: Context<'gc>,
+    table: Table<'gc>,
+    /// The key of the last entry yielded, or `None` once the traversal is over.
+    key: Option<Value<'gc>>,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'gc, K, V> Iterator for TablePairs<'gc, K, V>
+where
+    K: FromLua<'gc>,
+    V: FromLua<'gc>,
+{
+    type Item = Result<(K, V), ConversionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = match self.table.next(self.key?) {
+            Ok(Some(entry)) => entry,
+            Ok(None) | Err(()) => {
+                self.key = None;
+                return None;
+            }
+        };
+        self.key = Some(key);
+        let entry =
+            K::from_lua(self.ctx, key).and_then(|key| Ok((key, V::from_lua(self.ctx, value)?)));
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::convert::FromLua;
+    use crate::{Lua, StashedTable, Table, Value};
+
+    #[test]
+    fn typed_access() {
+        let mut lua = Lua::new();
+        let stashed: StashedTable = lua.enter(|ctx| {
+            let t = ctx
+                .eval(
+                    "setmetatable({1, 2, 3, name = 'x'}, {
+                        __index = function(_, k) return k .. '!' end,
+                        __newindex = function(t, k, v) rawset(t, k, v * 2) end,
+                    })",
+                )
+                .unwrap()[0];
+            ctx.stash(Table::from_lua(ctx, t).unwrap())
+        });
+
+        lua.enter(|ctx| {
+            let t = ctx.fetch(&stashed);
+            assert_eq!(t.get_as::<_, String>(ctx, "name").unwrap(), "x");
+            assert_eq!(t.get_as::<_, String>(ctx, "other").unwrap(), "other!");
+            assert_eq!(t.raw_get_as::<_, Option<String>>(ctx, "other"), Ok(None));
+            assert_eq!(t.get_as::<_, i64>(ctx, 2).unwrap(), 2);
+            let err = t.get_as::<_, i64>(ctx, "name").unwrap_err();
+            assert_eq!(err.to_string(), "number expected, got string");
+
+            t.set_as(ctx, "n", 5).unwrap();
+            t.raw_set_as(ctx, "m", 5).unwrap();
+            assert_eq!(t.raw_get_as::<_, i64>(ctx, "n"), Ok(10));
+            assert_eq!(t.raw_get_as::<_, i64>(ctx, "m"), Ok(5));
+            assert!(t.raw_set_as(ctx, f64::NAN, 1).is_err());
+            assert!(t.contains_key(ctx, "m").unwrap());
+            assert!(!t.contains_key(ctx, "other").unwrap());
+            assert_eq!(t.len(ctx).unwrap(), 3);
+
+            let lengthy = ctx
+                .eval("setmetatable({}, {__len = function() return 42 end})")
+                .unwrap()[0];
+            let lengthy = Table::from_lua(ctx, lengthy).unwrap();
+            assert_eq!(lengthy.len(ctx).unwrap(), 42);
+            assert_eq!(lengthy.length(), 0);
+
+            let (items, others): (Vec<_>, Vec<_>) =
+                t.pairs::<i64, i64>(ctx).partition(Result::is_ok);
+            let items: BTreeMap<_, _> = items.into_iter().map(Result::unwrap).collect();
+            assert_eq!(items, BTreeMap::from([(1, 1), (2, 2), (3, 3)]));
+            assert_eq!(others.len(), 3);
+            assert_eq!(t.pairs::<Value, Value>(ctx).count(), 6);
+        });
+    }
+}
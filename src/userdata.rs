@@ -0,0 +1,824 @@
+//! Rust values stored in Lua as userdata.
+
+use std::any::{type_name, Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::convert::{FromLua, FromLuaMulti, IntoLuaMulti};
+use crate::mem::{Gc, Lock, Managed, Mutation, Tracer};
+use crate::vm::{self, Stack};
+use crate::{
+    Context, Function, LuaError, LuaString, MaybeSend, NativeClosure, NativeReturn, RuntimeError,
+    Table, Value,
+};
+
+/// A Rust type that can be handed to Lua as a userdata.
+///
+/// Scripts can only pass a userdata around and compare it by identity; anything else they do with
+/// it goes through its metatable. Since the type is `'static`, it can't hold on to Lua values
+/// itself: it keeps them in the userdata's user value, or in the registry.
+pub trait UserData: MaybeSend + 'static {
+    /// Declares the methods, fields and metamethods of userdata created with
+    /// [`Context::create_userdata`]. Nothing by default.
+    fn register(_methods: &mut UserDataMethods<Self>)
+    where
+        Self: Sized,
+    {
+    }
+}
+
+/// A userdata holding a value of some [`UserData`] type, which can be borrowed back as that type.
+///
+/// The Rust value is dropped once the collector finds the userdata unreachable.
+#[derive(Copy, Clone)]
+pub struct AnyUserData<'gc>(Gc<'gc, UserDataState<'gc>>);
+
+pub struct UserDataState<'gc> {
+    metatable: Lock<Option<Table<'gc>>>,
+    /// A Lua value the host associates with the userdata, kept alive with it.
+    user_value: Lock<Value<'gc>>,
+    value: RefCell<Contents>,
+    type_id: TypeId,
+    type_name: &'static str,
+}
+
+unsafe impl<'gc> Managed for UserDataState<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.metatable.trace(tracer);
+        self.user_value.trace(tracer);
+    }
+}
+
+/// Where a userdata's value lives.
+pub(crate) enum Contents {
+    Owned(Box<dyn Any>),
+    /// Lent by the host for a [`Scope`](crate::Scope), which expires it before the borrow ends.
+    Shared(*const dyn Any),
+    Unique(*mut dyn Any),
+    Expired,
+}
+
+/// Why a userdata couldn't be borrowed as a type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UserDataError {
+    /// It holds a value of another type.
+    WrongType,
+    /// It is mutably borrowed, or borrowed at all for a mutable borrow.
+    Borrowed,
+    /// It was lent to a [`Scope`](crate::Scope) that has ended.
+    Expired,
+}
+
+impl fmt::Display for UserDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserDataError::WrongType => f.write_str("userdata is not of the expected type"),
+            UserDataError::Borrowed => f.write_str("userdata is already borrowed"),
+            UserDataError::Expired => f.write_str("userdata is no longer available"),
+        }
+    }
+}
+
+impl std::error::Error for UserDataError {}
+
+impl From<UserDataError> for RuntimeError {
+    fn from(err: UserDataError) -> RuntimeError {
+        RuntimeError::new(err.to_string())
+    }
+}
+
+impl<'gc> From<UserDataError> for LuaError<'gc> {
+    fn from(err: UserDataError) -> LuaError<'gc> {
+        RuntimeError::from(err).into()
+    }
+}
+
+impl<'gc> AnyUserData<'gc> {
+    /// Moves `value` into a new userdata, with no metatable.
+    pub fn new<T: UserData>(mc: &Mutation<'gc>, value: T) -> AnyUserData<'gc> {
+        AnyUserData::with_contents::<T>(mc, Contents::Owned(Box::new(value)))
+    }
+
+    pub(crate) fn with_contents<T: UserData>(
+        mc: &Mutation<'gc>,
+        contents: Contents,
+    ) -> AnyUserData<'gc> {
+        AnyUserData(Gc::new(
+            mc,
+            UserDataState {
+                metatable: Lock::new(None),
+                user_value: Lock::new(Value::Nil),
+                value: RefCell::new(contents),
+                type_id: TypeId::of::<T>(),
+                type_name: type_name::<T>(),
+            },
+        ))
+    }
+
+    /// Whether the userdata holds a `T`.
+    pub fn is<T: UserData>(self) -> bool {
+        self.0.as_ref().type_id == TypeId::of::<T>()
+    }
+
+    /// Borrows the value as a `T`.
+    pub fn borrow<T: UserData>(self) -> Result<Ref<'gc, T>, UserDataError> {
+        if !self.is::<T>() {
+            return Err(UserDataError::WrongType);
+        }
+        let value = self.0.as_ref().value.try_borrow();
+        let value = value.map_err(|_| UserDataError::Borrowed)?;
+        if let Contents::Expired = *value {
+            return Err(UserDataError::Expired);
+        }
+        Ok(Ref::map(value, |value| {
+            let value: &dyn Any = match value {
+                Contents::Owned(value) => &**value,
+                // SAFETY: the scope lending the value expires it before the loan ends.
+                Contents::Shared(value) => unsafe { &**value },
+                Contents::Unique(value) => unsafe { &**value },
+                Contents::Expired => unreachable!(),
+            };
+            value.downcast_ref().expect("checked type")
+        }))
+    }
+
+    /// Mutably borrows the value as a `T`.
+    pub fn borrow_mut<T: UserData>(self) -> Result<RefMut<'gc, T>, UserDataError> {
+        if !self.is::<T>() {
+            return Err(UserDataError::WrongType);
+        }
+        let value = self.0.as_ref().value.try_borrow_mut();
+        let value = value.map_err(|_| UserDataError::Borrowed)?;
+        match *value {
+            Contents::Shared(_) => return Err(UserDataError::Borrowed),
+            Contents::Expired => return Err(UserDataError::Expired),
+            Contents::Owned(_) | Contents::Unique(_) => {}
+        }
+        Ok(RefMut::map(value, |value| {
+            let value: &mut dyn Any = match value {
+                Contents::Owned(value) => &mut **value,
+                // SAFETY: as for `borrow`, and the scope holds the only other reference.
+                Contents::Unique(value) => unsafe { &mut **value },
+                Contents::Shared(_) | Contents::Expired => unreachable!(),
+            };
+            value.downcast_mut().expect("checked type")
+        }))
+    }
+
+    /// Gives up a value lent by a scope, which is ending.
+    ///
+    /// # Panics
+    ///
+    /// If the value is still borrowed.
+    pub(crate) fn expire(self) {
+        let mut value = self.0.as_ref().value.try_borrow_mut().unwrap_or_else(|_| {
+            panic!("a scoped userdata is still borrowed at the end of its scope")
+        });
+        *value = Contents::Expired;
+    }
+
+    /// The name of the Rust type of the value, as `std::any::type_name` gives it.
+    pub fn type_name(self) -> &'static str {
+        self.0.as_ref().type_name
+    }
+
+    pub fn metatable(self) -> Option<Table<'gc>> {
+        self.0.as_ref().metatable.get()
+    }
+
+    pub fn set_metatable(self, mc: &Mutation<'gc>, metatable: Option<Table<'gc>>) {
+        self.0.as_ref().metatable.set_in(mc, self.0, metatable);
+    }
+
+    /// The Lua value associated with the userdata, nil to begin with.
+    pub fn user_value(self) -> Value<'gc> {
+        self.0.as_ref().user_value.get()
+    }
+
+    pub fn set_user_value(self, mc: &Mutation<'gc>, value: Value<'gc>) {
+        self.0.as_ref().user_value.set_in(mc, self.0, value);
+    }
+
+    #[inline]
+    pub fn as_ptr(self) -> *const () {
+        Gc::as_ptr(self.0).cast()
+    }
+
+    /// The order the userdata was made in; see [`Gc::serial`].
+    #[inline]
+    pub(crate) fn serial(self) -> u32 {
+        Gc::serial(self.0)
+    }
+
+    /// Returns true if the current collection has marked the userdata so far.
+    #[inline]
+    pub(crate) fn is_marked(self, tracer: &Tracer) -> bool {
+        tracer.is_marked(self.0)
+    }
+}
+
+impl<'gc> PartialEq for AnyUserData<'gc> {
+    #[inline]
+    fn eq(&self, other: &AnyUserData<'gc>) -> bool {
+        Gc::ptr_eq(self.0, other.0)
+    }
+}
+
+impl<'gc> Eq for AnyUserData<'gc> {}
+
+impl<'gc> fmt::Debug for AnyUserData<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AnyUserData({}, {:p})", self.type_name(), self.as_ptr())
+    }
+}
+
+unsafe impl<'gc> Managed for AnyUserData<'gc> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.trace(tracer)
+    }
+}
+
+/// A method or field accessor, declared before there is a state to create its function in.
+#[cfg(not(feature = "send"))]
+type Method =
+    Box<dyn for<'gc> Fn(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>>>;
+/// A method or field accessor, declared before there is a state to create its function in.
+#[cfg(feature = "send")]
+type Method = Box<
+    dyn for<'gc> Fn(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>>
+        + Send,
+>;
+
+/// Names the closure's signature, so that it is inferred as a [`Method`].
+fn boxed<F>(f: F) -> Method
+where
+    F: for<'gc> Fn(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>>
+        + MaybeSend
+        + 'static,
+{
+    Box::new(f)
+}
+
+/// The methods, fields and metamethods of a userdata type, declared in [`UserData::register`].
+///
+/// Arguments are converted with [`FromLua`] and results with [`IntoLua`](crate::IntoLua), an
+/// argument that doesn't convert raising the usual "bad argument" error. Methods get the userdata
+/// as their first argument, so scripts call them as `v:name(...)`, and borrow the Rust value for
+/// the length of the call: a method taking `&mut T` that gets called again while it is running
+/// fails, rather than aliasing the value.
+pub struct UserDataMethods<T> {
+    methods: Vec<(String, Method)>,
+    meta: Vec<(String, Method)>,
+    getters: Vec<(String, Method)>,
+    setters: Vec<(String, Method)>,
+    _type: PhantomData<fn(&T)>,
+}
+
+impl<T: UserData> UserDataMethods<T> {
+    fn new() -> UserDataMethods<T> {
+        UserDataMethods {
+            methods: Vec::new(),
+            meta: Vec::new(),
+            getters: Vec::new(),
+            setters: Vec::new(),
+            _type: PhantomData,
+        }
+    }
+
+    /// Adds a method that borrows the value.
+    pub fn add_method<A, R, F>(&mut self, name: &str, method: F)
+    where
+        A: for<'gc> FromLuaMulti<'gc>,
+        R: for<'gc> IntoLuaMulti<'gc>,
+        F: for<'gc> Fn(Context<'gc>, &T, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+    {
+        self.methods
+            .push((name.to_owned(), borrowing(name, method)));
+    }
+
+    /// Adds a method that borrows the value mutably.
+    pub fn add_method_mut<A, R, F>(&mut self, name: &str, method: F)
+    where
+        A: for<'gc> FromLuaMulti<'gc>,
+        R: for<'gc> IntoLuaMulti<'gc>,
+        F: for<'gc> Fn(Context<'gc>, &mut T, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+    {
+        self.methods
+            .push((name.to_owned(), borrowing_mut(name, method)));
+    }
+
+    /// Adds a function that is looked up like a method, but gets all of its arguments converted,
+    /// the first one included.
+    pub fn add_function<A, R, F>(&mut self, name: &str, function: F)
+    where
+        A: for<'gc> FromLuaMulti<'gc>,
+        R: for<'gc> IntoLuaMulti<'gc>,
+        F: for<'gc> Fn(Context<'gc>, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+    {
+        self.methods
+            .push((name.to_owned(), converting(name, function)));
+    }
+
+    /// Adds a field that scripts read as `v.name`, computed from the value.
+    pub fn add_field_method_get<R, F>(&mut self, name: &str, get: F)
+    where
+        R: for<'gc> IntoLuaMulti<'gc>,
+        F: for<'gc> Fn(Context<'gc>, &T) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+    {
+        let get = borrowing(name, move |ctx, this, ()| get(ctx, this));
+        self.getters.push((name.to_owned(), get));
+    }
+
+    /// Adds a field that scripts assign as `v.name = x`, updating the value.
+    pub fn add_field_method_set<A, F>(&mut self, name: &str, set: F)
+    where
+        A: for<'gc> FromLua<'gc>,
+        F: for<'gc> Fn(Context<'gc>, &mut T, A) -> Result<(), LuaError<'gc>> + MaybeSend + 'static,
+    {
+        self.setters
+            .push((name.to_owned(), borrowing_mut(name, set)));
+    }
+
+    /// Adds a metamethod, such as `__tostring` or `__len`, that borrows the value.
+    ///
+    /// An `__index` or `__newindex` one is only consulted for keys that aren't methods or fields.
+    pub fn add_meta_method<A, R, F>(&mut self, name: &str, method: F)
+    where
+        A: for<'gc> FromLuaMulti<'gc>,
+        R: for<'gc> IntoLuaMulti<'gc>,
+        F: for<'gc> Fn(Context<'gc>, &T, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+    {
+        self.meta.push((name.to_owned(), borrowing(name, method)));
+    }
+
+    /// Adds a metamethod that borrows the value mutably.
+    pub fn add_meta_method_mut<A, R, F>(&mut self, name: &str, method: F)
+    where
+        A: for<'gc> FromLuaMulti<'gc>,
+        R: for<'gc> IntoLuaMulti<'gc>,
+        F: for<'gc> Fn(Context<'gc>, &mut T, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+    {
+        self.meta
+            .push((name.to_owned(), borrowing_mut(name, method)));
+    }
+
+    /// Adds a metamethod that gets all of its arguments converted. Binary operators such as
+    /// `__add` want this, since the userdata may be either operand.
+    pub fn add_meta_function<A, R, F>(&mut self, name: &str, function: F)
+    where
+        A: for<'gc> FromLuaMulti<'gc>,
+        R: for<'gc> IntoLuaMulti<'gc>,
+        F: for<'gc> Fn(Context<'gc>, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+    {
+        self.meta
+            .push((name.to_owned(), converting(name, function)));
+    }
+
+    /// The metatable for the type: the metamethods, `__name`, and `__index` and `__newindex`
+    /// functions finding the methods and fields.
+    fn into_metatable(self, ctx: Context<'_>) -> Table<'_> {
+        let metatable = Table::new(&ctx);
+        let name = LuaString::new(&ctx, short_type_name::<T>().as_bytes());
+        set(ctx, metatable, "__name", Value::String(name));
+        let function =
+            |f: Method| Value::Function(Function::from_fn(&ctx, move |ctx, stack| f(ctx, stack)));
+        for (name, f) in self.meta {
+            set(ctx, metatable, &name, function(f));
+        }
+        let lookup = |entries: Vec<(String, Method)>| {
+            let table = Table::new(&ctx);
+            for (name, f) in entries {
+                set(ctx, table, &name, function(f));
+            }
+            Value::Table(table)
+        };
+        if !self.methods.is_empty() || !self.getters.is_empty() {
+            let upvalues = [
+                lookup(self.methods),
+                lookup(self.getters),
+                metatable.get_str("__index"),
+            ];
+            let index = NativeClosure::new(&ctx, index, &upvalues);
+            set(ctx, metatable, "__index", Value::Function(index.into()));
+        }
+        if !self.setters.is_empty() {
+            let upvalues = [lookup(self.setters), metatable.get_str("__newindex")];
+            let new_index = NativeClosure::new(&ctx, new_index, &upvalues);
+            set(
+                ctx,
+                metatable,
+                "__newindex",
+                Value::Function(new_index.into()),
+            );
+        }
+        metatable
+    }
+}
+
+/// The callback for a method borrowing the userdata it is called on.
+fn borrowing<T, A, R, F>(name: &str, method: F) -> Method
+where
+    T: UserData,
+    A: for<'gc> FromLuaMulti<'gc>,
+    R: for<'gc> IntoLuaMulti<'gc>,
+    F: for<'gc> Fn(Context<'gc>, &T, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+{
+    let name = name.to_owned();
+    boxed(move |ctx, stack| {
+        let this = this::<T>(stack, &name)?;
+        let args = arguments(ctx, stack, 1, &name)?;
+        let results = method(ctx, &*this.borrow::<T>()?, args)?;
+        return_values(ctx, stack, results)
+    })
+}
+
+/// Like [`borrowing`], with a mutable borrow.
+fn borrowing_mut<T, A, R, F>(name: &str, method: F) -> Method
+where
+    T: UserData,
+    A: for<'gc> FromLuaMulti<'gc>,
+    R: for<'gc> IntoLuaMulti<'gc>,
+    F: for<'gc> Fn(Context<'gc>, &mut T, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+{
+    let name = name.to_owned();
+    boxed(move |ctx, stack| {
+        let this = this::<T>(stack, &name)?;
+        let args = arguments(ctx, stack, 1, &name)?;
+        let results = method(ctx, &mut *this.borrow_mut::<T>()?, args)?;
+        return_values(ctx, stack, results)
+    })
+}
+
+/// The callback for a function converting all of its arguments.
+fn converting<A, R, F>(name: &str, function: F) -> Method
+where
+    A: for<'gc> FromLuaMulti<'gc>,
+    R: for<'gc> IntoLuaMulti<'gc>,
+    F: for<'gc> Fn(Context<'gc>, A) -> Result<R, LuaError<'gc>> + MaybeSend + 'static,
+{
+    let name = name.to_owned();
+    boxed(move |ctx, stack| {
+        let args = arguments(ctx, stack, 0, &name)?;
+        let results = function(ctx, args)?;
+        return_values(ctx, stack, results)
+    })
+}
+
+/// The first argument to the method `name`, which has to be a userdata holding a `T`.
+fn this<'gc, T: UserData>(
+    stack: &Stack<'gc, '_>,
+    name: &str,
+) -> Result<AnyUserData<'gc>, RuntimeError> {
+    let got = match stack.get(0) {
+        Value::UserData(u) if u.is::<T>() => return Ok(u),
+        _ if stack.is_empty() => "no value",
+        Value::UserData(u) => short_name(u.type_name()),
+        v => v.type_name(),
+    };
+    let expected = short_type_name::<T>();
+    Err(RuntimeError::new(format!(
+        "bad argument #1 to '{name}' ({expected} expected, got {got})"
+    )))
+}
+
+/// Converts the arguments from index `first` on.
+fn arguments<'gc, A: FromLuaMulti<'gc>>(
+    ctx: Context<'gc>,
+    stack: &Stack<'gc, '_>,
+    first: usize,
+    name: &str,
+) -> Result<A, RuntimeError> {
+    let values: Vec<_> = (first..stack.len()).map(|i| stack.get(i)).collect();
+    A::from_lua_multi(ctx, &values).map_err(|err| err.into_runtime_error(name, first + 1))
+}
+
+fn return_values<'gc, R: IntoLuaMulti<'gc>>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+    results: R,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let results = results.into_lua_multi(ctx)?;
+    stack.replace(&results);
+    Ok(NativeReturn::Return)
+}
+
+fn set<'gc>(ctx: Context<'gc>, table: Table<'gc>, key: &str, value: Value<'gc>) {
+    table
+        .set(&ctx, LuaString::new(&ctx, key.as_bytes()), value)
+        .expect("string keys are always valid");
+}
+
+/// The `__index` of a userdata type with methods or fields. Keys that are neither go to the
+/// type's own `__index`, if it declared one.
+fn index<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (this, key) = (stack.get(0), stack.get(1));
+    let (Value::Table(methods), Value::Table(getters)) = (stack.upvalue(0), stack.upvalue(1))
+    else {
+        unreachable!("the lookup tables are kept as upvalues");
+    };
+    let method = methods.get(key);
+    if !method.is_nil() {
+        stack.replace(&[method]);
+        return Ok(NativeReturn::Return);
+    }
+    // Fields are called directly, rather than through another trip into the interpreter.
+    if let Value::Function(Function::Callback(get)) = getters.get(key) {
+        stack.replace(&[this]);
+        return get.call(ctx, stack);
+    }
+    let value = match stack.upvalue(2) {
+        Value::Nil => Value::Nil,
+        f @ Value::Function(_) => {
+            let results = vm::call(ctx, stack.thread(), f, &[this, key])?;
+            results.first().copied().unwrap_or_default()
+        }
+        fallback => vm::index(ctx, stack.thread(), fallback, key)?,
+    };
+    stack.replace(&[value]);
+    Ok(NativeReturn::Return)
+}
+
+/// The `__newindex` of a userdata type with fields that can be set. Setting anything else is an
+/// error, unless the type declared its own `__newindex`.
+fn new_index<'gc>(
+    ctx: Context<'gc>,
+    stack: &mut Stack<'gc, '_>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    let (this, key, value) = (stack.get(0), stack.get(1), stack.get(2));
+    let Value::Table(setters) = stack.upvalue(0) else {
+        unreachable!("the setters are kept as an upvalue");
+    };
+    if let Value::Function(Function::Callback(set)) = setters.get(key) {
+        stack.replace(&[this, value]);
+        return set.call(ctx, stack);
+    }
+    match stack.upvalue(1) {
+        Value::Nil => {
+            let name = match this {
+                Value::UserData(u) => short_name(u.type_name()),
+                v => v.type_name(),
+            };
+            let message = format!("cannot set unknown field '{key}' of {name}");
+            return Err(RuntimeError::new(message).into());
+        }
+        f @ Value::Function(_) => {
+            vm::call(ctx, stack.thread(), f, &[this, key, value])?;
+        }
+        fallback => vm::new_index(ctx, stack.thread(), fallback, key, value)?,
+    }
+    stack.clear();
+    Ok(NativeReturn::Return)
+}
+
+/// A type's name without its path, which is what scripts see: `Vec2` for `game::math::Vec2`.
+fn short_name(name: &'static str) -> &'static str {
+    let end = name.find('<').unwrap_or(name.len());
+    let start = name[..end].rfind("::").map_or(0, |i| i + 2);
+    &name[start..end]
+}
+
+pub(crate) fn short_type_name<T: ?Sized>() -> &'static str {
+    short_name(type_name::<T>())
+}
+
+impl<'gc> Context<'gc> {
+    /// Moves `value` into a new userdata, with the metatable [`UserData::register`] declares for
+    /// its type.
+    pub fn create_userdata<T: UserData>(self, value: T) -> AnyUserData<'gc> {
+        let userdata = AnyUserData::new(&self, value);
+        userdata.set_metatable(&self, Some(self.userdata_metatable::<T>()));
+        userdata
+    }
+
+    /// The metatable [`create_userdata`](Context::create_userdata) gives a `T`. It is built the
+    /// first time it is asked for and shared from then on, so changing it changes it for every
+    /// userdata of the type.
+    pub fn userdata_metatable<T: UserData>(self) -> Table<'gc> {
+        let id = TypeId::of::<T>();
+        if let Some(key) = self.state().userdata_metatables().borrow().get(&id) {
+            if let Value::Table(metatable) = self.registry_value(key) {
+                return metatable;
+            }
+        }
+        let mut methods = UserDataMethods::new();
+        T::register(&mut methods);
+        let metatable = methods.into_metatable(self);
+        let key = self.create_registry_value(metatable);
+        self.state()
+            .userdata_metatables()
+            .borrow_mut()
+            .insert(id, key);
+        metatable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::vm::Stack;
+    use crate::{Context, Function, Lua, LuaString, NativeReturn};
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Vec2 {
+        x: f64,
+        y: f64,
+    }
+
+    impl UserData for Vec2 {
+        fn register(methods: &mut UserDataMethods<Self>) {
+            methods.add_field_method_get("x", |_, v| Ok(v.x));
+            methods.add_field_method_set("x", |_, v, x| {
+                v.x = x;
+                Ok(())
+            });
+            methods.add_method("dot", |_, v, w: Vec2| Ok(v.x * w.x + v.y * w.y));
+            methods.add_method_mut("scale", |_, v, k: f64| {
+                v.x *= k;
+                v.y *= k;
+                Ok(())
+            });
+            methods.add_function("new", |_, (x, y)| Ok(Vec2 { x, y }));
+            methods.add_meta_function("__add", |_, (v, w): (Vec2, Vec2)| {
+                Ok(Vec2 {
+                    x: v.x + w.x,
+                    y: v.y + w.y,
+                })
+            });
+            methods.add_meta_method("__tostring", |_, v, ()| Ok(format!("({}, {})", v.x, v.y)));
+        }
+    }
+
+    /// Counts its drops.
+    struct Tracked(Arc<AtomicU32>);
+
+    impl UserData for Tracked {}
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn vec2<'gc>(stack: &Stack<'gc, '_>, i: usize) -> Result<Ref<'gc, Vec2>, LuaError<'gc>> {
+        match stack.get(i) {
+            Value::UserData(u) => Ok(u.borrow::<Vec2>()?),
+            v => Err(RuntimeError::new(format!("Vec2 expected, got {}", v.type_name())).into()),
+        }
+    }
+
+    fn add<'gc>(
+        ctx: Context<'gc>,
+        stack: &mut Stack<'gc, '_>,
+    ) -> Result<NativeReturn, LuaError<'gc>> {
+        let sum = {
+            let (a, b) = (vec2(stack, 0)?, vec2(stack, 1)?);
+            Vec2 {
+                x: a.x + b.x,
+                y: a.y + b.y,
+            }
+        };
+        let sum = AnyUserData::new(&ctx, sum);
+        if let Value::UserData(a) = stack.get(0) {
+            sum.set_metatable(&ctx, a.metatable());
+        }
+        stack.replace(&[Value::UserData(sum)]);
+        Ok(NativeReturn::Return)
+    }
+
+    fn tostring<'gc>(
+        ctx: Context<'gc>,
+        stack: &mut Stack<'gc, '_>,
+    ) -> Result<NativeReturn, LuaError<'gc>> {
+        let text = {
+            let v = vec2(stack, 0)?;
+            format!("({}, {})", v.x, v.y)
+        };
+        stack.replace(&[Value::String(LuaString::new(&ctx, text.as_bytes()))]);
+        Ok(NativeReturn::Return)
+    }
+
+    fn set<'gc>(ctx: Context<'gc>, table: Table<'gc>, key: &str, value: impl Into<Value<'gc>>) {
+        table
+            .set(&ctx, LuaString::new(&ctx, key.as_bytes()), value.into())
+            .expect("string keys are always valid");
+    }
+
+    #[test]
+    fn storing_and_borrowing() {
+        let mut lua = Lua::new();
+        let result = lua.enter(|ctx| {
+            let metatable = Table::new(&ctx);
+            set(ctx, metatable, "__add", Function::Native(add));
+            set(ctx, metatable, "__tostring", Function::Native(tostring));
+            let new = |x, y| {
+                let v = AnyUserData::new(&ctx, Vec2 { x, y });
+                v.set_metatable(&ctx, Some(metatable));
+                v
+            };
+            let (a, b) = (new(1.0, 2.0), new(0.5, -1.0));
+            set(ctx, ctx.globals(), "a", a);
+            set(ctx, ctx.globals(), "b", b);
+
+            assert!(a.is::<Vec2>() && !a.is::<Tracked>());
+            assert_eq!(*a.borrow::<Vec2>().unwrap(), Vec2 { x: 1.0, y: 2.0 });
+            assert_eq!(a.borrow::<Tracked>().err(), Some(UserDataError::WrongType));
+            {
+                let _held = a.borrow_mut::<Vec2>().unwrap();
+                assert_eq!(a.borrow::<Vec2>().err(), Some(UserDataError::Borrowed));
+            }
+            a.borrow_mut::<Vec2>().unwrap().x = 3.0;
+
+            let results = ctx
+                .eval("tostring(a + b), type(a), a == a, a == b, rawequal(a, b), ({[a] = 1})[a]")
+                .unwrap();
+            results.iter().map(|v| v.to_string()).collect::<Vec<_>>()
+        });
+        assert_eq!(
+            result,
+            ["(3.5, 1)", "userdata", "true", "false", "false", "1"]
+        );
+    }
+
+    #[test]
+    fn methods_and_fields() {
+        let mut lua = Lua::new();
+        let result = lua.enter(|ctx| {
+            let a = ctx.create_userdata(Vec2 { x: 1.0, y: 2.0 });
+            set(ctx, ctx.globals(), "a", a);
+            set(
+                ctx,
+                ctx.globals(),
+                "b",
+                ctx.create_userdata(Vec2 { x: 0.5, y: -1.0 }),
+            );
+            let results = ctx.eval(
+                "local v = a + b
+                v.x = 10
+                v:scale(2)
+                return tostring(v), v.x, v.y, a:dot(b), a.new(3, 4):dot(a.new(1, 1)),
+                    getmetatable(a).__name, select(2, pcall(a.dot, a, 'no')),
+                    select(2, pcall(a.dot, 5, b)), select(2, pcall(function() a.z = 1 end)),
+                    select(2, pcall(a.new, 1))",
+            );
+            results
+                .unwrap()
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            result,
+            [
+                "(20, 2)",
+                "20.0",
+                "nil",
+                "-1.5",
+                "7.0",
+                "Vec2",
+                "bad argument #2 to 'dot' (Vec2 expected, got string)",
+                "bad argument #1 to 'dot' (Vec2 expected, got number)",
+                "cannot set unknown field 'z' of Vec2",
+                "bad argument #2 to 'new' (number expected, got no value)",
+            ]
+        );
+    }
+
+    #[test]
+    fn collection() {
+        let drops = Arc::new(AtomicU32::new(0));
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            let kept = AnyUserData::new(&ctx, Tracked(drops.clone()));
+            kept.set_user_value(&ctx, Value::Table(Table::new(&ctx)));
+            set(ctx, ctx.globals(), "kept", kept);
+            let dropped = AnyUserData::new(&ctx, Tracked(drops.clone()));
+            let weak = ctx.eval("weak = setmetatable({}, {__mode = 'k'}) return weak");
+            let Value::Table(weak) = weak.unwrap()[0] else {
+                panic!("weak is a table");
+            };
+            weak.set(&ctx, dropped, true).unwrap();
+        });
+        lua.collect_all();
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        lua.enter(|ctx| {
+            assert!(ctx.eval("next(weak)").unwrap()[0].is_nil());
+            let Value::UserData(kept) = ctx.globals().get_str("kept") else {
+                panic!("kept is a userdata");
+            };
+            assert!(matches!(kept.user_value(), Value::Table(_)));
+            ctx.globals()
+                .set(&ctx, LuaString::new(&ctx, b"kept"), Value::Nil)
+                .unwrap();
+        });
+        lua.collect_all();
+        assert_eq!(drops.load(Ordering::Relaxed), 2);
+    }
+}
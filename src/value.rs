@@ -0,0 +1,177 @@
+use std::fmt;
+
+use crate::mem::{Managed, Tracer};
+use crate::{AnyUserData, Function, LuaString, Table, Thread};
+
+/// Any value that a Lua variable can hold.
+#[derive(Debug, Copy, Clone, Default)]
+pub enum Value<'gc> {
+    #[default]
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(LuaString<'gc>),
+    Table(Table<'gc>),
+    Function(Function<'gc>),
+    Thread(Thread<'gc>),
+    UserData(AnyUserData<'gc>),
+}
+
+impl<'gc> Value<'gc> {
+    /// The name of the value's type, as returned by Lua's `type` function.
+    pub fn type_name(self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Integer(_) | Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+            Value::Thread(_) => "thread",
+            Value::UserData(_) => "userdata",
+        }
+    }
+
+    #[inline]
+    pub fn is_nil(self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    /// Lua truthiness: everything except `nil` and `false` is true.
+    #[inline]
+    pub fn to_bool(self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    /// Returns the value as a float if it is a number.
+    #[inline]
+    pub fn to_number(self) -> Option<f64> {
+        match self {
+            Value::Integer(i) => Some(i as f64),
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as an integer if it is an integer, or a float with an exact integer representation.
+    #[inline]
+    pub fn to_integer(self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(i),
+            Value::Number(n) => f64_to_i64(n),
+            _ => None,
+        }
+    }
+}
+
+/// Converts a float to an integer if it has an exact integer representation.
+#[inline]
+pub(crate) fn f64_to_i64(n: f64) -> Option<i64> {
+    // `i64::MIN` is exactly representable as a float, but `i64::MAX` is not: the float `2^63` is out of range.
+    if n.floor() == n && (-9223372036854775808.0..9223372036854775808.0).contains(&n) {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+/// Raw equality, as performed by `rawequal`: numbers compare by mathematical value, strings by contents and
+/// everything else by identity.
+impl<'gc> PartialEq for Value<'gc> {
+    fn eq(&self, other: &Value<'gc>) -> bool {
+        match (*self, *other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Integer(a), Value::Number(b)) | (Value::Number(b), Value::Integer(a)) => {
+                f64_to_i64(b) == Some(a)
+            }
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::Thread(a), Value::Thread(b)) => a == b,
+            (Value::UserData(a), Value::UserData(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl<'gc> fmt::Display for Value<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Value::Nil => f.write_str("nil"),
+            Value::Boolean(b) => write!(f, "{b}"),
+            Value::Integer(i) => write!(f, "{i}"),
+            Value::Number(n) => f.write_str(&crate::vm::number_to_string(n)),
+            Value::String(s) => write!(f, "{s}"),
+            Value::Table(t) => write!(f, "table: {:p}", t.as_ptr()),
+            Value::Function(func) => write!(f, "function: {:p}", func.as_ptr()),
+            Value::Thread(t) => write!(f, "thread: {:p}", t.as_ptr()),
+            Value::UserData(u) => write!(f, "userdata: {:p}", u.as_ptr()),
+        }
+    }
+}
+
+impl<'gc> From<bool> for Value<'gc> {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl<'gc> From<i64> for Value<'gc> {
+    fn from(i: i64) -> Self {
+        Value::Integer(i)
+    }
+}
+
+impl<'gc> From<f64> for Value<'gc> {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl<'gc> From<LuaString<'gc>> for Value<'gc> {
+    fn from(s: LuaString<'gc>) -> Self {
+        Value::String(s)
+    }
+}
+
+impl<'gc> From<Table<'gc>> for Value<'gc> {
+    fn from(t: Table<'gc>) -> Self {
+        Value::Table(t)
+    }
+}
+
+impl<'gc> From<Function<'gc>> for Value<'gc> {
+    fn from(f: Function<'gc>) -> Self {
+        Value::Function(f)
+    }
+}
+
+impl<'gc> From<Thread<'gc>> for Value<'gc> {
+    fn from(t: Thread<'gc>) -> Self {
+        Value::Thread(t)
+    }
+}
+
+impl<'gc> From<AnyUserData<'gc>> for Value<'gc> {
+    fn from(u: AnyUserData<'gc>) -> Self {
+        Value::UserData(u)
+    }
+}
+
+unsafe impl<'gc> Managed for Value<'gc> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Value::String(s) => s.trace(tracer),
+            Value::Table(t) => t.trace(tracer),
+            Value::Function(f) => f.trace(tracer),
+            Value::Thread(t) => t.trace(tracer),
+            Value::UserData(u) => u.trace(tracer),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,416 @@
+//! Introspection of running threads and the hooks behind the `debug` library.
+//!
+//! Native functions have no frames, so the levels taken here count the Lua functions on a thread's
+//! stack: level 1 is the innermost one.
+
+use crate::bytecode::{OpCode, Prototype};
+use crate::mem::{Managed, Mutation, Tracer};
+use crate::{Closure, Context, LuaError, LuaString, RegistryKey, UpValue, UpValueState, Value};
+
+use super::{call, Frame, Thread};
+
+/// The events a hook is called for, besides the instruction count.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct HookMask {
+    /// Entering a function, with the event `"call"`, or `"tail call"` for a tail call.
+    pub call: bool,
+    /// Leaving a function, with the event `"return"`.
+    pub ret: bool,
+    /// Starting a new line of Lua code, or jumping back, with the event `"line"` and the line.
+    pub line: bool,
+}
+
+/// A function called as a thread runs, as `debug.sethook` sets one.
+#[derive(Debug, Copy, Clone)]
+pub struct Hook<'gc> {
+    /// Called with the name of the event, and the line for line events.
+    pub function: Value<'gc>,
+    pub mask: HookMask,
+    /// If not 0, the hook is also called with the event `"count"` every `count` instructions.
+    pub count: u32,
+}
+
+unsafe impl<'gc> Managed for Hook<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.function.trace(tracer);
+    }
+}
+
+/// A thread's hook and what it needs to tell when to call it.
+pub(super) struct HookState<'gc> {
+    pub(super) hook: Hook<'gc>,
+    /// For the state's hook, which version of it this is.
+    host_version: Option<u32>,
+    /// Instructions left until the next count event.
+    countdown: u32,
+    /// The last instruction traced, for telling when a line starts.
+    old_pc: usize,
+    /// Set while the hook runs, as the code it runs isn't hooked.
+    running: bool,
+}
+
+impl<'gc> HookState<'gc> {
+    fn new(hook: Hook<'gc>, host_version: Option<u32>) -> HookState<'gc> {
+        HookState {
+            hook,
+            host_version,
+            countdown: hook.count,
+            old_pc: 0,
+            running: false,
+        }
+    }
+    /// Counts the instruction at `pc` as it is about to run, returning whether that is a count
+    /// event and the line if it starts one.
+    pub(super) fn trace(
+        &mut self,
+        proto: &Prototype<'gc>,
+        pc: usize,
+    ) -> Option<(bool, Option<u32>)> {
+        if self.running {
+            return None;
+        }
+        let count = self.hook.count > 0 && {
+            self.countdown -= 1;
+            self.countdown == 0
+        };
+        if count {
+            self.countdown = self.hook.count;
+        }
+        let line = proto.line_at(pc);
+        let new_line = self.hook.mask.line
+            && (pc == 0 || pc <= self.old_pc || line != proto.line_at(self.old_pc));
+        self.old_pc = pc;
+        let line = line.filter(|_| new_line);
+        (count || line.is_some()).then_some((count, line))
+    }
+
+    /// Notes that a function returned to the instruction at `pc`, which ran before the call.
+    pub(super) fn returned_to(&mut self, pc: usize) {
+        if !self.running {
+            self.old_pc = pc;
+        }
+    }
+
+    pub(super) fn wants_call(&self) -> bool {
+        self.hook.mask.call && !self.running
+    }
+
+    pub(super) fn wants_return(&self) -> bool {
+        self.hook.mask.ret && !self.running
+    }
+}
+
+unsafe impl<'gc> Managed for HookState<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.hook.trace(tracer);
+    }
+}
+
+/// Calls the thread's hook for `event`, unless it is already running.
+pub(super) fn call_hook<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    event: &str,
+    line: Option<u32>,
+) -> Result<(), LuaError<'gc>> {
+    let function = {
+        let mut st = thread.0.borrow_mut(&ctx);
+        match st.hook.as_mut() {
+            Some(state) if !state.running => {
+                state.running = true;
+                state.hook.function
+            }
+            _ => return Ok(()),
+        }
+    };
+    let mut args = vec![Value::String(LuaString::new(&ctx, event.as_bytes()))];
+    args.extend(line.map(|line| Value::Integer(line.into())));
+    let result = call(ctx, thread, function, &args);
+    // The hook may have been replaced while it ran; a new one isn't running either way.
+    if let Some(state) = thread.0.borrow_mut(&ctx).hook.as_mut() {
+        state.running = false;
+    }
+    result.map(drop)
+}
+
+/// A Lua function running on a thread.
+#[derive(Debug, Copy, Clone)]
+pub struct FrameInfo<'gc> {
+    pub closure: Closure<'gc>,
+    /// The line of the instruction running, if the function has line information.
+    pub current_line: Option<u32>,
+    /// Whether the function was tail called, so that its caller is gone.
+    pub tail_call: bool,
+    /// How the caller names the function (the kind of variable, like `"global"` or `"method"`,
+    /// and the name), if it can tell.
+    pub name: Option<(&'static str, LuaString<'gc>)>,
+}
+
+impl<'gc> Frame<'gc> {
+    /// The traceback entry for the frame, like `chunk:line: in function <chunk:line>`.
+    pub(super) fn describe(&self) -> String {
+        let proto = self.closure.proto();
+        if proto.line_defined == 0 {
+            format!("{} in main chunk", self.location(false))
+        } else {
+            let defined = format!("{}:{}", proto.chunk_name, proto.line_defined);
+            format!("{} in function <{defined}>", self.location(false))
+        }
+    }
+}
+
+/// The name a native function being called at stack index `func` has in the innermost Lua function
+/// of `thread`, if that is what called it.
+pub(super) fn native_name(thread: Thread<'_>, func: usize) -> Option<LuaString<'_>> {
+    called_name(thread.0.borrow().frames.last()?, func)
+}
+
+/// The traceback entry for a native function at stack index `func`, called from `caller` or from
+/// the host, like `[C]: in function 'name'`.
+pub(super) fn describe_native(caller: Option<&Frame<'_>>, func: usize) -> String {
+    match caller.and_then(|caller| called_name(caller, func)) {
+        Some(name) => format!("[C]: in function '{name}'"),
+        None => "[C]: in ?".to_owned(),
+    }
+}
+
+/// The name `caller` calls the function at stack index `func` by, if the instruction it is running
+/// is that call.
+fn called_name<'gc>(caller: &Frame<'gc>, func: usize) -> Option<LuaString<'gc>> {
+    let proto = caller.closure.proto();
+    let pc = caller.pc.checked_sub(1)?;
+    let i = proto.code[pc];
+    match i.opcode()? {
+        OpCode::Call if caller.base + i.a() as usize == func => {
+            proto.register_name(pc, i.a()).map(|(_, name)| name)
+        }
+        _ => None,
+    }
+}
+
+/// The name the caller of `frames[i]` calls it by, if the instruction it is running is that call.
+fn function_name<'gc>(frames: &[Frame<'gc>], i: usize) -> Option<(&'static str, LuaString<'gc>)> {
+    let frame = &frames[i];
+    if frame.tail_call {
+        return None;
+    }
+    let caller = &frames[i.checked_sub(1)?];
+    let proto = caller.closure.proto();
+    let pc = caller.pc.checked_sub(1)?;
+    let i = proto.code[pc];
+    match i.opcode()? {
+        // Functions called from native code, or as metamethods, show up without a name.
+        OpCode::Call if caller.base + i.a() as usize == frame.func => {
+            proto.register_name(pc, i.a())
+        }
+        _ => None,
+    }
+}
+
+/// Where local `n` of `frames[i]` lives on the stack, and its name if it has one. Negative `n` are
+/// the extra arguments of a vararg function.
+fn local_slot<'gc>(
+    frames: &[Frame<'gc>],
+    stack_top: usize,
+    i: usize,
+    n: i64,
+) -> Option<(usize, Option<LuaString<'gc>>)> {
+    let frame = &frames[i];
+    let proto = frame.closure.proto();
+    if n < 0 {
+        let first = frame.func + 1 + proto.num_params as usize;
+        let index = first + (n.unsigned_abs() as usize - 1);
+        return (proto.is_vararg && index < frame.base).then_some((index, None));
+    }
+    let reg = usize::try_from(n).ok()?.checked_sub(1)?;
+    let pc = frame.pc.saturating_sub(1);
+    if let Some(name) = proto.local_name(reg as u32, pc) {
+        return Some((frame.base + reg, Some(name)));
+    }
+    // Past the named locals, the registers in use hold temporaries.
+    let top = frames.get(i + 1).map_or(stack_top, |next| next.func);
+    (frame.base + reg < top).then_some((frame.base + reg, None))
+}
+
+impl<'gc> Thread<'gc> {
+    /// The index into the thread's frames of the Lua function `level` frames down.
+    fn frame_index(frames: &[Frame<'gc>], level: usize) -> Option<usize> {
+        frames.len().checked_sub(level).filter(|_| level > 0)
+    }
+
+    /// Returns the Lua function `level` frames down the stack, with 1 the innermost one.
+    pub fn frame_info(self, level: usize) -> Option<FrameInfo<'gc>> {
+        let st = self.0.borrow();
+        let i = Thread::frame_index(&st.frames, level)?;
+        let frame = &st.frames[i];
+        Some(FrameInfo {
+            closure: frame.closure,
+            current_line: frame.closure.proto().line_at(frame.pc.saturating_sub(1)),
+            tail_call: frame.tail_call,
+            name: function_name(&st.frames, i),
+        })
+    }
+
+    /// The innermost Lua function and the index of the instruction it is running.
+    pub(crate) fn innermost(self) -> Option<(Closure<'gc>, usize)> {
+        let st = self.0.borrow();
+        let frame = st.frames.last()?;
+        Some((frame.closure, frame.pc.saturating_sub(1)))
+    }
+
+    /// The number of Lua functions on the stack, which is the deepest level
+    /// [`frame_info`](Thread::frame_info) finds.
+    pub fn depth(self) -> usize {
+        self.0.borrow().frames.len()
+    }
+
+    /// Returns the traceback entries of the Lua functions from `level` frames down outwards, like
+    /// the ones an error collects.
+    pub fn traceback(self, level: usize) -> Vec<String> {
+        let st = self.0.borrow();
+        let end = st.frames.len().saturating_sub(level.saturating_sub(1));
+        st.frames[..end].iter().rev().map(Frame::describe).collect()
+    }
+
+    /// Returns the name and value of local `n` of the Lua function `level` frames down, counting
+    /// from 1 in the order they were declared. Registers past the active locals are named
+    /// `(temporary)`, and the extra arguments of a vararg function, taken with negative `n`,
+    /// `(vararg)`.
+    pub fn local(
+        self,
+        mc: &Mutation<'gc>,
+        level: usize,
+        n: i64,
+    ) -> Option<(LuaString<'gc>, Value<'gc>)> {
+        let st = self.0.borrow();
+        let i = Thread::frame_index(&st.frames, level)?;
+        let (index, name) = local_slot(&st.frames, st.values.len(), i, n)?;
+        Some((local_name(mc, name, n), st.values[index]))
+    }
+
+    /// Sets local `n` of the Lua function `level` frames down, as numbered by [`Thread::local`],
+    /// returning its name.
+    pub fn set_local(
+        self,
+        mc: &Mutation<'gc>,
+        level: usize,
+        n: i64,
+        value: Value<'gc>,
+    ) -> Option<LuaString<'gc>> {
+        let mut st = self.0.borrow_mut(mc);
+        let i = Thread::frame_index(&st.frames, level)?;
+        let (index, name) = local_slot(&st.frames, st.values.len(), i, n)?;
+        st.values[index] = value;
+        Some(local_name(mc, name, n))
+    }
+
+    /// Returns the thread's hook, if it has one.
+    pub fn hook(self) -> Option<Hook<'gc>> {
+        self.0.borrow().hook.as_ref().map(|state| state.hook)
+    }
+
+    /// Sets or removes the thread's hook. A hook with no events to be called for is removed.
+    pub fn set_hook(self, mc: &Mutation<'gc>, hook: Option<Hook<'gc>>) {
+        let hook = hook.filter(|hook| hook.mask != HookMask::default() || hook.count > 0);
+        self.0.borrow_mut(mc).hook = hook.map(|hook| HookState::new(hook, None));
+    }
+}
+
+impl<'gc> Context<'gc> {
+    /// Sets or removes the hook of every thread that doesn't have one of its own, for the host to
+    /// watch all the code the state runs. A native [`Function`](crate::Function) makes a Rust hook,
+    /// and an error it returns is raised where the event happened: with a count, this is how a
+    /// watchdog interrupts a script.
+    ///
+    /// `debug.sethook` replaces the hook in the thread it is called for, until that thread's own
+    /// hook is removed again.
+    pub fn set_hook(self, hook: Option<Hook<'gc>>) {
+        let hook = hook.filter(|hook| hook.mask != HookMask::default() || hook.count > 0);
+        self.state().set_hook(&self, hook);
+    }
+
+    /// The hook set with [`Context::set_hook`], if any.
+    pub fn hook(self) -> Option<Hook<'gc>> {
+        self.state().hook().0
+    }
+
+    /// Sets `hook` as [`Context::set_hook`] does, for a tool watching the state across calls to
+    /// [`Lua::enter`](crate::Lua::enter). The hook it replaces is kept until the tool is done with
+    /// the state and puts it back.
+    pub fn replace_hook(self, hook: Option<Hook<'gc>>) -> ReplacedHook {
+        let replaced = self.hook().map(|hook| {
+            (
+                self.create_registry_value(hook.function),
+                hook.mask,
+                hook.count,
+            )
+        });
+        self.set_hook(hook);
+        ReplacedHook(replaced)
+    }
+}
+
+/// The hook [`Context::replace_hook`] replaced.
+#[must_use = "the replaced hook is lost unless it is restored"]
+pub struct ReplacedHook(Option<(RegistryKey, HookMask, u32)>);
+
+impl ReplacedHook {
+    /// Puts the hook back, in place of whatever is set by then.
+    pub fn restore(self, ctx: Context<'_>) {
+        ctx.set_hook(self.0.map(|(function, mask, count)| Hook {
+            function: ctx.registry_value(&function),
+            mask,
+            count,
+        }));
+    }
+}
+
+/// The address of a thread or prototype, by which the recorders of hooks tell them apart outside
+/// of the arena.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Address(*const ());
+
+// SAFETY: the address is only compared, never dereferenced.
+unsafe impl Send for Address {}
+
+impl<T> From<*const T> for Address {
+    fn from(ptr: *const T) -> Address {
+        Address(ptr.cast())
+    }
+}
+
+/// Gives a thread without a hook of its own the state's, or the latest version of it.
+pub(super) fn inherit_hook<'gc>(ctx: Context<'gc>, hook: &mut Option<HookState<'gc>>) {
+    let (host, version) = ctx.state().hook();
+    if let Some(state) = hook {
+        if !matches!(state.host_version, Some(v) if v != version) {
+            return;
+        }
+    }
+    *hook = host.map(|host| HookState::new(host, Some(version)));
+}
+
+fn local_name<'gc>(mc: &Mutation<'gc>, name: Option<LuaString<'gc>>, n: i64) -> LuaString<'gc> {
+    name.unwrap_or_else(|| {
+        let name: &[u8] = if n < 0 { b"(vararg)" } else { b"(temporary)" };
+        LuaString::new(mc, name)
+    })
+}
+
+impl<'gc> UpValue<'gc> {
+    /// The value of the variable, wherever it lives.
+    pub fn value(self) -> Value<'gc> {
+        match self.get() {
+            UpValueState::Open { thread, index } => thread.0.borrow().values[index],
+            UpValueState::Closed(value) => value,
+        }
+    }
+
+    /// Assigns to the variable, wherever it lives.
+    pub fn set_value(self, mc: &Mutation<'gc>, value: Value<'gc>) {
+        match self.get() {
+            UpValueState::Open { thread, index } => thread.0.borrow_mut(mc).values[index] = value,
+            UpValueState::Closed(_) => self.set(mc, UpValueState::Closed(value)),
+        }
+    }
+}
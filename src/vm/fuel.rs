@@ -0,0 +1,203 @@
+//! Fuel: a budget of work a host grants the scripts it runs, so that untrusted code can't run
+//! forever.
+
+use std::cell::Cell;
+use std::fmt;
+
+use crate::Context;
+
+/// Bytes of heap growth that cost as much fuel as an instruction.
+pub const ALLOCATION_FUEL_BYTES: usize = 64;
+
+/// How many instructions run between charges for the heap's growth.
+const ALLOCATION_CHECK_INTERVAL: u64 = 256;
+
+/// The fuel left, if it is being metered.
+#[derive(Default)]
+pub(crate) struct Fuel {
+    remaining: Cell<Option<u64>>,
+    /// The heap size the last charge for allocation was made at.
+    allocated: Cell<usize>,
+    preempt: Cell<bool>,
+}
+
+impl Fuel {
+    /// Takes one unit of fuel for an instruction about to run, and from time to time the fuel the
+    /// heap's growth since the last time costs. Returns false, taking nothing, if there is none left.
+    #[inline]
+    pub(crate) fn consume(&self, ctx: Context<'_>) -> bool {
+        let Some(mut remaining) = self.remaining.get() else {
+            return true;
+        };
+        if remaining % ALLOCATION_CHECK_INTERVAL == 0 {
+            remaining = remaining.saturating_sub(self.allocation_cost(ctx));
+        }
+        if remaining == 0 {
+            self.remaining.set(Some(0));
+            return false;
+        }
+        self.remaining.set(Some(remaining - 1));
+        true
+    }
+
+    /// Whether there is a budget to take fuel from.
+    #[inline]
+    pub(crate) fn is_metered(&self) -> bool {
+        self.remaining.get().is_some()
+    }
+
+    /// Whether a coroutine out of fuel should be preempted rather than raise [`OutOfFuel`].
+    pub(crate) fn preempts(&self) -> bool {
+        self.preempt.get()
+    }
+
+    fn allocation_cost(&self, ctx: Context<'_>) -> u64 {
+        let total = ctx.metrics().total_allocation();
+        // The heap only shrinks between entries into the state, after which growth counts from its
+        // new size.
+        let grown = total.saturating_sub(self.allocated.replace(total));
+        (grown / ALLOCATION_FUEL_BYTES) as u64
+    }
+}
+
+/// The error raised in a script that runs out of fuel. Once out, every instruction raises it
+/// again, so a script can't go on by catching it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OutOfFuel;
+
+impl fmt::Display for OutOfFuel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("out of fuel")
+    }
+}
+
+impl std::error::Error for OutOfFuel {}
+
+impl<'gc> Context<'gc> {
+    /// Meters the work scripts do, allowing `fuel` more of it, or stops metering with `None`, as a
+    /// state starts out. Each Lua instruction takes one unit, and every
+    /// [`ALLOCATION_FUEL_BYTES`] the heap grows by take another.
+    ///
+    /// A script that runs out raises an [`OutOfFuel`] error, which reaches the host as an external
+    /// error, unless it can be [preempted](Context::set_fuel_preemption). Native functions aren't metered beyond their allocations, but the Lua functions they
+    /// call are.
+    pub fn set_fuel(self, fuel: Option<u64>) {
+        let meter = self.state().fuel();
+        meter.remaining.set(fuel);
+        meter.allocated.set(self.metrics().total_allocation());
+    }
+
+    /// The fuel left, or `None` if it isn't being metered.
+    pub fn fuel(self) -> Option<u64> {
+        self.state().fuel().remaining.get()
+    }
+
+    /// Whether a coroutine the host resumed stops when it runs out of fuel instead of raising
+    /// [`OutOfFuel`]. [`Thread::resume`](crate::Thread::resume) then returns no values, leaving it
+    /// [`Preempted`](crate::ThreadStatus::Preempted), and resuming it once there is more fuel
+    /// continues where it stopped. Off by default.
+    ///
+    /// Code not running as such a coroutine, including coroutines resumed from Lua, still raises
+    /// the error.
+    pub fn set_fuel_preemption(self, preempt: bool) {
+        self.state().fuel().preempt.set(preempt);
+    }
+
+    /// Whether running out of fuel preempts coroutines.
+    pub fn fuel_preemption(self) -> bool {
+        self.state().fuel().preempts()
+    }
+
+    /// Grants `fuel` more, if fuel is being metered.
+    pub fn add_fuel(self, fuel: u64) {
+        let meter = self.state().fuel();
+        if let Some(remaining) = meter.remaining.get() {
+            meter.remaining.set(Some(remaining.saturating_add(fuel)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Lua, OutOfFuel, Thread, ThreadStatus};
+
+    #[test]
+    fn scripts_run_out() {
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            ctx.set_fuel(Some(1000));
+            let sum = ctx.eval("local n = 0 for i = 1, 10 do n = n + i end return n");
+            assert_eq!(sum.unwrap()[0].to_string(), "55");
+            assert!(ctx.fuel().unwrap() < 1000);
+
+            let err = ctx
+                .eval("local ok = pcall(function() while true do end end) while true do end")
+                .unwrap_err();
+            assert_eq!(err.to_string(), "out of fuel");
+            assert_eq!(ctx.fuel(), Some(0));
+            let err = err.into_owned(ctx);
+            assert_eq!(err.downcast_ref::<OutOfFuel>(), Some(&OutOfFuel));
+
+            // Growing the heap costs fuel as well.
+            let used = |source: &str| {
+                ctx.set_fuel(Some(1_000_000));
+                ctx.eval(source).unwrap();
+                1_000_000 - ctx.fuel().unwrap()
+            };
+            let plain = used("for i = 1, 1000 do local t = i end");
+            let allocating = used("for i = 1, 1000 do local t = {} end");
+            assert!(allocating > plain + 1000);
+
+            ctx.set_fuel(Some(0));
+            ctx.add_fuel(100);
+            assert_eq!(ctx.fuel(), Some(100));
+            ctx.set_fuel(None);
+            assert!(ctx.eval("for i = 1, 100000 do end").is_ok());
+        });
+    }
+
+    #[test]
+    fn coroutines_are_preempted() {
+        let mut lua = Lua::new();
+        lua.enter(|ctx| {
+            ctx.set_fuel_preemption(true);
+            ctx.set_fuel(Some(100));
+            let f = ctx
+                .load(
+                    "=count",
+                    "local n = 0 for i = 1, 100 do n = n + i end return n",
+                )
+                .unwrap();
+            let co = Thread::with_function(&ctx, f);
+            let mut turns = 0;
+            let results = loop {
+                let results = co.resume(ctx, &[]).unwrap();
+                if co.status() != ThreadStatus::Preempted {
+                    break results;
+                }
+                assert!(results.is_empty());
+                turns += 1;
+                ctx.add_fuel(100);
+            };
+            assert_eq!(results[0].to_string(), "5050");
+            assert_eq!(co.status(), ThreadStatus::Dead);
+            assert!(turns > 1);
+
+            // Without fuel, resuming it again stops it again right away.
+            let spin = ctx.load("=spin", "while true do end").unwrap();
+            let co = Thread::with_function(&ctx, spin);
+            ctx.set_fuel(Some(100));
+            for _ in 0..2 {
+                assert!(co.resume(ctx, &[]).unwrap().is_empty());
+                assert_eq!(co.status(), ThreadStatus::Preempted);
+            }
+
+            // Coroutines resumed from Lua still fail.
+            ctx.set_fuel(Some(100));
+            let err = ctx
+                .eval("coroutine.wrap(function() while true do end end)()")
+                .unwrap_err();
+            assert_eq!(err.to_string(), "out of fuel");
+        });
+    }
+}
@@ -0,0 +1,581 @@
+//! Compiling the functions that run most to native code, with the experimental `jit` feature.
+//!
+//! The compiler is a baseline one: each instruction it handles becomes a call to a small function
+//! that does what the interpreter would, specialized to the opcode, and the jumps, tests and loops
+//! between them become native branches. That removes the decoding and dispatch of the interpreter
+//! loop, without knowing anything about the types of values.
+//!
+//! Whatever the native code can't do on the spot, it leaves to the interpreter: it returns the
+//! index of the instruction to continue from, and the interpreter runs that one before going back
+//! into the native code at the next instruction that has any. Calls, returns, closures, upvalues,
+//! varargs, concatenation and new tables are never compiled, and an operation that needs a
+//! metamethod or fails goes back to the interpreter to be done there.
+//!
+//! The native code never checks fuel or calls hooks, so it only runs while neither is on. Setting
+//! a hook, which takes a call, sends every function back to the interpreter from that call on.
+
+use std::cell::{Cell, OnceCell};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use cranelift_codegen::control::ControlPlane;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::types::I32;
+use cranelift_codegen::ir::{
+    AbiParam, Block, BlockCall, Function, InstBuilder, JumpTableData, Signature, UserFuncName,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use memmap2::{Mmap, MmapMut};
+
+use super::for_prep;
+use super::ops::{self, ArithOp, BitOp, CompareOp, MetaResult};
+use crate::bytecode::{self, InlineCache, Instruction, OpCode, Prototype, RK_CONSTANT};
+use crate::{Context, Value};
+
+/// How many times a function is entered, or goes around a loop, before it is compiled.
+const HOT: u32 = 1000;
+
+/// What an instruction's helper returns: go on to the next instruction, take the other way of a
+/// test or loop, or leave the instruction to the interpreter.
+const NEXT: u32 = 0;
+const OTHER: u32 = 1;
+const EXIT: u32 = 2;
+
+/// How hot a function is, and its native code once it has been compiled: `None` if it couldn't be.
+#[derive(Default)]
+pub(crate) struct JitState {
+    heat: Cell<u32>,
+    native: OnceCell<Option<Native>>,
+}
+
+impl fmt::Debug for JitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JitState")
+            .field("heat", &self.heat.get())
+            .field("compiled", &self.native.get().map(Option::is_some))
+            .finish()
+    }
+}
+
+/// Counts a run of `proto`, and returns its native code if it has any, compiling it the run it
+/// gets hot.
+pub(crate) fn tick<'p>(proto: &'p Prototype<'_>) -> Option<&'p Native> {
+    let state = &proto.cache.jit;
+    if let Some(native) = state.native.get() {
+        return native.as_ref();
+    }
+    let heat = state.heat.get() + 1;
+    state.heat.set(heat);
+    if heat < HOT {
+        return None;
+    }
+    state.native.get_or_init(|| compile(proto)).as_ref()
+}
+
+/// Native code for a function, which can be entered at any instruction it compiled.
+pub(crate) struct Native {
+    /// The mapping the code lives in, which must outlive `entry`.
+    _code: Mmap,
+    entry: Entry,
+    /// Whether each instruction was compiled.
+    compiled: Box<[bool]>,
+}
+
+/// Runs from the instruction given, and returns the index of the one to continue from.
+type Entry = for<'a, 'gc> unsafe extern "C" fn(*mut JitFrame<'a, 'gc>, u32) -> u32;
+
+/// Does an instruction, given its encoding and index.
+type Helper = for<'a, 'gc> extern "C" fn(*mut JitFrame<'a, 'gc>, u32, u32) -> u32;
+
+impl Native {
+    pub(crate) fn enters(&self, pc: usize) -> bool {
+        self.compiled.get(pc).copied().unwrap_or(false)
+    }
+
+    /// Runs the function from `pc`, with `values` starting at its register 0, until an
+    /// instruction that is left to the interpreter. Returns the index of that instruction.
+    pub(crate) fn run<'gc>(
+        &self,
+        ctx: Context<'gc>,
+        values: &mut [Value<'gc>],
+        proto: &Prototype<'gc>,
+        pc: usize,
+    ) -> usize {
+        let mut frame = JitFrame {
+            ctx,
+            values,
+            constants: &proto.constants,
+            cache: &proto.cache,
+        };
+        // The code was compiled for this function, after it passed verification.
+        unsafe { (self.entry)(&mut frame, pc as u32) as usize }
+    }
+}
+
+/// What the helpers work on: the registers of the running function and its constants.
+pub(crate) struct JitFrame<'a, 'gc> {
+    ctx: Context<'gc>,
+    values: &'a mut [Value<'gc>],
+    constants: &'a [Value<'gc>],
+    cache: &'a InlineCache,
+}
+
+impl<'a, 'gc> JitFrame<'a, 'gc> {
+    fn rk(&self, x: u32) -> Value<'gc> {
+        if bytecode::is_constant(x) {
+            self.constants[(x & !RK_CONSTANT) as usize]
+        } else {
+            self.values[x as usize]
+        }
+    }
+
+    fn reg(&self, x: u32) -> Value<'gc> {
+        self.values[x as usize]
+    }
+
+    fn set(&mut self, x: u32, v: Value<'gc>) -> u32 {
+        self.values[x as usize] = v;
+        NEXT
+    }
+
+    fn arith(&mut self, op: ArithOp, i: Instruction) -> u32 {
+        let (b, c) = match op {
+            ArithOp::Unm => (self.reg(i.b()), self.reg(i.b())),
+            _ => (self.rk(i.b()), self.rk(i.c())),
+        };
+        match ops::arith_fast(op, b, c) {
+            Some(v) => self.set(i.a(), v),
+            None => match ops::arith(op, b, c) {
+                Ok(Some(v)) => self.set(i.a(), v),
+                _ => EXIT,
+            },
+        }
+    }
+
+    fn bitwise(&mut self, op: BitOp, i: Instruction) -> u32 {
+        let (b, c) = match op {
+            BitOp::Not => (self.reg(i.b()), self.reg(i.b())),
+            _ => (self.rk(i.b()), self.rk(i.c())),
+        };
+        match ops::bitwise(op, b, c) {
+            Ok(Some(v)) => self.set(i.a(), v),
+            _ => EXIT,
+        }
+    }
+
+    fn compare(&mut self, op: CompareOp, i: Instruction) -> u32 {
+        let (b, c) = (self.rk(i.b()), self.rk(i.c()));
+        let result = match ops::compare_fast(op, b, c) {
+            Some(result) => result,
+            None => match ops::compare_meta(self.ctx, op, b, c) {
+                Ok(MetaResult::Value(v)) => v.to_bool(),
+                _ => return EXIT,
+            },
+        };
+        if result == (i.a() != 0) {
+            NEXT
+        } else {
+            OTHER
+        }
+    }
+
+    fn get_table(&mut self, i: Instruction, pc: usize) -> u32 {
+        let (obj, key) = (self.reg(i.b()), self.rk(i.c()));
+        match ops::index_hinted(self.ctx, obj, key, self.cache.hint(pc)) {
+            Ok(MetaResult::Value(v)) => self.set(i.a(), v),
+            _ => EXIT,
+        }
+    }
+
+    fn set_table(&mut self, i: Instruction, pc: usize) -> u32 {
+        // The interpreter checks the limit before the next instruction, and a loop filling a table
+        // could otherwise go on for good.
+        if self.ctx.metrics().exceeds_limit() {
+            return EXIT;
+        }
+        let (table, key, value) = (self.reg(i.a()), self.rk(i.b()), self.rk(i.c()));
+        match ops::new_index_hinted(self.ctx, table, key, value, self.cache.hint(pc)) {
+            Ok(None) => NEXT,
+            _ => EXIT,
+        }
+    }
+
+    fn len(&mut self, i: Instruction) -> u32 {
+        match ops::len_meta(self.ctx, self.reg(i.b())) {
+            Ok(MetaResult::Value(v)) => self.set(i.a(), v),
+            _ => EXIT,
+        }
+    }
+
+    fn test(&mut self, i: Instruction) -> u32 {
+        if self.reg(i.a()).to_bool() == (i.c() != 0) {
+            NEXT
+        } else {
+            OTHER
+        }
+    }
+
+    fn test_set(&mut self, i: Instruction) -> u32 {
+        let v = self.reg(i.b());
+        if v.to_bool() == (i.c() != 0) {
+            self.set(i.a(), v)
+        } else {
+            OTHER
+        }
+    }
+
+    /// `NEXT` into the body, `OTHER` past the loop.
+    fn for_prep(&mut self, i: Instruction) -> u32 {
+        let ra = i.a() as usize;
+        match for_prep(self.values, ra) {
+            Ok(true) => {
+                self.values[ra + 3] = self.values[ra];
+                NEXT
+            }
+            Ok(false) => OTHER,
+            Err(_) => EXIT,
+        }
+    }
+
+    /// `OTHER` back to the top of the body, `NEXT` out of the loop.
+    fn for_loop(&mut self, i: Instruction) -> u32 {
+        let ra = i.a() as usize;
+        let v = &mut self.values[ra..ra + 4];
+        let next = match (v[0], v[1], v[2]) {
+            (Value::Integer(idx), Value::Integer(remaining), Value::Integer(step)) => {
+                if remaining == 0 {
+                    return NEXT;
+                }
+                v[1] = Value::Integer((remaining as u64 - 1) as i64);
+                Value::Integer(idx.wrapping_add(step))
+            }
+            (Value::Number(idx), Value::Number(limit), Value::Number(step)) => {
+                let idx = idx + step;
+                let cont = if step > 0.0 {
+                    idx <= limit
+                } else {
+                    limit <= idx
+                };
+                if !cont {
+                    return NEXT;
+                }
+                Value::Number(idx)
+            }
+            _ => return EXIT,
+        };
+        v[0] = next;
+        v[3] = next;
+        OTHER
+    }
+}
+
+/// Defines the helper that calls the native code calls for an instruction. A panic, which
+/// verified code shouldn't cause, goes back to the interpreter to happen there.
+macro_rules! helpers {
+    ($($name:ident => |$f:ident, $i:ident, $pc:ident| $body:expr;)*) => {
+        $(
+            extern "C" fn $name(frame: *mut JitFrame<'_, '_>, i: u32, pc: u32) -> u32 {
+                // The frame lives on the stack of `Native::run` for as long as its code runs.
+                let $f = unsafe { &mut *frame };
+                let ($i, $pc) = (Instruction(i), pc as usize);
+                let _ = $pc;
+                panic::catch_unwind(AssertUnwindSafe(|| $body)).unwrap_or(EXIT)
+            }
+        )*
+    };
+}
+
+helpers! {
+    op_move => |f, i, pc| f.set(i.a(), f.reg(i.b()));
+    op_load_k => |f, i, pc| f.set(i.a(), f.constants[i.bx() as usize]);
+    op_load_i => |f, i, pc| f.set(i.a(), Value::Integer(i.sbx() as i64));
+    op_load_bool => |f, i, pc| f.set(i.a(), Value::Boolean(i.b() != 0));
+    op_load_nil => |f, i, pc| {
+        let a = i.a() as usize;
+        f.values[a..=a + i.b() as usize].fill(Value::Nil);
+        NEXT
+    };
+    op_get_table => |f, i, pc| f.get_table(i, pc);
+    op_set_table => |f, i, pc| f.set_table(i, pc);
+    op_add => |f, i, pc| f.arith(ArithOp::Add, i);
+    op_sub => |f, i, pc| f.arith(ArithOp::Sub, i);
+    op_mul => |f, i, pc| f.arith(ArithOp::Mul, i);
+    op_mod => |f, i, pc| f.arith(ArithOp::Mod, i);
+    op_pow => |f, i, pc| f.arith(ArithOp::Pow, i);
+    op_div => |f, i, pc| f.arith(ArithOp::Div, i);
+    op_idiv => |f, i, pc| f.arith(ArithOp::IDiv, i);
+    op_unm => |f, i, pc| f.arith(ArithOp::Unm, i);
+    op_band => |f, i, pc| f.bitwise(BitOp::And, i);
+    op_bor => |f, i, pc| f.bitwise(BitOp::Or, i);
+    op_bxor => |f, i, pc| f.bitwise(BitOp::Xor, i);
+    op_shl => |f, i, pc| f.bitwise(BitOp::Shl, i);
+    op_shr => |f, i, pc| f.bitwise(BitOp::Shr, i);
+    op_bnot => |f, i, pc| f.bitwise(BitOp::Not, i);
+    op_not => |f, i, pc| f.set(i.a(), Value::Boolean(!f.reg(i.b()).to_bool()));
+    op_len => |f, i, pc| f.len(i);
+    op_eq => |f, i, pc| f.compare(CompareOp::Eq, i);
+    op_lt => |f, i, pc| f.compare(CompareOp::Lt, i);
+    op_le => |f, i, pc| f.compare(CompareOp::Le, i);
+    op_test => |f, i, pc| f.test(i);
+    op_test_set => |f, i, pc| f.test_set(i);
+    op_for_prep => |f, i, pc| f.for_prep(i);
+    op_for_loop => |f, i, pc| f.for_loop(i);
+}
+
+/// The helper for an instruction the native code does, if it does it. Jumps need none.
+fn helper(i: Instruction) -> Option<Helper> {
+    let helper: Helper = match i.opcode()? {
+        OpCode::Move => op_move,
+        OpCode::LoadK => op_load_k,
+        OpCode::LoadI => op_load_i,
+        OpCode::LoadBool => op_load_bool,
+        OpCode::LoadNil => op_load_nil,
+        OpCode::GetTable => op_get_table,
+        OpCode::SetTable => op_set_table,
+        OpCode::Add => op_add,
+        OpCode::Sub => op_sub,
+        OpCode::Mul => op_mul,
+        OpCode::Mod => op_mod,
+        OpCode::Pow => op_pow,
+        OpCode::Div => op_div,
+        OpCode::IDiv => op_idiv,
+        OpCode::Unm => op_unm,
+        OpCode::BAnd => op_band,
+        OpCode::BOr => op_bor,
+        OpCode::BXor => op_bxor,
+        OpCode::Shl => op_shl,
+        OpCode::Shr => op_shr,
+        OpCode::BNot => op_bnot,
+        OpCode::Not => op_not,
+        OpCode::Len => op_len,
+        OpCode::Eq => op_eq,
+        OpCode::Lt => op_lt,
+        OpCode::Le => op_le,
+        OpCode::Test => op_test,
+        OpCode::TestSet => op_test_set,
+        OpCode::ForPrep => op_for_prep,
+        OpCode::ForLoop => op_for_loop,
+        _ => return None,
+    };
+    Some(helper)
+}
+
+/// The index of the instruction `offset` after the one following `pc`.
+fn target(pc: usize, offset: i32) -> usize {
+    (pc as isize + 1 + offset as isize) as usize
+}
+
+/// Compiles `proto`, if it passes verification and has anything to compile, and the machine is
+/// one Cranelift knows.
+fn compile(proto: &Prototype<'_>) -> Option<Native> {
+    bytecode::verify(proto).ok()?;
+    let code = &proto.code;
+    let compiled: Box<[bool]> = code
+        .iter()
+        .map(|&i| helper(i).is_some() || (i.opcode() == Some(OpCode::Jmp) && i.a() == 0))
+        .collect();
+    if !compiled.iter().any(|&c| c) {
+        return None;
+    }
+
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").ok()?;
+    let isa = cranelift_native::builder()
+        .ok()?
+        .finish(settings::Flags::new(flags))
+        .ok()?;
+    let ptr = isa.pointer_type();
+    let mut signature = Signature::new(isa.default_call_conv());
+    signature
+        .params
+        .extend([AbiParam::new(ptr), AbiParam::new(I32)]);
+    signature.returns.push(AbiParam::new(I32));
+    let mut helper_signature = signature.clone();
+    helper_signature.params.push(AbiParam::new(I32));
+
+    let mut func = Function::with_name_signature(UserFuncName::default(), signature);
+    let mut builder_context = FunctionBuilderContext::new();
+    let mut b = FunctionBuilder::new(&mut func, &mut builder_context);
+    let helper_signature = b.import_signature(helper_signature);
+
+    let entry = b.create_block();
+    b.append_block_params_for_function_params(entry);
+    let blocks: Vec<Block> = code.iter().map(|_| b.create_block()).collect();
+    let exit = b.create_block();
+    b.append_block_param(exit, I32);
+
+    // Entering at an instruction that wasn't compiled leaves right away.
+    b.switch_to_block(entry);
+    let (frame, start) = (b.block_params(entry)[0], b.block_params(entry)[1]);
+    let pool = &mut b.func.dfg.value_lists;
+    let table: Vec<_> = blocks
+        .iter()
+        .map(|&block| BlockCall::new(block, &[], pool))
+        .collect();
+    let table = JumpTableData::new(BlockCall::new(exit, &[start], pool), &table);
+    let table = b.create_jump_table(table);
+    b.ins().br_table(start, table);
+
+    // Jumps to the instruction at `pc`, or out to the interpreter if it wasn't compiled.
+    let goto = |b: &mut FunctionBuilder<'_>, pc: usize| {
+        if compiled.get(pc).copied().unwrap_or(false) {
+            b.ins().jump(blocks[pc], &[]);
+        } else {
+            let pc = b.ins().iconst(I32, pc as i64);
+            b.ins().jump(exit, &[pc]);
+        }
+    };
+
+    for (pc, &i) in code.iter().enumerate() {
+        b.switch_to_block(blocks[pc]);
+        let Some(helper) = helper(i) else {
+            if compiled[pc] {
+                goto(&mut b, target(pc, i.sbx()));
+            } else {
+                let pc = b.ins().iconst(I32, pc as i64);
+                b.ins().jump(exit, &[pc]);
+            }
+            continue;
+        };
+        let callee = b.ins().iconst(ptr, helper as usize as i64);
+        let args = [
+            frame,
+            b.ins().iconst(I32, i.0 as i64),
+            b.ins().iconst(I32, pc as i64),
+        ];
+        let call = b.ins().call_indirect(helper_signature, callee, &args);
+        let status = b.inst_results(call)[0];
+
+        // Where `NEXT` and `OTHER` go.
+        let (next, other) = match i.opcode() {
+            Some(OpCode::LoadBool) if i.c() != 0 => (pc + 2, pc + 2),
+            Some(OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet) => {
+                (pc + 1, pc + 2)
+            }
+            Some(OpCode::ForPrep) => {
+                let forloop = target(pc, i.sbx());
+                (target(forloop, code[forloop].sbx()), forloop + 1)
+            }
+            Some(OpCode::ForLoop) => (pc + 1, target(pc, i.sbx())),
+            _ => (pc + 1, pc + 1),
+        };
+        let (exiting, going) = (b.create_block(), b.create_block());
+        let is_exit = b.ins().icmp_imm(IntCC::Equal, status, EXIT as i64);
+        b.ins().brif(is_exit, exiting, &[], going, &[]);
+        b.switch_to_block(exiting);
+        let here = b.ins().iconst(I32, pc as i64);
+        b.ins().jump(exit, &[here]);
+        b.switch_to_block(going);
+        if next == other {
+            goto(&mut b, next);
+        } else {
+            let (taking, staying) = (b.create_block(), b.create_block());
+            b.ins().brif(status, taking, &[], staying, &[]);
+            b.switch_to_block(taking);
+            goto(&mut b, other);
+            b.switch_to_block(staying);
+            goto(&mut b, next);
+        }
+    }
+
+    b.switch_to_block(exit);
+    let resume = b.block_params(exit)[0];
+    b.ins().return_(&[resume]);
+    b.seal_all_blocks();
+    b.finalize();
+
+    let mut context = cranelift_codegen::Context::for_function(func);
+    let compiled_code = context.compile(&*isa, &mut ControlPlane::default()).ok()?;
+    // Helpers are called by address, so the code has nothing to patch.
+    if !compiled_code.buffer.relocs().is_empty() {
+        return None;
+    }
+    let bytes = compiled_code.code_buffer();
+    let mut map = MmapMut::map_anon(bytes.len()).ok()?;
+    map.copy_from_slice(bytes);
+    let map = map.make_exec().ok()?;
+    // The mapping holds code compiled for this signature, and is kept with the pointer.
+    let entry = unsafe { std::mem::transmute::<*const u8, Entry>(map.as_ptr()) };
+    Some(Native {
+        _code: map,
+        entry,
+        compiled,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Function, Lua, Value};
+
+    #[test]
+    fn runs_hot_functions() {
+        let mut lua = Lua::with_debug();
+        lua.enter(|ctx| {
+            let source = "
+                local function f(n)
+                    local s, t = 0, {1, 2, 3, x = 0.5}
+                    for i = 1, n do
+                        if i % 3 == 0 and i ~= 6 then s = s + i * t[i % 3 + 1] // 2 else s = s - 1 end
+                        t.x = t.x + i / 4
+                        if #t > 2 and s < 0 then s = -s end
+                        s = s + tostring(i):len() + (i & 7)
+                    end
+                    return s, t.x
+                end
+                local results = {}
+                for round = 1, 1500 do results[round] = {f(round % 40)} end
+                return f, results
+            ";
+            let results = ctx.eval(source).unwrap();
+            let Value::Function(Function::Closure(f)) = results[0] else {
+                unreachable!();
+            };
+            let proto = f.proto();
+            assert!(matches!(proto.cache.jit.native.get(), Some(Some(_))));
+
+            // The same function, kept in the interpreter by a hook, gives the same results.
+            let check = "
+                local f, results = ...
+                local fresh = load(string.dump(f))
+                debug.sethook(function() end, '', 1 << 30)
+                for round = 1, 1500 do
+                    local s, x = fresh(round % 40)
+                    local r = results[round]
+                    if s ~= r[1] or x ~= r[2] then return round end
+                end
+                debug.sethook()
+                return true, fresh
+            ";
+            let check = ctx.load("=check", check).unwrap();
+            let out = ctx.call(check, &results).unwrap();
+            assert_eq!(out[0], Value::Boolean(true));
+            let Value::Function(Function::Closure(fresh)) = out[1] else {
+                unreachable!();
+            };
+            assert!(!matches!(fresh.proto().cache.jit.native.get(), Some(Some(_))));
+        });
+    }
+
+    #[test]
+    fn hooks_go_back_to_the_interpreter() {
+        let mut lua = Lua::with_debug();
+        lua.enter(|ctx| {
+            let source = "
+                local function f(n) local s = 0 for i = 1, n do s = s + i end return s end
+                for _ = 1, 2000 do f(10) end
+                local count = 0
+                debug.sethook(function() count = count + 1 end, '', 1)
+                local s = f(100)
+                debug.sethook()
+                return s, count
+            ";
+            let results = ctx.eval(source).unwrap();
+            assert_eq!(results[0], Value::Integer(5050));
+            let Value::Integer(count) = results[1] else {
+                unreachable!();
+            };
+            assert!(count > 200, "{count}");
+        });
+    }
+}
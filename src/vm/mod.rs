@@ -0,0 +1,2319 @@
+//! The bytecode interpreter.
+//!
+//! Lua-to-Lua calls push a [`Frame`] onto the [`Thread`] and continue in the same interpreter loop, so deep
+//! Lua recursion does not consume Rust stack. Native functions and metamethods are called re-entrantly.
+//!
+//! A coroutine runs its own interpreter loop inside [`Thread::resume`]. A native function called
+//! directly from that loop can yield, which returns from the loop with the thread's frames left in
+//! place to continue from on the next resume. So can a function a native one asks the loop to call
+//! with [`NativeReturn::Call`]: the loop makes the call itself, and afterwards continues the native
+//! function with the [`Sequence`] it left.
+
+mod debug;
+mod fuel;
+#[cfg(feature = "jit")]
+pub(crate) mod jit;
+pub mod ops;
+mod stack;
+mod thread;
+
+pub(crate) use self::debug::Address;
+pub use self::debug::{FrameInfo, Hook, HookMask, ReplacedHook};
+pub(crate) use self::fuel::Fuel;
+pub use self::fuel::{OutOfFuel, ALLOCATION_FUEL_BYTES};
+pub use self::ops::number_to_string;
+pub use self::stack::Stack;
+pub use self::thread::{Thread, ThreadStatus};
+
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::bytecode::{
+    self, Instruction, OpCode, Prototype, UpvalueDesc, FIELDS_PER_FLUSH, RK_CONSTANT,
+};
+use crate::executor;
+use crate::mem::{Managed, Mutation, Tracer};
+use crate::table::MetaEvent;
+use crate::{
+    Closure, Context, Function, LuaError, NativeReturn, PanicError, RuntimeError, Sequence,
+    SourcePosition, Table, UpValue, UpValueState, Value,
+};
+
+use self::debug::{call_hook, inherit_hook, HookState};
+use self::ops::{ArithOp, BitOp, CompareOp, MetaResult};
+use self::thread::ThreadState;
+
+/// The maximum number of values on a thread's stack.
+const MAX_STACK_SIZE: usize = 1_000_000;
+/// The default for [`Context::max_call_depth`].
+pub(crate) const DEFAULT_MAX_CALL_DEPTH: usize = 200_000;
+/// The default for [`Context::native_stack_limit`].
+pub(crate) const DEFAULT_NATIVE_STACK_LIMIT: usize = 1 << 20;
+/// How far past the stack limits a message handler can go, in frames and in stack slots.
+const HANDLER_FRAMES: usize = 200;
+const HANDLER_STACK_SIZE: usize = 20_000;
+/// The maximum number of nested re-entrant calls, each of which uses Rust stack.
+const MAX_NESTING: usize = 200;
+/// The largest capacity of a native function's stack kept for reuse once it returns.
+const MAX_BUFFER: usize = 256;
+
+struct Frame<'gc> {
+    closure: Closure<'gc>,
+    /// The stack index of the function being called. Its arguments follow it, and any extra
+    /// arguments to a vararg function stay there, just below `base`.
+    func: usize,
+    /// The stack index of register 0.
+    base: usize,
+    pc: usize,
+    /// How many results the caller expects, or `None` to keep them all.
+    results: Option<usize>,
+    /// Where a `Concat` waiting on a `__concat` metamethod left off: the stack index of its last
+    /// operand still to be concatenated.
+    concat_top: Option<usize>,
+    /// Whether the function replaced its caller's frame in a tail call.
+    tail_call: bool,
+    /// Set when the instruction at `pc` is about to run again after a hook or a metamethod
+    /// interrupted it, so that the hook doesn't see it twice.
+    traced: bool,
+}
+
+impl<'gc> Frame<'gc> {
+    /// Where the instruction running in the frame came from.
+    fn position(&self) -> SourcePosition {
+        let proto = self.closure.proto();
+        let pc = self.pc.saturating_sub(1);
+        SourcePosition {
+            chunk: proto.chunk_name.to_string(),
+            line: proto.line_at(pc).unwrap_or(0),
+            column: proto.column_at(pc),
+        }
+    }
+
+    /// The `chunk:line:` position of the instruction running in the frame, or `chunk:line:column:`
+    /// with `columns` set and the column known.
+    fn location(&self, columns: bool) -> String {
+        location(&self.position(), columns)
+    }
+}
+
+/// Prefixes the position of the instruction running in `frame` to the message of `err`, which it
+/// raised.
+// Kept out of `dispatch`, whose stack frame every call from native code into Lua adds to.
+#[cold]
+#[inline(never)]
+fn positioned<'gc>(ctx: Context<'gc>, frame: &Frame<'gc>, err: RuntimeError) -> LuaError<'gc> {
+    let position = frame.position();
+    let location = location(&position, ctx.error_columns());
+    let mut err = LuaError::from(RuntimeError::new(format!("{location} {}", err.message())));
+    err.set_position(position);
+    err
+}
+
+fn location(position: &SourcePosition, columns: bool) -> String {
+    match position.column {
+        Some(column) if columns => format!("{}:{}:{column}:", position.chunk, position.line),
+        _ => format!("{}:{}:", position.chunk, position.line),
+    }
+}
+
+unsafe impl<'gc> Managed for Frame<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.closure.trace(tracer);
+    }
+}
+
+/// A native function waiting for a call it made or a yield to finish.
+struct Continuation<'gc> {
+    /// How many frames there were when the function was called. Once the frames of the call it
+    /// made have returned, there are as many again.
+    depth: usize,
+    /// The stack index of the function. The results of its call are above it.
+    func: usize,
+    /// How many results the function's caller expects.
+    results: Option<usize>,
+    /// What the function continues with, or `None` to return the results of its call.
+    sequence: Option<Box<dyn Sequence<'gc> + 'gc>>,
+}
+
+unsafe impl<'gc> Managed for Continuation<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.sequence.trace(tracer);
+    }
+}
+
+/// Calls `function` with `args` on top of the thread's stack and returns all of its results.
+pub fn call<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    function: Value<'gc>,
+    args: &[Value<'gc>],
+) -> Result<Vec<Value<'gc>>, LuaError<'gc>> {
+    let func_idx = call_in_place(ctx, thread, function, args)?;
+    Ok(thread.0.borrow_mut(&ctx).values.split_off(func_idx))
+}
+
+/// Calls `function` like [`call`] and returns only its first result, as metamethods are used.
+fn call_first<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    function: Value<'gc>,
+    args: &[Value<'gc>],
+) -> Result<Value<'gc>, LuaError<'gc>> {
+    let func_idx = call_in_place(ctx, thread, function, args)?;
+    let mut st = thread.0.borrow_mut(&ctx);
+    let first = st.values.get(func_idx).copied().unwrap_or_default();
+    st.values.truncate(func_idx);
+    Ok(first)
+}
+
+/// Calls `function` with `args` on top of the thread's stack, leaving its results on top of the
+/// stack from the returned index, where the function was.
+fn call_in_place<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    function: Value<'gc>,
+    args: &[Value<'gc>],
+) -> Result<usize, LuaError<'gc>> {
+    check_nesting(ctx)?;
+    let nesting = ctx.state().nesting();
+    if nesting.get() == 0 {
+        run_finalizers(ctx, thread);
+    }
+    let (func_idx, depth) = {
+        let mut st = thread.0.borrow_mut(&ctx);
+        let func_idx = st.values.len();
+        st.values.push(function);
+        st.values.extend_from_slice(args);
+        (func_idx, st.frames.len())
+    };
+
+    nesting.set(nesting.get() + 1);
+    let result = match precall(ctx, thread, func_idx, args.len(), None, false) {
+        Ok(Called::Lua) => execute(ctx, thread, depth + 1).map(|yielded| {
+            debug_assert!(yielded.is_none(), "yielded across a native call");
+        }),
+        Ok(Called::Native) => Ok(()),
+        Ok(Called::Yield { .. }) => Err(yield_error(thread)),
+        Err(err) => Err(err),
+    };
+    nesting.set(nesting.get() - 1);
+
+    let mut err = match result {
+        Ok(()) => return Ok(func_idx),
+        Err(err) => err,
+    };
+    {
+        let st = thread.0.borrow();
+        push_traceback(&mut err, &st.frames, &st.sequences, depth);
+    }
+    // The message handler runs before anything unwinds, so it can still inspect the stack.
+    let handler = thread.0.borrow().handlers.last().copied().flatten();
+    if let (false, Some(handler)) = (err.handled, handler) {
+        let value = err.value(ctx);
+        thread.0.borrow_mut(&ctx).in_handler += 1;
+        let result = protected_call(ctx, thread, handler, &[value], None);
+        thread.0.borrow_mut(&ctx).in_handler -= 1;
+        err = match result {
+            Ok(results) => LuaError::new(results.first().copied().unwrap_or_default()),
+            Err(err) => err,
+        };
+        err.handled = true;
+    }
+
+    {
+        let mut st = thread.0.borrow_mut(&ctx);
+        st.frames.truncate(depth);
+        let waiting = st.sequences.iter().take_while(|c| c.depth < depth).count();
+        st.sequences.truncate(waiting);
+    }
+    let err = match close_tbc(ctx, thread, func_idx, Some(err)) {
+        Ok(()) => unreachable!("closing with an error returns an error"),
+        Err(err) => err,
+    };
+    let mut st = thread.0.borrow_mut(&ctx);
+    let st = &mut *st;
+    close_upvalues(&ctx, &st.values, &mut st.open_upvalues, func_idx);
+    st.values.truncate(func_idx);
+    Err(err)
+}
+
+/// Refuses to re-enter the interpreter once too many calls into it are nested, or once they have
+/// used up the native stack they are allowed.
+fn check_nesting(ctx: Context<'_>) -> Result<(), RuntimeError> {
+    let state = ctx.state();
+    let nesting = state.nesting().get();
+    if nesting == 0 {
+        state.stack_base().set(stack_address());
+    } else if nesting >= MAX_NESTING
+        || stack_address().abs_diff(state.stack_base().get()) > ctx.native_stack_limit()
+    {
+        return Err(RuntimeError::new("C stack overflow"));
+    }
+    Ok(())
+}
+
+/// An address in the native stack frame of the caller, or near enough.
+#[inline(never)]
+fn stack_address() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+/// Calls `__close` on the to-be-closed variables at stack index `from` and above, innermost first,
+/// passing each the error being unwound. An error raised while closing replaces it, and is passed
+/// on to the rest.
+fn close_tbc<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    from: usize,
+    mut err: Option<LuaError<'gc>>,
+) -> Result<(), LuaError<'gc>> {
+    loop {
+        let value = {
+            let mut st = thread.0.borrow_mut(&ctx);
+            match st.tbc.last() {
+                Some(&slot) if slot >= from => {
+                    st.tbc.pop();
+                    st.values[slot]
+                }
+                _ => break,
+            }
+        };
+        let close = ops::metamethod(ctx, value, "__close");
+        let error = err.as_ref().map_or(Value::Nil, |err| err.value(ctx));
+        if let Err(e) = call_first(ctx, thread, close, &[value, error]) {
+            err = Some(e);
+        }
+    }
+    err.map_or(Ok(()), Err)
+}
+
+/// Performs `obj[key]` on behalf of a native function, calling an `__index` function if it comes
+/// to that.
+pub fn index<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    obj: Value<'gc>,
+    key: Value<'gc>,
+) -> Result<Value<'gc>, LuaError<'gc>> {
+    match ops::index(ctx, obj, key)? {
+        MetaResult::Value(value) => Ok(value),
+        MetaResult::Call(function, args) => {
+            call_first(ctx, thread, Value::Function(function), &args)
+        }
+    }
+}
+
+/// Performs `obj[key] = value` on behalf of a native function, calling a `__newindex` function if
+/// it comes to that.
+pub fn new_index<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    obj: Value<'gc>,
+    key: Value<'gc>,
+    value: Value<'gc>,
+) -> Result<(), LuaError<'gc>> {
+    if let Some((function, args)) = ops::new_index(ctx, obj, key, value)? {
+        call_first(ctx, thread, Value::Function(function), &args)?;
+    }
+    Ok(())
+}
+
+/// Performs `#value` on behalf of a native function, calling a `__len` metamethod if there is one.
+pub fn len<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    value: Value<'gc>,
+) -> Result<Value<'gc>, LuaError<'gc>> {
+    match ops::len_meta(ctx, value)? {
+        MetaResult::Value(value) => Ok(value),
+        MetaResult::Call(function, args) => {
+            call_first(ctx, thread, Value::Function(function), &args)
+        }
+    }
+}
+
+/// Calls the `__gc` metamethods of the tables the collector has found unreachable since the last
+/// time, most recently marked first.
+///
+/// [`call`] does this on its way into the outermost call, so a host only needs to call it directly
+/// to have finalizers run sooner. Errors raised by finalizers can't propagate anywhere, so they are
+/// reported with [`Context::report_error`] and the remaining finalizers still run.
+pub fn run_finalizers<'gc>(ctx: Context<'gc>, thread: Thread<'gc>) {
+    let finalizers = ctx.state().finalizers();
+    // Counted as nested, so the calls made here don't start on the queue themselves.
+    let nesting = ctx.state().nesting();
+    nesting.set(nesting.get() + 1);
+    loop {
+        let Some(table) = finalizers.borrow_mut(&ctx).pending.pop_front() else {
+            break;
+        };
+        let gc = ops::fast_metamethod(ctx, Value::Table(table), MetaEvent::Gc);
+        if !gc.is_nil() {
+            if let Err(err) = protected_call(ctx, thread, gc, &[Value::Table(table)], None) {
+                ctx.report_error(&err);
+            }
+        }
+    }
+    nesting.set(nesting.get() - 1);
+}
+
+/// Calls `function` like [`call`], as the boundary errors raised inside it stop at.
+///
+/// With a `handler`, the first error raised inside the call is passed to it before the stack
+/// unwinds, as `xpcall` does, and the error returned carries its first result instead. Errors
+/// raised in the handler itself are returned as they are.
+pub fn protected_call<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    function: Value<'gc>,
+    args: &[Value<'gc>],
+    handler: Option<Value<'gc>>,
+) -> Result<Vec<Value<'gc>>, LuaError<'gc>> {
+    thread.0.borrow_mut(&ctx).handlers.push(handler);
+    let result = call(ctx, thread, function, args);
+    thread.0.borrow_mut(&ctx).handlers.pop();
+    result
+}
+
+/// Records the Lua frames from `depth` on that an error is unwinding through, innermost last in
+/// `frames`, along with the native functions among them waiting for a call to finish.
+///
+/// Native functions only show up between Lua frames: one raising an error itself is where the
+/// message says the error came from.
+fn push_traceback<'gc>(
+    err: &mut LuaError<'gc>,
+    frames: &[Frame<'gc>],
+    sequences: &[Continuation<'gc>],
+    depth: usize,
+) {
+    for i in (depth..=frames.len()).rev() {
+        if let Some(frame) = frames.get(i) {
+            err.push_traceback(frame.describe());
+        }
+        for waiting in sequences.iter().rev().filter(|c| c.depth == i) {
+            if !err.traceback().is_empty() {
+                let caller = i.checked_sub(1).map(|j| &frames[j]);
+                err.push_traceback(debug::describe_native(caller, waiting.func));
+            }
+        }
+    }
+}
+
+/// Marks the native function at `func` in the traceback of an error it returned, if the error came
+/// from Lua code it called.
+#[cold]
+fn native_failed<'gc>(thread: Thread<'gc>, func: usize, mut err: LuaError<'gc>) -> LuaError<'gc> {
+    if !err.traceback().is_empty() {
+        let st = thread.0.borrow();
+        err.push_traceback(debug::describe_native(st.frames.last(), func));
+    }
+    err
+}
+
+/// The error for a native function yielding where it can't.
+fn yield_error(thread: Thread<'_>) -> LuaError<'_> {
+    let message = if thread.0.borrow().resume_nesting.is_some() {
+        "attempt to yield across a C-call boundary"
+    } else {
+        "attempt to yield from outside a coroutine"
+    };
+    RuntimeError::new(message).into()
+}
+
+/// Runs the thread's frames until the frame at depth `entry` (counting from 1) returns. Returns the
+/// yielded values instead if a native function called from a coroutine's own loop yields, or no
+/// values if the coroutine is preempted.
+fn execute<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    entry: usize,
+) -> Result<Option<Vec<Value<'gc>>>, LuaError<'gc>> {
+    loop {
+        let called = match dispatch(ctx, thread)? {
+            Action::Call {
+                func,
+                nargs,
+                results,
+                tail,
+            } => precall(ctx, thread, func, nargs, results, tail)?,
+            // A returning frame, like a native function that has returned, may be what a
+            // continuation waits for.
+            Action::Return { .. } => Called::Native,
+            Action::HookedReturn { from, count } => {
+                call_hook(ctx, thread, "return", None)?;
+                let mut st = thread.0.borrow_mut(&ctx);
+                pop_frame(&ctx, &mut st, from, count);
+                Called::Native
+            }
+            Action::TailCall { .. } => unreachable!("tail calls are turned into calls"),
+            Action::OutOfMemory => {
+                // The coroutine of an executor waits for the full collection the limit asked for,
+                // and fails only if that leaves the heap over it.
+                if ctx.state().nesting().get() == 1
+                    && executor::drives(ctx, thread)
+                    && ctx.metrics().collect_before_failing()
+                {
+                    thread.0.borrow_mut(&ctx).preempted = true;
+                    return Ok(Some(Vec::new()));
+                }
+                let message = Value::String(ctx.state().memory_error());
+                return Err(LuaError::new(message));
+            }
+            Action::Collect => {
+                thread.0.borrow_mut(&ctx).preempted = true;
+                return Ok(Some(Vec::new()));
+            }
+            Action::OutOfFuel => {
+                // Only a coroutine resumed from outside of any interpreter loop can stop here: one
+                // resumed from Lua would look to its resumer like it had yielded.
+                let nesting = ctx.state().nesting().get();
+                let mut st = thread.0.borrow_mut(&ctx);
+                if !ctx.state().fuel().preempts() || nesting != 1 || st.resume_nesting != Some(1) {
+                    return Err(LuaError::external(OutOfFuel));
+                }
+                st.preempted = true;
+                return Ok(Some(Vec::new()));
+            }
+            Action::Trace { count, line } => {
+                if count {
+                    call_hook(ctx, thread, "count", None)?;
+                }
+                if let Some(line) = line {
+                    call_hook(ctx, thread, "line", Some(line))?;
+                }
+                let mut st = thread.0.borrow_mut(&ctx);
+                let frame = st.frames.last_mut().expect("no frame to resume");
+                frame.pc -= 1;
+                frame.traced = true;
+                continue;
+            }
+            Action::Meta {
+                function,
+                args,
+                then,
+            } => {
+                let first = call_first(ctx, thread, Value::Function(function), &args)?;
+                let mut st = thread.0.borrow_mut(&ctx);
+                match then {
+                    Then::Store(idx) => st.values[idx] = first,
+                    Then::Discard => {}
+                    Then::Test { expect } => {
+                        if first.to_bool() != expect {
+                            st.frames.last_mut().expect("no frame to resume").pc += 1;
+                        }
+                    }
+                    Then::Concat(idx) => {
+                        st.values[idx] = first;
+                        let frame = st.frames.last_mut().expect("no frame to resume");
+                        frame.concat_top = Some(idx);
+                        frame.pc -= 1;
+                        frame.traced = true;
+                    }
+                }
+                continue;
+            }
+            Action::Close { function, value } => {
+                close_variable(ctx, thread, function, value)?;
+                continue;
+            }
+        };
+        let called = match called {
+            Called::Native => run_sequences(ctx, thread, entry)?,
+            called => called,
+        };
+        match called {
+            Called::Lua => {}
+            // After a tail call, the native function's results were the frame's own.
+            Called::Native => {
+                if thread.0.borrow().frames.len() < entry {
+                    return Ok(None);
+                }
+            }
+            Called::Yield { func, results } => {
+                let resume_nesting = thread.0.borrow().resume_nesting;
+                if resume_nesting != Some(ctx.state().nesting().get()) {
+                    return Err(yield_error(thread));
+                }
+                return Ok(Some(thread.take_yield(&ctx, func, results)));
+            }
+        }
+    }
+}
+
+/// What the interpreter loop needs done before it can continue.
+enum Action<'gc> {
+    /// Call the function at stack index `func` with the `nargs` values above it.
+    Call {
+        func: usize,
+        nargs: usize,
+        results: Option<usize>,
+        /// Whether this is what a tail call turned into.
+        tail: bool,
+    },
+    /// Return the `count` values starting at stack index `from` from the current frame.
+    Return { from: usize, count: usize },
+    /// Call the return hook, then return like [`Action::Return`]. [`dispatch`] turns returns into
+    /// this while a hook wants them.
+    HookedReturn { from: usize, count: usize },
+    /// Replace the current frame with a call to the function at stack index `func` with the `nargs`
+    /// values above it. [`dispatch`] turns this into a [`Action::Call`] on the caller's behalf.
+    TailCall { func: usize, nargs: usize },
+    /// Call the hook for a count event, a new line or both, then run the instruction before the
+    /// frame's `pc`, which they interrupted.
+    Trace { count: bool, line: Option<u32> },
+    /// Raise [`OutOfFuel`], leaving the frame about to run the instruction it couldn't pay for.
+    OutOfFuel,
+    /// Stop the coroutine for the executor resuming it to collect garbage, leaving the frame about
+    /// to run the next instruction.
+    Collect,
+    /// Raise "not enough memory", the heap having grown past its limit, or first stop the coroutine
+    /// of an executor for a full collection. The instruction about to run is left to run again if
+    /// the frame is resumed.
+    OutOfMemory,
+    /// Call the `__close` metamethod `function` of the to-be-closed variable `value`, going out of
+    /// scope without an error, and discard its results.
+    Close {
+        function: Function<'gc>,
+        value: Value<'gc>,
+    },
+    /// Call a metamethod, then deal with its first result.
+    Meta {
+        function: Function<'gc>,
+        args: Vec<Value<'gc>>,
+        then: Then,
+    },
+}
+
+/// What to do with the result of a metamethod.
+enum Then {
+    /// Store it at the given stack index.
+    Store(usize),
+    Discard,
+    /// Skip the next instruction unless its truthiness matches `expect`.
+    Test {
+        expect: bool,
+    },
+    /// Store it at the given stack index and run the interrupted `Concat` again, with the operands
+    /// above that index done with.
+    Concat(usize),
+}
+
+/// How [`precall`] left a call.
+enum Called {
+    /// A Lua frame was pushed that still needs to be executed.
+    Lua,
+    /// A native function ran and its results are in place.
+    Native,
+    /// The native function at stack index `func` asked to yield the values it left above it.
+    Yield { func: usize, results: Option<usize> },
+}
+
+/// Prepares a call to the value at `func_idx`, made by a tail call if `tail` is set. Native functions
+/// run to completion immediately.
+fn precall<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    func_idx: usize,
+    nargs: usize,
+    results: Option<usize>,
+    tail: bool,
+) -> Result<Called, LuaError<'gc>> {
+    let mut st = thread.0.borrow_mut(&ctx);
+    inherit_hook(ctx, &mut st.hook);
+    st.values.truncate(func_idx + 1 + nargs);
+    let native = loop {
+        match st.values[func_idx] {
+            Value::Function(Function::Closure(closure)) => {
+                let proto = closure.proto();
+                let num_params = proto.num_params as usize;
+                let base = if proto.is_vararg {
+                    // The fixed parameters move above the extra arguments, which stay where they
+                    // are for `VARARG` to copy from.
+                    func_idx + 1 + nargs.max(num_params)
+                } else {
+                    func_idx + 1
+                };
+                let top = base + proto.max_stack as usize;
+                let (max_stack, max_frames) = if st.in_handler > 0 {
+                    (
+                        MAX_STACK_SIZE + HANDLER_STACK_SIZE,
+                        ctx.max_call_depth() + HANDLER_FRAMES,
+                    )
+                } else {
+                    (MAX_STACK_SIZE, ctx.max_call_depth())
+                };
+                if top > max_stack || st.frames.len() >= max_frames {
+                    // Blamed on the calling line, as errors raised by an instruction are.
+                    let message = match st.frames.last() {
+                        Some(caller) => {
+                            format!("{} stack overflow", caller.location(ctx.error_columns()))
+                        }
+                        None => "stack overflow".to_owned(),
+                    };
+                    return Err(RuntimeError::new(message).into());
+                }
+                if proto.is_vararg {
+                    st.values.resize(top, Value::Nil);
+                    let params = func_idx + 1..func_idx + 1 + num_params.min(nargs);
+                    st.values.copy_within(params.clone(), base);
+                    st.values[params].fill(Value::Nil);
+                } else {
+                    st.values.truncate(base + num_params);
+                    st.values.resize(top, Value::Nil);
+                }
+                st.frames.push(Frame {
+                    closure,
+                    func: func_idx,
+                    base,
+                    pc: 0,
+                    results,
+                    concat_top: None,
+                    tail_call: tail,
+                    traced: false,
+                });
+                if st.hook.as_ref().is_some_and(HookState::wants_call) {
+                    drop(st);
+                    call_hook(ctx, thread, call_event(tail), None)?;
+                }
+                return Ok(Called::Lua);
+            }
+            Value::Function(native) => break native,
+            value => {
+                let handler = ops::metamethod(ctx, value, "__call");
+                if !matches!(handler, Value::Function(_)) {
+                    return Err(RuntimeError::new(format!(
+                        "attempt to call a {} value",
+                        value.type_name()
+                    ))
+                    .into());
+                }
+                st.values.insert(func_idx, handler);
+            }
+        }
+    };
+    if st.hook.as_ref().is_some_and(HookState::wants_call) {
+        drop(st);
+        call_hook(ctx, thread, call_event(tail), None)?;
+        st = thread.0.borrow_mut(&ctx);
+    }
+    // Only the arguments are moved out, so that the rest of the stack stays reachable through open
+    // upvalues while the function runs.
+    let mut args = take_args(&mut st, func_idx);
+    drop(st);
+    let mut stack = Stack::new(thread, &mut args, 0).with_func(func_idx);
+    if let Function::NativeClosure(c) = native {
+        stack = stack.with_upvalues(c.upvalues());
+    }
+    let result = run_native(ctx, || match native {
+        Function::Native(f) => f(ctx, &mut stack),
+        Function::NativeClosure(c) => (c.function())(ctx, &mut stack),
+        Function::Callback(c) => c.call(ctx, &mut stack),
+        Function::Closure(_) => unreachable!("Lua functions push a frame instead"),
+    });
+    let then = stack.take_then();
+    put_back_args(&mut thread.0.borrow_mut(&ctx), args);
+    let returned = result.map_err(|err| native_failed(thread, func_idx, err))?;
+    native_returned(ctx, thread, func_idx, results, returned, then)
+}
+
+/// Calls the `__close` metamethod `function` of `value`, which went out of scope without an error.
+/// The arguments go in a buffer a native call left, rather than one of their own for every variable
+/// closed.
+#[inline(never)]
+fn close_variable<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    function: Function<'gc>,
+    value: Value<'gc>,
+) -> Result<(), LuaError<'gc>> {
+    let mut args = thread.0.borrow_mut(&ctx).buffers.pop().unwrap_or_default();
+    args.extend([value, Value::Nil]);
+    let result = call_first(ctx, thread, Value::Function(function), &args);
+    args.clear();
+    put_back_args(&mut thread.0.borrow_mut(&ctx), args);
+    result.map(drop)
+}
+
+/// Moves the values above the native function at `func_idx` into a buffer of their own for it to
+/// run with, reusing one an earlier call left.
+fn take_args<'gc>(st: &mut ThreadState<'gc>, func_idx: usize) -> Vec<Value<'gc>> {
+    let mut args = st.buffers.pop().unwrap_or_default();
+    args.extend(st.values.drain(func_idx + 1..));
+    args
+}
+
+/// Puts the values a native function left in `args` back on top of the stack, keeping the buffer
+/// for the next call unless it grew large.
+fn put_back_args<'gc>(st: &mut ThreadState<'gc>, mut args: Vec<Value<'gc>>) {
+    st.values.append(&mut args);
+    if args.capacity() <= MAX_BUFFER {
+        st.buffers.push(args);
+    }
+}
+
+/// Runs the Rust code of a native function, turning a panic into an error if the state catches them.
+fn run_native<'gc>(
+    ctx: Context<'gc>,
+    f: impl FnOnce() -> Result<NativeReturn, LuaError<'gc>>,
+) -> Result<NativeReturn, LuaError<'gc>> {
+    if !ctx.catch_panics() {
+        return f();
+    }
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(LuaError::external(PanicError::new(payload))))
+}
+
+/// Does what the native function at `func_idx` asked for as it returned, with the values it left
+/// above it. `then` is the sequence to continue it with after a yield or a call.
+fn native_returned<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    func_idx: usize,
+    results: Option<usize>,
+    returned: NativeReturn,
+    then: Option<Box<dyn Sequence<'gc> + 'gc>>,
+) -> Result<Called, LuaError<'gc>> {
+    let mut st = thread.0.borrow_mut(&ctx);
+    match returned {
+        NativeReturn::Return => {
+            if st.hook.as_ref().is_some_and(HookState::wants_return) {
+                drop(st);
+                call_hook(ctx, thread, "return", None)?;
+                st = thread.0.borrow_mut(&ctx);
+            }
+            let count = st.values.len() - (func_idx + 1);
+            finish_results(&mut st, func_idx, func_idx + 1, count, results);
+            Ok(Called::Native)
+        }
+        NativeReturn::Yield => {
+            if let Some(sequence) = then {
+                let depth = st.frames.len();
+                st.sequences.push(Continuation {
+                    depth,
+                    func: func_idx,
+                    results,
+                    sequence: Some(sequence),
+                });
+            }
+            Ok(Called::Yield {
+                func: func_idx,
+                results,
+            })
+        }
+        NativeReturn::Call => {
+            if st.values.len() == func_idx + 1 {
+                st.values.push(Value::Nil);
+            }
+            let nargs = st.values.len() - (func_idx + 2);
+            let depth = st.frames.len();
+            st.sequences.push(Continuation {
+                depth,
+                func: func_idx,
+                results,
+                sequence: then,
+            });
+            drop(st);
+            match precall(ctx, thread, func_idx + 1, nargs, None, false)? {
+                Called::Native => continue_sequence(ctx, thread),
+                called => Ok(called),
+            }
+        }
+    }
+}
+
+/// Continues the innermost waiting native function, whose call or yield is over with the results
+/// above its stack slot.
+fn continue_sequence<'gc>(ctx: Context<'gc>, thread: Thread<'gc>) -> Result<Called, LuaError<'gc>> {
+    let mut st = thread.0.borrow_mut(&ctx);
+    let continuation = st.sequences.pop().expect("no sequence to continue");
+    let (func, results) = (continuation.func, continuation.results);
+    let Some(mut sequence) = continuation.sequence else {
+        drop(st);
+        return native_returned(ctx, thread, func, results, NativeReturn::Return, None);
+    };
+    let mut args = take_args(&mut st, func);
+    drop(st);
+    let mut stack = Stack::new(thread, &mut args, 0).with_func(func);
+    let result = run_native(ctx, || sequence.step(ctx, &mut stack));
+    let then = stack.take_then();
+    put_back_args(&mut thread.0.borrow_mut(&ctx), args);
+    let returned = result.map_err(|err| native_failed(thread, func, err))?;
+    native_returned(
+        ctx,
+        thread,
+        func,
+        results,
+        returned,
+        then.or(Some(sequence)),
+    )
+}
+
+/// Continues the native functions whose calls have returned, for as long as the innermost one
+/// belongs to the loop running the frames from depth `entry` (counting from 1). Returns what the
+/// last of them did: anything but returning leaves the rest waiting.
+fn run_sequences<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    entry: usize,
+) -> Result<Called, LuaError<'gc>> {
+    loop {
+        let ready = {
+            let st = thread.0.borrow();
+            st.sequences
+                .last()
+                .is_some_and(|c| c.depth == st.frames.len() && c.depth + 1 >= entry)
+        };
+        if !ready {
+            return Ok(Called::Native);
+        }
+        match continue_sequence(ctx, thread)? {
+            Called::Native => {}
+            called => return Ok(called),
+        }
+    }
+}
+
+/// Moves `count` results starting at `from` down to `func_idx`, adjusting them to the number the caller expects.
+fn finish_results(
+    st: &mut ThreadState<'_>,
+    func_idx: usize,
+    from: usize,
+    count: usize,
+    expected: Option<usize>,
+) {
+    st.values.copy_within(from..from + count, func_idx);
+    match expected {
+        Some(n) => {
+            st.values.truncate(func_idx + count.min(n));
+            st.values.resize(func_idx + n, Value::Nil);
+            if let Some(frame) = st.frames.last() {
+                let top = frame.base + frame.closure.proto().max_stack as usize;
+                st.values.resize(top.max(func_idx + n), Value::Nil);
+            }
+        }
+        None => st.values.truncate(func_idx + count),
+    }
+}
+
+fn call_event(tail: bool) -> &'static str {
+    if tail {
+        "tail call"
+    } else {
+        "call"
+    }
+}
+
+/// Pops the returning topmost frame, moving the `count` results starting at stack index `from` to
+/// where its caller expects them.
+fn pop_frame<'gc>(mc: &Mutation<'gc>, st: &mut ThreadState<'gc>, from: usize, count: usize) {
+    let frame = st.frames.pop().expect("no frame to return from");
+    close_upvalues(mc, &st.values, &mut st.open_upvalues, frame.base);
+    finish_results(st, frame.func, from, count, frame.results);
+    if let (Some(hook), Some(caller)) = (st.hook.as_mut(), st.frames.last()) {
+        hook.returned_to(caller.pc.saturating_sub(1));
+    }
+}
+
+/// Runs the topmost frame until it needs to call out or return, popping it if it returned.
+fn dispatch<'gc>(ctx: Context<'gc>, thread: Thread<'gc>) -> Result<Action<'gc>, LuaError<'gc>> {
+    let mut st = thread.0.borrow_mut(&ctx);
+    let st = &mut *st;
+    inherit_hook(ctx, &mut st.hook);
+    let frame = st.frames.last_mut().expect("no frame to execute");
+    let (func, base) = (frame.func, frame.base);
+
+    let result = run(
+        ctx,
+        thread,
+        &mut st.values,
+        &mut st.open_upvalues,
+        &mut st.tbc,
+        &mut st.hook,
+        frame,
+    );
+    let result = result.map_err(|err| positioned(ctx, frame, err))?;
+
+    match result {
+        Action::Return { from, count } => {
+            if st.hook.as_ref().is_some_and(HookState::wants_return) {
+                return Ok(Action::HookedReturn { from, count });
+            }
+            pop_frame(&ctx, st, from, count);
+            Ok(result)
+        }
+        Action::TailCall { func: from, nargs } => {
+            close_upvalues(&ctx, &st.values, &mut st.open_upvalues, base);
+            let frame = st.frames.pop().expect("no frame to return from");
+            st.values.copy_within(from..from + 1 + nargs, func);
+            st.values.truncate(func + 1 + nargs);
+            Ok(Action::Call {
+                func,
+                nargs,
+                results: frame.results,
+                tail: true,
+            })
+        }
+        _ => Ok(result),
+    }
+}
+
+/// Reads an `RK` operand.
+#[inline]
+fn rk<'gc>(values: &[Value<'gc>], constants: &[Value<'gc>], base: usize, x: u32) -> Value<'gc> {
+    if bytecode::is_constant(x) {
+        constants[(x & !RK_CONSTANT) as usize]
+    } else {
+        values[base + x as usize]
+    }
+}
+
+/// Reads an upvalue, given the stack of the running `thread`, which is borrowed while it runs.
+fn upvalue_value<'gc>(
+    thread: Thread<'gc>,
+    values: &[Value<'gc>],
+    upvalue: UpValue<'gc>,
+) -> Value<'gc> {
+    match upvalue.get() {
+        UpValueState::Open { thread: t, index } if t == thread => values[index],
+        UpValueState::Open { thread: t, index } => t.0.borrow().values[index],
+        UpValueState::Closed(v) => v,
+    }
+}
+
+/// Reads upvalue `u` for indexing, from `env` if it is the upvalue cached there. A closed upvalue
+/// read is cached in its place.
+#[inline]
+fn env_upvalue<'gc>(
+    env: &mut Option<(u32, Value<'gc>)>,
+    thread: Thread<'gc>,
+    values: &[Value<'gc>],
+    upvalues: &[UpValue<'gc>],
+    u: u32,
+) -> Value<'gc> {
+    if let Some((cached, value)) = *env {
+        if cached == u {
+            return value;
+        }
+    }
+    let upvalue = upvalues[u as usize];
+    match upvalue.get() {
+        UpValueState::Closed(value) => {
+            *env = Some((u, value));
+            value
+        }
+        _ => upvalue_value(thread, values, upvalue),
+    }
+}
+
+/// Returns the open upvalue for the stack slot `index`, creating it if this is the first closure to
+/// capture the slot.
+fn find_upvalue<'gc>(
+    mc: &Mutation<'gc>,
+    thread: Thread<'gc>,
+    open_upvalues: &mut Vec<UpValue<'gc>>,
+    index: usize,
+) -> UpValue<'gc> {
+    let position = open_upvalues.binary_search_by_key(&index, |upvalue| match upvalue.get() {
+        UpValueState::Open { index, .. } => index,
+        UpValueState::Closed(_) => unreachable!("closed upvalue in the open list"),
+    });
+    match position {
+        Ok(i) => open_upvalues[i],
+        Err(i) => {
+            let upvalue = UpValue::new(mc, UpValueState::Open { thread, index });
+            open_upvalues.insert(i, upvalue);
+            upvalue
+        }
+    }
+}
+
+/// Closes the open upvalues for stack slot `from` and above, moving their values into them.
+fn close_upvalues<'gc>(
+    mc: &Mutation<'gc>,
+    values: &[Value<'gc>],
+    open_upvalues: &mut Vec<UpValue<'gc>>,
+    from: usize,
+) {
+    let start = open_upvalues.partition_point(|upvalue| match upvalue.get() {
+        UpValueState::Open { index, .. } => index < from,
+        UpValueState::Closed(_) => true,
+    });
+    for upvalue in open_upvalues.drain(start..) {
+        if let UpValueState::Open { index, .. } = upvalue.get() {
+            upvalue.set(mc, UpValueState::Closed(values[index]));
+        }
+    }
+}
+
+/// An operand of the instruction being run, as far as naming the variable behind it goes.
+#[derive(Copy, Clone)]
+enum Operand {
+    Register(u32),
+    Rk(u32),
+    Upvalue(u32),
+    /// The iterator function of a generic `for`.
+    ForIterator,
+}
+
+/// Adds the name of the variable behind an operand to an error about that operand's type, so
+/// `attempt to index a nil value` becomes `attempt to index a nil value (global 'x')`.
+///
+/// `candidates` are the operands that may be at fault, in the order the error would blame them,
+/// each with its value. Errors that aren't about any of them, like those raised further along a
+/// chain of `__index` tables, are returned as they are.
+fn name_culprit<'gc>(
+    err: RuntimeError,
+    proto: &Prototype<'gc>,
+    pc: usize,
+    candidates: &[(Value<'gc>, Operand)],
+) -> RuntimeError {
+    let message = err.message();
+    let no_integer = message == "number has no integer representation";
+    let arithmetic = message.starts_with("attempt to perform");
+    let at_fault = |value: Value<'gc>| {
+        if no_integer {
+            matches!(value, Value::Number(_)) && value.to_integer().is_none()
+        } else {
+            message.ends_with(&format!(" a {} value", value.type_name()))
+                && !(arithmetic && ops::coerce_number(value).is_some())
+        }
+    };
+    let Some(&(_, operand)) = candidates.iter().find(|(value, _)| at_fault(*value)) else {
+        return err;
+    };
+    let name = match operand {
+        Operand::Register(reg) => proto.register_name(pc, reg),
+        Operand::Rk(rk) => proto.rk_name(pc, rk),
+        Operand::Upvalue(index) => proto
+            .upvalue_names
+            .get(index as usize)
+            .map(|&name| ("upvalue", name)),
+        Operand::ForIterator => {
+            return RuntimeError::new(format!("{message} (for iterator 'for iterator')"))
+        }
+    };
+    match name {
+        Some((kind, name)) if no_integer => RuntimeError::new(format!(
+            "number ({kind} '{name}') has no integer representation"
+        )),
+        Some((kind, name)) => RuntimeError::new(format!("{message} ({kind} '{name}')")),
+        None => err,
+    }
+}
+
+fn run<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    values: &mut Vec<Value<'gc>>,
+    open_upvalues: &mut Vec<UpValue<'gc>>,
+    tbc: &mut Vec<usize>,
+    hook: &mut Option<HookState<'gc>>,
+    frame: &mut Frame<'gc>,
+) -> Result<Action<'gc>, RuntimeError> {
+    let (closure, func, base) = (frame.closure, frame.func, frame.base);
+    let retraced = &mut frame.traced;
+    let mut traced = std::mem::take(retraced);
+    // An instruction run again after an interruption has been paid for.
+    let mut paid = traced;
+    let fuel = ctx.state().fuel();
+    let pc = &mut frame.pc;
+    let concat_top = &mut frame.concat_top;
+    let proto = closure.proto().as_ref();
+    let upvalues = closure.upvalues();
+    let code = &proto.code;
+    let k = &proto.constants;
+
+    // Unwraps the result of an operation, naming the variable behind the operand at fault if it
+    // failed over an operand's type.
+    macro_rules! blame {
+        ($result:expr, $($candidate:expr),+) => {
+            match $result {
+                Ok(result) => result,
+                Err(err) => {
+                    return Err(name_culprit(err, proto, *pc - 1, &[$($candidate),+]));
+                }
+            }
+        };
+    }
+
+    macro_rules! store {
+        ($idx:expr, $result:expr) => {
+            match $result {
+                MetaResult::Value(v) => values[$idx] = v,
+                MetaResult::Call(function, args) => {
+                    return Ok(Action::Meta {
+                        function,
+                        args,
+                        then: Then::Store($idx),
+                    })
+                }
+            }
+        };
+    }
+
+    // Metering and hooks are only turned on or off by code running outside of this loop, so they
+    // are looked at once rather than before each instruction.
+    let metered = fuel.is_metered();
+    let hooked = hook.is_some();
+    // Set by the instructions that may grow the heap, for the next one to check the limit.
+    let mut allocated = true;
+    // Whether the loop can stop for the collector, looked at once there is collecting to do. Only
+    // the coroutine an executor resumes can, from its own loop, which runs at the first level.
+    let mut driven = None;
+    // The closed upvalue that `GetTabUp` and `SetTabUp` last indexed, usually `_ENV`, and its
+    // value. Only `SetUpval` can change a closed upvalue without a call, which ends this loop.
+    let mut env: Option<(u32, Value<'gc>)> = None;
+    // The native code of the function once it is hot, which can't meter fuel or call hooks.
+    #[cfg(feature = "jit")]
+    let mut native = match metered || hooked {
+        true => None,
+        false => jit::tick(proto),
+    };
+    // Set when the native code gave back the instruction at `pc`, for the interpreter to run.
+    #[cfg(feature = "jit")]
+    let mut interpret = false;
+
+    loop {
+        if allocated
+            && ctx.metrics().collection_due()
+            && *driven.get_or_insert_with(|| {
+                ctx.state().nesting().get() == 1 && executor::drives(ctx, thread)
+            })
+        {
+            return Ok(Action::Collect);
+        }
+        if metered && !std::mem::take(&mut paid) && !fuel.consume(ctx) {
+            return Ok(Action::OutOfFuel);
+        }
+        if std::mem::take(&mut allocated) && ctx.metrics().exceeds_limit() {
+            return Ok(Action::OutOfMemory);
+        }
+        if let (true, Some(hook)) = (hooked, hook.as_mut()) {
+            if !std::mem::take(&mut traced) {
+                if let Some((count, line)) = hook.trace(proto, *pc) {
+                    *pc += 1;
+                    return Ok(Action::Trace { count, line });
+                }
+            }
+        }
+        #[cfg(feature = "jit")]
+        if let Some(native) = native {
+            if !std::mem::take(&mut interpret) && native.enters(*pc) {
+                *pc = native.run(ctx, &mut values[base..], proto, *pc);
+                interpret = true;
+                allocated = true;
+                continue;
+            }
+        }
+        let i = code[*pc];
+        *pc += 1;
+        let ra = base + i.a() as usize;
+        let op = i
+            .opcode()
+            .ok_or_else(|| RuntimeError::new("invalid instruction"))?;
+
+        match op {
+            OpCode::Move => values[ra] = values[base + i.b() as usize],
+            OpCode::LoadK => values[ra] = k[i.bx() as usize],
+            OpCode::LoadI => values[ra] = Value::Integer(i.sbx() as i64),
+            OpCode::LoadBool => {
+                values[ra] = Value::Boolean(i.b() != 0);
+                if i.c() != 0 {
+                    *pc += 1;
+                }
+            }
+            OpCode::LoadNil => values[ra..=ra + i.b() as usize].fill(Value::Nil),
+            OpCode::GetTabUp => {
+                let table = env_upvalue(&mut env, thread, values, upvalues, i.b());
+                let key = rk(values, k, base, i.c());
+                let result = blame!(
+                    ops::index_hinted(ctx, table, key, proto.cache.hint(*pc - 1)),
+                    (table, Operand::Upvalue(i.b()))
+                );
+                store!(ra, result);
+            }
+            OpCode::SetTabUp => {
+                allocated = true;
+                let table = env_upvalue(&mut env, thread, values, upvalues, i.a());
+                let key = rk(values, k, base, i.b());
+                let value = rk(values, k, base, i.c());
+                let meta = blame!(
+                    ops::new_index_hinted(ctx, table, key, value, proto.cache.hint(*pc - 1)),
+                    (table, Operand::Upvalue(i.a()))
+                );
+                if let Some((function, args)) = meta {
+                    return Ok(Action::Meta {
+                        function,
+                        args,
+                        then: Then::Discard,
+                    });
+                }
+            }
+            OpCode::GetUpval => {
+                values[ra] = upvalue_value(thread, values, upvalues[i.b() as usize]);
+            }
+            OpCode::SetUpval => {
+                env = None;
+                let upvalue = upvalues[i.b() as usize];
+                match upvalue.get() {
+                    UpValueState::Open { thread: t, index } if t == thread => {
+                        values[index] = values[ra]
+                    }
+                    UpValueState::Open { thread: t, index } => {
+                        t.0.borrow_mut(&ctx).values[index] = values[ra];
+                    }
+                    UpValueState::Closed(_) => upvalue.set(&ctx, UpValueState::Closed(values[ra])),
+                }
+            }
+            OpCode::GetTable => {
+                let obj = values[base + i.b() as usize];
+                let key = rk(values, k, base, i.c());
+                let result = blame!(
+                    ops::index_hinted(ctx, obj, key, proto.cache.hint(*pc - 1)),
+                    (obj, Operand::Register(i.b()))
+                );
+                store!(ra, result);
+            }
+            OpCode::SetTable => {
+                allocated = true;
+                let key = rk(values, k, base, i.b());
+                let value = rk(values, k, base, i.c());
+                let hint = proto.cache.hint(*pc - 1);
+                let meta = blame!(
+                    ops::new_index_hinted(ctx, values[ra], key, value, hint),
+                    (values[ra], Operand::Register(i.a()))
+                );
+                if let Some((function, args)) = meta {
+                    return Ok(Action::Meta {
+                        function,
+                        args,
+                        then: Then::Discard,
+                    });
+                }
+            }
+            OpCode::NewTable => {
+                allocated = true;
+                values[ra] =
+                    Value::Table(Table::with_capacity(&ctx, i.b() as usize, i.c() as usize));
+            }
+            OpCode::Method => {
+                let obj = values[base + i.b() as usize];
+                values[ra + 1] = obj;
+                let key = rk(values, k, base, i.c());
+                let result = blame!(
+                    ops::index_hinted(ctx, obj, key, proto.cache.hint(*pc - 1)),
+                    (obj, Operand::Register(i.b()))
+                );
+                store!(ra, result);
+            }
+            OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Mod
+            | OpCode::Pow
+            | OpCode::Div
+            | OpCode::IDiv => {
+                let (b, c) = (rk(values, k, base, i.b()), rk(values, k, base, i.c()));
+                if let Some(v) = ops::arith_fast(arith_op(op), b, c) {
+                    values[ra] = v;
+                    continue;
+                }
+                let result = operation(ctx, values, proto, *pc - 1, base, i, op)?;
+                store!(ra, result);
+            }
+            OpCode::Unm
+            | OpCode::BAnd
+            | OpCode::BOr
+            | OpCode::BXor
+            | OpCode::Shl
+            | OpCode::Shr
+            | OpCode::BNot
+            | OpCode::Len => {
+                let result = operation(ctx, values, proto, *pc - 1, base, i, op)?;
+                store!(ra, result);
+            }
+            OpCode::Not => values[ra] = Value::Boolean(!values[base + i.b() as usize].to_bool()),
+            OpCode::Concat => {
+                allocated = true;
+                if let Some(action) = concat(ctx, values, concat_top, proto, *pc - 1, base, i)? {
+                    return Ok(action);
+                }
+            }
+            OpCode::Jmp => {
+                if i.a() != 0 {
+                    // Each variable closed comes back here, until none are left.
+                    if let Some(action) = next_tbc(ctx, values, tbc, ra - 1)? {
+                        *pc -= 1;
+                        *retraced = true;
+                        return Ok(action);
+                    }
+                    close_upvalues(&ctx, values, open_upvalues, ra - 1);
+                }
+                jump(pc, i.sbx());
+            }
+            OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                let op = match op {
+                    OpCode::Eq => CompareOp::Eq,
+                    OpCode::Lt => CompareOp::Lt,
+                    _ => CompareOp::Le,
+                };
+                let expect = i.a() != 0;
+                let (b, c) = (rk(values, k, base, i.b()), rk(values, k, base, i.c()));
+                if let Some(result) = ops::compare_fast(op, b, c) {
+                    if result != expect {
+                        *pc += 1;
+                    }
+                    continue;
+                }
+                match ops::compare_meta(ctx, op, b, c)? {
+                    MetaResult::Value(v) => {
+                        if v.to_bool() != expect {
+                            *pc += 1;
+                        }
+                    }
+                    MetaResult::Call(function, args) => {
+                        return Ok(Action::Meta {
+                            function,
+                            args,
+                            then: Then::Test { expect },
+                        })
+                    }
+                }
+            }
+            OpCode::Test => {
+                if values[ra].to_bool() != (i.c() != 0) {
+                    *pc += 1;
+                }
+            }
+            OpCode::TestSet => {
+                let v = values[base + i.b() as usize];
+                if v.to_bool() == (i.c() != 0) {
+                    values[ra] = v;
+                } else {
+                    *pc += 1;
+                }
+            }
+            OpCode::Call => {
+                blame!(
+                    ops::check_callable(ctx, values[ra]),
+                    (values[ra], Operand::Register(i.a()))
+                );
+                let nargs = match i.b() {
+                    0 => values.len() - ra - 1,
+                    b => b as usize - 1,
+                };
+                let results = match i.c() {
+                    0 => None,
+                    c => Some(c as usize - 1),
+                };
+                return Ok(Action::Call {
+                    func: ra,
+                    nargs,
+                    results,
+                    tail: false,
+                });
+            }
+            OpCode::TailCall => {
+                blame!(
+                    ops::check_callable(ctx, values[ra]),
+                    (values[ra], Operand::Register(i.a()))
+                );
+                let nargs = match i.b() {
+                    0 => values.len() - ra - 1,
+                    b => b as usize - 1,
+                };
+                return Ok(Action::TailCall { func: ra, nargs });
+            }
+            OpCode::Return => {
+                if let Some(action) = next_tbc(ctx, values, tbc, base)? {
+                    *pc -= 1;
+                    *retraced = true;
+                    return Ok(action);
+                }
+                let count = match i.b() {
+                    0 => values.len() - ra,
+                    b => b as usize - 1,
+                };
+                return Ok(Action::Return { from: ra, count });
+            }
+            OpCode::ForPrep => {
+                // Jumps straight into the body, or past the `FORLOOP` if it doesn't run at all.
+                let forloop = (*pc as isize + i.sbx() as isize) as usize;
+                if for_prep(values, ra)? {
+                    values[ra + 3] = values[ra];
+                    *pc = forloop + 1;
+                    jump(pc, code[forloop].sbx());
+                } else {
+                    *pc = forloop + 1;
+                }
+            }
+            OpCode::ForLoop => {
+                let next = match (values[ra], values[ra + 1], values[ra + 2]) {
+                    (Value::Integer(idx), Value::Integer(remaining), Value::Integer(step)) => {
+                        // The iteration count is unsigned, stored in the limit's slot.
+                        (remaining != 0).then(|| {
+                            values[ra + 1] = Value::Integer((remaining as u64 - 1) as i64);
+                            Value::Integer(idx.wrapping_add(step))
+                        })
+                    }
+                    (Value::Number(idx), Value::Number(limit), Value::Number(step)) => {
+                        let idx = idx + step;
+                        let cont = if step > 0.0 {
+                            idx <= limit
+                        } else {
+                            limit <= idx
+                        };
+                        cont.then_some(Value::Number(idx))
+                    }
+                    _ => unreachable!("'for' loop state was not prepared"),
+                };
+                if let Some(idx) = next {
+                    values[ra] = idx;
+                    values[ra + 3] = idx;
+                    jump(pc, i.sbx());
+                    #[cfg(feature = "jit")]
+                    if native.is_none() && !metered && !hooked {
+                        native = jit::tick(proto);
+                    }
+                }
+            }
+            OpCode::TForCall => {
+                blame!(
+                    ops::check_callable(ctx, values[ra]),
+                    (values[ra], Operand::ForIterator)
+                );
+                values.copy_within(ra..ra + 3, ra + 4);
+                return Ok(Action::Call {
+                    func: ra + 4,
+                    nargs: 2,
+                    results: Some(i.c() as usize),
+                    tail: false,
+                });
+            }
+            OpCode::TForLoop => {
+                let control = values[ra + 4];
+                if !control.is_nil() {
+                    values[ra + 2] = control;
+                    jump(pc, i.sbx());
+                }
+            }
+            OpCode::SetList => {
+                allocated = true;
+                set_list(ctx, values, proto, pc, base, i)?;
+            }
+            OpCode::Closure => {
+                allocated = true;
+                values[ra] = new_closure(ctx, thread, open_upvalues, proto, upvalues, base, i);
+            }
+            OpCode::VarArg => var_arg(values, proto, func, base, i),
+            OpCode::Tbc => to_be_closed(ctx, values, tbc, k, ra, i)?,
+            OpCode::ExtraArg => return Err(RuntimeError::new("unexpected EXTRAARG instruction")),
+        }
+    }
+}
+
+/// Returns the arithmetic operation that an arithmetic opcode performs.
+#[inline]
+fn arith_op(op: OpCode) -> ArithOp {
+    match op {
+        OpCode::Add => ArithOp::Add,
+        OpCode::Sub => ArithOp::Sub,
+        OpCode::Mul => ArithOp::Mul,
+        OpCode::Mod => ArithOp::Mod,
+        OpCode::Pow => ArithOp::Pow,
+        OpCode::Div => ArithOp::Div,
+        _ => ArithOp::IDiv,
+    }
+}
+
+// The handlers below run the instructions that are either rare or slow whatever the loop does,
+// like those that go through metamethods or allocate. They are kept out of `run`, so the loop
+// stays small, and the branches of the instructions it does run inline are predicted apart from
+// theirs.
+
+/// Runs an arithmetic instruction whose operands aren't both numbers, a unary, bitwise or length
+/// instruction at `pc`, through metamethods if needed.
+#[inline(never)]
+fn operation<'gc>(
+    ctx: Context<'gc>,
+    values: &[Value<'gc>],
+    proto: &Prototype<'gc>,
+    pc: usize,
+    base: usize,
+    i: Instruction,
+    op: OpCode,
+) -> Result<MetaResult<'gc>, RuntimeError> {
+    if let OpCode::Unm | OpCode::BNot | OpCode::Len = op {
+        let v = values[base + i.b() as usize];
+        let result = match op {
+            OpCode::Unm => ops::arith_meta(ctx, ArithOp::Unm, v, v),
+            OpCode::BNot => ops::bitwise_meta(ctx, BitOp::Not, v, v),
+            _ => ops::len_meta(ctx, v),
+        };
+        return result
+            .map_err(|err| name_culprit(err, proto, pc, &[(v, Operand::Register(i.b()))]));
+    }
+    let k = &proto.constants;
+    let (b, c) = (rk(values, k, base, i.b()), rk(values, k, base, i.c()));
+    let result = match op {
+        OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+            let op = match op {
+                OpCode::BAnd => BitOp::And,
+                OpCode::BOr => BitOp::Or,
+                OpCode::BXor => BitOp::Xor,
+                OpCode::Shl => BitOp::Shl,
+                _ => BitOp::Shr,
+            };
+            ops::bitwise_meta(ctx, op, b, c)
+        }
+        _ => ops::arith_meta(ctx, arith_op(op), b, c),
+    };
+    let candidates = [(b, Operand::Rk(i.b())), (c, Operand::Rk(i.c()))];
+    result.map_err(|err| name_culprit(err, proto, pc, &candidates))
+}
+
+/// Runs the `CONCAT` instruction at `pc`, returning the call to a `__concat` metamethod if one is
+/// needed.
+#[inline(never)]
+fn concat<'gc>(
+    ctx: Context<'gc>,
+    values: &mut [Value<'gc>],
+    concat_top: &mut Option<usize>,
+    proto: &Prototype<'gc>,
+    pc: usize,
+    base: usize,
+    i: Instruction,
+) -> Result<Option<Action<'gc>>, RuntimeError> {
+    let first = base + i.b() as usize;
+    let last = concat_top.take().unwrap_or(base + i.c() as usize);
+    let result = ops::concat(ctx, &mut values[first..=last]).map_err(|(at, err)| {
+        let culprit = (
+            values[first + at],
+            Operand::Register((first + at - base) as u32),
+        );
+        name_culprit(err, proto, pc, &[culprit])
+    });
+    match result? {
+        (_, MetaResult::Value(v)) => {
+            values[base + i.a() as usize] = v;
+            Ok(None)
+        }
+        (at, MetaResult::Call(function, args)) => Ok(Some(Action::Meta {
+            function,
+            args,
+            then: Then::Concat(first + at),
+        })),
+    }
+}
+
+/// Runs a `SETLIST` instruction, moving `pc` past its `EXTRAARG` if it has one.
+#[inline(never)]
+fn set_list<'gc>(
+    ctx: Context<'gc>,
+    values: &mut Vec<Value<'gc>>,
+    proto: &Prototype<'gc>,
+    pc: &mut usize,
+    base: usize,
+    i: Instruction,
+) -> Result<(), RuntimeError> {
+    let ra = base + i.a() as usize;
+    let count = match i.b() {
+        0 => values.len() - ra - 1,
+        b => b as usize,
+    };
+    let batch = match i.c() {
+        0 => {
+            let extra = proto.code[*pc].bx();
+            *pc += 1;
+            extra
+        }
+        c => c,
+    };
+    let Value::Table(table) = values[ra] else {
+        return Err(RuntimeError::new("SETLIST on a non-table value"));
+    };
+    let first = (batch as i64 - 1) * FIELDS_PER_FLUSH as i64;
+    for n in 1..=count {
+        table
+            .set(&ctx, Value::Integer(first + n as i64), values[ra + n])
+            .expect("integer keys are always valid");
+    }
+    if i.b() == 0 {
+        values.resize(base + proto.max_stack as usize, Value::Nil);
+    }
+    Ok(())
+}
+
+/// Runs a `CLOSURE` instruction, returning the new closure.
+#[inline(never)]
+fn new_closure<'gc>(
+    ctx: Context<'gc>,
+    thread: Thread<'gc>,
+    open_upvalues: &mut Vec<UpValue<'gc>>,
+    proto: &Prototype<'gc>,
+    upvalues: &[UpValue<'gc>],
+    base: usize,
+    i: Instruction,
+) -> Value<'gc> {
+    let proto = proto.prototypes[i.bx() as usize];
+    let captured = proto
+        .upvalues
+        .iter()
+        .map(|desc| match *desc {
+            UpvalueDesc::Local(r) => find_upvalue(&ctx, thread, open_upvalues, base + r as usize),
+            UpvalueDesc::Outer(u) => upvalues[u as usize],
+        })
+        .collect();
+    Value::Function(Closure::with_upvalues(&ctx, proto, captured).into())
+}
+
+/// Runs a `VARARG` instruction, copying the extra arguments of the call at `func`.
+#[inline(never)]
+fn var_arg(
+    values: &mut Vec<Value<'_>>,
+    proto: &Prototype<'_>,
+    func: usize,
+    base: usize,
+    i: Instruction,
+) {
+    let ra = base + i.a() as usize;
+    let varargs = func + 1 + proto.num_params as usize..base;
+    let count = match i.b() {
+        0 => varargs.len(),
+        b => b as usize - 1,
+    };
+    let available = count.min(varargs.len());
+    if values.len() < ra + count {
+        values.resize(ra + count, Value::Nil);
+    }
+    values.copy_within(varargs.start..varargs.start + available, ra);
+    values[ra + available..ra + count].fill(Value::Nil);
+    if i.b() == 0 {
+        values.truncate(ra + count);
+    }
+}
+
+/// Runs a `TBC` instruction, marking the variable in stack slot `ra` to be closed.
+#[inline(never)]
+fn to_be_closed<'gc>(
+    ctx: Context<'gc>,
+    values: &[Value<'gc>],
+    tbc: &mut Vec<usize>,
+    k: &[Value<'gc>],
+    ra: usize,
+    i: Instruction,
+) -> Result<(), RuntimeError> {
+    // Nil and false are allowed, and there is nothing to close.
+    let value = values[ra];
+    if value.to_bool() {
+        if let Value::Nil = ops::metamethod(ctx, value, "__close") {
+            return Err(RuntimeError::new(format!(
+                "variable '{}' got a non-closable value",
+                k[i.bx() as usize]
+            )));
+        }
+        tbc.push(ra);
+    }
+    Ok(())
+}
+
+/// Takes the innermost to-be-closed variable at stack index `from` or above off the list, and
+/// returns the call to its `__close` metamethod.
+fn next_tbc<'gc>(
+    ctx: Context<'gc>,
+    values: &[Value<'gc>],
+    tbc: &mut Vec<usize>,
+    from: usize,
+) -> Result<Option<Action<'gc>>, RuntimeError> {
+    match tbc.last() {
+        Some(&slot) if slot >= from => {
+            tbc.pop();
+            let value = values[slot];
+            match ops::metamethod(ctx, value, "__close") {
+                Value::Function(function) => Ok(Some(Action::Close { function, value })),
+                v => Err(RuntimeError::new(format!(
+                    "attempt to call a {} value (metamethod 'close')",
+                    v.type_name()
+                ))),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+#[inline]
+fn jump(pc: &mut usize, offset: i32) {
+    *pc = (*pc as isize + offset as isize) as usize;
+}
+
+/// Converts the loop state at `ra` into an integer or float loop, returning whether the loop runs at
+/// all.
+///
+/// An integer loop keeps the number of iterations left after the first in place of its limit, so
+/// that stepping never has to compare against a limit near the ends of the integer range, where
+/// the index would overflow.
+fn for_prep(values: &mut [Value<'_>], ra: usize) -> Result<bool, RuntimeError> {
+    let (init, limit, step) = (values[ra], values[ra + 1], values[ra + 2]);
+    if let (Value::Integer(init), Value::Integer(step)) = (init, step) {
+        if step == 0 {
+            return Err(RuntimeError::new("'for' step is zero"));
+        }
+        let Some(limit) = for_limit(limit, init, step)? else {
+            return Ok(false);
+        };
+        let span = if step > 0 {
+            limit.wrapping_sub(init) as u64 / step as u64
+        } else {
+            // `step` may be `i64::MIN`, whose magnitude only fits unsigned.
+            init.wrapping_sub(limit) as u64 / (step as u64).wrapping_neg()
+        };
+        values[ra + 1] = Value::Integer(span as i64);
+        return Ok(true);
+    }
+
+    // Numeric strings are accepted, and the limit is checked first, as in the reference
+    // implementation.
+    let number = |v: Value<'_>, what: &str| match ops::coerce_number(v) {
+        Some(n) => Ok(n.to_number().expect("coerced to a number")),
+        None => Err(for_error(v, what)),
+    };
+    let limit = number(limit, "limit")?;
+    let step = number(step, "step")?;
+    let init = number(init, "initial value")?;
+    if step == 0.0 {
+        return Err(RuntimeError::new("'for' step is zero"));
+    }
+    values[ra] = Value::Number(init);
+    values[ra + 1] = Value::Number(limit);
+    values[ra + 2] = Value::Number(step);
+    // Only an ordered comparison skips the loop, so a NaN bound runs the body once.
+    let (low, high) = if 0.0 < step {
+        (init, limit)
+    } else {
+        (limit, init)
+    };
+    Ok(high.partial_cmp(&low) != Some(std::cmp::Ordering::Less))
+}
+
+/// Converts the limit of an integer loop to an integer, rounding a float towards the loop's
+/// direction and clipping it to the integer range. Returns `None` if the loop doesn't run at all.
+fn for_limit(limit: Value<'_>, init: i64, step: i64) -> Result<Option<i64>, RuntimeError> {
+    let limit = match ops::coerce_number(limit) {
+        Some(Value::Integer(i)) => i,
+        Some(Value::Number(n)) => {
+            let n = if step < 0 { n.ceil() } else { n.floor() };
+            if (-9223372036854775808.0..9223372036854775808.0).contains(&n) {
+                n as i64
+            } else if 0.0 < n {
+                // Too large for any integer: a loop counting down from one never starts.
+                if step < 0 {
+                    return Ok(None);
+                }
+                i64::MAX
+            } else {
+                // Too small, or NaN.
+                if step > 0 {
+                    return Ok(None);
+                }
+                i64::MIN
+            }
+        }
+        _ => return Err(for_error(limit, "limit")),
+    };
+    let skip = if step > 0 { init > limit } else { init < limit };
+    Ok((!skip).then_some(limit))
+}
+
+fn for_error(value: Value<'_>, what: &str) -> RuntimeError {
+    RuntimeError::new(format!(
+        "bad 'for' {what} (number expected, got {})",
+        value.type_name()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{rk_constant, InlineCache, Instruction, Prototype};
+    use crate::mem::{Arena, Gc};
+    use crate::{LuaString, State, StateRoot};
+
+    // Clippy's suggested `State::new` is not general enough over the arena's lifetime.
+    #[allow(clippy::redundant_closure)]
+    fn new_arena() -> Arena<StateRoot> {
+        Arena::new(|mc| State::new(mc))
+    }
+
+    fn proto<'gc>(
+        mc: &Mutation<'gc>,
+        max_stack: u8,
+        code: Vec<Instruction>,
+        constants: Vec<Value<'gc>>,
+    ) -> Gc<'gc, Prototype<'gc>> {
+        let lines = vec![1; code.len()];
+        Gc::new(
+            mc,
+            Prototype {
+                chunk_name: LuaString::new(mc, b"test"),
+                line_defined: 0,
+                last_line_defined: 0,
+                num_params: 0,
+                is_vararg: false,
+                max_stack,
+                cache: InlineCache::new(code.len()),
+                code: code.into(),
+                constants: constants.into(),
+                prototypes: Box::new([]),
+                upvalues: Box::new([UpvalueDesc::Local(0)]),
+                line_info: lines.into(),
+                column_info: Box::new([]),
+                local_vars: Box::new([]),
+                upvalue_names: Box::new([]),
+            },
+        )
+    }
+
+    fn run_main<'gc>(
+        ctx: Context<'gc>,
+        proto: Gc<'gc, Prototype<'gc>>,
+    ) -> Result<Vec<Value<'gc>>, LuaError<'gc>> {
+        let thread = Thread::new(&ctx);
+        let closure = Closure::with_env(&ctx, proto, Value::Table(ctx.globals()));
+        call(ctx, thread, Value::Function(closure.into()), &[])
+    }
+
+    #[test]
+    fn numeric_for_loop() {
+        let arena = new_arena();
+        arena.mutate(|mc, state| {
+            let ctx = Context::new(mc, state);
+            // local s = 0; for i = 1, 10 do s = s + i end; return s
+            let code = vec![
+                Instruction::asbx(OpCode::LoadI, 0, 0),
+                Instruction::asbx(OpCode::LoadI, 1, 1),
+                Instruction::asbx(OpCode::LoadI, 2, 10),
+                Instruction::asbx(OpCode::LoadI, 3, 1),
+                Instruction::asbx(OpCode::ForPrep, 1, 1),
+                Instruction::abc(OpCode::Add, 0, 0, 4),
+                Instruction::asbx(OpCode::ForLoop, 1, -2),
+                Instruction::abc(OpCode::Return, 0, 2, 0),
+            ];
+            let results = run_main(ctx, proto(mc, 5, code, vec![])).unwrap();
+            assert_eq!(results, vec![Value::Integer(55)]);
+        });
+    }
+
+    #[test]
+    fn calls_native_functions() {
+        fn double<'gc>(
+            _: Context<'gc>,
+            stack: &mut Stack<'gc, '_>,
+        ) -> Result<NativeReturn, LuaError<'gc>> {
+            let n = stack.get(0).to_integer().unwrap();
+            stack.replace(&[Value::Integer(n * 2), Value::Boolean(true)]);
+            Ok(NativeReturn::Return)
+        }
+
+        let arena = new_arena();
+        arena.mutate(|mc, state| {
+            let ctx = Context::new(mc, state);
+            let name = Value::String(LuaString::new(mc, b"double"));
+            ctx.globals()
+                .set(mc, name, Value::Function(Function::Native(double)))
+                .unwrap();
+            // return double(21)
+            let code = vec![
+                Instruction::abc(OpCode::GetTabUp, 0, 0, rk_constant(0)),
+                Instruction::asbx(OpCode::LoadI, 1, 21),
+                Instruction::abc(OpCode::Call, 0, 2, 2),
+                Instruction::abc(OpCode::Return, 0, 2, 0),
+            ];
+            let results = run_main(ctx, proto(mc, 2, code, vec![name])).unwrap();
+            assert_eq!(results, vec![Value::Integer(42)]);
+        });
+    }
+
+    #[test]
+    fn index_metamethod_and_errors() {
+        fn index<'gc>(
+            _: Context<'gc>,
+            stack: &mut Stack<'gc, '_>,
+        ) -> Result<NativeReturn, LuaError<'gc>> {
+            let key = stack.get(1);
+            stack.replace(&[key]);
+            Ok(NativeReturn::Return)
+        }
+
+        let arena = new_arena();
+        arena.mutate(|mc, state| {
+            let ctx = Context::new(mc, state);
+            let t = Table::new(mc);
+            let mt = Table::new(mc);
+            mt.set(
+                mc,
+                Value::String(LuaString::new(mc, b"__index")),
+                Value::Function(Function::Native(index)),
+            )
+            .unwrap();
+            t.set_metatable(mc, Some(mt));
+            let name = Value::String(LuaString::new(mc, b"t"));
+            ctx.globals().set(mc, name, Value::Table(t)).unwrap();
+
+            // return t[7] + 1
+            let code = vec![
+                Instruction::abc(OpCode::GetTabUp, 0, 0, rk_constant(0)),
+                Instruction::abc(OpCode::GetTable, 0, 0, rk_constant(1)),
+                Instruction::abc(OpCode::Add, 0, 0, rk_constant(2)),
+                Instruction::abc(OpCode::Return, 0, 2, 0),
+            ];
+            let constants = vec![name, Value::Integer(7), Value::Integer(1)];
+            let results = run_main(ctx, proto(mc, 1, code, constants)).unwrap();
+            assert_eq!(results, vec![Value::Integer(8)]);
+
+            // return nil + 1
+            let code = vec![
+                Instruction::abc(OpCode::LoadNil, 0, 0, 0),
+                Instruction::abc(OpCode::Add, 0, 0, rk_constant(0)),
+                Instruction::abc(OpCode::Return, 0, 2, 0),
+            ];
+            let err = run_main(ctx, proto(mc, 1, code, vec![Value::Integer(1)])).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "test:1: attempt to perform arithmetic on a nil value"
+            );
+        });
+    }
+
+    #[test]
+    fn concat_metamethods() {
+        fn join<'gc>(
+            ctx: Context<'gc>,
+            stack: &mut Stack<'gc, '_>,
+        ) -> Result<NativeReturn, LuaError<'gc>> {
+            let show = |v: Value<'gc>| match v {
+                Value::Table(_) => "T".to_string(),
+                v => v.to_string(),
+            };
+            let s = format!("({},{})", show(stack.get(0)), show(stack.get(1)));
+            stack.replace(&[Value::String(LuaString::new(&ctx, s.as_bytes()))]);
+            Ok(NativeReturn::Return)
+        }
+
+        let mut lua = crate::Lua::new();
+        lua.enter(|ctx| {
+            let t = Table::new(&ctx);
+            let mt = Table::new(&ctx);
+            let name = |s: &str| LuaString::new(&ctx, s.as_bytes());
+            mt.set(&ctx, name("__concat"), Function::Native(join))
+                .unwrap();
+            t.set_metatable(&ctx, Some(mt));
+            ctx.globals().set(&ctx, name("t"), t).unwrap();
+            ctx.globals()
+                .set(&ctx, name("u"), Table::new(&ctx))
+                .unwrap();
+
+            let eval = |source: &str| match ctx.eval(source) {
+                Ok(values) => values[0].to_string(),
+                Err(err) => err.to_string(),
+            };
+            assert_eq!(eval("'a' .. 1 .. 2.0 .. 'b'"), "a12.0b");
+            // Runs on either side of a metamethod are joined on their own, right to left.
+            assert_eq!(eval("'a' .. 1 .. t .. 'b' .. 2.5"), "a1(T,b2.5)");
+            assert_eq!(eval("t .. t .. t"), "(T,(T,T))");
+            assert_eq!(eval("u .. t"), "(T,T)");
+            assert_eq!(
+                eval("return 'x' .. u .. 'y'"),
+                "[string \"return 'x' .. u .. 'y'\"]:1: attempt to concatenate a table value (global 'u')"
+            );
+            assert_eq!(
+                eval("return 1 .. nil"),
+                "[string \"return 1 .. nil\"]:1: attempt to concatenate a nil value"
+            );
+        });
+    }
+
+    #[test]
+    fn variable_names() {
+        let mut lua = crate::Lua::new();
+        lua.enter(|ctx| {
+            let run = |source: &str| {
+                let f = ctx.load("=t", source).unwrap();
+                ctx.call(f, &[]).unwrap_err().to_string()
+            };
+            let cases = [
+                ("x()", "attempt to call a nil value (global 'x')"),
+                (
+                    "local t = {} t.a.b = 1",
+                    "attempt to index a nil value (field 'a')",
+                ),
+                (
+                    "local s = {} s:m()",
+                    "attempt to call a nil value (method 'm')",
+                ),
+                (
+                    "local x return x + 1",
+                    "attempt to perform arithmetic on a nil value (local 'x')",
+                ),
+                (
+                    "return 'a' + 1",
+                    "attempt to perform arithmetic on a string value (constant 'a')",
+                ),
+                (
+                    "local x = 2.5 return x & 1",
+                    "number (local 'x') has no integer representation",
+                ),
+                (
+                    "local t = {a = 'a'} return t.a .. t.b",
+                    "attempt to concatenate a nil value (field 'b')",
+                ),
+                (
+                    "for _ in 1 do end",
+                    "attempt to call a number value (for iterator 'for iterator')",
+                ),
+                // Temporaries have no name to give.
+                ("return ({}) < 1", "attempt to compare table with number"),
+            ];
+            for (source, expected) in cases {
+                assert_eq!(run(source), format!("t:1: {expected}"), "{source}");
+            }
+            let f = ctx
+                .load("=t", "local up return function() return #up end")
+                .unwrap();
+            let inner = ctx.call(f, &[]).unwrap()[0];
+            assert_eq!(
+                ctx.call(inner, &[]).unwrap_err().to_string(),
+                "t:1: attempt to get length of a nil value (upvalue 'up')"
+            );
+        });
+    }
+
+    #[test]
+    fn memory_limit() {
+        let mut lua = crate::Lua::new();
+        let source = "
+            local ok, err = pcall(function()
+                local t = {}
+                for i = 1, 2e4 do t[i] = string.rep('x', 100) .. i end
+            end)
+            return ok, err
+        ";
+        let limit = lua.enter(|ctx| {
+            let limit = ctx.metrics().total_allocation() + 256 * 1024;
+            ctx.set_memory_limit(Some(limit));
+            assert_eq!(ctx.memory_limit(), Some(limit));
+            let results = ctx.eval(source).unwrap();
+            assert_eq!(results[0].to_string(), "false");
+            assert_eq!(results[1].to_string(), "not enough memory");
+            // Building the message of another error allocates, and fails in turn.
+            let err = ctx.eval("error('x' .. 1)").unwrap_err();
+            assert_eq!(err.to_string(), "not enough memory");
+            limit
+        });
+        // Leaving the state collected everything the failed script left behind.
+        assert!(lua.metrics().total_allocation() < limit);
+        lua.enter(|ctx| {
+            assert_eq!(
+                ctx.eval(source).unwrap()[1].to_string(),
+                "not enough memory"
+            );
+            ctx.set_memory_limit(None);
+            assert_eq!(ctx.eval(source).unwrap()[0].to_string(), "true");
+        });
+
+        // Called through an executor, the script waits for a full collection before failing, and
+        // only fails if that leaves the heap over the limit.
+        let (garbage, kept) = lua.enter(|ctx| {
+            ctx.set_memory_limit(Some(limit));
+            let garbage = "collectgarbage('stop')
+                for i = 1, 2e4 do local s = string.rep('x', 100) .. i end
+                collectgarbage('restart')
+                return 'done'";
+            let kept = "local t = {} for i = 1, 2e4 do t[i] = string.rep('x', 100) .. i end";
+            (
+                ctx.stash(ctx.load("=garbage", garbage).unwrap()),
+                ctx.stash(ctx.load("=kept", kept).unwrap()),
+            )
+        });
+        assert_eq!(lua.call::<_, String>(&garbage, ()).unwrap(), "done");
+        let err = lua.call::<_, ()>(&kept, ()).unwrap_err();
+        assert_eq!(err.to_string(), "not enough memory");
+    }
+
+    #[test]
+    fn tracebacks_cross_native_functions() {
+        let mut lua = crate::Lua::new();
+        lua.enter(|ctx| {
+            // Calls its argument from Rust.
+            let run = Function::from_fn(&ctx, |ctx, stack| {
+                let f = stack.get(0);
+                ctx.call(f, &[])?;
+                Ok(NativeReturn::Return)
+            });
+            // Has the interpreter loop call its argument.
+            let apply = Function::from_fn(&ctx, |_, _| Ok(NativeReturn::Call));
+            for (name, f) in [("run", run), ("apply", apply)] {
+                let name = LuaString::new(&ctx, name.as_bytes());
+                ctx.globals().set(&ctx, name, f).unwrap();
+            }
+            let source = "local function inner() error('deep') end
+                local function middle() apply(inner) end
+                local function outer() run(middle) end
+                local co = coroutine.wrap(outer)
+                co()";
+            let err = ctx.load("=t", source).unwrap();
+            let err = ctx.call(err, &[]).unwrap_err();
+            // `coroutine.wrap` adds where it was called, as in the reference implementation.
+            assert_eq!(err.to_string(), "t:5: t:1: deep");
+            assert_eq!(
+                err.traceback(),
+                [
+                    "t:1: in function <t:1>",
+                    "[C]: in function 'apply'",
+                    "t:2: in function <t:2>",
+                    "[C]: in function 'run'",
+                    "t:3: in function <t:3>",
+                    "[C]: in function 'co'",
+                    "t:5: in main chunk",
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn inline_caches_follow_tables() {
+        let mut lua = crate::Lua::new();
+        lua.enter(|ctx| {
+            // Each access runs once per table, so its hint is always left by the one before.
+            let source = "local log = {}
+                local function get(t) return t.x end
+                local function set(t, v) t.x = v end
+                local a, b = {x = 1}, {y = 0, x = 2}
+                local class = {x = 'class'}
+                log[#log + 1] = get(a) .. get(b)
+                set(a, 10)
+                for i = 1, 100 do a['k' .. i] = i end
+                log[#log + 1] = get(a) .. get(b)
+                a.x = nil
+                log[#log + 1] = tostring(get(a))
+                setmetatable(a, {__index = class})
+                log[#log + 1] = get(a) .. get(class)
+                setmetatable(b, {__newindex = function(t, k, v) rawset(t, k, v * 2) end})
+                set(b, 3)
+                b.x = nil
+                set(b, 4)
+                log[#log + 1] = get(b)
+                x = 'global'
+                local function global() return x end
+                log[#log + 1] = global()
+                _ENV.x = nil
+                log[#log + 1] = tostring(global())
+                return table.concat(log, ' ')";
+            let results = ctx.eval(source).unwrap();
+            assert_eq!(results[0].to_string(), "12 102 nil classclass 8 global nil");
+        });
+    }
+
+    #[test]
+    fn env_follows_assignments() {
+        let mut lua = crate::Lua::new();
+        lua.enter(|ctx| {
+            let source = "x = 1
+                local concat, outer = table.concat, _ENV
+                local function swap() _ENV = {x = 3} end
+                local function f()
+                    local a = x
+                    _ENV = {x = 2}
+                    local b = x
+                    swap()
+                    local c = x
+                    _ENV = outer
+                    return concat({a, b, c, x}, ' ')
+                end
+                return f()";
+            let results = ctx.eval(source).unwrap();
+            assert_eq!(results[0].to_string(), "1 2 3 1");
+        });
+    }
+
+    #[test]
+    fn native_calls_reuse_buffers() {
+        let mut lua = crate::Lua::new();
+        lua.enter(|ctx| {
+            let source = "local t = {}
+                for i = 1, 100 do t[i] = i end
+                local sum = 0
+                for _, v in ipairs(t) do sum = sum + math.max(v, 0) end
+                local _, _, n = pcall(pcall, select, '#', table.unpack(t))
+                local many = {table.unpack(t)}
+                return sum, n, #many";
+            let f = ctx.load("=chunk", source).unwrap();
+            let thread = Thread::new(&ctx);
+            let results = call(ctx, thread, Value::Function(f), &[]).unwrap();
+            assert_eq!(results[0], Value::Integer(5050));
+            assert_eq!(results[1], Value::Integer(100));
+            assert_eq!(results[2], Value::Integer(100));
+            // Two buffers for the nested calls to `pcall`, a third for `select` inside them.
+            assert_eq!(thread.0.borrow().buffers.len(), 3);
+
+            // Closing variables borrows one of them too.
+            let source = "local mt = {__close = function() end}
+                for i = 1, 100 do local x <close> = setmetatable({}, mt) end";
+            let f = ctx.load("=chunk", source).unwrap();
+            call(ctx, thread, Value::Function(f), &[]).unwrap();
+            assert_eq!(thread.0.borrow().buffers.len(), 3);
+        });
+    }
+
+    #[test]
+    fn stack_limits() {
+        let mut lua = crate::Lua::new();
+        lua.enter(|ctx| {
+            ctx.set_max_call_depth(100);
+            let f = ctx
+                .load("=t", "local function f() return 1 + f() end return f()")
+                .unwrap();
+            let err = ctx.call(f, &[]).unwrap_err();
+            assert_eq!(err.to_string(), "t:1: stack overflow");
+            assert_eq!(err.traceback().len(), 100);
+            let shown = format!("{err:#}");
+            assert!(shown.contains("\n\t...\t(skipping 79 levels)\n"), "{shown}");
+            assert_eq!(shown.lines().count(), 2 + 10 + 1 + 11);
+
+            // A message handler gets room to run past the limit.
+            let source = "
+                local function f() return 1 + f() end
+                return xpcall(f, function(m) return 'handled: ' .. m end)
+            ";
+            let f = ctx.load("=t", source).unwrap();
+            let handled = ctx.call(f, &[]).unwrap();
+            assert_eq!(handled[1].to_string(), "handled: t:2: stack overflow");
+            ctx.set_max_call_depth(DEFAULT_MAX_CALL_DEPTH);
+
+            // Each `__index` call re-enters the interpreter, until it runs out of native stack.
+            let t = Table::new(&ctx);
+            let mt = Table::new(&ctx);
+            let index = ctx
+                .eval("return function(t, k) depth = depth + 1 return t[k] end")
+                .unwrap()[0];
+            mt.set(&ctx, LuaString::new(&ctx, b"__index"), index)
+                .unwrap();
+            t.set_metatable(&ctx, Some(mt));
+            ctx.globals()
+                .set(&ctx, LuaString::new(&ctx, b"t"), t)
+                .unwrap();
+            let depth = |limit| {
+                ctx.set_native_stack_limit(limit);
+                let results = ctx
+                    .eval("depth = 0 return pcall(function() return t.x end)")
+                    .unwrap();
+                assert_eq!(results[1].to_string(), "C stack overflow");
+                match ctx.globals().get_str("depth") {
+                    Value::Integer(depth) => depth,
+                    depth => panic!("{depth:?}"),
+                }
+            };
+            assert_eq!(depth(DEFAULT_NATIVE_STACK_LIMIT), MAX_NESTING as i64 - 2);
+            assert!(depth(16 * 1024) < 50);
+        });
+    }
+}
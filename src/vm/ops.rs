@@ -0,0 +1,821 @@
+//! Value-level semantics of the Lua operators, shared by the interpreter and the standard library.
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::io::Write as _;
+
+use crate::compiler::lexer::{parse_number, Number};
+use crate::table::MetaEvent;
+use crate::{Context, Function, LuaStringBuilder, RuntimeError, Table, Value};
+
+/// The maximum number of `__index` / `__newindex` tables followed before giving up.
+const MAX_META_CHAIN: usize = 2000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    Unm,
+}
+
+impl ArithOp {
+    pub fn metamethod(self) -> &'static str {
+        match self {
+            ArithOp::Add => "__add",
+            ArithOp::Sub => "__sub",
+            ArithOp::Mul => "__mul",
+            ArithOp::Mod => "__mod",
+            ArithOp::Pow => "__pow",
+            ArithOp::Div => "__div",
+            ArithOp::IDiv => "__idiv",
+            ArithOp::Unm => "__unm",
+        }
+    }
+}
+
+/// Applies an arithmetic operator to two numbers, or strings that convert to numbers. Returns
+/// `Ok(None)` if either operand is neither.
+pub fn arith<'gc>(
+    op: ArithOp,
+    a: Value<'gc>,
+    b: Value<'gc>,
+) -> Result<Option<Value<'gc>>, RuntimeError> {
+    let (Some(a), Some(b)) = (coerce_number(a), coerce_number(b)) else {
+        return Ok(None);
+    };
+    if let (Value::Integer(x), Value::Integer(y)) = (a, b) {
+        let r = match op {
+            ArithOp::Add => x.wrapping_add(y),
+            ArithOp::Sub => x.wrapping_sub(y),
+            ArithOp::Mul => x.wrapping_mul(y),
+            ArithOp::Unm => x.wrapping_neg(),
+            ArithOp::Mod => {
+                if y == 0 {
+                    return Err(RuntimeError::new("attempt to perform 'n%0'"));
+                }
+                let r = x.wrapping_rem(y);
+                if r != 0 && (r ^ y) < 0 {
+                    r + y
+                } else {
+                    r
+                }
+            }
+            ArithOp::IDiv => {
+                if y == 0 {
+                    return Err(RuntimeError::new("attempt to perform 'n//0'"));
+                }
+                let q = x.wrapping_div(y);
+                if x.wrapping_rem(y) != 0 && (x ^ y) < 0 {
+                    q - 1
+                } else {
+                    q
+                }
+            }
+            ArithOp::Div => return Ok(Some(Value::Number(x as f64 / y as f64))),
+            ArithOp::Pow => return Ok(Some(Value::Number((x as f64).powf(y as f64)))),
+        };
+        return Ok(Some(Value::Integer(r)));
+    }
+
+    let (Some(x), Some(y)) = (a.to_number(), b.to_number()) else {
+        return Ok(None);
+    };
+    Ok(Some(Value::Number(match op {
+        ArithOp::Add => x + y,
+        ArithOp::Sub => x - y,
+        ArithOp::Mul => x * y,
+        ArithOp::Div => x / y,
+        ArithOp::Pow => x.powf(y),
+        ArithOp::Unm => -x,
+        ArithOp::IDiv => (x / y).floor(),
+        ArithOp::Mod => float_mod(x, y),
+    })))
+}
+
+/// Float modulo with the sign of the divisor, computed from the truncated remainder so that
+/// infinite divisors leave finite dividends alone.
+fn float_mod(x: f64, y: f64) -> f64 {
+    let m = x % y;
+    if (m > 0.0 && y < 0.0) || (m < 0.0 && y > 0.0) {
+        m + y
+    } else {
+        m
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    /// Unary `~`, which ignores its second operand.
+    Not,
+}
+
+impl BitOp {
+    pub fn metamethod(self) -> &'static str {
+        match self {
+            BitOp::And => "__band",
+            BitOp::Or => "__bor",
+            BitOp::Xor => "__bxor",
+            BitOp::Shl => "__shl",
+            BitOp::Shr => "__shr",
+            BitOp::Not => "__bnot",
+        }
+    }
+}
+
+/// Applies a bitwise operator to two numbers, or strings that convert to numbers. Returns `Ok(None)` if
+/// either operand is neither, and an error if either is a number with no integer representation.
+pub fn bitwise<'gc>(
+    op: BitOp,
+    a: Value<'gc>,
+    b: Value<'gc>,
+) -> Result<Option<Value<'gc>>, RuntimeError> {
+    let (Some(a), Some(b)) = (coerce_number(a), coerce_number(b)) else {
+        return Ok(None);
+    };
+    let (Some(x), Some(y)) = (a.to_integer(), b.to_integer()) else {
+        return Err(RuntimeError::new("number has no integer representation"));
+    };
+    Ok(Some(Value::Integer(match op {
+        BitOp::And => x & y,
+        BitOp::Or => x | y,
+        BitOp::Xor => x ^ y,
+        BitOp::Shl => shift_left(x, y),
+        BitOp::Shr => shift_left(x, y.wrapping_neg()),
+        BitOp::Not => !x,
+    })))
+}
+
+/// Returns a number as it is, or the number a string converts to. Strings convert the way numerals
+/// in source do, with surrounding whitespace allowed, so `" 0x10 "` is the integer 16.
+pub fn coerce_number(value: Value<'_>) -> Option<Value<'_>> {
+    match value {
+        Value::Integer(i) => Some(Value::Integer(i)),
+        Value::Number(n) => Some(Value::Number(n)),
+        Value::String(s) => Some(match parse_number(s.as_bytes())? {
+            Number::Integer(i) => Value::Integer(i),
+            Number::Float(n) => Value::Number(n),
+        }),
+        _ => None,
+    }
+}
+
+/// Shifts left by `n` bits, or logically right for negative `n`. Shifting by 64 or more bits gives zero.
+fn shift_left(x: i64, n: i64) -> i64 {
+    if n <= -64 || n >= 64 {
+        0
+    } else if n >= 0 {
+        ((x as u64) << n) as i64
+    } else {
+        ((x as u64) >> -n) as i64
+    }
+}
+
+/// Compares two values with `<` without metamethods, returning `None` if they are not both numbers or both
+/// strings. Integers and floats compare by their exact mathematical values.
+pub fn less_than(a: Value<'_>, b: Value<'_>) -> Option<bool> {
+    match (a, b) {
+        (Value::Integer(x), Value::Integer(y)) => Some(x < y),
+        (Value::Number(x), Value::Number(y)) => Some(x < y),
+        (Value::Integer(i), Value::Number(f)) => Some(int_cmp_float(i, f) == Some(Ordering::Less)),
+        (Value::Number(f), Value::Integer(i)) => {
+            Some(int_cmp_float(i, f) == Some(Ordering::Greater))
+        }
+        (Value::String(x), Value::String(y)) => Some(x.as_bytes() < y.as_bytes()),
+        _ => None,
+    }
+}
+
+/// Like [`less_than`], for `<=`.
+pub fn less_equal(a: Value<'_>, b: Value<'_>) -> Option<bool> {
+    match (a, b) {
+        (Value::Integer(x), Value::Integer(y)) => Some(x <= y),
+        (Value::Number(x), Value::Number(y)) => Some(x <= y),
+        (Value::Integer(i), Value::Number(f)) => Some(matches!(
+            int_cmp_float(i, f),
+            Some(Ordering::Less | Ordering::Equal)
+        )),
+        (Value::Number(f), Value::Integer(i)) => Some(matches!(
+            int_cmp_float(i, f),
+            Some(Ordering::Greater | Ordering::Equal)
+        )),
+        (Value::String(x), Value::String(y)) => Some(x.as_bytes() <= y.as_bytes()),
+        _ => None,
+    }
+}
+
+/// Compares an integer with a float exactly, without rounding the integer to the nearest float.
+/// Returns `None` if the float is NaN.
+fn int_cmp_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= 9223372036854775808.0 {
+        Some(Ordering::Less)
+    } else if f < -9223372036854775808.0 {
+        Some(Ordering::Greater)
+    } else {
+        // In range, so `f`'s integer part converts exactly and only its fraction can break a tie.
+        let whole = f.trunc();
+        Some(
+            i.cmp(&(whole as i64))
+                .then(0.0.partial_cmp(&(f - whole)).unwrap()),
+        )
+    }
+}
+
+/// Formats a float the way `tostring` does: as C's `%.14g` would, with `.0` added to anything that
+/// would otherwise read as an integer.
+pub fn number_to_string(n: f64) -> String {
+    let mut s = format_g(n, 14);
+    if s.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+        s.push_str(".0");
+    }
+    s
+}
+
+/// Formats a float as C's `printf` does with `%.{precision}g`: in fixed or exponential notation,
+/// whichever suits its magnitude, with `precision` significant digits and no trailing zeros.
+///
+/// Like glibc, a NaN shows its sign, so `0/0` on common hardware formats as `-nan`.
+pub fn format_g(n: f64, precision: usize) -> String {
+    let sign = if n.is_sign_negative() { "-" } else { "" };
+    if n.is_nan() {
+        return format!("{sign}nan");
+    } else if n.is_infinite() {
+        return format!("{sign}inf");
+    }
+    let precision = precision.max(1);
+    // Rounding to the precision first settles the exponent, as in 9.99999 becoming 1e+01.
+    let exp_form = format!("{:.*e}", precision - 1, n);
+    let (mantissa, exp) = exp_form.split_once('e').expect("exponential notation");
+    let exp: i32 = exp.parse().expect("integer exponent");
+    if -4 <= exp && exp < precision as i32 {
+        let fixed = format!("{:.*}", (precision as i32 - 1 - exp) as usize, n);
+        trim_fraction(&fixed).to_owned()
+    } else {
+        let exp_sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{exp_sign}{:02}", trim_fraction(mantissa), exp.abs())
+    }
+}
+
+/// Drops trailing zeros after a decimal point, and the point if nothing is left after it.
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Appends the string form of a string or number to `buf`, returning false for any other value.
+pub fn write_concat_operand(buf: &mut Vec<u8>, value: Value<'_>) -> bool {
+    match value {
+        Value::String(s) => buf.extend_from_slice(s.as_bytes()),
+        Value::Integer(i) => {
+            // Writing to a vector can't fail.
+            let _ = write!(buf, "{i}");
+        }
+        Value::Number(n) => buf.extend_from_slice(number_to_string(n).as_bytes()),
+        _ => return false,
+    }
+    true
+}
+
+/// Returns the metatable of a value, if it has one.
+pub fn metatable<'gc>(ctx: Context<'gc>, value: Value<'gc>) -> Option<Table<'gc>> {
+    match value {
+        Value::Table(t) => t.metatable(),
+        Value::String(_) => ctx.string_metatable(),
+        Value::UserData(u) => u.metatable(),
+        _ => None,
+    }
+}
+
+/// Sets the metatable of a table the way `setmetatable` does: if the metatable has a `__gc` field
+/// at this point, the table is marked for finalization.
+pub fn set_metatable<'gc>(ctx: Context<'gc>, table: Table<'gc>, metatable: Option<Table<'gc>>) {
+    table.set_metatable(&ctx, metatable);
+    let has_gc = metatable.is_some_and(|mt| !mt.metamethod(MetaEvent::Gc).is_nil());
+    if has_gc && !table.borrow().marked_for_finalization {
+        table.borrow_mut(&ctx).marked_for_finalization = true;
+        let finalizers = ctx.state().finalizers();
+        finalizers.borrow_mut(&ctx).marked.push(table.downgrade());
+    }
+}
+
+/// Returns the entry after `key` in the traversal order of `table`, or the first one if `key` is
+/// nil, as `next` does.
+///
+/// Clearing fields during a traversal is fine: a table keeps the slots of removed entries until it
+/// next grows, and only adding fields makes it grow. Once it has, the traversal may fail to find
+/// its place again, which is an error rather than a silent restart.
+pub fn next<'gc>(
+    table: Table<'gc>,
+    key: Value<'gc>,
+) -> Result<Option<(Value<'gc>, Value<'gc>)>, RuntimeError> {
+    table
+        .next(key)
+        .map_err(|()| RuntimeError::new("invalid key to 'next'"))
+}
+
+/// Looks up a metamethod of `value`, returning nil if there is none.
+pub fn metamethod<'gc>(ctx: Context<'gc>, value: Value<'gc>, name: &str) -> Value<'gc> {
+    match metatable(ctx, value) {
+        Some(mt) => mt.get_str(name),
+        None => Value::Nil,
+    }
+}
+
+/// Same as [`metamethod`], for one of the metamethods a metatable remembers the absence of.
+pub(crate) fn fast_metamethod<'gc>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+    event: MetaEvent,
+) -> Value<'gc> {
+    match metatable(ctx, value) {
+        Some(mt) => mt.metamethod(event),
+        None => Value::Nil,
+    }
+}
+
+/// Raises the error for calling `value` if it is neither a function nor has a `__call` metamethod.
+pub fn check_callable<'gc>(ctx: Context<'gc>, value: Value<'gc>) -> Result<(), RuntimeError> {
+    if matches!(value, Value::Function(_))
+        || matches!(metamethod(ctx, value, "__call"), Value::Function(_))
+    {
+        return Ok(());
+    }
+    Err(RuntimeError::new(format!(
+        "attempt to call a {} value",
+        value.type_name()
+    )))
+}
+
+/// The outcome of an indexing operation that may need to call a metamethod.
+pub enum MetaResult<'gc> {
+    Value(Value<'gc>),
+    Call(Function<'gc>, Vec<Value<'gc>>),
+}
+
+fn callable(handler: Value<'_>) -> Option<Function<'_>> {
+    match handler {
+        Value::Function(f) => Some(f),
+        _ => None,
+    }
+}
+
+/// Performs `obj[key]`, following `__index` tables and returning the call to make for an `__index` function.
+pub fn index<'gc>(
+    ctx: Context<'gc>,
+    obj: Value<'gc>,
+    key: Value<'gc>,
+) -> Result<MetaResult<'gc>, RuntimeError> {
+    index_hinted(ctx, obj, key, None)
+}
+
+/// Same as [`index`], looking the key up in each table with `hint` if there is one.
+pub(crate) fn index_hinted<'gc>(
+    ctx: Context<'gc>,
+    mut obj: Value<'gc>,
+    key: Value<'gc>,
+    hint: Option<&Cell<u32>>,
+) -> Result<MetaResult<'gc>, RuntimeError> {
+    for _ in 0..MAX_META_CHAIN {
+        let handler = match obj {
+            Value::Table(t) => {
+                let value = match hint {
+                    Some(hint) => t.get_hinted(key, hint),
+                    None => t.get(key),
+                };
+                if !value.is_nil() {
+                    return Ok(MetaResult::Value(value));
+                }
+                match t.metatable() {
+                    Some(mt) => mt.metamethod(MetaEvent::Index),
+                    None => return Ok(MetaResult::Value(Value::Nil)),
+                }
+            }
+            _ => {
+                let handler = fast_metamethod(ctx, obj, MetaEvent::Index);
+                if handler.is_nil() {
+                    return Err(RuntimeError::new(format!(
+                        "attempt to index a {} value",
+                        obj.type_name()
+                    )));
+                }
+                handler
+            }
+        };
+        if handler.is_nil() {
+            return Ok(MetaResult::Value(Value::Nil));
+        }
+        if let Some(f) = callable(handler) {
+            return Ok(MetaResult::Call(f, vec![obj, key]));
+        }
+        obj = handler;
+    }
+    Err(RuntimeError::new("'__index' chain too long; possible loop"))
+}
+
+/// Performs `obj[key] = value`, following `__newindex` tables and returning the call to make for a
+/// `__newindex` function.
+pub fn new_index<'gc>(
+    ctx: Context<'gc>,
+    obj: Value<'gc>,
+    key: Value<'gc>,
+    value: Value<'gc>,
+) -> Result<Option<(Function<'gc>, Vec<Value<'gc>>)>, RuntimeError> {
+    new_index_hinted(ctx, obj, key, value, None)
+}
+
+/// Same as [`new_index`], looking the key up in each table with `hint` if there is one.
+pub(crate) fn new_index_hinted<'gc>(
+    ctx: Context<'gc>,
+    mut obj: Value<'gc>,
+    key: Value<'gc>,
+    value: Value<'gc>,
+    hint: Option<&Cell<u32>>,
+) -> Result<Option<(Function<'gc>, Vec<Value<'gc>>)>, RuntimeError> {
+    for _ in 0..MAX_META_CHAIN {
+        let handler = match obj {
+            Value::Table(t) => {
+                let present = |t: Table<'gc>| match hint {
+                    Some(hint) => !t.get_hinted(key, hint).is_nil(),
+                    None => !t.get(key).is_nil(),
+                };
+                let handler = match t.metatable() {
+                    Some(mt) if !present(t) => mt.metamethod(MetaEvent::NewIndex),
+                    _ => Value::Nil,
+                };
+                if handler.is_nil() {
+                    match hint {
+                        Some(hint) => t.set_hinted(&ctx, key, value, hint),
+                        None => t.set(&ctx, key, value),
+                    }
+                    .map_err(|e| RuntimeError::new(e.to_string()))?;
+                    return Ok(None);
+                }
+                handler
+            }
+            _ => {
+                let handler = fast_metamethod(ctx, obj, MetaEvent::NewIndex);
+                if handler.is_nil() {
+                    return Err(RuntimeError::new(format!(
+                        "attempt to index a {} value",
+                        obj.type_name()
+                    )));
+                }
+                handler
+            }
+        };
+        if let Some(f) = callable(handler) {
+            return Ok(Some((f, vec![obj, key, value])));
+        }
+        obj = handler;
+    }
+    Err(RuntimeError::new(
+        "'__newindex' chain too long; possible loop",
+    ))
+}
+
+/// The result of `a op b` where it is the plain arithmetic of two numbers that can't fail: the
+/// interpreter's fast path, leaving strings, metamethods and errors to [`arith_meta`].
+#[inline(always)]
+pub(crate) fn arith_fast<'gc>(op: ArithOp, a: Value<'gc>, b: Value<'gc>) -> Option<Value<'gc>> {
+    let (x, y) = match (a, b) {
+        (Value::Integer(x), Value::Integer(y)) => {
+            return match op {
+                ArithOp::Add => Some(Value::Integer(x.wrapping_add(y))),
+                ArithOp::Sub => Some(Value::Integer(x.wrapping_sub(y))),
+                ArithOp::Mul => Some(Value::Integer(x.wrapping_mul(y))),
+                ArithOp::Div => Some(Value::Number(x as f64 / y as f64)),
+                _ => None,
+            }
+        }
+        (Value::Number(x), Value::Number(y)) => (x, y),
+        (Value::Integer(x), Value::Number(y)) => (x as f64, y),
+        (Value::Number(x), Value::Integer(y)) => (x, y as f64),
+        _ => return None,
+    };
+    match op {
+        ArithOp::Add => Some(Value::Number(x + y)),
+        ArithOp::Sub => Some(Value::Number(x - y)),
+        ArithOp::Mul => Some(Value::Number(x * y)),
+        ArithOp::Div => Some(Value::Number(x / y)),
+        _ => None,
+    }
+}
+
+/// Resolves an arithmetic operation to either its result or the metamethod call computing it.
+pub fn arith_meta<'gc>(
+    ctx: Context<'gc>,
+    op: ArithOp,
+    a: Value<'gc>,
+    b: Value<'gc>,
+) -> Result<MetaResult<'gc>, RuntimeError> {
+    if let Some(v) = arith(op, a, b)? {
+        return Ok(MetaResult::Value(v));
+    }
+    let mut handler = metamethod(ctx, a, op.metamethod());
+    if handler.is_nil() {
+        handler = metamethod(ctx, b, op.metamethod());
+    }
+    match callable(handler) {
+        Some(f) => Ok(MetaResult::Call(f, vec![a, b])),
+        None => {
+            let culprit = if coerce_number(a).is_none() { a } else { b };
+            Err(RuntimeError::new(format!(
+                "attempt to perform arithmetic on a {} value",
+                culprit.type_name()
+            )))
+        }
+    }
+}
+
+/// Like [`arith_meta`], for the bitwise operators. Numbers without an integer representation still
+/// give their operands' metamethods a chance before raising an error.
+pub fn bitwise_meta<'gc>(
+    ctx: Context<'gc>,
+    op: BitOp,
+    a: Value<'gc>,
+    b: Value<'gc>,
+) -> Result<MetaResult<'gc>, RuntimeError> {
+    let result = bitwise(op, a, b);
+    if let Ok(Some(v)) = result {
+        return Ok(MetaResult::Value(v));
+    }
+    let mut handler = metamethod(ctx, a, op.metamethod());
+    if handler.is_nil() {
+        handler = metamethod(ctx, b, op.metamethod());
+    }
+    match (callable(handler), result) {
+        (Some(f), _) => Ok(MetaResult::Call(f, vec![a, b])),
+        (None, Err(err)) => Err(err),
+        (None, _) => {
+            let culprit = if coerce_number(a).is_none() { a } else { b };
+            Err(RuntimeError::new(format!(
+                "attempt to perform bitwise operation on a {} value",
+                culprit.type_name()
+            )))
+        }
+    }
+}
+
+/// The comparison operators that may dispatch to metamethods.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Lt,
+    Le,
+}
+
+/// The result of comparing `a` and `b` where both are integers or both are floats: the
+/// interpreter's fast path, leaving the rest to [`compare_meta`].
+#[inline(always)]
+pub(crate) fn compare_fast(op: CompareOp, a: Value<'_>, b: Value<'_>) -> Option<bool> {
+    match (a, b) {
+        (Value::Integer(x), Value::Integer(y)) => Some(match op {
+            CompareOp::Eq => x == y,
+            CompareOp::Lt => x < y,
+            CompareOp::Le => x <= y,
+        }),
+        (Value::Number(x), Value::Number(y)) => Some(match op {
+            CompareOp::Eq => x == y,
+            CompareOp::Lt => x < y,
+            CompareOp::Le => x <= y,
+        }),
+        _ => None,
+    }
+}
+
+/// Resolves a comparison to either a boolean or the metamethod call deciding it.
+pub fn compare_meta<'gc>(
+    ctx: Context<'gc>,
+    op: CompareOp,
+    a: Value<'gc>,
+    b: Value<'gc>,
+) -> Result<MetaResult<'gc>, RuntimeError> {
+    let (result, name) = match op {
+        CompareOp::Eq => {
+            if a == b {
+                return Ok(MetaResult::Value(Value::Boolean(true)));
+            }
+            if !matches!(
+                (a, b),
+                (Value::Table(_), Value::Table(_)) | (Value::UserData(_), Value::UserData(_))
+            ) {
+                return Ok(MetaResult::Value(Value::Boolean(false)));
+            }
+            (None, "__eq")
+        }
+        CompareOp::Lt => (less_than(a, b), "__lt"),
+        CompareOp::Le => (less_equal(a, b), "__le"),
+    };
+    if let Some(result) = result {
+        return Ok(MetaResult::Value(Value::Boolean(result)));
+    }
+    let lookup = |value| match op {
+        CompareOp::Eq => fast_metamethod(ctx, value, MetaEvent::Eq),
+        _ => metamethod(ctx, value, name),
+    };
+    let mut handler = lookup(a);
+    if handler.is_nil() {
+        handler = lookup(b);
+    }
+    match callable(handler) {
+        Some(f) => Ok(MetaResult::Call(f, vec![a, b])),
+        None if op == CompareOp::Eq => Ok(MetaResult::Value(Value::Boolean(false))),
+        None => {
+            let (ta, tb) = (a.type_name(), b.type_name());
+            Err(RuntimeError::new(if ta == tb {
+                format!("attempt to compare two {ta} values")
+            } else {
+                format!("attempt to compare {ta} with {tb}")
+            }))
+        }
+    }
+}
+
+/// Concatenates `operands` the way `..` does, right to left, leaving them partly reduced in place.
+///
+/// Each run of strings and numbers is joined into one buffer, so a long chain costs a single copy
+/// instead of one per operand, and a run starting with a string is appended to it, in place if that
+/// string was the last one appended to. Returns the result once there is one, or else the index of the
+/// operand to replace with the result of the `__concat` call to make, after which the caller goes
+/// on concatenating the operands up to and including that index. Errors come with the index of the
+/// operand at fault.
+pub fn concat<'gc>(
+    ctx: Context<'gc>,
+    operands: &mut [Value<'gc>],
+) -> Result<(usize, MetaResult<'gc>), (usize, RuntimeError)> {
+    let is_operand =
+        |v: &Value<'gc>| matches!(v, Value::String(_) | Value::Integer(_) | Value::Number(_));
+    let mut top = operands.len();
+    while top > 1 {
+        let run = operands[..top]
+            .iter()
+            .rev()
+            .take_while(|v| is_operand(v))
+            .count();
+        if run >= 2 {
+            let start = top - run;
+            // A string first is appended to, which `s = s .. x` in a loop does in place.
+            let (first, rest) = match operands[start] {
+                Value::String(s) => (Some(s), start + 1),
+                _ => (None, start),
+            };
+            // Numbers are rarely longer than this, so the buffer is usually the right size.
+            let capacity = operands[rest..top]
+                .iter()
+                .map(|v| match v {
+                    Value::String(s) => s.len(),
+                    _ => 24,
+                })
+                .sum();
+            let mut builder = LuaStringBuilder::with_capacity(capacity);
+            for &value in &operands[rest..top] {
+                builder.push_value(value);
+            }
+            operands[start] = Value::String(match first {
+                Some(s) => s.append(ctx.mutation(), builder.as_bytes()),
+                None => builder.finish(ctx.mutation()),
+            });
+            top = start + 1;
+            continue;
+        }
+        let (a, b) = (operands[top - 2], operands[top - 1]);
+        let mut handler = metamethod(ctx, a, "__concat");
+        if handler.is_nil() {
+            handler = metamethod(ctx, b, "__concat");
+        }
+        return match callable(handler) {
+            Some(f) => Ok((top - 2, MetaResult::Call(f, vec![a, b]))),
+            None => {
+                let culprit = if is_operand(&a) { top - 1 } else { top - 2 };
+                Err((
+                    culprit,
+                    RuntimeError::new(format!(
+                        "attempt to concatenate a {} value",
+                        operands[culprit].type_name()
+                    )),
+                ))
+            }
+        };
+    }
+    Ok((0, MetaResult::Value(operands[0])))
+}
+
+/// Performs `#value`, returning the call to make for a `__len` metamethod. Strings always have their
+/// byte length, while a table's metamethod takes precedence over its border.
+pub fn len_meta<'gc>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+) -> Result<MetaResult<'gc>, RuntimeError> {
+    if let Value::String(s) = value {
+        return Ok(MetaResult::Value(Value::Integer(s.len() as i64)));
+    }
+    match callable(fast_metamethod(ctx, value, MetaEvent::Len)) {
+        Some(f) => Ok(MetaResult::Call(f, vec![value, value])),
+        None => len(value).map(MetaResult::Value),
+    }
+}
+
+/// The length of a string or table, without metamethods.
+pub fn len(value: Value<'_>) -> Result<Value<'_>, RuntimeError> {
+    match value {
+        Value::String(s) => Ok(Value::Integer(s.len() as i64)),
+        Value::Table(t) => Ok(Value::Integer(t.length() as i64)),
+        _ => Err(RuntimeError::new(format!(
+            "attempt to get length of a {} value",
+            value.type_name()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_format_like_c() {
+        let cases = [
+            (0.1, "0.1"),
+            (0.1 + 0.2, "0.3"),
+            (1.0 / 3.0, "0.33333333333333"),
+            (100.0, "100.0"),
+            (-0.0, "-0.0"),
+            (9.999999999999999, "10.0"),
+            (12345678901234.0, "12345678901234.0"),
+            (123456789012345.0, "1.2345678901234e+14"),
+            (1e15, "1e+15"),
+            (1e100, "1e+100"),
+            (0.0001, "0.0001"),
+            (1e-5, "1e-05"),
+            (2f64.powi(53), "9.007199254741e+15"),
+            (2f64.powi(63), "9.2233720368548e+18"),
+            (i64::MIN as f64, "-9.2233720368548e+18"),
+            (f64::MAX, "1.7976931348623e+308"),
+            (5e-324, "4.9406564584125e-324"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+            (f64::NAN, "nan"),
+            (-f64::NAN, "-nan"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(number_to_string(n), expected, "{n:?}");
+        }
+        assert_eq!(format_g(0.000123456, 3), "0.000123");
+        assert_eq!(format_g(1234567.0, 6), "1.23457e+06");
+        assert_eq!(format_g(0.5, 0), "0.5");
+    }
+
+    #[test]
+    fn fast_paths_agree() {
+        let values: [Value<'static>; 9] = [
+            Value::Integer(7),
+            Value::Integer(-2),
+            Value::Integer(0),
+            Value::Integer(i64::MAX),
+            Value::Number(2.5),
+            Value::Number(-0.0),
+            Value::Number(f64::NAN),
+            Value::Number(f64::INFINITY),
+            Value::Boolean(true),
+        ];
+        // NaN is never equal to itself.
+        let same = |a: Value<'static>, b: Value<'static>| a == b || a.to_string() == b.to_string();
+        for a in values {
+            for b in values {
+                for op in [ArithOp::Add, ArithOp::Sub, ArithOp::Mul, ArithOp::Div] {
+                    if let Some(fast) = arith_fast(op, a, b) {
+                        let slow = arith(op, a, b).unwrap().unwrap();
+                        assert!(same(fast, slow), "{a} {op:?} {b}: {fast} != {slow}");
+                        assert_eq!(
+                            matches!(fast, Value::Integer(_)),
+                            matches!(slow, Value::Integer(_))
+                        );
+                    }
+                }
+                for op in [CompareOp::Eq, CompareOp::Lt, CompareOp::Le] {
+                    if let Some(fast) = compare_fast(op, a, b) {
+                        let slow = match op {
+                            CompareOp::Eq => Some(a == b),
+                            CompareOp::Lt => less_than(a, b),
+                            CompareOp::Le => less_equal(a, b),
+                        };
+                        assert_eq!(Some(fast), slow, "{a} {op:?} {b}");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::function::StaticSequence;
+use crate::mem::{Gc, Lock, Mutation};
+use crate::{Context, LuaError, LuaString, MaybeSend, NativeReturn, Sequence, Thread, Value};
+
+/// The arguments of a native function call, which become its return values.
+///
+/// A native function receives its arguments starting at index 0 and returns whatever values are on the stack
+/// when it finishes.
+pub struct Stack<'gc, 'a> {
+    thread: Thread<'gc>,
+    values: &'a mut Vec<Value<'gc>>,
+    bottom: usize,
+    upvalues: &'gc [Gc<'gc, Lock<Value<'gc>>>],
+    /// Where the function being called sits on its thread's stack.
+    func: Option<usize>,
+    then: Option<Box<dyn Sequence<'gc> + 'gc>>,
+}
+
+impl<'gc, 'a> Stack<'gc, 'a> {
+    pub(crate) fn new(
+        thread: Thread<'gc>,
+        values: &'a mut Vec<Value<'gc>>,
+        bottom: usize,
+    ) -> Stack<'gc, 'a> {
+        debug_assert!(bottom <= values.len());
+        Stack {
+            thread,
+            values,
+            bottom,
+            upvalues: &[],
+            func: None,
+            then: None,
+        }
+    }
+
+    /// Records where the function being called sits on the thread's stack, which tells
+    /// [`function_name`](Stack::function_name) where to look.
+    pub(crate) fn with_func(mut self, func: usize) -> Self {
+        self.func = Some(func);
+        self
+    }
+
+    /// Gives the function being called access to the upvalues of its
+    /// [`NativeClosure`](crate::NativeClosure).
+    pub(crate) fn with_upvalues(mut self, upvalues: &'gc [Gc<'gc, Lock<Value<'gc>>>]) -> Self {
+        self.upvalues = upvalues;
+        self
+    }
+
+    /// The thread the function was called on, which it can make calls of its own on.
+    #[inline]
+    pub fn thread(&self) -> Thread<'gc> {
+        self.thread
+    }
+
+    /// The name the calling Lua code knows the function by, like `f` for a call to `f(x)` or
+    /// `t.f(x)`, or nothing if it wasn't called directly from Lua code.
+    pub fn function_name(&self) -> Option<LuaString<'gc>> {
+        super::debug::native_name(self.thread, self.func?)
+    }
+
+    /// Continues with `sequence` once the yield or call the function returns with is over. Returning
+    /// [`NativeReturn::Return`] drops it.
+    pub fn then(&mut self, sequence: impl Sequence<'gc> + 'gc) {
+        self.then = Some(Box::new(sequence));
+    }
+
+    /// Like [`then`](Stack::then), with a closure that can't hold Lua values between steps.
+    pub fn then_fn<F>(&mut self, f: F)
+    where
+        F: FnMut(Context<'gc>, &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>>
+            + MaybeSend
+            + 'static,
+    {
+        self.then(StaticSequence(f));
+    }
+
+    pub(crate) fn take_then(&mut self) -> Option<Box<dyn Sequence<'gc> + 'gc>> {
+        self.then.take()
+    }
+
+    /// Returns upvalue `index` of the native closure being called, or nil if there is no such
+    /// upvalue.
+    #[inline]
+    pub fn upvalue(&self, index: usize) -> Value<'gc> {
+        self.upvalues
+            .get(index)
+            .map(|v| v.get())
+            .unwrap_or_default()
+    }
+
+    /// Sets upvalue `index` of the native closure being called, which keeps the value for the next
+    /// call.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no such upvalue.
+    pub fn set_upvalue(&self, mc: &Mutation<'gc>, index: usize, value: Value<'gc>) {
+        self.upvalues[index].set(mc, value);
+    }
+
+    /// Returns the value at `index`, or nil if it is out of range.
+    #[inline]
+    pub fn get(&self, index: usize) -> Value<'gc> {
+        self.values
+            .get(self.bottom + index)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    #[inline]
+    pub fn push(&mut self, value: Value<'gc>) {
+        self.values.push(value);
+    }
+
+    #[inline]
+    pub fn pop(&mut self) -> Option<Value<'gc>> {
+        if self.values.len() > self.bottom {
+            self.values.pop()
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.values.truncate(self.bottom);
+    }
+
+    #[inline]
+    pub fn truncate(&mut self, len: usize) {
+        self.values.truncate(self.bottom + len);
+    }
+
+    /// Resizes the stack to exactly `len` values, padding with nil.
+    #[inline]
+    pub fn resize(&mut self, len: usize) {
+        self.values.resize(self.bottom + len, Value::Nil);
+    }
+
+    pub fn extend(&mut self, values: impl IntoIterator<Item = Value<'gc>>) {
+        self.values.extend(values);
+    }
+
+    /// Replaces the whole contents of the stack, typically with the return values.
+    pub fn replace(&mut self, values: &[Value<'gc>]) {
+        self.clear();
+        self.values.extend_from_slice(values);
+    }
+}
+
+impl<'gc, 'a> Deref for Stack<'gc, 'a> {
+    type Target = [Value<'gc>];
+
+    #[inline]
+    fn deref(&self) -> &[Value<'gc>] {
+        &self.values[self.bottom..]
+    }
+}
+
+impl<'gc, 'a> DerefMut for Stack<'gc, 'a> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [Value<'gc>] {
+        &mut self.values[self.bottom..]
+    }
+}
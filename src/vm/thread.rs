@@ -0,0 +1,570 @@
+use std::{fmt, mem};
+
+use crate::mem::{Gc, Managed, Mutation, RefLock, Tracer};
+use crate::{Context, Function, LuaError, RuntimeError, UpValue, Value};
+
+use super::debug::HookState;
+use super::{
+    check_nesting, close_tbc, close_upvalues, continue_sequence, execute, finish_results, precall,
+    push_traceback, run_sequences, Called, Continuation, Frame,
+};
+
+/// Whether a thread can be resumed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThreadStatus {
+    /// Not started yet, or stopped in a yield.
+    Suspended,
+    /// Stopped for running out of fuel, with [fuel preemption](Context::set_fuel_preemption) on, or
+    /// for the collector to run, in the coroutine an [`Executor`](crate::Executor) runs. Resuming
+    /// it, once there is fuel again, continues where it stopped.
+    Preempted,
+    /// Running code, or waiting for a coroutine it resumed to yield or return.
+    Running,
+    /// Its function returned or raised an error.
+    Dead,
+}
+
+pub(super) struct ThreadState<'gc> {
+    pub(super) values: Vec<Value<'gc>>,
+    pub(super) frames: Vec<Frame<'gc>>,
+    /// The native functions waiting for calls they made or yields to finish, innermost last.
+    pub(super) sequences: Vec<Continuation<'gc>>,
+    /// The upvalues pointing into `values`, ordered by stack index.
+    pub(super) open_upvalues: Vec<UpValue<'gc>>,
+    /// The stack indices of the to-be-closed variables in scope, innermost last.
+    pub(super) tbc: Vec<usize>,
+    pub(super) status: ThreadStatus,
+    /// The error a coroutine died with, which its pending to-be-closed variables get when it is
+    /// closed.
+    pub(super) error: Option<Value<'gc>>,
+    /// While suspended in a yield: the stack index of the native function that yielded, and how
+    /// many results its caller expects. The arguments to the next resume become its results.
+    pub(super) yielded: Option<(usize, Option<usize>)>,
+    /// While running as a coroutine: the nesting level its interpreter loop runs at. Only native
+    /// functions called directly from that loop can yield.
+    pub(super) resume_nesting: Option<usize>,
+    /// One entry per protected call in progress on this thread, innermost last, with the message
+    /// handler it runs on errors.
+    pub(super) handlers: Vec<Option<Value<'gc>>>,
+    /// How many message handlers are running, which may go past the stack limits a little so that
+    /// they can handle the error of reaching them.
+    pub(super) in_handler: usize,
+    pub(super) hook: Option<HookState<'gc>>,
+    /// Set as the coroutine's loop stops for running out of fuel, to tell that from a yield.
+    pub(super) preempted: bool,
+    /// Emptied buffers that native functions ran with, for the next native calls to take the
+    /// arguments into instead of allocating.
+    pub(super) buffers: Vec<Vec<Value<'gc>>>,
+}
+
+unsafe impl<'gc> Managed for ThreadState<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.values.trace(tracer);
+        self.frames.trace(tracer);
+        self.sequences.trace(tracer);
+        self.open_upvalues.trace(tracer);
+        self.error.trace(tracer);
+        self.handlers.trace(tracer);
+        self.hook.trace(tracer);
+    }
+}
+
+/// A Lua thread: a value stack and call frames of running Lua code.
+///
+/// A thread created with [`Thread::new`] is for calling into with [`vm::call`](super::call), the
+/// way Lua's main thread is used. One created with [`Thread::with_function`] is a coroutine, which
+/// runs its function step by step through [`Thread::resume`].
+#[derive(Copy, Clone)]
+pub struct Thread<'gc>(pub(super) Gc<'gc, RefLock<ThreadState<'gc>>>);
+
+impl<'gc> Thread<'gc> {
+    /// Creates a thread with an empty stack. It counts as running and can't be resumed.
+    pub fn new(mc: &Mutation<'gc>) -> Thread<'gc> {
+        Thread::with_state(mc, ThreadStatus::Running, Vec::new())
+    }
+
+    /// Creates a suspended coroutine that runs `function` when first resumed.
+    pub fn with_function(mc: &Mutation<'gc>, function: Function<'gc>) -> Thread<'gc> {
+        Thread::with_state(mc, ThreadStatus::Suspended, vec![Value::Function(function)])
+    }
+
+    fn with_state(
+        mc: &Mutation<'gc>,
+        status: ThreadStatus,
+        values: Vec<Value<'gc>>,
+    ) -> Thread<'gc> {
+        Thread(Gc::new(
+            mc,
+            RefLock::new(ThreadState {
+                values,
+                frames: Vec::new(),
+                sequences: Vec::new(),
+                open_upvalues: Vec::new(),
+                tbc: Vec::new(),
+                status,
+                error: None,
+                yielded: None,
+                resume_nesting: None,
+                handlers: Vec::new(),
+                in_handler: 0,
+                hook: None,
+                preempted: false,
+                buffers: Vec::new(),
+            }),
+        ))
+    }
+
+    pub fn as_ptr(self) -> *const () {
+        Gc::as_ptr(self.0).cast()
+    }
+
+    /// The order the thread was made in; see [`Gc::serial`].
+    pub(crate) fn serial(self) -> u32 {
+        Gc::serial(self.0)
+    }
+
+    /// Returns true if the current collection has marked the thread so far.
+    #[inline]
+    pub(crate) fn is_marked(self, tracer: &Tracer) -> bool {
+        tracer.is_marked(self.0)
+    }
+
+    pub fn status(self) -> ThreadStatus {
+        self.0.borrow().status
+    }
+
+    /// Returns true for a coroutine, false for a thread made with [`Thread::new`].
+    pub fn is_coroutine(self) -> bool {
+        let st = self.0.borrow();
+        st.status != ThreadStatus::Running || st.resume_nesting.is_some()
+    }
+
+    /// Returns true if a native function called now from the thread's innermost Lua function could
+    /// yield: the thread is a running coroutine and isn't inside a metamethod or a call from native
+    /// code.
+    pub fn is_yieldable(self, ctx: Context<'gc>) -> bool {
+        self.0.borrow().resume_nesting == Some(ctx.state().nesting().get())
+    }
+
+    /// Returns the `chunk:line:` position of the Lua function `level` frames down the stack, with 1
+    /// the innermost one, as the `error` function prefixes to messages. The column follows the
+    /// line if [`Context::set_error_columns`] asks for it.
+    pub fn location(self, ctx: Context<'gc>, level: usize) -> Option<String> {
+        let st = self.0.borrow();
+        let frame = st.frames.len().checked_sub(level).map(|i| &st.frames[i])?;
+        Some(frame.location(ctx.error_columns()))
+    }
+
+    /// Runs a suspended coroutine until its function yields or returns, and returns the values it
+    /// yielded or returned.
+    ///
+    /// On the first resume `args` are passed to the function; after that they become the results of
+    /// the yield the coroutine is suspended in. If the function raises an error the coroutine dies
+    /// and the error is returned.
+    ///
+    /// A preempted coroutine continues where it ran out of fuel, ignoring `args`, and returns no
+    /// values if preempted again.
+    pub fn resume(
+        self,
+        ctx: Context<'gc>,
+        args: &[Value<'gc>],
+    ) -> Result<Vec<Value<'gc>>, LuaError<'gc>> {
+        self.resume_with(ctx, Ok(args))
+    }
+
+    /// Resumes a suspended coroutine by raising `error` where it is suspended, as if the function
+    /// it yielded from had failed.
+    ///
+    /// A yield never crosses a protected call, so nothing in the coroutine can catch the error: it
+    /// dies, and the error is returned with the coroutine's traceback.
+    pub fn resume_error(
+        self,
+        ctx: Context<'gc>,
+        error: LuaError<'gc>,
+    ) -> Result<Vec<Value<'gc>>, LuaError<'gc>> {
+        self.resume_with(ctx, Err(error))
+    }
+
+    fn resume_with(
+        self,
+        ctx: Context<'gc>,
+        args: Result<&[Value<'gc>], LuaError<'gc>>,
+    ) -> Result<Vec<Value<'gc>>, LuaError<'gc>> {
+        let nesting = ctx.state().nesting();
+        let (preempted, yielded) = {
+            let mut st = self.0.borrow_mut(&ctx);
+            match st.status {
+                ThreadStatus::Suspended | ThreadStatus::Preempted => {}
+                ThreadStatus::Running => {
+                    return Err(RuntimeError::new("cannot resume non-suspended coroutine").into())
+                }
+                ThreadStatus::Dead => {
+                    return Err(RuntimeError::new("cannot resume dead coroutine").into())
+                }
+            }
+            check_nesting(ctx)?;
+            let preempted = st.status == ThreadStatus::Preempted;
+            st.status = ThreadStatus::Running;
+            st.resume_nesting = Some(nesting.get() + 1);
+            match args {
+                Ok(args) if !preempted => st.values.extend_from_slice(args),
+                _ => {}
+            }
+            (preempted, st.yielded.take())
+        };
+
+        nesting.set(nesting.get() + 1);
+        let result = match args {
+            // The frames are as they were when the fuel ran out, down to the instruction to run.
+            Ok(_) if preempted => execute(ctx, self, 1),
+            Ok(args) => self.run(ctx, yielded, args.len()),
+            Err(err) => Err(err),
+        };
+        nesting.set(nesting.get() - 1);
+
+        let mut st = self.0.borrow_mut(&ctx);
+        let st = &mut *st;
+        st.resume_nesting = None;
+        match result {
+            Ok(Some(values)) => {
+                st.status = match mem::take(&mut st.preempted) {
+                    true => ThreadStatus::Preempted,
+                    false => ThreadStatus::Suspended,
+                };
+                Ok(values)
+            }
+            Ok(None) => {
+                st.status = ThreadStatus::Dead;
+                Ok(mem::take(&mut st.values))
+            }
+            Err(mut err) => {
+                st.status = ThreadStatus::Dead;
+                push_traceback(&mut err, &st.frames, &st.sequences, 0);
+                st.frames.clear();
+                st.sequences.clear();
+                st.error = Some(err.value(ctx));
+                // To-be-closed variables wait for the coroutine to be closed, so the stack stays.
+                if st.tbc.is_empty() {
+                    close_upvalues(&ctx, &st.values, &mut st.open_upvalues, 0);
+                    st.values.clear();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Kills a suspended or dead coroutine, calling `__close` on the to-be-closed variables still
+    /// in scope in it.
+    ///
+    /// Returns the error the coroutine died with, if it did, or the last one raised while closing.
+    pub fn close(self, ctx: Context<'gc>) -> Result<(), LuaError<'gc>> {
+        let err = {
+            let mut st = self.0.borrow_mut(&ctx);
+            if st.status == ThreadStatus::Running {
+                return Err(RuntimeError::new("cannot close a running coroutine").into());
+            }
+            st.status = ThreadStatus::Dead;
+            st.frames.clear();
+            st.sequences.clear();
+            st.yielded = None;
+            st.error.take().map(LuaError::new)
+        };
+        let result = close_tbc(ctx, self, 0, err);
+        let mut st = self.0.borrow_mut(&ctx);
+        let st = &mut *st;
+        close_upvalues(&ctx, &st.values, &mut st.open_upvalues, 0);
+        st.values.clear();
+        result
+    }
+
+    /// Starts or continues the coroutine with `nargs` arguments on top of its stack. Returns the
+    /// yielded values if it yielded again.
+    fn run(
+        self,
+        ctx: Context<'gc>,
+        yielded: Option<(usize, Option<usize>)>,
+        nargs: usize,
+    ) -> Result<Option<Vec<Value<'gc>>>, LuaError<'gc>> {
+        let called = match yielded {
+            Some((func_idx, results)) => {
+                let mut st = self.0.borrow_mut(&ctx);
+                let continues = st
+                    .sequences
+                    .last()
+                    .is_some_and(|c| c.func == func_idx && c.depth == st.frames.len());
+                if continues {
+                    // The sequence gets the values above the function's slot, unadjusted.
+                    st.values.insert(func_idx, Value::Nil);
+                    drop(st);
+                    continue_sequence(ctx, self)?
+                } else {
+                    finish_results(&mut st, func_idx, func_idx, nargs, results);
+                    Called::Native
+                }
+            }
+            None => precall(ctx, self, 0, nargs, None, false)?,
+        };
+        let called = match called {
+            Called::Native => run_sequences(ctx, self, 1)?,
+            called => called,
+        };
+        match called {
+            Called::Lua => execute(ctx, self, 1),
+            Called::Native if self.0.borrow().frames.is_empty() => Ok(None),
+            Called::Native => execute(ctx, self, 1),
+            Called::Yield { func, results } => Ok(Some(self.take_yield(&ctx, func, results))),
+        }
+    }
+
+    /// Suspends the thread in the yield of the native function at `func_idx`, returning the values
+    /// it yielded.
+    pub(super) fn take_yield(
+        self,
+        mc: &Mutation<'gc>,
+        func_idx: usize,
+        results: Option<usize>,
+    ) -> Vec<Value<'gc>> {
+        let mut st = self.0.borrow_mut(mc);
+        let values = st.values.split_off(func_idx + 1);
+        st.values.truncate(func_idx);
+        st.yielded = Some((func_idx, results));
+        values
+    }
+}
+
+impl<'gc> PartialEq for Thread<'gc> {
+    fn eq(&self, other: &Thread<'gc>) -> bool {
+        Gc::ptr_eq(self.0, other.0)
+    }
+}
+
+impl<'gc> Eq for Thread<'gc> {}
+
+impl<'gc> fmt::Debug for Thread<'gc> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Thread({:p})", self.as_ptr())
+    }
+}
+
+unsafe impl<'gc> Managed for Thread<'gc> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.trace(tracer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::mem::Arena;
+    use crate::vm::{call, Stack};
+    use crate::{Closure, LuaString, NativeReturn, State, StateRoot, Table};
+
+    #[allow(clippy::redundant_closure)]
+    fn new_arena() -> Arena<StateRoot> {
+        Arena::new(|mc| State::new(mc))
+    }
+
+    fn yield_<'gc>(_: Context<'gc>, _: &mut Stack<'gc, '_>) -> Result<NativeReturn, LuaError<'gc>> {
+        Ok(NativeReturn::Yield)
+    }
+
+    /// Resumes the thread passed as the first argument with the rest of the arguments.
+    fn resume<'gc>(
+        ctx: Context<'gc>,
+        stack: &mut Stack<'gc, '_>,
+    ) -> Result<NativeReturn, LuaError<'gc>> {
+        let Value::Thread(thread) = stack.get(0) else {
+            return Err(RuntimeError::new("expected a thread").into());
+        };
+        let args: Vec<_> = (1..stack.len()).map(|i| stack.get(i)).collect();
+        let results = thread.resume(ctx, &args)?;
+        stack.replace(&results);
+        Ok(NativeReturn::Return)
+    }
+
+    fn set_global<'gc>(ctx: Context<'gc>, name: &str, value: Value<'gc>) {
+        let key = Value::String(LuaString::new(&ctx, name.as_bytes()));
+        ctx.globals().set(&ctx, key, value).unwrap();
+    }
+
+    /// Runs `source` on a fresh thread with `yield` and `resume` available.
+    fn run<'gc>(ctx: Context<'gc>, source: &str) -> Result<Vec<Value<'gc>>, LuaError<'gc>> {
+        set_global(ctx, "yield", Value::Function(Function::Native(yield_)));
+        set_global(ctx, "resume", Value::Function(Function::Native(resume)));
+        let proto = compile(&ctx, source.as_bytes(), "test").unwrap();
+        let closure = Closure::with_env(&ctx, proto, Value::Table(ctx.globals()));
+        call(ctx, Thread::new(&ctx), Value::Function(closure.into()), &[])
+    }
+
+    /// Creates a coroutine from the function that `source` returns.
+    fn coroutine<'gc>(ctx: Context<'gc>, source: &str) -> Thread<'gc> {
+        let Value::Function(f) = run(ctx, source).unwrap()[0] else {
+            panic!("expected a function");
+        };
+        Thread::with_function(&ctx, f)
+    }
+
+    fn ints<'gc>(values: &[i64]) -> Vec<Value<'gc>> {
+        values.iter().map(|&i| Value::Integer(i)).collect()
+    }
+
+    #[test]
+    fn resume_and_yield_pass_values() {
+        new_arena().mutate(|mc, state| {
+            let ctx = Context::new(mc, state);
+            let co = coroutine(
+                ctx,
+                "return function(a, b)
+                    local c = yield(a + b, a - b)
+                    local d, e = yield(c * 2)
+                    return d + e, 'done'
+                end",
+            );
+            assert_eq!(co.status(), ThreadStatus::Suspended);
+            assert_eq!(co.resume(ctx, &ints(&[5, 3])).unwrap(), ints(&[8, 2]));
+            assert_eq!(co.status(), ThreadStatus::Suspended);
+            assert_eq!(co.resume(ctx, &ints(&[10])).unwrap(), ints(&[20]));
+            let results = co.resume(ctx, &ints(&[1, 2, 3])).unwrap();
+            assert_eq!(results[0], Value::Integer(3));
+            assert_eq!(results[1].to_string(), "done");
+            assert_eq!(co.status(), ThreadStatus::Dead);
+
+            let err = co.resume(ctx, &[]).unwrap_err();
+            assert_eq!(err.to_string(), "cannot resume dead coroutine");
+
+            // A native function can be the coroutine's body.
+            let co = Thread::with_function(&ctx, Function::Native(yield_));
+            assert_eq!(co.resume(ctx, &ints(&[1, 2])).unwrap(), ints(&[1, 2]));
+            assert_eq!(co.resume(ctx, &ints(&[3])).unwrap(), ints(&[3]));
+            assert_eq!(co.status(), ThreadStatus::Dead);
+        });
+    }
+
+    #[test]
+    fn nested_coroutines_and_upvalues() {
+        new_arena().mutate(|mc, state| {
+            let ctx = Context::new(mc, state);
+            let inner = coroutine(
+                ctx,
+                "return function()
+                    for i = 1, 3 do yield(i) end
+                    return 0
+                end",
+            );
+            set_global(ctx, "inner", Value::Thread(inner));
+            let outer = coroutine(
+                ctx,
+                "local n = 0
+                return function()
+                    local total = 0
+                    local f = function(x) total = total + x n = n + 1 end
+                    while true do
+                        local v = resume(inner)
+                        if v == 0 then break end
+                        f(v)
+                        yield(total)
+                    end
+                    return total, n
+                end",
+            );
+            let mut seen = Vec::new();
+            while outer.status() == ThreadStatus::Suspended {
+                seen.extend(outer.resume(ctx, &[]).unwrap());
+            }
+            assert_eq!(seen, ints(&[1, 3, 6, 6, 3]));
+            assert_eq!(inner.status(), ThreadStatus::Dead);
+        });
+    }
+
+    #[test]
+    fn errors() {
+        new_arena().mutate(|mc, state| {
+            let ctx = Context::new(mc, state);
+            let co = coroutine(ctx, "return function(x) yield(x) return x + nil end");
+            co.resume(ctx, &ints(&[1])).unwrap();
+            let err = co.resume(ctx, &[]).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "test:1: attempt to perform arithmetic on a nil value"
+            );
+            assert_eq!(co.status(), ThreadStatus::Dead);
+
+            // A running coroutine can't be resumed again.
+            let co = coroutine(ctx, "return function() return resume(co) end");
+            set_global(ctx, "co", Value::Thread(co));
+            let err = co.resume(ctx, &[]).unwrap_err();
+            assert_eq!(err.to_string(), "cannot resume non-suspended coroutine");
+
+            let err = run(ctx, "yield(1)").unwrap_err();
+            assert_eq!(err.to_string(), "attempt to yield from outside a coroutine");
+
+            // Metamethods run in a nested call, which can't be suspended.
+            let t = Table::new(&ctx);
+            let mt = Table::new(&ctx);
+            let index = Value::String(LuaString::new(&ctx, b"__index"));
+            mt.set(&ctx, index, Value::Function(Function::Native(yield_)))
+                .unwrap();
+            t.set_metatable(&ctx, Some(mt));
+            set_global(ctx, "t", Value::Table(t));
+            let co = coroutine(ctx, "return function() return t.x end");
+            let err = co.resume(ctx, &[]).unwrap_err();
+            assert_eq!(err.to_string(), "attempt to yield across a C-call boundary");
+            assert_eq!(co.status(), ThreadStatus::Dead);
+        });
+    }
+
+    #[test]
+    fn closing_coroutines() {
+        /// Records the error it is closed with, or `true` without one, in the global `closed`.
+        fn record<'gc>(
+            ctx: Context<'gc>,
+            stack: &mut Stack<'gc, '_>,
+        ) -> Result<NativeReturn, LuaError<'gc>> {
+            let closed = match stack.get(1) {
+                Value::Nil => Value::Boolean(true),
+                err => err,
+            };
+            set_global(ctx, "closed", closed);
+            Ok(NativeReturn::Return)
+        }
+
+        new_arena().mutate(|mc, state| {
+            let ctx = Context::new(mc, state);
+            let obj = Table::new(&ctx);
+            let mt = Table::new(&ctx);
+            let close = Value::String(LuaString::new(&ctx, b"__close"));
+            mt.set(&ctx, close, Value::Function(Function::Native(record)))
+                .unwrap();
+            obj.set_metatable(&ctx, Some(mt));
+            set_global(ctx, "obj", Value::Table(obj));
+            let closed = || {
+                let key = Value::String(LuaString::new(&ctx, b"closed"));
+                ctx.globals().get(key).to_string()
+            };
+
+            // A coroutine suspended in a yield still has its variable in scope.
+            let co = coroutine(ctx, "return function() local x <close> = obj yield(1) end");
+            assert_eq!(co.resume(ctx, &[]).unwrap(), ints(&[1]));
+            assert_eq!(closed(), "nil");
+            co.close(ctx).unwrap();
+            assert_eq!(closed(), "true");
+            assert_eq!(co.status(), ThreadStatus::Dead);
+
+            // One that died with an error closes its variables with that error.
+            let co = coroutine(
+                ctx,
+                "return function() local x <close> = obj return x + 1 end",
+            );
+            let err = co.resume(ctx, &[]).unwrap_err().to_string();
+            assert_eq!(closed(), "true");
+            assert_eq!(co.close(ctx).unwrap_err().to_string(), err);
+            assert_eq!(closed(), err);
+
+            // One that never started has nothing to close.
+            let co = coroutine(ctx, "return function() end");
+            assert!(co.close(ctx).is_ok());
+            assert_eq!(co.status(), ThreadStatus::Dead);
+        });
+    }
+}
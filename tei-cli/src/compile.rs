@@ -0,0 +1,162 @@
+//! The `compile` and `check` subcommands, which turn scripts into binary chunks and report the
+//! syntax errors of scripts without running them, as `luac` does.
+
+use std::fs;
+use std::path::Path;
+
+use tei::bytecode::{self, Prototype};
+use tei::mem::Gc;
+use tei::{Context, Function, Lua, LuaError};
+
+pub const COMPILE_USAGE: &str = "usage: tei compile [options] script
+Available options are:
+  -o name   write the binary chunk to 'name' (default: the script with '.tbc' for its extension)
+  --strip   leave out debug information
+  -l        list the bytecode";
+
+pub const CHECK_USAGE: &str = "usage: tei check [-l] scripts...
+Available options are:
+  -l        list the bytecode";
+
+/// `tei compile`: writes a script, or a binary chunk, as a binary chunk. Returns whether it did.
+pub fn compile(args: &[String]) -> bool {
+    let mut output = None;
+    let mut strip = false;
+    let mut list = false;
+    let mut script = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => match args.next() {
+                Some(name) => output = Some(name.clone()),
+                None => return usage("'-o' needs argument", COMPILE_USAGE),
+            },
+            "--strip" | "-s" => strip = true,
+            "-l" => list = true,
+            option if option.starts_with('-') => {
+                return usage(&format!("unrecognized option '{option}'"), COMPILE_USAGE);
+            }
+            name if script.is_none() => script = Some(name.to_owned()),
+            _ => return usage("only one script can be compiled at a time", COMPILE_USAGE),
+        }
+    }
+    let Some(script) = script else {
+        return usage("no script given", COMPILE_USAGE);
+    };
+    let output = output.unwrap_or_else(|| {
+        Path::new(&script)
+            .with_extension("tbc")
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let compiled = Lua::empty().enter(|ctx| -> Result<_, String> {
+        let proto = load(ctx, &script)?;
+        if list {
+            print!("{}", bytecode::disassemble(&proto));
+        }
+        Ok(bytecode::dump(&proto, strip))
+    });
+    let chunk = match compiled {
+        Ok(chunk) => chunk,
+        Err(message) => return failure(&message),
+    };
+    match fs::write(&output, chunk) {
+        Ok(()) => true,
+        Err(err) => failure(&format!("cannot write {output}: {err}")),
+    }
+}
+
+/// `tei check`: loads each script without running it, reporting the ones that don't compile.
+/// Returns whether they all did.
+pub fn check(args: &[String]) -> bool {
+    let mut list = false;
+    let mut scripts = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-l" => list = true,
+            option if option.starts_with('-') => {
+                return usage(&format!("unrecognized option '{option}'"), CHECK_USAGE);
+            }
+            script => scripts.push(script),
+        }
+    }
+    if scripts.is_empty() {
+        return usage("no scripts given", CHECK_USAGE);
+    }
+
+    let mut lua = Lua::empty();
+    let mut ok = true;
+    for script in scripts {
+        let checked = lua.enter(|ctx| -> Result<_, String> {
+            let proto = load(ctx, script)?;
+            if list {
+                print!("{}", bytecode::disassemble(&proto));
+            }
+            Ok(())
+        });
+        if let Err(message) = checked {
+            eprintln!("tei: {message}");
+            ok = false;
+        }
+    }
+    ok
+}
+
+fn usage(message: &str, usage: &str) -> bool {
+    eprintln!("tei: {message}\n{usage}");
+    false
+}
+
+fn failure(message: &str) -> bool {
+    eprintln!("tei: {message}");
+    false
+}
+
+/// Compiles the file `script`, or loads it if it is a binary chunk.
+fn load<'gc>(ctx: Context<'gc>, script: &str) -> Result<Gc<'gc, Prototype<'gc>>, String> {
+    let source = fs::read(script).map_err(|err| format!("cannot open {script}: {err}"))?;
+    let function = ctx
+        .load(&format!("@{script}"), crate::skip_shebang(source))
+        .map_err(|err: LuaError<'_>| err.to_string())?;
+    match function {
+        Function::Closure(closure) => Ok(closure.proto()),
+        _ => unreachable!("loaded chunks are Lua functions"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_and_lists() {
+        let dir = std::env::temp_dir().join(format!("tei-compile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("s.lua");
+        fs::write(
+            &script,
+            "#!/usr/bin/env tei\nlocal x = 1\nfunction f() return x + 2 end\n",
+        )
+        .unwrap();
+        let script = script.to_string_lossy().into_owned();
+        let output = dir.join("s.tbc").to_string_lossy().into_owned();
+
+        assert!(compile(&[script.clone(), "--strip".to_owned()]));
+        let chunk = fs::read(&output).unwrap();
+        assert!(chunk.starts_with(bytecode::SIGNATURE));
+        assert!(check(std::slice::from_ref(&output)));
+
+        let listed = Lua::empty().enter(|ctx| bytecode::disassemble(&load(ctx, &script).unwrap()));
+        assert!(
+            listed.contains(&format!("main <{script}:0,0> (4 instructions)")),
+            "{listed}"
+        );
+        // The blanked-out shebang line still counts.
+        assert!(listed.contains("\t1\t[2]\tLOADI    \t0 1\n"), "{listed}");
+
+        fs::write(&script, "local = 1").unwrap();
+        assert!(!check(&[script]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,353 @@
+//! `tei`: runs Lua scripts, or reads statements and expressions at a prompt and runs them one at a
+//! time, in the manner of the reference implementation's `lua`.
+
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::process::ExitCode;
+
+mod compile;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use tei::compiler::chunk_id;
+use tei::stdlib::{self, inspect, InspectOptions};
+use tei::{Context, Error, Lua, LuaString, Table, Variadic};
+
+const USAGE: &str = "usage: tei [options] [script [args]]
+       tei compile [options] script
+       tei check [-l] scripts...
+Available options are:
+  -e stat   execute string 'stat'
+  -i        enter interactive mode after executing 'script'
+  -v        show version information
+  --        stop handling options
+  -         stop handling options and execute stdin";
+
+/// What the command line asks for.
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    /// The `-e` statements, in order.
+    statements: Vec<String>,
+    interactive: bool,
+    version: bool,
+    /// The script and its arguments. A script of `-` is standard input.
+    script: Option<(String, Vec<String>)>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-e" => match args.next() {
+                    Some(statement) => options.statements.push(statement.clone()),
+                    None => return Err("'-e' needs argument".to_owned()),
+                },
+                "-i" => options.interactive = true,
+                "-v" => options.version = true,
+                "--" => {
+                    if let Some(script) = args.next() {
+                        options.script = Some((script.clone(), args.cloned().collect()));
+                    }
+                    break;
+                }
+                option if option.starts_with('-') && option != "-" => {
+                    return Err(format!("unrecognized option '{option}'"));
+                }
+                script => {
+                    options.script = Some((script.to_owned(), args.cloned().collect()));
+                    break;
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn main() -> ExitCode {
+    let argv: Vec<String> = env::args().collect();
+    let args = argv.get(1..).unwrap_or_default();
+    // A script named like a subcommand runs as `tei -- name`.
+    let subcommand = match args.first().map(String::as_str) {
+        Some("compile") => Some(compile::compile(&args[1..])),
+        Some("check") => Some(compile::check(&args[1..])),
+        _ => None,
+    };
+    match subcommand {
+        Some(true) => return ExitCode::SUCCESS,
+        Some(false) => return ExitCode::FAILURE,
+        None => {}
+    }
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("tei: {message}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // The script's arguments follow it, and the interpreter and its options come before.
+    let script = match &options.script {
+        Some((_, script_args)) => argv.len() - script_args.len() - 1,
+        None => 0,
+    };
+    let mut lua = Lua::with_debug();
+    lua.enter(|ctx| {
+        stdlib::load_inspect(ctx);
+        ctx.set_error_columns(true);
+        let arg = arg_table(ctx, &argv, script);
+        ctx.globals()
+            .set(&ctx, LuaString::new(&ctx, b"arg"), arg)
+            .expect("string keys are always valid");
+    });
+    if options.version {
+        println!("{}", version());
+    }
+    for statement in &options.statements {
+        if !run_chunk(&mut lua, "=(command line)", statement.as_bytes(), &[]) {
+            return ExitCode::FAILURE;
+        }
+    }
+    if let Some((script, args)) = &options.script {
+        if !run_script(&mut lua, script, args) {
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let ran_something = options.script.is_some() || !options.statements.is_empty();
+    if options.interactive || (!ran_something && !options.version) {
+        if io::stdin().is_terminal() {
+            if !options.version {
+                println!("{}", version());
+            }
+            repl(&mut lua);
+        } else if !run_script(&mut lua, "-", &[]) {
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn version() -> String {
+    format!("TEI {}, Lua 5.4", env!("CARGO_PKG_VERSION"))
+}
+
+/// The global `arg`: the command line, with the script at index 0, its arguments after it, and
+/// the interpreter and its options at negative indices. Without a script, the interpreter is at 0.
+fn arg_table<'gc>(ctx: Context<'gc>, argv: &[String], script: usize) -> Table<'gc> {
+    let arg = Table::new(&ctx);
+    for (i, value) in argv.iter().enumerate() {
+        let value = LuaString::new(&ctx, value.as_bytes());
+        arg.set(&ctx, i as i64 - script as i64, value)
+            .expect("integer keys are always valid");
+    }
+    arg
+}
+
+/// Runs the file `script`, or standard input for `-`, with `args` as its arguments. Returns
+/// whether it ran without error.
+fn run_script(lua: &mut Lua, script: &str, args: &[String]) -> bool {
+    let (name, source) = if script == "-" {
+        let mut source = Vec::new();
+        if let Err(err) = io::stdin().read_to_end(&mut source) {
+            eprintln!("tei: cannot read stdin: {err}");
+            return false;
+        }
+        ("=stdin".to_owned(), source)
+    } else {
+        match fs::read(script) {
+            Ok(source) => (format!("@{script}"), source),
+            Err(err) => {
+                eprintln!("tei: cannot open {script}: {err}");
+                return false;
+            }
+        }
+    };
+    let source = skip_shebang(source);
+    run_chunk(lua, &name, &source, args)
+}
+
+/// Runs the chunk `source`, named `name`, with `args` as its arguments. Returns whether it ran
+/// without error.
+///
+/// The chunk is run with [`Lua::call`], which collects garbage as it goes, rather than all inside
+/// one [`Lua::enter`], after which a long script would only get to free anything once it ended.
+fn run_chunk(lua: &mut Lua, name: &str, source: &[u8], args: &[String]) -> bool {
+    let function = lua.enter(|ctx| match ctx.load(name, source) {
+        Ok(function) => Ok(ctx.stash(function)),
+        Err(err) => Err(err.into_owned(ctx)),
+    });
+    let args: Variadic<String> = args.iter().cloned().collect();
+    let result = function.and_then(|function| lua.call::<_, ()>(&function, args));
+    report(result, name, source)
+}
+
+/// Blanks out a first line starting with `#`, keeping its newline so that line numbers still
+/// match the file.
+pub(crate) fn skip_shebang(mut source: Vec<u8>) -> Vec<u8> {
+    if source.first() == Some(&b'#') {
+        let end = source
+            .iter()
+            .position(|&b| b == b'\n')
+            .unwrap_or(source.len());
+        source.drain(..end);
+    }
+    source
+}
+
+/// Prints the error of `result`, if it is one, with its traceback. Returns whether it wasn't.
+///
+/// An error raised in the chunk `name` itself also shows the line of `source` it came from.
+fn report<T>(result: Result<T, Error>, name: &str, source: &[u8]) -> bool {
+    let Err(err) = result else {
+        return true;
+    };
+    let message = err.to_string();
+    let full = format!("{err:#}");
+    let traceback = full.strip_prefix(&message).unwrap_or("");
+    let snippet = err
+        .position()
+        .filter(|position| position.chunk == chunk_id(name.as_bytes()))
+        .and_then(|position| position.snippet(source));
+    match snippet {
+        Some(snippet) => eprintln!("tei: {message}\n{snippet}{traceback}"),
+        None => eprintln!("tei: {message}{traceback}"),
+    }
+    false
+}
+
+/// Reads lines at a prompt, running each statement or expression once it is complete, until the
+/// end of the input.
+fn repl(lua: &mut Lua) {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            eprintln!("tei: {err}");
+            return;
+        }
+    };
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { "> " } else { ">> " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !input.is_empty() {
+                    input.push('\n');
+                }
+                input.push_str(&line);
+            }
+            // Ctrl-C drops what has been typed so far.
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("tei: {err}");
+                break;
+            }
+        }
+        let output = lua.enter(|ctx| evaluate(ctx, &input));
+        match output {
+            Evaluated::Incomplete => continue,
+            Evaluated::Results(results) => {
+                if !results.is_empty() {
+                    println!("{results}");
+                }
+            }
+            Evaluated::Error(message) => eprintln!("{message}"),
+        }
+        let _ = editor.add_history_entry(input.as_str());
+        input.clear();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Evaluated {
+    /// The input ends partway through a statement, and more lines are needed.
+    Incomplete,
+    /// The results, each written as `inspect` writes it and separated by tabs.
+    Results(String),
+    Error(String),
+}
+
+/// Runs a line of input, as the expression whose values to print if it is one, or else as
+/// statements. `=expr` is short for `return expr`.
+fn evaluate(ctx: Context<'_>, input: &str) -> Evaluated {
+    let input = match input.strip_prefix('=') {
+        Some(expression) => format!("return {expression}"),
+        None => input.to_owned(),
+    };
+    let function = match ctx.load("=stdin", format!("return {input};")) {
+        Ok(function) => function,
+        Err(_) => match ctx.load("=stdin", &input) {
+            Ok(function) => function,
+            Err(err) if err.to_string().ends_with("<eof>") => return Evaluated::Incomplete,
+            Err(err) => return Evaluated::Error(err.to_string()),
+        },
+    };
+    match ctx.call(function, &[]) {
+        Ok(values) => {
+            let options = InspectOptions::default();
+            let values: Vec<_> = values.iter().map(|&v| inspect(v, &options)).collect();
+            Evaluated::Results(values.join("\t"))
+        }
+        Err(err) => Evaluated::Error(format!("{err:#}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_lines() {
+        let mut lua = Lua::new();
+        let mut eval = |input: &str| lua.enter(|ctx| evaluate(ctx, input));
+        let results = |s: &str| Evaluated::Results(s.to_owned());
+        assert_eq!(eval("1 + 1"), results("2"));
+        assert_eq!(eval("=1, 'a'"), results("1\t\"a\""));
+        assert_eq!(eval("x = {1, y = true}"), results(""));
+        assert_eq!(eval("x"), results("{1, y = true}"));
+        assert_eq!(eval("for i = 1, 2 do"), Evaluated::Incomplete);
+        assert_eq!(eval("for i = 1, 2 do\nx[i] = i * 10 end"), results(""));
+        assert_eq!(eval("return x[2]"), results("20"));
+        assert_eq!(
+            eval("x = = 1"),
+            Evaluated::Error("stdin:1: unexpected symbol near '='".to_owned())
+        );
+        assert!(matches!(eval("error('no')"), Evaluated::Error(e) if e.ends_with("no")));
+
+        let args = ["-e", "x = 1", "-i", "script.lua", "-v", "a"].map(String::from);
+        assert_eq!(
+            Options::parse(&args),
+            Ok(Options {
+                statements: vec!["x = 1".to_owned()],
+                interactive: true,
+                version: false,
+                script: Some((
+                    "script.lua".to_owned(),
+                    vec!["-v".to_owned(), "a".to_owned()]
+                )),
+            })
+        );
+        assert!(Options::parse(&["-x".to_owned()]).is_err());
+        assert_eq!(skip_shebang(b"#!/bin/tei\nprint()".to_vec()), b"\nprint()");
+    }
+
+    #[test]
+    fn fills_arg() {
+        let argv = ["tei", "-e", "x = 1", "script.lua", "a", "b"].map(String::from);
+        let listed = Lua::new().enter(|ctx| {
+            let arg = arg_table(ctx, &argv, 3);
+            ctx.globals()
+                .set(&ctx, LuaString::new(&ctx, b"arg"), arg)
+                .unwrap();
+            let listed = ctx.eval("local t = {} for i = -3, #arg do t[#t + 1] = arg[i] end return table.concat(t, ',')");
+            listed.unwrap()[0].to_string()
+        });
+        assert_eq!(listed, "tei,-e,x = 1,script.lua,a,b");
+    }
+}
@@ -0,0 +1,449 @@
+//! Derive macros for TEI's `FromLua` and `IntoLua` conversion traits, re-exported by `tei` with its
+//! `derive` feature.
+//!
+//! A struct with named fields maps to a table with a string key per field. A newtype struct maps
+//! to whatever its field does. An enum of unit variants maps to the variant's name as a string;
+//! one with fields needs `#[lua(tag = "...")]` and maps to a table naming the variant under that
+//! key, next to the variant's fields.
+//!
+//! Attributes:
+//!
+//! - `#[lua(rename_all = "...")]` on the type renames every field or variant, to `"lowercase"`,
+//!   `"UPPERCASE"`, `"snake_case"`, `"SCREAMING_SNAKE_CASE"`, `"camelCase"`, `"PascalCase"` or
+//!   `"kebab-case"`.
+//! - `#[lua(rename = "...")]` on a field or variant gives it another name.
+//! - `#[lua(default)]` on a field lets it be nil or missing, taking `Default::default()`;
+//!   `#[lua(default = "path")]` calls the function at `path` instead.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, Ident, LitByteStr, LitStr,
+    Path, Result,
+};
+
+#[proc_macro_derive(FromLua, attributes(lua))]
+pub fn derive_from_lua(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    Shape::parse(&input)
+        .map(|shape| from_lua(&input.ident, &shape))
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(IntoLua, attributes(lua))]
+pub fn derive_into_lua(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    Shape::parse(&input)
+        .map(|shape| into_lua(&input.ident, &shape))
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// The attributes of a type, field or variant.
+#[derive(Default)]
+struct Attrs {
+    rename: Option<String>,
+    rename_all: Option<RenameRule>,
+    tag: Option<String>,
+    default: Option<DefaultValue>,
+}
+
+/// What a field that is nil or missing gets.
+enum DefaultValue {
+    Trait,
+    Function(Path),
+}
+
+impl Attrs {
+    fn parse(attrs: &[Attribute]) -> Result<Attrs> {
+        let mut parsed = Attrs::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("lua")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    parsed.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("rename_all") {
+                    let rule = meta.value()?.parse::<LitStr>()?;
+                    parsed.rename_all = Some(RenameRule::parse(&rule)?);
+                } else if meta.path.is_ident("tag") {
+                    parsed.tag = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("default") {
+                    parsed.default = Some(match meta.value() {
+                        Ok(value) => DefaultValue::Function(value.parse::<LitStr>()?.parse()?),
+                        Err(_) => DefaultValue::Trait,
+                    });
+                } else {
+                    return Err(meta.error("unknown lua attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(parsed)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum RenameRule {
+    Lower,
+    Upper,
+    Snake,
+    ScreamingSnake,
+    Camel,
+    Pascal,
+    Kebab,
+}
+
+impl RenameRule {
+    fn parse(rule: &LitStr) -> Result<RenameRule> {
+        Ok(match rule.value().as_str() {
+            "lowercase" => RenameRule::Lower,
+            "UPPERCASE" => RenameRule::Upper,
+            "snake_case" => RenameRule::Snake,
+            "SCREAMING_SNAKE_CASE" => RenameRule::ScreamingSnake,
+            "camelCase" => RenameRule::Camel,
+            "PascalCase" => RenameRule::Pascal,
+            "kebab-case" => RenameRule::Kebab,
+            _ => return Err(Error::new_spanned(rule, "unknown rename rule")),
+        })
+    }
+
+    /// Renames a field (in snake case) or a variant (in Pascal case).
+    fn apply(self, name: &str) -> String {
+        let mut words = Vec::new();
+        for part in name.split('_').filter(|part| !part.is_empty()) {
+            let mut word = String::new();
+            for c in part.chars() {
+                if c.is_uppercase() && !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                word.extend(c.to_lowercase());
+            }
+            words.push(word);
+        }
+        let capitalized = |word: &String| {
+            let mut chars = word.chars();
+            chars.next().map_or(String::new(), |first| {
+                first.to_uppercase().chain(chars).collect()
+            })
+        };
+        match self {
+            RenameRule::Lower => words.concat(),
+            RenameRule::Upper => words.concat().to_uppercase(),
+            RenameRule::Snake => words.join("_"),
+            RenameRule::ScreamingSnake => words.join("_").to_uppercase(),
+            RenameRule::Kebab => words.join("-"),
+            RenameRule::Pascal => words.iter().map(capitalized).collect(),
+            RenameRule::Camel => {
+                let mut words = words.iter();
+                let first = words.next().cloned().unwrap_or_default();
+                first + &words.map(capitalized).collect::<String>()
+            }
+        }
+    }
+}
+
+/// A named field, and the key it has in the table.
+struct Field {
+    ident: Ident,
+    key: String,
+    default: Option<DefaultValue>,
+}
+
+struct Variant {
+    ident: Ident,
+    name: String,
+    /// `None` for a unit variant.
+    fields: Option<Vec<Field>>,
+}
+
+/// How a type maps to Lua values.
+enum Shape {
+    Struct(Vec<Field>),
+    Newtype,
+    /// Unit variants, as strings.
+    Names(Vec<Variant>),
+    Tagged {
+        tag: String,
+        variants: Vec<Variant>,
+    },
+}
+
+impl Shape {
+    fn parse(input: &DeriveInput) -> Result<Shape> {
+        if !input.generics.params.is_empty() {
+            let message = "generic types can't derive the Lua conversions";
+            return Err(Error::new_spanned(&input.generics, message));
+        }
+        let attrs = Attrs::parse(&input.attrs)?;
+        match &input.data {
+            Data::Struct(data) => match &data.fields {
+                Fields::Named(_) => {
+                    Ok(Shape::Struct(named_fields(&data.fields, attrs.rename_all)?))
+                }
+                Fields::Unnamed(fields) if fields.unnamed.len() == 1 => Ok(Shape::Newtype),
+                _ => Err(Error::new_spanned(
+                    &input.ident,
+                    "only structs with named fields or a single unnamed one are supported",
+                )),
+            },
+            Data::Enum(data) => {
+                let mut variants = Vec::new();
+                for variant in &data.variants {
+                    let variant_attrs = Attrs::parse(&variant.attrs)?;
+                    let ident = variant.ident.clone();
+                    let name = match (variant_attrs.rename, attrs.rename_all) {
+                        (Some(name), _) => name,
+                        (None, Some(rule)) => rule.apply(&ident.to_string()),
+                        (None, None) => ident.to_string(),
+                    };
+                    let fields = match &variant.fields {
+                        Fields::Unit => None,
+                        Fields::Named(_) => Some(named_fields(&variant.fields, None)?),
+                        Fields::Unnamed(_) => {
+                            let message =
+                                "only unit variants and ones with named fields are supported";
+                            return Err(Error::new_spanned(variant, message));
+                        }
+                    };
+                    variants.push(Variant {
+                        ident,
+                        name,
+                        fields,
+                    });
+                }
+                match attrs.tag {
+                    Some(tag) => Ok(Shape::Tagged { tag, variants }),
+                    None if variants.iter().all(|v| v.fields.is_none()) => Ok(Shape::Names(variants)),
+                    None => Err(Error::new_spanned(
+                        &input.ident,
+                        "enums with fields need #[lua(tag = \"...\")] to name the key holding the variant",
+                    )),
+                }
+            }
+            Data::Union(_) => Err(Error::new_spanned(&input.ident, "unions are not supported")),
+        }
+    }
+}
+
+fn named_fields(fields: &Fields, rename_all: Option<RenameRule>) -> Result<Vec<Field>> {
+    fields
+        .iter()
+        .map(|field| {
+            let attrs = Attrs::parse(&field.attrs)?;
+            let ident = field.ident.clone().expect("named fields have names");
+            let key = match (attrs.rename, rename_all) {
+                (Some(key), _) => key,
+                (None, Some(rule)) => rule.apply(&ident.to_string()),
+                (None, None) => ident.to_string(),
+            };
+            Ok(Field {
+                ident,
+                key,
+                default: attrs.default,
+            })
+        })
+        .collect()
+}
+
+/// An expression converting `table`'s fields, for `Self { ... }` or `Self::Variant { ... }`.
+fn read_fields(type_name: &str, fields: &[Field]) -> TokenStream2 {
+    let inits = fields.iter().map(|field| {
+        let Field { ident, key, .. } = field;
+        let convert = quote! {
+            ::tei::FromLua::from_lua(ctx, value).map_err(|err| ::tei::ConversionError {
+                from: "table",
+                to: #type_name,
+                message: ::std::option::Option::Some(::std::format!("bad field '{}' ({})", #key, err)),
+            })?
+        };
+        let value = match &field.default {
+            None => convert,
+            Some(default) => {
+                let default = match default {
+                    DefaultValue::Trait => quote!(::std::default::Default::default()),
+                    DefaultValue::Function(path) => quote!(#path()),
+                };
+                quote!(if value.is_nil() { #default } else { #convert })
+            }
+        };
+        quote! {
+            #ident: {
+                let value = table.get_str(#key);
+                #value
+            }
+        }
+    });
+    quote!({ #(#inits),* })
+}
+
+/// Statements setting `table`'s fields from bindings named after them.
+fn write_fields(fields: &[Field]) -> TokenStream2 {
+    let sets = fields.iter().map(|Field { ident, key, .. }| {
+        quote! {
+            table
+                .set(&ctx, ::tei::LuaString::new(&ctx, #key.as_bytes()), ::tei::IntoLua::into_lua(#ident, ctx)?)
+                .expect("string keys are always valid");
+        }
+    });
+    quote!(#(#sets)*)
+}
+
+/// The error for a variant name that isn't one of `variants`.
+fn unknown_variant(type_name: &str, variants: &[Variant], name: TokenStream2) -> TokenStream2 {
+    let expected = variants
+        .iter()
+        .map(|v| format!("'{}'", v.name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let message = format!("unknown {type_name} '{{}}', expected one of {expected}");
+    quote! {
+        ::std::result::Result::Err(::tei::ConversionError {
+            from: "string",
+            to: #type_name,
+            message: ::std::option::Option::Some(::std::format!(#message, #name.to_string_lossy())),
+        })
+    }
+}
+
+fn from_lua(ident: &Ident, shape: &Shape) -> TokenStream2 {
+    let type_name = ident.to_string();
+    let table = quote! {
+        let table = match value {
+            ::tei::Value::Table(table) => table,
+            _ => return ::std::result::Result::Err(::tei::ConversionError::mismatch(value, "table")),
+        };
+    };
+    let body = match shape {
+        Shape::Struct(fields) => {
+            let fields = read_fields(&type_name, fields);
+            quote! {
+                #table
+                ::std::result::Result::Ok(Self #fields)
+            }
+        }
+        Shape::Newtype => quote! {
+            ::std::result::Result::Ok(Self(::tei::FromLua::from_lua(ctx, value)?))
+        },
+        Shape::Names(variants) => {
+            let arms = variants.iter().map(|Variant { ident, name, .. }| {
+                let name = LitByteStr::new(name.as_bytes(), ident.span());
+                quote!(#name => ::std::result::Result::Ok(Self::#ident),)
+            });
+            let unknown = unknown_variant(&type_name, variants, quote!(name));
+            quote! {
+                let name = match value {
+                    ::tei::Value::String(name) => name,
+                    _ => return ::std::result::Result::Err(::tei::ConversionError::mismatch(value, "string")),
+                };
+                match name.as_bytes() {
+                    #(#arms)*
+                    _ => #unknown,
+                }
+            }
+        }
+        Shape::Tagged { tag, variants } => {
+            let arms = variants.iter().map(|variant| {
+                let Variant {
+                    ident,
+                    name,
+                    fields,
+                } = variant;
+                let name = LitByteStr::new(name.as_bytes(), ident.span());
+                match fields {
+                    None => quote!(#name => ::std::result::Result::Ok(Self::#ident),),
+                    Some(fields) => {
+                        let fields = read_fields(&type_name, fields);
+                        quote!(#name => ::std::result::Result::Ok(Self::#ident #fields),)
+                    }
+                }
+            });
+            let unknown = unknown_variant(&type_name, variants, quote!(name));
+            quote! {
+                #table
+                let name = match table.get_str(#tag) {
+                    ::tei::Value::String(name) => name,
+                    value => {
+                        let err = ::tei::ConversionError::mismatch(value, "string");
+                        return ::std::result::Result::Err(::tei::ConversionError {
+                            from: "table",
+                            to: #type_name,
+                            message: ::std::option::Option::Some(::std::format!("bad field '{}' ({})", #tag, err)),
+                        });
+                    }
+                };
+                match name.as_bytes() {
+                    #(#arms)*
+                    _ => #unknown,
+                }
+            }
+        }
+    };
+    quote! {
+        impl<'gc> ::tei::FromLua<'gc> for #ident {
+            fn from_lua(
+                ctx: ::tei::Context<'gc>,
+                value: ::tei::Value<'gc>,
+            ) -> ::std::result::Result<Self, ::tei::ConversionError> {
+                #body
+            }
+        }
+    }
+}
+
+fn into_lua(ident: &Ident, shape: &Shape) -> TokenStream2 {
+    let body = match shape {
+        Shape::Struct(fields) => {
+            let bindings = fields.iter().map(|field| &field.ident);
+            let sets = write_fields(fields);
+            quote! {
+                let Self { #(#bindings),* } = self;
+                let table = ::tei::Table::new(&ctx);
+                #sets
+                ::std::result::Result::Ok(::tei::Value::Table(table))
+            }
+        }
+        Shape::Newtype => quote!(::tei::IntoLua::into_lua(self.0, ctx)),
+        Shape::Names(variants) => {
+            let arms = variants
+                .iter()
+                .map(|Variant { ident, name, .. }| quote!(Self::#ident => #name,));
+            quote! {
+                let name: &str = match self { #(#arms)* };
+                ::std::result::Result::Ok(::tei::Value::String(::tei::LuaString::new(&ctx, name.as_bytes())))
+            }
+        }
+        Shape::Tagged { tag, variants } => {
+            let arms = variants.iter().map(|Variant { ident, name, fields }| {
+                let set_tag = quote! {
+                    table
+                        .set(&ctx, ::tei::LuaString::new(&ctx, #tag.as_bytes()), ::tei::LuaString::new(&ctx, #name.as_bytes()))
+                        .expect("string keys are always valid");
+                };
+                match fields {
+                    None => quote!(Self::#ident => { #set_tag }),
+                    Some(fields) => {
+                        let bindings = fields.iter().map(|field| &field.ident);
+                        let sets = write_fields(fields);
+                        quote!(Self::#ident { #(#bindings),* } => { #set_tag #sets })
+                    }
+                }
+            });
+            quote! {
+                let table = ::tei::Table::new(&ctx);
+                match self { #(#arms)* }
+                ::std::result::Result::Ok(::tei::Value::Table(table))
+            }
+        }
+    };
+    quote! {
+        impl<'gc> ::tei::IntoLua<'gc> for #ident {
+            fn into_lua(
+                self,
+                ctx: ::tei::Context<'gc>,
+            ) -> ::std::result::Result<::tei::Value<'gc>, ::tei::ConversionError> {
+                #body
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use tei::{FromLua, IntoLua, Lua, Value};
+
+#[derive(Debug, PartialEq, FromLua, IntoLua)]
+#[lua(rename_all = "camelCase")]
+struct Config {
+    window_title: String,
+    #[lua(rename = "size")]
+    dimensions: Size,
+    #[lua(default)]
+    fullscreen: bool,
+    #[lua(default = "default_scale")]
+    ui_scale: f64,
+    theme: Theme,
+    shapes: Vec<Shape>,
+    extra: Option<HashMap<String, i64>>,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+#[derive(Debug, PartialEq, FromLua, IntoLua)]
+struct Size {
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug, PartialEq, FromLua, IntoLua)]
+#[lua(rename_all = "lowercase")]
+enum Theme {
+    Light,
+    Dark,
+    #[lua(rename = "high-contrast")]
+    HighContrast,
+}
+
+#[derive(Debug, PartialEq, FromLua, IntoLua)]
+#[lua(tag = "kind", rename_all = "snake_case")]
+enum Shape {
+    Circle { radius: f64 },
+    Rect { width: f64, height: f64 },
+    Empty,
+}
+
+#[derive(Debug, PartialEq, FromLua, IntoLua)]
+struct Meters(f64);
+
+#[test]
+fn from_tables() {
+    Lua::new().enter(|ctx| {
+        let value = ctx
+            .eval(
+                "return {
+                    windowTitle = 'demo', size = {width = 640, height = 480},
+                    theme = 'high-contrast',
+                    shapes = {{kind = 'circle', radius = 2}, {kind = 'empty'}},
+                }",
+            )
+            .unwrap()[0];
+        assert_eq!(
+            Config::from_lua(ctx, value).unwrap(),
+            Config {
+                window_title: "demo".to_owned(),
+                dimensions: Size {
+                    width: 640,
+                    height: 480
+                },
+                fullscreen: false,
+                ui_scale: 1.0,
+                theme: Theme::HighContrast,
+                shapes: vec![Shape::Circle { radius: 2.0 }, Shape::Empty],
+                extra: None,
+            }
+        );
+        assert_eq!(Meters::from_lua(ctx, Value::Integer(3)).unwrap(), Meters(3.0));
+
+        let error = |source: &str| {
+            let value = ctx.eval(source).unwrap()[0];
+            Config::from_lua(ctx, value).unwrap_err().to_string()
+        };
+        let base = "windowTitle = 'x', theme = 'dark', shapes = {}";
+        assert_eq!(
+            error(&format!("return {{{base}, size = {{width = 1}}}}")),
+            "bad field 'size' (bad field 'height' (number expected, got nil))"
+        );
+        assert_eq!(
+            error(&format!("return {{{base}, size = {{width = 1, height = 2}}, theme = 'blue'}}")),
+            "bad field 'theme' (unknown Theme 'blue', expected one of 'light', 'dark', 'high-contrast')"
+        );
+        assert_eq!(
+            error(&format!(
+                "return {{{base}, size = {{width = 1, height = 2}}, shapes = {{{{radius = 1}}}}}}"
+            )),
+            "bad field 'shapes' (bad element #1 (bad field 'kind' (string expected, got nil)))"
+        );
+        assert_eq!(error("return 5"), "table expected, got number");
+    });
+}
+
+#[test]
+fn round_trip() {
+    Lua::new().enter(|ctx| {
+        let config = Config {
+            window_title: "demo".to_owned(),
+            dimensions: Size {
+                width: 1,
+                height: 2,
+            },
+            fullscreen: true,
+            ui_scale: 1.5,
+            theme: Theme::Dark,
+            shapes: vec![Shape::Rect {
+                width: 3.0,
+                height: 4.0,
+            }],
+            extra: Some(HashMap::from([("retries".to_owned(), 3)])),
+        };
+        let value = config.into_lua(ctx).unwrap();
+        let Value::Table(table) = value else {
+            panic!("structs convert to tables");
+        };
+        assert_eq!(table.get_str("windowTitle").to_string(), "demo");
+        assert_eq!(table.get_str("theme").to_string(), "dark");
+        ctx.globals()
+            .set(&ctx, "config".into_lua(ctx).unwrap(), table)
+            .unwrap();
+        let kind = ctx
+            .eval("return config.shapes[1].kind, config.size.height")
+            .unwrap();
+        assert_eq!(kind[0].to_string(), "rect");
+        assert_eq!(kind[1].to_string(), "2");
+        let back = Config::from_lua(ctx, value).unwrap();
+        assert_eq!(
+            back.shapes,
+            [Shape::Rect {
+                width: 3.0,
+                height: 4.0
+            }]
+        );
+        assert_eq!(back.extra.unwrap()["retries"], 3);
+    });
+}